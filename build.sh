@@ -35,7 +35,7 @@ while [ $# -gt 0 ]; do
             ;;
         *)
             echo "Usage: $0 [debug|release] [--flash] [--model <MODEL>]"
-            echo "Supported models: std ${!DEVICE_PROFILES[*]}"
+            echo "Supported models: std wasi ${!DEVICE_PROFILES[*]}"
             exit 1
             ;;
     esac
@@ -49,8 +49,10 @@ resolve_device_config() {
         echo "samples/${config[0]} +${config[1]} ${config[2]} ${config[3]}"
     elif [[ "$model" == "std" ]]; then
         echo "samples/std"
+    elif [[ "$model" == "wasi" ]]; then
+        echo "samples/wasi +stable wasm32-wasip1"
     else
-        echo "Error: Unsupported model '$model'. Supported: std ${!DEVICE_PROFILES[*]}" >&2
+        echo "Error: Unsupported model '$model'. Supported: std wasi ${!DEVICE_PROFILES[*]}" >&2
         exit 1
     fi
 }
@@ -95,6 +97,9 @@ flash_device() {
         esp*)
             flash_cmd="$FLASH_TOOL --chip $MODEL $EMBEDDED_PROJECT_DIR/target/$EMBEDDED_TARGET/$BUILD_MODE/program"
             ;;
+        wasi)
+            flash_cmd="wasmtime run -S inherit-network=y,allow-ip-name-lookup=y $EMBEDDED_PROJECT_DIR/target/$EMBEDDED_TARGET/$BUILD_MODE/program.wasm"
+            ;;
         *)
             flash_cmd="cd $EMBEDDED_PROJECT_DIR && cargo +stable run"
             ;;
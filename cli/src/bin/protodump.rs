@@ -0,0 +1,303 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::Read;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::DateTime;
+use clap::Parser;
+use protocol::Message;
+
+#[derive(Parser)]
+#[command(name = "protodump", about = "Decode dispatcher protocol messages from a pcap capture or raw byte stream")]
+struct Cli {
+    #[arg(help = "Capture to read, - for stdin")]
+    path: PathBuf,
+    #[arg(long, help = "Treat the input as raw frames even if it looks like a pcap")]
+    raw: bool,
+    #[arg(long, help = "Only follow TCP traffic to or from this port")]
+    port: Option<u16>,
+    #[arg(long, help = "Print a hexdump of every frame below the message")]
+    hex: bool,
+}
+
+// A chunk of the byte stream, pcap records carry when and where it was seen.
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    timestamp: Option<Duration>,
+    flow: Option<String>,
+    payload: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum Frame {
+    Message(Message, Vec<u8>),
+    // Bytes that did not decode, the rest of the flow is dropped as framing is lost.
+    Garbage(Vec<u8>, protocol::Error),
+}
+
+const PCAP_MICROS: u32 = 0xa1b2c3d4;
+const PCAP_NANOS: u32 = 0xa1b23c4d;
+
+fn is_pcap(data: &[u8]) -> bool {
+    data.len() >= 4 && [PCAP_MICROS, PCAP_NANOS].iter().any(|magic| {
+        data[..4] == magic.to_le_bytes() || data[..4] == magic.to_be_bytes()
+    })
+}
+
+// Classic pcap only, pcapng captures can be converted with `editcap -F pcap`.
+fn read_pcap(data: &[u8], port: Option<u16>) -> Result<Vec<Segment>, String> {
+    if data.len() < 24 {
+        return Err("truncated pcap header".into());
+    }
+    let magic = [data[0], data[1], data[2], data[3]];
+    let (little, nanos) = match magic {
+        _ if magic == PCAP_MICROS.to_le_bytes() => (true, false),
+        _ if magic == PCAP_NANOS.to_le_bytes() => (true, true),
+        _ if magic == PCAP_MICROS.to_be_bytes() => (false, false),
+        _ => (false, true),
+    };
+    let u32_at = |offset: usize| {
+        let bytes = [data[offset], data[offset + 1], data[offset + 2], data[offset + 3]];
+        if little { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+    };
+    let link_type = u32_at(20);
+
+    let mut segments = Vec::new();
+    let mut offset = 24;
+    while offset + 16 <= data.len() {
+        let (secs, frac, len) = (u32_at(offset), u32_at(offset + 4), u32_at(offset + 8) as usize);
+        let packet = data
+            .get(offset + 16..offset + 16 + len)
+            .ok_or_else(|| format!("truncated record at offset {}", offset))?;
+        offset += 16 + len;
+
+        let Some((flow, ports, payload)) = link_payload(link_type, packet) else {
+            continue;
+        };
+        if port.is_some_and(|port| !ports.contains(&port)) || payload.is_empty() {
+            continue;
+        }
+        let frac = if nanos { frac } else { frac.saturating_mul(1000) };
+        segments.push(Segment {
+            timestamp: Some(Duration::new(secs as u64, frac)),
+            flow: Some(flow),
+            payload: payload.to_vec(),
+        });
+    }
+    Ok(segments)
+}
+
+// Strips the link, IP and TCP headers, returns the flow name, its ports and the TCP payload.
+fn link_payload(link_type: u32, packet: &[u8]) -> Option<(String, [u16; 2], &[u8])> {
+    let ip = match link_type {
+        // Ethernet, skipping a single VLAN tag.
+        1 => match u16::from_be_bytes([*packet.get(12)?, *packet.get(13)?]) {
+            0x8100 => packet.get(18..)?,
+            _ => packet.get(14..)?,
+        },
+        // Raw IP.
+        101 => packet,
+        // Linux cooked capture, what `tcpdump -i any` writes.
+        113 => packet.get(16..)?,
+        _ => return None,
+    };
+
+    let (source, destination, protocol, tcp) = match ip.first()? >> 4 {
+        4 => {
+            let header_len = (ip[0] & 0x0f) as usize * 4;
+            let total_len = u16::from_be_bytes([*ip.get(2)?, *ip.get(3)?]) as usize;
+            let source = IpAddr::from(<[u8; 4]>::try_from(ip.get(12..16)?).ok()?);
+            let destination = IpAddr::from(<[u8; 4]>::try_from(ip.get(16..20)?).ok()?);
+            (source, destination, ip[9], ip.get(header_len..total_len.min(ip.len()))?)
+        }
+        6 => {
+            let payload_len = u16::from_be_bytes([*ip.get(4)?, *ip.get(5)?]) as usize;
+            let source = IpAddr::from(<[u8; 16]>::try_from(ip.get(8..24)?).ok()?);
+            let destination = IpAddr::from(<[u8; 16]>::try_from(ip.get(24..40)?).ok()?);
+            (source, destination, ip[6], ip.get(40..(40 + payload_len).min(ip.len()))?)
+        }
+        _ => return None,
+    };
+    if protocol != 6 {
+        return None;
+    }
+
+    let source_port = u16::from_be_bytes([*tcp.first()?, *tcp.get(1)?]);
+    let destination_port = u16::from_be_bytes([*tcp.get(2)?, *tcp.get(3)?]);
+    let data_offset = (*tcp.get(12)? >> 4) as usize * 4;
+    let flow = format!(
+        "{} -> {}",
+        SocketAddr::new(source, source_port),
+        SocketAddr::new(destination, destination_port)
+    );
+    Some((flow, [source_port, destination_port], tcp.get(data_offset..)?))
+}
+
+// Buffers each direction of each connection separately, messages may span TCP segments.
+#[derive(Default)]
+struct Reassembler {
+    buffers: HashMap<Option<String>, Vec<u8>>,
+}
+
+impl Reassembler {
+    fn push(&mut self, segment: &Segment) -> Vec<Frame> {
+        let buffer = self.buffers.entry(segment.flow.clone()).or_default();
+        buffer.extend_from_slice(&segment.payload);
+
+        let mut frames = Vec::new();
+        loop {
+            match Message::decode(buffer) {
+                Ok((message, consumed)) => frames.push(Frame::Message(message, buffer.drain(..consumed).collect())),
+                Err(protocol::Error::InsufficientData) => break,
+                Err(e) => {
+                    frames.push(Frame::Garbage(std::mem::take(buffer), e));
+                    break;
+                }
+            }
+        }
+        frames
+    }
+
+    // Whatever is left once the input ends, one entry per flow with an incomplete frame.
+    fn leftovers(&self) -> impl Iterator<Item = (&Option<String>, &Vec<u8>)> {
+        self.buffers.iter().filter(|(_, buffer)| !buffer.is_empty())
+    }
+}
+
+fn hexdump(data: &[u8]) -> String {
+    data.chunks(16)
+        .enumerate()
+        .map(|(row, chunk)| {
+            let hex = chunk.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+            let ascii = chunk
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+                .collect::<String>();
+            format!("  {:08x}  {:<47}  |{}|", row * 16, hex, ascii)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn header(segment: &Segment) -> String {
+    let timestamp = segment
+        .timestamp
+        .and_then(|timestamp| DateTime::from_timestamp(timestamp.as_secs() as i64, timestamp.subsec_nanos()))
+        .map(|timestamp| timestamp.format("%H:%M:%S%.6f ").to_string())
+        .unwrap_or_default();
+    let flow = segment.flow.as_deref().map(|flow| format!("{} ", flow)).unwrap_or_default();
+    format!("{}{}", timestamp, flow)
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    let data = if cli.path.as_os_str() == "-" {
+        let mut data = Vec::new();
+        std::io::stdin().read_to_end(&mut data)?;
+        data
+    } else {
+        std::fs::read(&cli.path)?
+    };
+
+    let segments = if !cli.raw && is_pcap(&data) {
+        read_pcap(&data, cli.port)?
+    } else {
+        vec![Segment {
+            timestamp: None,
+            flow: None,
+            payload: data,
+        }]
+    };
+
+    let mut reassembler = Reassembler::default();
+    for segment in &segments {
+        for frame in reassembler.push(segment) {
+            match frame {
+                Frame::Message(message, bytes) => {
+                    println!("{}{}", header(segment), serde_json::to_string_pretty(&message)?);
+                    if cli.hex {
+                        println!("{}", hexdump(&bytes));
+                    }
+                }
+                Frame::Garbage(bytes, e) => {
+                    println!("{}undecodable frame: {}\n{}", header(segment), e, hexdump(&bytes));
+                }
+            }
+        }
+    }
+    for (flow, buffer) in reassembler.leftovers() {
+        let flow = flow.as_deref().unwrap_or("input");
+        println!("{}: {} trailing bytes of an incomplete frame\n{}", flow, buffer.len(), hexdump(buffer));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use protocol::TaskId;
+
+    use super::*;
+
+    // Ethernet + IPv4 + TCP around `payload`, sent from port 3030.
+    fn packet(payload: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0; 12];
+        packet.extend_from_slice(&[0x08, 0x00]);
+        let total_len = (20 + 20 + payload.len()) as u16;
+        packet.extend_from_slice(&[0x45, 0, (total_len >> 8) as u8, total_len as u8, 0, 0, 0, 0, 64, 6, 0, 0]);
+        packet.extend_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        packet.extend_from_slice(&[0x0b, 0xd6, 0xc3, 0x50, 0, 0, 0, 0, 0, 0, 0, 0, 0x50, 0x18, 0, 0, 0, 0, 0, 0]);
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn pcap(packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut pcap = PCAP_MICROS.to_le_bytes().to_vec();
+        pcap.extend_from_slice(&[2, 0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 1, 0, 0, 0]);
+        for (i, packet) in packets.iter().enumerate() {
+            pcap.extend_from_slice(&(1_700_000_000u32 + i as u32).to_le_bytes());
+            pcap.extend_from_slice(&500u32.to_le_bytes());
+            pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(&(packet.len() as u32).to_le_bytes());
+            pcap.extend_from_slice(packet);
+        }
+        pcap
+    }
+
+    #[test]
+    fn test_pcap_reassembly() {
+        let cancel = Message::ServerCancel { task_id: TaskId(7) }.encode().unwrap();
+        let unpin = Message::ServerUnpin { module: "blink".into() }.encode().unwrap();
+        let mut stream = cancel.clone();
+        stream.extend_from_slice(&unpin);
+        let (first, second) = stream.split_at(6);
+
+        let capture = pcap(&[packet(first), packet(second)]);
+        assert!(is_pcap(&capture));
+        let segments = read_pcap(&capture, Some(3030)).unwrap();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].flow.as_deref(), Some("10.0.0.1:3030 -> 10.0.0.2:50000"));
+        assert_eq!(segments[1].timestamp, Some(Duration::new(1_700_000_001, 500_000)));
+        assert!(read_pcap(&capture, Some(80)).unwrap().is_empty());
+
+        let mut reassembler = Reassembler::default();
+        let frames = reassembler.push(&segments[0]);
+        assert!(matches!(&frames[..], [Frame::Message(Message::ServerCancel { .. }, bytes)] if *bytes == cancel));
+        let frames = reassembler.push(&segments[1]);
+        assert!(matches!(&frames[..], [Frame::Message(Message::ServerUnpin { .. }, _)]));
+        assert_eq!(reassembler.leftovers().count(), 0);
+    }
+
+    #[test]
+    fn test_raw_garbage() {
+        let mut reassembler = Reassembler::default();
+        let frames = reassembler.push(&Segment {
+            timestamp: None,
+            flow: None,
+            payload: vec![0, 1, 0xff, 0, 3],
+        });
+        assert!(matches!(&frames[..], [Frame::Garbage(bytes, _)] if bytes.len() == 5));
+        assert_eq!(hexdump(b"AB\x00"), "  00000000  41 42 00                                         |AB.|");
+    }
+}
@@ -0,0 +1,559 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{Map, Value as Json};
+
+#[allow(clippy::all)]
+mod pb {
+    tonic::include_proto!("control");
+}
+
+use pb::control_client::ControlClient;
+use pb::value::Kind;
+use protocol::Message;
+
+#[derive(Parser)]
+#[command(name = "prototype-cli", about = "Operate a prototype dispatcher through its control API")]
+struct Cli {
+    #[arg(long, default_value = "http://localhost:50051")]
+    endpoint: String,
+    #[arg(long, help = "Print JSON instead of a table")]
+    json: bool,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(subcommand)]
+    Tasks(TasksCommand),
+    #[command(subcommand)]
+    Sessions(SessionsCommand),
+    #[command(subcommand)]
+    Modules(ModulesCommand),
+    #[command(subcommand)]
+    Blobs(BlobsCommand),
+    #[command(subcommand)]
+    Firmware(FirmwareCommand),
+    #[command(subcommand)]
+    Results(ResultsCommand),
+    #[command(subcommand)]
+    Message(MessageCommand),
+}
+
+#[derive(Subcommand)]
+enum TasksCommand {
+    List,
+    Get {
+        task_id: u64,
+    },
+    Submit(SubmitArgs),
+    Schedule {
+        #[command(flatten)]
+        task: SubmitArgs,
+        #[arg(long, help = "Name of the recurring task, defaults to the module name")]
+        name: Option<String>,
+        #[arg(long, help = "Re-enqueue every N seconds", conflicts_with = "cron", required_unless_present = "cron")]
+        every: Option<u64>,
+        #[arg(long, help = "Cron expression such as \"*/5 * * * *\"")]
+        cron: Option<String>,
+    },
+}
+
+#[derive(Args)]
+struct SubmitArgs {
+    #[arg(long)]
+    module: String,
+    #[arg(long = "param", help = "Typed parameter such as i32:800, f64:0.5 or blob:<id>")]
+    params: Vec<String>,
+    #[arg(long, default_value_t = 1)]
+    priority: u32,
+    #[arg(long)]
+    broadcast: bool,
+    #[arg(long = "label")]
+    labels: Vec<String>,
+    #[arg(long, default_value = "", help = "Tenant the task is accounted to for fair sharing")]
+    tenant: String,
+    #[arg(long = "env", help = "Environment variable the module sees, as KEY=VALUE")]
+    env: Vec<String>,
+    #[arg(long, help = "Only run on otherwise idle devices, cancelled when real work arrives")]
+    filler: bool,
+    #[arg(long, default_value = "", help = "Job the task is counted into, shown as one entry in the inspector")]
+    group: String,
+    #[arg(long, help = "Take the device of a lower-priority task when every device is busy")]
+    preempt: bool,
+}
+
+impl SubmitArgs {
+    fn into_request(self) -> Result<pb::SubmitTaskRequest, String> {
+        let params = self
+            .params
+            .iter()
+            .map(|param| parse_param(param))
+            .collect::<Result<Vec<_>, _>>()?;
+        let env = self.env.iter().map(|var| parse_env(var)).collect::<Result<Vec<_>, _>>()?;
+        Ok(pb::SubmitTaskRequest {
+            module: self.module,
+            params,
+            priority: self.priority,
+            broadcast: self.broadcast,
+            labels: self.labels,
+            tenant: self.tenant,
+            env,
+            filler: self.filler,
+            group: self.group,
+            preempt: self.preempt,
+        })
+    }
+}
+
+#[derive(Subcommand)]
+enum SessionsCommand {
+    List,
+}
+
+#[derive(Subcommand)]
+enum ModulesCommand {
+    Upload {
+        path: PathBuf,
+        #[arg(long, help = "Module name, defaults to the file stem")]
+        name: Option<String>,
+        #[arg(long, default_value_t = 0)]
+        chunk_size: u32,
+        #[arg(long, help = "Upload a native cdylib for workers running with --allow-native")]
+        native: bool,
+        #[arg(long = "library", help = "Uploaded module this one imports from, linked on the device")]
+        libraries: Vec<String>,
+    },
+    Prefetch {
+        name: String,
+    },
+    #[command(about = "Keep the module in device caches, devices pick the pin up with their next transfer of it")]
+    Pin {
+        name: String,
+    },
+    #[command(about = "Let devices evict the module again")]
+    Unpin {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum BlobsCommand {
+    #[command(about = "Store a task input on the server, tasks reference it with --param blob:<id>")]
+    Upload {
+        path: PathBuf,
+        #[arg(long, default_value_t = 0)]
+        chunk_size: u32,
+    },
+}
+
+#[derive(Subcommand)]
+enum FirmwareCommand {
+    Update {
+        path: PathBuf,
+        #[arg(long)]
+        version: String,
+        #[arg(long, default_value = "", help = "Only update devices of this architecture, e.g. xtensa")]
+        arch: String,
+        #[arg(long, default_value_t = 0)]
+        chunk_size: u32,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+#[derive(Subcommand)]
+enum ResultsCommand {
+    #[command(about = "Write stored results to a CSV or Parquet file, the server needs RESULTS_DB")]
+    Export {
+        output: PathBuf,
+        #[arg(long, value_enum, help = "Defaults to the extension of the output file")]
+        format: Option<ExportFormat>,
+        #[arg(long, help = "Only export results of this module")]
+        module: Option<String>,
+        #[arg(long, help = "Only export results completed after this many seconds since the UNIX epoch")]
+        since: Option<u64>,
+    },
+}
+
+impl ExportFormat {
+    fn from_path(path: &std::path::Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "csv" => Some(ExportFormat::Csv),
+            "parquet" | "pq" => Some(ExportFormat::Parquet),
+            _ => None,
+        }
+    }
+}
+
+// Works offline on dispatcher protocol frames, e.g. to read a packet capture or build a fixture.
+#[derive(Subcommand)]
+enum MessageCommand {
+    #[command(about = "Print the JSON form of a hex encoded frame, length header included")]
+    Decode {
+        frame: String,
+    },
+    #[command(about = "Print the hex encoded frame of a JSON message")]
+    Encode {
+        json: String,
+    },
+}
+
+fn run_message(command: MessageCommand) -> Result<String, Box<dyn Error>> {
+    match command {
+        MessageCommand::Decode { frame } => {
+            let (message, _) = Message::decode(&parse_hex(&frame)?)?;
+            Ok(message.encode_json()?)
+        }
+        MessageCommand::Encode { json } => {
+            let frame = Message::decode_json(&json)?.encode()?;
+            Ok(frame.iter().map(|b| format!("{:02x}", b)).collect())
+        }
+    }
+}
+
+struct Table {
+    headers: &'static [&'static str],
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn render(&self, json: bool) -> String {
+        if json {
+            let rows = self
+                .rows
+                .iter()
+                .map(|row| {
+                    let object = self
+                        .headers
+                        .iter()
+                        .zip(row)
+                        .map(|(header, cell)| (header.to_string(), Json::String(cell.clone())))
+                        .collect::<Map<_, _>>();
+                    Json::Object(object)
+                })
+                .collect();
+            return serde_json::to_string_pretty(&Json::Array(rows)).unwrap();
+        }
+
+        let widths = self
+            .headers
+            .iter()
+            .enumerate()
+            .map(|(i, header)| {
+                self.rows
+                    .iter()
+                    .map(|row| row[i].len())
+                    .fold(header.len(), usize::max)
+            })
+            .collect::<Vec<_>>();
+
+        let line = |cells: Vec<&str>| {
+            cells
+                .iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        };
+
+        let mut lines = vec![line(self.headers.to_vec())];
+        lines.extend(self.rows.iter().map(|row| line(row.iter().map(String::as_str).collect())));
+        lines.join("\n")
+    }
+}
+
+fn parse_param(param: &str) -> Result<pb::Value, String> {
+    let (ty, value) = param.split_once(':').unwrap_or((param, ""));
+    let error = |e: &dyn std::fmt::Display| format!("invalid {} parameter {:?}: {}", ty, value, e);
+
+    let kind = match ty {
+        "void" => Kind::Void(true),
+        "i32" => Kind::I32(value.parse().map_err(|e| error(&e))?),
+        "i64" => Kind::I64(value.parse().map_err(|e| error(&e))?),
+        "f32" => Kind::F32(value.parse().map_err(|e| error(&e))?),
+        "f64" => Kind::F64(value.parse().map_err(|e| error(&e))?),
+        "v128" => Kind::V128(value.parse::<i128>().map_err(|e| error(&e))?.to_be_bytes().to_vec()),
+        "bytes" => Kind::Bytes(parse_hex(value).map_err(|e| error(&e))?),
+        // The server fills in size and hash from its blob store.
+        "blob" => Kind::BlobRef(pb::BlobRef { id: value.parse().map_err(|e| error(&e))?, size: 0, hash: 0 }),
+        _ => {
+            return Err(format!(
+                "unknown parameter type {:?}, expected void, i32, i64, f32, f64, v128, bytes or blob",
+                ty
+            ))
+        }
+    };
+    Ok(pb::Value { kind: Some(kind) })
+}
+
+fn parse_env(var: &str) -> Result<pb::EnvVar, String> {
+    match var.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok(pb::EnvVar { key: key.into(), value: value.into() }),
+        _ => Err(format!("invalid environment variable {:?}, expected KEY=VALUE", var)),
+    }
+}
+
+fn parse_hex(value: &str) -> Result<Vec<u8>, String> {
+    if !value.len().is_multiple_of(2) {
+        return Err("odd number of hex digits".into());
+    }
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+fn format_value(value: &pb::Value) -> String {
+    match &value.kind {
+        Some(Kind::Void(_)) => "void".into(),
+        Some(Kind::I32(v)) => format!("i32:{}", v),
+        Some(Kind::I64(v)) => format!("i64:{}", v),
+        Some(Kind::F32(v)) => format!("f32:{}", v),
+        Some(Kind::F64(v)) => format!("f64:{}", v),
+        Some(Kind::V128(v)) => match <[u8; 16]>::try_from(v.as_slice()) {
+            Ok(bytes) => format!("v128:{}", i128::from_be_bytes(bytes)),
+            Err(_) => "v128:?".into(),
+        },
+        // Buffers such as pixel data are summarized, use --json for scripting.
+        Some(Kind::Bytes(v)) if v.len() > 16 => format!("bytes:<{} bytes>", v.len()),
+        Some(Kind::Bytes(v)) => format!("bytes:{}", v.iter().map(|b| format!("{:02x}", b)).collect::<String>()),
+        Some(Kind::BlobRef(blob)) => format!("blob:{}", blob.id),
+        None => "-".into(),
+    }
+}
+
+fn task_table(tasks: Vec<pb::TaskReply>) -> Table {
+    Table {
+        headers: &["id", "name", "module", "phase", "result"],
+        rows: tasks
+            .into_iter()
+            .map(|task| {
+                vec![
+                    task.task_id.to_string(),
+                    task.name.clone(),
+                    task.module.clone(),
+                    task.phase().as_str_name().to_lowercase(),
+                    task.result.iter().map(format_value).collect::<Vec<_>>().join(" "),
+                ]
+            })
+            .collect(),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    if let Command::Message(command) = cli.command {
+        println!("{}", run_message(command)?);
+        return Ok(());
+    }
+    let mut client = ControlClient::connect(cli.endpoint).await?;
+
+    let table = match cli.command {
+        Command::Tasks(TasksCommand::List) => {
+            task_table(client.list_tasks(pb::ListTasksRequest {}).await?.into_inner().tasks)
+        }
+        Command::Tasks(TasksCommand::Get { task_id }) => {
+            task_table(vec![client.get_task(pb::GetTaskRequest { task_id }).await?.into_inner()])
+        }
+        Command::Tasks(TasksCommand::Submit(task)) => {
+            task_table(vec![client.submit_task(task.into_request()?).await?.into_inner()])
+        }
+        Command::Tasks(TasksCommand::Schedule { task, name, every, cron }) => {
+            let request = pb::ScheduleTaskRequest {
+                task: Some(task.into_request()?),
+                name: name.unwrap_or_default(),
+                interval_secs: every.unwrap_or_default(),
+                cron: cron.unwrap_or_default(),
+            };
+            let scheduled = client.schedule_task(request).await?.into_inner();
+            Table {
+                headers: &["name", "next_run_secs"],
+                rows: vec![vec![scheduled.name, scheduled.next_run_secs.to_string()]],
+            }
+        }
+        Command::Sessions(SessionsCommand::List) => {
+            let sessions = client.list_sessions(pb::ListSessionsRequest {}).await?.into_inner().sessions;
+            Table {
+                headers: &["id", "addr", "status", "ram", "latency_ms", "protocol_errors", "labels"],
+                rows: sessions
+                    .into_iter()
+                    .map(|session| {
+                        vec![
+                            session.id.to_string(),
+                            session.addr,
+                            session.status,
+                            session.ram.to_string(),
+                            session.latency_ms.to_string(),
+                            session.protocol_errors.to_string(),
+                            session.labels.join(","),
+                        ]
+                    })
+                    .collect(),
+            }
+        }
+        Command::Modules(ModulesCommand::Upload { path, name, chunk_size, native, libraries }) => {
+            let name = match name {
+                Some(name) => name,
+                None => path
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .ok_or("cannot derive module name from path, pass --name")?
+                    .to_string(),
+            };
+            let binary = tokio::fs::read(&path).await?;
+            let request = pb::UploadModuleRequest {
+                name,
+                binary,
+                chunk_size,
+                native,
+                libraries,
+            };
+            let module = client.upload_module(request).await?.into_inner();
+            Table {
+                headers: &["name", "size", "replaced"],
+                rows: vec![vec![module.name, module.size.to_string(), module.replaced.to_string()]],
+            }
+        }
+        Command::Modules(ModulesCommand::Prefetch { name }) => {
+            let prefetch = client.prefetch_module(pb::PrefetchModuleRequest { name }).await?.into_inner();
+            Table {
+                headers: &["name", "devices"],
+                rows: vec![vec![prefetch.name, prefetch.devices.to_string()]],
+            }
+        }
+        Command::Modules(ModulesCommand::Pin { name }) => {
+            let pin = client.pin_module(pb::PinModuleRequest { name, pinned: true }).await?.into_inner();
+            Table {
+                headers: &["name", "pinned"],
+                rows: vec![vec![pin.name, pin.pinned.to_string()]],
+            }
+        }
+        Command::Modules(ModulesCommand::Unpin { name }) => {
+            let pin = client.pin_module(pb::PinModuleRequest { name, pinned: false }).await?.into_inner();
+            Table {
+                headers: &["name", "pinned"],
+                rows: vec![vec![pin.name, pin.pinned.to_string()]],
+            }
+        }
+        Command::Blobs(BlobsCommand::Upload { path, chunk_size }) => {
+            let request = pb::UploadBlobRequest {
+                data: tokio::fs::read(&path).await?,
+                chunk_size,
+            };
+            let reply = client.upload_blob(request).await?.into_inner();
+            let blob = reply.blob.unwrap_or_default();
+            Table {
+                headers: &["param", "size", "hash", "existing"],
+                rows: vec![vec![
+                    format!("blob:{}", blob.id),
+                    blob.size.to_string(),
+                    format!("{:08x}", blob.hash),
+                    reply.existing.to_string(),
+                ]],
+            }
+        }
+        Command::Firmware(FirmwareCommand::Update { path, version, arch, chunk_size }) => {
+            let request = pb::UpdateFirmwareRequest {
+                version,
+                image: tokio::fs::read(&path).await?,
+                chunk_size,
+                arch,
+            };
+            let update = client.update_firmware(request).await?.into_inner();
+            Table {
+                headers: &["version", "devices"],
+                rows: vec![vec![update.version, update.devices.to_string()]],
+            }
+        }
+        Command::Results(ResultsCommand::Export { output, format, module, since }) => {
+            let format = format
+                .or_else(|| ExportFormat::from_path(&output))
+                .ok_or("cannot derive format from the output file, pass --format")?;
+            let request = pb::ExportResultsRequest {
+                format: match format {
+                    ExportFormat::Csv => pb::ExportFormat::Csv,
+                    ExportFormat::Parquet => pb::ExportFormat::Parquet,
+                } as i32,
+                module: module.unwrap_or_default(),
+                since_secs: since.unwrap_or_default(),
+            };
+            let export = client.export_results(request).await?.into_inner();
+            tokio::fs::write(&output, &export.file).await?;
+            Table {
+                headers: &["path", "rows", "bytes"],
+                rows: vec![vec![output.display().to_string(), export.rows.to_string(), export.file.len().to_string()]],
+            }
+        }
+        Command::Message(_) => unreachable!("handled before connecting"),
+    };
+
+    println!("{}", table.render(cli.json));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_param() {
+        assert_eq!(parse_param("i32:800").unwrap().kind, Some(Kind::I32(800)));
+        assert_eq!(parse_param("f64:-0.5").unwrap().kind, Some(Kind::F64(-0.5)));
+        assert_eq!(parse_param("void").unwrap().kind, Some(Kind::Void(true)));
+        assert_eq!(format_value(&parse_param("v128:-2").unwrap()), "v128:-2");
+        assert_eq!(parse_param("bytes:00ff").unwrap().kind, Some(Kind::Bytes(vec![0x00, 0xff])));
+        assert_eq!(format_value(&parse_param("bytes:0a0b").unwrap()), "bytes:0a0b");
+        assert_eq!(format_value(&parse_param("blob:42").unwrap()), "blob:42");
+        assert!(parse_param("blob:").is_err());
+        assert!(parse_param("bytes:abc").is_err());
+        assert!(parse_param("i32:abc").is_err());
+        assert!(parse_param("u8:1").is_err());
+    }
+
+    #[test]
+    fn test_parse_env() {
+        assert_eq!(parse_env("MODE=fast").unwrap(), pb::EnvVar { key: "MODE".into(), value: "fast".into() });
+        assert_eq!(parse_env("EXPR=a=b").unwrap().value, "a=b");
+        assert_eq!(parse_env("EMPTY=").unwrap().value, "");
+        assert!(parse_env("MODE").is_err());
+        assert!(parse_env("=fast").is_err());
+    }
+
+    #[test]
+    fn test_render_table() {
+        let table = Table {
+            headers: &["id", "name"],
+            rows: vec![vec!["1".into(), "fractal_0_100".into()]],
+        };
+        assert_eq!(table.render(false), "id  name\n1   fractal_0_100");
+
+        let json = serde_json::from_str::<Json>(&table.render(true)).unwrap();
+        assert_eq!(json[0]["name"], "fractal_0_100");
+    }
+
+    #[test]
+    fn test_export_format() {
+        assert!(matches!(ExportFormat::from_path("out/results.csv".as_ref()), Some(ExportFormat::Csv)));
+        assert!(matches!(ExportFormat::from_path("results.parquet".as_ref()), Some(ExportFormat::Parquet)));
+        assert!(ExportFormat::from_path("results".as_ref()).is_none());
+    }
+
+    #[test]
+    fn test_message_command() {
+        let json = r#"{"ServerCancel":{"task_id":7}}"#;
+        let frame = run_message(MessageCommand::Encode { json: json.into() }).unwrap();
+        assert_eq!(frame, "00020d07");
+        assert_eq!(run_message(MessageCommand::Decode { frame }).unwrap(), json);
+        assert!(run_message(MessageCommand::Decode { frame: "0004".into() }).is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use program::{Buf, BufMut, Clock, Executor, Session, Transport, Type};
+use serde_json::Value as Json;
+use tokio::task::JoinHandle;
+use tonic::transport::Channel;
+
+#[allow(clippy::all)]
+pub mod pb {
+    tonic::include_proto!("control");
+}
+
+pub use pb::control_client::ControlClient;
+
+const HOST: &str = "127.0.0.1";
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+const DEVICE_RAM: u64 = 64 * 1024;
+
+// Binds port 0 and releases it again, the kernel will not hand it out twice in a row.
+pub fn free_port() -> u16 {
+    TcpListener::bind((HOST, 0)).unwrap().local_addr().unwrap().port()
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn timestamp(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+pub struct TcpTransport {
+    stream: TcpStream,
+}
+
+impl TcpTransport {
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Transport for TcpTransport {
+    type Error = io::Error;
+
+    fn read<B>(&mut self, buf: &mut B) -> Result<usize, Self::Error>
+    where
+        B: BufMut + ?Sized,
+    {
+        let mut buffer = [0u8; 2048];
+        let bytes_read = match self.stream.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(e),
+        };
+        buf.put_slice(&buffer[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    fn write<B>(&mut self, src: &mut B) -> Result<usize, Self::Error>
+    where
+        B: Buf,
+    {
+        match self.stream.write(src.chunk()) {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+// Stands in for a wasm runtime, the first byte of a module selects how i32 params are folded.
+pub struct ArithmeticExecutor;
+
+impl ArithmeticExecutor {
+    pub const ADD: u8 = b'+';
+    pub const MUL: u8 = b'*';
+}
+
+impl Executor for ArithmeticExecutor {
+    type Error = io::Error;
+
+    fn execute(&self, module: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        let values = params.iter().filter_map(|param| match param {
+            Type::I32(value) => Some(*value),
+            _ => None,
+        });
+        let result = match module.first() {
+            Some(&Self::ADD) => values.sum(),
+            Some(&Self::MUL) => values.product(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown module")),
+        };
+        Ok(vec![Type::I32(result)])
+    }
+}
+
+pub struct TestCluster {
+    pub inspector_addr: String,
+    pub dispatcher_addr: String,
+    pub control_addr: String,
+    server: JoinHandle<()>,
+}
+
+impl TestCluster {
+    // Starts a `server::Server` with the port order of the server binary and waits until the
+    // inspector reports the dispatcher listening where it was asked to.
+    pub async fn start() -> Self {
+        let ports = [free_port(), free_port(), free_port(), free_port()];
+        let addrs = server::ListenAddrs::resolve(HOST, &ports).unwrap();
+        // Workloads only see the tasks they submit themselves.
+        let mut server = server::Server::builder().listen(addrs).bundled_tasks(false).build().unwrap();
+        let server = tokio::spawn(async move {
+            server.start();
+            server.wait().await.expect("server failed")
+        });
+
+        let cluster = Self {
+            inspector_addr: format!("{}:{}", HOST, ports[0]),
+            dispatcher_addr: format!("{}:{}", HOST, ports[1]),
+            control_addr: format!("{}:{}", HOST, ports[3]),
+            server,
+        };
+
+        let started = Instant::now();
+        loop {
+            if let Ok((200, ready)) = cluster.inspect("/readyz").await {
+                assert_eq!(ready["listening"], cluster.dispatcher_addr.as_str(), "dispatcher bound elsewhere");
+                break;
+            }
+            assert!(started.elapsed() < STARTUP_TIMEOUT, "server did not become ready");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        cluster
+    }
+
+    pub async fn control(&self) -> ControlClient<Channel> {
+        let started = Instant::now();
+        loop {
+            match ControlClient::connect(format!("http://{}", self.control_addr)).await {
+                Ok(client) => return client,
+                Err(e) if started.elapsed() > STARTUP_TIMEOUT => panic!("control API unreachable: {}", e),
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+    }
+
+    // Minimal HTTP/1.0 GET against the inspector, returning the status and JSON body.
+    pub async fn inspect(&self, path: &str) -> io::Result<(u16, Json)> {
+        let addr = self.inspector_addr.clone();
+        let path = path.to_string();
+        tokio::task::spawn_blocking(move || {
+            let mut stream = TcpStream::connect(&addr)?;
+            write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, addr)?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed response");
+            let status = response.split_whitespace().nth(1).and_then(|s| s.parse().ok()).ok_or_else(invalid)?;
+            let (_, body) = response.split_once("\r\n\r\n").ok_or_else(invalid)?;
+            Ok((status, serde_json::from_str(body).map_err(|_| invalid())?))
+        })
+        .await?
+    }
+
+    // Runs a program session on its own thread, as the std sample does on a desktop worker.
+    pub fn spawn_device(&self) {
+        let transport = TcpTransport::connect(&self.dispatcher_addr).unwrap();
+        thread::spawn(move || {
+            let mut session = Session::new(transport, ArithmeticExecutor, SystemClock, DEVICE_RAM);
+            session.run().ok();
+        });
+    }
+}
+
+impl Drop for TestCluster {
+    fn drop(&mut self) {
+        self.server.abort();
+    }
+}
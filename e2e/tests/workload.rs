@@ -0,0 +1,76 @@
+use std::time::{Duration, Instant};
+
+use e2e::{pb, ArithmeticExecutor, TestCluster};
+
+const DEVICES: usize = 3;
+const TASKS_PER_MODULE: i32 = 4;
+const WORKLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+fn i32_value(value: i32) -> pb::Value {
+    pb::Value {
+        kind: Some(pb::value::Kind::I32(value)),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_multi_module_workload() {
+    let cluster = TestCluster::start().await;
+    let mut control = cluster.control().await;
+
+    // The adder spans several chunks so the transfer path is exercised as well.
+    for (name, op, size) in [("adder", ArithmeticExecutor::ADD, 2500), ("multiplier", ArithmeticExecutor::MUL, 600)] {
+        control
+            .upload_module(pb::UploadModuleRequest {
+                name: name.into(),
+                binary: vec![op; size],
+                chunk_size: 1024,
+                native: false,
+                libraries: vec![],
+            })
+            .await
+            .unwrap();
+    }
+
+    let mut expected = Vec::new();
+    for i in 0..TASKS_PER_MODULE {
+        for (module, result) in [("adder", i + 3), ("multiplier", i * 3)] {
+            let task = control
+                .submit_task(pb::SubmitTaskRequest {
+                    module: module.into(),
+                    params: vec![i32_value(i), i32_value(3)],
+                    ..Default::default()
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            expected.push((task.task_id, vec![i32_value(result)]));
+        }
+    }
+
+    for _ in 0..DEVICES {
+        cluster.spawn_device();
+    }
+
+    let started = Instant::now();
+    loop {
+        let tasks = control
+            .list_tasks(pb::ListTasksRequest {})
+            .await
+            .unwrap()
+            .into_inner()
+            .tasks;
+        if tasks.iter().all(|task| task.phase() == pb::TaskPhase::Completed) {
+            let mut results = tasks.into_iter().map(|task| (task.task_id, task.result)).collect::<Vec<_>>();
+            results.sort_by_key(|(task_id, _)| *task_id);
+            expected.sort_by_key(|(task_id, _)| *task_id);
+            assert_eq!(results, expected);
+            break;
+        }
+        assert!(started.elapsed() < WORKLOAD_TIMEOUT, "workload did not complete: {:?}", tasks);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    let (status, ready) = cluster.inspect("/readyz").await.unwrap();
+    assert_eq!(status, 200);
+    assert_eq!(ready["sessions"], DEVICES);
+}
@@ -0,0 +1,9 @@
+// Dashboard data layer for the server inspector. The model follows `/api/diff/ws` with the
+// reactive crate's signals, the wasm entry point renders it into the page served from
+// `server/assets`, built with `wasm-pack build inspector --target web --out-dir ../server/assets/pkg`.
+
+mod model;
+#[cfg(target_arch = "wasm32")]
+mod web;
+
+pub use model::*;
@@ -0,0 +1,353 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+
+use protocol::Type;
+use reactive::{transaction, StateHandle};
+use serde::Deserialize;
+
+// Mirrors of the views the server inspector serializes for `/api/diff` and `/api/diff/ws`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TaskView {
+    pub entity: u64,
+    pub task_id: Option<u64>,
+    pub name: String,
+    pub module: u64,
+    pub priority: u8,
+    pub kind: String,
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub group: Option<u64>,
+    pub params: Vec<Type>,
+    pub result: Vec<Type>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TaskStateView {
+    pub entity: u64,
+    pub phase: String,
+    pub assigned_device: Option<u64>,
+    pub results: HashMap<u64, Vec<Type>>,
+    #[serde(default)]
+    pub progress: Option<ProgressView>,
+    #[serde(default)]
+    pub transfer: Option<TransferView>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct ProgressView {
+    pub percent: u8,
+    pub stage: String,
+    pub age_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TransferView {
+    pub chunks_acked: u32,
+    pub total_chunks: u32,
+    pub bytes_acked: u64,
+    pub bytes_per_sec: f64,
+    pub stalled_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TaskGroupView {
+    pub entity: u64,
+    pub name: String,
+    pub status: String,
+    pub total: u32,
+    pub completed: u32,
+    pub failed: u32,
+    pub percent: u8,
+    pub created_at: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ComponentDiff<V> {
+    pub added: Vec<V>,
+    pub changed: Vec<V>,
+    pub removed: Vec<u64>,
+}
+
+impl<V> Default for ComponentDiff<V> {
+    fn default() -> Self {
+        Self {
+            added: Vec::new(),
+            changed: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WorldDiff {
+    pub version: usize,
+    pub tasks: ComponentDiff<TaskView>,
+    pub task_states: ComponentDiff<TaskStateView>,
+    #[serde(default)]
+    pub task_groups: ComponentDiff<TaskGroupView>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DiffResponse {
+    pub version: usize,
+    pub resync: bool,
+    pub diffs: Vec<WorldDiff>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskRow {
+    pub entity: u64,
+    pub task_id: Option<u64>,
+    pub name: String,
+    pub group: Option<u64>,
+    pub phase: String,
+    pub device: Option<u64>,
+    pub progress: Option<ProgressView>,
+    pub transfer: Option<TransferView>,
+}
+
+trait Keyed {
+    fn entity(&self) -> u64;
+}
+
+impl Keyed for TaskView {
+    fn entity(&self) -> u64 {
+        self.entity
+    }
+}
+
+impl Keyed for TaskStateView {
+    fn entity(&self) -> u64 {
+        self.entity
+    }
+}
+
+impl Keyed for TaskGroupView {
+    fn entity(&self) -> u64 {
+        self.entity
+    }
+}
+
+fn apply_component<V: Keyed + Clone + 'static>(signal: &StateHandle<BTreeMap<u64, V>>, diff: ComponentDiff<V>, resync: bool) {
+    let mut entries = if resync { BTreeMap::new() } else { (*signal.get()).clone() };
+    for view in diff.added.into_iter().chain(diff.changed) {
+        entries.insert(view.entity(), view);
+    }
+    for entity in diff.removed {
+        entries.remove(&entity);
+    }
+    signal.set(entries);
+}
+
+// Task, task state and task group tables kept in sync with the server, one signal per table.
+pub struct InspectorModel {
+    version: StateHandle<usize>,
+    tasks: StateHandle<BTreeMap<u64, TaskView>>,
+    task_states: StateHandle<BTreeMap<u64, TaskStateView>>,
+    task_groups: StateHandle<BTreeMap<u64, TaskGroupView>>,
+}
+
+impl Default for InspectorModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InspectorModel {
+    pub fn new() -> Self {
+        Self {
+            version: StateHandle::new(0),
+            tasks: StateHandle::new(BTreeMap::new()),
+            task_states: StateHandle::new(BTreeMap::new()),
+            task_groups: StateHandle::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn version(&self) -> StateHandle<usize> {
+        self.version.clone()
+    }
+
+    pub fn tasks(&self) -> StateHandle<BTreeMap<u64, TaskView>> {
+        self.tasks.clone()
+    }
+
+    pub fn task_states(&self) -> StateHandle<BTreeMap<u64, TaskStateView>> {
+        self.task_states.clone()
+    }
+
+    pub fn task_groups(&self) -> StateHandle<BTreeMap<u64, TaskGroupView>> {
+        self.task_groups.clone()
+    }
+
+    // A resync replaces all tables with its snapshot, effects see the whole response at once.
+    pub fn apply(&self, response: DiffResponse) {
+        transaction(|| {
+            let mut resync = response.resync;
+            for diff in response.diffs {
+                apply_component(&self.tasks, diff.tasks, resync);
+                apply_component(&self.task_states, diff.task_states, resync);
+                apply_component(&self.task_groups, diff.task_groups, resync);
+                resync = false;
+            }
+            self.version.set(response.version);
+            Ok::<_, ()>(())
+        })
+        .ok();
+    }
+
+    pub fn apply_json(&self, text: &str) -> Result<(), serde_json::Error> {
+        self.apply(serde_json::from_str(text)?);
+        Ok(())
+    }
+
+    // Tracked, ordered by entity.
+    pub fn rows(&self) -> Vec<TaskRow> {
+        let tasks = self.tasks.get_tracked();
+        let states = self.task_states.get_tracked();
+        tasks
+            .values()
+            .map(|task| {
+                let state = states.get(&task.entity);
+                TaskRow {
+                    entity: task.entity,
+                    task_id: task.task_id,
+                    name: task.name.clone(),
+                    group: task.group,
+                    phase: state.map_or_else(|| "template".into(), |state| state.phase.clone()),
+                    device: state.and_then(|state| state.assigned_device),
+                    progress: state.and_then(|state| state.progress.clone()),
+                    transfer: state.and_then(|state| state.transfer.clone()),
+                }
+            })
+            .collect()
+    }
+
+    // Tracked, number of unfinished tasks held by each session.
+    pub fn sessions(&self) -> BTreeMap<u64, usize> {
+        let mut sessions = BTreeMap::new();
+        for state in self.task_states.get_tracked().values() {
+            if let (Some(device), false) = (state.assigned_device, state.phase == "completed") {
+                *sessions.entry(device).or_insert(0) += 1;
+            }
+        }
+        sessions
+    }
+
+    // Plain text tables, what the dashboard puts on screen. Tasks of a group only show up as
+    // their group's row.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, "version {}", self.version.get_tracked()).ok();
+        writeln!(out, "{:>20} {:<24} {:<12} {:>12}  progress", "group", "name", "status", "tasks").ok();
+        for group in self.task_groups.get_tracked().values() {
+            let tasks = format!("{}/{}", group.completed, group.total);
+            let failed = match group.failed {
+                0 => String::new(),
+                failed => format!(" ({} failed)", failed),
+            };
+            writeln!(out, "{:>20} {:<24} {:<12} {:>12}  {}%{}", group.entity, group.name, group.status, tasks, group.percent, failed).ok();
+        }
+        writeln!(out, "\n{:>20} {:>20} {:<24} {:<12} {:>20}  progress", "entity", "task", "name", "phase", "device").ok();
+        for row in self.rows().into_iter().filter(|row| row.group.is_none()) {
+            let task_id = row.task_id.map_or_else(|| "-".into(), |id| id.to_string());
+            let device = row.device.map_or_else(|| "-".into(), |device| device.to_string());
+            // A task still waiting on its transfer has no progress from the device yet.
+            let transfer = row.transfer.map(|transfer| {
+                format!(
+                    "transfer {}/{} chunks ({:.0} B/s, {}s since ack)",
+                    transfer.chunks_acked, transfer.total_chunks, transfer.bytes_per_sec, transfer.stalled_secs
+                )
+            });
+            let progress = row
+                .progress
+                .map(|progress| format!("{} {}% ({}s ago)", progress.stage, progress.percent, progress.age_secs))
+                .or(transfer)
+                .unwrap_or_else(|| "-".into());
+            writeln!(out, "{:>20} {:>20} {:<24} {:<12} {:>20}  {}", row.entity, task_id, row.name, row.phase, device, progress).ok();
+        }
+        writeln!(out, "\n{:>20} {:>10}", "session", "tasks").ok();
+        for (session, tasks) in self.sessions() {
+            writeln!(out, "{:>20} {:>10}", session, tasks).ok();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use reactive::create_effect;
+
+    use super::*;
+
+    const SNAPSHOT: &str = r#"{"version": 3, "resync": true, "diffs": [{
+        "version": 3,
+        "tasks": {"added": [
+            {"entity": 1, "task_id": 10, "name": "sum", "module": 7, "priority": 1, "kind": "Single",
+             "tenant": null, "params": [{"I32": 1}], "result": []},
+            {"entity": 2, "task_id": 11, "name": "blink", "module": 8, "priority": 1, "kind": "Single",
+             "tenant": "lab", "params": [], "result": []},
+            {"entity": 3, "task_id": 12, "name": "fractal_0_100", "module": 9, "priority": 1, "kind": "Single",
+             "tenant": null, "group": 5, "params": [], "result": []}
+        ], "changed": [], "removed": []},
+        "task_states": {"added": [
+            {"entity": 1, "phase": "executing", "assigned_device": 42, "results": {},
+             "progress": {"percent": 40, "stage": "execute", "age_secs": 2}},
+            {"entity": 2, "phase": "distributing", "assigned_device": 43, "results": {},
+             "transfer": {"chunks_acked": 3, "total_chunks": 8, "bytes_acked": 3072, "bytes_per_sec": 1024.0,
+                          "stalled_secs": 1}}
+        ], "changed": [], "removed": []},
+        "task_groups": {"added": [
+            {"entity": 5, "name": "fractal", "status": "running", "total": 6, "completed": 4, "failed": 0,
+             "percent": 73, "created_at": 1700000000}
+        ], "changed": [], "removed": []}
+    }]}"#;
+
+    const UPDATE: &str = r#"{"version": 4, "resync": false, "diffs": [{
+        "version": 4,
+        "tasks": {"added": [], "changed": [], "removed": [2, 3]},
+        "task_states": {"added": [], "changed": [
+            {"entity": 1, "phase": "completed", "assigned_device": 42, "results": {"42": [{"I32": 3}]}}
+        ], "removed": [2]}
+    }]}"#;
+
+    #[test]
+    fn test_apply_diffs() {
+        let model = InspectorModel::new();
+        let renders = StateHandle::new(0);
+        create_effect({
+            let (version, renders) = (model.version(), renders.clone());
+            let tasks = model.tasks();
+            move || {
+                version.track();
+                tasks.track();
+                renders.set(*renders.get() + 1);
+            }
+        });
+
+        model.apply_json(SNAPSHOT).unwrap();
+        assert_eq!(*renders.get(), 2);
+        assert_eq!(model.rows().len(), 3);
+        assert_eq!(model.sessions(), BTreeMap::from([(42, 1), (43, 1)]));
+        assert!(model.render().contains("execute 40% (2s ago)"));
+        assert!(model.render().contains("transfer 3/8 chunks (1024 B/s, 1s since ack)"));
+        // The sweep shows up as its group alone.
+        assert!(model.render().contains("fractal                  running               4/6  73%"));
+        assert!(!model.render().contains("fractal_0_100"));
+
+        model.apply_json(UPDATE).unwrap();
+        assert_eq!(*model.version().get(), 4);
+        assert_eq!(model.rows(), vec![TaskRow {
+            entity: 1,
+            task_id: Some(10),
+            name: "sum".into(),
+            group: None,
+            phase: "completed".into(),
+            device: Some(42),
+            progress: None,
+            transfer: None,
+        }]);
+        assert!(model.sessions().is_empty());
+        assert_eq!(model.task_states().get()[&1].results[&42], vec![Type::I32(3)]);
+        assert!(model.render().contains("completed"));
+    }
+}
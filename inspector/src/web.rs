@@ -0,0 +1,43 @@
+use std::rc::Rc;
+
+use reactive::create_effect;
+use wasm_bindgen::prelude::*;
+use web_sys::{MessageEvent, WebSocket};
+
+use crate::InspectorModel;
+
+#[wasm_bindgen(start)]
+pub fn start() -> Result<(), JsValue> {
+    let window = web_sys::window().ok_or("no window")?;
+    let document = window.document().ok_or("no document")?;
+    let location = window.location();
+
+    let output = document.create_element("pre")?;
+    document.body().ok_or("no body")?.append_child(&output)?;
+
+    let model = Rc::new(InspectorModel::new());
+    create_effect({
+        let model = Rc::clone(&model);
+        move || output.set_text_content(Some(&model.render()))
+    });
+
+    let scheme = match location.protocol()?.as_str() {
+        "https:" => "wss",
+        _ => "ws",
+    };
+    let socket = WebSocket::new(&format!("{}://{}/api/diff/ws", scheme, location.host()?))?;
+    let on_message = Closure::<dyn FnMut(MessageEvent)>::new(move |event: MessageEvent| {
+        let Some(text) = event.data().as_string() else {
+            return;
+        };
+        if let Err(e) = model.apply_json(&text) {
+            web_sys::console::warn_1(&format!("Malformed diff: {}", e).into());
+        }
+    });
+    socket.set_onmessage(Some(on_message.as_ref().unchecked_ref()));
+
+    // Both live as long as the page does.
+    on_message.forget();
+    std::mem::forget(socket);
+    Ok(())
+}
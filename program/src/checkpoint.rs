@@ -0,0 +1,113 @@
+// Convention for long tasks that resume where they left off when they are reassigned:
+//
+// - A module that can resume exports `checkpoint() -> i64`, returning the region that holds its
+//   state packed as `(ptr << 32) | len`, and `resume(ptr: i32, len: i32)`.
+// - Whenever its state is consistent the module calls the host import `checkpoint.save()`. The
+//   host calls `checkpoint` right away and ships a copy of the region to the server.
+// - A task the server reassigns carries the latest state it received. Before `run` the host
+//   allocates a buffer through `alloc`, copies the state in and calls `resume` with it.
+//
+// Executors implement `Resumable` for their instance, link `save` to `save_checkpoint` and call
+// `resume_checkpoint` ahead of `run`. Modules that do not export `checkpoint` start over.
+
+use alloc::vec::Vec;
+
+use crate::memory::{GuestMemory, MemoryError};
+
+pub const MODULE: &str = "checkpoint";
+pub const SAVE: &str = "save";
+
+pub trait Resumable: GuestMemory {
+    // Calls the module's exported `checkpoint`, `None` when the module does not export one.
+    fn checkpoint(&mut self) -> Result<Option<i64>, Self::Error>;
+
+    // Calls `resume`, `false` when the module does not export it.
+    fn resume(&mut self, ptr: u32, len: u32) -> Result<bool, Self::Error>;
+}
+
+// Copies out the state the module points `checkpoint` at, `None` when it exports none.
+pub fn save_checkpoint<M: Resumable>(memory: &mut M) -> Result<Option<Vec<u8>>, MemoryError<M::Error>> {
+    let Some(region) = memory.checkpoint().map_err(MemoryError::Guest)? else {
+        return Ok(None);
+    };
+    let (ptr, len) = ((region as u64 >> 32) as u32, region as u32);
+    memory.read(ptr, len).map(Some).map_err(MemoryError::Guest)
+}
+
+// Hands `data` to the module's `resume`, `false` when the module cannot take it and starts over.
+pub fn resume_checkpoint<M: Resumable>(memory: &mut M, data: &[u8]) -> Result<bool, MemoryError<M::Error>> {
+    let len = u32::try_from(data.len()).map_err(|_| MemoryError::TooLarge(data.len()))?;
+    let ptr = memory
+        .alloc(len)
+        .map_err(MemoryError::Guest)?
+        .ok_or(MemoryError::MissingAlloc(data.len()))?;
+    memory.write(ptr, data).map_err(MemoryError::Guest)?;
+    memory.resume(ptr, len).map_err(MemoryError::Guest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockInstance {
+        data: Vec<u8>,
+        region: Option<i64>,
+        resumed: Option<(u32, u32)>,
+    }
+
+    impl GuestMemory for MockInstance {
+        type Error = ();
+
+        fn alloc(&mut self, len: u32) -> Result<Option<u32>, ()> {
+            let ptr = self.data.len() as u32;
+            self.data.resize(self.data.len() + len as usize, 0);
+            Ok(Some(ptr))
+        }
+
+        fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), ()> {
+            self.data[ptr as usize..ptr as usize + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self, ptr: u32, len: u32) -> Result<Vec<u8>, ()> {
+            self.data.get(ptr as usize..(ptr + len) as usize).map(<[u8]>::to_vec).ok_or(())
+        }
+
+        fn output(&mut self) -> Result<Option<(u32, u32)>, ()> {
+            Ok(None)
+        }
+    }
+
+    impl Resumable for MockInstance {
+        fn checkpoint(&mut self) -> Result<Option<i64>, ()> {
+            Ok(self.region)
+        }
+
+        fn resume(&mut self, ptr: u32, len: u32) -> Result<bool, ()> {
+            self.resumed = Some((ptr, len));
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_save_and_resume() {
+        let mut instance = MockInstance {
+            data: vec![0, 0, 7, 8, 9],
+            ..Default::default()
+        };
+        assert_eq!(save_checkpoint(&mut instance).unwrap(), None);
+
+        instance.region = Some((2 << 32) | 3);
+        let saved = save_checkpoint(&mut instance).unwrap().unwrap();
+        assert_eq!(saved, vec![7, 8, 9]);
+
+        // The state lands in a fresh buffer, the module is told where.
+        assert!(resume_checkpoint(&mut instance, &saved).unwrap());
+        assert_eq!(instance.resumed, Some((5, 3)));
+        assert_eq!(instance.data[5..], [7, 8, 9]);
+
+        instance.region = Some((4 << 32) | 8);
+        assert!(save_checkpoint(&mut instance).is_err());
+    }
+}
@@ -0,0 +1,67 @@
+// Convention for handing a task's environment to a module, matching WASI preview 1 so a module
+// built for wasm32-wasip1 reads it with `std::env::var`:
+//
+// - `wasi_snapshot_preview1.environ_sizes_get(count_ptr: i32, buf_size_ptr: i32) -> i32` stores
+//   the number of variables and the bytes their strings take.
+// - `wasi_snapshot_preview1.environ_get(environ_ptr: i32, environ_buf_ptr: i32) -> i32` packs
+//   the `KEY=VALUE\0` strings at `environ_buf_ptr` and a table of pointers to them at
+//   `environ_ptr`.
+//
+// Both return 0 on success. Executors link the two imports and write the regions `Environ`
+// lays out, modules that do not import them run unchanged.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+pub const MODULE: &str = "wasi_snapshot_preview1";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Environ {
+    buf: Vec<u8>,
+    offsets: Vec<u32>,
+}
+
+impl Environ {
+    pub fn new(env: &[(String, String)]) -> Self {
+        let mut environ = Self::default();
+        for (key, value) in env {
+            environ.offsets.push(environ.buf.len() as u32);
+            environ.buf.extend_from_slice(key.as_bytes());
+            environ.buf.push(b'=');
+            environ.buf.extend_from_slice(value.as_bytes());
+            environ.buf.push(0);
+        }
+        environ
+    }
+
+    pub fn count(&self) -> u32 {
+        self.offsets.len() as u32
+    }
+
+    // Bytes written at `environ_buf_ptr`.
+    pub fn buf(&self) -> &[u8] {
+        &self.buf
+    }
+
+    // Bytes written at `environ_ptr`, little-endian pointers into the buffer at `buf_ptr`.
+    pub fn pointers(&self, buf_ptr: u32) -> Vec<u8> {
+        self.offsets
+            .iter()
+            .flat_map(|offset| (buf_ptr + offset).to_le_bytes())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_environ() {
+        let environ = Environ::new(&[("MODE".into(), "fast".into()), ("EMPTY".into(), String::new())]);
+        assert_eq!(environ.count(), 2);
+        assert_eq!(environ.buf(), b"MODE=fast\0EMPTY=\0");
+        assert_eq!(environ.pointers(100), [100, 0, 0, 0, 110, 0, 0, 0]);
+        assert_eq!(Environ::new(&[]).count(), 0);
+    }
+}
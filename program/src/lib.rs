@@ -3,16 +3,21 @@
 #[macro_use]
 extern crate alloc;
 
+pub mod checkpoint;
+pub mod env;
+pub mod memory;
 mod session;
 
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::time::Duration;
 
 pub use bytes::{Buf, BufMut};
-pub use protocol::{Config, Type};
+pub use protocol::{CacheStats, Checksum, Config, ExecutionStats, ExecutorFlavor, TaskId, Telemetry, Type, NATIVE_LABEL};
 pub use session::*;
 
 #[derive(Debug, thiserror::Error)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Error {
     #[error("Protocol error: {0}")]
     Protocol(#[from] protocol::Error),
@@ -27,21 +32,158 @@ pub enum Error {
     #[error("Invalid chunk size (expected {0}, got {1})")]
     InvalidChunkSize(usize, usize),
     #[error("Task not found: {0}")]
-    TaskNotFound(u64),
+    TaskNotFound(TaskId),
     #[error("Cache entry not found: {0}")]
     CacheEntryNotFound(String),
-    #[error("Cache full (allocated: {0}/{1})")]
-    CacheFull(usize, usize),
+    #[error("Cache full (allocated: {0}/{1}, pinned: {2})")]
+    CacheFull(usize, usize, usize),
+    #[error("Storage error: {0}")]
+    Storage(String),
+    #[error("Firmware error: {0}")]
+    Firmware(String),
+    #[error("Blob {0} does not match its reference")]
+    BlobMismatch(u64),
+}
+
+// Architecture name as understood by `wamrc --target`, the server uses it to pick AOT artifacts.
+pub fn target_arch() -> &'static str {
+    if cfg!(target_arch = "xtensa") {
+        "xtensa"
+    } else if cfg!(target_arch = "riscv32") {
+        "riscv32"
+    } else if cfg!(target_arch = "x86_64") {
+        "x86_64"
+    } else if cfg!(target_arch = "aarch64") {
+        "aarch64"
+    } else {
+        ""
+    }
 }
 
 pub trait Clock {
+    // Nanoseconds since the UNIX epoch, matching the server's heartbeat latency calculation.
     fn timestamp(&self) -> u64;
+
+    // Blocks the host loop for `duration`. The default spins on `timestamp`, hosts with a timer
+    // or a scheduler should yield instead.
+    fn sleep(&self, duration: Duration) {
+        let until = self.timestamp().saturating_add(duration.as_nanos() as u64);
+        while self.timestamp() < until {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+// Platform readings for telemetry, the defaults suit hosts that cannot take them.
+pub trait TelemetryProbe {
+    fn free_heap(&self) -> Option<u64> {
+        None
+    }
+
+    // WiFi signal strength in dBm.
+    fn rssi(&self) -> Option<i8> {
+        None
+    }
+}
+
+pub trait PowerManager {
+    // Sleeps for at most `duration`, waking early is fine when the transport has data.
+    fn light_sleep(&mut self, duration: Duration);
+
+    // Powers down until `until`, a `Clock` timestamp. Targets that reset on wake never return.
+    fn deep_sleep(&mut self, until: u64);
+}
+
+// Fed at the start of every `Session::step`. A transport or executor call that never returns stops
+// the feeding, so the watchdog resets the device instead of leaving it hung. Its timeout has to
+// outlast the longest sleep between steps, the heartbeat interval at most.
+pub trait Watchdog {
+    fn feed(&mut self);
+}
+
+pub trait CacheStore {
+    fn keys(&self) -> Result<Vec<String>, Error>;
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), Error>;
+
+    fn remove(&mut self, key: &str) -> Result<(), Error>;
+}
+
+pub trait FirmwareSink {
+    // Prepares the inactive slot for an image of `size` bytes.
+    fn begin(&mut self, version: &str, size: usize) -> Result<(), Error>;
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error>;
+
+    // Compares the written image with `protocol::Checksum` and marks it bootable.
+    fn finish(&mut self, checksum: u32) -> Result<(), Error>;
+
+    // Called once the verified image has been acknowledged to the server.
+    fn reboot(&mut self);
 }
 
 pub trait Executor {
     type Error: core::error::Error;
 
     fn execute(&self, module: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error>;
+
+    // Executors that can observe memory or instruction usage override this, the session
+    // fills in wall time when it is left at zero.
+    fn execute_with_stats(&self, module: &[u8], params: Vec<Type>) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.execute(module, params).map(|result| (result, ExecutionStats::default()))
+    }
+
+    // Executors that can pass the task's environment to the module, see `env`, override this, the
+    // rest ignore it.
+    fn execute_with_env(
+        &self,
+        module: &[u8],
+        params: Vec<Type>,
+        _env: &[(String, String)],
+    ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.execute_with_stats(module, params)
+    }
+
+    // `libraries` are the task's other modules by name, executors that link override this and
+    // instantiate them first so the module's imports from those names resolve to their exports.
+    // The rest ignore them, a module importing one then fails to load.
+    fn execute_linked(
+        &self,
+        module: &[u8],
+        _libraries: &[(String, Vec<u8>)],
+        params: Vec<Type>,
+        env: &[(String, String)],
+    ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.execute_with_env(module, params, env)
+    }
+
+    // What the session calls. Executors that follow `checkpoint` override this, they resume the
+    // module from `checkpoint` when the task carries one and hand every state it saves to `save`.
+    // The rest start the module over and never save.
+    fn execute_resumable(
+        &self,
+        module: &[u8],
+        libraries: &[(String, Vec<u8>)],
+        params: Vec<Type>,
+        env: &[(String, String)],
+        _checkpoint: Option<&[u8]>,
+        _save: &mut dyn FnMut(Vec<u8>),
+    ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.execute_linked(module, libraries, params, env)
+    }
+
+    // Advertised in ClientReady so the scheduler can account for slower runtimes.
+    fn flavor(&self) -> ExecutorFlavor {
+        ExecutorFlavor::Interpreter
+    }
+
+    // Executors that also load native cdylib modules override this, the session then advertises
+    // `NATIVE_LABEL` next to its own labels.
+    fn native(&self) -> bool {
+        false
+    }
 }
 
 pub trait Transport {
@@ -0,0 +1,126 @@
+// Convention for passing buffers through a module's exported linear memory:
+//
+// - A module that accepts `Type::Bytes` params exports `alloc(len: i32) -> i32`. The host
+//   allocates each buffer through it, copies the bytes in and passes `(ptr: i32, len: i32)`
+//   in place of the param.
+// - A module that produces a buffer exports `output_ptr() -> i32` and `output_len() -> i32`.
+//   After `run` returns the host reads that region and appends it as a `Type::Bytes` result.
+//
+// Executors implement `GuestMemory` for their instance and wrap `run` with `lower_params`
+// and `lift_results`.
+
+use alloc::vec::Vec;
+
+use protocol::Type;
+
+pub trait GuestMemory {
+    type Error;
+
+    // Calls the module's exported `alloc`, `None` when the module does not export one.
+    fn alloc(&mut self, len: u32) -> Result<Option<u32>, Self::Error>;
+
+    fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), Self::Error>;
+
+    fn read(&mut self, ptr: u32, len: u32) -> Result<Vec<u8>, Self::Error>;
+
+    // Calls `output_ptr` and `output_len`, `None` when the module does not export them.
+    fn output(&mut self) -> Result<Option<(u32, u32)>, Self::Error>;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum MemoryError<E> {
+    #[error("Module does not export `alloc` but received a {0} byte buffer")]
+    MissingAlloc(usize),
+    #[error("Buffer of {0} bytes does not fit in 32-bit linear memory")]
+    TooLarge(usize),
+    #[error("Guest memory error: {0:?}")]
+    Guest(E),
+}
+
+pub fn lower_params<M: GuestMemory>(memory: &mut M, params: Vec<Type>) -> Result<Vec<Type>, MemoryError<M::Error>> {
+    let mut lowered = Vec::with_capacity(params.len());
+    for param in params {
+        match param {
+            Type::Bytes(data) => {
+                let len = u32::try_from(data.len()).map_err(|_| MemoryError::TooLarge(data.len()))?;
+                let ptr = memory
+                    .alloc(len)
+                    .map_err(MemoryError::Guest)?
+                    .ok_or(MemoryError::MissingAlloc(data.len()))?;
+                memory.write(ptr, &data).map_err(MemoryError::Guest)?;
+                lowered.push(Type::I32(ptr as i32));
+                lowered.push(Type::I32(len as i32));
+            }
+            param => lowered.push(param),
+        }
+    }
+    Ok(lowered)
+}
+
+pub fn lift_results<M: GuestMemory>(memory: &mut M, mut results: Vec<Type>) -> Result<Vec<Type>, MemoryError<M::Error>> {
+    if let Some((ptr, len)) = memory.output().map_err(MemoryError::Guest)? {
+        results.push(Type::Bytes(memory.read(ptr, len).map_err(MemoryError::Guest)?));
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct MockMemory {
+        data: Vec<u8>,
+        exports_alloc: bool,
+        output: Option<(u32, u32)>,
+    }
+
+    impl GuestMemory for MockMemory {
+        type Error = ();
+
+        fn alloc(&mut self, len: u32) -> Result<Option<u32>, ()> {
+            if !self.exports_alloc {
+                return Ok(None);
+            }
+            let ptr = self.data.len() as u32;
+            self.data.resize(self.data.len() + len as usize, 0);
+            Ok(Some(ptr))
+        }
+
+        fn write(&mut self, ptr: u32, data: &[u8]) -> Result<(), ()> {
+            self.data[ptr as usize..ptr as usize + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn read(&mut self, ptr: u32, len: u32) -> Result<Vec<u8>, ()> {
+            self.data.get(ptr as usize..(ptr + len) as usize).map(<[u8]>::to_vec).ok_or(())
+        }
+
+        fn output(&mut self) -> Result<Option<(u32, u32)>, ()> {
+            Ok(self.output)
+        }
+    }
+
+    #[test]
+    fn test_lower_and_lift() {
+        let mut memory = MockMemory {
+            data: vec![0; 8],
+            exports_alloc: true,
+            output: None,
+        };
+
+        let params = vec![Type::I32(7), Type::Bytes(vec![1, 2, 3])];
+        let lowered = lower_params(&mut memory, params).unwrap();
+        assert_eq!(lowered, vec![Type::I32(7), Type::I32(8), Type::I32(3)]);
+        assert_eq!(memory.data[8..], [1, 2, 3]);
+
+        assert_eq!(lift_results(&mut memory, vec![Type::I32(1)]).unwrap(), vec![Type::I32(1)]);
+        memory.output = Some((9, 2));
+        let results = lift_results(&mut memory, vec![Type::I32(1)]).unwrap();
+        assert_eq!(results, vec![Type::I32(1), Type::Bytes(vec![2, 3])]);
+
+        memory.exports_alloc = false;
+        let missing = lower_params(&mut memory, vec![Type::Bytes(vec![0; 4])]);
+        assert!(matches!(missing, Err(MemoryError::MissingAlloc(4))));
+    }
+}
@@ -1,63 +1,240 @@
+use alloc::boxed::Box;
 use alloc::collections::BTreeMap;
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
+use core::cmp::Ordering;
 
-use crate::Error;
+use log::warn;
+use protocol::CacheStats;
+
+use crate::{CacheStore, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryMeta {
+    pub size: usize,
+    pub access: usize,
+    pub last_access: u64,
+}
+
+pub trait EvictionPolicy {
+    // Entries ordered `Less` are evicted first.
+    fn compare(&self, a: &EntryMeta, b: &EntryMeta) -> Ordering;
+}
+
+pub struct LruPolicy;
+
+impl EvictionPolicy for LruPolicy {
+    fn compare(&self, a: &EntryMeta, b: &EntryMeta) -> Ordering {
+        a.last_access.cmp(&b.last_access)
+    }
+}
+
+pub struct LfuPolicy;
+
+impl EvictionPolicy for LfuPolicy {
+    fn compare(&self, a: &EntryMeta, b: &EntryMeta) -> Ordering {
+        a.access
+            .cmp(&b.access)
+            .then_with(|| a.last_access.cmp(&b.last_access))
+    }
+}
+
+pub struct SizeWeightedPolicy;
+
+impl EvictionPolicy for SizeWeightedPolicy {
+    fn compare(&self, a: &EntryMeta, b: &EntryMeta) -> Ordering {
+        let a_score = a.access.pow(2) * b.size;
+        let b_score = b.access.pow(2) * a.size;
+        a_score.cmp(&b_score)
+    }
+}
 
 pub struct ModuleCache {
     entries: BTreeMap<String, CacheEntry>,
+    policy: Box<dyn EvictionPolicy>,
     capacity: usize,
     allocated: usize,
+    tick: u64,
+    stats: CacheStats,
+    store: Option<Box<dyn CacheStore>>,
+    // Keys evicted since the last `take_evicted`, the server still counts them as cached.
+    evicted: Vec<String>,
 }
 
 struct CacheEntry {
     data: Vec<u8>,
     access: usize,
+    last_access: u64,
+    pinned: bool,
+}
+
+impl CacheEntry {
+    fn meta(&self) -> EntryMeta {
+        EntryMeta {
+            size: self.data.len(),
+            access: self.access,
+            last_access: self.last_access,
+        }
+    }
 }
 
 impl ModuleCache {
     pub fn new(capacity: usize) -> Self {
+        Self::with_policy(capacity, SizeWeightedPolicy)
+    }
+
+    pub fn with_policy(capacity: usize, policy: impl EvictionPolicy + 'static) -> Self {
         Self {
             entries: BTreeMap::new(),
+            policy: Box::new(policy),
             capacity,
             allocated: 0,
+            tick: 0,
+            stats: CacheStats::default(),
+            store: None,
+            evicted: Vec::new(),
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: impl EvictionPolicy + 'static) {
+        self.policy = Box::new(policy);
+    }
+
+    // Entries that cannot be restored, unreadable or larger than the whole cache, are dropped from
+    // the store rather than failing the session on every start.
+    pub fn attach_store(&mut self, mut store: impl CacheStore + 'static) -> Result<usize, Error> {
+        let mut restored = 0;
+        for key in store.keys()? {
+            let result = store.load(&key).and_then(|data| match data {
+                Some(data) => self.put(&key, data.len()).map(|_| Some(data)),
+                None => Ok(None),
+            });
+            match result {
+                Ok(Some(data)) => {
+                    self.put_slice(&key, 0, &data)?;
+                    restored += 1;
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Dropping stored module {}: {}", key, e);
+                    if let Err(e) = store.remove(&key) {
+                        warn!("Failed to remove stored module {}: {}", key, e);
+                    }
+                }
+            }
         }
+        self.store = Some(Box::new(store));
+        Ok(restored)
+    }
+
+    // Loads `key` from the store when only the store holds it, e.g. because another session
+    // sharing the store received it. Returns whether the key is cached afterwards.
+    pub fn restore(&mut self, key: &str) -> Result<bool, Error> {
+        if self.entries.contains_key(key) {
+            return Ok(true);
+        }
+        let Some(data) = self.store.as_ref().map(|store| store.load(key)).transpose()?.flatten() else {
+            return Ok(false);
+        };
+        self.put(key, data.len())?;
+        self.put_slice(key, 0, &data)?;
+        Ok(true)
+    }
+
+    pub fn persist(&mut self, key: &str) -> Result<(), Error> {
+        if let Some(store) = self.store.as_mut() {
+            let entry = self
+                .entries
+                .get(key)
+                .ok_or(Error::CacheEntryNotFound(key.to_string()))?;
+            store.store(key, &entry.data)?;
+        }
+        Ok(())
     }
 
     pub fn keys(&self) -> Vec<String> {
         self.entries.keys().cloned().collect()
     }
 
+    pub fn take_evicted(&mut self) -> Vec<String> {
+        core::mem::take(&mut self.evicted)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            bytes_used: self.allocated as u64,
+            ..self.stats.clone()
+        }
+    }
+
     pub fn contains_key(&mut self, key: &str) -> bool {
         self.entries.contains_key(key)
     }
 
+    pub fn pinned(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|entry| entry.pinned)
+            .map(|entry| entry.data.len())
+            .sum()
+    }
+
+    pub fn pin(&mut self, key: &str) -> Result<(), Error> {
+        self.set_pinned(key, true)
+    }
+
+    pub fn unpin(&mut self, key: &str) -> Result<(), Error> {
+        self.set_pinned(key, false)
+    }
+
+    fn set_pinned(&mut self, key: &str, pinned: bool) -> Result<(), Error> {
+        let entry = self
+            .entries
+            .get_mut(key)
+            .ok_or(Error::CacheEntryNotFound(key.to_string()))?;
+        entry.pinned = pinned;
+        Ok(())
+    }
+
     pub fn get(&mut self, key: &str) -> Option<&[u8]> {
-        self.entries.get_mut(key).map(|entry| {
-            entry.access += 1;
-            &entry.data[..]
-        })
+        self.tick += 1;
+        match self.entries.get_mut(key) {
+            Some(entry) => {
+                self.stats.hits += 1;
+                entry.access += 1;
+                entry.last_access = self.tick;
+                Some(&entry.data[..])
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
     }
 
     pub fn put(&mut self, key: &str, size: usize) -> Result<usize, Error> {
+        let mut pinned = false;
         if let Some(removed_entry) = self.entries.remove(key) {
             self.allocated -= removed_entry.data.len();
+            pinned = removed_entry.pinned;
         }
 
         while self.capacity - self.allocated < size {
             let victim = self
                 .entries
                 .iter()
-                .min_by(|a, b| {
-                    let a_score = a.1.access.pow(2) * b.1.data.len();
-                    let b_score = b.1.access.pow(2) * a.1.data.len();
-                    a_score.cmp(&b_score)
-                })
+                .filter(|(_, entry)| !entry.pinned)
+                .min_by(|a, b| self.policy.compare(&a.1.meta(), &b.1.meta()))
                 .map(|(k, _)| k.clone());
 
             if let Some(victim_key) = victim {
                 if let Some(removed_entry) = self.entries.remove(&victim_key) {
                     self.allocated -= removed_entry.data.len();
+                    self.stats.evictions += 1;
+                    if let Some(store) = self.store.as_mut() {
+                        store.remove(&victim_key)?;
+                    }
+                    self.evicted.push(victim_key);
                 }
             } else {
                 break;
@@ -65,47 +242,91 @@ impl ModuleCache {
         }
 
         if size <= self.capacity - self.allocated {
+            self.tick += 1;
             self.entries.insert(
                 key.to_string(),
                 CacheEntry {
                     data: vec![0; size],
                     access: 1,
+                    last_access: self.tick,
+                    pinned,
                 },
             );
             self.allocated += size;
 
             Ok(size)
         } else {
-            Err(Error::CacheFull(self.allocated, self.capacity))
+            Err(Error::CacheFull(self.allocated, self.capacity, self.pinned()))
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<(), Error> {
+        if let Some(removed_entry) = self.entries.remove(key) {
+            self.allocated -= removed_entry.data.len();
+            if let Some(store) = self.store.as_mut() {
+                store.remove(key)?;
+            }
         }
+        Ok(())
     }
 
     pub fn put_slice(&mut self, key: &str, offset: usize, data: &[u8]) -> Result<usize, Error> {
-        let entry = self
+        let current = self
             .entries
-            .get_mut(key)
+            .get(key)
+            .map(|entry| entry.data.len())
             .ok_or(Error::CacheEntryNotFound(key.to_string()))?;
 
         let end = offset + data.len();
+        let required = end.saturating_sub(current);
+        if self.allocated + required > self.capacity {
+            return Err(Error::CacheFull(self.allocated, self.capacity, self.pinned()));
+        }
+        self.allocated += required;
+        self.tick += 1;
+
+        let entry = self.entries.get_mut(key).unwrap();
         if end > entry.data.len() {
-            let required = end - entry.data.len();
-            if self.allocated + required > self.capacity {
-                return Err(Error::CacheFull(self.allocated, self.capacity));
-            }
             entry.data.resize(end, 0);
-            self.allocated += required;
         }
-
         entry.data[offset..end].copy_from_slice(data);
         entry.access += 1;
+        entry.last_access = self.tick;
         Ok(data.len())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use alloc::collections::BTreeMap;
+    use alloc::rc::Rc;
+    use core::cell::RefCell;
+
     use super::*;
 
+    #[derive(Clone, Default)]
+    struct MemoryStore(Rc<RefCell<BTreeMap<String, Vec<u8>>>>);
+
+    impl CacheStore for MemoryStore {
+        fn keys(&self) -> Result<Vec<String>, Error> {
+            Ok(self.0.borrow().keys().cloned().collect())
+        }
+
+        fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+            Ok(self.0.borrow().get(key).cloned())
+        }
+
+        fn store(&mut self, key: &str, data: &[u8]) -> Result<(), Error> {
+            self.0.borrow_mut().insert(key.to_string(), data.to_vec());
+            Ok(())
+        }
+
+        fn remove(&mut self, key: &str) -> Result<(), Error> {
+            self.0.borrow_mut().remove(key);
+            Ok(())
+        }
+    }
+
     #[test]
     fn test_basic_eviction() {
         let mut cache = ModuleCache::new(15);
@@ -137,6 +358,17 @@ mod tests {
         assert_eq!(cache.get("k1"), Some(&[1, 2, 3][..]));
     }
 
+    #[test]
+    fn test_remove_entry() {
+        let mut cache = ModuleCache::new(10);
+
+        cache.put("k1", 4).unwrap();
+        cache.remove("k1").unwrap();
+        assert!(!cache.contains_key("k1"));
+        assert_eq!(cache.stats().bytes_used, 0);
+        cache.put("k2", 10).unwrap();
+    }
+
     #[test]
     fn test_access_count_affects_eviction() {
         let mut cache = ModuleCache::new(15);
@@ -157,4 +389,129 @@ mod tests {
         assert!(cache.get("k2").is_some());
         assert!(cache.get("k3").is_some());
     }
+
+    #[test]
+    fn test_lru_policy() {
+        let mut cache = ModuleCache::with_policy(15, LruPolicy);
+
+        cache.put("k1", 5).unwrap();
+        cache.put("k2", 5).unwrap();
+        cache.put("k3", 5).unwrap();
+
+        cache.get("k2");
+        cache.get("k2");
+        cache.get("k1");
+
+        cache.put("k4", 5).unwrap();
+        assert!(cache.get("k1").is_some());
+        assert!(cache.get("k2").is_some());
+        assert!(cache.get("k3").is_none());
+    }
+
+    #[test]
+    fn test_lfu_policy() {
+        let mut cache = ModuleCache::with_policy(15, LfuPolicy);
+
+        cache.put("k1", 5).unwrap();
+        cache.put("k2", 5).unwrap();
+        cache.put("k3", 5).unwrap();
+
+        cache.get("k1");
+        cache.get("k1");
+        cache.get("k3");
+
+        cache.put("k4", 5).unwrap();
+        assert!(cache.get("k1").is_some());
+        assert!(cache.get("k2").is_none());
+        assert!(cache.get("k3").is_some());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut cache = ModuleCache::new(10);
+
+        cache.put("k1", 6).unwrap();
+        cache.get("k1");
+        cache.get("k2");
+        cache.put("k2", 8).unwrap();
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.evictions, 1);
+        assert_eq!(stats.bytes_used, 8);
+        assert_eq!(cache.take_evicted(), vec!["k1".to_string()]);
+        assert!(cache.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn test_pinned_entries() {
+        let mut cache = ModuleCache::new(15);
+
+        cache.put("k1", 10).unwrap();
+        cache.pin("k1").unwrap();
+        cache.put("k2", 5).unwrap();
+
+        cache.put("k3", 5).unwrap();
+        assert!(cache.get("k1").is_some());
+        assert!(cache.get("k2").is_none());
+
+        let result = cache.put("k4", 10);
+        assert!(matches!(result, Err(Error::CacheFull(_, 15, 10))));
+
+        cache.unpin("k1").unwrap();
+        cache.put("k4", 10).unwrap();
+        assert!(cache.get("k1").is_none());
+        assert!(cache.get("k4").is_some());
+    }
+
+    #[test]
+    fn test_store_rehydrate() {
+        let store = MemoryStore::default();
+
+        let mut cache = ModuleCache::new(15);
+        cache.attach_store(store.clone()).unwrap();
+        cache.put("k1", 5).unwrap();
+        cache.put_slice("k1", 0, &[1; 5]).unwrap();
+        cache.persist("k1").unwrap();
+        cache.put("k2", 10).unwrap();
+        cache.put_slice("k2", 0, &[2; 10]).unwrap();
+        cache.persist("k2").unwrap();
+        cache.put("k3", 5).unwrap();
+        assert_eq!(store.keys().unwrap(), vec![String::from("k1")]);
+
+        let mut restored = ModuleCache::new(15);
+        assert_eq!(restored.attach_store(store).unwrap(), 1);
+        assert_eq!(restored.keys(), vec![String::from("k1")]);
+        assert_eq!(restored.get("k1"), Some(&[1; 5][..]));
+    }
+
+    #[test]
+    fn test_attach_store_oversized() {
+        let mut store = MemoryStore::default();
+        store.store("small", &[1; 5]).unwrap();
+        store.store("large", &[2; 20]).unwrap();
+
+        let mut cache = ModuleCache::new(15);
+        assert_eq!(cache.attach_store(store.clone()).unwrap(), 1);
+        assert_eq!(cache.keys(), vec![String::from("small")]);
+        assert_eq!(store.keys().unwrap(), vec![String::from("small")]);
+    }
+
+    #[test]
+    fn test_restore_shared() {
+        let store = MemoryStore::default();
+        let mut first = ModuleCache::new(15);
+        let mut second = ModuleCache::new(15);
+        first.attach_store(store.clone()).unwrap();
+        second.attach_store(store).unwrap();
+
+        first.put("k1", 5).unwrap();
+        first.put_slice("k1", 0, &[1; 5]).unwrap();
+        first.persist("k1").unwrap();
+        assert!(!second.contains_key("k1"));
+        assert!(second.restore("k1").unwrap());
+        assert_eq!(second.get("k1"), Some(&[1; 5][..]));
+        assert!(!second.restore("k2").unwrap());
+    }
 }
@@ -1,11 +1,12 @@
 use alloc::collections::VecDeque;
 
-use protocol::Message;
+use protocol::{ServerMessage, TaskId};
 
 #[derive(Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum SessionEvent {
-    Message(Message),
-    TaskTimeout(u64),
+    Message(ServerMessage),
+    TaskTimeout(TaskId),
 }
 
 pub struct EventQueue {
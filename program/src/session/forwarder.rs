@@ -0,0 +1,100 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use protocol::LogLevel;
+use spin::Mutex;
+
+pub struct ForwardedLog {
+    pub level: LogLevel,
+    pub module: String,
+    pub message: String,
+}
+
+// Global logger keeping the latest records in a ring buffer until a session forwards them
+// as ClientLog, the oldest record is dropped once `capacity` is reached. Install it with
+// `log::set_logger` from a `static` and hand the same reference to `Session::with_log_forwarder`.
+pub struct LogForwarder {
+    records: Mutex<VecDeque<ForwardedLog>>,
+    capacity: usize,
+    level: LevelFilter,
+}
+
+impl LogForwarder {
+    pub const fn new(capacity: usize, level: LevelFilter) -> Self {
+        Self {
+            records: Mutex::new(VecDeque::new()),
+            capacity,
+            level,
+        }
+    }
+
+    pub fn drain(&self, max: usize) -> Vec<ForwardedLog> {
+        let mut records = self.records.lock();
+        let count = records.len().min(max);
+        records.drain(..count).collect()
+    }
+}
+
+impl Log for LogForwarder {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) || self.capacity == 0 {
+            return;
+        }
+
+        let level = match record.level() {
+            Level::Error => LogLevel::Error,
+            Level::Warn => LogLevel::Warn,
+            Level::Info => LogLevel::Info,
+            Level::Debug => LogLevel::Debug,
+            Level::Trace => LogLevel::Trace,
+        };
+        let mut records = self.records.lock();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(ForwardedLog {
+            level,
+            module: record.target().to_string(),
+            message: record.args().to_string(),
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn log(forwarder: &LogForwarder, level: Level, message: &str) {
+        forwarder.log(
+            &Record::builder()
+                .level(level)
+                .target("program::tests")
+                .args(format_args!("{}", message))
+                .build(),
+        );
+    }
+
+    #[test]
+    fn test_ring_buffer() {
+        let forwarder = LogForwarder::new(2, LevelFilter::Info);
+        log(&forwarder, Level::Debug, "filtered");
+        log(&forwarder, Level::Info, "first");
+        log(&forwarder, Level::Warn, "second");
+        log(&forwarder, Level::Error, "third");
+
+        let records = forwarder.drain(8);
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].level, LogLevel::Warn);
+        assert_eq!(records[0].message, "second");
+        assert_eq!(records[1].module, "program::tests");
+        assert!(forwarder.drain(8).is_empty());
+    }
+}
@@ -1,21 +1,32 @@
 mod cache;
 mod events;
+mod forwarder;
 mod transfer;
 
-use alloc::borrow::ToOwned;
-use alloc::collections::BTreeMap;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
 use alloc::string::{String, ToString};
 use alloc::vec::Vec;
 use core::cell::RefCell;
+use core::time::Duration;
 
 use bytes::{Buf, BytesMut};
 use cache::ModuleCache;
 use events::{EventQueue, SessionEvent};
-use log::{error, info, warn};
-use protocol::{AckInfo, Message, Type};
+use log::{debug, error, info, warn};
+use protocol::{
+    AckInfo, CacheStats, Checksum, ClientMessage, ExecutionStats, ExecutorFlavor, Message, ModuleInfo,
+    ProtocolErrorCode, ProtocolOptions, SequenceCheck, SequenceTracker, ServerMessage, SessionStats, TaskId, Telemetry, Type,
+    NATIVE_LABEL,
+};
 use transfer::ModuleTransfer;
 
-use crate::{Clock, Error, Executor, Transport};
+pub use cache::{EntryMeta, EvictionPolicy, LfuPolicy, LruPolicy, SizeWeightedPolicy};
+pub use forwarder::{ForwardedLog, LogForwarder};
+
+use crate::{
+    target_arch, CacheStore, Clock, Error, Executor, FirmwareSink, PowerManager, TelemetryProbe, Transport, Watchdog,
+};
 
 pub struct TaskMeta {
     pub module: String,
@@ -34,25 +45,159 @@ impl TaskMeta {
 pub enum SessionState {
     Ready,
     Transferring {
-        task_id: u64,
+        task_id: TaskId,
         transfer: ModuleTransfer,
-        params: Vec<Type>,
+        // `None` for a prefetch, the module is only cached.
+        params: Option<Vec<Type>>,
+        env: Vec<(String, String)>,
+        // Libraries of the task by name, read from the cache when the task arrived.
+        libraries: Vec<(String, Vec<u8>)>,
+        attempt: u32,
+        // State an earlier attempt saved, the module resumes from it once it arrived.
+        checkpoint: Option<Vec<u8>>,
         retries: u8,
     },
     Executing {
-        task_id: u64,
+        task_id: TaskId,
         deadline: u64,
     },
+    Updating {
+        task_id: TaskId,
+        transfer: ModuleTransfer,
+        checksum: u32,
+    },
     Completed,
     Failed,
 }
 
+// Outcome of a single `Session::step`, telling a host driven loop how soon to call again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StepStatus {
+    // Nothing happened, but the session is mid exchange and should be polled again promptly.
+    Idle,
+    // Bytes moved or events were handled, more work may already be waiting.
+    Progress,
+    // Nothing is outstanding until the next timer, the host may sleep until the transport wakes it.
+    NeedsSleep(Duration),
+    // The iteration failed, the session is back to Ready and the host decides whether to reconnect.
+    Failed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SessionPhase {
+    Ready,
+    Transferring,
+    Executing,
+    Updating,
+}
+
+// What a device UI may show about the session, taken with `Session::snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SessionSnapshot {
+    pub phase: SessionPhase,
+    pub active_task: Option<TaskId>,
+    // Chunks received and expected while a module or firmware image is transferring.
+    pub progress: Option<(usize, usize)>,
+    pub cached_modules: Vec<String>,
+    // Frames refused in either direction, see Message::ProtocolError.
+    pub protocol_errors: u32,
+}
+
 struct SharedState {
     module_cache: ModuleCache,
-    active_tasks: BTreeMap<u64, TaskMeta>,
+    // Sent with ClientSubmit and waiting for ServerSubmitted, in order.
+    submissions: VecDeque<TaskMeta>,
+    // Accepted submissions waiting for their ServerResult. Kept apart from anything this device
+    // runs, the submitter may well be assigned its own task.
+    submitted: BTreeMap<TaskId, TaskMeta>,
+    remote_results: VecDeque<(TaskId, Vec<Type>)>,
     incoming: BytesMut,
     outgoing: BytesMut,
     device_ram: u64,
+    labels: Vec<String>,
+    executor: ExecutorFlavor,
+    // Appends `NATIVE_LABEL` to the advertised labels.
+    native: bool,
+    arch: String,
+    redirect: Option<String>,
+    // Seconds the server asked to wait after turning the connection away.
+    busy: Option<u32>,
+    // Issued by ServerSession, presented again in ClientReady after a reconnect.
+    resume_token: Option<u64>,
+    last_heartbeat: u64,
+    started_at: u64,
+    tasks_executed: u64,
+    protocol_errors: u32,
+    // Next sequence number for frames to the server, `None` sends them unnumbered.
+    next_sequence: Option<u32>,
+    sequence: SequenceTracker,
+    options: ProtocolOptions,
+    // Bytes of a refused frame still to arrive, dropped as they do instead of buffered.
+    discard: usize,
+    stats: SessionStats,
+}
+
+impl SharedState {
+    // Frames go through the outbox, split off from the cache so a checkpoint can be sent while
+    // the module that saved it still runs out of the cache.
+    fn split(&mut self) -> (&mut ModuleCache, Outbox<'_>) {
+        let outbox = Outbox {
+            outgoing: &mut self.outgoing,
+            next_sequence: &mut self.next_sequence,
+            options: &self.options,
+            stats: &mut self.stats,
+        };
+        (&mut self.module_cache, outbox)
+    }
+}
+
+struct Outbox<'a> {
+    outgoing: &'a mut BytesMut,
+    next_sequence: &'a mut Option<u32>,
+    options: &'a ProtocolOptions,
+    stats: &'a mut SessionStats,
+}
+
+impl Outbox<'_> {
+    fn send(&mut self, message: ClientMessage) -> Result<(), Error> {
+        // Encoded in place at the end of the outgoing buffer, no frame is allocated on its own.
+        let message = Message::from(message);
+        let prefix = match *self.next_sequence {
+            Some(_) => Message::HEADER_SIZE + Message::SEQUENCE_SIZE,
+            None => 0,
+        };
+        let start = self.outgoing.len();
+        self.outgoing.resize(start + prefix + self.options.encoded_len(&message)?, 0);
+        let buf = &mut self.outgoing[start..];
+        let encoded = match self.next_sequence.as_mut() {
+            Some(sequence) => self.options.encode_sequenced_into(&message, *sequence, buf).inspect(|_| {
+                *sequence = sequence.wrapping_add(1);
+            }),
+            None => self.options.encode_into(&message, buf),
+        };
+        if let Err(e) = encoded {
+            self.outgoing.truncate(start);
+            return Err(e.into());
+        }
+        self.stats.frames_out += 1;
+        Ok(())
+    }
+}
+
+// Checkpoint the task resumes from and where the states the module saves go, see `checkpoint`.
+struct Resume<'a> {
+    checkpoint: Option<&'a [u8]>,
+    save: &'a mut dyn FnMut(Vec<u8>),
+}
+
+struct PowerState {
+    manager: Box<dyn PowerManager>,
+    light_after: Duration,
+    deep_after: Option<Duration>,
+    idle_since: Option<u64>,
 }
 
 pub struct Session<T: Transport, E: Executor, C: Clock> {
@@ -62,47 +207,396 @@ pub struct Session<T: Transport, E: Executor, C: Clock> {
     shared: RefCell<SharedState>,
     state: SessionState,
     events: RefCell<EventQueue>,
+    announced: bool,
+    power: Option<PowerState>,
+    firmware: Option<Box<dyn FirmwareSink>>,
+    rebooting: bool,
+    logs: Option<&'static LogForwarder>,
+    probe: Option<Box<dyn TelemetryProbe>>,
+    watchdog: Option<Box<dyn Watchdog>>,
+    // ServerTasks that arrived mid transfer, started in order once the session is free again.
+    queued: VecDeque<ServerMessage>,
+    queue_limit: usize,
 }
 
 impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
     const MAX_MODULE_CACHE_SIZE: usize = 1024 * 64;
     const MAX_BUFF_SIZE: usize = 2048;
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+    const LOGS_PER_STEP: usize = 8;
 
     pub fn new(transport: T, executor: E, clock: C, device_ram: u64) -> Self {
+        let flavor = executor.flavor();
+        let native = executor.native();
+        let started_at = clock.timestamp();
         Self {
             transport,
             executor,
             clock,
             shared: RefCell::new(SharedState {
                 module_cache: ModuleCache::new(Self::MAX_MODULE_CACHE_SIZE),
-                active_tasks: BTreeMap::new(),
+                submissions: VecDeque::new(),
+                submitted: BTreeMap::new(),
+                remote_results: VecDeque::new(),
                 incoming: BytesMut::with_capacity(Self::MAX_BUFF_SIZE),
                 outgoing: BytesMut::with_capacity(Self::MAX_BUFF_SIZE),
                 device_ram,
+                labels: Vec::new(),
+                executor: flavor,
+                native,
+                arch: target_arch().to_string(),
+                redirect: None,
+                busy: None,
+                resume_token: None,
+                last_heartbeat: 0,
+                started_at,
+                tasks_executed: 0,
+                protocol_errors: 0,
+                next_sequence: None,
+                sequence: SequenceTracker::default(),
+                options: ProtocolOptions::DEFAULT,
+                discard: 0,
+                stats: SessionStats::default(),
             }),
             state: SessionState::Ready,
             events: RefCell::new(EventQueue::new()),
+            announced: false,
+            power: None,
+            firmware: None,
+            rebooting: false,
+            logs: None,
+            probe: None,
+            watchdog: None,
+            queued: VecDeque::new(),
+            queue_limit: 0,
         }
     }
 
-    pub fn run(&mut self) -> Result<(), Error> {
-        Self::send_ready(&mut self.shared.borrow_mut(), Vec::new())?;
+    pub fn with_eviction_policy(self, policy: impl EvictionPolicy + 'static) -> Self {
+        self.shared.borrow_mut().module_cache.set_policy(policy);
+        self
+    }
+
+    pub fn with_labels(self, labels: &[&str]) -> Self {
+        self.shared.borrow_mut().labels = labels.iter().map(|label| label.to_string()).collect();
+        self
+    }
+
+    pub fn with_cache_store(self, store: impl CacheStore + 'static) -> Result<Self, Error> {
+        let restored = self.shared.borrow_mut().module_cache.attach_store(store)?;
+        info!("Restored {} modules from cache store", restored);
+        Ok(self)
+    }
+
+    // Once the session has had nothing to do for `light_after` it light sleeps until the next
+    // heartbeat is due, past `deep_after` it deep sleeps until then instead.
+    pub fn with_power_manager(
+        mut self,
+        manager: impl PowerManager + 'static,
+        light_after: Duration,
+        deep_after: Option<Duration>,
+    ) -> Self {
+        self.power = Some(PowerState {
+            manager: Box::new(manager),
+            light_after,
+            deep_after,
+            idle_since: None,
+        });
+        self
+    }
+
+    // Without a sink every ServerFirmware is rejected.
+    pub fn with_firmware_sink(mut self, sink: impl FirmwareSink + 'static) -> Self {
+        self.firmware = Some(Box::new(sink));
+        self
+    }
+
+    // Records are stamped with the session clock as they are sent, at most a step late.
+    pub fn with_log_forwarder(mut self, forwarder: &'static LogForwarder) -> Self {
+        self.logs = Some(forwarder);
+        self
+    }
+
+    pub fn with_telemetry_probe(mut self, probe: impl TelemetryProbe + 'static) -> Self {
+        self.probe = Some(Box::new(probe));
+        self
+    }
+
+    pub fn with_watchdog(mut self, watchdog: impl Watchdog + 'static) -> Self {
+        self.watchdog = Some(Box::new(watchdog));
+        self
+    }
+
+    // Tasks assigned while another one is still transferring wait here, at most `limit` of them.
+    // Past that, or with the default of none, they are refused with ClientBusy.
+    pub fn with_task_queue(mut self, limit: usize) -> Self {
+        self.queue_limit = limit;
+        self
+    }
+
+    // Numbers every frame so a transport that may duplicate or reorder them can be told apart,
+    // the server numbers its frames in reply and telemetry reports how they arrived.
+    pub fn with_sequence_numbers(self) -> Self {
+        self.shared.borrow_mut().next_sequence = Some(0);
+        self
+    }
+
+    // Must match the server's encoding. A lower `max_payload` refuses larger frames from their
+    // header, so a device with a small heap never buffers them.
+    pub fn with_protocol_options(self, options: ProtocolOptions) -> Self {
+        self.shared.borrow_mut().options = options;
+        self
+    }
+
+    pub fn submit(&self, module: &str, params: Vec<Type>, priority: u8) -> Result<(), Error> {
+        let mut shared = self.shared.borrow_mut();
+        let message = ClientMessage::ClientSubmit {
+            module_name: module.to_string(),
+            params: params.clone(),
+            priority,
+        };
+        Self::send_message(&mut shared, message)?;
+        shared.submissions.push_back(TaskMeta::new(module.to_string(), params));
+        Ok(())
+    }
+
+    pub fn take_remote_result(&self) -> Option<(TaskId, Vec<Type>)> {
+        self.shared.borrow_mut().remote_results.pop_front()
+    }
+
+    pub fn take_redirect(&self) -> Option<String> {
+        self.shared.borrow_mut().redirect.take()
+    }
+
+    // Set once the server rejected this connection as full, hosts should wait this many seconds
+    // before reconnecting.
+    pub fn take_busy(&self) -> Option<u32> {
+        self.shared.borrow_mut().busy.take()
+    }
+
+    // Swaps in a fresh connection after the old one dropped, the next step announces the device
+    // again with its resume token so the server keeps its tasks and transfers. Frames half read
+    // or half written on the old connection are discarded.
+    pub fn reconnect(&mut self, transport: T) {
+        let mut shared = self.shared.borrow_mut();
+        shared.incoming.clear();
+        shared.outgoing.clear();
+        shared.discard = 0;
+        // Numbering starts over with each connection.
+        if shared.next_sequence.is_some() {
+            shared.next_sequence = Some(0);
+        }
+        shared.sequence = SequenceTracker::default();
+        self.transport = transport;
+        self.announced = false;
+    }
+
+    pub fn resume_token(&self) -> Option<u64> {
+        self.shared.borrow().resume_token
+    }
+
+    // Nothing is running, transferring or waiting to be written, a host may stop without losing a
+    // result.
+    pub fn is_settled(&self) -> bool {
+        !self.awaiting_server()
+            && !matches!(self.state, SessionState::Executing { .. })
+            && self.shared.borrow().outgoing.is_empty()
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.shared.borrow().module_cache.stats()
+    }
+
+    // Also reported to the server with every telemetry frame.
+    pub fn stats(&self) -> SessionStats {
+        self.shared.borrow().stats
+    }
+
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let (phase, active_task, progress) = match &self.state {
+            SessionState::Transferring { task_id, transfer, .. } => {
+                (SessionPhase::Transferring, Some(*task_id), Some(transfer.progress()))
+            }
+            SessionState::Updating { task_id, transfer, .. } => {
+                (SessionPhase::Updating, Some(*task_id), Some(transfer.progress()))
+            }
+            SessionState::Executing { task_id, .. } => (SessionPhase::Executing, Some(*task_id), None),
+            SessionState::Ready | SessionState::Completed | SessionState::Failed => (SessionPhase::Ready, None, None),
+        };
+        let shared = self.shared.borrow();
+        let mut cached_modules = shared.module_cache.keys();
+        cached_modules.sort();
+
+        SessionSnapshot {
+            phase,
+            active_task,
+            progress,
+            cached_modules,
+            protocol_errors: shared.protocol_errors,
+        }
+    }
 
+    pub fn run(&mut self) -> Result<(), Error> {
         loop {
-            self.process_io();
-            self.process_events();
-            self.process_state();
+            self.step()?;
+        }
+    }
+
+    // One IO, event and state pass. The first call announces the device with ClientReady.
+    pub fn step(&mut self) -> Result<StepStatus, Error> {
+        if let Some(watchdog) = self.watchdog.as_mut() {
+            watchdog.feed();
+        }
+        if !self.announced {
+            let mut shared = self.shared.borrow_mut();
+            let modules = shared.module_cache.keys();
+            Self::send_ready(&mut shared, modules)?;
+            self.announced = true;
+        }
+
+        self.forward_logs();
+        let io = self.process_io();
+        // The reboot waits until the verification ack has left the device.
+        if self.rebooting && self.shared.borrow().outgoing.is_empty() {
+            self.rebooting = false;
+            if let Some(sink) = self.firmware.as_mut() {
+                info!("Rebooting into new firmware");
+                sink.reboot();
+            }
+        }
+        let events = self.process_events();
+        self.process_state();
+
+        if matches!(self.state, SessionState::Failed) {
+            self.state = SessionState::Ready;
+            return Ok(StepStatus::Failed);
+        }
+        let status = match io || events {
+            true => StepStatus::Progress,
+            false => self.idle_status(),
+        };
+        Ok(self.settle(status, events))
+    }
+
+    // Hands long idle stretches to the power manager. Only server messages or pending work end
+    // an idle stretch, our own heartbeats do not. Sleeping here already covered the wait, so the
+    // host is told to poll again rather than sleep a second time.
+    fn settle(&mut self, status: StepStatus, events: bool) -> StepStatus {
+        let busy = events || self.awaiting_server();
+        let Some(power) = self.power.as_mut() else {
+            return status;
+        };
+        if busy {
+            power.idle_since = None;
+            return status;
+        }
+
+        let now = self.clock.timestamp();
+        let idle = Duration::from_nanos(now.saturating_sub(*power.idle_since.get_or_insert(now)));
+        let StepStatus::NeedsSleep(duration) = status else {
+            return status;
+        };
+        if power.deep_after.is_some_and(|after| idle >= after) {
+            info!("Idle for {:?}, deep sleeping for {:?}", idle, duration);
+            power.manager.deep_sleep(now + duration.as_nanos() as u64);
+        } else if idle >= power.light_after {
+            power.manager.light_sleep(duration);
+        } else {
+            return status;
+        }
+        StepStatus::Idle
+    }
+
+    // Chunks or submitted results are on their way, sleeping would only add latency.
+    fn awaiting_server(&self) -> bool {
+        let shared = self.shared.borrow();
+        matches!(self.state, SessionState::Transferring { .. } | SessionState::Updating { .. })
+            || self.rebooting
+            || !shared.incoming.is_empty()
+            || !shared.submissions.is_empty()
+            || !shared.submitted.is_empty()
+    }
+
+    fn idle_status(&self) -> StepStatus {
+        if self.awaiting_server() || !self.shared.borrow().outgoing.is_empty() {
+            return StepStatus::Idle;
+        }
+
+        let now = self.clock.timestamp();
+        let shared = self.shared.borrow();
+        let heartbeat = (shared.last_heartbeat + Self::HEARTBEAT_INTERVAL.as_nanos() as u64).saturating_sub(now);
+        match &self.state {
+            SessionState::Executing { deadline, .. } => {
+                StepStatus::NeedsSleep(Duration::from_nanos(heartbeat.min(deadline.saturating_sub(now))))
+            }
+            _ => StepStatus::NeedsSleep(Duration::from_nanos(heartbeat)),
         }
     }
 
-    fn process_io(&mut self) {
+    fn forward_logs(&mut self) {
+        let Some(forwarder) = self.logs else {
+            return;
+        };
+        let timestamp = self.clock.timestamp();
         let mut shared = self.shared.borrow_mut();
+        for log in forwarder.drain(Self::LOGS_PER_STEP) {
+            let message = ClientMessage::ClientLog {
+                level: log.level,
+                module: log.module,
+                message: log.message,
+                timestamp,
+            };
+            // Logging the failure would only feed the forwarder again.
+            if Self::send_message(&mut shared, message).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn process_io(&mut self) -> bool {
+        let mut shared = self.shared.borrow_mut();
+        let mut progress = false;
 
         match self.transport.read(&mut shared.incoming) {
             Ok(n) if n > 0 => {
-                while let Ok((message, consumed)) = Message::decode(&shared.incoming) {
-                    self.events.borrow_mut().push(SessionEvent::Message(message));
-                    shared.incoming.advance(consumed);
+                progress = true;
+                shared.stats.bytes_in += n as u64;
+                let skipped = shared.discard.min(shared.incoming.len());
+                shared.incoming.advance(skipped);
+                shared.discard -= skipped;
+                while shared.discard == 0 {
+                    let options = shared.options;
+                    match options.decode_as::<ServerMessage>(&shared.incoming) {
+                        Ok((message, sequence, consumed)) => {
+                            shared.incoming.advance(consumed);
+                            shared.stats.frames_in += 1;
+                            if let Some(sequence) = sequence {
+                                match shared.sequence.check(sequence) {
+                                    SequenceCheck::InOrder => {}
+                                    SequenceCheck::Reordered => debug!("Frame {} arrived out of order", sequence),
+                                    SequenceCheck::Duplicate => {
+                                        debug!("Dropped duplicate frame {}", sequence);
+                                        continue;
+                                    }
+                                }
+                            }
+                            self.events.borrow_mut().push(SessionEvent::Message(message));
+                        }
+                        Err(e) => {
+                            let (Some(code), Some(len)) = (ProtocolErrorCode::of(&e), Message::frame_len(&shared.incoming))
+                            else {
+                                break;
+                            };
+                            // Frames are length prefixed, so the rest of the stream stays usable.
+                            warn!("Dropped a frame of {} bytes that failed to decode: {:?}", len, e);
+                            shared.stats.decode_errors += 1;
+                            Self::send_protocol_error(&mut shared, code, format!("{}", e));
+                            // A frame refused from its header has mostly not arrived yet.
+                            let skipped = len.min(shared.incoming.len());
+                            shared.incoming.advance(skipped);
+                            shared.discard = len - skipped;
+                        }
+                    }
                 }
             }
             Err(e) => {
@@ -117,10 +611,12 @@ impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
             match write_result {
                 Ok(n) => {
                     shared.outgoing.advance(n);
+                    shared.stats.bytes_out += n as u64;
                     if n == 0 {
                         warn!("Zero bytes written, connection may be closed");
                         break;
                     }
+                    progress = true;
                 }
                 Err(e) => {
                     error!("Transport write error: {:?}", e);
@@ -128,16 +624,27 @@ impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
                 }
             }
         }
+        progress
     }
 
-    fn process_events(&mut self) {
+    fn process_events(&mut self) -> bool {
+        let mut progress = false;
         loop {
             let event = self.events.borrow_mut().pop();
+            let event = event.or_else(|| self.dequeue_task());
             if let Some(event) = event.as_ref() {
+                progress = true;
+                #[cfg(feature = "defmt")]
+                defmt::debug!("Session event {}", event);
                 match event {
                     SessionEvent::Message(msg) => {
                         if let Err(e) = self.handle_message(msg) {
                             error!("Resolve message error: {:?}", e);
+                            #[cfg(feature = "defmt")]
+                            defmt::error!("Resolve message error: {}", e);
+                            let mut shared = self.shared.borrow_mut();
+                            Self::send_protocol_error(&mut shared, ProtocolErrorCode::Unexpected, format!("{:?}", e));
+                            drop(shared);
                             self.state = SessionState::Failed;
                             break;
                         }
@@ -156,56 +663,149 @@ impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
                 break;
             }
         }
+        progress
+    }
+
+    // Higher priorities go first, equal ones in the order they came. A full queue gives up its
+    // lowest task for one of higher priority, the server hands the refused task elsewhere.
+    fn enqueue(&mut self, msg: &ServerMessage, task_id: TaskId, priority: u8) -> Result<(), Error> {
+        let queued_priority = |queued: &ServerMessage| match queued {
+            ServerMessage::ServerTask { priority, .. } => *priority,
+            _ => u8::MAX,
+        };
+        let queue_len = self.queued.len();
+        let refused = match self.queued.back() {
+            _ if queue_len < self.queue_limit => None,
+            Some(lowest) if queued_priority(lowest) < priority => self.queued.pop_back(),
+            _ => {
+                warn!("Refusing task {}, {} tasks already queued", task_id, queue_len);
+                let message = ClientMessage::ClientBusy { task_id, queue_len: queue_len as u32 };
+                return Self::send_message(&mut self.shared.borrow_mut(), message);
+            }
+        };
+        let position = self
+            .queued
+            .iter()
+            .position(|queued| queued_priority(queued) < priority)
+            .unwrap_or(self.queued.len());
+        info!("Queued task {} behind {} others", task_id, position);
+        self.queued.insert(position, msg.clone());
+
+        match refused {
+            Some(ServerMessage::ServerTask { task_id: refused, .. }) => {
+                warn!("Refusing queued task {} for task {} of higher priority", refused, task_id);
+                let message = ClientMessage::ClientBusy { task_id: refused, queue_len: queue_len as u32 };
+                Self::send_message(&mut self.shared.borrow_mut(), message)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn dequeue_task(&mut self) -> Option<SessionEvent> {
+        if !matches!(self.state, SessionState::Ready | SessionState::Completed) {
+            return None;
+        }
+        self.queued.pop_front().map(SessionEvent::Message)
     }
 
     fn process_state(&mut self) {
+        let now = self.clock.timestamp();
+        {
+            let mut shared = self.shared.borrow_mut();
+            if now.saturating_sub(shared.last_heartbeat) >= Self::HEARTBEAT_INTERVAL.as_nanos() as u64 {
+                shared.last_heartbeat = now;
+                if let Err(e) = Self::send_telemetry(&mut shared, self.probe.as_deref(), now) {
+                    warn!("Failed to queue telemetry: {:?}", e);
+                }
+            }
+        }
+
         match &mut self.state {
             SessionState::Transferring { task_id, retries, .. } => {
                 let mut shared = self.shared.borrow_mut();
                 if *retries > 3 {
-                    let modules: Vec<String> = shared.module_cache.keys();
-                    Self::send_ack(&mut shared, *task_id, AckInfo::Module { modules }).unwrap();
+                    let ack_info = AckInfo::TaskAck { accepted: false };
+                    if let Err(e) = Self::send_ack(&mut shared, *task_id, ack_info) {
+                        error!("Failed to reject task {}: {:?}", task_id, e);
+                    }
                     self.state = SessionState::Failed;
                 }
             }
-            SessionState::Executing { task_id, deadline } => {
-                if self.clock.timestamp() > *deadline {
-                    self.events
-                        .borrow_mut()
-                        .push(SessionEvent::TaskTimeout(*task_id));
-                }
+            SessionState::Executing { task_id, deadline } if now > *deadline => {
+                self.events
+                    .borrow_mut()
+                    .push(SessionEvent::TaskTimeout(*task_id));
             }
             _ => {}
         }
     }
 
-    fn handle_message(&mut self, msg: &Message) -> Result<(), Error> {
+    fn handle_message(&mut self, msg: &ServerMessage) -> Result<(), Error> {
         match msg {
-            Message::ServerTask { task_id, module, params } => {
-                info!("Received ServerTask id {} module {} params {:?}", task_id, module.name, params);
+            ServerMessage::ServerTask { task_id, attempt, module, params, env, libraries, priority, checkpoint } => {
+                info!("Received ServerTask id {} attempt {} module {} params {:?}", task_id, attempt, module.name, params);
+                if matches!(self.state, SessionState::Transferring { .. } | SessionState::Updating { .. }) {
+                    return self.enqueue(msg, *task_id, *priority);
+                }
                 let module_name = module.name.clone();
                 let mut shared = self.shared.borrow_mut();
 
+                // The server fetches referenced blobs and libraries first, one missing by now was
+                // evicted.
+                let resolved = Self::resolve_blobs(&mut shared, params)
+                    .and_then(|params| Ok((params, Self::resolve_libraries(&mut shared, libraries)?)));
+                let (params, libraries) = match resolved {
+                    Ok(resolved) => resolved,
+                    Err(e) => {
+                        warn!("Rejecting task {}: {}", task_id, e);
+                        let ack_info = AckInfo::TaskAck { accepted: false };
+                        return Self::send_ack(&mut shared, *task_id, ack_info);
+                    }
+                };
+
+                // Another session sharing the cache store may already have received the module.
+                if let Err(e) = shared.module_cache.restore(&module_name) {
+                    warn!("Failed to restore module {}: {:?}", module_name, e);
+                }
                 let modules: Vec<String> = shared.module_cache.keys();
-                Self::send_ack(&mut shared, *task_id, AckInfo::Module { modules })?;
-
-                if let Some(cached) = shared.module_cache.get(&module_name) {
-                    let result = self
-                        .executor
-                        .execute(cached, params.to_owned())
-                        .map_err(|e| Error::Execution(e.to_string()))?;
-                    Self::send_result(&mut shared, *task_id, result)?;
+                Self::send_ack(&mut shared, *task_id, AckInfo::ModuleListAck { modules })?;
+
+                if module.pinned && shared.module_cache.contains_key(&module_name) {
+                    shared.module_cache.pin(&module_name)?;
+                }
+
+                let (module_cache, mut outbox) = shared.split();
+                if let Some(cached) = module_cache.get(&module_name) {
+                    let transport = &mut self.transport;
+                    let mut save = |data| Self::send_checkpoint(transport, &mut outbox, *task_id, *attempt, data);
+                    let resume = Resume { checkpoint: checkpoint.as_deref(), save: &mut save };
+                    let executed = Self::execute(&self.executor, &self.clock, cached, &libraries, params, env, resume);
+                    shared.stats.cache_hits += 1;
+                    shared.stats.executions += 1;
+                    let (result, stats) = executed?;
+                    Self::send_result(&mut shared, *task_id, *attempt, result, stats)?;
                 } else {
-                    shared
-                        .module_cache
-                        .put(&module_name, module.size as usize)?;
+                    let stored = shared.module_cache.put(&module_name, module.size as usize);
+                    Self::send_evictions(&mut shared)?;
+                    if let Err(e) = stored {
+                        warn!("Rejecting task {}: {}", task_id, e);
+                        let ack_info = AckInfo::TaskAck { accepted: false };
+                        return Self::send_ack(&mut shared, *task_id, ack_info);
+                    }
 
                     if shared.module_cache.contains_key(&module_name) {
+                        if module.pinned {
+                            shared.module_cache.pin(&module_name)?;
+                        }
                         let transfer = ModuleTransfer::new(module);
                         self.state = SessionState::Transferring {
                             task_id: *task_id,
                             transfer,
-                            params: params.to_owned(),
+                            params: Some(params),
+                            env: env.clone(),
+                            libraries,
+                            attempt: *attempt,
+                            checkpoint: checkpoint.clone(),
                             retries: 0,
                         };
                     } else {
@@ -213,11 +813,15 @@ impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
                     }
                 }
             }
-            Message::ServerModule { task_id, chunk_index, chunk_data } => {
+            ServerMessage::ServerModule { task_id, chunk_index, chunk_data } => {
                 if let SessionState::Transferring {
                     task_id: current_id,
                     transfer,
                     params,
+                    env,
+                    libraries,
+                    attempt,
+                    checkpoint,
                     retries,
                 } = &mut self.state
                 {
@@ -226,35 +830,55 @@ impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
                     }
 
                     let mut shared = self.shared.borrow_mut();
+                    let before = Self::percent(transfer.progress());
                     match transfer.add_chunk(
                         &mut shared.module_cache,
                         *chunk_index as usize,
                         chunk_data,
                     ) {
                         Ok(_) => {
-                            Self::send_ack(&mut shared, *task_id, AckInfo::Chunk {
+                            Self::send_ack(&mut shared, *task_id, AckInfo::ChunkAck {
                                 chunk_index: *chunk_index,
                                 success: true,
                             })?;
 
+                            // Every tenth of a task's module, a prefetch has no task to report on.
+                            let percent = Self::percent(transfer.progress());
+                            if params.is_some() && percent / 10 > before / 10 && !transfer.is_complete() {
+                                let stage = "transfer".to_string();
+                                Self::send_message(&mut shared, ClientMessage::ClientProgress { task_id: *task_id, percent, stage })?;
+                            }
+
                             if transfer.is_complete() {
                                 info!("Module transfer completed for task {:?}", task_id);
                                 let module_name = transfer.name().to_string();
-                                let module_data = shared
-                                    .module_cache
+                                if let Err(e) = shared.module_cache.persist(&module_name) {
+                                    warn!("Failed to persist module {}: {:?}", module_name, e);
+                                }
+                                let Some(params) = params.clone() else {
+                                    info!("Prefetched module {}", module_name);
+                                    self.state = SessionState::Ready;
+                                    return Ok(());
+                                };
+                                let (module_cache, mut outbox) = shared.split();
+                                let module_data = module_cache
                                     .get(&module_name)
                                     .ok_or(Error::CacheEntryNotFound(module_name))?;
 
-                                let result = self
-                                    .executor
-                                    .execute(module_data, params.clone())
-                                    .map_err(|e| Error::Execution(e.to_string()))?;
-                                Self::send_result(&mut shared, *task_id, result)?;
+                                let transport = &mut self.transport;
+                                let mut save =
+                                    |data| Self::send_checkpoint(transport, &mut outbox, *task_id, *attempt, data);
+                                let resume = Resume { checkpoint: checkpoint.as_deref(), save: &mut save };
+                                let executed =
+                                    Self::execute(&self.executor, &self.clock, module_data, libraries, params, env, resume);
+                                shared.stats.executions += 1;
+                                let (result, stats) = executed?;
+                                Self::send_result(&mut shared, *task_id, *attempt, result, stats)?;
                                 self.state = SessionState::Completed;
                             }
                         }
                         Err(e) => {
-                            Self::send_ack(&mut shared, *task_id, AckInfo::Chunk {
+                            Self::send_ack(&mut shared, *task_id, AckInfo::ChunkAck {
                                 chunk_index: *chunk_index,
                                 success: false,
                             })?;
@@ -262,17 +886,171 @@ impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
                             return Err(e);
                         }
                     }
+                } else if let SessionState::Updating {
+                    task_id: current_id,
+                    transfer,
+                    checksum,
+                } = &mut self.state
+                {
+                    if *current_id != *task_id {
+                        return Err(Error::TaskNotFound(*task_id));
+                    }
+                    let Some(sink) = self.firmware.as_mut() else {
+                        return Err(Error::Firmware("no firmware sink".into()));
+                    };
+
+                    let mut shared = self.shared.borrow_mut();
+                    let verified = match transfer.add_chunk(sink.as_mut(), *chunk_index as usize, chunk_data) {
+                        Ok(_) => {
+                            Self::send_ack(&mut shared, *task_id, AckInfo::ChunkAck {
+                                chunk_index: *chunk_index,
+                                success: true,
+                            })?;
+                            if !transfer.is_complete() {
+                                return Ok(());
+                            }
+                            sink.finish(*checksum)
+                        }
+                        Err(e) => Err(e),
+                    };
+
+                    // Any failure ends the update, the server frees the device on a negative ack.
+                    match verified {
+                        Ok(()) => {
+                            info!("Firmware {} verified", transfer.name());
+                            self.rebooting = true;
+                        }
+                        Err(ref e) => warn!("Firmware {} rejected: {:?}", transfer.name(), e),
+                    }
+                    let ack_info = AckInfo::FirmwareAck { verified: verified.is_ok() };
+                    Self::send_ack(&mut shared, *task_id, ack_info)?;
+                    self.state = SessionState::Ready;
                 }
             }
-            Message::ServerAck { task_id, success } => {
-                if let Some(_task) = self.shared.borrow_mut().active_tasks.remove(task_id) {
-                    if *success {
-                        info!("Task {} completed successfully", task_id);
-                    } else {
-                        warn!("Task {} failed on server side", task_id);
+            ServerMessage::ServerFirmware { task_id, firmware } => {
+                info!("Received ServerFirmware id {} version {}", task_id, firmware.version);
+                let mut shared = self.shared.borrow_mut();
+                let begun = match self.firmware.as_mut() {
+                    Some(sink) => sink.begin(&firmware.version, firmware.size as usize),
+                    None => Err(Error::Firmware("no firmware sink".into())),
+                };
+                if let Err(e) = begun {
+                    warn!("Rejecting firmware {}: {:?}", firmware.version, e);
+                    return Self::send_ack(&mut shared, *task_id, AckInfo::TaskAck { accepted: false });
+                }
+
+                Self::send_ack(&mut shared, *task_id, AckInfo::TaskAck { accepted: true })?;
+                self.state = SessionState::Updating {
+                    task_id: *task_id,
+                    transfer: ModuleTransfer::firmware(firmware),
+                    checksum: firmware.checksum,
+                };
+            }
+            ServerMessage::ServerPrefetch { task_id, module } => {
+                info!("Received ServerPrefetch id {} module {}", task_id, module.name);
+                let mut shared = self.shared.borrow_mut();
+
+                if let Err(e) = shared.module_cache.restore(&module.name) {
+                    warn!("Failed to restore module {}: {:?}", module.name, e);
+                }
+                let modules: Vec<String> = shared.module_cache.keys();
+                Self::send_ack(&mut shared, *task_id, AckInfo::ModuleListAck { modules })?;
+                if shared.module_cache.contains_key(&module.name) {
+                    return Ok(());
+                }
+
+                let stored = shared.module_cache.put(&module.name, module.size as usize);
+                Self::send_evictions(&mut shared)?;
+                if let Err(e) = stored {
+                    warn!("Rejecting prefetch {}: {}", task_id, e);
+                    let ack_info = AckInfo::TaskAck { accepted: false };
+                    return Self::send_ack(&mut shared, *task_id, ack_info);
+                }
+                if module.pinned {
+                    shared.module_cache.pin(&module.name)?;
+                }
+                self.state = SessionState::Transferring {
+                    task_id: *task_id,
+                    transfer: ModuleTransfer::new(module),
+                    params: None,
+                    env: Vec::new(),
+                    libraries: Vec::new(),
+                    attempt: 0,
+                    checkpoint: None,
+                    retries: 0,
+                };
+            }
+            ServerMessage::ServerCancel { task_id } => {
+                self.queued.retain(|queued| !matches!(queued, ServerMessage::ServerTask { task_id: id, .. } if id == task_id));
+                // Execution runs to completion in place, only a pending transfer can be abandoned.
+                match &self.state {
+                    SessionState::Transferring { task_id: current_id, transfer, .. } if current_id == task_id => {
+                        info!("Task {} canceled by server during transfer", task_id);
+                        // The partially written module must not be mistaken for a cached one.
+                        self.shared.borrow_mut().module_cache.remove(transfer.name())?;
+                        self.state = SessionState::Ready;
+                    }
+                    // The unfinished image is never marked bootable, the next update overwrites it.
+                    SessionState::Updating { task_id: current_id, .. } if current_id == task_id => {
+                        info!("Firmware update {} canceled by server", task_id);
+                        self.state = SessionState::Ready;
+                    }
+                    _ => {}
+                }
+            }
+            ServerMessage::ServerRedirect { addr } => {
+                info!("Received ServerRedirect to {}", addr);
+                self.shared.borrow_mut().redirect = Some(addr.clone());
+            }
+            ServerMessage::ServerSession { resume_token, resumed } => {
+                info!("Received ServerSession, resumed {}", resumed);
+                self.shared.borrow_mut().resume_token = Some(*resume_token);
+            }
+            ServerMessage::ServerBusy { max_sessions, retry_after_secs } => {
+                warn!("Server busy with {} sessions, retry in {} secs", max_sessions, retry_after_secs);
+                self.shared.borrow_mut().busy = Some(*retry_after_secs);
+            }
+            ServerMessage::ServerUnpin { module } => {
+                info!("Received ServerUnpin for module {}", module);
+                let mut shared = self.shared.borrow_mut();
+                if shared.module_cache.contains_key(module) {
+                    shared.module_cache.unpin(module)?;
+                }
+            }
+            ServerMessage::ServerSubmitted { task_id } => {
+                let mut shared = self.shared.borrow_mut();
+                if let Some(meta) = shared.submissions.pop_front() {
+                    match task_id {
+                        Some(task_id) => {
+                            info!("Submitted module {} as task {}", meta.module, task_id);
+                            shared.submitted.insert(*task_id, meta);
+                        }
+                        None => warn!("Submission of module {} rejected by server", meta.module),
                     }
                 }
             }
+            ServerMessage::ServerResult { task_id, result } => {
+                let mut shared = self.shared.borrow_mut();
+                if shared.submitted.remove(task_id).is_some() {
+                    info!("Received result for submitted task {}", task_id);
+                    shared.remote_results.push_back((*task_id, result.clone()));
+                }
+            }
+            ServerMessage::ServerAck { task_id, success } => {
+                if *success {
+                    info!("Task {} completed successfully", task_id);
+                } else {
+                    warn!("Task {} failed on server side", task_id);
+                }
+            }
+            ServerMessage::ProtocolError { code, detail } => {
+                warn!("Server refused a frame ({:?}): {}", code, detail);
+                self.shared.borrow_mut().protocol_errors += 1;
+            }
+            ServerMessage::ServerPing { nonce } => {
+                debug!("Received ServerPing {}", nonce);
+                Self::send_message(&mut self.shared.borrow_mut(), ClientMessage::ClientPong { nonce: *nonce })?;
+            }
             _ => {}
         }
         Ok(())
@@ -280,32 +1058,1162 @@ impl<T: Transport, E: Executor, C: Clock> Session<T, E, C> {
 
     #[inline]
     fn send_ready(state: &mut SharedState, modules: Vec<String>) -> Result<(), Error> {
-        let message = Message::ClientReady { modules, device_ram: state.device_ram };
-        Self::send_message(state, &message)
+        let mut labels = state.labels.clone();
+        if state.native && !labels.iter().any(|label| label == NATIVE_LABEL) {
+            labels.push(NATIVE_LABEL.to_string());
+        }
+        let message = ClientMessage::ClientReady {
+            modules,
+            device_ram: state.device_ram,
+            labels,
+            executor: state.executor,
+            arch: state.arch.clone(),
+            resume_token: state.resume_token,
+        };
+        Self::send_message(state, message)
     }
 
     #[inline]
-    fn send_ack(state: &mut SharedState, task_id: u64, ack_info: AckInfo) -> Result<(), Error> {
-        let message = Message::ClientAck { task_id, ack_info };
-        Self::send_message(state, &message)
+    fn send_ack(state: &mut SharedState, task_id: TaskId, ack_info: AckInfo) -> Result<(), Error> {
+        let message = ClientMessage::ClientAck { task_id, ack_info };
+        Self::send_message(state, message)
     }
 
-    #[inline]
-    fn send_result(state: &mut SharedState, task_id: u64, result: Vec<Type>) -> Result<(), Error> {
-        let message = Message::ClientResult { task_id, result };
-        Self::send_message(state, &message)
+    fn percent((done, total): (usize, usize)) -> u8 {
+        (done * 100).checked_div(total).unwrap_or(100) as u8
+    }
+
+    // The module list ack went out before the cache made room, so evicted modules are reported
+    // separately.
+    fn send_evictions(state: &mut SharedState) -> Result<(), Error> {
+        let removed = state.module_cache.take_evicted();
+        if removed.is_empty() {
+            return Ok(());
+        }
+        info!("Evicted modules {:?}", removed);
+        Self::send_message(state, ClientMessage::ClientCacheUpdate { added: Vec::new(), removed })
+    }
+
+    // Swaps blob references for the cached content. A blob failing its checksum is dropped and
+    // reported so the server sends it again.
+    fn resolve_blobs(state: &mut SharedState, params: &[Type]) -> Result<Vec<Type>, Error> {
+        let mut resolved = Vec::with_capacity(params.len());
+        for param in params {
+            let Type::BlobRef(id, size, hash) = param else {
+                resolved.push(param.clone());
+                continue;
+            };
+            let name = protocol::blob_name(*id);
+            if let Err(e) = state.module_cache.restore(&name) {
+                warn!("Failed to restore blob {}: {:?}", name, e);
+            }
+            let data = state
+                .module_cache
+                .get(&name)
+                .ok_or_else(|| Error::CacheEntryNotFound(name.clone()))?;
+            if data.len() as u64 != *size || Checksum::of(data) != *hash {
+                state.module_cache.remove(&name)?;
+                let removed = vec![name];
+                Self::send_message(state, ClientMessage::ClientCacheUpdate { added: Vec::new(), removed })?;
+                return Err(Error::BlobMismatch(*id));
+            }
+            resolved.push(Type::Bytes(data.to_vec()));
+        }
+        Ok(resolved)
+    }
+
+    fn resolve_libraries(state: &mut SharedState, libraries: &[ModuleInfo]) -> Result<Vec<(String, Vec<u8>)>, Error> {
+        libraries
+            .iter()
+            .map(|library| {
+                if let Err(e) = state.module_cache.restore(&library.name) {
+                    warn!("Failed to restore library {}: {:?}", library.name, e);
+                }
+                let data = state
+                    .module_cache
+                    .get(&library.name)
+                    .ok_or_else(|| Error::CacheEntryNotFound(library.name.clone()))?;
+                Ok((library.name.clone(), data.to_vec()))
+            })
+            .collect()
+    }
+
+    fn execute(
+        executor: &E,
+        clock: &C,
+        module: &[u8],
+        libraries: &[(String, Vec<u8>)],
+        params: Vec<Type>,
+        env: &[(String, String)],
+        resume: Resume<'_>,
+    ) -> Result<(Vec<Type>, ExecutionStats), Error> {
+        let started = clock.timestamp();
+        let (result, mut stats) = executor
+            .execute_resumable(module, libraries, params, env, resume.checkpoint, resume.save)
+            .map_err(|e| Error::Execution(e.to_string()))?;
+        if stats.wall_time_us == 0 {
+            stats.wall_time_us = clock.timestamp().saturating_sub(started) / 1000;
+        }
+        Ok((result, stats))
     }
 
     #[inline]
-    fn send_heartbeat(state: &mut SharedState, timestamp: u64) -> Result<(), Error> {
-        let message = Message::Heartbeat { timestamp };
-        Self::send_message(state, &message)
+    fn send_result(
+        state: &mut SharedState,
+        task_id: TaskId,
+        attempt: u32,
+        result: Vec<Type>,
+        stats: ExecutionStats,
+    ) -> Result<(), Error> {
+        state.tasks_executed += 1;
+        let message = ClientMessage::ClientResult { task_id, attempt, result, stats };
+        Self::send_message(state, message)
+    }
+
+    fn send_telemetry(state: &mut SharedState, probe: Option<&dyn TelemetryProbe>, timestamp: u64) -> Result<(), Error> {
+        let telemetry = Telemetry {
+            free_heap: probe.and_then(|probe| probe.free_heap()),
+            tasks_executed: state.tasks_executed,
+            uptime_secs: Duration::from_nanos(timestamp.saturating_sub(state.started_at)).as_secs(),
+            rssi: probe.and_then(|probe| probe.rssi()),
+            sequence: state.next_sequence.map(|_| state.sequence.stats()),
+            session: state.stats,
+        };
+        let cache = state.module_cache.stats();
+        let message = ClientMessage::ClientTelemetry { timestamp, cache, telemetry };
+        Self::send_message(state, message)
     }
 
     #[inline]
-    fn send_message(state: &mut SharedState, message: &Message) -> Result<(), Error> {
-        let data = message.encode()?;
-        state.outgoing.extend_from_slice(&data);
-        Ok(())
+    fn send_message(state: &mut SharedState, message: ClientMessage) -> Result<(), Error> {
+        state.split().1.send(message)
+    }
+
+    // Writes the checkpoint out at once, the session only flushes its frames again after the
+    // execution returned and the device may not live that long.
+    fn send_checkpoint(transport: &mut T, outbox: &mut Outbox<'_>, task_id: TaskId, attempt: u32, data: Vec<u8>) {
+        debug!("Saving checkpoint of {} bytes for task {}", data.len(), task_id);
+        if let Err(e) = outbox.send(ClientMessage::ClientCheckpoint { task_id, attempt, data }) {
+            warn!("Failed to send checkpoint: {:?}", e);
+            return;
+        }
+        while !outbox.outgoing.is_empty() {
+            match transport.write(&mut *outbox.outgoing) {
+                Ok(n) if n > 0 => {
+                    outbox.outgoing.advance(n);
+                    outbox.stats.bytes_out += n as u64;
+                }
+                // Whatever is left goes out with the next step.
+                Ok(_) => break,
+                Err(e) => {
+                    warn!("Failed to send checkpoint: {:?}", e);
+                    break;
+                }
+            }
+        }
+    }
+
+    // Tells the server why its frame went nowhere, failing to do so only costs the diagnostic.
+    fn send_protocol_error(state: &mut SharedState, code: ProtocolErrorCode, detail: String) {
+        state.protocol_errors += 1;
+        if let Err(e) = Self::send_message(state, ClientMessage::ProtocolError { code, detail }) {
+            warn!("Failed to report protocol error: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::rc::Rc;
+    use core::cell::Cell;
+    use core::convert::Infallible;
+
+    use bytes::BufMut;
+    use log::Log;
+    use protocol::{Checksum, FirmwareInfo, SequenceStats};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct MockTransport {
+        inbound: Rc<RefCell<Vec<u8>>>,
+        outbound: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Transport for MockTransport {
+        type Error = Infallible;
+
+        fn read<B>(&mut self, buf: &mut B) -> Result<usize, Self::Error>
+        where
+            B: BufMut + ?Sized,
+        {
+            let data = core::mem::take(&mut *self.inbound.borrow_mut());
+            buf.put_slice(&data);
+            Ok(data.len())
+        }
+
+        fn write<B>(&mut self, src: &mut B) -> Result<usize, Self::Error>
+        where
+            B: Buf,
+        {
+            let len = src.remaining();
+            self.outbound.borrow_mut().extend_from_slice(src.chunk());
+            Ok(len)
+        }
+    }
+
+    struct MockClock(Rc<Cell<u64>>);
+
+    impl Clock for MockClock {
+        fn timestamp(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    struct EchoExecutor;
+
+    impl Executor for EchoExecutor {
+        type Error = Infallible;
+
+        fn execute(&self, _module: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+            Ok(params)
+        }
+    }
+
+    // Answers with the environment it was handed, as the WASI `environ_get` buffer.
+    struct EnvExecutor;
+
+    impl Executor for EnvExecutor {
+        type Error = Infallible;
+
+        fn execute(&self, _module: &[u8], _params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+            Ok(vec![])
+        }
+
+        fn execute_with_env(
+            &self,
+            _module: &[u8],
+            _params: Vec<Type>,
+            env: &[(String, String)],
+        ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+            Ok((vec![Type::Bytes(crate::env::Environ::new(env).buf().to_vec())], ExecutionStats::default()))
+        }
+    }
+
+    // Answers with the names and binaries of the libraries it was handed, one after the other.
+    struct LinkExecutor;
+
+    impl Executor for LinkExecutor {
+        type Error = Infallible;
+
+        fn execute(&self, _module: &[u8], _params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+            Ok(vec![])
+        }
+
+        fn execute_linked(
+            &self,
+            _module: &[u8],
+            libraries: &[(String, Vec<u8>)],
+            _params: Vec<Type>,
+            _env: &[(String, String)],
+        ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+            let linked = libraries.iter().flat_map(|(name, binary)| name.bytes().chain(binary.iter().copied()));
+            Ok((vec![Type::Bytes(linked.collect())], ExecutionStats::default()))
+        }
+    }
+
+    // Saves the state it resumed from with one more byte, then answers with what it saved.
+    struct CheckpointExecutor;
+
+    impl Executor for CheckpointExecutor {
+        type Error = Infallible;
+
+        fn execute(&self, _module: &[u8], _params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+            Ok(vec![])
+        }
+
+        fn execute_resumable(
+            &self,
+            _module: &[u8],
+            _libraries: &[(String, Vec<u8>)],
+            _params: Vec<Type>,
+            _env: &[(String, String)],
+            checkpoint: Option<&[u8]>,
+            save: &mut dyn FnMut(Vec<u8>),
+        ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+            let mut state = checkpoint.map(<[u8]>::to_vec).unwrap_or_default();
+            state.push(state.len() as u8);
+            save(state.clone());
+            Ok((vec![Type::Bytes(state)], ExecutionStats::default()))
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct MockPower(Rc<RefCell<Vec<(bool, u64)>>>);
+
+    impl PowerManager for MockPower {
+        fn light_sleep(&mut self, duration: Duration) {
+            self.0.borrow_mut().push((false, duration.as_nanos() as u64));
+        }
+
+        fn deep_sleep(&mut self, until: u64) {
+            self.0.borrow_mut().push((true, until));
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct MemorySink {
+        image: Rc<RefCell<Vec<u8>>>,
+        rebooted: Rc<Cell<bool>>,
+    }
+
+    impl FirmwareSink for MemorySink {
+        fn begin(&mut self, _version: &str, size: usize) -> Result<(), Error> {
+            *self.image.borrow_mut() = vec![0; size];
+            Ok(())
+        }
+
+        fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+            self.image.borrow_mut()[offset..offset + data.len()].copy_from_slice(data);
+            Ok(())
+        }
+
+        fn finish(&mut self, checksum: u32) -> Result<(), Error> {
+            match Checksum::of(&self.image.borrow()) == checksum {
+                true => Ok(()),
+                false => Err(Error::Firmware("checksum mismatch".into())),
+            }
+        }
+
+        fn reboot(&mut self) {
+            self.rebooted.set(true);
+        }
+    }
+
+    impl MockTransport {
+        fn deliver(&self, message: &Message) {
+            self.inbound.borrow_mut().extend_from_slice(&message.encode().unwrap());
+        }
+
+        fn sent(&self) -> Vec<Message> {
+            let data = core::mem::take(&mut *self.outbound.borrow_mut());
+            let mut messages = Vec::new();
+            let mut offset = 0;
+            while let Ok((message, consumed)) = Message::decode(&data[offset..]) {
+                messages.push(message);
+                offset += consumed;
+            }
+            messages
+        }
+    }
+
+    fn secs(secs: u64) -> u64 {
+        Duration::from_secs(secs).as_nanos() as u64
+    }
+
+    #[test]
+    fn test_step() {
+        let transport = MockTransport::default();
+        let now = Rc::new(Cell::new(Duration::from_secs(100).as_nanos() as u64));
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(now.clone()), 4096);
+
+        // ClientReady goes out first, the heartbeat it schedules is flushed on the next step.
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert_eq!(session.step().unwrap(), StepStatus::NeedsSleep(Session::<MockTransport, EchoExecutor, MockClock>::HEARTBEAT_INTERVAL));
+
+        now.set(now.get() + Duration::from_secs(4).as_nanos() as u64);
+        assert_eq!(session.step().unwrap(), StepStatus::NeedsSleep(Duration::from_secs(6)));
+
+        let task = Message::ServerTask {
+            task_id: TaskId(1),
+            attempt: 2,
+            module: ModuleInfo {
+                name: "echo".into(),
+                size: 8,
+                chunk_size: 4,
+                total_chunks: 2,
+                pinned: false,
+            },
+            params: vec![Type::I32(7)],
+            env: vec![],
+            libraries: vec![],
+            priority: 1,
+            checkpoint: None,
+        };
+        transport.inbound.borrow_mut().extend_from_slice(&task.encode().unwrap());
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        // Waiting on chunks keeps the host polling instead of sleeping.
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert_eq!(session.step().unwrap(), StepStatus::Idle);
+
+        let chunk = Message::ServerModule {
+            task_id: TaskId(1),
+            chunk_index: 9,
+            chunk_data: vec![0; 4],
+        };
+        transport.inbound.borrow_mut().extend_from_slice(&chunk.encode().unwrap());
+        assert_eq!(session.step().unwrap(), StepStatus::Failed);
+        assert!(matches!(session.state, SessionState::Ready));
+        session.step().unwrap();
+        assert!(transport.sent().iter().any(|message| matches!(
+            message,
+            Message::ProtocolError { code: ProtocolErrorCode::Unexpected, .. }
+        )));
+    }
+
+    #[test]
+    fn test_submit_own_task() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        session.step().unwrap();
+        session.submit("echo", vec![Type::I32(7)], 1).unwrap();
+        transport.deliver(&Message::ServerSubmitted { task_id: Some(TaskId(3)) });
+        session.step().unwrap();
+
+        // Acknowledging the run this device made of its own submission leaves it waiting for the result.
+        transport.deliver(&Message::ServerAck {
+            task_id: TaskId(3),
+            success: true,
+        });
+        session.step().unwrap();
+        assert_eq!(session.take_remote_result(), None);
+
+        transport.deliver(&Message::ServerResult {
+            task_id: TaskId(3),
+            result: vec![Type::I32(7)],
+        });
+        session.step().unwrap();
+        assert_eq!(session.take_remote_result(), Some((TaskId(3), vec![Type::I32(7)])));
+    }
+
+    #[test]
+    fn test_protocol_error() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        session.step().unwrap();
+        transport.sent();
+
+        // The unknown variant is reported and skipped, the frames behind it still decode.
+        transport.inbound.borrow_mut().extend_from_slice(&[0, 2, 250, 0]);
+        transport.deliver(&Message::ServerUnpin { module: "echo".into() });
+        transport.deliver(&Message::ProtocolError {
+            code: ProtocolErrorCode::Unexpected,
+            detail: "stale ack".into(),
+        });
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert!(session.shared.borrow().incoming.is_empty());
+        session.step().unwrap();
+        let sent = transport.sent();
+        assert!(sent.iter().any(|message| matches!(
+            message,
+            Message::ProtocolError { code: ProtocolErrorCode::Malformed, .. }
+        )));
+        // A report from the server is only counted, never answered.
+        assert_eq!(sent.iter().filter(|message| matches!(message, Message::ProtocolError { .. })).count(), 1);
+        assert_eq!(session.snapshot().protocol_errors, 2);
+
+        // Only devices send heartbeats, one arriving from the server is refused.
+        transport.deliver(&Message::Heartbeat { timestamp: 0, cache: CacheStats::default() });
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().iter().any(|message| matches!(
+            message,
+            Message::ProtocolError { code: ProtocolErrorCode::Misdirected, .. }
+        )));
+        assert_eq!(session.snapshot().protocol_errors, 3);
+    }
+
+    #[test]
+    fn test_protocol_options() {
+        let transport = MockTransport::default();
+        let options = ProtocolOptions { max_payload: 64, ..Default::default() };
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096)
+            .with_protocol_options(options);
+        session.step().unwrap();
+        transport.sent();
+
+        // Refused once its header arrives, the rest is dropped as it trickles in.
+        let large = Message::ServerUnpin { module: "a".repeat(200) }.encode().unwrap();
+        transport.inbound.borrow_mut().extend_from_slice(&large[..10]);
+        session.step().unwrap();
+        assert!(session.shared.borrow().incoming.is_empty());
+        transport.inbound.borrow_mut().extend_from_slice(&large[10..]);
+        transport.deliver(&Message::ProtocolError {
+            code: ProtocolErrorCode::Unexpected,
+            detail: "stale ack".into(),
+        });
+        session.step().unwrap();
+        session.step().unwrap();
+        assert_eq!(session.shared.borrow().discard, 0);
+        assert!(session.shared.borrow().incoming.is_empty());
+
+        let sent = transport.sent();
+        assert_eq!(sent.iter().filter(|message| matches!(message, Message::ProtocolError { .. })).count(), 1);
+        assert!(sent.iter().any(|message| matches!(
+            message,
+            Message::ProtocolError { code: ProtocolErrorCode::TooLarge, .. }
+        )));
+        // The report from the server behind the refused frame still counts.
+        assert_eq!(session.snapshot().protocol_errors, 2);
+    }
+
+    #[test]
+    fn test_power_manager() {
+        let transport = MockTransport::default();
+        let power = MockPower::default();
+        let now = Rc::new(Cell::new(secs(100)));
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(now.clone()), 4096)
+            .with_power_manager(power.clone(), Duration::from_secs(2), Some(Duration::from_secs(30)));
+
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert_eq!(session.step().unwrap(), StepStatus::NeedsSleep(Duration::from_secs(10)));
+        assert!(power.0.borrow().is_empty());
+
+        // Past the light threshold the session sleeps itself until the heartbeat deadline.
+        now.set(secs(103));
+        assert_eq!(session.step().unwrap(), StepStatus::Idle);
+        assert_eq!(power.0.borrow_mut().pop(), Some((false, secs(7))));
+
+        // Heartbeats alone do not end the idle stretch.
+        now.set(secs(135));
+        assert_eq!(session.step().unwrap(), StepStatus::Idle);
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert_eq!(session.step().unwrap(), StepStatus::Idle);
+        assert_eq!(power.0.borrow_mut().pop(), Some((true, secs(145))));
+
+        let unpin = Message::ServerUnpin { module: "none".into() };
+        transport.inbound.borrow_mut().extend_from_slice(&unpin.encode().unwrap());
+        assert_eq!(session.step().unwrap(), StepStatus::Progress);
+        assert_eq!(session.step().unwrap(), StepStatus::NeedsSleep(Duration::from_secs(10)));
+        assert!(power.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_firmware_update() {
+        let transport = MockTransport::default();
+        let sink = MemorySink::default();
+        let now = Rc::new(Cell::new(secs(100)));
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(now), 4096)
+            .with_firmware_sink(sink.clone());
+        session.step().unwrap();
+        session.step().unwrap();
+        transport.sent();
+
+        let image = (0..100u8).collect::<Vec<_>>();
+        for (task_id, checksum) in [(1, 0), (2, Checksum::of(&image))] {
+            let task_id = TaskId(task_id);
+            transport.deliver(&Message::ServerFirmware {
+                task_id,
+                firmware: FirmwareInfo {
+                    version: "2.0.0".into(),
+                    size: image.len() as u64,
+                    chunk_size: 64,
+                    total_chunks: 2,
+                    checksum,
+                },
+            });
+            for (index, chunk) in image.chunks(64).enumerate() {
+                transport.deliver(&Message::ServerModule {
+                    task_id,
+                    chunk_index: index as u32,
+                    chunk_data: chunk.to_vec(),
+                });
+            }
+            session.step().unwrap();
+            session.step().unwrap();
+
+            let verified = checksum != 0;
+            assert_eq!(transport.sent(), vec![
+                Message::ClientAck { task_id, ack_info: AckInfo::TaskAck { accepted: true } },
+                Message::ClientAck { task_id, ack_info: AckInfo::ChunkAck { chunk_index: 0, success: true } },
+                Message::ClientAck { task_id, ack_info: AckInfo::ChunkAck { chunk_index: 1, success: true } },
+                Message::ClientAck { task_id, ack_info: AckInfo::FirmwareAck { verified } },
+            ]);
+            // The reboot follows the flushed ack, a corrupt image never gets that far.
+            assert_eq!(sink.rebooted.get(), verified);
+        }
+        assert_eq!(*sink.image.borrow(), image);
+    }
+
+    #[test]
+    fn test_log_forwarding() {
+        let transport = MockTransport::default();
+        let forwarder: &'static LogForwarder = Box::leak(Box::new(LogForwarder::new(16, log::LevelFilter::Info)));
+        let now = Rc::new(Cell::new(secs(100)));
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(now), 4096)
+            .with_log_forwarder(forwarder);
+        session.step().unwrap();
+        transport.sent();
+
+        forwarder.log(
+            &log::Record::builder()
+                .level(log::Level::Warn)
+                .target("sensor")
+                .args(format_args!("calibration drift {}", 3))
+                .build(),
+        );
+        session.step().unwrap();
+        assert!(transport.sent().contains(&Message::ClientLog {
+            level: protocol::LogLevel::Warn,
+            module: "sensor".into(),
+            message: "calibration drift 3".into(),
+            timestamp: secs(100),
+        }));
+    }
+
+    struct FixedProbe;
+
+    impl TelemetryProbe for FixedProbe {
+        fn free_heap(&self) -> Option<u64> {
+            Some(50_000)
+        }
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let transport = MockTransport::default();
+        let now = Rc::new(Cell::new(secs(100)));
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(now.clone()), 4096)
+            .with_telemetry_probe(FixedProbe);
+        now.set(secs(190));
+        session.step().unwrap();
+        session.step().unwrap();
+
+        let telemetry = transport.sent().into_iter().find_map(|message| match message {
+            Message::ClientTelemetry { telemetry, .. } => Some(telemetry),
+            _ => None,
+        });
+        let telemetry = telemetry.unwrap();
+        assert_eq!(telemetry, Telemetry {
+            free_heap: Some(50_000),
+            tasks_executed: 0,
+            uptime_secs: 90,
+            rssi: None,
+            sequence: None,
+            session: telemetry.session,
+        });
+        // Only the ClientReady ahead of it had gone out.
+        assert_eq!((telemetry.session.frames_in, telemetry.session.frames_out), (0, 1));
+        assert!(telemetry.session.bytes_out > 0);
+    }
+
+    struct CountingWatchdog(Rc<Cell<u32>>);
+
+    impl Watchdog for CountingWatchdog {
+        fn feed(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn test_watchdog_fed_each_step() {
+        let feeds = Rc::new(Cell::new(0));
+        let mut session = Session::new(MockTransport::default(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096)
+            .with_watchdog(CountingWatchdog(feeds.clone()));
+        for _ in 0..3 {
+            session.step().unwrap();
+        }
+        assert_eq!(feeds.get(), 3);
+    }
+
+    #[test]
+    fn test_session_stats() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        session.step().unwrap();
+        transport.sent();
+
+        let task = |task_id| Message::ServerTask {
+            task_id: TaskId(task_id),
+            attempt: 1,
+            module: ModuleInfo {
+                name: "echo".into(),
+                size: 4,
+                chunk_size: 4,
+                total_chunks: 1,
+                pinned: false,
+            },
+            params: vec![Type::I32(task_id as i32)],
+            env: vec![],
+            libraries: vec![],
+            priority: 1,
+            checkpoint: None,
+        };
+        transport.deliver(&task(1));
+        transport.deliver(&Message::ServerModule {
+            task_id: TaskId(1),
+            chunk_index: 0,
+            chunk_data: vec![0; 4],
+        });
+        for _ in 0..4 {
+            session.step().unwrap();
+        }
+        // The second task finds the module cached.
+        transport.deliver(&task(2));
+        transport.inbound.borrow_mut().extend_from_slice(&[0, 2, 250, 0]);
+        for _ in 0..4 {
+            session.step().unwrap();
+        }
+
+        let stats = session.stats();
+        assert_eq!(
+            (stats.frames_in, stats.decode_errors, stats.executions, stats.cache_hits),
+            (3, 1, 2, 1)
+        );
+        let sent = transport.sent();
+        assert_eq!(stats.frames_out, sent.len() as u64 + 1);
+        assert!(stats.bytes_in > 0 && stats.bytes_out > stats.frames_out);
+    }
+
+    #[test]
+    fn test_server_busy() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        transport.deliver(&Message::ServerBusy {
+            max_sessions: 64,
+            retry_after_secs: 5,
+        });
+        session.step().unwrap();
+        assert_eq!(session.take_busy(), Some(5));
+        assert_eq!(session.take_busy(), None);
+    }
+
+    #[test]
+    fn test_ping() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        transport.deliver(&Message::ServerPing { nonce: 42 });
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().contains(&Message::ClientPong { nonce: 42 }));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), CheckpointExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        session.step().unwrap();
+        transport.sent();
+
+        let task = |task_id, checkpoint| Message::ServerTask {
+            task_id: TaskId(task_id),
+            attempt: 2,
+            module: ModuleInfo {
+                name: "resume".into(),
+                size: 4,
+                chunk_size: 4,
+                total_chunks: 1,
+                pinned: false,
+            },
+            params: vec![],
+            env: vec![],
+            libraries: vec![],
+            priority: 1,
+            checkpoint,
+        };
+        transport.deliver(&task(1, Some(vec![0, 1])));
+        session.step().unwrap();
+        transport.deliver(&Message::ServerModule { task_id: TaskId(1), chunk_index: 0, chunk_data: vec![0; 4] });
+        session.step().unwrap();
+
+        // The checkpoint is written while the module runs, its result only on the next step.
+        let checkpoint = Message::ClientCheckpoint { task_id: TaskId(1), attempt: 2, data: vec![0, 1, 2] };
+        let sent = transport.sent();
+        assert!(sent.contains(&checkpoint));
+        assert!(!sent.iter().any(|message| matches!(message, Message::ClientResult { .. })));
+        session.step().unwrap();
+        assert!(transport.sent().iter().any(|message| matches!(
+            message,
+            Message::ClientResult { task_id: TaskId(1), result, .. } if *result == vec![Type::Bytes(vec![0, 1, 2])]
+        )));
+
+        // A task without a checkpoint starts over, a cached module runs right away.
+        transport.deliver(&task(2, None));
+        session.step().unwrap();
+        session.step().unwrap();
+        let sent = transport.sent();
+        assert!(sent.contains(&Message::ClientCheckpoint { task_id: TaskId(2), attempt: 2, data: vec![0] }));
+        assert!(sent.iter().any(|message| matches!(
+            message,
+            Message::ClientResult { task_id: TaskId(2), result, .. } if *result == vec![Type::Bytes(vec![0])]
+        )));
+    }
+
+    #[test]
+    fn test_task_queue() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096)
+            .with_task_queue(1);
+        let task = |task_id, priority| Message::ServerTask {
+            task_id: TaskId(task_id),
+            attempt: 1,
+            module: ModuleInfo {
+                name: "echo".into(),
+                size: 4,
+                chunk_size: 4,
+                total_chunks: 1,
+                pinned: false,
+            },
+            params: vec![Type::I32(task_id as i32)],
+            env: vec![],
+            libraries: vec![],
+            priority,
+            checkpoint: None,
+        };
+        for task_id in 1..=3 {
+            transport.deliver(&task(task_id, 1));
+        }
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().contains(&Message::ClientBusy { task_id: TaskId(3), queue_len: 1 }));
+
+        // A task of higher priority takes the place of the queued one.
+        transport.deliver(&task(4, 5));
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().contains(&Message::ClientBusy { task_id: TaskId(2), queue_len: 1 }));
+
+        // The queued task starts once the first one's module is in, and finds it cached.
+        transport.deliver(&Message::ServerModule {
+            task_id: TaskId(1),
+            chunk_index: 0,
+            chunk_data: vec![0; 4],
+        });
+        session.step().unwrap();
+        session.step().unwrap();
+        let results = transport
+            .sent()
+            .into_iter()
+            .filter_map(|message| match message {
+                Message::ClientResult { task_id, .. } => Some(task_id),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(results, vec![TaskId(1), TaskId(4)]);
+        assert_eq!(session.stats().cache_hits, 1);
+    }
+
+    #[test]
+    fn test_default_sleep() {
+        // Every reading moves the clock a millisecond, as if time passed while spinning.
+        struct TickingClock(Cell<u64>);
+
+        impl Clock for TickingClock {
+            fn timestamp(&self) -> u64 {
+                self.0.set(self.0.get() + Duration::from_millis(1).as_nanos() as u64);
+                self.0.get()
+            }
+        }
+
+        let clock = TickingClock(Cell::new(0));
+        clock.sleep(Duration::from_millis(5));
+        assert!(clock.0.get() >= Duration::from_millis(5).as_nanos() as u64);
+    }
+
+    #[test]
+    fn test_native_label() {
+        struct NativeExecutor;
+
+        impl Executor for NativeExecutor {
+            type Error = Infallible;
+
+            fn execute(&self, _module: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+                Ok(params)
+            }
+
+            fn native(&self) -> bool {
+                true
+            }
+        }
+
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), NativeExecutor, MockClock(Rc::new(Cell::new(0))), 4096)
+            .with_labels(&["gpu"]);
+        session.step().unwrap();
+        match transport.sent().first() {
+            Some(Message::ClientReady { labels, executor, .. }) => {
+                assert_eq!(labels, &vec!["gpu".to_string(), NATIVE_LABEL.to_string()]);
+                assert_eq!(*executor, ExecutorFlavor::Interpreter);
+            }
+            other => panic!("expected ClientReady, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        transport.deliver(&Message::ServerSession {
+            resume_token: 42,
+            resumed: false,
+        });
+        session.step().unwrap();
+        assert!(matches!(transport.sent().first(), Some(Message::ClientReady { resume_token: None, .. })));
+        assert_eq!(session.resume_token(), Some(42));
+
+        let fresh = MockTransport::default();
+        session.reconnect(fresh.clone());
+        session.step().unwrap();
+        assert!(matches!(fresh.sent().first(), Some(Message::ClientReady { resume_token: Some(42), .. })));
+        assert!(session.is_settled());
+
+        fresh.deliver(&Message::ServerPrefetch {
+            task_id: TaskId(5),
+            module: ModuleInfo {
+                name: "echo".into(),
+                size: 8,
+                chunk_size: 4,
+                total_chunks: 2,
+                pinned: false,
+            },
+        });
+        session.step().unwrap();
+        assert!(!session.is_settled());
+    }
+
+    #[test]
+    fn test_transfer_progress() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        transport.deliver(&Message::ServerTask {
+            task_id: TaskId(3),
+            attempt: 1,
+            module: ModuleInfo {
+                name: "echo".into(),
+                size: 80,
+                chunk_size: 4,
+                total_chunks: 20,
+                pinned: false,
+            },
+            params: vec![Type::I32(1)],
+            env: vec![],
+            libraries: vec![],
+            priority: 1,
+            checkpoint: None,
+        });
+        session.step().unwrap();
+        for chunk_index in 0..20 {
+            transport.deliver(&Message::ServerModule {
+                task_id: TaskId(3),
+                chunk_index,
+                chunk_data: vec![0; 4],
+            });
+            session.step().unwrap();
+        }
+        session.step().unwrap();
+
+        let sent = transport.sent();
+        let percents = sent
+            .iter()
+            .filter_map(|message| match message {
+                Message::ClientProgress { task_id: TaskId(3), percent, stage } if stage == "transfer" => Some(*percent),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(percents, vec![10, 20, 30, 40, 50, 60, 70, 80, 90]);
+        // The result answers the attempt the task arrived with.
+        assert!(sent.iter().any(|message| matches!(message, Message::ClientResult { task_id: TaskId(3), attempt: 1, .. })));
+    }
+
+    #[test]
+    fn test_cache_update() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        let prefetch = |task_id, name: &str| Message::ServerPrefetch {
+            task_id: TaskId(task_id),
+            module: ModuleInfo {
+                name: name.into(),
+                size: 40 * 1024,
+                chunk_size: 1024,
+                total_chunks: 40,
+                pinned: false,
+            },
+        };
+
+        transport.deliver(&prefetch(1, "blink"));
+        session.step().unwrap();
+        transport.deliver(&prefetch(2, "sum"));
+        session.step().unwrap();
+        session.step().unwrap();
+        let updates = transport
+            .sent()
+            .into_iter()
+            .filter(|message| matches!(message, Message::ClientCacheUpdate { .. }))
+            .collect::<Vec<_>>();
+        assert_eq!(updates, vec![Message::ClientCacheUpdate {
+            added: vec![],
+            removed: vec!["blink".into()],
+        }]);
+    }
+
+    #[test]
+    fn test_blob_params() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EchoExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        let blob = vec![1, 2, 3, 4, 5, 6, 7, 8];
+        transport.deliver(&Message::ServerPrefetch {
+            task_id: TaskId(4),
+            module: ModuleInfo {
+                name: protocol::blob_name(5),
+                size: 8,
+                chunk_size: 4,
+                total_chunks: 2,
+                pinned: false,
+            },
+        });
+        session.step().unwrap();
+        for (chunk_index, chunk_data) in blob.chunks(4).enumerate() {
+            transport.deliver(&Message::ServerModule {
+                task_id: TaskId(4),
+                chunk_index: chunk_index as u32,
+                chunk_data: chunk_data.to_vec(),
+            });
+            session.step().unwrap();
+        }
+
+        let task = |task_id, hash| Message::ServerTask {
+            task_id: TaskId(task_id),
+            attempt: 1,
+            module: ModuleInfo {
+                name: "echo".into(),
+                size: 4,
+                chunk_size: 4,
+                total_chunks: 1,
+                pinned: false,
+            },
+            params: vec![Type::I32(1), Type::BlobRef(5, 8, hash)],
+            env: vec![],
+            libraries: vec![],
+            priority: 1,
+            checkpoint: None,
+        };
+        transport.deliver(&task(4, Checksum::of(&blob)));
+        session.step().unwrap();
+        transport.deliver(&Message::ServerModule { task_id: TaskId(4), chunk_index: 0, chunk_data: vec![0; 4] });
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().iter().any(|message| matches!(
+            message,
+            Message::ClientResult { task_id: TaskId(4), result, .. } if *result == vec![Type::I32(1), Type::Bytes(blob.clone())]
+        )));
+
+        // A blob failing its checksum is dropped so the server sends it again.
+        transport.deliver(&task(6, 0));
+        session.step().unwrap();
+        session.step().unwrap();
+        let sent = transport.sent();
+        assert!(sent.contains(&Message::ClientAck { task_id: TaskId(6), ack_info: AckInfo::TaskAck { accepted: false } }));
+        assert!(sent.contains(&Message::ClientCacheUpdate { added: vec![], removed: vec![protocol::blob_name(5)] }));
+    }
+
+    #[test]
+    fn test_task_env() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), EnvExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        transport.deliver(&Message::ServerTask {
+            task_id: TaskId(3),
+            attempt: 1,
+            module: ModuleInfo {
+                name: "env".into(),
+                size: 4,
+                chunk_size: 4,
+                total_chunks: 1,
+                pinned: false,
+            },
+            params: vec![],
+            env: vec![("MODE".into(), "fast".into())],
+            libraries: vec![],
+            priority: 1,
+            checkpoint: None,
+        });
+        session.step().unwrap();
+        transport.deliver(&Message::ServerModule { task_id: TaskId(3), chunk_index: 0, chunk_data: vec![0; 4] });
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().iter().any(|message| matches!(
+            message,
+            Message::ClientResult { task_id: TaskId(3), result, .. } if *result == vec![Type::Bytes(b"MODE=fast\0".to_vec())]
+        )));
+    }
+
+    #[test]
+    fn test_task_libraries() {
+        let transport = MockTransport::default();
+        let mut session = Session::new(transport.clone(), LinkExecutor, MockClock(Rc::new(Cell::new(0))), 4096);
+        let library = |name: &str| ModuleInfo {
+            name: name.into(),
+            size: 4,
+            chunk_size: 4,
+            total_chunks: 1,
+            pinned: false,
+        };
+        transport.deliver(&Message::ServerPrefetch { task_id: TaskId(2), module: library("libm") });
+        session.step().unwrap();
+        transport.deliver(&Message::ServerModule { task_id: TaskId(2), chunk_index: 0, chunk_data: vec![9; 4] });
+        session.step().unwrap();
+
+        let task = |task_id, libraries| Message::ServerTask {
+            task_id: TaskId(task_id),
+            attempt: 1,
+            module: library("main"),
+            params: vec![],
+            env: vec![],
+            libraries,
+            priority: 1,
+            checkpoint: None,
+        };
+        transport.deliver(&task(3, vec![library("libm")]));
+        session.step().unwrap();
+        transport.deliver(&Message::ServerModule { task_id: TaskId(3), chunk_index: 0, chunk_data: vec![0; 4] });
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().iter().any(|message| matches!(
+            message,
+            Message::ClientResult { task_id: TaskId(3), result, .. } if *result == vec![Type::Bytes(b"libm\x09\x09\x09\x09".to_vec())]
+        )));
+
+        // A library the device does not hold is refused like a missing blob.
+        transport.deliver(&task(4, vec![library("libz")]));
+        session.step().unwrap();
+        session.step().unwrap();
+        assert!(transport.sent().contains(&Message::ClientAck {
+            task_id: TaskId(4),
+            ack_info: AckInfo::TaskAck { accepted: false },
+        }));
+    }
+
+    #[test]
+    fn test_sequence_numbers() {
+        let transport = MockTransport::default();
+        let now = Rc::new(Cell::new(secs(100)));
+        let mut session =
+            Session::new(transport.clone(), EchoExecutor, MockClock(now.clone()), 4096).with_sequence_numbers();
+        session.step().unwrap();
+        let data = core::mem::take(&mut *transport.outbound.borrow_mut());
+        let (message, sequence, _) = Message::decode_sequenced(&data).unwrap();
+        assert!(matches!((message, sequence), (Message::ClientReady { .. }, Some(0))));
+
+        // A repeated frame is handled once.
+        for sequence in [0, 0, 1] {
+            let unpin = Message::ServerUnpin { module: "echo".into() };
+            transport.inbound.borrow_mut().extend_from_slice(&unpin.encode_sequenced(sequence).unwrap());
+        }
+        session.step().unwrap();
+        now.set(secs(200));
+        session.step().unwrap();
+        session.step().unwrap();
+
+        let mut telemetry = transport.sent().into_iter().filter_map(|message| match message {
+            Message::ClientTelemetry { telemetry, .. } => Some(telemetry),
+            _ => None,
+        });
+        assert_eq!(telemetry.next_back().unwrap().sequence, Some(SequenceStats {
+            received: 2,
+            duplicates: 1,
+            reordered: 0,
+            missing: 0,
+        }));
     }
 }
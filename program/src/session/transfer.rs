@@ -1,10 +1,27 @@
 use alloc::string::String;
 
 use bitvec::vec::BitVec;
-use protocol::ModuleInfo;
+use protocol::{FirmwareInfo, ModuleInfo};
 
 use super::cache::ModuleCache;
-use crate::Error;
+use crate::{Error, FirmwareSink};
+
+// Destination of the chunks a transfer accepts.
+pub trait ChunkSink {
+    fn put_chunk(&mut self, name: &str, offset: usize, data: &[u8]) -> Result<(), Error>;
+}
+
+impl ChunkSink for ModuleCache {
+    fn put_chunk(&mut self, name: &str, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.put_slice(name, offset, data).map(|_| ())
+    }
+}
+
+impl ChunkSink for dyn FirmwareSink {
+    fn put_chunk(&mut self, _name: &str, offset: usize, data: &[u8]) -> Result<(), Error> {
+        self.write(offset, data)
+    }
+}
 
 pub struct ModuleTransfer {
     name: String,
@@ -27,6 +44,17 @@ impl ModuleTransfer {
         }
     }
 
+    // Firmware images are named by version, the name only shows up in logs.
+    pub fn firmware(meta: &FirmwareInfo) -> Self {
+        Self {
+            name: meta.version.clone(),
+            size: meta.size as usize,
+            chunk_size: meta.chunk_size as usize,
+            total_chunks: meta.total_chunks as usize,
+            received: BitVec::repeat(false, meta.total_chunks as usize),
+        }
+    }
+
     pub fn name(&self) -> &str {
         self.name.as_str()
     }
@@ -35,28 +63,38 @@ impl ModuleTransfer {
         self.received.all()
     }
 
+    // Chunks received so far and the total expected.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.received.count_ones(), self.total_chunks)
+    }
+
+    // `index` is the first chunk `data` covers, a server on a fast link merges consecutive chunks
+    // into one message.
     pub fn add_chunk(
         &mut self,
-        cache: &mut ModuleCache,
+        sink: &mut (impl ChunkSink + ?Sized),
         index: usize,
         data: &[u8],
     ) -> Result<(), Error> {
         if index >= self.total_chunks {
             return Err(Error::InvalidChunkIndex(index, self.total_chunks));
         }
-        if self.received[index] {
+
+        let chunks = data.len().div_ceil(self.chunk_size.max(1)).max(1);
+        let end = (index + chunks).min(self.total_chunks);
+        if self.received[index..end].any() {
             return Err(Error::DuplicateChunk(index));
         }
 
-        let expected_size = if index == self.total_chunks - 1 {
-            self.size - self.chunk_size * (self.total_chunks - 1)
+        let expected_size = if end == self.total_chunks {
+            self.size - self.chunk_size * index
         } else {
-            self.chunk_size
+            self.chunk_size * chunks
         };
 
         if data.len() == expected_size {
-            cache.put_slice(&self.name, index * self.chunk_size, data)?;
-            self.received.set(index, true);
+            sink.put_chunk(&self.name, index * self.chunk_size, data)?;
+            self.received[index..end].fill(true);
 
             log::debug!(
                 "Received chunk {} ({}B) for '{}' [{}/{}]",
@@ -86,6 +124,7 @@ mod tests {
             size: (3 * 1024 + 512) as u64,
             chunk_size: 1024,
             total_chunks: 4,
+            pinned: false,
         };
         let mut cache = ModuleCache::new(4096);
         let mut transfer = ModuleTransfer::new(&meta);
@@ -116,6 +155,7 @@ mod tests {
             size: (2 * 1024 + 512) as u64,
             chunk_size: 1024,
             total_chunks: 3,
+            pinned: false,
         };
         let mut cache = ModuleCache::new(4096);
         let mut transfer = ModuleTransfer::new(&meta);
@@ -132,6 +172,32 @@ mod tests {
         assert_eq!(&assembled[2048..], &vec![2u8; 512][..]);
     }
 
+    #[test]
+    fn test_merged_chunks() {
+        let meta = ModuleInfo {
+            name: String::from("test"),
+            size: (3 * 1024 + 512) as u64,
+            chunk_size: 1024,
+            total_chunks: 4,
+            pinned: false,
+        };
+        let mut cache = ModuleCache::new(4096);
+        let mut transfer = ModuleTransfer::new(&meta);
+
+        cache.put(&meta.name, meta.size as usize).unwrap();
+        transfer.add_chunk(&mut cache, 0, &vec![0u8; 2048]).unwrap();
+        assert_eq!(transfer.progress(), (2, 4));
+        // A merged chunk must end on a chunk boundary or at the end of the module.
+        assert!(transfer.add_chunk(&mut cache, 2, &vec![2u8; 1536 - 1]).is_err());
+        assert!(transfer.add_chunk(&mut cache, 1, &vec![1u8; 2048]).is_err());
+        transfer.add_chunk(&mut cache, 2, &vec![2u8; 1536]).unwrap();
+        assert!(transfer.is_complete());
+
+        let assembled = cache.get("test").unwrap();
+        assert!(assembled[..2048].iter().all(|&b| b == 0));
+        assert!(assembled[2048..].iter().all(|&b| b == 2));
+    }
+
     #[test]
     fn test_invalid_chunk() {
         let meta = ModuleInfo {
@@ -139,6 +205,7 @@ mod tests {
             size: 1024,
             chunk_size: 1024,
             total_chunks: 1,
+            pinned: false,
         };
         let mut cache = ModuleCache::new(4096);
         let mut transfer = ModuleTransfer::new(&meta);
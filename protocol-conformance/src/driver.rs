@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+use protocol::{Error, ExecutionStats, Message, TaskId};
+
+use crate::scripts::{Script, Step};
+
+// How often the peer gets a turn before an expected message counts as missing.
+const MAX_POLLS: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    // The device, program::Session in this tree.
+    Client,
+    Server,
+}
+
+// The implementation under test, seen from the side the driver plays.
+pub trait Peer {
+    // Frames the driver sent, for the implementation to read on its next turn.
+    fn deliver(&mut self, frames: &[u8]);
+
+    // Gives the implementation a turn, returning the frames it wrote meanwhile.
+    fn poll(&mut self) -> Vec<u8>;
+
+    // Lets `by` pass without a frame from the driver.
+    fn advance(&mut self, by: Duration);
+
+    // Called ahead of each message the implementation is expected to send. Messages an application
+    // rather than the protocol triggers, such as ClientSubmit, are sent from here.
+    fn prompt(&mut self, _message: &Message) {}
+}
+
+#[derive(Debug)]
+pub struct Mismatch {
+    pub script: &'static str,
+    pub step: usize,
+    pub expected: Message,
+    // `None` when the peer sent nothing more, or nothing that decodes.
+    pub received: Option<Message>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "script {} step {}: expected {:?}, received {:?}",
+            self.script, self.step, self.expected, self.received
+        )
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+// Plays every step of `script` sent by the side opposite `side` and checks the steps sent by
+// `side` against what `peer` writes. Heartbeats, telemetry and logs may come in between.
+pub fn run(script: &Script, side: Side, peer: &mut impl Peer) -> Result<(), Box<Mismatch>> {
+    let mut driver = Driver {
+        peer,
+        incoming: Vec::new(),
+        task_ids: HashMap::new(),
+    };
+    for (index, step) in script.steps.iter().enumerate() {
+        match step {
+            Step::Client(message) | Step::Server(message) if step.sender() == Some(side) => {
+                driver.peer.prompt(message);
+                let received = driver.receive();
+                if !received.as_ref().is_some_and(|received| driver.matches(message, received)) {
+                    return Err(Box::new(Mismatch {
+                        script: script.name,
+                        step: index,
+                        expected: message.clone(),
+                        received,
+                    }));
+                }
+            }
+            Step::Client(message) | Step::Server(message) => driver.send(message),
+            Step::Idle(duration) => driver.peer.advance(*duration),
+        }
+    }
+    Ok(())
+}
+
+struct Driver<'a, P> {
+    peer: &'a mut P,
+    incoming: Vec<u8>,
+    // Ids the script uses mapped to the ones the implementation picked, taken from the first
+    // message that carries them.
+    task_ids: HashMap<TaskId, TaskId>,
+}
+
+impl<P: Peer> Driver<'_, P> {
+    fn send(&mut self, message: &Message) {
+        let mut message = message.clone();
+        if let Some(task_id) = task_id(&mut message) {
+            *task_id = *self.task_ids.entry(*task_id).or_insert(*task_id);
+        }
+        self.peer.deliver(&message.encode().expect("script message does not encode"));
+    }
+
+    // Next message the peer wrote that a script can expect.
+    fn receive(&mut self) -> Option<Message> {
+        let mut polls = 0;
+        loop {
+            match Message::decode(&self.incoming) {
+                Ok((message, consumed)) => {
+                    self.incoming.drain(..consumed);
+                    if !background(&message) {
+                        return Some(message);
+                    }
+                }
+                Err(Error::InsufficientData) if polls < MAX_POLLS => {
+                    polls += 1;
+                    let written = self.peer.poll();
+                    self.incoming.extend(written);
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+
+    fn matches(&mut self, expected: &Message, received: &Message) -> bool {
+        let (mut expected, mut received) = (expected.clone(), received.clone());
+        if let (Some(script_id), Some(actual)) = (task_id(&mut expected), task_id(&mut received)) {
+            *script_id = *self.task_ids.entry(*script_id).or_insert(*actual);
+        }
+        normalize(expected) == normalize(received)
+    }
+}
+
+// Sent on timers of their own, no script can tell when they show up.
+fn background(message: &Message) -> bool {
+    matches!(message, Message::Heartbeat { .. } | Message::ClientTelemetry { .. } | Message::ClientLog { .. })
+}
+
+fn task_id(message: &mut Message) -> Option<&mut TaskId> {
+    match message {
+        Message::ServerTask { task_id, .. }
+        | Message::ServerModule { task_id, .. }
+        | Message::ClientAck { task_id, .. }
+        | Message::ClientResult { task_id, .. }
+        | Message::ServerAck { task_id, .. }
+        | Message::ServerSubmitted { task_id: Some(task_id) }
+        | Message::ServerResult { task_id, .. }
+        | Message::ServerCancel { task_id }
+        | Message::ServerPrefetch { task_id, .. }
+        | Message::ServerFirmware { task_id, .. }
+        | Message::ClientProgress { task_id, .. }
+        | Message::ClientBusy { task_id, .. }
+        | Message::ClientCheckpoint { task_id, .. } => Some(task_id),
+        _ => None,
+    }
+}
+
+// Clears what differs between conforming implementations, or between runs of the same one.
+fn normalize(mut message: Message) -> Message {
+    match &mut message {
+        Message::ClientReady { arch, .. } => arch.clear(),
+        Message::ClientResult { stats, .. } => *stats = ExecutionStats::default(),
+        Message::ServerSession { resume_token, .. } => *resume_token = 0,
+        Message::ProtocolError { detail, .. } => detail.clear(),
+        _ => {}
+    }
+    message
+}
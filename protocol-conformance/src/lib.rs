@@ -0,0 +1,16 @@
+// What any implementation of the device protocol has to agree on, split in two:
+//
+// - `vectors.json` holds one golden frame per Message variant in the default encoding. Other
+//   implementations, such as a browser worker or Python tooling, decode each `frame` and compare it
+//   with `message`, then encode `message` and compare it with `frame`.
+// - `scripts` are exchanges between a device and the server. `run` plays one side of a script
+//   against a `Peer` wrapping the implementation of the other side and checks what it answers.
+//   program::Session and the server systems both run every script, see the tests.
+
+mod driver;
+mod scripts;
+mod vectors;
+
+pub use driver::{run, Mismatch, Peer, Side};
+pub use scripts::{scripts, Script, Step, DEVICE_RAM};
+pub use vectors::{hex, unhex, vectors, Vector, VECTORS};
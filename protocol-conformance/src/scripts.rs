@@ -0,0 +1,205 @@
+use std::time::Duration;
+
+use protocol::{AckInfo, ExecutionStats, ExecutorFlavor, Message, ModuleInfo, ProtocolErrorCode, TaskId, Type};
+
+use crate::driver::Side;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Client(Message),
+    Server(Message),
+    // Neither side sends anything for this long.
+    Idle(Duration),
+}
+
+impl Step {
+    pub fn sender(&self) -> Option<Side> {
+        match self {
+            Self::Client(_) => Some(Side::Client),
+            Self::Server(_) => Some(Side::Server),
+            Self::Idle(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Script {
+    pub name: &'static str,
+    // Modules the server holds before the device connects, transferred in `chunk_size` chunks.
+    pub modules: Vec<(String, Vec<u8>)>,
+    pub chunk_size: u32,
+    // Side the script is meant for, when it has the other side send what it never would.
+    pub only: Option<Side>,
+    pub steps: Vec<Step>,
+}
+
+impl Script {
+    fn new(name: &'static str, steps: Vec<Step>) -> Self {
+        Self {
+            name,
+            modules: Vec::new(),
+            chunk_size: 1024,
+            only: None,
+            steps,
+        }
+    }
+
+    pub fn applies_to(&self, side: Side) -> bool {
+        self.only.is_none_or(|only| only == side)
+    }
+}
+
+// Device RAM every script's device announces.
+pub const DEVICE_RAM: u64 = 64 * 1024;
+
+fn handshake() -> Vec<Step> {
+    vec![
+        Step::Client(Message::ClientReady {
+            modules: vec![],
+            device_ram: DEVICE_RAM,
+            labels: vec![],
+            executor: ExecutorFlavor::Interpreter,
+            arch: String::new(),
+            resume_token: None,
+        }),
+        Step::Server(Message::ServerSession {
+            resume_token: 0,
+            resumed: false,
+        }),
+    ]
+}
+
+pub fn scripts() -> Vec<Script> {
+    let task_id = TaskId(1);
+    let echo = b"echo-module".to_vec();
+    let params = vec![Type::I32(7), Type::Bytes(vec![1, 2, 3])];
+    let chunk = |chunk_index: u32| Message::ServerModule {
+        task_id,
+        chunk_index,
+        chunk_data: echo[chunk_index as usize * 8..echo.len().min((chunk_index as usize + 1) * 8)].to_vec(),
+    };
+    let chunk_ack = |chunk_index| Message::ClientAck {
+        task_id,
+        ack_info: AckInfo::ChunkAck {
+            chunk_index,
+            success: true,
+        },
+    };
+
+    vec![
+        Script::new("handshake", handshake()),
+        // A silent device is pinged once the server's idle timeout passed.
+        Script::new("ping", [
+            handshake(),
+            vec![
+                Step::Idle(Duration::from_secs(17)),
+                Step::Server(Message::ServerPing { nonce: 0 }),
+                Step::Client(Message::ClientPong { nonce: 0 }),
+            ],
+        ]
+        .concat()),
+        Script::new("submit_unknown", [
+            handshake(),
+            vec![
+                Step::Client(Message::ClientSubmit {
+                    module_name: "missing".into(),
+                    params: vec![],
+                    priority: 1,
+                }),
+                Step::Server(Message::ServerSubmitted { task_id: None }),
+            ],
+        ]
+        .concat()),
+        // The device submits a task and, being the only one around, runs it as well.
+        Script {
+            modules: vec![("echo".into(), echo.clone())],
+            chunk_size: 8,
+            ..Script::new("submit_and_execute", [
+                handshake(),
+                vec![
+                    Step::Client(Message::ClientSubmit {
+                        module_name: "echo".into(),
+                        params: params.clone(),
+                        priority: 1,
+                    }),
+                    Step::Server(Message::ServerSubmitted { task_id: Some(task_id) }),
+                    Step::Server(Message::ServerTask {
+                        task_id,
+                        attempt: 1,
+                        module: ModuleInfo {
+                            name: "echo".into(),
+                            size: echo.len() as u64,
+                            chunk_size: 8,
+                            total_chunks: 2,
+                            pinned: false,
+                        },
+                        params: params.clone(),
+                        env: vec![],
+                        libraries: vec![],
+                        priority: 1,
+                        checkpoint: None,
+                    }),
+                    Step::Client(Message::ClientAck {
+                        task_id,
+                        ack_info: AckInfo::ModuleListAck { modules: vec![] },
+                    }),
+                    Step::Server(chunk(0)),
+                    Step::Client(chunk_ack(0)),
+                    Step::Client(Message::ClientProgress {
+                        task_id,
+                        percent: 50,
+                        stage: "transfer".into(),
+                    }),
+                    Step::Server(chunk(1)),
+                    Step::Client(chunk_ack(1)),
+                    Step::Client(Message::ClientResult {
+                        task_id,
+                        attempt: 1,
+                        result: params.clone(),
+                        stats: ExecutionStats::default(),
+                    }),
+                    Step::Server(Message::ServerAck { task_id, success: true }),
+                    Step::Server(Message::ServerResult { task_id, result: params }),
+                ],
+            ]
+            .concat())
+        },
+        // A frame only the server sends is refused, the connection carries on.
+        Script {
+            only: Some(Side::Server),
+            ..Script::new("misdirected", [
+                handshake(),
+                vec![
+                    Step::Client(Message::ServerCancel { task_id }),
+                    Step::Server(Message::ProtocolError {
+                        code: ProtocolErrorCode::Misdirected,
+                        detail: String::new(),
+                    }),
+                    Step::Client(Message::ClientSubmit {
+                        module_name: "missing".into(),
+                        params: vec![],
+                        priority: 1,
+                    }),
+                    Step::Server(Message::ServerSubmitted { task_id: None }),
+                ],
+            ]
+            .concat())
+        },
+        Script {
+            only: Some(Side::Client),
+            ..Script::new("misdirected", [
+                handshake(),
+                vec![
+                    Step::Server(Message::ClientPong { nonce: 3 }),
+                    Step::Client(Message::ProtocolError {
+                        code: ProtocolErrorCode::Misdirected,
+                        detail: String::new(),
+                    }),
+                    Step::Server(Message::ServerPing { nonce: 4 }),
+                    Step::Client(Message::ClientPong { nonce: 4 }),
+                ],
+            ]
+            .concat())
+        },
+    ]
+}
@@ -0,0 +1,59 @@
+use std::fmt::Write;
+
+use protocol::Message;
+use serde_json::Value;
+
+// `message` is the JSON form of `Message::encode_json`, `frame` the bytes on the wire in hex.
+pub const VECTORS: &str = include_str!("../vectors.json");
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Vector {
+    // Name of the variant the vector covers, as listed by `protocol::schema`.
+    pub name: String,
+    pub message: Message,
+    pub frame: Vec<u8>,
+}
+
+// Panics on a malformed `vectors.json`, it ships with the crate.
+pub fn vectors() -> Vec<Vector> {
+    let entries: Vec<Value> = serde_json::from_str(VECTORS).expect("vectors.json is not a JSON array");
+    entries
+        .into_iter()
+        .map(|entry| Vector {
+            name: entry["name"].as_str().expect("vector without a name").to_string(),
+            message: serde_json::from_value(entry["message"].clone()).expect("vector with a malformed message"),
+            frame: unhex(entry["frame"].as_str().expect("vector without a frame")).expect("vector with a malformed frame"),
+        })
+        .collect()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        write!(out, "{:02x}", byte).ok();
+        out
+    })
+}
+
+// `None` for an odd length or a character that is not a hex digit.
+pub fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex(&[0, 0x7f, 0xff]), "007fff");
+        assert_eq!(unhex("007fFF"), Some(vec![0, 0x7f, 0xff]));
+        assert_eq!(unhex("0"), None);
+        assert_eq!(unhex("0g"), None);
+    }
+}
@@ -0,0 +1,97 @@
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+use std::rc::Rc;
+use std::time::Duration;
+
+use program::{Buf, BufMut, Clock, Executor, Session, Transport, Type};
+use protocol::Message;
+use protocol_conformance::{run, scripts, Peer, Side, DEVICE_RAM};
+
+#[derive(Clone, Default)]
+struct MemoryTransport {
+    inbound: Rc<RefCell<Vec<u8>>>,
+    outbound: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Transport for MemoryTransport {
+    type Error = Infallible;
+
+    fn read<B>(&mut self, buf: &mut B) -> Result<usize, Self::Error>
+    where
+        B: BufMut + ?Sized,
+    {
+        let data = std::mem::take(&mut *self.inbound.borrow_mut());
+        buf.put_slice(&data);
+        Ok(data.len())
+    }
+
+    fn write<B>(&mut self, src: &mut B) -> Result<usize, Self::Error>
+    where
+        B: Buf,
+    {
+        let len = src.remaining();
+        self.outbound.borrow_mut().extend_from_slice(src.chunk());
+        Ok(len)
+    }
+}
+
+struct ManualClock(Rc<Cell<u64>>);
+
+impl Clock for ManualClock {
+    fn timestamp(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+struct EchoExecutor;
+
+impl Executor for EchoExecutor {
+    type Error = Infallible;
+
+    fn execute(&self, _module: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        Ok(params)
+    }
+}
+
+struct ProgramPeer {
+    session: Session<MemoryTransport, EchoExecutor, ManualClock>,
+    transport: MemoryTransport,
+    now: Rc<Cell<u64>>,
+}
+
+impl ProgramPeer {
+    fn new() -> Self {
+        let transport = MemoryTransport::default();
+        let now = Rc::new(Cell::new(Duration::from_secs(100).as_nanos() as u64));
+        let session = Session::new(transport.clone(), EchoExecutor, ManualClock(now.clone()), DEVICE_RAM);
+        Self { session, transport, now }
+    }
+}
+
+impl Peer for ProgramPeer {
+    fn deliver(&mut self, frames: &[u8]) {
+        self.transport.inbound.borrow_mut().extend_from_slice(frames);
+    }
+
+    fn poll(&mut self) -> Vec<u8> {
+        self.session.step().unwrap();
+        std::mem::take(&mut *self.transport.outbound.borrow_mut())
+    }
+
+    fn advance(&mut self, by: Duration) {
+        self.now.set(self.now.get() + by.as_nanos() as u64);
+    }
+
+    fn prompt(&mut self, message: &Message) {
+        if let Message::ClientSubmit { module_name, params, priority } = message {
+            self.session.submit(module_name, params.clone(), *priority).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_program_conformance() {
+    for script in scripts().iter().filter(|script| script.applies_to(Side::Client)) {
+        run(script, Side::Client, &mut ProgramPeer::new()).unwrap_or_else(|mismatch| panic!("{}", mismatch));
+    }
+}
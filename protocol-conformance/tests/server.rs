@@ -0,0 +1,79 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use hecs::World;
+use protocol_conformance::{run, scripts, Peer, Script, Side};
+use server::{DispatcherService, LifecycleSystem, Module, SessionHealth};
+use tokio::net::TcpListener;
+use tokio::runtime::Runtime;
+
+// The dispatcher's systems over a world holding a single session, the device end of its
+// connection is ours.
+struct ServerPeer {
+    runtime: Runtime,
+    world: World,
+    device: TcpStream,
+}
+
+impl ServerPeer {
+    fn new(script: &Script) -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let mut world = World::new();
+        for (name, binary) in &script.modules {
+            world.spawn((Module {
+                name: name.clone(),
+                binary: binary.clone(),
+                dependencies: vec![],
+                chunk_size: script.chunk_size,
+                pinned: false,
+            },));
+        }
+
+        let listener = runtime.block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+        let device = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        device.set_nonblocking(true).unwrap();
+        let (stream, addr) = runtime.block_on(listener.accept()).unwrap();
+        LifecycleSystem::accept_connection(&mut world, stream, addr);
+        Self { runtime, world, device }
+    }
+}
+
+impl Peer for ServerPeer {
+    fn deliver(&mut self, frames: &[u8]) {
+        self.device.write_all(frames).unwrap();
+    }
+
+    fn poll(&mut self) -> Vec<u8> {
+        let world = &mut self.world;
+        self.runtime.block_on(async {
+            DispatcherService::tick(world).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(2)).await;
+        });
+
+        let mut written = Vec::new();
+        let mut buffer = [0; 2048];
+        loop {
+            match self.device.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(n) => written.extend_from_slice(&buffer[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("reading from the server failed: {}", e),
+            }
+        }
+        written
+    }
+
+    fn advance(&mut self, by: Duration) {
+        for (_, health) in self.world.query_mut::<&mut SessionHealth>() {
+            health.last_heartbeat -= by;
+        }
+    }
+}
+
+#[test]
+fn test_server_conformance() {
+    for script in scripts().iter().filter(|script| script.applies_to(Side::Server)) {
+        run(script, Side::Server, &mut ServerPeer::new(script)).unwrap_or_else(|mismatch| panic!("{}", mismatch));
+    }
+}
@@ -0,0 +1,37 @@
+use protocol::schema::Shape;
+use protocol::{schema, Message};
+use protocol_conformance::{hex, vectors};
+
+#[test]
+fn test_vectors_cover_schema() {
+    let schema = schema();
+    let Shape::Enum(variants) = schema.definition(schema.root).unwrap().shape else {
+        panic!("root is not an enum");
+    };
+    let names: Vec<_> = vectors().into_iter().map(|vector| vector.name).collect();
+    let expected: Vec<_> = variants.iter().map(|variant| variant.name).collect();
+    assert_eq!(names, expected);
+}
+
+#[test]
+fn test_vectors_encode() {
+    for (index, vector) in vectors().into_iter().enumerate() {
+        let encoded = vector.message.encode().unwrap();
+        assert_eq!(hex(&encoded), hex(&vector.frame), "{} encodes differently", vector.name);
+        // The discriminant follows the length header, it is the variant's index in the schema.
+        assert_eq!(vector.frame[Message::HEADER_SIZE] as usize, index, "{}", vector.name);
+    }
+}
+
+#[test]
+fn test_vectors_decode() {
+    for vector in vectors() {
+        let (decoded, consumed) = Message::decode(&vector.frame).unwrap();
+        assert_eq!(decoded, vector.message, "{} decodes differently", vector.name);
+        assert_eq!(consumed, vector.frame.len());
+
+        let mut sequenced = Message::sequence_prefix(9).to_vec();
+        sequenced.extend_from_slice(&vector.frame);
+        assert_eq!(Message::decode_sequenced(&sequenced).unwrap(), (vector.message, Some(9), sequenced.len()));
+    }
+}
@@ -0,0 +1,182 @@
+# Reference encoder for the dispatcher protocol, driven by the schema from `protocol-schema`.
+#
+# Values follow serde's JSON layout: structs are dicts, unit variants are strings, other
+# variants are {"Name": fields} and newtypes such as TaskId are their inner value.
+import json
+import struct
+
+_FLOATS = {"f32": ">f", "f64": ">d"}
+_SIGNED = {"i32", "i64", "i128"}
+_UNSIGNED = {"u32", "u64"}
+
+
+def _varint(value):
+    if value < 251:
+        return bytes([value])
+    for tag, size in ((251, 2), (252, 4), (253, 8), (254, 16)):
+        if value < 1 << (size * 8):
+            return bytes([tag]) + value.to_bytes(size, "big")
+    raise ValueError(f"{value} does not fit in u128")
+
+
+def _zigzag(value):
+    return value * 2 if value >= 0 else -value * 2 - 1
+
+
+# Element types of a tuple type such as "(string, list<u8>)".
+def _elements(ty):
+    elements, depth, start = [], 0, 1
+    for i, char in enumerate(ty[1:-1], 1):
+        if char in "<(":
+            depth += 1
+        elif char in ">)":
+            depth -= 1
+        elif char == "," and depth == 0:
+            elements.append(ty[start:i].strip())
+            start = i + 1
+    elements.append(ty[start:-1].strip())
+    return elements
+
+
+class Schema:
+    def __init__(self, schema):
+        self.header_size = schema["encoding"]["header_size"]
+        self.sequence_size = schema["encoding"].get("sequence_size", 0)
+        self.root = schema["root"]
+        self.definitions = {definition["name"]: definition for definition in schema["definitions"]}
+
+    @classmethod
+    def load(cls, path):
+        with open(path) as file:
+            return cls(json.load(file))
+
+    def encode(self, message):
+        payload = self.encode_value(self.root, message)
+        if len(payload) >= 1 << (self.header_size * 8):
+            raise ValueError("message too large")
+        return len(payload).to_bytes(self.header_size, "big") + payload
+
+    # Returns the message and the number of bytes consumed, None while the frame is incomplete.
+    # The sequence number of a sequenced frame is skipped.
+    def decode(self, data):
+        start = 0
+        if self.sequence_size and len(data) >= self.header_size and not any(data[: self.header_size]):
+            start = self.header_size + self.sequence_size
+        if len(data) < start + self.header_size:
+            return None
+        size = int.from_bytes(data[start : start + self.header_size], "big")
+        end = start + self.header_size + size
+        if len(data) < end:
+            return None
+        value, offset = self.decode_value(self.root, data, start + self.header_size)
+        if offset != end:
+            raise ValueError("trailing bytes in frame")
+        return value, end
+
+    def encode_value(self, ty, value):
+        if ty == "bool":
+            return bytes([1 if value else 0])
+        if ty == "u8":
+            return bytes([value])
+        if ty == "i8":
+            return struct.pack(">b", value)
+        if ty in _UNSIGNED:
+            return _varint(value)
+        if ty in _SIGNED:
+            return _varint(_zigzag(value))
+        if ty in _FLOATS:
+            return struct.pack(_FLOATS[ty], value)
+        if ty == "string":
+            data = value.encode()
+            return _varint(len(data)) + data
+        if ty.startswith("option<"):
+            return b"\x00" if value is None else b"\x01" + self.encode_value(ty[7:-1], value)
+        if ty.startswith("list<"):
+            inner = ty[5:-1]
+            return _varint(len(value)) + b"".join(self.encode_value(inner, item) for item in value)
+        if ty.startswith("("):
+            return b"".join(self.encode_value(inner, item) for inner, item in zip(_elements(ty), value))
+
+        definition = self.definitions[ty]
+        if definition["kind"] == "struct":
+            return self._encode_fields(definition["fields"], value)
+        name, fields = (value, None) if isinstance(value, str) else next(iter(value.items()))
+        for variant in definition["variants"]:
+            if variant["name"] == name:
+                return _varint(variant["index"]) + self._encode_fields(variant["fields"], fields)
+        raise ValueError(f"unknown variant {name} of {ty}")
+
+    def _encode_fields(self, fields, value):
+        if not fields:
+            return b""
+        if fields[0]["name"] == "0":
+            values = [value] if len(fields) == 1 else value
+            return b"".join(self.encode_value(field["type"], item) for field, item in zip(fields, values))
+        return b"".join(self.encode_value(field["type"], value[field["name"]]) for field in fields)
+
+    def decode_value(self, ty, data, offset):
+        if ty == "bool":
+            return data[offset] != 0, offset + 1
+        if ty == "u8":
+            return data[offset], offset + 1
+        if ty == "i8":
+            return struct.unpack_from(">b", data, offset)[0], offset + 1
+        if ty in _UNSIGNED or ty in _SIGNED:
+            tag = data[offset]
+            if tag < 251:
+                value, offset = tag, offset + 1
+            else:
+                size = {251: 2, 252: 4, 253: 8, 254: 16}[tag]
+                value, offset = int.from_bytes(data[offset + 1 : offset + 1 + size], "big"), offset + 1 + size
+            if ty in _SIGNED:
+                value = value >> 1 if value & 1 == 0 else -((value + 1) >> 1)
+            return value, offset
+        if ty in _FLOATS:
+            return struct.unpack_from(_FLOATS[ty], data, offset)[0], offset + struct.calcsize(_FLOATS[ty])
+        if ty == "string":
+            size, offset = self.decode_value("u64", data, offset)
+            return data[offset : offset + size].decode(), offset + size
+        if ty.startswith("option<"):
+            if data[offset] == 0:
+                return None, offset + 1
+            return self.decode_value(ty[7:-1], data, offset + 1)
+        if ty.startswith("list<"):
+            size, offset = self.decode_value("u64", data, offset)
+            items = []
+            for _ in range(size):
+                item, offset = self.decode_value(ty[5:-1], data, offset)
+                items.append(item)
+            return items, offset
+        if ty.startswith("("):
+            items = []
+            for inner in _elements(ty):
+                item, offset = self.decode_value(inner, data, offset)
+                items.append(item)
+            return items, offset
+
+        definition = self.definitions[ty]
+        if definition["kind"] == "struct":
+            return self._decode_fields(definition["fields"], data, offset)
+        index, offset = self.decode_value("u32", data, offset)
+        variant = definition["variants"][index]
+        if not variant["fields"]:
+            return variant["name"], offset
+        fields, offset = self._decode_fields(variant["fields"], data, offset)
+        return {variant["name"]: fields}, offset
+
+    def _decode_fields(self, fields, data, offset):
+        values = []
+        for field in fields:
+            value, offset = self.decode_value(field["type"], data, offset)
+            values.append(value)
+        if fields and fields[0]["name"] == "0":
+            return (values[0] if len(values) == 1 else values), offset
+        return {field["name"]: value for field, value in zip(fields, values)}, offset
+
+
+if __name__ == "__main__":
+    import sys
+
+    schema = Schema.load(sys.argv[1] if len(sys.argv) > 1 else "schema.json")
+    frame = schema.encode({"ServerCancel": {"task_id": 7}})
+    print(frame.hex(), schema.decode(frame))
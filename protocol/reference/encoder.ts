@@ -0,0 +1,228 @@
+// Reference encoder for the dispatcher protocol, driven by the schema from `protocol-schema`.
+//
+// Values follow serde's JSON layout: structs are objects, unit variants are strings, other
+// variants are {Name: fields} and newtypes such as TaskId are their inner value. Integers
+// decode as bigint so u64 and i128 round-trip, numbers are accepted when encoding.
+
+export interface Field {
+  name: string;
+  type: string;
+}
+
+export interface Definition {
+  name: string;
+  kind: "struct" | "enum";
+  fields?: Field[];
+  variants?: { name: string; index: number; fields: Field[] }[];
+}
+
+export interface SchemaJson {
+  encoding: { header_size: number; sequence_size?: number };
+  root: string;
+  definitions: Definition[];
+}
+
+const SIGNED = new Set(["i32", "i64", "i128"]);
+const UNSIGNED = new Set(["u32", "u64"]);
+const VARINT_TAGS: [number, number][] = [
+  [251, 2],
+  [252, 4],
+  [253, 8],
+  [254, 16],
+];
+
+function varint(value: bigint): number[] {
+  if (value < 251n) return [Number(value)];
+  for (const [tag, size] of VARINT_TAGS) {
+    if (value < 1n << BigInt(size * 8)) {
+      const out = [tag];
+      for (let i = size - 1; i >= 0; i--) out.push(Number((value >> BigInt(i * 8)) & 0xffn));
+      return out;
+    }
+  }
+  throw new Error(`${value} does not fit in u128`);
+}
+
+function zigzag(value: bigint): bigint {
+  return value >= 0n ? value * 2n : -value * 2n - 1n;
+}
+
+// Element types of a tuple type such as "(string, list<u8>)".
+function elements(ty: string): string[] {
+  const out = [];
+  let depth = 0;
+  let start = 1;
+  for (let i = 1; i < ty.length - 1; i++) {
+    if ("<(".includes(ty[i])) depth++;
+    else if (">)".includes(ty[i])) depth--;
+    else if (ty[i] === "," && depth === 0) {
+      out.push(ty.slice(start, i).trim());
+      start = i + 1;
+    }
+  }
+  out.push(ty.slice(start, -1).trim());
+  return out;
+}
+
+export class Schema {
+  readonly headerSize: number;
+  readonly sequenceSize: number;
+  readonly root: string;
+  private definitions = new Map<string, Definition>();
+
+  constructor(schema: SchemaJson) {
+    this.headerSize = schema.encoding.header_size;
+    this.sequenceSize = schema.encoding.sequence_size ?? 0;
+    this.root = schema.root;
+    for (const definition of schema.definitions) this.definitions.set(definition.name, definition);
+  }
+
+  encode(message: unknown): Uint8Array {
+    const payload: number[] = [];
+    this.encodeValue(this.root, message, payload);
+    if (payload.length >= 2 ** (this.headerSize * 8)) throw new Error("message too large");
+    const out = new Uint8Array(this.headerSize + payload.length);
+    for (let i = 0; i < this.headerSize; i++) {
+      out[i] = (payload.length >> ((this.headerSize - 1 - i) * 8)) & 0xff;
+    }
+    out.set(payload, this.headerSize);
+    return out;
+  }
+
+  // Returns the message and the number of bytes consumed, null while the frame is incomplete.
+  // The sequence number of a sequenced frame is skipped.
+  decode(data: Uint8Array): [unknown, number] | null {
+    let start = 0;
+    if (this.sequenceSize && data.length >= this.headerSize && data.subarray(0, this.headerSize).every((b) => b === 0)) {
+      start = this.headerSize + this.sequenceSize;
+    }
+    if (data.length < start + this.headerSize) return null;
+    let size = 0;
+    for (let i = 0; i < this.headerSize; i++) size = size * 256 + data[start + i];
+    const end = start + this.headerSize + size;
+    if (data.length < end) return null;
+    const [value, offset] = this.decodeValue(this.root, data, start + this.headerSize);
+    if (offset !== end) throw new Error("trailing bytes in frame");
+    return [value, end];
+  }
+
+  encodeValue(ty: string, value: any, out: number[]): void {
+    if (ty === "bool") out.push(value ? 1 : 0);
+    else if (ty === "u8") out.push(value & 0xff);
+    else if (ty === "i8") out.push(value & 0xff);
+    else if (UNSIGNED.has(ty)) out.push(...varint(BigInt(value)));
+    else if (SIGNED.has(ty)) out.push(...varint(zigzag(BigInt(value))));
+    else if (ty === "f32" || ty === "f64") {
+      const view = new DataView(new ArrayBuffer(ty === "f32" ? 4 : 8));
+      if (ty === "f32") view.setFloat32(0, value);
+      else view.setFloat64(0, value);
+      out.push(...new Uint8Array(view.buffer));
+    } else if (ty === "string") {
+      const data = new TextEncoder().encode(value);
+      out.push(...varint(BigInt(data.length)), ...data);
+    } else if (ty.startsWith("option<")) {
+      if (value === null || value === undefined) out.push(0);
+      else {
+        out.push(1);
+        this.encodeValue(ty.slice(7, -1), value, out);
+      }
+    } else if (ty.startsWith("list<")) {
+      out.push(...varint(BigInt(value.length)));
+      for (const item of value) this.encodeValue(ty.slice(5, -1), item, out);
+    } else if (ty.startsWith("(")) {
+      elements(ty).forEach((inner, i) => this.encodeValue(inner, value[i], out));
+    } else {
+      const definition = this.definition(ty);
+      if (definition.kind === "struct") return this.encodeFields(definition.fields!, value, out);
+      const [name, fields] = typeof value === "string" ? [value, undefined] : Object.entries(value)[0];
+      const variant = definition.variants!.find((variant) => variant.name === name);
+      if (!variant) throw new Error(`unknown variant ${name} of ${ty}`);
+      out.push(...varint(BigInt(variant.index)));
+      this.encodeFields(variant.fields, fields, out);
+    }
+  }
+
+  private encodeFields(fields: Field[], value: any, out: number[]): void {
+    if (fields.length > 0 && fields[0].name === "0") {
+      const values = fields.length === 1 ? [value] : value;
+      fields.forEach((field, i) => this.encodeValue(field.type, values[i], out));
+    } else {
+      for (const field of fields) this.encodeValue(field.type, value[field.name], out);
+    }
+  }
+
+  decodeValue(ty: string, data: Uint8Array, offset: number): [any, number] {
+    const view = new DataView(data.buffer, data.byteOffset, data.byteLength);
+    if (ty === "bool") return [data[offset] !== 0, offset + 1];
+    if (ty === "u8") return [data[offset], offset + 1];
+    if (ty === "i8") return [view.getInt8(offset), offset + 1];
+    if (UNSIGNED.has(ty) || SIGNED.has(ty)) {
+      const tag = data[offset];
+      let value = BigInt(tag);
+      offset += 1;
+      if (tag >= 251) {
+        const size = VARINT_TAGS.find(([t]) => t === tag)![1];
+        value = 0n;
+        for (let i = 0; i < size; i++) value = (value << 8n) | BigInt(data[offset + i]);
+        offset += size;
+      }
+      if (SIGNED.has(ty)) value = value & 1n ? -((value + 1n) >> 1n) : value >> 1n;
+      return [value, offset];
+    }
+    if (ty === "f32") return [view.getFloat32(offset), offset + 4];
+    if (ty === "f64") return [view.getFloat64(offset), offset + 8];
+    if (ty === "string") {
+      const [size, start] = this.decodeValue("u64", data, offset);
+      const end = start + Number(size);
+      return [new TextDecoder().decode(data.subarray(start, end)), end];
+    }
+    if (ty.startsWith("option<")) {
+      if (data[offset] === 0) return [null, offset + 1];
+      return this.decodeValue(ty.slice(7, -1), data, offset + 1);
+    }
+    if (ty.startsWith("list<")) {
+      let [size, next] = this.decodeValue("u64", data, offset);
+      const items = [];
+      for (let i = 0n; i < size; i++) {
+        const [item, after] = this.decodeValue(ty.slice(5, -1), data, next);
+        items.push(item);
+        next = after;
+      }
+      return [items, next];
+    }
+    if (ty.startsWith("(")) {
+      const items = [];
+      for (const inner of elements(ty)) {
+        const [item, next] = this.decodeValue(inner, data, offset);
+        items.push(item);
+        offset = next;
+      }
+      return [items, offset];
+    }
+
+    const definition = this.definition(ty);
+    if (definition.kind === "struct") return this.decodeFields(definition.fields!, data, offset);
+    const [index, next] = this.decodeValue("u32", data, offset);
+    const variant = definition.variants![Number(index)];
+    if (variant.fields.length === 0) return [variant.name, next];
+    const [fields, end] = this.decodeFields(variant.fields, data, next);
+    return [{ [variant.name]: fields }, end];
+  }
+
+  private decodeFields(fields: Field[], data: Uint8Array, offset: number): [any, number] {
+    const values = [];
+    for (const field of fields) {
+      const [value, next] = this.decodeValue(field.type, data, offset);
+      values.push(value);
+      offset = next;
+    }
+    if (fields.length > 0 && fields[0].name === "0") return [fields.length === 1 ? values[0] : values, offset];
+    return [Object.fromEntries(fields.map((field, i) => [field.name, values[i]])), offset];
+  }
+
+  private definition(name: string): Definition {
+    const definition = this.definitions.get(name);
+    if (!definition) throw new Error(`unknown type ${name}`);
+    return definition;
+  }
+}
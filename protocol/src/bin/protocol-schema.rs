@@ -0,0 +1,24 @@
+use std::path::PathBuf;
+use std::{env, fs, io};
+
+const ENCODERS: &[(&str, &str)] = &[
+    ("encoder.py", include_str!("../../reference/encoder.py")),
+    ("encoder.ts", include_str!("../../reference/encoder.ts")),
+];
+
+// Prints the schema, or with a directory writes it there as schema.json next to the reference
+// encoders that read it.
+fn main() -> io::Result<()> {
+    let schema = protocol::schema().to_json();
+    let Some(dir) = env::args_os().nth(1).map(PathBuf::from) else {
+        print!("{}", schema);
+        return Ok(());
+    };
+
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join("schema.json"), schema)?;
+    for (name, source) in ENCODERS {
+        fs::write(dir.join(name), source)?;
+    }
+    Ok(())
+}
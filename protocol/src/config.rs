@@ -11,6 +11,8 @@ pub struct Config {
     pub host: Arc<str>,
     pub dispatcher_port: u16,
     pub inspector_port: u16,
+    pub replication_port: u16,
+    pub control_port: u16,
     pub wifi: Option<Wifi>,
 }
 
@@ -26,6 +28,14 @@ impl Config {
             .and_then(|s| s.parse::<u16>().ok())
             .unwrap_or(3000);
 
+        let replication_port = option_env!("REPLICATION_PORT")
+            .and_then(|s| s.parse::<u16>().ok())
+            .unwrap_or(3031);
+
+        let control_port = option_env!("CONTROL_PORT")
+            .and_then(|s| s.parse::<u16>().ok())
+            .unwrap_or(50051);
+
         let wifi = option_env!("WIFI_SSID")
             .zip(option_env!("WIFI_PASSWORD"))
             .map(|(ssid, password)| Wifi {
@@ -37,6 +47,8 @@ impl Config {
             host,
             dispatcher_port,
             inspector_port,
+            replication_port,
+            control_port,
             wifi,
         }
     }
@@ -48,6 +60,8 @@ impl Default for Config {
             host: Arc::from("localhost"),
             dispatcher_port: 3030,
             inspector_port: 3000,
+            replication_port: 3031,
+            control_port: 50051,
             wifi: None,
         }
     }
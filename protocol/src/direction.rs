@@ -0,0 +1,224 @@
+// Messages by the side that sends them. `Message` stays the envelope on the wire, both peers decode
+// frames into it and narrow it down to what the other side may send, so a frame travelling the
+// wrong way is refused like any other bad frame. Variants are documented on `Message`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::{
+    AckInfo, CacheStats, Error, ExecutionStats, ExecutorFlavor, FirmwareInfo, LogLevel, Message, ModuleInfo,
+    ProtocolErrorCode, TaskId, Telemetry, Type,
+};
+
+macro_rules! directed {
+    ($(#[$meta:meta])* $name:ident { $($variant:ident { $($field:ident: $ty:ty),* $(,)? }),* $(,)? }) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, PartialEq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        pub enum $name {
+            $($variant { $($field: $ty),* }),*
+        }
+
+        impl From<$name> for Message {
+            fn from(message: $name) -> Self {
+                match message {
+                    $($name::$variant { $($field),* } => Message::$variant { $($field),* }),*
+                }
+            }
+        }
+
+        #[cfg(feature = "defmt")]
+        impl defmt::Format for $name {
+            fn format(&self, f: defmt::Formatter) {
+                match self {
+                    $($name::$variant { $($field),* } => {
+                        crate::format::summarize(f, stringify!($variant), &[$($field as &dyn core::any::Any),*])
+                    })*
+                }
+            }
+        }
+
+        impl TryFrom<Message> for $name {
+            type Error = Error;
+
+            fn try_from(message: Message) -> Result<Self, Error> {
+                match message {
+                    $(Message::$variant { $($field),* } => Ok($name::$variant { $($field),* }),)*
+                    _ => Err(Error::Misdirected),
+                }
+            }
+        }
+    };
+}
+
+directed! {
+    // Sent by devices to the server.
+    ClientMessage {
+        ClientReady {
+            modules: Vec<String>,
+            device_ram: u64,
+            labels: Vec<String>,
+            executor: ExecutorFlavor,
+            arch: String,
+            resume_token: Option<u64>,
+        },
+        ClientAck {
+            task_id: TaskId,
+            ack_info: AckInfo,
+        },
+        ClientResult {
+            task_id: TaskId,
+            attempt: u32,
+            result: Vec<Type>,
+            stats: ExecutionStats,
+        },
+        Heartbeat {
+            timestamp: u64,
+            cache: CacheStats,
+        },
+        ClientSubmit {
+            module_name: String,
+            params: Vec<Type>,
+            priority: u8,
+        },
+        ClientLog {
+            level: LogLevel,
+            module: String,
+            message: String,
+            timestamp: u64,
+        },
+        ClientTelemetry {
+            timestamp: u64,
+            cache: CacheStats,
+            telemetry: Telemetry,
+        },
+        ClientCacheUpdate {
+            added: Vec<String>,
+            removed: Vec<String>,
+        },
+        ClientProgress {
+            task_id: TaskId,
+            percent: u8,
+            stage: String,
+        },
+        ProtocolError {
+            code: ProtocolErrorCode,
+            detail: String,
+        },
+        ClientBusy {
+            task_id: TaskId,
+            queue_len: u32,
+        },
+        ClientCheckpoint {
+            task_id: TaskId,
+            attempt: u32,
+            data: Vec<u8>,
+        },
+        ClientPong {
+            nonce: u32,
+        },
+    }
+}
+
+directed! {
+    // Sent by the server to devices.
+    ServerMessage {
+        ServerTask {
+            task_id: TaskId,
+            attempt: u32,
+            module: ModuleInfo,
+            params: Vec<Type>,
+            env: Vec<(String, String)>,
+            libraries: Vec<ModuleInfo>,
+            priority: u8,
+            checkpoint: Option<Vec<u8>>,
+        },
+        ServerModule {
+            task_id: TaskId,
+            chunk_index: u32,
+            chunk_data: Vec<u8>,
+        },
+        ServerAck {
+            task_id: TaskId,
+            success: bool,
+        },
+        ServerUnpin {
+            module: String,
+        },
+        ServerSubmitted {
+            task_id: Option<TaskId>,
+        },
+        ServerResult {
+            task_id: TaskId,
+            result: Vec<Type>,
+        },
+        ServerRateLimited {
+            max_messages: u32,
+            max_bytes: u64,
+        },
+        ServerRedirect {
+            addr: String,
+        },
+        ServerCancel {
+            task_id: TaskId,
+        },
+        ServerPrefetch {
+            task_id: TaskId,
+            module: ModuleInfo,
+        },
+        ServerFirmware {
+            task_id: TaskId,
+            firmware: FirmwareInfo,
+        },
+        ServerBusy {
+            max_sessions: u32,
+            retry_after_secs: u32,
+        },
+        ServerSession {
+            resume_token: u64,
+            resumed: bool,
+        },
+        ProtocolError {
+            code: ProtocolErrorCode,
+            detail: String,
+        },
+        ServerPing {
+            nonce: u32,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::ProtocolOptions;
+
+    #[test]
+    fn test_directions() {
+        let result = ClientMessage::ClientResult {
+            task_id: TaskId(4),
+            attempt: 1,
+            result: vec![Type::I32(8)],
+            stats: ExecutionStats::default(),
+        };
+        let encoded = Message::from(result.clone()).encode().unwrap();
+        let (decoded, _, consumed) = ProtocolOptions::DEFAULT.decode_as::<ClientMessage>(&encoded).unwrap();
+        assert_eq!((decoded, consumed), (result, encoded.len()));
+
+        // A frame only the receiver itself sends is refused, the length header still skips it.
+        let error = ProtocolOptions::DEFAULT.decode_as::<ServerMessage>(&encoded).unwrap_err();
+        assert!(matches!(error, Error::Misdirected));
+        assert_eq!(ProtocolErrorCode::of(&error), Some(ProtocolErrorCode::Misdirected));
+        assert_eq!(Message::frame_len(&encoded), Some(encoded.len()));
+
+        // Either side reports frames it refused.
+        let report = Message::ProtocolError {
+            code: ProtocolErrorCode::Malformed,
+            detail: "bad".into(),
+        };
+        assert!(ClientMessage::try_from(report.clone()).is_ok());
+        assert_eq!(Message::from(ServerMessage::try_from(report.clone()).unwrap()), report);
+    }
+}
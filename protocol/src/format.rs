@@ -0,0 +1,74 @@
+// defmt output for targets logging over RTT. Messages are summarized by their variant and the task
+// they concern, params, chunks and checkpoints would only fill the RTT buffer.
+
+use core::any::Any;
+
+use defmt::{write, Debug2Format, Format, Formatter};
+
+use crate::{Error, Message, TaskId};
+
+impl Format for Error {
+    fn format(&self, f: Formatter) {
+        match self {
+            Self::InsufficientData => write!(f, "Insufficient data"),
+            Self::InvalidMessage => write!(f, "Invalid message"),
+            Self::DecodeError(e) => write!(f, "Decode error: {}", Debug2Format(e)),
+            Self::EncodeError(e) => write!(f, "Encode error: {}", Debug2Format(e)),
+            Self::FrameTooLarge(len) => write!(f, "Frame payload of {=usize} bytes exceeds the configured maximum", len),
+            Self::Misdirected => write!(f, "Message is only sent by the receiving side"),
+            #[cfg(feature = "json")]
+            Self::JsonError(e) => write!(f, "JSON error: {}", Debug2Format(e)),
+        }
+    }
+}
+
+impl Format for Message {
+    fn format(&self, f: Formatter) {
+        let (name, task_id) = match self {
+            Self::ClientReady { .. } => ("ClientReady", None),
+            Self::ServerTask { task_id, .. } => ("ServerTask", Some(*task_id)),
+            Self::ServerModule { task_id, .. } => ("ServerModule", Some(*task_id)),
+            Self::ClientAck { task_id, .. } => ("ClientAck", Some(*task_id)),
+            Self::ClientResult { task_id, .. } => ("ClientResult", Some(*task_id)),
+            Self::ServerAck { task_id, .. } => ("ServerAck", Some(*task_id)),
+            Self::ServerUnpin { .. } => ("ServerUnpin", None),
+            Self::Heartbeat { .. } => ("Heartbeat", None),
+            Self::ClientSubmit { .. } => ("ClientSubmit", None),
+            Self::ServerSubmitted { task_id } => ("ServerSubmitted", *task_id),
+            Self::ServerResult { task_id, .. } => ("ServerResult", Some(*task_id)),
+            Self::ServerRateLimited { .. } => ("ServerRateLimited", None),
+            Self::ServerRedirect { .. } => ("ServerRedirect", None),
+            Self::ServerCancel { task_id } => ("ServerCancel", Some(*task_id)),
+            Self::ServerPrefetch { task_id, .. } => ("ServerPrefetch", Some(*task_id)),
+            Self::ServerFirmware { task_id, .. } => ("ServerFirmware", Some(*task_id)),
+            Self::ClientLog { .. } => ("ClientLog", None),
+            Self::ClientTelemetry { .. } => ("ClientTelemetry", None),
+            Self::ServerBusy { .. } => ("ServerBusy", None),
+            Self::ClientCacheUpdate { .. } => ("ClientCacheUpdate", None),
+            Self::ClientProgress { task_id, .. } => ("ClientProgress", Some(*task_id)),
+            Self::ServerSession { .. } => ("ServerSession", None),
+            Self::ProtocolError { .. } => ("ProtocolError", None),
+            Self::ClientBusy { task_id, .. } => ("ClientBusy", Some(*task_id)),
+            Self::ClientCheckpoint { task_id, .. } => ("ClientCheckpoint", Some(*task_id)),
+            Self::ServerPing { .. } => ("ServerPing", None),
+            Self::ClientPong { .. } => ("ClientPong", None),
+        };
+        summary(f, name, task_id);
+    }
+}
+
+// For the messages of one direction, which name their task among `fields`.
+pub(crate) fn summarize(f: Formatter, name: &'static str, fields: &[&dyn Any]) {
+    let task_id = fields.iter().find_map(|field| match field.downcast_ref::<Option<TaskId>>() {
+        Some(task_id) => *task_id,
+        None => field.downcast_ref::<TaskId>().copied(),
+    });
+    summary(f, name, task_id);
+}
+
+fn summary(f: Formatter, name: &'static str, task_id: Option<TaskId>) {
+    match task_id {
+        Some(task_id) => write!(f, "{=str} (task {=u64})", name, task_id.0),
+        None => write!(f, "{=str}", name),
+    }
+}
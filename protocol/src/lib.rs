@@ -3,11 +3,23 @@
 extern crate alloc;
 
 mod config;
+mod direction;
+#[cfg(feature = "defmt")]
+mod format;
+mod options;
+pub mod schema;
+mod sequence;
 
+use alloc::format;
 use alloc::string::String;
 use alloc::vec::Vec;
+use core::fmt;
 
 pub use config::{Config, Wifi};
+pub use direction::{ClientMessage, ServerMessage};
+pub use options::{Endian, IntEncoding, MessageRef, ProtocolOptions};
+pub use schema::schema;
+pub use sequence::{SequenceCheck, SequenceStats, SequenceTracker};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
@@ -19,9 +31,18 @@ pub enum Error {
     DecodeError(bincode::error::DecodeError),
     #[error("Encode error: {0:?}")]
     EncodeError(bincode::error::EncodeError),
+    #[error("Frame payload of {0} bytes exceeds the configured maximum")]
+    FrameTooLarge(usize),
+    #[error("Message is only sent by the receiving side")]
+    Misdirected,
+    #[cfg(feature = "json")]
+    #[error("JSON error: {0}")]
+    JsonError(serde_json::Error),
 }
 
 #[derive(bincode::Encode, bincode::Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum Type {
     Void,
     I32(i32),
@@ -29,106 +50,440 @@ pub enum Type {
     F32(f32),
     F64(f64),
     V128(i128),
+    // Passed through guest linear memory as a (ptr, len) pair, see program::memory.
+    Bytes(Vec<u8>),
+    // (id, size, hash) of an input held in the server's blob store. The device fetches it like a
+    // module, caches it under `blob_name(id)` and passes it on as `Bytes` once `Checksum` matches.
+    BlobRef(u64, u64, u32),
+}
+
+// Name a blob is announced and cached under, it shares the module cache with modules.
+pub fn blob_name(id: u64) -> String {
+    format!("blob:{}", id)
+}
+
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskId(pub u64);
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
 }
 
 #[derive(bincode::Encode, bincode::Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub struct ModuleInfo {
     pub name: String,
     pub size: u64,
     pub chunk_size: u32,
     pub total_chunks: u32,
+    pub pinned: bool,
 }
 
+// A firmware image delivered through the same chunked transfer as modules.
 #[derive(bincode::Encode, bincode::Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FirmwareInfo {
+    pub version: String,
+    pub size: u64,
+    pub chunk_size: u32,
+    pub total_chunks: u32,
+    // `Checksum` of the whole image, checked by the device before it reboots into it.
+    pub checksum: u32,
+}
+
+// FNV-1a, cheap enough for microcontrollers to fold over an image chunk by chunk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum(u32);
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self(0x811c9dc5)
+    }
+}
+
+impl Checksum {
+    pub fn of(data: &[u8]) -> u32 {
+        let mut checksum = Self::default();
+        checksum.update(data);
+        checksum.value()
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        self.0 = data
+            .iter()
+            .fold(self.0, |hash, byte| (hash ^ *byte as u32).wrapping_mul(0x01000193));
+    }
+
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub bytes_used: u64,
+}
+
+// Device health reported alongside the heartbeat, readings the platform cannot take are `None`.
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Telemetry {
+    pub free_heap: Option<u64>,
+    pub tasks_executed: u64,
+    pub uptime_secs: u64,
+    // WiFi signal strength in dBm.
+    pub rssi: Option<i8>,
+    // Only for a device numbering its frames, how the server's frames arrived.
+    pub sequence: Option<SequenceStats>,
+    pub session: SessionStats,
+}
+
+// Counters a device keeps over its session, reconnects included.
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SessionStats {
+    pub frames_in: u64,
+    pub frames_out: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    // Frames from the server refused for failing to decode.
+    pub decode_errors: u64,
+    // Modules run, failed runs included.
+    pub executions: u64,
+    // Tasks whose module was already cached and needed no transfer.
+    pub cache_hits: u64,
+}
+
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ExecutionStats {
+    pub wall_time_us: u64,
+    pub peak_memory: u64,
+    // Only reported by executors that meter instructions, e.g. through fuel.
+    pub instructions: Option<u64>,
+}
+
+// Ordered from slowest to fastest so the scheduler can compare flavors directly.
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ExecutorFlavor {
+    #[default]
+    Interpreter,
+    Jit,
+    Aot,
+}
+
+// Label of trusted std workers that also load native cdylib modules. It says nothing about how
+// fast they run wasm, that is still their ExecutorFlavor.
+pub const NATIVE_LABEL: &str = "native";
+
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+// Why a peer's frame was refused, carried by Message::ProtocolError.
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProtocolErrorCode {
+    // The payload is not a message the receiver knows, such as a variant from a newer release.
+    Malformed,
+    // The payload decoded to a message shorter than its length header.
+    TrailingData,
+    // A well-formed message the receiver cannot act on in its current state.
+    Unexpected,
+    // The frame is larger than the receiver accepts.
+    TooLarge,
+    // A message only the receiver itself sends, such as a ServerTask reaching the server.
+    Misdirected,
+}
+
+impl ProtocolErrorCode {
+    // `None` for a frame that is only incomplete so far.
+    pub fn of(error: &Error) -> Option<Self> {
+        match error {
+            Error::InsufficientData => None,
+            Error::InvalidMessage => Some(Self::TrailingData),
+            Error::FrameTooLarge(_) => Some(Self::TooLarge),
+            Error::Misdirected => Some(Self::Misdirected),
+            _ => Some(Self::Malformed),
+        }
+    }
+}
+
+// Variant order is part of the wire format: ChunkAck and ModuleListAck keep the
+// discriminants of the former Chunk and Module variants so older clients still decode.
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum AckInfo {
-    Chunk {
+    ChunkAck {
         chunk_index: u32,
         success: bool,
     },
-    Module {
+    ModuleListAck {
         modules: Vec<String>,
     },
+    TaskAck {
+        accepted: bool,
+    },
+    // Sent once the whole image is written, a verified device reboots into it right after.
+    FirmwareAck {
+        verified: bool,
+    },
 }
 
 #[derive(bincode::Encode, bincode::Decode, Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Message {
     ClientReady {
         modules: Vec<String>,
         device_ram: u64,
+        labels: Vec<String>,
+        executor: ExecutorFlavor,
+        arch: String,
+        // Token from an earlier ServerSession, lets the server pick up the session it belonged to.
+        resume_token: Option<u64>,
     },
+    // `attempt` counts how often the server has handed out `task_id`, a result is only accepted
+    // for the attempt the task is currently on. `env` reaches the module as environment
+    // variables, for string settings that do not fit the numeric params. `libraries` are
+    // instantiated along with `module` to resolve its imports by name, the server sends them
+    // ahead of the task so the device only has to find them in its cache. A device with tasks
+    // queued starts the one of highest `priority` first. `checkpoint` is the latest state an
+    // earlier attempt saved, the module resumes from it instead of starting over.
     ServerTask {
-        task_id: u64,
+        task_id: TaskId,
+        attempt: u32,
         module: ModuleInfo,
         params: Vec<Type>,
+        env: Vec<(String, String)>,
+        libraries: Vec<ModuleInfo>,
+        priority: u8,
+        checkpoint: Option<Vec<u8>>,
     },
     ServerModule {
-        task_id: u64,
+        task_id: TaskId,
         chunk_index: u32,
         chunk_data: Vec<u8>,
     },
     ClientAck {
-        task_id: u64,
+        task_id: TaskId,
         ack_info: AckInfo,
     },
+    // Echoes the `attempt` of the ServerTask it answers, resending it is harmless.
     ClientResult {
-        task_id: u64,
+        task_id: TaskId,
+        attempt: u32,
         result: Vec<Type>,
+        stats: ExecutionStats,
     },
     ServerAck {
-        task_id: u64,
+        task_id: TaskId,
         success: bool,
     },
+    ServerUnpin {
+        module: String,
+    },
     Heartbeat {
         timestamp: u64,
+        cache: CacheStats,
+    },
+    ClientSubmit {
+        module_name: String,
+        params: Vec<Type>,
+        priority: u8,
+    },
+    ServerSubmitted {
+        task_id: Option<TaskId>,
+    },
+    ServerResult {
+        task_id: TaskId,
+        result: Vec<Type>,
+    },
+    ServerRateLimited {
+        max_messages: u32,
+        max_bytes: u64,
+    },
+    ServerRedirect {
+        addr: String,
+    },
+    ServerCancel {
+        task_id: TaskId,
+    },
+    // Transfers a module ahead of any task needing it, chunks follow under `task_id`.
+    ServerPrefetch {
+        task_id: TaskId,
+        module: ModuleInfo,
+    },
+    // Starts a firmware update, the device answers with a TaskAck and chunks follow as
+    // ServerModule under `task_id`.
+    ServerFirmware {
+        task_id: TaskId,
+        firmware: FirmwareInfo,
+    },
+    // A log line forwarded from the device, `timestamp` follows the heartbeat clock.
+    ClientLog {
+        level: LogLevel,
+        module: String,
+        message: String,
+        timestamp: u64,
+    },
+    // Supersedes Heartbeat, the server treats both as a liveness signal.
+    ClientTelemetry {
+        timestamp: u64,
+        cache: CacheStats,
+        telemetry: Telemetry,
+    },
+    // Sent instead of accepting a connection once every session slot is taken, the server
+    // closes the connection right after.
+    ServerBusy {
+        max_sessions: u32,
+        retry_after_secs: u32,
+    },
+    // Changes to the device's module cache the server did not drive, such as modules evicted to
+    // make room for another transfer.
+    ClientCacheUpdate {
+        added: Vec<String>,
+        removed: Vec<String>,
+    },
+    // How far the device got with `task_id`, `percent` runs from 0 to 100 within `stage`.
+    ClientProgress {
+        task_id: TaskId,
+        percent: u8,
+        stage: String,
+    },
+    // Answers ClientReady. `resumed` tells whether the server reattached the connection to the
+    // session named by the device's token, a device reconnecting presents `resume_token`.
+    ServerSession {
+        resume_token: u64,
+        resumed: bool,
+    },
+    // Sent by either side about a frame it dropped, before it skips past the frame or closes
+    // the connection. Only ever logged and counted, never answered.
+    ProtocolError {
+        code: ProtocolErrorCode,
+        detail: String,
+    },
+    // Refuses a ServerTask that arrived while the device was busy and its task queue was full,
+    // `queue_len` tasks were already waiting. The server hands the task to another device.
+    ClientBusy {
+        task_id: TaskId,
+        queue_len: u32,
+    },
+    // State a running module saved, see `program::checkpoint`. The server keeps the latest one of
+    // the current `attempt` and hands it to the device the task is reassigned to.
+    ClientCheckpoint {
+        task_id: TaskId,
+        attempt: u32,
+        data: Vec<u8>,
+    },
+    // Sent to a device the server has not heard from for its idle timeout. The device answers
+    // right away with a ClientPong echoing `nonce`, though any frame shows it is still there.
+    ServerPing {
+        nonce: u32,
+    },
+    ClientPong {
+        nonce: u32,
     },
 }
 
 impl Message {
     pub const HEADER_SIZE: usize = 2;
+    // A frame may carry a sequence number ahead of its header. No message encodes to an empty
+    // payload, so a zero length marks it: `[0, 0]`, the sequence as a big-endian u32, then the
+    // usual header and payload. Receivers take both forms on the same connection.
+    pub const SEQUENCE_SIZE: usize = 4;
 
-    pub fn encode(&self) -> Result<Vec<u8>, Error> {
-        let config = bincode::config::standard()
-            .with_variable_int_encoding()
-            .with_big_endian();
-        let payload = bincode::encode_to_vec(self, config).map_err(Error::EncodeError)?;
-        let payload_len = payload.len();
+    // What goes ahead of an encoded frame to make it sequenced, lets a sender number a frame it
+    // encoded once for several peers.
+    pub fn sequence_prefix(sequence: u32) -> [u8; Self::HEADER_SIZE + Self::SEQUENCE_SIZE] {
+        let mut prefix = [0; Self::HEADER_SIZE + Self::SEQUENCE_SIZE];
+        prefix[Self::HEADER_SIZE..].copy_from_slice(&sequence.to_be_bytes());
+        prefix
+    }
 
-        if payload_len > u16::MAX as usize {
-            return Err(Error::InvalidMessage);
-        }
+    pub fn encode(&self) -> Result<Vec<u8>, Error> {
+        ProtocolOptions::DEFAULT.encode(self)
+    }
 
-        let mut output = Vec::with_capacity(Self::HEADER_SIZE + payload_len);
-        output.extend_from_slice(&(payload_len as u16).to_be_bytes());
-        output.extend(payload);
+    pub fn encode_sequenced(&self, sequence: u32) -> Result<Vec<u8>, Error> {
+        ProtocolOptions::DEFAULT.encode_sequenced(self, sequence)
+    }
 
-        Ok(output)
+    // Returns the frame length, see `ProtocolOptions::encode_into`.
+    pub fn encode_into(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        ProtocolOptions::DEFAULT.encode_into(self, buf)
     }
 
-    pub fn decode(data: &[u8]) -> Result<(Self, usize), Error> {
-        if data.len() < Self::HEADER_SIZE {
-            return Err(Error::InsufficientData);
+    // Splits off the sequence prefix, if any, returning it with its length.
+    pub(crate) fn sequence(data: &[u8]) -> Result<(Option<u32>, usize), Error> {
+        match data {
+            [0, 0, sequence @ ..] => {
+                let sequence = sequence.get(..Self::SEQUENCE_SIZE).ok_or(Error::InsufficientData)?;
+                let sequence = u32::from_be_bytes([sequence[0], sequence[1], sequence[2], sequence[3]]);
+                Ok((Some(sequence), Self::HEADER_SIZE + Self::SEQUENCE_SIZE))
+            }
+            [_, _, ..] => Ok((None, 0)),
+            _ => Err(Error::InsufficientData),
         }
+    }
 
-        let payload_len = u16::from_be_bytes([data[0], data[1]]) as usize;
-        let total_len = Self::HEADER_SIZE + payload_len;
+    // Length of the frame at the start of `data`, prefix and header included, once its header
+    // arrived. Lets a receiver skip a frame it failed to decode and carry on with the next one.
+    pub fn frame_len(data: &[u8]) -> Option<usize> {
+        let (_, prefix) = Self::sequence(data).ok()?;
+        let header = data.get(prefix..prefix + Self::HEADER_SIZE)?;
+        Some(prefix + Self::HEADER_SIZE + u16::from_be_bytes([header[0], header[1]]) as usize)
+    }
 
-        if data.len() < total_len {
-            return Err(Error::InsufficientData);
-        }
+    pub fn decode(data: &[u8]) -> Result<(Self, usize), Error> {
+        Self::decode_sequenced(data).map(|(message, _, consumed)| (message, consumed))
+    }
 
-        let config = bincode::config::standard()
-            .with_variable_int_encoding()
-            .with_big_endian();
+    // Like `decode`, also returning the sequence number of a sequenced frame.
+    pub fn decode_sequenced(data: &[u8]) -> Result<(Self, Option<u32>, usize), Error> {
+        ProtocolOptions::DEFAULT.decode(data)
+    }
 
-        let (message, size) =
-            bincode::decode_from_slice(&data[Self::HEADER_SIZE..total_len], config)
-                .map_err(Error::DecodeError)?;
+    pub fn decode_borrowed(data: &[u8]) -> Result<(MessageRef<'_>, usize), Error> {
+        ProtocolOptions::DEFAULT
+            .decode_borrowed(data)
+            .map(|(message, _, consumed)| (message, consumed))
+    }
+}
 
-        if size != payload_len {
-            return Err(Error::InvalidMessage);
-        }
+// Human-readable form for debugging and fixtures, devices only ever see the bincode frames.
+#[cfg(feature = "json")]
+impl Message {
+    pub fn encode_json(&self) -> Result<String, Error> {
+        serde_json::to_string(self).map_err(Error::JsonError)
+    }
 
-        Ok((message, total_len))
+    pub fn decode_json(text: &str) -> Result<Self, Error> {
+        serde_json::from_str(text).map_err(Error::JsonError)
     }
 }
 
@@ -143,6 +498,10 @@ mod tests {
         let msg = Message::ClientReady {
             modules: vec!["test".into()],
             device_ram: 0,
+            labels: vec!["camera".into(), "rev-b".into()],
+            executor: ExecutorFlavor::Jit,
+            arch: "riscv32".into(),
+            resume_token: Some(0x5eed),
         };
         let encoded = msg.encode().unwrap();
         let decoded = Message::decode(&encoded).unwrap();
@@ -152,12 +511,14 @@ mod tests {
     #[test]
     fn test_server_task() {
         let msg = Message::ServerTask {
-            task_id: 99,
+            task_id: TaskId(99),
+            attempt: 2,
             module: ModuleInfo {
                 name: "test".into(),
                 size: 1024,
                 chunk_size: 256,
                 total_chunks: 4,
+                pinned: true,
             },
             params: vec![
                 Type::Void,
@@ -166,7 +527,19 @@ mod tests {
                 Type::I64(987_654_321),
                 Type::F64(core::f64::consts::E),
                 Type::V128(123456789012345678901234567890),
+                Type::Bytes(vec![0xff, 0x00, 0x7f]),
+                Type::BlobRef(7, 1 << 20, 0xdeadbeef),
             ],
+            env: vec![("MODE".into(), "fast".into()), ("LABEL".into(), String::new())],
+            libraries: vec![ModuleInfo {
+                name: "libm".into(),
+                size: 300,
+                chunk_size: 256,
+                total_chunks: 2,
+                pinned: false,
+            }],
+            priority: 200,
+            checkpoint: Some(vec![1, 2, 3]),
         };
         let encoded = msg.encode().unwrap();
         let decoded = Message::decode(&encoded).unwrap();
@@ -176,7 +549,7 @@ mod tests {
     #[test]
     fn test_server_module() {
         let msg = Message::ServerModule {
-            task_id: 99,
+            task_id: TaskId(99),
             chunk_index: 1,
             chunk_data: vec![10, 20, 30, 40, 50],
         };
@@ -188,8 +561,8 @@ mod tests {
     #[test]
     fn test_client_ack() {
         let msg_success = Message::ClientAck {
-            task_id: 99,
-            ack_info: AckInfo::Module {
+            task_id: TaskId(99),
+            ack_info: AckInfo::ModuleListAck {
                 modules: vec!["test".into()],
             },
         };
@@ -198,11 +571,66 @@ mod tests {
         assert_eq!(msg_success, decoded.0);
     }
 
+    #[test]
+    fn test_client_ack_task() {
+        let msg = Message::ClientAck {
+            task_id: TaskId(99),
+            ack_info: AckInfo::TaskAck { accepted: false },
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_client_ack_legacy_layout() {
+        #[derive(bincode::Encode)]
+        enum LegacyAckInfo {
+            Chunk { chunk_index: u32, success: bool },
+            Module { modules: Vec<String> },
+        }
+
+        let config = bincode::config::standard()
+            .with_variable_int_encoding()
+            .with_big_endian();
+        let legacy = [
+            LegacyAckInfo::Chunk {
+                chunk_index: 3,
+                success: true,
+            },
+            LegacyAckInfo::Module {
+                modules: vec!["test".into()],
+            },
+        ];
+        let expected = [
+            AckInfo::ChunkAck {
+                chunk_index: 3,
+                success: true,
+            },
+            AckInfo::ModuleListAck {
+                modules: vec!["test".into()],
+            },
+        ];
+
+        for (legacy, expected) in legacy.iter().zip(expected) {
+            let encoded = bincode::encode_to_vec(legacy, config).unwrap();
+            let (decoded, _): (AckInfo, usize) =
+                bincode::decode_from_slice(&encoded, config).unwrap();
+            assert_eq!(decoded, expected);
+        }
+    }
+
     #[test]
     fn test_client_result() {
         let msg = Message::ClientResult {
-            task_id: 99,
+            task_id: TaskId(99),
+            attempt: 2,
             result: vec![Type::I32(42), Type::F64(-5.67)],
+            stats: ExecutionStats {
+                wall_time_us: 1500,
+                peak_memory: 65536,
+                instructions: Some(120_000),
+            },
         };
         let encoded = msg.encode().unwrap();
         let decoded = Message::decode(&encoded).unwrap();
@@ -212,7 +640,7 @@ mod tests {
     #[test]
     fn test_server_ack() {
         let msg_success = Message::ServerAck {
-            task_id: 1,
+            task_id: TaskId(1),
             success: true,
         };
         let encoded = msg_success.encode().unwrap();
@@ -220,22 +648,323 @@ mod tests {
         assert_eq!(msg_success, decoded.0);
     }
 
+    #[test]
+    fn test_server_unpin() {
+        let msg = Message::ServerUnpin {
+            module: "test".into(),
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
     #[test]
     fn test_heartbeat() {
         let msg = Message::Heartbeat {
             timestamp: 1234567890,
+            cache: CacheStats {
+                hits: 12,
+                misses: 3,
+                evictions: 1,
+                bytes_used: 4096,
+            },
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_client_submit() {
+        let msg = Message::ClientSubmit {
+            module_name: "test".into(),
+            params: vec![Type::I32(1), Type::F64(2.5)],
+            priority: 3,
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_server_submitted() {
+        for task_id in [Some(TaskId(7)), None] {
+            let msg = Message::ServerSubmitted { task_id };
+            let encoded = msg.encode().unwrap();
+            let decoded = Message::decode(&encoded).unwrap();
+            assert_eq!(msg, decoded.0);
+        }
+    }
+
+    #[test]
+    fn test_server_result() {
+        let msg = Message::ServerResult {
+            task_id: TaskId(7),
+            result: vec![Type::I64(-1)],
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_server_rate_limited() {
+        let msg = Message::ServerRateLimited {
+            max_messages: 256,
+            max_bytes: 64 * 1024,
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_server_redirect() {
+        let msg = Message::ServerRedirect {
+            addr: "10.0.0.2:3030".into(),
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_server_cancel() {
+        let msg = Message::ServerCancel { task_id: TaskId(7) };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_server_prefetch() {
+        let msg = Message::ServerPrefetch {
+            task_id: TaskId(8),
+            module: ModuleInfo {
+                name: "mock_module".into(),
+                size: 2048,
+                chunk_size: 1024,
+                total_chunks: 2,
+                pinned: false,
+            },
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_server_firmware() {
+        let image = [0x5au8; 3000];
+        let msg = Message::ServerFirmware {
+            task_id: TaskId(9),
+            firmware: FirmwareInfo {
+                version: "1.2.0".into(),
+                size: image.len() as u64,
+                chunk_size: 1024,
+                total_chunks: 3,
+                checksum: Checksum::of(&image),
+            },
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+
+        let ack = Message::ClientAck {
+            task_id: TaskId(9),
+            ack_info: AckInfo::FirmwareAck { verified: true },
+        };
+        let encoded = ack.encode().unwrap();
+        assert_eq!(ack, Message::decode(&encoded).unwrap().0);
+    }
+
+    #[test]
+    fn test_client_log() {
+        let msg = Message::ClientLog {
+            level: LogLevel::Warn,
+            module: "program::session".into(),
+            message: "Task 3 timed out".into(),
+            timestamp: 1_700_000_000_000_000_000,
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_client_telemetry() {
+        let msg = Message::ClientTelemetry {
+            timestamp: 1_700_000_000_000_000_000,
+            cache: CacheStats {
+                hits: 4,
+                misses: 2,
+                evictions: 1,
+                bytes_used: 4096,
+            },
+            telemetry: Telemetry {
+                free_heap: Some(81_920),
+                tasks_executed: 12,
+                uptime_secs: 3600,
+                rssi: Some(-67),
+                sequence: Some(SequenceStats {
+                    received: 40,
+                    duplicates: 1,
+                    reordered: 2,
+                    missing: 0,
+                }),
+                session: SessionStats {
+                    frames_in: 90,
+                    frames_out: 70,
+                    bytes_in: 65_536,
+                    bytes_out: 2048,
+                    decode_errors: 1,
+                    executions: 12,
+                    cache_hits: 9,
+                },
+            },
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_server_busy() {
+        let msg = Message::ServerBusy {
+            max_sessions: 64,
+            retry_after_secs: 5,
         };
         let encoded = msg.encode().unwrap();
         let decoded = Message::decode(&encoded).unwrap();
         assert_eq!(msg, decoded.0);
     }
 
+    #[test]
+    fn test_client_progress() {
+        let msg = Message::ClientProgress {
+            task_id: TaskId(7),
+            percent: 40,
+            stage: "transfer".into(),
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_client_busy() {
+        let msg = Message::ClientBusy {
+            task_id: TaskId(9),
+            queue_len: 2,
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_client_checkpoint() {
+        let msg = Message::ClientCheckpoint {
+            task_id: TaskId(9),
+            attempt: 3,
+            data: vec![0, 1, 2, 255],
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_ping_pong() {
+        for msg in [Message::ServerPing { nonce: 7 }, Message::ClientPong { nonce: u32::MAX }] {
+            let encoded = msg.encode().unwrap();
+            let decoded = Message::decode(&encoded).unwrap();
+            assert_eq!(msg, decoded.0);
+        }
+    }
+
+    #[test]
+    fn test_server_session() {
+        let msg = Message::ServerSession {
+            resume_token: u64::MAX,
+            resumed: true,
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_client_cache_update() {
+        let msg = Message::ClientCacheUpdate {
+            added: vec![],
+            removed: vec!["blink".into(), "sum".into()],
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+    }
+
+    #[test]
+    fn test_protocol_error() {
+        let msg = Message::ProtocolError {
+            code: ProtocolErrorCode::Malformed,
+            detail: "unknown variant 200".into(),
+        };
+        let encoded = msg.encode().unwrap();
+        let decoded = Message::decode(&encoded).unwrap();
+        assert_eq!(msg, decoded.0);
+
+        assert_eq!(Message::frame_len(&encoded), Some(encoded.len()));
+        assert_eq!(Message::frame_len(&[0, 5, 1]), Some(7));
+        assert_eq!(Message::frame_len(&[0]), None);
+        assert_eq!(ProtocolErrorCode::of(&Error::InsufficientData), None);
+        assert_eq!(ProtocolErrorCode::of(&Error::InvalidMessage), Some(ProtocolErrorCode::TrailingData));
+        let garbage = Message::decode(&[0, 1, 250]).unwrap_err();
+        assert_eq!(ProtocolErrorCode::of(&garbage), Some(ProtocolErrorCode::Malformed));
+    }
+
+    #[test]
+    fn test_sequenced_frame() {
+        let msg = Message::ServerCancel { task_id: TaskId(7) };
+        let plain = msg.encode().unwrap();
+        let encoded = msg.encode_sequenced(0x0102_0304).unwrap();
+        assert_eq!(&encoded[..6], &[0, 0, 1, 2, 3, 4]);
+        assert_eq!(&encoded[6..], plain.as_slice());
+        assert_eq!(Message::decode_sequenced(&encoded).unwrap(), (msg.clone(), Some(0x0102_0304), encoded.len()));
+        assert_eq!(Message::decode_sequenced(&plain).unwrap(), (msg.clone(), None, plain.len()));
+        assert_eq!(Message::decode(&encoded).unwrap(), (msg, encoded.len()));
+        assert_eq!(Message::frame_len(&encoded[..8]), Some(encoded.len()));
+
+        for len in [2, 5, 7, encoded.len() - 1] {
+            assert!(matches!(Message::decode(&encoded[..len]), Err(Error::InsufficientData)));
+        }
+        assert_eq!(Message::frame_len(&encoded[..7]), None);
+        assert!(matches!(Message::decode(&[0, 0, 0, 0, 0, 1, 0, 0]), Err(Error::InvalidMessage)));
+    }
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(Checksum::of(b""), 0x811c9dc5);
+        assert_eq!(Checksum::of(b"a"), 0xe40c292c);
+
+        let mut checksum = Checksum::default();
+        for chunk in b"firmware image".chunks(4) {
+            checksum.update(chunk);
+        }
+        assert_eq!(checksum.value(), Checksum::of(b"firmware image"));
+    }
+
     #[test]
     fn test_encode_invalid_message() {
         let long_string = "a".repeat(u16::MAX as usize + 1);
         let msg = Message::ClientReady {
             modules: vec![long_string],
             device_ram: 0,
+            labels: Vec::new(),
+            executor: ExecutorFlavor::Interpreter,
+            arch: String::new(),
+            resume_token: None,
         };
         let result = msg.encode();
         assert!(result.is_err());
@@ -263,6 +992,10 @@ mod tests {
         let msg = Message::ClientReady {
             modules: Vec::new(),
             device_ram: 0,
+            labels: Vec::new(),
+            executor: ExecutorFlavor::Interpreter,
+            arch: String::new(),
+            resume_token: None,
         };
         let mut encoded = msg.encode().unwrap();
         if encoded.len() > 2 {
@@ -272,4 +1005,39 @@ mod tests {
         assert!(result.is_err());
         assert!(matches!(result.unwrap_err(), Error::DecodeError(_)));
     }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_json_fixture() {
+        const FIXTURE: &str = r#"{"ClientAck":{"task_id":7,"ack_info":{"ChunkAck":{"chunk_index":3,"success":true}}}}"#;
+
+        let msg = Message::decode_json(FIXTURE).unwrap();
+        assert_eq!(msg, Message::ClientAck {
+            task_id: TaskId(7),
+            ack_info: AckInfo::ChunkAck {
+                chunk_index: 3,
+                success: true,
+            },
+        });
+        assert_eq!(msg.encode_json().unwrap(), FIXTURE);
+
+        let msg = Message::ServerTask {
+            task_id: TaskId(1),
+            attempt: 1,
+            module: ModuleInfo {
+                name: "test".into(),
+                size: 16,
+                chunk_size: 16,
+                total_chunks: 1,
+                pinned: false,
+            },
+            params: vec![Type::V128(-1), Type::Bytes(vec![0, 255]), Type::Void],
+            env: vec![("MODE".into(), "fast".into())],
+            libraries: vec![],
+            priority: 1,
+            checkpoint: None,
+        };
+        assert_eq!(Message::decode_json(&msg.encode_json().unwrap()).unwrap(), msg);
+        assert!(matches!(Message::decode_json("{}"), Err(Error::JsonError(_))));
+    }
 }
@@ -0,0 +1,361 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use bincode::config::{self, Config};
+use bincode::de::read::{BorrowReader, Reader};
+use bincode::de::{BorrowDecode, Decoder, DecoderImpl};
+use bincode::enc::write::SizeWriter;
+use bincode::error::{DecodeError, EncodeError};
+
+use crate::{Error, Message, TaskId};
+
+// Runs `$body` with `$config` bound to the bincode config the options describe, each combination
+// is its own type.
+macro_rules! with_config {
+    ($options:expr, $config:ident => $body:expr) => {{
+        let standard = config::standard().with_limit::<{ ProtocolOptions::DECODE_LIMIT }>();
+        match ($options.int_encoding, $options.endian) {
+            (IntEncoding::Varint, Endian::Big) => {
+                let $config = standard.with_variable_int_encoding().with_big_endian();
+                $body
+            }
+            (IntEncoding::Varint, Endian::Little) => {
+                let $config = standard.with_variable_int_encoding().with_little_endian();
+                $body
+            }
+            (IntEncoding::Fixed, Endian::Big) => {
+                let $config = standard.with_fixed_int_encoding().with_big_endian();
+                $body
+            }
+            (IntEncoding::Fixed, Endian::Little) => {
+                let $config = standard.with_fixed_int_encoding().with_little_endian();
+                $body
+            }
+        }
+    }};
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntEncoding {
+    Varint,
+    Fixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+// How frames are laid out and how large they may get. Both peers must agree on the encoding, the
+// default is what every shipped peer speaks. `max_payload` is local, a device with a small heap
+// lowers it to refuse large frames from their header, before any of the payload is buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolOptions {
+    pub int_encoding: IntEncoding,
+    pub endian: Endian,
+    pub max_payload: u16,
+}
+
+// A decoded frame, see `ProtocolOptions::decode_borrowed`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageRef<'a> {
+    ServerModule {
+        task_id: TaskId,
+        chunk_index: u32,
+        chunk_data: &'a [u8],
+    },
+    Owned(Message),
+}
+
+impl Default for ProtocolOptions {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl ProtocolOptions {
+    pub const DEFAULT: Self = Self {
+        int_encoding: IntEncoding::Varint,
+        endian: Endian::Big,
+        max_payload: u16::MAX,
+    };
+
+    // Memory a decode may claim for containers at once, whatever their length prefix says, so a
+    // forged length fails before it allocates. bincode claims `len * size_of::<T>()`, every element
+    // takes at least a byte of a frame and none takes more than `ELEMENT_SIZE` bytes in memory.
+    // A frame narrows it down to what its own payload could hold, see `decode_payload`.
+    pub const DECODE_LIMIT: usize = u16::MAX as usize * Self::ELEMENT_SIZE;
+    const ELEMENT_SIZE: usize = 64;
+
+    // Discriminant of ServerModule in `Message`.
+    const SERVER_MODULE: u32 = 2;
+
+    // Bytes `encode` would produce for `message`, header included.
+    pub fn encoded_len(&self, message: &Message) -> Result<usize, Error> {
+        let mut size = SizeWriter::default();
+        with_config!(self, config => bincode::encode_into_writer(message, &mut size, config)).map_err(Error::EncodeError)?;
+        Ok(Message::HEADER_SIZE + size.bytes_written)
+    }
+
+    pub fn encode(&self, message: &Message) -> Result<Vec<u8>, Error> {
+        let mut output = vec![0; self.encoded_len(message)?];
+        self.encode_into(message, &mut output)?;
+        Ok(output)
+    }
+
+    pub fn encode_sequenced(&self, message: &Message, sequence: u32) -> Result<Vec<u8>, Error> {
+        let mut output = vec![0; Message::HEADER_SIZE + Message::SEQUENCE_SIZE + self.encoded_len(message)?];
+        self.encode_sequenced_into(message, sequence, &mut output)?;
+        Ok(output)
+    }
+
+    // Writes the frame to the start of `buf` without allocating and returns its length. A `buf`
+    // too short for it fails with `EncodeError(UnexpectedEnd)`, see `encoded_len`.
+    pub fn encode_into(&self, message: &Message, buf: &mut [u8]) -> Result<usize, Error> {
+        let (header, payload) = buf
+            .split_at_mut_checked(Message::HEADER_SIZE)
+            .ok_or(Error::EncodeError(EncodeError::UnexpectedEnd))?;
+        let payload_len =
+            with_config!(self, config => bincode::encode_into_slice(message, payload, config)).map_err(Error::EncodeError)?;
+
+        if payload_len > self.max_payload as usize {
+            return Err(Error::InvalidMessage);
+        }
+
+        header.copy_from_slice(&(payload_len as u16).to_be_bytes());
+        Ok(Message::HEADER_SIZE + payload_len)
+    }
+
+    pub fn encode_sequenced_into(&self, message: &Message, sequence: u32, buf: &mut [u8]) -> Result<usize, Error> {
+        let (prefix, frame) = buf
+            .split_at_mut_checked(Message::HEADER_SIZE + Message::SEQUENCE_SIZE)
+            .ok_or(Error::EncodeError(EncodeError::UnexpectedEnd))?;
+        let frame_len = self.encode_into(message, frame)?;
+        prefix.copy_from_slice(&Message::sequence_prefix(sequence));
+        Ok(prefix.len() + frame_len)
+    }
+
+    // The message, the sequence number of a sequenced frame and the bytes consumed.
+    pub fn decode(&self, data: &[u8]) -> Result<(Message, Option<u32>, usize), Error> {
+        let (payload, sequence, consumed) = self.frame(data)?;
+        let (message, size) =
+            with_config!(self, config => Self::decode_payload(payload, config)).map_err(Error::DecodeError)?;
+
+        if size != payload.len() {
+            return Err(Error::InvalidMessage);
+        }
+
+        Ok((message, sequence, consumed))
+    }
+
+    // Like `decode`, narrowed down to the messages the peer sends, see `ClientMessage`.
+    pub fn decode_as<M: TryFrom<Message, Error = Error>>(&self, data: &[u8]) -> Result<(M, Option<u32>, usize), Error> {
+        let (message, sequence, consumed) = self.decode(data)?;
+        Ok((M::try_from(message)?, sequence, consumed))
+    }
+
+    // Like `decode`, except that a ServerModule chunk, most of what a device receives, borrows its
+    // data from `data` instead of copying it out.
+    pub fn decode_borrowed<'a>(&self, data: &'a [u8]) -> Result<(MessageRef<'a>, Option<u32>, usize), Error> {
+        let (payload, sequence, consumed) = self.frame(data)?;
+        let (variant, offset) =
+            with_config!(self, config => Self::decode_payload::<u32, _>(payload, config)).map_err(Error::DecodeError)?;
+
+        let (message, size) = match variant {
+            Self::SERVER_MODULE => {
+                let ((task_id, chunk_index, chunk_data), size) = with_config!(self, config => {
+                    Self::decode_payload::<(TaskId, u32, &[u8]), _>(&payload[offset..], config)
+                })
+                .map_err(Error::DecodeError)?;
+                (MessageRef::ServerModule { task_id, chunk_index, chunk_data }, offset + size)
+            }
+            _ => {
+                let (message, size) =
+                    with_config!(self, config => Self::decode_payload(payload, config)).map_err(Error::DecodeError)?;
+                (MessageRef::Owned(message), size)
+            }
+        };
+
+        if size != payload.len() {
+            return Err(Error::InvalidMessage);
+        }
+
+        Ok((message, sequence, consumed))
+    }
+
+    // Decodes `T` from the start of `payload` and returns the bytes it took. Containers share the
+    // `ELEMENT_SIZE` bytes per payload byte left of `DECODE_LIMIT`, claimed up front, so a length
+    // prefix longer than the payload could hold fails with `LimitExceeded` before it allocates.
+    fn decode_payload<'a, T: BorrowDecode<'a, ()>, C: Config>(payload: &'a [u8], config: C) -> Result<(T, usize), DecodeError> {
+        let mut decoder = DecoderImpl::new(PayloadReader { rest: payload }, config, ());
+        decoder.claim_bytes_read(Self::DECODE_LIMIT.saturating_sub(payload.len() * Self::ELEMENT_SIZE))?;
+        let value = T::borrow_decode(&mut decoder)?;
+        Ok((value, payload.len() - decoder.reader().rest.len()))
+    }
+
+    // The payload of the frame at the start of `data`, its sequence number and the bytes it takes.
+    fn frame<'a>(&self, data: &'a [u8]) -> Result<(&'a [u8], Option<u32>, usize), Error> {
+        let (sequence, prefix) = Message::sequence(data)?;
+        let data = &data[prefix..];
+        if data.len() < Message::HEADER_SIZE {
+            return Err(Error::InsufficientData);
+        }
+
+        let payload_len = u16::from_be_bytes([data[0], data[1]]) as usize;
+        let total_len = Message::HEADER_SIZE + payload_len;
+
+        if payload_len > self.max_payload as usize {
+            return Err(Error::FrameTooLarge(payload_len));
+        }
+        if data.len() < total_len {
+            return Err(Error::InsufficientData);
+        }
+        // A second marker, nothing encodes to it.
+        if payload_len == 0 {
+            return Err(Error::InvalidMessage);
+        }
+
+        Ok((&data[Message::HEADER_SIZE..total_len], sequence, prefix + total_len))
+    }
+}
+
+// Like bincode's SliceReader, which does not tell how much of the slice is left.
+struct PayloadReader<'a> {
+    rest: &'a [u8],
+}
+
+impl Reader for PayloadReader<'_> {
+    fn read(&mut self, bytes: &mut [u8]) -> Result<(), DecodeError> {
+        bytes.copy_from_slice(self.take_bytes(bytes.len())?);
+        Ok(())
+    }
+
+    fn peek_read(&mut self, n: usize) -> Option<&[u8]> {
+        self.rest.get(..n)
+    }
+
+    fn consume(&mut self, n: usize) {
+        self.rest = self.rest.get(n..).unwrap_or_default();
+    }
+}
+
+impl<'a> BorrowReader<'a> for PayloadReader<'a> {
+    fn take_bytes(&mut self, length: usize) -> Result<&'a [u8], DecodeError> {
+        let additional = length.saturating_sub(self.rest.len());
+        let (taken, rest) = self.rest.split_at_checked(length).ok_or(DecodeError::UnexpectedEnd { additional })?;
+        self.rest = rest;
+        Ok(taken)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+
+    use super::*;
+    use crate::{ProtocolErrorCode, TaskId, Type};
+
+    #[test]
+    fn test_options_roundtrip() {
+        let msg = Message::ServerResult {
+            task_id: TaskId(300),
+            result: vec![Type::I64(-2), Type::Bytes(vec![1, 2, 3])],
+        };
+        for int_encoding in [IntEncoding::Varint, IntEncoding::Fixed] {
+            for endian in [Endian::Big, Endian::Little] {
+                let options = ProtocolOptions { int_encoding, endian, ..Default::default() };
+                let encoded = options.encode_sequenced(&msg, 9).unwrap();
+                assert_eq!(options.decode(&encoded).unwrap(), (msg.clone(), Some(9), encoded.len()));
+            }
+        }
+
+        // As many elements as fit a frame still decode.
+        let large = Message::ServerResult {
+            task_id: TaskId(1),
+            result: vec![Type::Void; 60_000],
+        };
+        let encoded = large.encode().unwrap();
+        assert_eq!(Message::decode(&encoded).unwrap().0, large);
+
+        let fixed = ProtocolOptions { int_encoding: IntEncoding::Fixed, ..Default::default() };
+        assert_ne!(fixed.encode(&msg).unwrap(), msg.encode().unwrap());
+    }
+
+    #[test]
+    fn test_encode_into() {
+        let chunk = Message::ServerModule {
+            task_id: TaskId(7),
+            chunk_index: 3,
+            chunk_data: vec![9; 40],
+        };
+        let mut buf = [0; 80];
+        for int_encoding in [IntEncoding::Varint, IntEncoding::Fixed] {
+            let options = ProtocolOptions { int_encoding, ..Default::default() };
+            let len = options.encode_into(&chunk, &mut buf).unwrap();
+            assert_eq!(&buf[..len], options.encode(&chunk).unwrap());
+            assert_eq!(options.encoded_len(&chunk).unwrap(), len);
+
+            let (decoded, sequence, consumed) = options.decode_borrowed(&buf).unwrap();
+            assert_eq!((sequence, consumed), (None, len));
+            let MessageRef::ServerModule { task_id, chunk_index, chunk_data } = decoded else {
+                panic!("expected a borrowed chunk, got {:?}", decoded);
+            };
+            assert_eq!((task_id, chunk_index), (TaskId(7), 3));
+            assert!(buf.as_ptr_range().contains(&chunk_data.as_ptr()));
+            assert_eq!(chunk_data, [9; 40]);
+        }
+
+        let len = chunk.encode_into(&mut buf).unwrap();
+        assert!(matches!(
+            chunk.encode_into(&mut buf[..len - 1]),
+            Err(Error::EncodeError(bincode::error::EncodeError::UnexpectedEnd))
+        ));
+        let len = ProtocolOptions::DEFAULT.encode_sequenced_into(&chunk, 5, &mut buf).unwrap();
+        assert_eq!(&buf[..len], chunk.encode_sequenced(5).unwrap());
+
+        // Anything but a chunk is decoded as usual.
+        let unpin = Message::ServerUnpin { module: "blink".into() };
+        let len = unpin.encode_into(&mut buf).unwrap();
+        assert_eq!(Message::decode_borrowed(&buf).unwrap(), (MessageRef::Owned(unpin), len));
+    }
+
+    #[test]
+    fn test_options_limits() {
+        let msg = Message::ServerUnpin { module: "a".repeat(100) };
+        let encoded = msg.encode().unwrap();
+        let small = ProtocolOptions { max_payload: 64, ..Default::default() };
+        assert!(matches!(small.encode(&msg), Err(Error::InvalidMessage)));
+
+        // Refused from the header alone, the payload has not arrived yet.
+        let error = small.decode(&encoded[..Message::HEADER_SIZE]).unwrap_err();
+        assert!(matches!(error, Error::FrameTooLarge(len) if len == encoded.len() - Message::HEADER_SIZE));
+        assert_eq!(ProtocolErrorCode::of(&error), Some(ProtocolErrorCode::TooLarge));
+
+        // A ServerUnpin whose module name claims 4 GiB fails without allocating it.
+        let forged = [0, 6, 6, 252, 0xff, 0xff, 0xff, 0xff];
+        assert!(matches!(
+            Message::decode(&forged),
+            Err(Error::DecodeError(bincode::error::DecodeError::LimitExceeded))
+        ));
+    }
+
+    #[test]
+    fn test_forged_lengths() {
+        // Lengths well within `DECODE_LIMIT` that the frame cannot hold are refused before the
+        // container is allocated, reading past the payload would fail with UnexpectedEnd instead.
+        let name = [0, 4, 6, 251, 0xea, 0x60];
+        let result = [0, 5, 10, 1, 251, 0x03, 0xe8];
+        let chunk = [0, 6, 2, 7, 0, 251, 0x03, 0xe8];
+        for forged in [&name[..], &result[..], &chunk[..]] {
+            assert!(matches!(
+                Message::decode(forged),
+                Err(Error::DecodeError(DecodeError::LimitExceeded))
+            ));
+            assert!(matches!(
+                Message::decode_borrowed(forged),
+                Err(Error::DecodeError(DecodeError::LimitExceeded))
+            ));
+        }
+    }
+}
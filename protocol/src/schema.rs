@@ -0,0 +1,484 @@
+use alloc::string::String;
+use core::fmt::{self, Write};
+
+use super::Message;
+
+// Field types as they appear on the wire. Integers wider than a byte are varints, signed ones
+// zigzag encoded first, `List` and `String` carry a varint length and enum variants a varint
+// index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ty {
+    Bool,
+    U8,
+    U32,
+    U64,
+    I8,
+    I32,
+    I64,
+    I128,
+    F32,
+    F64,
+    String,
+    Option(&'static Ty),
+    List(&'static Ty),
+    // Elements encoded one after the other, without a length.
+    Tuple(&'static [Ty]),
+    // Another definition in the same schema.
+    Named(&'static str),
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Bool => f.write_str("bool"),
+            Self::U8 => f.write_str("u8"),
+            Self::U32 => f.write_str("u32"),
+            Self::U64 => f.write_str("u64"),
+            Self::I8 => f.write_str("i8"),
+            Self::I32 => f.write_str("i32"),
+            Self::I64 => f.write_str("i64"),
+            Self::I128 => f.write_str("i128"),
+            Self::F32 => f.write_str("f32"),
+            Self::F64 => f.write_str("f64"),
+            Self::String => f.write_str("string"),
+            Self::Option(inner) => write!(f, "option<{}>", inner),
+            Self::List(inner) => write!(f, "list<{}>", inner),
+            Self::Tuple(elements) => {
+                f.write_char('(')?;
+                for (i, element) in elements.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { "" } else { ", " }, element)?;
+                }
+                f.write_char(')')
+            }
+            Self::Named(name) => f.write_str(name),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Field {
+    // Tuple fields are named by their position, "0" for newtypes.
+    pub name: &'static str,
+    pub ty: Ty,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Variant {
+    pub name: &'static str,
+    pub fields: &'static [Field],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shape {
+    Struct(&'static [Field]),
+    // Variants in discriminant order.
+    Enum(&'static [Variant]),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Definition {
+    pub name: &'static str,
+    pub shape: Shape,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Schema {
+    // Every frame is a big-endian length header of this size followed by one `root` value.
+    pub header_size: usize,
+    // A zero length header is followed by a big-endian sequence number of this size, then the
+    // frame proper.
+    pub sequence_size: usize,
+    pub root: &'static str,
+    pub definitions: &'static [Definition],
+}
+
+const fn field(name: &'static str, ty: Ty) -> Field {
+    Field { name, ty }
+}
+
+const fn variant(name: &'static str, fields: &'static [Field]) -> Variant {
+    Variant { name, fields }
+}
+
+const TASK_ID: Ty = Ty::Named("TaskId");
+const STRINGS: Ty = Ty::List(&Ty::String);
+const TYPES: Ty = Ty::List(&Ty::Named("Type"));
+
+const DEFINITIONS: &[Definition] = &[
+    Definition {
+        name: "TaskId",
+        shape: Shape::Struct(&[field("0", Ty::U64)]),
+    },
+    Definition {
+        name: "Type",
+        shape: Shape::Enum(&[
+            variant("Void", &[]),
+            variant("I32", &[field("0", Ty::I32)]),
+            variant("I64", &[field("0", Ty::I64)]),
+            variant("F32", &[field("0", Ty::F32)]),
+            variant("F64", &[field("0", Ty::F64)]),
+            variant("V128", &[field("0", Ty::I128)]),
+            variant("Bytes", &[field("0", Ty::List(&Ty::U8))]),
+            variant("BlobRef", &[field("0", Ty::U64), field("1", Ty::U64), field("2", Ty::U32)]),
+        ]),
+    },
+    Definition {
+        name: "LogLevel",
+        shape: Shape::Enum(&[
+            variant("Error", &[]),
+            variant("Warn", &[]),
+            variant("Info", &[]),
+            variant("Debug", &[]),
+            variant("Trace", &[]),
+        ]),
+    },
+    Definition {
+        name: "ProtocolErrorCode",
+        shape: Shape::Enum(&[
+            variant("Malformed", &[]),
+            variant("TrailingData", &[]),
+            variant("Unexpected", &[]),
+            variant("TooLarge", &[]),
+            variant("Misdirected", &[]),
+        ]),
+    },
+    Definition {
+        name: "ExecutorFlavor",
+        shape: Shape::Enum(&[
+            variant("Interpreter", &[]),
+            variant("Jit", &[]),
+            variant("Aot", &[]),
+        ]),
+    },
+    Definition {
+        name: "ModuleInfo",
+        shape: Shape::Struct(&[
+            field("name", Ty::String),
+            field("size", Ty::U64),
+            field("chunk_size", Ty::U32),
+            field("total_chunks", Ty::U32),
+            field("pinned", Ty::Bool),
+        ]),
+    },
+    Definition {
+        name: "FirmwareInfo",
+        shape: Shape::Struct(&[
+            field("version", Ty::String),
+            field("size", Ty::U64),
+            field("chunk_size", Ty::U32),
+            field("total_chunks", Ty::U32),
+            field("checksum", Ty::U32),
+        ]),
+    },
+    Definition {
+        name: "CacheStats",
+        shape: Shape::Struct(&[
+            field("hits", Ty::U64),
+            field("misses", Ty::U64),
+            field("evictions", Ty::U64),
+            field("bytes_used", Ty::U64),
+        ]),
+    },
+    Definition {
+        name: "Telemetry",
+        shape: Shape::Struct(&[
+            field("free_heap", Ty::Option(&Ty::U64)),
+            field("tasks_executed", Ty::U64),
+            field("uptime_secs", Ty::U64),
+            field("rssi", Ty::Option(&Ty::I8)),
+            field("sequence", Ty::Option(&Ty::Named("SequenceStats"))),
+            field("session", Ty::Named("SessionStats")),
+        ]),
+    },
+    Definition {
+        name: "SessionStats",
+        shape: Shape::Struct(&[
+            field("frames_in", Ty::U64),
+            field("frames_out", Ty::U64),
+            field("bytes_in", Ty::U64),
+            field("bytes_out", Ty::U64),
+            field("decode_errors", Ty::U64),
+            field("executions", Ty::U64),
+            field("cache_hits", Ty::U64),
+        ]),
+    },
+    Definition {
+        name: "SequenceStats",
+        shape: Shape::Struct(&[
+            field("received", Ty::U64),
+            field("duplicates", Ty::U64),
+            field("reordered", Ty::U64),
+            field("missing", Ty::U64),
+        ]),
+    },
+    Definition {
+        name: "ExecutionStats",
+        shape: Shape::Struct(&[
+            field("wall_time_us", Ty::U64),
+            field("peak_memory", Ty::U64),
+            field("instructions", Ty::Option(&Ty::U64)),
+        ]),
+    },
+    Definition {
+        name: "AckInfo",
+        shape: Shape::Enum(&[
+            variant("ChunkAck", &[field("chunk_index", Ty::U32), field("success", Ty::Bool)]),
+            variant("ModuleListAck", &[field("modules", STRINGS)]),
+            variant("TaskAck", &[field("accepted", Ty::Bool)]),
+            variant("FirmwareAck", &[field("verified", Ty::Bool)]),
+        ]),
+    },
+    Definition {
+        name: "Message",
+        shape: Shape::Enum(&[
+            variant("ClientReady", &[
+                field("modules", STRINGS),
+                field("device_ram", Ty::U64),
+                field("labels", STRINGS),
+                field("executor", Ty::Named("ExecutorFlavor")),
+                field("arch", Ty::String),
+                field("resume_token", Ty::Option(&Ty::U64)),
+            ]),
+            variant("ServerTask", &[
+                field("task_id", TASK_ID),
+                field("attempt", Ty::U32),
+                field("module", Ty::Named("ModuleInfo")),
+                field("params", TYPES),
+                field("env", Ty::List(&Ty::Tuple(&[Ty::String, Ty::String]))),
+                field("libraries", Ty::List(&Ty::Named("ModuleInfo"))),
+                field("priority", Ty::U8),
+                field("checkpoint", Ty::Option(&Ty::List(&Ty::U8))),
+            ]),
+            variant("ServerModule", &[
+                field("task_id", TASK_ID),
+                field("chunk_index", Ty::U32),
+                field("chunk_data", Ty::List(&Ty::U8)),
+            ]),
+            variant("ClientAck", &[field("task_id", TASK_ID), field("ack_info", Ty::Named("AckInfo"))]),
+            variant("ClientResult", &[
+                field("task_id", TASK_ID),
+                field("attempt", Ty::U32),
+                field("result", TYPES),
+                field("stats", Ty::Named("ExecutionStats")),
+            ]),
+            variant("ServerAck", &[field("task_id", TASK_ID), field("success", Ty::Bool)]),
+            variant("ServerUnpin", &[field("module", Ty::String)]),
+            variant("Heartbeat", &[field("timestamp", Ty::U64), field("cache", Ty::Named("CacheStats"))]),
+            variant("ClientSubmit", &[
+                field("module_name", Ty::String),
+                field("params", TYPES),
+                field("priority", Ty::U8),
+            ]),
+            variant("ServerSubmitted", &[field("task_id", Ty::Option(&TASK_ID))]),
+            variant("ServerResult", &[field("task_id", TASK_ID), field("result", TYPES)]),
+            variant("ServerRateLimited", &[field("max_messages", Ty::U32), field("max_bytes", Ty::U64)]),
+            variant("ServerRedirect", &[field("addr", Ty::String)]),
+            variant("ServerCancel", &[field("task_id", TASK_ID)]),
+            variant("ServerPrefetch", &[field("task_id", TASK_ID), field("module", Ty::Named("ModuleInfo"))]),
+            variant("ServerFirmware", &[field("task_id", TASK_ID), field("firmware", Ty::Named("FirmwareInfo"))]),
+            variant("ClientLog", &[
+                field("level", Ty::Named("LogLevel")),
+                field("module", Ty::String),
+                field("message", Ty::String),
+                field("timestamp", Ty::U64),
+            ]),
+            variant("ClientTelemetry", &[
+                field("timestamp", Ty::U64),
+                field("cache", Ty::Named("CacheStats")),
+                field("telemetry", Ty::Named("Telemetry")),
+            ]),
+            variant("ServerBusy", &[field("max_sessions", Ty::U32), field("retry_after_secs", Ty::U32)]),
+            variant("ClientCacheUpdate", &[field("added", STRINGS), field("removed", STRINGS)]),
+            variant("ClientProgress", &[
+                field("task_id", TASK_ID),
+                field("percent", Ty::U8),
+                field("stage", Ty::String),
+            ]),
+            variant("ServerSession", &[field("resume_token", Ty::U64), field("resumed", Ty::Bool)]),
+            variant("ProtocolError", &[
+                field("code", Ty::Named("ProtocolErrorCode")),
+                field("detail", Ty::String),
+            ]),
+            variant("ClientBusy", &[field("task_id", TASK_ID), field("queue_len", Ty::U32)]),
+            variant("ClientCheckpoint", &[
+                field("task_id", TASK_ID),
+                field("attempt", Ty::U32),
+                field("data", Ty::List(&Ty::U8)),
+            ]),
+            variant("ServerPing", &[field("nonce", Ty::U32)]),
+            variant("ClientPong", &[field("nonce", Ty::U32)]),
+        ]),
+    },
+];
+
+// Hand-maintained next to the types, the tests below catch a variant added to one but not the
+// other.
+pub fn schema() -> Schema {
+    Schema {
+        header_size: Message::HEADER_SIZE,
+        sequence_size: Message::SEQUENCE_SIZE,
+        root: "Message",
+        definitions: DEFINITIONS,
+    }
+}
+
+impl Schema {
+    pub fn definition(&self, name: &str) -> Option<&'static Definition> {
+        self.definitions.iter().find(|definition| definition.name == name)
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.write_json(&mut out).ok();
+        out
+    }
+
+    fn write_json(&self, out: &mut String) -> fmt::Result {
+        let write_fields = |out: &mut String, fields: &[Field]| -> fmt::Result {
+            out.push('[');
+            for (i, field) in fields.iter().enumerate() {
+                let separator = if i == 0 { "" } else { ", " };
+                write!(out, "{}{{\"name\": \"{}\", \"type\": \"{}\"}}", separator, field.name, field.ty)?;
+            }
+            out.push(']');
+            Ok(())
+        };
+
+        writeln!(out, "{{")?;
+        writeln!(
+            out,
+            "  \"encoding\": {{\"header_size\": {}, \"sequence_size\": {}, \"header_endian\": \"big\", \"int_encoding\": \"varint\", \"endian\": \"big\"}},",
+            self.header_size, self.sequence_size
+        )?;
+        writeln!(out, "  \"root\": \"{}\",", self.root)?;
+        writeln!(out, "  \"definitions\": [")?;
+        for (i, definition) in self.definitions.iter().enumerate() {
+            write!(out, "    {{\"name\": \"{}\", ", definition.name)?;
+            match definition.shape {
+                Shape::Struct(fields) => {
+                    out.push_str("\"kind\": \"struct\", \"fields\": ");
+                    write_fields(out, fields)?;
+                }
+                Shape::Enum(variants) => {
+                    out.push_str("\"kind\": \"enum\", \"variants\": [");
+                    for (index, variant) in variants.iter().enumerate() {
+                        let separator = if index == 0 { "\n" } else { ",\n" };
+                        write!(out, "{}      {{\"name\": \"{}\", \"index\": {}, \"fields\": ", separator, variant.name, index)?;
+                        write_fields(out, variant.fields)?;
+                        out.push('}');
+                    }
+                    out.push_str("\n    ]");
+                }
+            }
+            writeln!(out, "}}{}", if i + 1 == self.definitions.len() { "" } else { "," })?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    use super::*;
+    use crate::*;
+
+    // Exhaustive on purpose, a new variant fails to compile here until the schema knows it.
+    fn variant_name(message: &Message) -> &'static str {
+        match message {
+            Message::ClientReady { .. } => "ClientReady",
+            Message::ServerTask { .. } => "ServerTask",
+            Message::ServerModule { .. } => "ServerModule",
+            Message::ClientAck { .. } => "ClientAck",
+            Message::ClientResult { .. } => "ClientResult",
+            Message::ServerAck { .. } => "ServerAck",
+            Message::ServerUnpin { .. } => "ServerUnpin",
+            Message::Heartbeat { .. } => "Heartbeat",
+            Message::ClientSubmit { .. } => "ClientSubmit",
+            Message::ServerSubmitted { .. } => "ServerSubmitted",
+            Message::ServerResult { .. } => "ServerResult",
+            Message::ServerRateLimited { .. } => "ServerRateLimited",
+            Message::ServerRedirect { .. } => "ServerRedirect",
+            Message::ServerCancel { .. } => "ServerCancel",
+            Message::ServerPrefetch { .. } => "ServerPrefetch",
+            Message::ServerFirmware { .. } => "ServerFirmware",
+            Message::ClientLog { .. } => "ClientLog",
+            Message::ClientTelemetry { .. } => "ClientTelemetry",
+            Message::ServerBusy { .. } => "ServerBusy",
+            Message::ClientCacheUpdate { .. } => "ClientCacheUpdate",
+            Message::ClientProgress { .. } => "ClientProgress",
+            Message::ServerSession { .. } => "ServerSession",
+            Message::ProtocolError { .. } => "ProtocolError",
+            Message::ClientBusy { .. } => "ClientBusy",
+            Message::ClientCheckpoint { .. } => "ClientCheckpoint",
+            Message::ServerPing { .. } => "ServerPing",
+            Message::ClientPong { .. } => "ClientPong",
+        }
+    }
+
+    #[test]
+    fn test_schema_variant_order() {
+        let schema = schema();
+        let Shape::Enum(variants) = schema.definition(schema.root).unwrap().shape else {
+            panic!("root is not an enum");
+        };
+
+        let messages = [
+            Message::ServerUnpin { module: "blink".into() },
+            Message::ServerCancel { task_id: TaskId(1) },
+            Message::ServerSubmitted { task_id: None },
+            Message::ClientTelemetry {
+                timestamp: 0,
+                cache: CacheStats::default(),
+                telemetry: Telemetry::default(),
+            },
+            Message::ProtocolError {
+                code: ProtocolErrorCode::Unexpected,
+                detail: String::new(),
+            },
+            Message::ClientBusy { task_id: TaskId(1), queue_len: 0 },
+            Message::ClientCheckpoint {
+                task_id: TaskId(1),
+                attempt: 0,
+                data: vec![],
+            },
+            Message::ServerPing { nonce: 0 },
+            Message::ClientPong { nonce: 0 },
+        ];
+        for message in messages {
+            let index = variants.iter().position(|variant| variant.name == variant_name(&message)).unwrap();
+            // Discriminants below 251 are a single varint byte right after the header.
+            assert_eq!(message.encode().unwrap()[Message::HEADER_SIZE] as usize, index);
+        }
+        assert_eq!(variants.len(), 27);
+    }
+
+    #[test]
+    fn test_schema_names_resolve() {
+        fn named(ty: Ty, out: &mut Vec<&'static str>) {
+            match ty {
+                Ty::Option(inner) | Ty::List(inner) => named(*inner, out),
+                Ty::Tuple(elements) => elements.iter().for_each(|element| named(*element, out)),
+                Ty::Named(name) => out.push(name),
+                _ => {}
+            }
+        }
+
+        let schema = schema();
+        let mut names = vec![schema.root];
+        for definition in schema.definitions {
+            let fields = match definition.shape {
+                Shape::Struct(fields) => vec![fields],
+                Shape::Enum(variants) => variants.iter().map(|variant| variant.fields).collect(),
+            };
+            for field in fields.into_iter().flatten() {
+                named(field.ty, &mut names);
+            }
+        }
+        assert!(names.iter().all(|name| schema.definition(name).is_some()));
+        assert!(schema.to_json().contains("\"name\": \"ClientTelemetry\", \"index\": 17"));
+        assert!(schema.to_json().contains("{\"name\": \"env\", \"type\": \"list<(string, string)>\"}"));
+    }
+}
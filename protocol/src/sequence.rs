@@ -0,0 +1,117 @@
+// Receiving side of sequenced frames, see `Message::encode_sequenced`. Sequences are compared in
+// serial number arithmetic so the sender's counter may wrap.
+
+// What a receiver saw of its peer's sequence numbers on the current connection.
+#[derive(bincode::Encode, bincode::Decode, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SequenceStats {
+    pub received: u64,
+    // Frames seen before, the receiver drops them.
+    pub duplicates: u64,
+    // Frames that arrived after one sent later than them.
+    pub reordered: u64,
+    // Sequence numbers skipped over and not seen since.
+    pub missing: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SequenceCheck {
+    InOrder,
+    Reordered,
+    Duplicate,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SequenceTracker {
+    highest: Option<u32>,
+    // Bit `i` is set once `highest - 1 - i` arrived.
+    window: u64,
+    stats: SequenceStats,
+}
+
+impl SequenceTracker {
+    // How far behind the highest sequence a late frame is still told apart from a duplicate.
+    pub const WINDOW: u32 = u64::BITS;
+
+    pub fn check(&mut self, sequence: u32) -> SequenceCheck {
+        let Some(highest) = self.highest else {
+            self.highest = Some(sequence);
+            self.stats.received += 1;
+            return SequenceCheck::InOrder;
+        };
+
+        let ahead = sequence.wrapping_sub(highest) as i32;
+        let check = if ahead > 0 {
+            let ahead = ahead as u32;
+            self.stats.missing += (ahead - 1) as u64;
+            self.window = self.window.checked_shl(ahead).unwrap_or(0) | 1u64.checked_shl(ahead - 1).unwrap_or(0);
+            self.highest = Some(sequence);
+            SequenceCheck::InOrder
+        } else {
+            // Frames older than the window cannot be told apart from a replay and are dropped too.
+            let behind = ahead.unsigned_abs();
+            let bit = match behind {
+                0 => None,
+                behind if behind > Self::WINDOW => None,
+                behind => Some(1u64 << (behind - 1)),
+            };
+            match bit {
+                Some(bit) if self.window & bit == 0 => {
+                    self.window |= bit;
+                    self.stats.missing = self.stats.missing.saturating_sub(1);
+                    self.stats.reordered += 1;
+                    SequenceCheck::Reordered
+                }
+                _ => {
+                    self.stats.duplicates += 1;
+                    return SequenceCheck::Duplicate;
+                }
+            }
+        };
+        self.stats.received += 1;
+        check
+    }
+
+    pub fn stats(&self) -> SequenceStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sequence_tracker() {
+        let mut tracker = SequenceTracker::default();
+        let checks = [0, 1, 3, 3, 2, 1, 70, 5]
+            .into_iter()
+            .map(|sequence| tracker.check(sequence))
+            .collect::<alloc::vec::Vec<_>>();
+        assert_eq!(checks, [
+            SequenceCheck::InOrder,
+            SequenceCheck::InOrder,
+            SequenceCheck::InOrder,
+            SequenceCheck::Duplicate,
+            SequenceCheck::Reordered,
+            SequenceCheck::Duplicate,
+            SequenceCheck::InOrder,
+            SequenceCheck::Duplicate,
+        ]);
+        assert_eq!(tracker.stats(), SequenceStats {
+            received: 5,
+            duplicates: 3,
+            reordered: 1,
+            missing: 66,
+        });
+
+        // The counter wrapping around is not a step backwards.
+        let mut tracker = SequenceTracker::default();
+        assert_eq!(tracker.check(u32::MAX), SequenceCheck::InOrder);
+        assert_eq!(tracker.check(0), SequenceCheck::InOrder);
+        assert_eq!(tracker.check(u32::MAX), SequenceCheck::Duplicate);
+        assert_eq!(tracker.stats().missing, 0);
+    }
+}
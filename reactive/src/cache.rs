@@ -0,0 +1,211 @@
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::hash::Hash;
+use core::time::Duration;
+
+use alloc::rc::Rc;
+use alloc::vec::Vec;
+
+use hashbrown::HashMap;
+use program::Clock;
+
+use super::create_root;
+use super::effect::{create_effect, untrack, Scope};
+use super::resource::{use_resource, Resource};
+use super::state::StateHandle;
+
+struct CacheEntry<V, E> {
+    resource: Resource<V, E>,
+    // Clock timestamp of the latest fetch for this key.
+    fetched_at: Rc<Cell<u64>>,
+    _scope: Scope,
+}
+
+pub struct Cached<K, V, E, C> {
+    entries: Rc<RefCell<HashMap<K, CacheEntry<V, E>>>>,
+    ttl: Option<Duration>,
+    clock: Rc<C>,
+}
+
+impl<K, V, E, C> Cached<K, V, E, C>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: 'static,
+    E: 'static,
+    C: Clock + 'static,
+{
+    pub fn get(&self, key: &K) -> Option<Resource<V, E>> {
+        self.entries.borrow().get(key).map(|entry| entry.resource.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.borrow().is_empty()
+    }
+
+    pub fn invalidate(&self, key: &K) {
+        let resource = self.get(key);
+        if let Some(resource) = resource {
+            resource.refetch();
+        }
+    }
+
+    pub fn invalidate_all(&self) {
+        let resources = self.entries.borrow().values().map(|entry| entry.resource.clone()).collect::<Vec<_>>();
+        for resource in resources {
+            resource.refetch();
+        }
+    }
+
+    // Refetches every settled entry older than the ttl, returns how many were refetched.
+    pub fn poll(&self) -> usize {
+        let Some(ttl) = self.ttl else {
+            return 0;
+        };
+        let now = self.clock.timestamp();
+        let expired = self
+            .entries
+            .borrow()
+            .values()
+            .filter(|entry| !*entry.resource.loading().get())
+            .filter(|entry| now.saturating_sub(entry.fetched_at.get()) >= ttl.as_nanos() as u64)
+            .map(|entry| entry.resource.clone())
+            .collect::<Vec<_>>();
+
+        for resource in &expired {
+            resource.refetch();
+        }
+        expired.len()
+    }
+}
+
+// Keeps one resource per key in `keys`, fetched once and reused until invalidated or older than
+// `ttl`. Keys leaving the list dispose their resource along with the scope it was created in.
+pub fn use_cached<K, V, E, C, F, Fut>(
+    keys: StateHandle<Vec<K>>,
+    fetcher: F,
+    ttl: Option<Duration>,
+    clock: C,
+) -> Cached<K, V, E, C>
+where
+    K: Eq + Hash + Clone + 'static,
+    V: 'static,
+    E: 'static,
+    C: Clock + 'static,
+    F: Fn(&K) -> Fut + 'static,
+    Fut: Future<Output = Result<V, E>> + 'static,
+{
+    let cached = Cached {
+        entries: Rc::new(RefCell::new(HashMap::new())),
+        ttl,
+        clock: Rc::new(clock),
+    };
+    let fetcher = Rc::new(fetcher);
+
+    create_effect({
+        let entries = Rc::clone(&cached.entries);
+        let clock = Rc::clone(&cached.clock);
+        move || {
+            let keys = keys.get_tracked();
+            untrack(|| {
+                // Dropped outside the borrow, disposing a scope may run cleanups touching the cache.
+                let removed = {
+                    let mut entries = entries.borrow_mut();
+                    let stale = entries.keys().filter(|key| !keys.contains(key)).cloned().collect::<Vec<_>>();
+                    stale.iter().filter_map(|key| entries.remove(key)).collect::<Vec<_>>()
+                };
+                drop(removed);
+
+                for key in keys.iter() {
+                    if entries.borrow().contains_key(key) {
+                        continue;
+                    }
+
+                    let fetched_at = Rc::new(Cell::new(0));
+                    let mut resource = None;
+                    let scope = create_root(|| {
+                        let (key, fetcher, clock, fetched_at) =
+                            (key.clone(), Rc::clone(&fetcher), Rc::clone(&clock), Rc::clone(&fetched_at));
+                        resource = Some(use_resource(move || {
+                            fetched_at.set(clock.timestamp());
+                            fetcher(&key)
+                        }));
+                    });
+
+                    entries.borrow_mut().insert(key.clone(), CacheEntry {
+                        resource: resource.unwrap(),
+                        fetched_at,
+                        _scope: scope,
+                    });
+                }
+            });
+        }
+    });
+
+    cached
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use core::future;
+    use core::time::Duration;
+
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+
+    use program::Clock;
+
+    use crate::*;
+
+    #[derive(Clone)]
+    struct MockClock(Rc<Cell<u64>>);
+
+    impl Clock for MockClock {
+        fn timestamp(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_cached() {
+        let clock = MockClock(Rc::new(Cell::new(0)));
+        let sessions = StateHandle::new(vec![1u64, 2]);
+        let fetches = Rc::new(Cell::new(0));
+
+        let cached = use_cached(
+            sessions.clone(),
+            {
+                let fetches = fetches.clone();
+                move |id: &u64| {
+                    fetches.set(fetches.get() + 1);
+                    future::ready(Ok::<_, ()>(id * 100))
+                }
+            },
+            Some(Duration::from_secs(10)),
+            clock.clone(),
+        );
+        assert_eq!(fetches.get(), 2);
+        assert_eq!(*cached.get(&2).unwrap().value().get(), Some(200));
+
+        // Known keys are served from the cache, departed ones are disposed.
+        sessions.set(vec![2, 3]);
+        assert_eq!(fetches.get(), 3);
+        assert!(cached.get(&1).is_none());
+        assert_eq!(cached.len(), 2);
+
+        cached.invalidate(&2);
+        assert_eq!(fetches.get(), 4);
+
+        assert_eq!(cached.poll(), 0);
+        clock.0.set(Duration::from_secs(10).as_nanos() as u64);
+        assert_eq!(cached.poll(), 2);
+        assert_eq!(fetches.get(), 6);
+
+        sessions.set(Vec::new());
+        assert!(cached.is_empty());
+    }
+}
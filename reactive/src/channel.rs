@@ -0,0 +1,95 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use super::state::StateHandle;
+
+// Send half of a channel signal, cheap to clone and safe to move onto other threads.
+pub struct SignalSender<T>(Sender<T>);
+
+impl<T> Clone for SignalSender<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> SignalSender<T> {
+    // Hands the value back once the receiving half is gone.
+    pub fn send(&self, value: T) -> Result<(), T> {
+        self.0.send(value).map_err(|e| e.0)
+    }
+}
+
+pub struct ChannelSignal<T> {
+    receiver: Receiver<T>,
+    signal: StateHandle<Option<T>>,
+}
+
+impl<T: 'static> ChannelSignal<T> {
+    // Latest value taken off the channel, `None` until the first flush that finds one.
+    pub fn signal(&self) -> StateHandle<Option<T>> {
+        self.signal.clone()
+    }
+
+    // Drains everything queued since the last flush and notifies once with the newest value,
+    // so a burst of messages costs one effect run. Returns how many messages were drained.
+    pub fn flush(&self) -> usize {
+        let mut latest = None;
+        let mut drained = 0;
+        while let Ok(value) = self.receiver.try_recv() {
+            latest = Some(value);
+            drained += 1;
+        }
+
+        if latest.is_some() {
+            self.signal.set(latest);
+        }
+        drained
+    }
+}
+
+// Bridges code outside the reactive graph into it. The sender may live on any thread, the
+// signal only changes when the host loop calls `flush` on the thread owning the root.
+pub fn create_channel_signal<T: 'static>() -> (SignalSender<T>, ChannelSignal<T>) {
+    let (sender, receiver) = mpsc::channel();
+    let signal = StateHandle::new(None);
+    (SignalSender(sender), ChannelSignal { receiver, signal })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use crate::*;
+
+    #[test]
+    fn test_channel_signal() {
+        let (sender, channel) = create_channel_signal::<u32>();
+        let runs = StateHandle::new(0);
+        let seen = StateHandle::new(None);
+
+        create_effect({
+            let (signal, runs, seen) = (channel.signal(), runs.clone(), seen.clone());
+            move || {
+                seen.set(*signal.get_tracked());
+                runs.set(*runs.get() + 1);
+            }
+        });
+        assert_eq!(channel.flush(), 0);
+        assert_eq!(*runs.get(), 1);
+
+        thread::spawn(move || {
+            for reading in 1..=3 {
+                sender.send(reading).unwrap();
+            }
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(channel.flush(), 3);
+        assert_eq!(*runs.get(), 2);
+        assert_eq!(*seen.get(), Some(3));
+
+        // Every sender is gone, flushing keeps the last value.
+        assert_eq!(channel.flush(), 0);
+        assert_eq!(*channel.signal().get(), Some(3));
+    }
+}
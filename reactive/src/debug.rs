@@ -0,0 +1,170 @@
+use core::cell::Cell;
+use core::panic::Location;
+
+#[cfg(feature = "debug-leaks")]
+use alloc::collections::BTreeMap;
+#[cfg(feature = "debug-leaks")]
+use alloc::vec::Vec;
+#[cfg(feature = "debug-leaks")]
+use core::cell::RefCell;
+
+use super::effect::{orphan_effects, scheduled_effects};
+
+thread_local! {
+    static SIGNALS: Cell<usize> = const { Cell::new(0) };
+    static EFFECTS: Cell<usize> = const { Cell::new(0) };
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static DEEPEST: Cell<usize> = const { Cell::new(0) };
+}
+
+#[cfg(feature = "debug-leaks")]
+thread_local! {
+    static NEXT_NODE: Cell<usize> = const { Cell::new(0) };
+    static LIVE: RefCell<BTreeMap<usize, (NodeKind, &'static Location<'static>)>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeKind {
+    Signal,
+    Effect,
+}
+
+impl NodeKind {
+    fn counter(self) -> &'static std::thread::LocalKey<Cell<usize>> {
+        match self {
+            Self::Signal => &SIGNALS,
+            Self::Effect => &EFFECTS,
+        }
+    }
+}
+
+// Counts for the current thread, every reactive graph lives on the thread that created it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReactiveStats {
+    pub signals: usize,
+    pub effects: usize,
+    // Deferred effects notified but not yet flushed.
+    pub dirty: usize,
+    // Deepest nesting of roots seen so far, every effect run opens one.
+    pub deepest_scope: usize,
+    // Effects created outside any root, nothing ever disposes them.
+    pub orphans: usize,
+}
+
+pub fn stats() -> ReactiveStats {
+    ReactiveStats {
+        signals: SIGNALS.with(Cell::get),
+        effects: EFFECTS.with(Cell::get),
+        dirty: scheduled_effects(),
+        deepest_scope: DEEPEST.with(Cell::get),
+        orphans: orphan_effects(),
+    }
+}
+
+// Held by every signal and effect, keeps the counters and with `debug-leaks` the creation site.
+pub(super) struct NodeGuard {
+    kind: NodeKind,
+    #[cfg(feature = "debug-leaks")]
+    id: usize,
+}
+
+impl NodeGuard {
+    #[track_caller]
+    pub(super) fn new(kind: NodeKind) -> Self {
+        let _location = Location::caller();
+        kind.counter().with(|count| count.set(count.get() + 1));
+
+        #[cfg(feature = "debug-leaks")]
+        {
+            let id = NEXT_NODE.with(|next| next.replace(next.get() + 1));
+            LIVE.with(|live| live.borrow_mut().insert(id, (kind, _location)));
+            Self { kind, id }
+        }
+        #[cfg(not(feature = "debug-leaks"))]
+        Self { kind }
+    }
+}
+
+impl Drop for NodeGuard {
+    fn drop(&mut self) {
+        // Nodes may outlive the thread locals during thread teardown.
+        self.kind.counter().try_with(|count| count.set(count.get().saturating_sub(1))).ok();
+        #[cfg(feature = "debug-leaks")]
+        LIVE.try_with(|live| live.borrow_mut().remove(&self.id)).ok();
+    }
+}
+
+pub(super) fn enter_scope() {
+    let depth = DEPTH.with(|depth| {
+        depth.set(depth.get() + 1);
+        depth.get()
+    });
+    DEEPEST.with(|deepest| deepest.set(deepest.get().max(depth)));
+}
+
+pub(super) fn leave_scope() {
+    DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+}
+
+// Every node still alive with where it was created, oldest first.
+#[cfg(feature = "debug-leaks")]
+pub fn live_nodes() -> Vec<(NodeKind, &'static Location<'static>)> {
+    LIVE.with(|live| live.borrow().values().copied().collect())
+}
+
+// Logs every node still alive, meant for points where the graph should have been torn down.
+#[cfg(feature = "debug-leaks")]
+pub fn log_leaks() -> usize {
+    let nodes = live_nodes();
+    for (kind, location) in &nodes {
+        log::warn!("{:?} created at {} was never disposed", kind, location);
+    }
+    nodes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    #[test]
+    fn test_stats() {
+        let before = stats();
+        let state = StateHandle::new(0);
+        let root = create_root(|| {
+            create_effect({
+                let state = state.clone();
+                move || {
+                    state.track();
+                    create_effect(|| {});
+                }
+            });
+        });
+
+        let during = stats();
+        assert_eq!(during.signals, before.signals + 1);
+        assert_eq!(during.effects, before.effects + 2);
+        assert!(during.deepest_scope >= 3);
+        assert_eq!(during.orphans, before.orphans);
+
+        // A re-run replaces the inner effect rather than adding one.
+        state.set(1);
+        assert_eq!(stats().effects, during.effects);
+
+        drop(root);
+        drop(state);
+        assert_eq!(stats().effects, before.effects);
+        assert_eq!(stats().signals, before.signals);
+    }
+
+    #[cfg(feature = "debug-leaks")]
+    #[test]
+    fn test_live_nodes() {
+        let state = StateHandle::new(0);
+        let (kind, location) = *live_nodes().last().unwrap();
+        assert_eq!(kind, NodeKind::Signal);
+        assert_eq!(location.file(), file!());
+
+        drop(state);
+        assert!(live_nodes().iter().all(|(_, location)| location.file() != file!()));
+    }
+}
@@ -1,18 +1,47 @@
 use core::any::Any;
-use core::cell::RefCell;
+use core::cell::{Cell, RefCell};
 use core::hash::{Hash, Hasher};
 use core::{mem, ptr};
 
+use alloc::collections::VecDeque;
 use alloc::rc::{Rc, Weak};
 
 use hashbrown::HashSet;
 
 use super::create_root;
+use super::debug::{NodeGuard, NodeKind};
 use super::state::SignalEmitter;
 
 thread_local! {
     pub(super) static CONTEXTS: RefCell<Vec<Weak<RefCell<Option<Effect>>>>> = const { RefCell::new(Vec::new()) };
     pub(super) static OWNER: RefCell<Option<Scope>> = const { RefCell::new(None) };
+    // Deferred effects waiting for `flush_effects`, normal priority first and idle second.
+    static SCHEDULED: RefCell<[VecDeque<Scheduled>; 2]> = const { RefCell::new([VecDeque::new(), VecDeque::new()]) };
+    // Owner of effects created outside any root.
+    static GLOBAL: RefCell<Scope> = RefCell::new(Scope::default());
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EffectPriority {
+    // Re-runs inside the `set` that changed a dependency.
+    #[default]
+    Immediate,
+    // Queued until `flush_effects`.
+    Normal,
+    // Queued like normal effects, but only run once no normal effect is waiting.
+    Idle,
+}
+
+// Lets a queued effect through on its next call instead of queueing it again.
+#[derive(Default)]
+struct Dispatch {
+    queued: Cell<bool>,
+    release: Cell<bool>,
+}
+
+struct Scheduled {
+    execute: Weak<RefCell<dyn FnMut()>>,
+    dispatch: Rc<Dispatch>,
 }
 
 #[derive(Clone)]
@@ -42,6 +71,7 @@ pub(super) struct Effect {
     pub(super) execute: Rc<RefCell<dyn FnMut()>>,
     pub(super) dependencies: HashSet<Dependency>,
     scope: Scope,
+    _node: NodeGuard,
 }
 
 impl Effect {
@@ -71,6 +101,26 @@ impl Scope {
     pub(super) fn add_cleanup(&mut self, cleanup: Box<dyn FnOnce()>) {
         self.cleanup.push(cleanup);
     }
+
+    // Re-enters the root, effects and cleanups created by `f` are owned by this scope instead of
+    // the current owner or the thread wide fallback. Roots living side by side on one thread stay
+    // independent, dropping one disposes only what was created in it.
+    pub fn run_in<R>(&mut self, f: impl FnOnce() -> R) -> R {
+        let outer = OWNER.with(|owner| owner.replace(Some(mem::take(self))));
+        let ret = f();
+        *self = OWNER
+            .with(|owner| owner.replace(outer))
+            .expect("Owner should be valid inside the reactive root");
+        ret
+    }
+
+    #[track_caller]
+pub fn create_effect<F>(&mut self, effect: F)
+    where
+        F: FnMut() + 'static,
+    {
+        self.run_in(|| create_effect(effect));
+    }
 }
 
 impl Drop for Scope {
@@ -85,10 +135,18 @@ impl Drop for Scope {
     }
 }
 
-pub(super) fn create_effect_dyn(
-    initial: Box<dyn FnOnce() -> (Box<dyn FnMut()>, Box<dyn Any>)>,
-) -> Box<dyn Any> {
+pub(super) type EffectInitial = Box<dyn FnOnce() -> (Box<dyn FnMut()>, Box<dyn Any>)>;
+
+#[track_caller]
+pub(super) fn create_effect_dyn(initial: EffectInitial) -> Box<dyn Any> {
+    create_effect_scheduled(initial, EffectPriority::Immediate)
+}
+
+#[track_caller]
+fn create_effect_scheduled(initial: EffectInitial, priority: EffectPriority) -> Box<dyn Any> {
+    let node = NodeGuard::new(NodeKind::Effect);
     let running: Rc<RefCell<Option<Effect>>> = Rc::new(RefCell::new(None));
+    let dispatch = Rc::new(Dispatch::default());
 
     let mut effect: Option<Box<dyn FnMut()>> = None;
     let ret: Rc<RefCell<Option<Box<dyn Any>>>> = Rc::new(RefCell::new(None));
@@ -98,7 +156,22 @@ pub(super) fn create_effect_dyn(
     let execute: Rc<RefCell<dyn FnMut()>> = Rc::new(RefCell::new({
         let running = Rc::downgrade(&running);
         let ret = Rc::downgrade(&ret);
+        let dispatch = Rc::clone(&dispatch);
         move || {
+            // Deferred effects only queue themselves when notified, the flush runs them.
+            if priority != EffectPriority::Immediate && initial.is_none() && !dispatch.release.replace(false) {
+                if !dispatch.queued.replace(true) {
+                    let running = running.upgrade().unwrap();
+                    let entry = Scheduled {
+                        execute: Rc::downgrade(&running.borrow().as_ref().unwrap().execute),
+                        dispatch: Rc::clone(&dispatch),
+                    };
+                    let phase = (priority == EffectPriority::Idle) as usize;
+                    SCHEDULED.with(|scheduled| scheduled.borrow_mut()[phase].push_back(entry));
+                }
+                return;
+            }
+
             CONTEXTS.with(|effects| {
                 let initial_context_size = effects.borrow().len();
 
@@ -156,6 +229,7 @@ pub(super) fn create_effect_dyn(
         execute: Rc::clone(&execute),
         dependencies: HashSet::new(),
         scope: Default::default(),
+        _node: node,
     });
     debug_assert_eq!(
         Rc::strong_count(&running),
@@ -167,9 +241,6 @@ pub(super) fn create_effect_dyn(
         if scope.borrow().is_some() {
             scope.borrow_mut().as_mut().unwrap().add_effect(running);
         } else {
-            thread_local! {
-                static GLOBAL: RefCell<Scope> = RefCell::new(Scope::default());
-            }
             GLOBAL.with(|global| global.borrow_mut().add_effect(running));
         }
     });
@@ -180,6 +251,7 @@ pub(super) fn create_effect_dyn(
     ret.into_inner().unwrap()
 }
 
+#[track_caller]
 pub fn create_effect_init<R: 'static>(
     initial: impl FnOnce() -> (Box<dyn FnMut()>, R) + 'static,
 ) -> R {
@@ -201,6 +273,51 @@ where
     }));
 }
 
+// Like `create_effect`, the first run is immediate, later runs follow `priority`.
+#[track_caller]
+pub fn create_effect_with_priority<F>(priority: EffectPriority, mut effect: F)
+where
+    F: FnMut() + 'static,
+{
+    create_effect_scheduled(
+        Box::new(|| {
+            effect();
+            (Box::new(effect), Box::new(()))
+        }),
+        priority,
+    );
+}
+
+// Runs queued effects until none is left, normal ones before idle ones. An idle effect that
+// queues normal work lets that run first. Returns how many effects ran.
+pub fn flush_effects() -> usize {
+    let mut ran = 0;
+    loop {
+        let next = SCHEDULED.with(|scheduled| {
+            let mut scheduled = scheduled.borrow_mut();
+            scheduled[0].pop_front().or_else(|| scheduled[1].pop_front())
+        });
+        let Some(next) = next else {
+            return ran;
+        };
+
+        next.dispatch.queued.set(false);
+        if let Some(execute) = next.execute.upgrade() {
+            next.dispatch.release.set(true);
+            execute.borrow_mut()();
+            ran += 1;
+        }
+    }
+}
+
+pub(super) fn scheduled_effects() -> usize {
+    SCHEDULED.with(|scheduled| scheduled.borrow().iter().map(VecDeque::len).sum())
+}
+
+pub(super) fn orphan_effects() -> usize {
+    GLOBAL.with(|global| global.borrow().effects.len())
+}
+
 pub fn untrack<T>(f: impl FnOnce() -> T) -> T {
     let f = Rc::new(RefCell::new(Some(f)));
     let g = Rc::clone(&f);
@@ -403,4 +520,73 @@ mod tests {
         state.set(2);
         assert_eq!(*counter.get_tracked(), 2);
     }
+
+    #[test]
+    fn test_effect_priorities() {
+        let state = StateHandle::new(0);
+        let order = StateHandle::new(Vec::new());
+
+        for (priority, name) in [
+            (EffectPriority::Idle, "idle"),
+            (EffectPriority::Normal, "normal"),
+            (EffectPriority::Immediate, "immediate"),
+        ] {
+            create_effect_with_priority(priority, {
+                let (state, order) = (state.clone(), order.clone());
+                move || {
+                    state.track();
+                    let mut runs = (*order.get()).clone();
+                    runs.push(name);
+                    order.set(runs);
+                }
+            });
+        }
+        order.set(Vec::new());
+
+        // Repeated changes queue each deferred effect once.
+        state.set(1);
+        state.set(2);
+        assert_eq!(*order.get(), vec!["immediate", "immediate"]);
+
+        assert_eq!(flush_effects(), 2);
+        assert_eq!(*order.get(), vec!["immediate", "immediate", "normal", "idle"]);
+        assert_eq!(flush_effects(), 0);
+    }
+
+    #[test]
+    fn test_independent_roots() {
+        let state = StateHandle::new(0);
+        let (server_runs, device_runs) = (StateHandle::new(0), StateHandle::new(0));
+
+        let mut server = Scope::default();
+        let mut device = create_root(|| {});
+        for (root, runs) in [(&mut server, &server_runs), (&mut device, &device_runs)] {
+            root.create_effect({
+                let (state, runs) = (state.clone(), runs.clone());
+                move || {
+                    state.track();
+                    runs.set(*runs.get() + 1);
+                }
+            });
+        }
+
+        state.set(1);
+        assert_eq!((*server_runs.get(), *device_runs.get()), (2, 2));
+
+        drop(server);
+        state.set(2);
+        assert_eq!((*server_runs.get(), *device_runs.get()), (2, 3));
+
+        // Effects added later still belong to the same root.
+        let later = StateHandle::new(0);
+        device.run_in(|| {
+            create_effect({
+                let (state, later) = (state.clone(), later.clone());
+                move || later.set(*state.get_tracked())
+            })
+        });
+        drop(device);
+        state.set(3);
+        assert_eq!((*device_runs.get(), *later.get()), (3, 2));
+    }
 }
@@ -0,0 +1,209 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use program::{Clock, Error, Executor, Session, SessionPhase, StepStatus, TaskId, Transport};
+
+use super::state::{transaction, StateHandle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionStatus {
+    // No step has run yet, ClientReady is still to be sent.
+    Connecting,
+    Connected,
+    // The last step failed on the transport, the host decides whether to reconnect.
+    Disconnected,
+}
+
+// Drives a program session and mirrors what a device UI cares about into signals. Signals are
+// only set when their value changed, all of them at once after each step.
+pub struct ReactiveSession<T: Transport, E: Executor, C: Clock> {
+    session: Session<T, E, C>,
+    status: StateHandle<ConnectionStatus>,
+    phase: StateHandle<SessionPhase>,
+    active_task: StateHandle<Option<TaskId>>,
+    progress: StateHandle<Option<(usize, usize)>>,
+    cached_modules: StateHandle<Vec<String>>,
+}
+
+fn set_if_changed<V: PartialEq + 'static>(signal: &StateHandle<V>, value: V) {
+    if *signal.get() != value {
+        signal.set(value);
+    }
+}
+
+impl<T: Transport, E: Executor, C: Clock> ReactiveSession<T, E, C> {
+    pub fn new(session: Session<T, E, C>) -> Self {
+        let snapshot = session.snapshot();
+        Self {
+            session,
+            status: StateHandle::new(ConnectionStatus::Connecting),
+            phase: StateHandle::new(snapshot.phase),
+            active_task: StateHandle::new(snapshot.active_task),
+            progress: StateHandle::new(snapshot.progress),
+            cached_modules: StateHandle::new(snapshot.cached_modules),
+        }
+    }
+
+    pub fn session(&self) -> &Session<T, E, C> {
+        &self.session
+    }
+
+    pub fn session_mut(&mut self) -> &mut Session<T, E, C> {
+        &mut self.session
+    }
+
+    pub fn status(&self) -> StateHandle<ConnectionStatus> {
+        self.status.clone()
+    }
+
+    pub fn phase(&self) -> StateHandle<SessionPhase> {
+        self.phase.clone()
+    }
+
+    pub fn active_task(&self) -> StateHandle<Option<TaskId>> {
+        self.active_task.clone()
+    }
+
+    // Chunks received and expected while a module or firmware image is transferring.
+    pub fn progress(&self) -> StateHandle<Option<(usize, usize)>> {
+        self.progress.clone()
+    }
+
+    pub fn cached_modules(&self) -> StateHandle<Vec<String>> {
+        self.cached_modules.clone()
+    }
+
+    pub fn step(&mut self) -> Result<StepStatus, Error> {
+        let result = self.session.step();
+        let snapshot = self.session.snapshot();
+        let status = match result {
+            Ok(_) => ConnectionStatus::Connected,
+            Err(_) => ConnectionStatus::Disconnected,
+        };
+
+        transaction(|| {
+            set_if_changed(&self.status, status);
+            set_if_changed(&self.phase, snapshot.phase);
+            set_if_changed(&self.active_task, snapshot.active_task);
+            set_if_changed(&self.progress, snapshot.progress);
+            set_if_changed(&self.cached_modules, snapshot.cached_modules);
+            Ok::<_, ()>(())
+        })
+        .ok();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+
+    use alloc::rc::Rc;
+    use alloc::vec::Vec;
+
+    use program::{Buf, BufMut, Clock, Executor, Session, SessionPhase, TaskId, Transport, Type};
+    use protocol::{Message, ModuleInfo};
+
+    use crate::*;
+
+    #[derive(Clone, Default)]
+    struct MockTransport(Rc<RefCell<Vec<u8>>>);
+
+    impl Transport for MockTransport {
+        type Error = Infallible;
+
+        fn read<B>(&mut self, buf: &mut B) -> Result<usize, Self::Error>
+        where
+            B: BufMut + ?Sized,
+        {
+            let data = core::mem::take(&mut *self.0.borrow_mut());
+            buf.put_slice(&data);
+            Ok(data.len())
+        }
+
+        fn write<B>(&mut self, src: &mut B) -> Result<usize, Self::Error>
+        where
+            B: Buf,
+        {
+            Ok(src.remaining())
+        }
+    }
+
+    struct MockClock;
+
+    impl Clock for MockClock {
+        fn timestamp(&self) -> u64 {
+            0
+        }
+    }
+
+    struct EchoExecutor;
+
+    impl Executor for EchoExecutor {
+        type Error = Infallible;
+
+        fn execute(&self, _module: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+            Ok(params)
+        }
+    }
+
+    #[test]
+    fn test_reactive_session() {
+        let transport = MockTransport::default();
+        let mut session = ReactiveSession::new(Session::new(transport.clone(), EchoExecutor, MockClock, 4096));
+        assert_eq!(*session.status().get(), ConnectionStatus::Connecting);
+
+        let progress_runs = StateHandle::new(0);
+        create_effect({
+            let (progress, progress_runs) = (session.progress(), progress_runs.clone());
+            move || {
+                progress.track();
+                progress_runs.set(*progress_runs.get() + 1);
+            }
+        });
+
+        let deliver = |message: Message| transport.0.borrow_mut().extend_from_slice(&message.encode().unwrap());
+        deliver(Message::ServerTask {
+            task_id: TaskId(7),
+            attempt: 1,
+            module: ModuleInfo {
+                name: "blink".into(),
+                size: 32,
+                chunk_size: 16,
+                total_chunks: 2,
+                pinned: false,
+            },
+            params: vec![Type::I32(1)],
+            env: vec![],
+            libraries: vec![],
+            priority: 1,
+            checkpoint: None,
+        });
+        deliver(Message::ServerModule {
+            task_id: TaskId(7),
+            chunk_index: 0,
+            chunk_data: vec![0; 16],
+        });
+        session.step().unwrap();
+        assert_eq!(*session.status().get(), ConnectionStatus::Connected);
+        assert_eq!(*session.phase().get(), SessionPhase::Transferring);
+        assert_eq!(*session.active_task().get(), Some(TaskId(7)));
+        assert_eq!(*session.progress().get(), Some((1, 2)));
+
+        // A step that changes nothing leaves dependent effects alone.
+        session.step().unwrap();
+        assert_eq!(*progress_runs.get(), 2);
+
+        deliver(Message::ServerModule {
+            task_id: TaskId(7),
+            chunk_index: 1,
+            chunk_data: vec![0; 16],
+        });
+        session.step().unwrap();
+        session.step().unwrap();
+        assert_eq!(*session.phase().get(), SessionPhase::Ready);
+        assert_eq!(*session.progress().get(), None);
+        assert_eq!(*session.cached_modules().get(), vec!["blink".to_string()]);
+    }
+}
@@ -1,22 +1,42 @@
 #[macro_use]
 extern crate alloc;
 
+mod cache;
+mod channel;
+mod debug;
 mod effect;
+mod firmware;
 mod iter;
+mod resource;
 mod state;
+mod store;
+#[cfg(feature = "async")]
+mod stream;
+mod timing;
 
 use core::{ffi, mem, ptr};
 
+pub use cache::*;
+pub use channel::*;
+pub use debug::*;
 pub use effect::*;
+pub use firmware::*;
 pub use iter::*;
+pub use resource::*;
 pub use state::*;
+pub use store::*;
+#[cfg(feature = "async")]
+pub use stream::*;
+pub use timing::*;
 
 #[must_use = "create_root returns the owner of the effects created inside this scope"]
 pub fn create_root<'a>(callback: impl FnOnce() + 'a) -> Scope {
     fn internal<'a>(callback: Box<dyn FnOnce() + 'a>) -> Scope {
         OWNER.with(|scope| {
             let outer_scope = scope.replace(Some(Default::default()));
+            debug::enter_scope();
             callback();
+            debug::leave_scope();
 
             scope
                 .replace(outer_scope)
@@ -63,6 +83,10 @@ pub extern "C" fn use_state_buf(buffer: CValueBuffer) -> *mut CStateBufHandle {
     Box::into_raw(Box::new(CStateBufHandle(StateHandle::new(vec))))
 }
 
+// The handle functions take pointers returned by `use_state` and `use_state_buf`. The C caller
+// keeps a handle alive while it uses it, which clippy cannot see, so they keep safe signatures for
+// the exported ABI. The getters and `use_list` accept null, the setters do not.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[unsafe(no_mangle)]
 pub extern "C" fn state_get(handle: *const CStateHandle) -> CValue {
     if !handle.is_null() {
@@ -73,6 +97,7 @@ pub extern "C" fn state_get(handle: *const CStateHandle) -> CValue {
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[unsafe(no_mangle)]
 pub extern "C" fn state_buf_get(handle: *const CStateBufHandle) -> CValueBuffer {
     if !handle.is_null() {
@@ -95,6 +120,7 @@ pub extern "C" fn state_buf_get(handle: *const CStateBufHandle) -> CValueBuffer
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[unsafe(no_mangle)]
 pub extern "C" fn state_get_raw(handle: *const CStateHandle) -> CValue {
     if !handle.is_null() {
@@ -105,12 +131,14 @@ pub extern "C" fn state_get_raw(handle: *const CStateHandle) -> CValue {
     }
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[unsafe(no_mangle)]
 pub extern "C" fn state_set(handle: *mut CStateHandle, value: CValue) {
     let signal = unsafe { &(*(handle)).0 };
     signal.set(value);
 }
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[unsafe(no_mangle)]
 pub extern "C" fn state_buf_set(handle: *mut CStateBufHandle, buffer: CValueBuffer) {
     let vec = unsafe { Vec::from_raw_parts(buffer.data, buffer.len, buffer.capacity) };
@@ -132,6 +160,7 @@ pub extern "C" fn use_effect(cx: *mut ffi::c_void, effect: extern "C" fn(*mut ff
 
 type MapFn = extern "C" fn(*const CValue) -> CValue;
 
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[unsafe(no_mangle)]
 pub extern "C" fn use_list(handle: *mut CStateBufHandle, map_fn: MapFn) -> *mut CStateBufHandle {
     if !handle.is_null() {
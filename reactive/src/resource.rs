@@ -0,0 +1,255 @@
+use core::cell::{Cell, RefCell};
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use alloc::rc::{Rc, Weak};
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+
+use super::effect::{create_effect, untrack};
+use super::state::StateHandle;
+
+thread_local! {
+    static PENDING: RefCell<Vec<Weak<ResourceTask>>> = const { RefCell::new(Vec::new()) };
+}
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()>>>;
+
+struct WakeFlag(AtomicBool);
+
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::Release);
+    }
+}
+
+// The in-flight fetch of a resource, `generation` changes whenever a newer fetch replaces it.
+struct ResourceTask {
+    future: RefCell<Option<BoxFuture>>,
+    generation: Cell<u64>,
+    woken: Arc<WakeFlag>,
+}
+
+impl ResourceTask {
+    fn replace(&self, future: BoxFuture) {
+        self.generation.set(self.generation.get() + 1);
+        *self.future.borrow_mut() = Some(future);
+        self.woken.0.store(true, Ordering::Release);
+    }
+
+    // The future is taken out while polling, so signals it sets may start a newer fetch.
+    fn poll(&self) -> bool {
+        if !self.woken.0.swap(false, Ordering::AcqRel) {
+            return self.future.borrow().is_some();
+        }
+        let Some(mut future) = self.future.borrow_mut().take() else {
+            return false;
+        };
+
+        let generation = self.generation.get();
+        let waker = Waker::from(Arc::clone(&self.woken));
+        let poll = untrack(|| future.as_mut().poll(&mut Context::from_waker(&waker)));
+
+        if generation != self.generation.get() {
+            return true;
+        }
+        match poll {
+            Poll::Ready(()) => false,
+            Poll::Pending => {
+                *self.future.borrow_mut() = Some(future);
+                true
+            }
+        }
+    }
+}
+
+pub struct Resource<T, E> {
+    value: StateHandle<Option<T>>,
+    error: StateHandle<Option<E>>,
+    loading: StateHandle<bool>,
+    trigger: StateHandle<()>,
+}
+
+impl<T: 'static, E: 'static> Resource<T, E> {
+    // Last successful value, kept while a newer fetch is loading.
+    pub fn value(&self) -> StateHandle<Option<T>> {
+        self.value.clone()
+    }
+
+    pub fn error(&self) -> StateHandle<Option<E>> {
+        self.error.clone()
+    }
+
+    pub fn loading(&self) -> StateHandle<bool> {
+        self.loading.clone()
+    }
+
+    pub fn refetch(&self) {
+        self.trigger.set(());
+    }
+}
+
+impl<T, E> Clone for Resource<T, E> {
+    fn clone(&self) -> Self {
+        Self {
+            value: self.value.clone(),
+            error: self.error.clone(),
+            loading: self.loading.clone(),
+            trigger: self.trigger.clone(),
+        }
+    }
+}
+
+// Runs `fetcher` inside an effect, signals read before its future is created become dependencies
+// and a change drops the fetch in flight for a new one. Futures are driven by `poll_resources`.
+pub fn use_resource<T, E, F, Fut>(mut fetcher: F) -> Resource<T, E>
+where
+    T: 'static,
+    E: 'static,
+    F: FnMut() -> Fut + 'static,
+    Fut: Future<Output = Result<T, E>> + 'static,
+{
+    let resource = Resource {
+        value: StateHandle::new(None),
+        error: StateHandle::new(None),
+        loading: StateHandle::new(true),
+        trigger: StateHandle::new(()),
+    };
+    let task = Rc::new(ResourceTask {
+        future: RefCell::new(None),
+        generation: Cell::new(0),
+        woken: Arc::new(WakeFlag(AtomicBool::new(false))),
+    });
+    PENDING.with(|pending| pending.borrow_mut().push(Rc::downgrade(&task)));
+
+    create_effect({
+        let resource = resource.clone();
+        move || {
+            resource.trigger.track();
+            let future = fetcher();
+
+            let Resource { value, error, loading, .. } = resource.clone();
+            task.replace(Box::pin(async move {
+                match future.await {
+                    Ok(result) => {
+                        value.set(Some(result));
+                        error.set(None);
+                    }
+                    Err(e) => error.set(Some(e)),
+                }
+                loading.set(false);
+            }));
+
+            untrack(|| {
+                if !*resource.loading.get() {
+                    resource.loading.set(true);
+                }
+                task.poll();
+            });
+        }
+    });
+
+    resource
+}
+
+// Polls every woken resource once, returns whether any fetch is still in flight.
+pub fn poll_resources() -> bool {
+    let tasks = PENDING.with(|pending| {
+        let mut pending = pending.borrow_mut();
+        pending.retain(|task| task.strong_count() > 0);
+        pending.iter().filter_map(Weak::upgrade).collect::<Vec<_>>()
+    });
+
+    tasks.iter().fold(false, |in_flight, task| task.poll() | in_flight)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use core::future::{self, Future};
+    use core::pin::Pin;
+    use core::task::{Context, Poll, Waker};
+
+    use alloc::rc::Rc;
+
+    use crate::*;
+
+    // Completes with whatever is stored in the slot, staying pending while it is empty.
+    struct Deferred(Rc<Cell<Option<Result<i32, &'static str>>>>, Rc<Cell<Option<Waker>>>);
+
+    impl Future for Deferred {
+        type Output = Result<i32, &'static str>;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            match self.0.take() {
+                Some(result) => Poll::Ready(result),
+                None => {
+                    self.1.set(Some(cx.waker().clone()));
+                    Poll::Pending
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_resource_ready() {
+        let id = StateHandle::new(1);
+        let resource = use_resource({
+            let id = id.clone();
+            move || future::ready(Ok::<_, ()>(*id.get_tracked() * 10))
+        });
+
+        assert_eq!(*resource.value().get(), Some(10));
+        assert!(!*resource.loading().get());
+
+        id.set(2);
+        assert_eq!(*resource.value().get(), Some(20));
+        assert!(!poll_resources());
+    }
+
+    #[test]
+    fn test_resource_pending() {
+        let id = StateHandle::new(1);
+        let slot = Rc::new(Cell::new(None));
+        let waker = Rc::new(Cell::new(None));
+        let fetches = Rc::new(Cell::new(0));
+
+        let resource = use_resource({
+            let (id, slot, waker, fetches) = (id.clone(), slot.clone(), waker.clone(), fetches.clone());
+            move || {
+                id.track();
+                fetches.set(fetches.get() + 1);
+                Deferred(slot.clone(), waker.clone())
+            }
+        });
+        assert!(*resource.loading().get());
+        assert!(poll_resources());
+
+        // Nothing is polled again until the waker fires.
+        slot.set(Some(Ok(5)));
+        assert!(poll_resources());
+        assert_eq!(*resource.value().get(), None);
+        waker.take().unwrap().wake();
+        assert!(!poll_resources());
+        assert_eq!(*resource.value().get(), Some(5));
+        assert!(!*resource.loading().get());
+
+        // A refetch keeps the previous value until it settles.
+        id.set(2);
+        assert_eq!(fetches.get(), 2);
+        assert!(*resource.loading().get());
+        assert_eq!(*resource.value().get(), Some(5));
+
+        slot.set(Some(Err("offline")));
+        waker.take().unwrap().wake();
+        poll_resources();
+        assert_eq!(*resource.error().get(), Some("offline"));
+        assert_eq!(*resource.value().get(), Some(5));
+
+        resource.refetch();
+        assert_eq!(fetches.get(), 3);
+    }
+}
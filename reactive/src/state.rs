@@ -5,7 +5,20 @@ use alloc::rc::{Rc, Weak};
 use fnv::FnvBuildHasher;
 use indexmap::IndexMap;
 
-use super::effect::CONTEXTS;
+use super::debug::{NodeGuard, NodeKind};
+use super::effect::{untrack, CONTEXTS};
+
+thread_local! {
+    // One frame of recorded writes per open transaction, innermost last.
+    static TRANSACTIONS: RefCell<Vec<Vec<Write>>> = const { RefCell::new(Vec::new()) };
+}
+
+// First write to a signal inside a transaction, able to undo it or announce it later.
+struct Write {
+    signal: *const (),
+    restore: Box<dyn FnOnce()>,
+    subscribers: Box<dyn Fn() -> Vec<Callback>>,
+}
 
 pub(super) type CallbackPtr = *const RefCell<dyn FnMut()>;
 
@@ -14,6 +27,7 @@ pub(super) type Callback = Weak<RefCell<dyn FnMut()>>;
 pub(super) struct Signal<T> {
     value: Rc<T>,
     emitter: IndexMap<CallbackPtr, Callback, FnvBuildHasher>,
+    _node: NodeGuard,
 }
 
 pub(super) trait SignalEmitter {
@@ -33,14 +47,22 @@ impl<T> SignalEmitter for RefCell<Signal<T>> {
     }
 }
 
-#[derive(Clone)]
 pub struct StateHandle<T>(Rc<RefCell<Signal<T>>>);
 
+// Handles share the signal, so cloning one never requires cloning the value.
+impl<T> Clone for StateHandle<T> {
+    fn clone(&self) -> Self {
+        Self(Rc::clone(&self.0))
+    }
+}
+
 impl<T: 'static> StateHandle<T> {
+    #[track_caller]
     pub fn new(value: T) -> Self {
         Self(Rc::new(RefCell::new(Signal {
             value: Rc::new(value),
             emitter: IndexMap::default(),
+            _node: NodeGuard::new(NodeKind::Signal),
         })))
     }
 
@@ -55,8 +77,33 @@ impl<T: 'static> StateHandle<T> {
     }
 
     pub fn set(&self, value: T) {
-        self.0.borrow_mut().value = Rc::new(value);
-        self.notify();
+        let previous = core::mem::replace(&mut self.0.borrow_mut().value, Rc::new(value));
+        if !self.record(previous) {
+            self.notify();
+        }
+    }
+
+    // Returns false outside of transactions, the caller notifies right away then. Cleanups may
+    // still set signals while thread locals are torn down, those count as outside as well.
+    fn record(&self, previous: Rc<T>) -> bool {
+        TRANSACTIONS.try_with(|transactions| {
+            let mut transactions = transactions.borrow_mut();
+            let Some(writes) = transactions.last_mut() else {
+                return false;
+            };
+
+            let signal = Rc::as_ptr(&self.0).cast::<()>();
+            if writes.iter().all(|write| write.signal != signal) {
+                let (restore, handle) = (self.clone(), self.clone());
+                writes.push(Write {
+                    signal,
+                    restore: Box::new(move || restore.0.borrow_mut().value = previous),
+                    subscribers: Box::new(move || handle.0.borrow().emitter.values().rev().cloned().collect()),
+                });
+            }
+            true
+        })
+        .unwrap_or(false)
     }
 
     pub fn track(&self) {
@@ -84,6 +131,50 @@ impl<T: 'static> StateHandle<T> {
     }
 }
 
+// Runs `f` untracked with notifications held back. On `Ok` every effect depending on a written
+// signal runs once after the outermost transaction ends, on `Err` the writes made inside are
+// rolled back silently.
+pub fn transaction<R, E>(f: impl FnOnce() -> Result<R, E>) -> Result<R, E> {
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().push(Vec::new()));
+    let result = untrack(f);
+    let writes = TRANSACTIONS.with(|transactions| transactions.borrow_mut().pop().unwrap_or_default());
+
+    match result {
+        Ok(_) => {
+            let outer = TRANSACTIONS.with(|transactions| match transactions.borrow_mut().last_mut() {
+                Some(outer) => {
+                    for write in writes {
+                        if outer.iter().all(|recorded| recorded.signal != write.signal) {
+                            outer.push(write);
+                        }
+                    }
+                    None
+                }
+                None => Some(writes),
+            });
+            // An effect depending on several written signals still runs once.
+            let mut seen = Vec::new();
+            for write in outer.into_iter().flatten() {
+                for subscriber in (write.subscribers)() {
+                    if seen.contains(&Weak::as_ptr(&subscriber)) {
+                        continue;
+                    }
+                    seen.push(Weak::as_ptr(&subscriber));
+                    if let Some(callback) = subscriber.upgrade() {
+                        callback.borrow_mut()();
+                    }
+                }
+            }
+        }
+        Err(_) => {
+            for write in writes.into_iter().rev() {
+                (write.restore)();
+            }
+        }
+    }
+    result
+}
+
 #[cfg(test)]
 mod tests {
     use crate::*;
@@ -108,4 +199,44 @@ mod tests {
         state.set(1);
         assert_eq!(double(), 2);
     }
+
+    #[test]
+    fn test_transaction() {
+        let mode = StateHandle::new("idle");
+        let progress = StateHandle::new(0);
+        let runs = StateHandle::new(0);
+
+        create_effect({
+            let (mode, progress, runs) = (mode.clone(), progress.clone(), runs.clone());
+            move || {
+                mode.track();
+                progress.track();
+                runs.set(*runs.get() + 1);
+            }
+        });
+
+        let result = transaction(|| {
+            mode.set("running");
+            progress.set(10);
+            progress.set(20);
+            assert_eq!(*runs.get(), 1);
+            Ok::<_, ()>(())
+        });
+        assert!(result.is_ok());
+        assert_eq!((*mode.get(), *progress.get()), ("running", 20));
+        assert_eq!(*runs.get(), 2);
+
+        let result = transaction(|| {
+            mode.set("failed");
+            // A nested transaction that succeeds is still undone with its parent.
+            transaction(|| {
+                progress.set(0);
+                Ok::<_, &str>(())
+            })?;
+            Err::<(), _>("invalid transition")
+        });
+        assert_eq!(result, Err("invalid transition"));
+        assert_eq!((*mode.get(), *progress.get()), ("running", 20));
+        assert_eq!(*runs.get(), 2);
+    }
 }
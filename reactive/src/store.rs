@@ -0,0 +1,227 @@
+use core::cell::RefCell;
+
+use alloc::rc::{Rc, Weak};
+use alloc::vec::Vec;
+
+use super::state::StateHandle;
+
+type Lens<T, U> = Rc<dyn Fn(&mut T) -> &mut U>;
+
+// Compares the field it was created for against its last snapshot, `alive` ends with the field.
+struct Watcher<T> {
+    changed: Box<dyn FnMut(&mut T) -> bool>,
+    trigger: StateHandle<()>,
+    alive: Weak<()>,
+}
+
+struct StoreInner<T> {
+    value: RefCell<T>,
+    trigger: StateHandle<()>,
+    watchers: RefCell<Vec<Watcher<T>>>,
+}
+
+pub struct Store<T>(Rc<StoreInner<T>>);
+
+impl<T> Clone for Store<T> {
+    fn clone(&self) -> Self {
+        Self(Rc::clone(&self.0))
+    }
+}
+
+pub fn create_store<T: 'static>(value: T) -> Store<T> {
+    Store(Rc::new(StoreInner {
+        value: RefCell::new(value),
+        trigger: StateHandle::new(()),
+        watchers: RefCell::new(Vec::new()),
+    }))
+}
+
+impl<T: 'static> Store<T> {
+    // Tracks the whole store, any update re-runs the caller.
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        self.0.trigger.track();
+        self.with_untracked(f)
+    }
+
+    pub fn with_untracked<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.0.value.borrow())
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut T)) {
+        f(&mut self.0.value.borrow_mut());
+        self.commit();
+    }
+
+    pub fn field<U>(&self, lens: impl Fn(&mut T) -> &mut U + 'static) -> StoreField<T, U>
+    where
+        U: Clone + PartialEq + 'static,
+    {
+        StoreField::new(self.clone(), Rc::new(lens))
+    }
+
+    // Only fields whose value differs from their snapshot notify, the store itself always does.
+    fn commit(&self) {
+        let changed = {
+            let mut value = self.0.value.borrow_mut();
+            let mut watchers = self.0.watchers.borrow_mut();
+            watchers.retain(|watcher| watcher.alive.strong_count() > 0);
+            watchers
+                .iter_mut()
+                .filter_map(|watcher| (watcher.changed)(&mut value).then(|| watcher.trigger.clone()))
+                .collect::<Vec<_>>()
+        };
+
+        for trigger in changed {
+            trigger.set(());
+        }
+        self.0.trigger.set(());
+    }
+}
+
+pub struct StoreField<T, U> {
+    store: Store<T>,
+    lens: Lens<T, U>,
+    trigger: StateHandle<()>,
+    _alive: Rc<()>,
+}
+
+impl<T, U> Clone for StoreField<T, U> {
+    fn clone(&self) -> Self {
+        Self {
+            store: self.store.clone(),
+            lens: Rc::clone(&self.lens),
+            trigger: self.trigger.clone(),
+            _alive: Rc::clone(&self._alive),
+        }
+    }
+}
+
+impl<T: 'static, U: Clone + PartialEq + 'static> StoreField<T, U> {
+    fn new(store: Store<T>, lens: Lens<T, U>) -> Self {
+        let trigger = StateHandle::new(());
+        let alive = Rc::new(());
+
+        let mut snapshot = lens(&mut store.0.value.borrow_mut()).clone();
+        store.0.watchers.borrow_mut().push(Watcher {
+            changed: Box::new({
+                let lens = Rc::clone(&lens);
+                move |value| {
+                    let current = lens(value);
+                    let changed = *current != snapshot;
+                    if changed {
+                        snapshot = current.clone();
+                    }
+                    changed
+                }
+            }),
+            trigger: trigger.clone(),
+            alive: Rc::downgrade(&alive),
+        });
+
+        Self {
+            store,
+            lens,
+            trigger,
+            _alive: alive,
+        }
+    }
+
+    pub fn get(&self) -> U {
+        (self.lens)(&mut self.store.0.value.borrow_mut()).clone()
+    }
+
+    pub fn get_tracked(&self) -> U {
+        self.trigger.track();
+        self.get()
+    }
+
+    pub fn set(&self, value: U) {
+        *(self.lens)(&mut self.store.0.value.borrow_mut()) = value;
+        self.store.commit();
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut U)) {
+        f((self.lens)(&mut self.store.0.value.borrow_mut()));
+        self.store.commit();
+    }
+
+    // Narrows the path further, `store.field(|s| &mut s.a).field(|a| &mut a.b)`.
+    pub fn field<V>(&self, lens: impl Fn(&mut U) -> &mut V + 'static) -> StoreField<T, V>
+    where
+        V: Clone + PartialEq + 'static,
+    {
+        let parent = Rc::clone(&self.lens);
+        StoreField::new(self.store.clone(), Rc::new(move |value| lens(parent(value))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::string::String;
+
+    use crate::*;
+
+    #[derive(Clone, PartialEq)]
+    struct Config {
+        name: String,
+        retries: u32,
+    }
+
+    struct Device {
+        online: bool,
+        config: Config,
+    }
+
+    #[test]
+    fn test_store_fields() {
+        let store = create_store(Device {
+            online: false,
+            config: Config {
+                name: "esp32".into(),
+                retries: 3,
+            },
+        });
+        let online = store.field(|device| &mut device.online);
+        let retries = store.field(|device| &mut device.config).field(|config| &mut config.retries);
+
+        let online_runs = StateHandle::new(0);
+        let retries_runs = StateHandle::new(0);
+        let store_runs = StateHandle::new(0);
+        create_effect({
+            let (online, online_runs) = (online.clone(), online_runs.clone());
+            move || {
+                online.get_tracked();
+                online_runs.set(*online_runs.get() + 1);
+            }
+        });
+        create_effect({
+            let (retries, retries_runs) = (retries.clone(), retries_runs.clone());
+            move || {
+                retries.get_tracked();
+                retries_runs.set(*retries_runs.get() + 1);
+            }
+        });
+        create_effect({
+            let (store, store_runs) = (store.clone(), store_runs.clone());
+            move || {
+                store.with(|device| device.online);
+                store_runs.set(*store_runs.get() + 1);
+            }
+        });
+
+        online.set(true);
+        assert_eq!((*online_runs.get(), *retries_runs.get(), *store_runs.get()), (2, 1, 2));
+
+        // Writing the same value again leaves the field effects alone.
+        store.update(|device| device.online = true);
+        assert_eq!((*online_runs.get(), *retries_runs.get(), *store_runs.get()), (2, 1, 3));
+
+        store.update(|device| device.config.retries = 5);
+        assert_eq!(retries.get(), 5);
+        assert_eq!((*online_runs.get(), *retries_runs.get(), *store_runs.get()), (2, 2, 4));
+
+        store.field(|device| &mut device.config).update(|config| config.name = "esp32c3".into());
+        assert_eq!(*retries_runs.get(), 2);
+        assert_eq!(store.with_untracked(|device| device.config.name.clone()), "esp32c3");
+    }
+}
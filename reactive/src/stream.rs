@@ -0,0 +1,92 @@
+use core::cell::{Cell, RefCell};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
+
+use alloc::rc::Rc;
+
+use futures_core::Stream;
+
+use super::create_root;
+use super::effect::{create_effect, Scope};
+use super::state::StateHandle;
+
+struct StreamState {
+    dirty: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+// Yields the current value first and then the value after every change. Changes between two
+// polls are coalesced, a slow consumer only sees the latest one.
+pub struct SignalStream<T> {
+    signal: StateHandle<T>,
+    state: Rc<StreamState>,
+    _scope: Scope,
+}
+
+impl<T: 'static> StateHandle<T> {
+    pub fn to_stream(&self) -> SignalStream<T> {
+        let state = Rc::new(StreamState {
+            dirty: Cell::new(true),
+            waker: RefCell::new(None),
+        });
+        let scope = create_root(|| {
+            create_effect({
+                let (signal, state) = (self.clone(), Rc::clone(&state));
+                move || {
+                    signal.track();
+                    state.dirty.set(true);
+                    if let Some(waker) = state.waker.borrow_mut().take() {
+                        waker.wake();
+                    }
+                }
+            });
+        });
+
+        SignalStream {
+            signal: self.clone(),
+            state,
+            _scope: scope,
+        }
+    }
+}
+
+impl<T: 'static> Stream for SignalStream<T> {
+    type Item = Rc<T>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.state.dirty.replace(false) {
+            return Poll::Ready(Some(self.signal.get()));
+        }
+        *self.state.waker.borrow_mut() = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::pin::Pin;
+    use core::task::{Context, Poll, Waker};
+
+    use futures_core::Stream;
+
+    use crate::*;
+
+    #[test]
+    fn test_signal_stream() {
+        let sessions = StateHandle::new(1);
+        let mut stream = sessions.to_stream();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut next = || match Pin::new(&mut stream).poll_next(&mut cx) {
+            Poll::Ready(value) => value.map(|value| *value),
+            Poll::Pending => None,
+        };
+
+        assert_eq!(next(), Some(1));
+        assert_eq!(next(), None);
+
+        sessions.set(2);
+        sessions.set(3);
+        assert_eq!(next(), Some(3));
+        assert_eq!(next(), None);
+    }
+}
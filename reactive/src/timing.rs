@@ -0,0 +1,205 @@
+use core::cell::RefCell;
+use core::time::Duration;
+
+use alloc::rc::Rc;
+
+use program::Clock;
+
+use super::effect::{create_effect, untrack};
+use super::state::StateHandle;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Debounce,
+    Throttle,
+}
+
+struct Limiter<T> {
+    mode: Mode,
+    window: u64,
+    pending: Option<T>,
+    // Clock timestamp at which `pending` may be emitted.
+    deadline: u64,
+    last_emit: Option<u64>,
+}
+
+impl<T> Limiter<T> {
+    // Returns the value to emit right away, if the mode allows one.
+    fn offer(&mut self, value: T, now: u64) -> Option<T> {
+        match self.mode {
+            Mode::Debounce => {
+                self.pending = Some(value);
+                self.deadline = now + self.window;
+                None
+            }
+            Mode::Throttle => match self.last_emit {
+                Some(last) if now < last + self.window => {
+                    self.pending = Some(value);
+                    self.deadline = last + self.window;
+                    None
+                }
+                _ => {
+                    self.pending = None;
+                    self.last_emit = Some(now);
+                    Some(value)
+                }
+            },
+        }
+    }
+}
+
+// Rate limited copy of a signal, held back values are only emitted from `poll`.
+pub struct RateLimited<T, C> {
+    output: StateHandle<T>,
+    limiter: Rc<RefCell<Limiter<T>>>,
+    clock: Rc<C>,
+}
+
+impl<T: Clone + 'static, C: Clock + 'static> RateLimited<T, C> {
+    fn new(source: StateHandle<T>, mode: Mode, window: Duration, clock: C) -> Self {
+        let output = StateHandle::new((*source.get()).clone());
+        let limiter = Rc::new(RefCell::new(Limiter {
+            mode,
+            window: window.as_nanos() as u64,
+            pending: None,
+            deadline: 0,
+            last_emit: None,
+        }));
+        let clock = Rc::new(clock);
+
+        create_effect({
+            let (output, limiter, clock) = (output.clone(), Rc::clone(&limiter), Rc::clone(&clock));
+            let mut initial = true;
+            move || {
+                let value = (*source.get_tracked()).clone();
+                if initial {
+                    initial = false;
+                    return;
+                }
+                untrack(|| {
+                    let emit = limiter.borrow_mut().offer(value, clock.timestamp());
+                    if let Some(value) = emit {
+                        output.set(value);
+                    }
+                });
+            }
+        });
+
+        Self { output, limiter, clock }
+    }
+
+    pub fn signal(&self) -> StateHandle<T> {
+        self.output.clone()
+    }
+
+    // Emits a held back value whose window has passed. Returns how long until the next one is
+    // due, so the host loop knows how long it may sleep.
+    pub fn poll(&self) -> Option<Duration> {
+        let now = self.clock.timestamp();
+        let value = {
+            let mut limiter = self.limiter.borrow_mut();
+            if now < limiter.deadline {
+                let remaining = Duration::from_nanos(limiter.deadline - now);
+                return limiter.pending.is_some().then_some(remaining);
+            }
+            let value = limiter.pending.take()?;
+            limiter.last_emit = Some(now);
+            value
+        };
+
+        self.output.set(value);
+        None
+    }
+}
+
+// Follows `signal` once it has stopped changing for `window`.
+pub fn use_debounced<T, C>(signal: StateHandle<T>, window: Duration, clock: C) -> RateLimited<T, C>
+where
+    T: Clone + 'static,
+    C: Clock + 'static,
+{
+    RateLimited::new(signal, Mode::Debounce, window, clock)
+}
+
+// Follows `signal` at most once per `window`, the latest value of a busy window is emitted at its end.
+pub fn use_throttled<T, C>(signal: StateHandle<T>, window: Duration, clock: C) -> RateLimited<T, C>
+where
+    T: Clone + 'static,
+    C: Clock + 'static,
+{
+    RateLimited::new(signal, Mode::Throttle, window, clock)
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use core::time::Duration;
+
+    use alloc::rc::Rc;
+
+    use program::Clock;
+
+    use crate::*;
+
+    #[derive(Clone)]
+    struct MockClock(Rc<Cell<u64>>);
+
+    impl MockClock {
+        fn advance(&self, millis: u64) {
+            self.0.set(self.0.get() + millis * 1_000_000);
+        }
+    }
+
+    impl Clock for MockClock {
+        fn timestamp(&self) -> u64 {
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_debounced() {
+        let clock = MockClock(Rc::new(Cell::new(0)));
+        let rssi = StateHandle::new(-70);
+        let debounced = use_debounced(rssi.clone(), Duration::from_millis(100), clock.clone());
+
+        rssi.set(-71);
+        clock.advance(60);
+        rssi.set(-72);
+        clock.advance(60);
+        assert_eq!(debounced.poll(), Some(Duration::from_millis(40)));
+        assert_eq!(*debounced.signal().get(), -70);
+
+        clock.advance(40);
+        assert_eq!(debounced.poll(), None);
+        assert_eq!(*debounced.signal().get(), -72);
+    }
+
+    #[test]
+    fn test_throttled() {
+        let clock = MockClock(Rc::new(Cell::new(0)));
+        let heap = StateHandle::new(0);
+        let throttled = use_throttled(heap.clone(), Duration::from_millis(100), clock.clone());
+
+        let runs = StateHandle::new(0);
+        create_effect({
+            let (signal, runs) = (throttled.signal(), runs.clone());
+            move || {
+                signal.track();
+                runs.set(*runs.get() + 1);
+            }
+        });
+
+        // The first change passes at once, the rest of the window is coalesced.
+        for value in 1..=5 {
+            heap.set(value);
+            clock.advance(10);
+        }
+        assert_eq!(*throttled.signal().get(), 1);
+        assert_eq!(throttled.poll(), Some(Duration::from_millis(50)));
+
+        clock.advance(50);
+        throttled.poll();
+        assert_eq!(*throttled.signal().get(), 5);
+        assert_eq!(*runs.get(), 3);
+    }
+}
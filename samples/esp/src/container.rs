@@ -1,101 +1,123 @@
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::net::TcpStream;
-
-use protocol::{Message, Type};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use esp_idf_svc::nvs::EspDefaultNvsPartition;
+use esp_idf_svc::sys;
+use log::{error, warn};
+use program::{Buf, BufMut, Clock, Executor, ExecutorFlavor, Session, StepStatus, Transport, Type};
 use wamr_rust_sdk::{
-    function::Function, instance::Instance, module::Module, runtime::Runtime, value::WasmValue,
+    function::Function, instance::Instance, module::Module, runtime::Runtime, value::WasmValue, RuntimeError,
 };
 
+use crate::ota::{self, EspOtaSink};
+use crate::store::NvsCacheStore;
+use crate::telemetry::EspTelemetry;
+use crate::watchdog::EspWatchdog;
 use crate::Error;
 
-enum ModuleState {
-    Idle,
-    Loading {
-        module_name: String,
-        module_chunks: Vec<Vec<u8>>,
-        module_params: Vec<Type>,
-    },
-    Executing {
-        module_name: String,
-        module_binary: Vec<u8>,
-        module_params: Vec<Type>,
-    },
+pub struct EspClock;
+
+impl Clock for EspClock {
+    fn timestamp(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64
+    }
+
+    // Yields to FreeRTOS so the idle task runs and the WiFi stack keeps up.
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
 }
 
-fn process_message(
-    state: &mut ModuleState,
-    msg: &Message,
-    socket: &mut TcpStream,
-) -> Result<(), Error> {
-    match msg {
-        Message::ServerTask {
-            task_id,
-            module,
-            params
-        } => {
+pub struct EspTransport {
+    stream: TcpStream,
+    closed: Arc<AtomicBool>,
+}
+
+impl EspTransport {
+    pub fn connect(addr: &str) -> io::Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            stream,
+            closed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    pub fn closed(&self) -> Arc<AtomicBool> {
+        self.closed.clone()
+    }
 
+    fn fail(&self, error: io::Error) -> io::Error {
+        self.closed.store(true, Ordering::Relaxed);
+        error
+    }
+}
+
+impl Transport for EspTransport {
+    type Error = io::Error;
+
+    fn read<B>(&mut self, buf: &mut B) -> Result<usize, Self::Error>
+    where
+        B: BufMut + ?Sized,
+    {
+        let mut buffer = [0u8; 2048];
+        let bytes_read = match self.stream.read(&mut buffer) {
+            Ok(0) => return Err(self.fail(io::ErrorKind::UnexpectedEof.into())),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(self.fail(e)),
+        };
+        buf.put_slice(&buffer[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    fn write<B>(&mut self, src: &mut B) -> Result<usize, Self::Error>
+    where
+        B: Buf + ?Sized,
+    {
+        match self.stream.write(src.chunk()) {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(self.fail(e)),
         }
-        Message::ServerModule {
-            task_id,
-            chunk_index,
-            chunk_data,
-        } => match module_state {
-            ModuleState::Loading {
-                module_name,
-                mut module_chunks,
-                module_params,
-            } => {
-                if chunk_index < module_chunks.len() as u32 {
-                    module_chunks[chunk_index as usize] = chunk_data;
-                    if module_chunks.iter().all(|c| !c.is_empty()) {
-                        let binary: Vec<u8> = module_chunks.concat();
-                        let result = execute_wasm(binary, module_params.clone())?;
-                        let result_msg = Message::ClientResult { task_id, result };
-                        socket.write_all(&result_msg.encode()?)?;
-                        module_state = ModuleState::Execute {
-                            module_name,
-                            module_binary: binary,
-                            module_params,
-                        }
-                    }
-                }
-            }
-            _ => {}
-        },
-        Message::ServerAck { .. } => match module_state {
-            ModuleState::Execute {
-                module_name,
-                module_binary,
-                ..
-            } => {
-                let ready_message = Message::ClientReady {
-                    module_name: None,
-                    device_ram: 0,
-                };
-                socket.write_all(&ready_message.encode()?)?;
-                module_state = ModuleState::Pending {
-                    module_name,
-                    module_binary,
-                }
-            }
-            _ => {}
-        },
-        _ => {}
     }
 }
 
-pub fn execute_wasm<T: Into<Vec<u8>>>(binary: T, params: Vec<Type>) -> Result<Vec<Type>, Error> {
+pub struct WamrExecutor;
+
+impl Executor for WamrExecutor {
+    type Error = RuntimeError;
+
+    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        execute_wasm(binary, params)
+    }
+
+    fn flavor(&self) -> ExecutorFlavor {
+        ExecutorFlavor::Interpreter
+    }
+}
+
+pub fn execute_wasm<T: Into<Vec<u8>>>(binary: T, params: Vec<Type>) -> Result<Vec<Type>, RuntimeError> {
+    // The WAMR bindings expose no linear memory access, so buffers are refused.
     let wasm_params = params
         .iter()
         .map(|f| match f {
-            Type::Void => WasmValue::Void,
-            Type::I32(v) => WasmValue::I32(*v),
-            Type::I64(v) => WasmValue::I64(*v),
-            Type::F32(v) => WasmValue::F32(*v),
-            Type::F64(v) => WasmValue::F64(*v),
-            Type::V128(v) => WasmValue::V128(*v),
+            Type::Void => Ok(WasmValue::Void),
+            Type::I32(v) => Ok(WasmValue::I32(*v)),
+            Type::I64(v) => Ok(WasmValue::I64(*v)),
+            Type::F32(v) => Ok(WasmValue::F32(*v)),
+            Type::F64(v) => Ok(WasmValue::F64(*v)),
+            Type::V128(v) => Ok(WasmValue::V128(*v)),
+            Type::Bytes(_) | Type::BlobRef(..) => Err(RuntimeError::NotImplemented),
         })
-        .collect();
+        .collect::<Result<Vec<_>, _>>()?;
 
     let runtime = Runtime::new()?;
     let module = Module::from_vec(&runtime, binary.into(), "container")?;
@@ -120,111 +142,51 @@ pub fn execute_wasm<T: Into<Vec<u8>>>(binary: T, params: Vec<Type>) -> Result<Ve
     Ok(result)
 }
 
-fn handle_connection(mut socket: TcpStream) -> Result<(), Error> {
-    let mut module_state = ModuleState::Starting;
-    let mut buf = [0u8; 2048];
+// Runs one session for as long as the device is up, reconnecting whenever the server drops it.
+// Modules cached in NVS are announced again after a reset.
+pub fn setup_container(host: &str, port: u16, nvs: EspDefaultNvsPartition) -> Result<(), Error> {
+    const RETRY_DELAY: Duration = Duration::from_secs(5);
+    // Fed by every step, a module runs within one step so this bounds its execution as well. Only
+    // steps feed it, a server unreachable for this long resets the device too.
+    const WATCHDOG_TIMEOUT: Duration = Duration::from_secs(60);
 
-    let ready_message = Message::ClientReady {
-        module_name: None,
-        device_ram: 0,
-    };
-    socket.write_all(&ready_message.encode()?)?;
+    let addr = format!("{}:{}", host, port);
+    let transport = EspTransport::connect(&addr)?;
+    let mut closed = transport.closed();
+    if let Err(e) = ota::confirm_running_image() {
+        warn!("Running image not confirmed: {}", e);
+    }
+
+    // Advertise what is left of the heap once WiFi is up, the module shares it with the runtime.
+    let device_ram = unsafe { sys::esp_get_free_heap_size() } as u64;
+    let mut session = Session::new(transport, WamrExecutor, EspClock, device_ram)
+        .with_cache_store(NvsCacheStore::new(nvs)?)?
+        .with_firmware_sink(EspOtaSink::new())
+        .with_telemetry_probe(EspTelemetry)
+        .with_watchdog(EspWatchdog::new(WATCHDOG_TIMEOUT)?);
 
     loop {
-        let n = socket.read(&mut buf)?;
-
-        match Message::decode(&buf[..n])? {
-            Message::ServerTask {
-                task_id,
-                module,
-                params,
-            } => match module_state {
-                ModuleState::Starting => {
-                    module_state = ModuleState::Loading {
-                        module_name: module.name,
-                        module_chunks: vec![Vec::new(); module.total_chunks as usize],
-                        module_params: params,
-                    };
-                }
-                ModuleState::Pending {
-                    module_name,
-                    module_binary,
-                } => {
-                    if module.name == module_name {
-                        let result = execute_wasm(module_binary, params.clone())?;
-                        let result_msg = Message::ClientResult { task_id, result };
-                        socket.write_all(&result_msg.encode()?)?;
-                        module_state = ModuleState::Execute {
-                            module_name,
-                            module_binary,
-                            module_params: params,
-                        }
-                    } else {
-                        module_state = ModuleState::Loading {
-                            module_name: module.name,
-                            module_chunks: vec![Vec::new(); module.total_chunks as usize],
-                            module_params: params,
-                        };
-                    }
+        if closed.load(Ordering::Relaxed) {
+            warn!("Connection to {} lost, reconnecting", addr);
+            match EspTransport::connect(&addr) {
+                Ok(transport) => {
+                    closed = transport.closed();
+                    session.reconnect(transport);
                 }
-                _ => {}
-            },
-            Message::ServerModule {
-                task_id,
-                chunk_index,
-                chunk_data,
-            } => match module_state {
-                ModuleState::Loading {
-                    module_name,
-                    mut module_chunks,
-                    module_params,
-                } => {
-                    if chunk_index < module_chunks.len() as u32 {
-                        module_chunks[chunk_index as usize] = chunk_data;
-                        if module_chunks.iter().all(|c| !c.is_empty()) {
-                            let binary: Vec<u8> = module_chunks.concat();
-                            let result = execute_wasm(binary, module_params.clone())?;
-                            let result_msg = Message::ClientResult { task_id, result };
-                            socket.write_all(&result_msg.encode()?)?;
-                            module_state = ModuleState::Execute {
-                                module_name,
-                                module_binary: binary,
-                                module_params,
-                            }
-                        }
-                    }
+                Err(e) => {
+                    error!("Connection failed: {}, retrying in {:?}", e, RETRY_DELAY);
+                    EspClock.sleep(RETRY_DELAY);
+                    continue;
                 }
-                _ => {}
-            },
-            Message::ServerAck { .. } => match module_state {
-                ModuleState::Execute {
-                    module_name,
-                    module_binary,
-                    ..
-                } => {
-                    let ready_message = Message::ClientReady {
-                        module_name: None,
-                        device_ram: 0,
-                    };
-                    socket.write_all(&ready_message.encode()?)?;
-                    module_state = ModuleState::Pending {
-                        module_name,
-                        module_binary,
-                    }
-                }
-                _ => {}
-            },
-            _ => {}
+            }
         }
-    }
-}
-
-pub fn setup_container(host: &str, port: u16) -> Result<(), Error> {
-    let addr = format!("{}:{}", host, port);
-
-    let stream = TcpStream::connect(&addr)?;
-
-    handle_connection(stream)?;
 
-    Ok(())
+        // The socket is non-blocking and cannot wake us, so sleeps are capped to keep latency low.
+        match session.step() {
+            Ok(StepStatus::NeedsSleep(duration)) => EspClock.sleep(duration.min(Duration::from_millis(10))),
+            Ok(StepStatus::Failed) => warn!("Session step failed"),
+            Ok(StepStatus::Idle | StepStatus::Progress) => {}
+            Err(e) => error!("Session error: {}", e),
+        }
+    }
 }
@@ -1,25 +1,34 @@
 mod container;
+mod ota;
+mod store;
+mod telemetry;
+mod watchdog;
 
 use std::io;
 
 use container::{execute_wasm, setup_container};
 use esp_idf_svc::{eventloop, hal, log as esp_log, nvs, sys, wifi};
 use log::{error, info};
-use protocol::{Config, Error as ProtocolError, Type, Wifi};
+use protocol::{Config, Type, Wifi};
 
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("protocol: {0}")]
-    ProtocolError(#[from] ProtocolError),
+    #[error("program: {0}")]
+    ProgramError(#[from] program::Error),
     #[error("iwasm: {0}")]
     ContainerError(#[from] wamr_rust_sdk::RuntimeError),
+    #[error("esp: {0}")]
+    EspError(#[from] sys::EspError),
     #[error("io: {0}")]
     IoError(#[from] io::Error),
 }
 
-fn setup_wifi(ssid: &str, password: &str) -> Result<wifi::EspWifi<'static>, sys::EspError> {
+fn setup_wifi(
+    ssid: &str,
+    password: &str,
+    nvs: nvs::EspDefaultNvsPartition,
+) -> Result<wifi::EspWifi<'static>, sys::EspError> {
     let sys_loop = eventloop::EspSystemEventLoop::take()?;
-    let nvs = nvs::EspDefaultNvsPartition::take()?;
 
     let peripherals = hal::prelude::Peripherals::take()?;
 
@@ -47,13 +56,19 @@ fn main() {
     // Bind the log crate to the ESP Logging facilities
     esp_log::EspLogger::initialize_default();
 
-    let Config { host, port, wifi } = Config::new();
+    let Config { host, dispatcher_port, wifi, .. } = Config::new();
 
     if let Some(Wifi { ssid, password }) = wifi {
-        match setup_wifi(&ssid, &password) {
-            Ok(_) => {
+        // The partition can only be taken once, WiFi calibration and the module cache share it.
+        let nvs = match nvs::EspDefaultNvsPartition::take() {
+            Ok(nvs) => nvs,
+            Err(err) => return error!("NVS unavailable: {err}"),
+        };
+        match setup_wifi(&ssid, &password, nvs.clone()) {
+            // Dropping the driver would take the station down.
+            Ok(_wifi) => {
                 info!("Wifi connected");
-                if let Err(err) = setup_container(&host, port) {
+                if let Err(err) = setup_container(&host, dispatcher_port, nvs) {
                     error!("Container error: {err}");
                 }
             }
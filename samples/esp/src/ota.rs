@@ -0,0 +1,89 @@
+use core::ptr;
+
+use esp_idf_svc::sys::{self, esp, EspError};
+use log::info;
+use program::{Checksum, Error, FirmwareSink};
+
+// Goes through the raw esp_ota API, `EspOtaUpdate` borrows its `EspOta` and cannot live in a
+// sink the session owns across many chunk messages.
+pub struct EspOtaSink {
+    partition: *const sys::esp_partition_t,
+    handle: Option<sys::esp_ota_handle_t>,
+    written: usize,
+    checksum: Checksum,
+}
+
+impl EspOtaSink {
+    pub fn new() -> Self {
+        Self {
+            partition: ptr::null(),
+            handle: None,
+            written: 0,
+            checksum: Checksum::default(),
+        }
+    }
+
+    fn abort(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            unsafe { sys::esp_ota_abort(handle) };
+        }
+    }
+}
+
+// With rollback enabled in the bootloader a freshly written image boots on probation and is
+// reverted on its next reset, unless it confirms itself once it reached the server again.
+pub fn confirm_running_image() -> Result<(), EspError> {
+    esp!(unsafe { sys::esp_ota_mark_app_valid_cancel_rollback() })
+}
+
+fn firmware_error(e: EspError) -> Error {
+    Error::Firmware(e.to_string())
+}
+
+impl FirmwareSink for EspOtaSink {
+    fn begin(&mut self, version: &str, size: usize) -> Result<(), Error> {
+        // A canceled update leaves its handle open, the next one starts over.
+        self.abort();
+
+        let partition = unsafe { sys::esp_ota_get_next_update_partition(ptr::null()) };
+        if partition.is_null() {
+            return Err(Error::Firmware("no OTA partition to update".into()));
+        }
+        let mut handle = 0;
+        esp!(unsafe { sys::esp_ota_begin(partition, size, &mut handle) }).map_err(firmware_error)?;
+        info!("Writing firmware {} ({} bytes)", version, size);
+
+        self.partition = partition;
+        self.handle = Some(handle);
+        self.written = 0;
+        self.checksum = Checksum::default();
+        Ok(())
+    }
+
+    // esp_ota_write only appends, the server sends chunks in order and a gap fails the update.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), Error> {
+        let handle = self.handle.ok_or(Error::Firmware("no update in progress".into()))?;
+        if offset != self.written {
+            return Err(Error::Firmware(format!("expected offset {}, got {}", self.written, offset)));
+        }
+        esp!(unsafe { sys::esp_ota_write(handle, data.as_ptr().cast(), data.len()) }).map_err(firmware_error)?;
+        self.written += data.len();
+        self.checksum.update(data);
+        Ok(())
+    }
+
+    fn finish(&mut self, checksum: u32) -> Result<(), Error> {
+        if self.checksum.value() != checksum {
+            self.abort();
+            return Err(Error::Firmware("image checksum mismatch".into()));
+        }
+        let handle = self.handle.take().ok_or(Error::Firmware("no update in progress".into()))?;
+        // esp_ota_end also validates the image header and its appended digest.
+        esp!(unsafe { sys::esp_ota_end(handle) }).map_err(firmware_error)?;
+        esp!(unsafe { sys::esp_ota_set_boot_partition(self.partition) }).map_err(firmware_error)
+    }
+
+    fn reboot(&mut self) {
+        unsafe { sys::esp_restart() };
+    }
+}
@@ -0,0 +1,85 @@
+use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
+use esp_idf_svc::sys::EspError;
+use program::{CacheStore, Error};
+
+pub struct NvsCacheStore {
+    nvs: EspNvs<NvsDefault>,
+}
+
+impl NvsCacheStore {
+    const NAMESPACE: &str = "modules";
+    const INDEX_KEY: &str = "index";
+
+    pub fn new(partition: EspDefaultNvsPartition) -> Result<Self, EspError> {
+        let nvs = EspNvs::new(partition, Self::NAMESPACE, true)?;
+        Ok(Self { nvs })
+    }
+
+    // NVS keys are limited to 15 characters, so module names are hashed into a fixed-size key.
+    fn blob_key(key: &str) -> String {
+        let hash = key.bytes().fold(0x811c9dc5u32, |hash, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x01000193)
+        });
+        format!("m{:08x}", hash)
+    }
+
+    fn read_blob(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let len = match self.nvs.blob_len(key).map_err(|e| Error::Storage(e.to_string()))? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+        let mut buf = vec![0u8; len];
+        let data = self
+            .nvs
+            .get_blob(key, &mut buf)
+            .map_err(|e| Error::Storage(e.to_string()))?;
+        Ok(data.map(|data| data.to_vec()))
+    }
+
+    fn write_index(&mut self, keys: &[String]) -> Result<(), Error> {
+        self.nvs
+            .set_blob(Self::INDEX_KEY, keys.join("\n").as_bytes())
+            .map_err(|e| Error::Storage(e.to_string()))
+    }
+}
+
+impl CacheStore for NvsCacheStore {
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let index = self.read_blob(Self::INDEX_KEY)?.unwrap_or_default();
+        Ok(String::from_utf8_lossy(&index)
+            .split('\n')
+            .filter(|key| !key.is_empty())
+            .map(String::from)
+            .collect())
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.read_blob(&Self::blob_key(key))
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), Error> {
+        self.nvs
+            .set_blob(&Self::blob_key(key), data)
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        let mut keys = self.keys()?;
+        if !keys.iter().any(|k| k == key) {
+            keys.push(key.to_string());
+            self.write_index(&keys)?;
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Error> {
+        self.nvs
+            .remove(&Self::blob_key(key))
+            .map_err(|e| Error::Storage(e.to_string()))?;
+
+        let keys = self
+            .keys()?
+            .into_iter()
+            .filter(|k| k != key)
+            .collect::<Vec<_>>();
+        self.write_index(&keys)
+    }
+}
@@ -0,0 +1,19 @@
+use esp_idf_svc::sys;
+use program::TelemetryProbe;
+
+pub struct EspTelemetry;
+
+impl TelemetryProbe for EspTelemetry {
+    fn free_heap(&self) -> Option<u64> {
+        Some(unsafe { sys::esp_get_free_heap_size() } as u64)
+    }
+
+    // Fails while the station is not associated, the reading is then left out.
+    fn rssi(&self) -> Option<i8> {
+        let mut info = sys::wifi_ap_record_t::default();
+        match unsafe { sys::esp_wifi_sta_get_ap_info(&mut info) } {
+            sys::ESP_OK => Some(info.rssi),
+            _ => None,
+        }
+    }
+}
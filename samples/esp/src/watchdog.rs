@@ -0,0 +1,38 @@
+use core::ptr;
+use core::time::Duration;
+
+use esp_idf_svc::sys::{self, esp, EspError};
+use program::Watchdog;
+
+// Subscribes the calling task to the esp-idf task watchdog, so it has to be created on the task
+// that runs the session.
+pub struct EspWatchdog;
+
+impl EspWatchdog {
+    pub fn new(timeout: Duration) -> Result<Self, EspError> {
+        let config = sys::esp_task_wdt_config_t {
+            timeout_ms: timeout.as_millis() as u32,
+            idle_core_mask: 0,
+            trigger_panic: true,
+        };
+        // The bootloader config usually starts the watchdog already, only its timeout changes then.
+        match unsafe { sys::esp_task_wdt_reconfigure(&config) } {
+            sys::ESP_ERR_INVALID_STATE => esp!(unsafe { sys::esp_task_wdt_init(&config) })?,
+            code => esp!(code)?,
+        }
+        esp!(unsafe { sys::esp_task_wdt_add(ptr::null_mut()) })?;
+        Ok(Self)
+    }
+}
+
+impl Watchdog for EspWatchdog {
+    fn feed(&mut self) {
+        unsafe { sys::esp_task_wdt_reset() };
+    }
+}
+
+impl Drop for EspWatchdog {
+    fn drop(&mut self) {
+        unsafe { sys::esp_task_wdt_delete(ptr::null_mut()) };
+    }
+}
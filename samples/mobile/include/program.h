@@ -0,0 +1,58 @@
+// C surface of the mobile worker, link libprogram.a on iOS or load libprogram.so on Android.
+#ifndef PROGRAM_H
+#define PROGRAM_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef enum {
+    PROGRAM_STOPPED = 0,
+    // Network or battery constraints forbid work, the connection is closed until they lift.
+    PROGRAM_PAUSED = 1,
+    PROGRAM_CONNECTING = 2,
+    PROGRAM_READY = 3,
+    PROGRAM_TRANSFERRING = 4,
+    PROGRAM_EXECUTING = 5,
+    PROGRAM_UPDATING = 6,
+} program_state_t;
+
+typedef struct {
+    uint8_t state; // program_state_t
+    bool has_task;
+    uint64_t task_id;
+    uint32_t chunks_done;
+    uint32_t chunks_total;
+    uint64_t cache_hits;
+    uint64_t cache_misses;
+} program_status_t;
+
+typedef struct {
+    // Cellular or a hotspot, the worker never transfers over it.
+    bool metered;
+    uint8_t battery_percent;
+    bool charging;
+} program_constraints_t;
+
+typedef struct program_worker program_worker_t;
+
+// Called from the worker thread whenever the status changes, `status` is only valid during the call.
+typedef void (*program_status_callback_t)(const program_status_t *status, void *user_data);
+
+// Returns NULL when `host` is not UTF-8. The worker stays paused until constraints allow work.
+program_worker_t *program_start(const char *host, uint16_t port, program_status_callback_t callback, void *user_data);
+
+// Report every connectivity or battery change, e.g. from NWPathMonitor or ConnectivityManager.
+void program_set_constraints(program_worker_t *worker, program_constraints_t constraints);
+
+// Blocks until a held task finishes or a few seconds pass, then frees `worker`. Not from the callback.
+void program_stop(program_worker_t *worker);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,60 @@
+use program::{Executor, Type};
+use wasmi::{Engine, Linker, Module, Store, Val};
+
+// iOS forbids JIT pages outside the system browser, so modules run in the wasmi interpreter on
+// both platforms.
+pub struct WasmiExecutor {
+    engine: Engine,
+}
+
+impl WasmiExecutor {
+    pub fn new() -> Self {
+        Self { engine: Engine::default() }
+    }
+}
+
+impl Executor for WasmiExecutor {
+    type Error = wasmi::Error;
+
+    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        // Buffers need the guest's alloc export, see program::memory, which wasmi is not wired to.
+        let params = params
+            .into_iter()
+            .map(|param| match param {
+                Type::I32(v) => Ok(Val::I32(v)),
+                Type::I64(v) => Ok(Val::I64(v)),
+                Type::F32(v) => Ok(Val::F32(v.into())),
+                Type::F64(v) => Ok(Val::F64(v.into())),
+                other => Err(wasmi::Error::new(format!("unsupported param {:?}", other))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let module = Module::new(&self.engine, binary)?;
+        let mut store = Store::new(&self.engine, ());
+        let instance = Linker::<()>::new(&self.engine)
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+        let run = instance
+            .get_func(&store, "run")
+            .ok_or_else(|| wasmi::Error::new("module exports no run function"))?;
+
+        let mut results = run
+            .ty(&store)
+            .results()
+            .iter()
+            .map(|ty| Val::default(*ty))
+            .collect::<Vec<_>>();
+        run.call(&mut store, &params, &mut results)?;
+
+        results
+            .into_iter()
+            .map(|result| match result {
+                Val::I32(v) => Ok(Type::I32(v)),
+                Val::I64(v) => Ok(Type::I64(v)),
+                Val::F32(v) => Ok(Type::F32(v.into())),
+                Val::F64(v) => Ok(Type::F64(v.into())),
+                other => Err(wasmi::Error::new(format!("unsupported result {:?}", other))),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,255 @@
+use std::ffi::{c_char, c_void, CStr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+mod executor;
+mod transport;
+
+use executor::WasmiExecutor;
+use program::*;
+use transport::{Constraints, MobileTransport, SharedConstraints};
+
+// How long `program_stop` lets a held task finish before dropping it.
+const STOP_GRACE: Duration = Duration::from_secs(10);
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn timestamp(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramState {
+    Stopped = 0,
+    // Constraints forbid work, the connection is closed until they lift.
+    Paused = 1,
+    Connecting = 2,
+    Ready = 3,
+    Transferring = 4,
+    Executing = 5,
+    Updating = 6,
+}
+
+// Mirrors `program.h`, passed by pointer and only valid for the duration of the callback.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramStatus {
+    pub state: ProgramState,
+    pub has_task: bool,
+    pub task_id: u64,
+    pub chunks_done: u32,
+    pub chunks_total: u32,
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramConstraints {
+    pub metered: bool,
+    pub battery_percent: u8,
+    pub charging: bool,
+}
+
+pub type ProgramStatusCallback = Option<extern "C" fn(status: *const ProgramStatus, user_data: *mut c_void)>;
+
+// The app owns `user_data` and promises it may be used from the worker thread.
+struct Listener {
+    callback: ProgramStatusCallback,
+    user_data: *mut c_void,
+    last: Option<ProgramStatus>,
+}
+
+unsafe impl Send for Listener {}
+
+impl Listener {
+    // Only changes reach the app, a UI has no use for the same status every step.
+    fn notify(&mut self, status: ProgramStatus) {
+        if self.last == Some(status) {
+            return;
+        }
+        self.last = Some(status);
+        if let Some(callback) = self.callback {
+            callback(&status, self.user_data);
+        }
+    }
+}
+
+pub struct ProgramWorker {
+    constraints: SharedConstraints,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+fn status(state: ProgramState, session: Option<&Session<MobileTransport, WasmiExecutor, SystemClock>>) -> ProgramStatus {
+    let (snapshot, cache) = match session {
+        Some(session) => (Some(session.snapshot()), session.cache_stats()),
+        None => (None, CacheStats::default()),
+    };
+    let state = match (state, snapshot.as_ref().map(|snapshot| snapshot.phase)) {
+        (ProgramState::Ready, Some(SessionPhase::Transferring)) => ProgramState::Transferring,
+        (ProgramState::Ready, Some(SessionPhase::Executing)) => ProgramState::Executing,
+        (ProgramState::Ready, Some(SessionPhase::Updating)) => ProgramState::Updating,
+        (state, _) => state,
+    };
+    let task = snapshot.as_ref().and_then(|snapshot| snapshot.active_task);
+    let (chunks_done, chunks_total) = snapshot.and_then(|snapshot| snapshot.progress).unwrap_or_default();
+    ProgramStatus {
+        state,
+        has_task: task.is_some(),
+        task_id: task.map_or(0, |task| task.0),
+        chunks_done: chunks_done as u32,
+        chunks_total: chunks_total as u32,
+        cache_hits: cache.hits,
+        cache_misses: cache.misses,
+    }
+}
+
+// Connects only while the constraints allow it, and parks the session once it has finished what
+// it holds when they stop allowing it. The resume token carries its tasks across both.
+fn run(addr: String, constraints: SharedConstraints, stop: Arc<AtomicBool>, mut listener: Listener) {
+    let mut session: Option<Session<MobileTransport, WasmiExecutor, SystemClock>> = None;
+    let mut closed = Arc::new(AtomicBool::new(true));
+    let mut retry_at = Instant::now();
+    let mut retry_delay = Duration::from_secs(1);
+    let mut stop_by = None;
+
+    loop {
+        let current = constraints.get();
+        let connected = !closed.load(Ordering::Relaxed);
+        let settled = session.as_ref().is_none_or(|session| session.is_settled());
+
+        if stop.load(Ordering::Relaxed) {
+            let deadline = *stop_by.get_or_insert_with(|| Instant::now() + STOP_GRACE);
+            if settled || !connected || Instant::now() > deadline {
+                break;
+            }
+        } else if connected && !current.allows_work() && settled {
+            log::info!("Constraints no longer allow work, parking the session");
+            if let Some(session) = session.as_mut() {
+                session.reconnect(MobileTransport::parked());
+            }
+            closed = Arc::new(AtomicBool::new(true));
+        } else if !connected && current.allows_work() && Instant::now() >= retry_at {
+            listener.notify(status(ProgramState::Connecting, session.as_ref()));
+            match MobileTransport::connect(&addr, &current) {
+                Ok(transport) => {
+                    closed = transport.closed();
+                    retry_delay = Duration::from_secs(1);
+                    match session.as_mut() {
+                        Some(session) => session.reconnect(transport),
+                        None => {
+                            // Phones get a small slice of memory, the app still needs the rest.
+                            session = Some(
+                                Session::new(transport, WasmiExecutor::new(), SystemClock, 1024 * 16)
+                                    .with_labels(&["mobile"]),
+                            )
+                        }
+                    }
+                }
+                Err(e) => {
+                    log::warn!("Connection failed: {}, retrying in {:?}", e, retry_delay);
+                    retry_at = Instant::now() + retry_delay;
+                    retry_delay = (retry_delay * 2).min(Duration::from_secs(300));
+                }
+            }
+        }
+
+        let connected = !closed.load(Ordering::Relaxed);
+        let Some(session) = session.as_mut().filter(|_| connected) else {
+            let state = if current.allows_work() { ProgramState::Connecting } else { ProgramState::Paused };
+            listener.notify(status(state, session.as_ref()));
+            SystemClock.sleep(Duration::from_millis(250));
+            continue;
+        };
+
+        match session.step() {
+            Ok(StepStatus::NeedsSleep(duration)) => SystemClock.sleep(duration.min(Duration::from_millis(50))),
+            Ok(StepStatus::Failed) => log::warn!("Session step failed"),
+            Ok(StepStatus::Idle | StepStatus::Progress) => {}
+            Err(e) => log::error!("Session error: {}", e),
+        }
+        listener.notify(status(ProgramState::Ready, Some(session)));
+    }
+
+    listener.notify(status(ProgramState::Stopped, None));
+}
+
+/// Starts a worker thread against `host:port` and returns its handle, or null when `host` is not
+/// valid UTF-8. The worker stays paused until `program_set_constraints` allows it to work.
+///
+/// # Safety
+///
+/// `host` must point to a NUL terminated string. `callback` is invoked from the worker thread with
+/// `user_data`, which must stay valid until `program_stop` returns.
+#[no_mangle]
+pub unsafe extern "C" fn program_start(
+    host: *const c_char,
+    port: u16,
+    callback: ProgramStatusCallback,
+    user_data: *mut c_void,
+) -> *mut ProgramWorker {
+    let Ok(host) = CStr::from_ptr(host).to_str() else {
+        return std::ptr::null_mut();
+    };
+    let addr = format!("{}:{}", host, port);
+    let constraints = SharedConstraints::default();
+    let stop = Arc::new(AtomicBool::new(false));
+    let listener = Listener { callback, user_data, last: None };
+
+    let thread = thread::Builder::new().name("program-worker".into()).spawn({
+        let (constraints, stop) = (constraints.clone(), stop.clone());
+        move || run(addr, constraints, stop, listener)
+    });
+    match thread {
+        Ok(thread) => Box::into_raw(Box::new(ProgramWorker { constraints, stop, thread: Some(thread) })),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Reports the phone's network and battery state, call it whenever the platform signals a change.
+///
+/// # Safety
+///
+/// `worker` must come from `program_start` and not have been passed to `program_stop`.
+#[no_mangle]
+pub unsafe extern "C" fn program_set_constraints(worker: *mut ProgramWorker, constraints: ProgramConstraints) {
+    let Some(worker) = worker.as_ref() else {
+        return;
+    };
+    worker.constraints.set(Constraints {
+        metered: constraints.metered,
+        battery_percent: constraints.battery_percent.min(100),
+        charging: constraints.charging,
+    });
+}
+
+/// Stops the worker, giving a held task a few seconds to finish, and frees the handle. Blocks
+/// until the worker thread has exited, so do not call it from the status callback.
+///
+/// # Safety
+///
+/// `worker` must come from `program_start` and is invalid once this returns.
+#[no_mangle]
+pub unsafe extern "C" fn program_stop(worker: *mut ProgramWorker) {
+    if worker.is_null() {
+        return;
+    }
+    let mut worker = Box::from_raw(worker);
+    worker.stop.store(true, Ordering::Relaxed);
+    if let Some(thread) = worker.thread.take() {
+        thread.join().ok();
+    }
+}
@@ -0,0 +1,113 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use program::{Buf, BufMut, Transport};
+
+// Below this charge a phone off its charger stops taking work.
+const MIN_BATTERY_PERCENT: u8 = 30;
+
+// What the app last reported about the phone, through `program_set_constraints`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Constraints {
+    pub metered: bool,
+    pub battery_percent: u8,
+    pub charging: bool,
+}
+
+impl Default for Constraints {
+    // Nothing reported yet, hold off until the app says otherwise.
+    fn default() -> Self {
+        Self { metered: true, battery_percent: 0, charging: false }
+    }
+}
+
+impl Constraints {
+    // Modules and results are never pulled over cellular, and a draining battery is left alone.
+    pub fn allows_work(&self) -> bool {
+        !self.metered && (self.charging || self.battery_percent >= MIN_BATTERY_PERCENT)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SharedConstraints(Arc<Mutex<Constraints>>);
+
+impl SharedConstraints {
+    pub fn get(&self) -> Constraints {
+        self.0.lock().map(|constraints| *constraints).unwrap_or_default()
+    }
+
+    pub fn set(&self, constraints: Constraints) {
+        if let Ok(mut current) = self.0.lock() {
+            *current = constraints;
+        }
+    }
+}
+
+// A parked transport holds no socket, so nothing moves until the worker reconnects it.
+pub struct MobileTransport {
+    stream: Option<TcpStream>,
+    closed: Arc<AtomicBool>,
+}
+
+impl MobileTransport {
+    // Refuses to open a connection the constraints do not allow.
+    pub fn connect(addr: &str, constraints: &Constraints) -> std::io::Result<Self> {
+        if !constraints.allows_work() {
+            return Err(std::io::Error::other("network or battery constraints forbid connecting"));
+        }
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream: Some(stream), closed: Arc::new(AtomicBool::new(false)) })
+    }
+
+    pub fn parked() -> Self {
+        Self { stream: None, closed: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn closed(&self) -> Arc<AtomicBool> {
+        self.closed.clone()
+    }
+
+    fn fail(&self, error: std::io::Error) -> std::io::Error {
+        self.closed.store(true, Ordering::Relaxed);
+        error
+    }
+}
+
+impl Transport for MobileTransport {
+    type Error = std::io::Error;
+
+    fn read<B>(&mut self, buf: &mut B) -> Result<usize, Self::Error>
+    where
+        B: BufMut + ?Sized,
+    {
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(0);
+        };
+        let mut buffer = [0u8; 2048];
+        let bytes_read = match stream.read(&mut buffer) {
+            Ok(0) => return Err(self.fail(std::io::ErrorKind::UnexpectedEof.into())),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(self.fail(e)),
+        };
+        buf.put_slice(&buffer[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    fn write<B>(&mut self, src: &mut B) -> Result<usize, Self::Error>
+    where
+        B: Buf + ?Sized,
+    {
+        let Some(stream) = self.stream.as_mut() else {
+            return Ok(0);
+        };
+        match stream.write(src.chunk()) {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(self.fail(e)),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::fs;
+use std::path::Path;
+
+// Limits the container runtime placed on this process, `None` where it set none.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    pub memory: Option<u64>,
+    pub cpus: Option<f64>,
+}
+
+impl Limits {
+    // cgroup v2 first, then the v1 controllers older runtimes still mount.
+    pub fn detect() -> Self {
+        Self::from_v2(Path::new("/sys/fs/cgroup")).unwrap_or_else(|| Self::from_v1(Path::new("/sys/fs/cgroup")))
+    }
+
+    fn from_v2(root: &Path) -> Option<Self> {
+        let memory = fs::read_to_string(root.join("memory.max")).ok();
+        let cpu = fs::read_to_string(root.join("cpu.max")).ok();
+        if memory.is_none() && cpu.is_none() {
+            return None;
+        }
+        Some(Self {
+            memory: memory.as_deref().and_then(parse_memory),
+            cpus: cpu.as_deref().and_then(parse_cpu_max),
+        })
+    }
+
+    fn from_v1(root: &Path) -> Self {
+        let read = |path: &str| fs::read_to_string(root.join(path)).ok();
+        let quota = read("cpu/cpu.cfs_quota_us").and_then(|quota| quota.trim().parse::<i64>().ok());
+        let period = read("cpu/cpu.cfs_period_us").and_then(|period| period.trim().parse::<u64>().ok());
+        Self {
+            memory: read("memory/memory.limit_in_bytes").as_deref().and_then(parse_memory),
+            cpus: match (quota, period) {
+                (Some(quota), Some(period)) if quota > 0 && period > 0 => Some(quota as f64 / period as f64),
+                _ => None,
+            },
+        }
+    }
+
+    // One session per allotted core, a fractional quota still gets one.
+    pub fn workers(&self) -> Option<usize> {
+        self.cpus.map(|cpus| (cpus.ceil() as usize).max(1))
+    }
+}
+
+// v1 reports "no limit" as a page aligned i64::MAX rather than "max".
+fn parse_memory(value: &str) -> Option<u64> {
+    match value.trim() {
+        "max" => None,
+        value => value.parse::<u64>().ok().filter(|&bytes| bytes < i64::MAX as u64 & !0xfff),
+    }
+}
+
+// "<quota> <period>" in microseconds, quota is "max" when unlimited.
+fn parse_cpu_max(value: &str) -> Option<f64> {
+    let mut fields = value.split_whitespace();
+    let quota = fields.next()?.parse::<u64>().ok()?;
+    let period = fields.next().map_or(Some(100_000), |period| period.parse::<u64>().ok())?;
+    (period > 0).then(|| quota as f64 / period as f64)
+}
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::hash::{BuildHasher, RandomState};
+use std::io::Write;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use program::{CacheStats, SessionSnapshot};
+
+// Reconnect delays double from `base` up to `max`, each one drawn from the upper half of its
+// window so workers dropped together do not come back together.
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self { base, max, attempt: 0 }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let window = self.base.saturating_mul(1 << self.attempt.min(16)).min(self.max);
+        self.attempt += 1;
+        let jitter = RandomState::new().hash_one(self.attempt) % 1000;
+        window / 2 + window / 2 * jitter as u32 / 1000
+    }
+
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+}
+
+// Set by SIGTERM or SIGINT, workers finish what they hold and exit.
+#[derive(Clone)]
+pub struct Shutdown(Arc<AtomicBool>);
+
+impl Shutdown {
+    pub fn install() -> std::io::Result<Self> {
+        let flag = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, flag.clone())?;
+        signal_hook::flag::register(signal_hook::consts::SIGINT, flag.clone())?;
+        Ok(Self(flag))
+    }
+
+    pub fn requested(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    // Sleeps for `duration` unless shutdown is requested meanwhile.
+    pub fn sleep(&self, duration: Duration) {
+        let step = Duration::from_millis(100);
+        let mut left = duration;
+        while !left.is_zero() && !self.requested() {
+            thread::sleep(left.min(step));
+            left = left.saturating_sub(step);
+        }
+    }
+}
+
+// Latest state of every worker, one line each on the status socket.
+#[derive(Clone, Default)]
+pub struct StatusBoard(Arc<Mutex<BTreeMap<usize, String>>>);
+
+impl StatusBoard {
+    pub fn update(&self, worker: usize, connected: bool, snapshot: &SessionSnapshot, cache: &CacheStats) {
+        let task = snapshot.active_task.map_or_else(|| "-".into(), |task| task.to_string());
+        let progress = snapshot
+            .progress
+            .map_or_else(|| "-".into(), |(done, total)| format!("{}/{}", done, total));
+        let line = format!(
+            "worker-{} connected={} phase={:?} task={} progress={} modules={} hits={} misses={} evictions={} bytes={} protocol_errors={}",
+            worker,
+            connected,
+            snapshot.phase,
+            task,
+            progress,
+            snapshot.cached_modules.len(),
+            cache.hits,
+            cache.misses,
+            cache.evictions,
+            cache.bytes_used,
+            snapshot.protocol_errors,
+        );
+        if let Ok(mut workers) = self.0.lock() {
+            workers.insert(worker, line);
+        }
+    }
+
+    // Answers every connection with the current board and closes it, `nc 127.0.0.1 <port>` is
+    // enough to read it.
+    pub fn serve(&self, addr: SocketAddr) -> std::io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        log::info!("Status socket listening on {}", listener.local_addr()?);
+        let board = self.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let report = match board.0.lock() {
+                    Ok(workers) => workers.values().map(|line| format!("{}\n", line)).collect::<String>(),
+                    Err(_) => continue,
+                };
+                stream.write_all(report.as_bytes()).ok();
+            }
+        });
+        Ok(())
+    }
+}
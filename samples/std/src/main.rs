@@ -1,12 +1,35 @@
+use std::fs;
 use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::net::{SocketAddr, TcpStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+#[cfg(feature = "container")]
+mod cgroup;
+mod daemon;
+#[cfg(feature = "native")]
+mod native_executor;
+#[cfg(feature = "wasm3")]
+mod wasm3_executor;
+#[cfg(feature = "wasmtime")]
+mod wasmtime_executor;
+
+use daemon::{Backoff, Shutdown, StatusBoard};
 use program::*;
+#[cfg(feature = "wamr")]
 use wamr_rust_sdk::{
     function::Function, instance::Instance, module::Module, runtime::Runtime, value::WasmValue,
     RuntimeError,
 };
+#[cfg(feature = "native")]
+use native_executor::NativeExecutor;
+#[cfg(feature = "wasm3")]
+use wasm3_executor::Wasm3Executor;
+#[cfg(feature = "wasmtime")]
+use wasmtime_executor::WasmtimeExecutor;
 
 pub struct SystemClock;
 
@@ -15,29 +38,41 @@ impl Clock for SystemClock {
         SystemTime::now()
             .duration_since(UNIX_EPOCH)
             .unwrap()
-            .as_secs()
+            .as_nanos() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
     }
 }
 
+#[cfg(feature = "wamr")]
 pub struct WasmExecutor;
 
-impl Executor for WasmExecutor {
-    type Error = RuntimeError;
-
-    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+#[cfg(feature = "wamr")]
+impl WasmExecutor {
+    fn run(&self, binary: &[u8], libraries: &[(String, Vec<u8>)], params: Vec<Type>) -> Result<Vec<Type>, RuntimeError> {
+        // The WAMR bindings expose no linear memory access, so buffers need the wasmtime executor.
         let wasm_params = params
             .iter()
             .map(|f| match f {
-                Type::Void => WasmValue::Void,
-                Type::I32(v) => WasmValue::I32(*v),
-                Type::I64(v) => WasmValue::I64(*v),
-                Type::F32(v) => WasmValue::F32(*v),
-                Type::F64(v) => WasmValue::F64(*v),
-                Type::V128(v) => WasmValue::V128(*v),
+                Type::Void => Ok(WasmValue::Void),
+                Type::I32(v) => Ok(WasmValue::I32(*v)),
+                Type::I64(v) => Ok(WasmValue::I64(*v)),
+                Type::F32(v) => Ok(WasmValue::F32(*v)),
+                Type::F64(v) => Ok(WasmValue::F64(*v)),
+                Type::V128(v) => Ok(WasmValue::V128(*v)),
+                Type::Bytes(_) | Type::BlobRef(..) => Err(RuntimeError::NotImplemented),
             })
-            .collect();
+            .collect::<Result<Vec<_>, _>>()?;
 
         let runtime = Runtime::new()?;
+        // Loading registers each library under its name, the main module's imports resolve against
+        // them while it is instantiated, so they stay loaded until the call returns.
+        let _libraries = libraries
+            .iter()
+            .map(|(name, binary)| Module::from_vec(&runtime, binary.clone(), name))
+            .collect::<Result<Vec<_>, _>>()?;
         let module = Module::from_vec(&runtime, binary.to_vec(), "container")?;
 
         let instance = Instance::new(&runtime, &module, 1024 * 64)?;
@@ -61,15 +96,171 @@ impl Executor for WasmExecutor {
     }
 }
 
+#[cfg(feature = "wamr")]
+impl Executor for WasmExecutor {
+    type Error = RuntimeError;
+
+    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        self.run(binary, &[], params)
+    }
+
+    fn execute_linked(
+        &self,
+        binary: &[u8],
+        libraries: &[(String, Vec<u8>)],
+        params: Vec<Type>,
+        _env: &[(String, String)],
+    ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.run(binary, libraries, params).map(|result| (result, ExecutionStats::default()))
+    }
+
+    // WAMR builds its fast interpreter unless the `llvmjit` feature is enabled. With `aot` it takes
+    // the server's wamrc output, `Module::from_vec` tells it from wasm by its magic, and falls back
+    // to the interpreter for modules that failed to compile.
+    fn flavor(&self) -> ExecutorFlavor {
+        if cfg!(feature = "aot") {
+            ExecutorFlavor::Aot
+        } else if cfg!(feature = "llvmjit") {
+            ExecutorFlavor::Jit
+        } else {
+            ExecutorFlavor::Interpreter
+        }
+    }
+}
+
+pub struct FsCacheStore {
+    root: PathBuf,
+}
+
+impl FsCacheStore {
+    const EXTENSION: &str = "wasm";
+
+    pub fn new(root: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let root = root.into();
+        fs::create_dir_all(&root)?;
+        Ok(Self { root })
+    }
+
+    // Keys are module names from the server, everything but ASCII alphanumerics, `-` and `_` is
+    // escaped as `%XX` so dots, separators and `..` never reach the file name.
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(format!("{}.{}", Self::escape(key), Self::EXTENSION))
+    }
+
+    fn escape(key: &str) -> String {
+        key.bytes()
+            .map(|byte| match byte {
+                b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'-' | b'_' => (byte as char).to_string(),
+                _ => format!("%{:02X}", byte),
+            })
+            .collect()
+    }
+
+    fn unescape(stem: &str) -> Option<String> {
+        let mut bytes = Vec::with_capacity(stem.len());
+        let mut chars = stem.bytes();
+        while let Some(byte) = chars.next() {
+            match byte {
+                b'%' => {
+                    let hex = [chars.next()?, chars.next()?];
+                    bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+                }
+                _ => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).ok()
+    }
+}
+
+impl CacheStore for FsCacheStore {
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        let entries = fs::read_dir(&self.root).map_err(|e| Error::Storage(e.to_string()))?;
+        Ok(entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().and_then(|e| e.to_str()) == Some(Self::EXTENSION))
+            .filter_map(|path| Self::unescape(path.file_stem()?.to_str()?))
+            .collect())
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(self.path(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Storage(e.to_string())),
+        }
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), Error> {
+        fs::write(self.path(key), data).map_err(|e| Error::Storage(e.to_string()))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Error> {
+        match fs::remove_file(self.path(key)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error::Storage(e.to_string())),
+            _ => Ok(()),
+        }
+    }
+}
+
+// One store behind every worker of the process, a module any of them received is restored by the
+// others instead of being transferred again.
+#[derive(Clone)]
+pub struct SharedCacheStore(Arc<Mutex<FsCacheStore>>);
+
+impl SharedCacheStore {
+    pub fn new(store: FsCacheStore) -> Self {
+        Self(Arc::new(Mutex::new(store)))
+    }
+
+    fn lock(&self) -> Result<std::sync::MutexGuard<'_, FsCacheStore>, Error> {
+        self.0.lock().map_err(|e| Error::Storage(e.to_string()))
+    }
+}
+
+impl CacheStore for SharedCacheStore {
+    fn keys(&self) -> Result<Vec<String>, Error> {
+        self.lock()?.keys()
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        self.lock()?.load(key)
+    }
+
+    fn store(&mut self, key: &str, data: &[u8]) -> Result<(), Error> {
+        self.lock()?.store(key, data)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<(), Error> {
+        self.lock()?.remove(key)
+    }
+}
+
 pub struct TcpTransport {
     stream: TcpStream,
+    closed: Arc<AtomicBool>,
 }
 
 impl TcpTransport {
     pub fn new(addr: &str) -> Result<Self, Box<dyn std::error::Error>> {
         let stream = TcpStream::connect(addr)?;
         stream.set_nonblocking(true)?;
-        Ok(Self { stream })
+        Ok(Self {
+            stream,
+            closed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    // Raised once the peer closed the connection or it failed, the session only sees an error.
+    pub fn closed(&self) -> Arc<AtomicBool> {
+        self.closed.clone()
+    }
+
+    fn check<T>(&self, result: std::io::Result<T>) -> std::io::Result<T> {
+        if result.is_err() {
+            self.closed.store(true, Ordering::Relaxed);
+        }
+        result
     }
 }
 
@@ -82,9 +273,10 @@ impl Transport for TcpTransport {
     {
         let mut buffer = [0u8; 2048];
         let bytes_read = match self.stream.read(&mut buffer) {
+            Ok(0) => return self.check(Err(std::io::ErrorKind::UnexpectedEof.into())),
             Ok(n) => n,
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
-            Err(e) => return Err(e),
+            Err(e) => return self.check(Err(e)),
         };
         buf.put_slice(&buffer[..bytes_read]);
         Ok(bytes_read)
@@ -98,32 +290,180 @@ impl Transport for TcpTransport {
         let bytes_written = match self.stream.write(src_bytes) {
             Ok(n) => n,
             Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
-            Err(e) => return Err(e),
+            Err(e) => return self.check(Err(e)),
         };
         Ok(bytes_written)
     }
 }
 
+// How long a worker asked to stop may keep going to hand in the task it holds.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(30);
+
+// Memory each session advertises in ClientReady outside a container.
+const DEVICE_RAM: u64 = 1024 * 64;
+
+// Lets selectors target, or avoid, the pool of containerized workers.
+#[cfg(feature = "container")]
+const LABELS: &[&str] = &["container"];
+#[cfg(not(feature = "container"))]
+const LABELS: &[&str] = &[];
+
 fn main() {
     let Config { host, dispatcher_port, .. } = Config::new();
-    let addr = format!("{}:{}", host, dispatcher_port);
+    // The image is built once and pointed at a server when it runs.
+    let addr = flag_value("--server")
+        .or_else(|| std::env::var("PROGRAM_SERVER").ok())
+        .unwrap_or_else(|| format!("{}:{}", host, dispatcher_port));
 
     env_logger::init();
 
-    let transport = loop {
-        match TcpTransport::new(&addr) {
-            Ok(t) => break t,
+    let shutdown = Shutdown::install().unwrap();
+    let status = StatusBoard::default();
+    if let Some(status_addr) = flag_value("--status").and_then(|addr| addr.parse::<SocketAddr>().ok()) {
+        status.serve(status_addr).unwrap();
+    }
+
+    let store = SharedCacheStore::new(FsCacheStore::new("cache").unwrap());
+    let workers = worker_count();
+    let device_ram = device_ram(workers);
+    log::info!("Starting {} workers with {} bytes each against {}", workers, device_ram, addr);
+
+    let handles = (0..workers)
+        .map(|worker| {
+            let (addr, store, shutdown, status) = (addr.clone(), store.clone(), shutdown.clone(), status.clone());
+            thread::Builder::new()
+                .name(format!("worker-{}", worker))
+                .spawn(move || run_worker(worker, &addr, device_ram, store, &shutdown, &status))
+                .unwrap()
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().ok();
+    }
+    log::info!("All workers stopped");
+}
+
+fn flag_value(flag: &str) -> Option<String> {
+    std::env::args().skip_while(|arg| arg != flag).nth(1)
+}
+
+// `--workers N` runs N sessions side by side, `--workers 0` one per CPU core. In a container the
+// default is one per core of its CPU quota.
+fn worker_count() -> usize {
+    match flag_value("--workers").and_then(|count| count.parse::<usize>().ok()) {
+        Some(0) => thread::available_parallelism().map(|cores| cores.get()).unwrap_or(1),
+        Some(count) => count,
+        #[cfg(feature = "container")]
+        None => cgroup::Limits::detect().workers().unwrap_or(1),
+        #[cfg(not(feature = "container"))]
+        None => 1,
+    }
+}
+
+// A container splits its memory limit between the workers, each advertises its share.
+#[cfg(feature = "container")]
+fn device_ram(workers: usize) -> u64 {
+    let limits = cgroup::Limits::detect();
+    log::info!("Container limits: memory {:?} bytes, {:?} cpus", limits.memory, limits.cpus);
+    limits.memory.map_or(DEVICE_RAM, |memory| memory / workers.max(1) as u64)
+}
+
+#[cfg(not(feature = "container"))]
+fn device_ram(_workers: usize) -> u64 {
+    DEVICE_RAM
+}
+
+// `None` once shutdown was requested before a connection came up.
+fn connect(addr: &str, backoff: &mut Backoff, shutdown: &Shutdown) -> Option<TcpTransport> {
+    while !shutdown.requested() {
+        match TcpTransport::new(addr) {
+            Ok(transport) => {
+                backoff.reset();
+                return Some(transport);
+            }
             Err(e) => {
-                log::error!("Connection failed: {}, retrying in 10 seconds...", e);
-                std::thread::sleep(Duration::from_secs(10));
+                let delay = backoff.next_delay();
+                log::error!("Connection failed: {}, retrying in {:?}...", e, delay);
+                shutdown.sleep(delay);
             }
         }
-    };
+    }
+    None
+}
 
+// Executors are built per worker, the runtimes behind them are not shared across threads.
+fn run_worker(
+    worker: usize,
+    addr: &str,
+    device_ram: u64,
+    store: SharedCacheStore,
+    shutdown: &Shutdown,
+    status: &StatusBoard,
+) {
+    // Desktop workers prefer the wasmtime JIT when it is compiled in.
+    #[cfg(feature = "wasmtime")]
+    let executor = WasmtimeExecutor::new().unwrap();
+    #[cfg(all(feature = "wasm3", not(feature = "wasmtime")))]
+    let executor = Wasm3Executor;
+    #[cfg(all(feature = "wamr", not(any(feature = "wasmtime", feature = "wasm3"))))]
     let executor = WasmExecutor;
-    let clock = SystemClock;
 
-    let mut session = Session::new(transport, executor, clock, 1024 * 64);
+    // Native plugins are unsandboxed, so they stay off unless explicitly allowed.
+    #[cfg(feature = "native")]
+    if std::env::args().any(|arg| arg == "--allow-native") {
+        let executor = NativeExecutor::new(executor, "native").unwrap();
+        return run_session(worker, addr, executor, device_ram, store, shutdown, status);
+    }
+
+    run_session(worker, addr, executor, device_ram, store, shutdown, status);
+}
+
+// Keeps one session alive across dropped connections, the resume token lets the server hand it
+// back its tasks. On shutdown the session may run until it has nothing left to send.
+fn run_session<E: Executor>(
+    worker: usize,
+    addr: &str,
+    executor: E,
+    device_ram: u64,
+    store: SharedCacheStore,
+    shutdown: &Shutdown,
+    status: &StatusBoard,
+) {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    let Some(transport) = connect(addr, &mut backoff, shutdown) else {
+        return;
+    };
+    let mut closed = transport.closed();
+
+    let mut session = Session::new(transport, executor, SystemClock, device_ram)
+        .with_labels(LABELS)
+        .with_cache_store(store)
+        .unwrap();
+    let mut stop_by = None;
+
+    loop {
+        if shutdown.requested() {
+            let deadline = *stop_by.get_or_insert_with(|| Instant::now() + SHUTDOWN_GRACE);
+            if session.is_settled() || closed.load(Ordering::Relaxed) || Instant::now() > deadline {
+                log::info!("Worker {} stopped", worker);
+                return;
+            }
+        } else if closed.load(Ordering::Relaxed) {
+            log::warn!("Worker {} lost its connection, reconnecting", worker);
+            let Some(transport) = connect(addr, &mut backoff, shutdown) else {
+                return;
+            };
+            closed = transport.closed();
+            session.reconnect(transport);
+        }
 
-    session.run().unwrap();
+        // The socket is non-blocking and cannot wake us, so sleeps are capped to keep latency low.
+        match session.step() {
+            Ok(StepStatus::NeedsSleep(duration)) => SystemClock.sleep(duration.min(Duration::from_millis(10))),
+            Ok(StepStatus::Failed) => log::warn!("Worker {} session step failed", worker),
+            Ok(StepStatus::Idle | StepStatus::Progress) => {}
+            Err(e) => log::error!("Worker {} session error: {}", worker, e),
+        }
+        status.update(worker, !closed.load(Ordering::Relaxed), &session.snapshot(), &session.cache_stats());
+    }
 }
@@ -0,0 +1,153 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+
+use libloading::{Library, Symbol};
+use program::{Executor, ExecutorFlavor, Type};
+
+const MAX_RESULTS: usize = 16;
+
+// C ABI shared with native task plugins: `tag` selects the variant of `Type` and
+// `bits` holds its little-endian value.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NativeValue {
+    pub tag: u8,
+    pub bits: [u8; 16],
+}
+
+impl TryFrom<&Type> for NativeValue {
+    type Error = NativeError;
+
+    fn try_from(value: &Type) -> Result<Self, NativeError> {
+        let mut bits = [0u8; 16];
+        let tag = match value {
+            Type::Void => 0,
+            Type::I32(v) => {
+                bits[..4].copy_from_slice(&v.to_le_bytes());
+                1
+            }
+            Type::I64(v) => {
+                bits[..8].copy_from_slice(&v.to_le_bytes());
+                2
+            }
+            Type::F32(v) => {
+                bits[..4].copy_from_slice(&v.to_le_bytes());
+                3
+            }
+            Type::F64(v) => {
+                bits[..8].copy_from_slice(&v.to_le_bytes());
+                4
+            }
+            Type::V128(v) => {
+                bits = v.to_le_bytes();
+                5
+            }
+            Type::Bytes(_) | Type::BlobRef(..) => return Err(NativeError::UnsupportedBytes),
+        };
+        Ok(Self { tag, bits })
+    }
+}
+
+impl TryFrom<NativeValue> for Type {
+    type Error = NativeError;
+
+    fn try_from(value: NativeValue) -> Result<Self, NativeError> {
+        let bits = value.bits;
+        Ok(match value.tag {
+            0 => Type::Void,
+            1 => Type::I32(i32::from_le_bytes(bits[..4].try_into().unwrap())),
+            2 => Type::I64(i64::from_le_bytes(bits[..8].try_into().unwrap())),
+            3 => Type::F32(f32::from_le_bytes(bits[..4].try_into().unwrap())),
+            4 => Type::F64(f64::from_le_bytes(bits[..8].try_into().unwrap())),
+            5 => Type::V128(i128::from_le_bytes(bits)),
+            tag => return Err(NativeError::InvalidValue(tag)),
+        })
+    }
+}
+
+// `run(params, params_len, results, results_cap)` returns the number of results
+// written, or a negative plugin specific error code.
+type RunFn = unsafe extern "C" fn(*const NativeValue, usize, *mut NativeValue, usize) -> isize;
+
+#[derive(Debug, thiserror::Error)]
+pub enum NativeError {
+    #[error("Wasm execution failed: {0}")]
+    Wasm(String),
+    #[error("Failed to stage native module: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Failed to load native module: {0}")]
+    Load(#[from] libloading::Error),
+    #[error("Native module returned error code {0}")]
+    Failed(isize),
+    #[error("Native module returned invalid value tag {0}")]
+    InvalidValue(u8),
+    #[error("Native modules do not accept byte buffers")]
+    UnsupportedBytes,
+}
+
+// Runs wasm modules on the wrapped executor and loads anything else as a native
+// cdylib. Plugins run unsandboxed, so only trusted workers should enable this.
+pub struct NativeExecutor<E: Executor> {
+    inner: E,
+    dir: PathBuf,
+}
+
+impl<E: Executor> NativeExecutor<E> {
+    const WASM_MAGIC: &[u8] = b"\0asm";
+
+    pub fn new(inner: E, dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { inner, dir })
+    }
+
+    fn run_native(&self, binary: &[u8], params: &[Type]) -> Result<Vec<Type>, NativeError> {
+        let mut hasher = DefaultHasher::new();
+        binary.hash(&mut hasher);
+        let path = self.dir.join(format!("{:016x}", hasher.finish())).with_extension(std::env::consts::DLL_EXTENSION);
+        if !path.exists() {
+            fs::write(&path, binary)?;
+        }
+
+        let params = params.iter().map(NativeValue::try_from).collect::<Result<Vec<_>, _>>()?;
+        let mut results = [NativeValue::default(); MAX_RESULTS];
+
+        // Safety: loading and calling the plugin is only enabled by the --allow-native flag,
+        // which declares its modules trusted to follow the `RunFn` ABI.
+        let written = unsafe {
+            let library = Library::new(&path)?;
+            let run: Symbol<RunFn> = library.get(b"run")?;
+            run(params.as_ptr(), params.len(), results.as_mut_ptr(), results.len())
+        };
+
+        if written < 0 {
+            return Err(NativeError::Failed(written));
+        }
+        results[..(written as usize).min(MAX_RESULTS)]
+            .iter()
+            .map(|value| Type::try_from(*value))
+            .collect()
+    }
+}
+
+impl<E: Executor> Executor for NativeExecutor<E> {
+    type Error = NativeError;
+
+    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        if binary.starts_with(Self::WASM_MAGIC) {
+            return self.inner.execute(binary, params).map_err(|e| NativeError::Wasm(e.to_string()));
+        }
+        self.run_native(binary, &params)
+    }
+
+    // Wasm runs on the inner executor, so its flavor is the inner one.
+    fn flavor(&self) -> ExecutorFlavor {
+        self.inner.flavor()
+    }
+
+    fn native(&self) -> bool {
+        true
+    }
+}
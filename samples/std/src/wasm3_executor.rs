@@ -0,0 +1,141 @@
+use std::ffi::{c_void, CStr, CString};
+use std::ptr;
+
+use program::{Executor, ExecutorFlavor, Type};
+use wasm3_sys as ffi;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Wasm3Error {
+    #[error("Wasm3 error: {0}")]
+    Runtime(String),
+    #[error("Module does not export a `run` function")]
+    MissingExport,
+    #[error("Unsupported value {0}")]
+    Unsupported(String),
+}
+
+// Null on success, otherwise a static message owned by Wasm3.
+fn check(result: ffi::M3Result) -> Result<(), Wasm3Error> {
+    if result.is_null() {
+        return Ok(());
+    }
+    let message = unsafe { CStr::from_ptr(result) };
+    Err(Wasm3Error::Runtime(message.to_string_lossy().into_owned()))
+}
+
+// One value slot per param or result, Wasm3 reads and writes them through untyped pointers.
+#[derive(Clone, Copy)]
+#[repr(C)]
+union Slot {
+    i32: i32,
+    i64: i64,
+    f32: f32,
+    f64: f64,
+}
+
+// Wasm3 interprets modules without generating code, for chips where neither AOT nor JIT is an
+// option. It is selected with the `wasm3` feature in place of WAMR.
+pub struct Wasm3Executor;
+
+impl Wasm3Executor {
+    const STACK_SIZE: u32 = 1024 * 64;
+
+    fn run(&self, binary: &[u8], params: &[Type]) -> Result<Vec<Type>, Wasm3Error> {
+        let mut slots = params
+            .iter()
+            .map(|param| match param {
+                Type::I32(v) => Ok(Slot { i32: *v }),
+                Type::I64(v) => Ok(Slot { i64: *v }),
+                Type::F32(v) => Ok(Slot { f32: *v }),
+                Type::F64(v) => Ok(Slot { f64: *v }),
+                other => Err(Wasm3Error::Unsupported(format!("{:?}", other))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // Wasm3 parses in place, the bytes have to outlive the runtime that owns the module.
+        let binary = binary.to_vec();
+        let runtime = Runtime::new(Self::STACK_SIZE)?;
+        runtime.load(&binary)?;
+
+        let name = CString::new("run").unwrap();
+        let mut function: ffi::IM3Function = ptr::null_mut();
+        check(unsafe { ffi::m3_FindFunction(&mut function, runtime.raw, name.as_ptr()) })
+            .map_err(|_| Wasm3Error::MissingExport)?;
+
+        let arg_count = unsafe { ffi::m3_GetArgCount(function) } as usize;
+        if arg_count != slots.len() {
+            return Err(Wasm3Error::Runtime(format!("`run` takes {} params, got {}", arg_count, slots.len())));
+        }
+        let mut args = slots.iter_mut().map(|slot| slot as *mut Slot as *const c_void).collect::<Vec<_>>();
+        check(unsafe { ffi::m3_Call(function, args.len() as u32, args.as_mut_ptr()) })?;
+
+        let ret_count = unsafe { ffi::m3_GetRetCount(function) };
+        let mut results = vec![Slot { i64: 0 }; ret_count as usize];
+        let mut rets = results.iter_mut().map(|slot| slot as *mut Slot as *const c_void).collect::<Vec<_>>();
+        check(unsafe { ffi::m3_GetResults(function, ret_count, rets.as_mut_ptr()) })?;
+
+        results
+            .iter()
+            .enumerate()
+            .map(|(index, slot)| unsafe {
+                match ffi::m3_GetRetType(function, index as u32) {
+                    ffi::M3ValueType::c_m3Type_i32 => Ok(Type::I32(slot.i32)),
+                    ffi::M3ValueType::c_m3Type_i64 => Ok(Type::I64(slot.i64)),
+                    ffi::M3ValueType::c_m3Type_f32 => Ok(Type::F32(slot.f32)),
+                    ffi::M3ValueType::c_m3Type_f64 => Ok(Type::F64(slot.f64)),
+                    other => Err(Wasm3Error::Unsupported(format!("{:?}", other))),
+                }
+            })
+            .collect()
+    }
+}
+
+// An environment with a single runtime, freed together once the call returns.
+struct Runtime {
+    environment: ffi::IM3Environment,
+    raw: ffi::IM3Runtime,
+}
+
+impl Runtime {
+    fn new(stack_size: u32) -> Result<Self, Wasm3Error> {
+        let environment = unsafe { ffi::m3_NewEnvironment() };
+        if environment.is_null() {
+            return Err(Wasm3Error::Runtime("out of memory".into()));
+        }
+        let raw = unsafe { ffi::m3_NewRuntime(environment, stack_size, ptr::null_mut()) };
+        if raw.is_null() {
+            unsafe { ffi::m3_FreeEnvironment(environment) };
+            return Err(Wasm3Error::Runtime("out of memory".into()));
+        }
+        Ok(Self { environment, raw })
+    }
+
+    // The runtime owns the module once it is loaded, a module that fails to load is freed here.
+    fn load(&self, binary: &[u8]) -> Result<(), Wasm3Error> {
+        let mut module: ffi::IM3Module = ptr::null_mut();
+        check(unsafe { ffi::m3_ParseModule(self.environment, &mut module, binary.as_ptr(), binary.len() as u32) })?;
+        check(unsafe { ffi::m3_LoadModule(self.raw, module) }).inspect_err(|_| unsafe { ffi::m3_FreeModule(module) })
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::m3_FreeRuntime(self.raw);
+            ffi::m3_FreeEnvironment(self.environment);
+        }
+    }
+}
+
+impl Executor for Wasm3Executor {
+    type Error = Wasm3Error;
+
+    // Wasm3 resolves imports against host functions only, modules linking libraries need WAMR.
+    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        self.run(binary, &params)
+    }
+
+    fn flavor(&self) -> ExecutorFlavor {
+        ExecutorFlavor::Interpreter
+    }
+}
@@ -0,0 +1,363 @@
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use program::checkpoint::{self, Resumable};
+use program::env::{self, Environ};
+use program::memory::{self, GuestMemory, MemoryError};
+use program::{ExecutionStats, Executor, ExecutorFlavor, Type};
+use wasmtime::{
+    AsContextMut, Caller, Config, Engine, Extern, Instance, Linker, Module, Store, Trap, Val, ValType, V128,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum WasmtimeError {
+    #[error("Wasmtime error: {0}")]
+    Runtime(String),
+    #[error("Module does not export a `run` function")]
+    MissingExport,
+    #[error("Task ran out of fuel ({0} units)")]
+    OutOfFuel(u64),
+    #[error("Task exceeded its deadline ({0:?})")]
+    Interrupted(Duration),
+}
+
+impl From<wasmtime::Error> for WasmtimeError {
+    fn from(error: wasmtime::Error) -> Self {
+        Self::Runtime(error.to_string())
+    }
+}
+
+impl From<MemoryError<wasmtime::Error>> for WasmtimeError {
+    fn from(error: MemoryError<wasmtime::Error>) -> Self {
+        Self::Runtime(error.to_string())
+    }
+}
+
+// What host functions reach through the store. `instance` is the task's module once it is
+// instantiated, checkpoints it saves go to the thread waiting on the task.
+struct Host {
+    environ: Environ,
+    instance: Option<Instance>,
+    checkpoints: mpsc::Sender<Vec<u8>>,
+}
+
+// Over the store while the host drives the module, or over the caller inside a host function.
+struct InstanceMemory<S> {
+    store: S,
+    instance: Instance,
+}
+
+impl<S: AsContextMut> InstanceMemory<S> {
+    fn call(&mut self, name: &str, param: Option<i32>) -> wasmtime::Result<Option<i32>> {
+        let Some(function) = self.instance.get_func(&mut self.store, name) else {
+            return Ok(None);
+        };
+        let result = match param {
+            Some(param) => function.typed::<i32, i32>(&self.store)?.call(&mut self.store, param)?,
+            None => function.typed::<(), i32>(&self.store)?.call(&mut self.store, ())?,
+        };
+        Ok(Some(result))
+    }
+
+    fn memory(&mut self) -> wasmtime::Result<wasmtime::Memory> {
+        self.instance
+            .get_memory(&mut self.store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("module does not export `memory`"))
+    }
+}
+
+impl<S: AsContextMut> GuestMemory for InstanceMemory<S> {
+    type Error = wasmtime::Error;
+
+    fn alloc(&mut self, len: u32) -> wasmtime::Result<Option<u32>> {
+        Ok(self.call("alloc", Some(len as i32))?.map(|ptr| ptr as u32))
+    }
+
+    fn write(&mut self, ptr: u32, data: &[u8]) -> wasmtime::Result<()> {
+        let memory = self.memory()?;
+        memory.write(&mut self.store, ptr as usize, data)?;
+        Ok(())
+    }
+
+    fn read(&mut self, ptr: u32, len: u32) -> wasmtime::Result<Vec<u8>> {
+        let memory = self.memory()?;
+        let mut data = vec![0u8; len as usize];
+        memory.read(&self.store, ptr as usize, &mut data)?;
+        Ok(data)
+    }
+
+    fn output(&mut self) -> wasmtime::Result<Option<(u32, u32)>> {
+        let ptr = self.call("output_ptr", None)?;
+        let len = self.call("output_len", None)?;
+        Ok(ptr.zip(len).map(|(ptr, len)| (ptr as u32, len as u32)))
+    }
+}
+
+impl<S: AsContextMut> Resumable for InstanceMemory<S> {
+    fn checkpoint(&mut self) -> wasmtime::Result<Option<i64>> {
+        let Some(function) = self.instance.get_func(&mut self.store, "checkpoint") else {
+            return Ok(None);
+        };
+        Ok(Some(function.typed::<(), i64>(&self.store)?.call(&mut self.store, ())?))
+    }
+
+    fn resume(&mut self, ptr: u32, len: u32) -> wasmtime::Result<bool> {
+        let Some(function) = self.instance.get_func(&mut self.store, "resume") else {
+            return Ok(false);
+        };
+        function.typed::<(i32, i32), ()>(&self.store)?.call(&mut self.store, (ptr as i32, len as i32))?;
+        Ok(true)
+    }
+}
+
+pub struct WasmtimeExecutor {
+    engine: Engine,
+    fuel: u64,
+    tick: Duration,
+    deadline_ticks: u64,
+}
+
+impl WasmtimeExecutor {
+    const DEFAULT_FUEL: u64 = 1 << 32;
+    const DEFAULT_TICK: Duration = Duration::from_millis(10);
+    const DEFAULT_DEADLINE_TICKS: u64 = 3000;
+
+    pub fn new() -> Result<Self, WasmtimeError> {
+        Self::with_limits(Self::DEFAULT_FUEL, Self::DEFAULT_TICK, Self::DEFAULT_DEADLINE_TICKS)
+    }
+
+    // `fuel` bounds the instructions a single task may run, `tick * deadline_ticks`
+    // bounds its wall-clock time.
+    pub fn with_limits(fuel: u64, tick: Duration, deadline_ticks: u64) -> Result<Self, WasmtimeError> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        config.epoch_interruption(true);
+        let engine = Engine::new(&config)?;
+
+        let ticker = engine.clone();
+        thread::Builder::new()
+            .name("wasmtime-epoch".into())
+            .spawn(move || loop {
+                thread::sleep(tick);
+                ticker.increment_epoch();
+            })
+            .map_err(|e| WasmtimeError::Runtime(e.to_string()))?;
+
+        Ok(Self {
+            engine,
+            fuel,
+            tick,
+            deadline_ticks,
+        })
+    }
+
+    fn to_val(value: &Type) -> Option<Val> {
+        match value {
+            Type::Void => None,
+            Type::I32(v) => Some(Val::I32(*v)),
+            Type::I64(v) => Some(Val::I64(*v)),
+            Type::F32(v) => Some(Val::F32(v.to_bits())),
+            Type::F64(v) => Some(Val::F64(v.to_bits())),
+            Type::V128(v) => Some(Val::V128(V128::from(*v as u128))),
+            // Buffers are lowered to (ptr, len) pairs before the call, the session has already
+            // swapped blob references for buffers.
+            Type::Bytes(_) | Type::BlobRef(..) => None,
+        }
+    }
+
+    fn from_val(value: &Val) -> Type {
+        match value {
+            Val::I32(v) => Type::I32(*v),
+            Val::I64(v) => Type::I64(*v),
+            Val::F32(v) => Type::F32(f32::from_bits(*v)),
+            Val::F64(v) => Type::F64(f64::from_bits(*v)),
+            Val::V128(v) => Type::V128(v.as_u128() as i128),
+            _ => Type::Void,
+        }
+    }
+
+    // The task's environment behind the WASI preview 1 `environ_*` imports, and `checkpoint.save`.
+    fn linker(&self) -> wasmtime::Result<Linker<Host>> {
+        let mut linker = Linker::new(&self.engine);
+        linker.func_wrap(
+            env::MODULE,
+            "environ_sizes_get",
+            |mut caller: Caller<'_, Host>, count_ptr: i32, buf_size_ptr: i32| -> wasmtime::Result<i32> {
+                let environ = &caller.data().environ;
+                let (count, buf_size) = (environ.count(), environ.buf().len() as u32);
+                Self::write_guest(&mut caller, count_ptr, &count.to_le_bytes())?;
+                Self::write_guest(&mut caller, buf_size_ptr, &buf_size.to_le_bytes())?;
+                Ok(0)
+            },
+        )?;
+        linker.func_wrap(
+            env::MODULE,
+            "environ_get",
+            |mut caller: Caller<'_, Host>, environ_ptr: i32, buf_ptr: i32| -> wasmtime::Result<i32> {
+                let pointers = caller.data().environ.pointers(buf_ptr as u32);
+                let buf = caller.data().environ.buf().to_vec();
+                Self::write_guest(&mut caller, environ_ptr, &pointers)?;
+                Self::write_guest(&mut caller, buf_ptr, &buf)?;
+                Ok(0)
+            },
+        )?;
+        linker.func_wrap(checkpoint::MODULE, checkpoint::SAVE, |mut caller: Caller<'_, Host>| -> wasmtime::Result<()> {
+            let Some(instance) = caller.data().instance else {
+                return Ok(());
+            };
+            let saved = checkpoint::save_checkpoint(&mut InstanceMemory { store: &mut caller, instance })
+                .map_err(|e| wasmtime::Error::msg(e.to_string()))?;
+            if let Some(data) = saved {
+                caller.data().checkpoints.send(data).ok();
+            }
+            Ok(())
+        })?;
+        Ok(linker)
+    }
+
+    fn write_guest(caller: &mut Caller<'_, Host>, ptr: i32, data: &[u8]) -> wasmtime::Result<()> {
+        let Some(Extern::Memory(memory)) = caller.get_export("memory") else {
+            return Err(wasmtime::Error::msg("module does not export `memory`"));
+        };
+        memory.write(caller, ptr as u32 as usize, data)?;
+        Ok(())
+    }
+
+    fn default_val(ty: &ValType) -> Val {
+        match ty {
+            ValType::I64 => Val::I64(0),
+            ValType::F32 => Val::F32(0),
+            ValType::F64 => Val::F64(0),
+            ValType::V128 => Val::V128(V128::from(0)),
+            _ => Val::I32(0),
+        }
+    }
+
+    fn run(
+        &self,
+        binary: &[u8],
+        libraries: &[(String, Vec<u8>)],
+        params: Vec<Type>,
+        env: &[(String, String)],
+        state: Option<&[u8]>,
+        checkpoints: mpsc::Sender<Vec<u8>>,
+    ) -> Result<(Vec<Type>, ExecutionStats), WasmtimeError> {
+        let started = Instant::now();
+        let module = Module::new(&self.engine, binary)?;
+
+        let host = Host {
+            environ: Environ::new(env),
+            instance: None,
+            checkpoints,
+        };
+        let mut store = Store::new(&self.engine, host);
+        store.set_fuel(self.fuel)?;
+        store.set_epoch_deadline(self.deadline_ticks);
+
+        // Libraries come in dependency order, so each one can import from those before it.
+        let mut linker = self.linker()?;
+        for (name, library) in libraries {
+            let library = Module::new(&self.engine, library)?;
+            let instance = linker.instantiate(&mut store, &library)?;
+            linker.instance(&mut store, name, instance)?;
+        }
+        let instance = linker.instantiate(&mut store, &module)?;
+        store.data_mut().instance = Some(instance);
+        let function = instance
+            .get_func(&mut store, "run")
+            .ok_or(WasmtimeError::MissingExport)?;
+
+        // A module that does not export `resume` starts over.
+        if let Some(state) = state {
+            checkpoint::resume_checkpoint(&mut InstanceMemory { store: &mut store, instance }, state)?;
+        }
+
+        let params = memory::lower_params(&mut InstanceMemory { store: &mut store, instance }, params)?;
+        let wasm_params = params.iter().filter_map(Self::to_val).collect::<Vec<_>>();
+        let mut wasm_results = function
+            .ty(&store)
+            .results()
+            .map(|ty| Self::default_val(&ty))
+            .collect::<Vec<_>>();
+
+        if let Err(error) = function.call(&mut store, &wasm_params, &mut wasm_results) {
+            return Err(match error.downcast_ref::<Trap>() {
+                Some(Trap::OutOfFuel) => WasmtimeError::OutOfFuel(self.fuel),
+                Some(Trap::Interrupt) => WasmtimeError::Interrupted(self.tick * self.deadline_ticks as u32),
+                _ => error.into(),
+            });
+        }
+
+        let results = wasm_results.iter().map(Self::from_val).collect();
+        let results = memory::lift_results(&mut InstanceMemory { store: &mut store, instance }, results)?;
+
+        // Linear memory only grows, so its final size is the peak.
+        let stats = ExecutionStats {
+            wall_time_us: started.elapsed().as_micros() as u64,
+            peak_memory: instance
+                .get_memory(&mut store, "memory")
+                .map_or(0, |memory| memory.data_size(&store) as u64),
+            instructions: Some(self.fuel - store.get_fuel()?),
+        };
+        Ok((results, stats))
+    }
+}
+
+impl Executor for WasmtimeExecutor {
+    type Error = WasmtimeError;
+
+    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        self.execute_with_stats(binary, params).map(|(result, _)| result)
+    }
+
+    fn execute_with_stats(&self, binary: &[u8], params: Vec<Type>) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.execute_with_env(binary, params, &[])
+    }
+
+    fn execute_with_env(
+        &self,
+        binary: &[u8],
+        params: Vec<Type>,
+        env: &[(String, String)],
+    ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.execute_linked(binary, &[], params, env)
+    }
+
+    fn execute_linked(
+        &self,
+        binary: &[u8],
+        libraries: &[(String, Vec<u8>)],
+        params: Vec<Type>,
+        env: &[(String, String)],
+    ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        self.execute_resumable(binary, libraries, params, env, None, &mut |_| {})
+    }
+
+    // The module runs on a scoped thread, so the checkpoints it saves reach `save` while it is
+    // still running.
+    fn execute_resumable(
+        &self,
+        binary: &[u8],
+        libraries: &[(String, Vec<u8>)],
+        params: Vec<Type>,
+        env: &[(String, String)],
+        state: Option<&[u8]>,
+        save: &mut dyn FnMut(Vec<u8>),
+    ) -> Result<(Vec<Type>, ExecutionStats), Self::Error> {
+        let (checkpoints, saved) = mpsc::channel();
+        thread::scope(|scope| {
+            let running = scope.spawn(move || self.run(binary, libraries, params, env, state, checkpoints));
+            for data in saved {
+                save(data);
+            }
+            running
+                .join()
+                .unwrap_or_else(|_| Err(WasmtimeError::Runtime("task thread panicked".into())))
+        })
+    }
+
+    fn flavor(&self) -> ExecutorFlavor {
+        ExecutorFlavor::Jit
+    }
+}
@@ -0,0 +1,159 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::os::fd::{FromRawFd, RawFd};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use program::*;
+use wasmi::{Engine, Linker, Module, Store, Val};
+
+// Both readings go through WASI, `clock_time_get` and `poll_oneoff` on a clock subscription.
+pub struct WasiClock;
+
+impl Clock for WasiClock {
+    fn timestamp(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+// Modules run in wasmi, a guest cannot hand its host a second wasm runtime.
+pub struct WasmiExecutor {
+    engine: Engine,
+}
+
+impl WasmiExecutor {
+    pub fn new() -> Self {
+        Self { engine: Engine::default() }
+    }
+}
+
+impl Executor for WasmiExecutor {
+    type Error = wasmi::Error;
+
+    fn execute(&self, binary: &[u8], params: Vec<Type>) -> Result<Vec<Type>, Self::Error> {
+        // Buffers need the guest's alloc export, see program::memory, which wasmi is not wired to.
+        let params = params
+            .into_iter()
+            .map(|param| match param {
+                Type::I32(v) => Ok(Val::I32(v)),
+                Type::I64(v) => Ok(Val::I64(v)),
+                Type::F32(v) => Ok(Val::F32(v.into())),
+                Type::F64(v) => Ok(Val::F64(v.into())),
+                other => Err(wasmi::Error::new(format!("unsupported param {:?}", other))),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let module = Module::new(&self.engine, binary)?;
+        let mut store = Store::new(&self.engine, ());
+        let instance = Linker::<()>::new(&self.engine)
+            .instantiate(&mut store, &module)?
+            .start(&mut store)?;
+        let run = instance
+            .get_func(&store, "run")
+            .ok_or_else(|| wasmi::Error::new("module exports no run function"))?;
+
+        let mut results = run
+            .ty(&store)
+            .results()
+            .iter()
+            .map(|ty| Val::default(*ty))
+            .collect::<Vec<_>>();
+        run.call(&mut store, &params, &mut results)?;
+
+        results
+            .into_iter()
+            .map(|result| match result {
+                Val::I32(v) => Ok(Type::I32(v)),
+                Val::I64(v) => Ok(Type::I64(v)),
+                Val::F32(v) => Ok(Type::F32(v.into())),
+                Val::F64(v) => Ok(Type::F64(v.into())),
+                other => Err(wasmi::Error::new(format!("unsupported result {:?}", other))),
+            })
+            .collect()
+    }
+}
+
+pub struct WasiTransport {
+    stream: TcpStream,
+}
+
+impl WasiTransport {
+    // Preview 1 has no `connect`, so a runtime that preopens the dispatcher connection passes its
+    // descriptor in `PROGRAM_SOCKET_FD`. Runtimes extending WASI with outbound sockets connect
+    // directly instead.
+    pub fn new(addr: &str) -> std::io::Result<Self> {
+        let stream = match std::env::var("PROGRAM_SOCKET_FD").ok().and_then(|fd| fd.parse::<RawFd>().ok()) {
+            Some(fd) => unsafe { TcpStream::from_raw_fd(fd) },
+            None => TcpStream::connect(addr)?,
+        };
+        stream.set_nonblocking(true)?;
+        Ok(Self { stream })
+    }
+}
+
+impl Transport for WasiTransport {
+    type Error = std::io::Error;
+
+    fn read<B>(&mut self, buf: &mut B) -> Result<usize, Self::Error>
+    where
+        B: BufMut + ?Sized,
+    {
+        let mut buffer = [0u8; 2048];
+        let bytes_read = match self.stream.read(&mut buffer) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => 0,
+            Err(e) => return Err(e),
+        };
+        buf.put_slice(&buffer[..bytes_read]);
+        Ok(bytes_read)
+    }
+
+    fn write<B>(&mut self, src: &mut B) -> Result<usize, Self::Error>
+    where
+        B: Buf + ?Sized,
+    {
+        match self.stream.write(src.chunk()) {
+            Ok(n) => Ok(n),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn main() {
+    let Config { host, dispatcher_port, .. } = Config::new();
+    let addr = format!("{}:{}", host, dispatcher_port);
+
+    env_logger::init();
+
+    let clock = WasiClock;
+    let transport = loop {
+        match WasiTransport::new(&addr) {
+            Ok(transport) => break transport,
+            Err(e) => {
+                log::error!("Connection failed: {}, retrying in 10 seconds...", e);
+                clock.sleep(Duration::from_secs(10));
+            }
+        }
+    };
+
+    // Edge runtimes tend to cap guest memory well below a desktop's.
+    let mut session = Session::new(transport, WasmiExecutor::new(), WasiClock, 1024 * 32);
+
+    // Reads cannot block on WASI either, so sleeps are capped to keep latency low.
+    loop {
+        match session.step() {
+            Ok(StepStatus::NeedsSleep(duration)) => clock.sleep(duration.min(Duration::from_millis(10))),
+            Ok(StepStatus::Failed) => log::warn!("Session step failed"),
+            Ok(StepStatus::Idle | StepStatus::Progress) => {}
+            Err(e) => log::error!("Session error: {}", e),
+        }
+    }
+}
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+use std::env;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
+
+use hecs::{Entity, World};
+use log::{info, warn};
+use protocol::{Checksum, ExecutorFlavor};
+use tokio::process::Command;
+use tokio::sync::Mutex;
+
+use crate::components::*;
+
+const COMPILE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CompileJob {
+    pub module: Entity,
+    pub name: String,
+    pub arch: String,
+}
+
+// Every (module, arch) pair an AOT capable session could run that has no artifact yet.
+pub fn pending_jobs(world: &World) -> Vec<CompileJob> {
+    let archs = world
+        .query::<&SessionInfo>()
+        .iter()
+        .filter(|(_, info)| info.executor == ExecutorFlavor::Aot && !info.arch.is_empty())
+        .map(|(_, info)| info.arch.clone())
+        .collect::<HashSet<_>>();
+
+    let mut jobs = Vec::new();
+    for (module, (definition, artifacts)) in world
+        .query::<(&Module, Option<&ModuleArtifacts>)>()
+        .without::<&NativeModule>()
+        .iter()
+    {
+        for arch in &archs {
+            let done = artifacts.is_some_and(|artifacts| {
+                artifacts.aot.contains_key(arch) || artifacts.failed.contains(arch)
+            });
+            if !done {
+                jobs.push(CompileJob {
+                    module,
+                    name: definition.name.clone(),
+                    arch: arch.clone(),
+                });
+            }
+        }
+    }
+    jobs
+}
+
+pub fn store_artifact(world: &mut World, job: &CompileJob, artifact: Option<Vec<u8>>) {
+    if world.get::<&ModuleArtifacts>(job.module).is_err()
+        && world.insert_one(job.module, ModuleArtifacts::default()).is_err()
+    {
+        return;
+    }
+
+    let mut artifacts = world.get::<&mut ModuleArtifacts>(job.module).unwrap();
+    match artifact {
+        Some(binary) => {
+            artifacts.aot.insert(job.arch.clone(), binary);
+        }
+        None => {
+            artifacts.failed.insert(job.arch.clone());
+        }
+    }
+}
+
+// Module names and archs come from clients, so neither ends up in the path. The process id keeps
+// servers sharing a temp dir apart.
+fn temp_stem(job: &CompileJob) -> String {
+    let mut checksum = Checksum::default();
+    checksum.update(job.name.as_bytes());
+    checksum.update(&[0]);
+    checksum.update(job.arch.as_bytes());
+    format!("{}-{:08x}", process::id(), checksum.value())
+}
+
+async fn compile(wamrc: &str, job: &CompileJob, binary: &[u8]) -> Result<Vec<u8>, String> {
+    let dir = env::temp_dir().join("prototype-aot");
+    tokio::fs::create_dir_all(&dir).await.map_err(|e| e.to_string())?;
+
+    let stem = temp_stem(job);
+    let input = dir.join(&stem).with_extension("wasm");
+    let output = dir.join(&stem).with_extension("aot");
+    tokio::fs::write(&input, binary).await.map_err(|e| e.to_string())?;
+
+    let status = Command::new(wamrc)
+        .arg(format!("--target={}", job.arch))
+        .arg("-o")
+        .arg(&output)
+        .arg(&input)
+        .status()
+        .await
+        .map_err(|e| format!("failed to spawn {}: {}", wamrc, e))?;
+
+    let artifact = match status.success() {
+        true => tokio::fs::read(&output).await.map_err(|e| e.to_string()),
+        false => Err(format!("{} exited with {}", wamrc, status)),
+    };
+    tokio::fs::remove_file(&input).await.ok();
+    tokio::fs::remove_file(&output).await.ok();
+    artifact
+}
+
+pub async fn run(world: &Arc<Mutex<World>>) {
+    let wamrc = env::var("WAMRC").unwrap_or_else(|_| "wamrc".into());
+
+    loop {
+        let jobs = {
+            let world = world.lock().await;
+            pending_jobs(&world)
+                .into_iter()
+                .filter_map(|job| {
+                    let binary = world.get::<&Module>(job.module).ok()?.binary.clone();
+                    Some((job, binary))
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // The world stays unlocked while wamrc runs, compiling a module can take seconds.
+        for (job, binary) in jobs {
+            let artifact = match compile(&wamrc, &job, &binary).await {
+                Ok(artifact) => {
+                    info!("Compiled module {} for {} ({} bytes)", job.name, job.arch, artifact.len());
+                    Some(artifact)
+                }
+                Err(e) => {
+                    warn!("AOT compilation of {} for {} failed, sending wasm: {}", job.name, job.arch, e);
+                    None
+                }
+            };
+            store_artifact(&mut *world.lock().await, &job, artifact);
+        }
+
+        tokio::time::sleep(COMPILE_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::SystemTime;
+
+    use protocol::CacheStats;
+
+    use super::*;
+
+    #[test]
+    fn test_pending_jobs() {
+        let mut world = World::new();
+        let module = world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        for (executor, arch) in [(ExecutorFlavor::Aot, "xtensa"), (ExecutorFlavor::Interpreter, "riscv32")] {
+            world.spawn((
+                Session {
+                    message_queue: VecDeque::new(),
+                    latency: Duration::default(),
+                    cache_stats: CacheStats::default(),
+                },
+                DeviceInventory::default(),
+                SessionInfo {
+                    device_addr: "0.0.0.0:0".parse().unwrap(),
+                    device_ram: 4096,
+                    executor,
+                    arch: arch.into(),
+                },
+                SessionHealth {
+                    retries: 0,
+                    status: SessionStatus::Connected,
+                    last_heartbeat: SystemTime::now(),
+                    protocol_errors: 0,
+                    last_protocol_error: None,
+                    sequence: None,
+                },
+            ));
+        }
+
+        let jobs = pending_jobs(&world);
+        assert_eq!(jobs, vec![CompileJob {
+            module,
+            name: "mock_module".into(),
+            arch: "xtensa".into(),
+        }]);
+
+        store_artifact(&mut world, &jobs[0], None);
+        assert!(pending_jobs(&world).is_empty());
+        assert!(world.get::<&ModuleArtifacts>(module).unwrap().failed.contains("xtensa"));
+    }
+
+    #[test]
+    fn test_temp_stem() {
+        let job = CompileJob {
+            module: Entity::DANGLING,
+            name: "../../etc/passwd.d".into(),
+            arch: "x86_64/..".into(),
+        };
+        let stem = temp_stem(&job);
+        assert!(stem.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'));
+        assert_ne!(stem, temp_stem(&CompileJob { arch: "xtensa".into(), ..job }));
+    }
+}
@@ -0,0 +1,324 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use hecs::Entity;
+use protocol::Telemetry;
+use serde::{Deserialize, Serialize};
+
+use super::TaskStatePhase;
+use crate::snapshot::WorldSnapshot;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServerMode {
+    #[default]
+    Running,
+    Draining,
+}
+
+// Position of this dispatcher within a sharded cluster, `peers` holds every
+// shard's dispatcher address in shard order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClusterShard {
+    pub index: usize,
+    pub peers: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShardChecked;
+
+// Singleton spawned once the dispatcher listener is bound, `last_tick` is refreshed
+// on every pass of the dispatch loop.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DispatcherStatus {
+    pub addr: SocketAddr,
+    pub last_tick: SystemTime,
+}
+
+// Singleton bounding how many sessions the dispatcher holds and how long a new connection may
+// take to send ClientReady. Connections past `max_sessions` are told to retry after `retry_after`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max_sessions: usize,
+    pub handshake_timeout: Duration,
+    pub retry_after: Duration,
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self {
+            max_sessions: 1024,
+            handshake_timeout: Duration::from_secs(10),
+            retry_after: Duration::from_secs(5),
+        }
+    }
+}
+
+// Singleton holding the tunables a `ServerBuilder` was given. `chunk_size` applies to the modules
+// the server loads itself and to uploads that leave it unset. A connected session silent for
+// `idle_timeout` is pinged, it turns Zombie once silent for `heartbeat_timeout` with the ping
+// unanswered. An executing task is requeued after `execution_timeout`. No metrics are sampled
+// while `metrics` is off, and the modules and tasks bundled by the task crate are not loaded while
+// `bundled_tasks` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ServerSettings {
+    pub chunk_size: u32,
+    pub idle_timeout: Duration,
+    pub heartbeat_timeout: Duration,
+    pub execution_timeout: Duration,
+    pub metrics: bool,
+    pub bundled_tasks: bool,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1024,
+            idle_timeout: Duration::from_secs(16),
+            heartbeat_timeout: Duration::from_secs(32),
+            execution_timeout: Duration::from_secs(60),
+            metrics: true,
+            bundled_tasks: true,
+        }
+    }
+}
+
+// A task executing `slowdown` times longer than predicted for its device class gets a copy on
+// another idle device, as long as live copies stay within `max_ratio` of the connected fleet.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpeculationPolicy {
+    pub enabled: bool,
+    pub slowdown: f64,
+    pub max_ratio: f64,
+}
+
+impl Default for SpeculationPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            slowdown: 3.0,
+            max_ratio: 0.1,
+        }
+    }
+}
+
+// Singleton naming the uploaded module every newly registered device runs once per calibration
+// kernel before other work, nothing is calibrated while it is absent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Calibration {
+    pub module: String,
+}
+
+// Singleton holding back queued tasks from assignment, either all of them or those of the
+// listed tenants. Tasks already on a device run to completion.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SchedulingPause {
+    pub all: bool,
+    pub tenants: HashSet<String>,
+}
+
+impl SchedulingPause {
+    pub fn holds(&self, tenant: &str) -> bool {
+        self.all || self.tenants.contains(tenant)
+    }
+}
+
+// Relative device shares per tenant, tenants without an entry weigh `default_weight`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FairSharePolicy {
+    pub weights: HashMap<String, u32>,
+    pub default_weight: u32,
+}
+
+impl FairSharePolicy {
+    pub fn weight(&self, tenant: &str) -> u32 {
+        self.weights.get(tenant).copied().unwrap_or(self.default_weight).max(1)
+    }
+}
+
+impl Default for FairSharePolicy {
+    fn default() -> Self {
+        Self {
+            weights: HashMap::new(),
+            default_weight: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NotificationEvent {
+    TaskCompleted,
+    // An attempt ended without a result and the task went back to the queue.
+    TaskFailed,
+    // Every device of a broadcast task reported back.
+    GroupCompleted,
+}
+
+impl NotificationEvent {
+    pub const ALL: [NotificationEvent; 3] = [Self::TaskCompleted, Self::TaskFailed, Self::GroupCompleted];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::TaskCompleted => "task.completed",
+            Self::TaskFailed => "task.failed",
+            Self::GroupCompleted => "group.completed",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|event| event.name() == name)
+    }
+}
+
+// Posted to `url` on each of `events`, the body rendered from `template`, see notifier::render.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Webhook {
+    pub url: String,
+    pub template: String,
+    pub events: HashSet<NotificationEvent>,
+}
+
+// Singleton listing the configured webhooks, nothing is posted while it is empty.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Webhooks {
+    pub hooks: Vec<Webhook>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSample {
+    pub session: Entity,
+    pub telemetry: Option<Telemetry>,
+    pub latency: Duration,
+    pub cache_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSample {
+    pub timestamp: SystemTime,
+    // Tasks completed since the previous sample.
+    pub completed: usize,
+    pub queued: usize,
+    pub sessions: Vec<SessionSample>,
+}
+
+// Singleton holding one sample every `interval`, samples older than `retention` are dropped.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsHistory {
+    pub retention: Duration,
+    pub interval: Duration,
+    pub samples: VecDeque<MetricsSample>,
+    pub completed_total: usize,
+}
+
+impl MetricsHistory {
+    pub fn new(retention: Duration, interval: Duration) -> Self {
+        Self {
+            retention,
+            interval,
+            samples: VecDeque::new(),
+            completed_total: 0,
+        }
+    }
+
+    pub fn due(&self, now: SystemTime) -> bool {
+        self.samples
+            .back()
+            .is_none_or(|last| now.duration_since(last.timestamp).unwrap_or_default() >= self.interval)
+    }
+
+    pub fn push(&mut self, sample: MetricsSample) {
+        let cutoff = sample.timestamp.checked_sub(self.retention);
+        while self
+            .samples
+            .front()
+            .zip(cutoff)
+            .is_some_and(|(oldest, cutoff)| oldest.timestamp < cutoff)
+        {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+}
+
+impl Default for MetricsHistory {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(6 * 60 * 60), Duration::from_secs(10))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SchedulerEventKind {
+    Queued,
+    Assigned { device: Entity, attempt: u32 },
+    Executing,
+    Completed,
+    // Despawned, whether canceled or cleaned up after completing.
+    Removed,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SchedulerEvent {
+    pub timestamp: SystemTime,
+    pub task: Entity,
+    pub kind: SchedulerEventKind,
+}
+
+// Singleton keeping a world snapshot every `interval` along with the scheduler events in between,
+// so the state at any moment after the oldest of the last `capacity` snapshots can be rebuilt.
+// Events older than that snapshot go with it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulerTimeline {
+    pub interval: Duration,
+    pub capacity: usize,
+    pub snapshots: VecDeque<WorldSnapshot>,
+    pub events: VecDeque<SchedulerEvent>,
+    // Phase, device and attempt of every task on the previous pass, events are the differences.
+    pub seen: HashMap<Entity, (TaskStatePhase, Option<Entity>, u32)>,
+}
+
+impl SchedulerTimeline {
+    pub fn new(interval: Duration, capacity: usize) -> Self {
+        Self {
+            interval,
+            capacity,
+            snapshots: VecDeque::new(),
+            events: VecDeque::new(),
+            seen: HashMap::new(),
+        }
+    }
+
+    pub fn due(&self, now: SystemTime) -> bool {
+        self.snapshots
+            .back()
+            .is_none_or(|last| now.duration_since(last.taken_at).unwrap_or_default() >= self.interval)
+    }
+
+    pub fn push(&mut self, snapshot: WorldSnapshot) {
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > self.capacity.max(1) {
+            self.snapshots.pop_front();
+        }
+        let oldest = self.snapshots.front().map(|snapshot| snapshot.taken_at);
+        while self.events.front().zip(oldest).is_some_and(|(event, oldest)| event.timestamp <= oldest) {
+            self.events.pop_front();
+        }
+    }
+
+    // The last snapshot taken by `at` and the events after it up to `at`, replaying them on the
+    // snapshot gives the scheduler state at that moment.
+    pub fn at(&self, at: SystemTime) -> Option<(&WorldSnapshot, Vec<&SchedulerEvent>)> {
+        let snapshot = self.snapshots.iter().rev().find(|snapshot| snapshot.taken_at <= at)?;
+        let events = self
+            .events
+            .iter()
+            .filter(|event| event.timestamp > snapshot.taken_at && event.timestamp <= at)
+            .collect();
+        Some((snapshot, events))
+    }
+}
+
+impl Default for SchedulerTimeline {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(10), 360)
+    }
+}
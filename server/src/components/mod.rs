@@ -1,7 +1,9 @@
+mod control;
 mod module;
 mod session;
 mod task;
 
+pub use control::*;
 pub use module::*;
 pub use session::*;
 pub use task::*;
@@ -1,25 +1,263 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
 use bitvec::prelude::BitVec;
+use bytes::Bytes;
 
 use hecs::Entity;
+use protocol::{Checksum, FirmwareInfo, ModuleInfo, Type};
+use serde::{Deserialize, Serialize};
+
+use super::{DeviceClass, TaskId, TaskMetrics};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ModuleTransferState {
     Pending,
     Requested,
     Transferring,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// Sending of one module to one device, an entity of its own so every task waiting for the
+// module on that device shares it. Chunks and acks travel under `task_id`, the id of the task
+// that started the transfer or a fresh one for prefetches and firmware updates.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ModuleTransfer {
+    pub task_id: TaskId,
     pub state: ModuleTransferState,
     pub acked_chunks: BitVec,
     pub session: Entity,
+    // A `Module`, a `Blob` for task inputs or a `Firmware` for firmware updates.
+    pub module: Entity,
+    // Architecture of the AOT artifact being sent, `None` sends the raw wasm binary.
+    pub arch: Option<String>,
+    // Chunks sent and not acked yet, keyed by the index of their first chunk.
+    pub in_flight: BTreeMap<u32, ChunkSpan>,
+}
+
+// Module pushed to several devices at once. Each chunk is encoded once under the shared
+// `task_id` and the same frame queued to every member, a `ModuleTransfer` marked with
+// `SharedTransfer` that keeps its own acks. Despawned once no member is left.
+#[derive(Debug, Clone)]
+pub struct TransferGroup {
+    pub module: Entity,
+    pub task_id: TaskId,
+    pub arch: Option<String>,
+    // Encoded ServerModule frame of every chunk, filled as chunks are first sent.
+    pub frames: Vec<Option<Bytes>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SharedTransfer {
+    pub group: Entity,
+}
+
+// One ServerModule message covering `chunks` consecutive chunks of the transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSpan {
+    pub chunks: u32,
+    pub sent: SystemTime,
+}
+
+// How many chunks a device gets per message, kept on the session. It starts at one and grows
+// while acks come back quickly, a slow ack halves it and a failed or lost one starts over, like
+// TCP slow start. Clients that cannot take merged chunks fail them and so stay at one.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ChunkSizing {
+    pub chunks: u32,
+    pub threshold: u32,
+    // Smoothed ack latency, for the inspector.
+    pub latency: Option<Duration>,
+}
+
+// Transfer of a module ahead of any task, the entity carries a `ModuleTransfer` but no `Task`
+// and is despawned once the device holds the module.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModulePrefetch {
+    pub module: Entity,
+}
+
+// Firmware image being rolled out to devices of `arch`, empty matches any. It is despawned
+// once no `FirmwareUpdate` refers to it anymore.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Firmware {
+    pub version: String,
+    pub binary: Vec<u8>,
+    pub chunk_size: u32,
+    pub arch: String,
+}
+
+// Update of a single device, the entity carries a `ModuleTransfer` pointing at the `Firmware`
+// and is despawned when the device acknowledges the image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FirmwareUpdate {
+    pub firmware: Entity,
+}
+
+// Task input held by the server so params can reference it as `Type::BlobRef` instead of
+// inlining it. Devices fetch it through a `ModuleTransfer` and cache it next to their modules.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Blob {
+    pub id: u64,
+    pub binary: Vec<u8>,
+    pub chunk_size: u32,
+}
+
+// Fetch of a blob `task` references, or a library its module links, before the task itself is
+// sent. The entity carries a `ModuleTransfer` pointing at the `Blob` or `Module` under the task's id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlobFetch {
+    pub task: Entity,
 }
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Module {
     pub name: String,
     pub binary: Vec<u8>,
+    // Libraries instantiated along with the module on the device, each after the ones it links.
     pub dependencies: Vec<Entity>,
     pub chunk_size: u32,
+    pub pinned: bool,
+}
+
+// Marks a module whose binary is a native cdylib instead of wasm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NativeModule;
+
+// Precompiled AOT binaries of a module keyed by target architecture.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModuleArtifacts {
+    pub aot: HashMap<String, Vec<u8>>,
+    pub failed: HashSet<String>,
+}
+
+// Historical execution cost of a module per device class.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModuleCost {
+    pub classes: HashMap<DeviceClass, CostEstimate>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CostEstimate {
+    pub samples: u32,
+    pub wall_time: Duration,
+    pub peak_memory: u64,
+}
+
+impl CostEstimate {
+    pub fn record(&mut self, metrics: &TaskMetrics) {
+        let total = self.wall_time * self.samples + metrics.wall_time;
+        self.samples += 1;
+        self.wall_time = total / self.samples;
+        self.peak_memory = self.peak_memory.max(metrics.peak_memory);
+    }
+}
+
+impl Default for ChunkSizing {
+    fn default() -> Self {
+        Self {
+            chunks: 1,
+            threshold: Self::MAX_CHUNKS,
+            latency: None,
+        }
+    }
+}
+
+impl ChunkSizing {
+    const MAX_CHUNKS: u32 = 64;
+    // Frame lengths are 16 bits, merged chunks stay well below that.
+    const MAX_BYTES: u64 = 32 * 1024;
+    const TARGET_LATENCY: Duration = Duration::from_millis(250);
+
+    // `None` for a chunk the device failed or that was lost.
+    pub fn record(&mut self, latency: Option<Duration>) {
+        let Some(latency) = latency else {
+            self.threshold = (self.chunks / 2).max(1);
+            self.chunks = 1;
+            return;
+        };
+        self.latency = Some(self.latency.map_or(latency, |smoothed| (smoothed * 7 + latency) / 8));
+        if latency > Self::TARGET_LATENCY {
+            self.threshold = (self.chunks / 2).max(1);
+            self.chunks = self.threshold;
+        } else if self.chunks < self.threshold {
+            self.chunks = (self.chunks * 2).min(Self::MAX_CHUNKS);
+        } else {
+            self.chunks = (self.chunks + 1).min(Self::MAX_CHUNKS);
+        }
+    }
+
+    // Chunks of `chunk_size` bytes merged into one message, a device gets at most a sixteenth of
+    // its memory at once.
+    pub fn span(&self, chunk_size: u32, device_ram: u64) -> u32 {
+        let max_bytes = Self::MAX_BYTES.min(device_ram / 16);
+        let fitting = (max_bytes / chunk_size.max(1) as u64).max(1);
+        self.chunks.min(fitting as u32).max(1)
+    }
+}
+
+impl Module {
+    pub fn payload<'a>(&'a self, artifacts: Option<&'a ModuleArtifacts>, arch: Option<&str>) -> &'a [u8] {
+        arch.and_then(|arch| artifacts?.aot.get(arch))
+            .map_or(&self.binary, |binary| binary)
+    }
+
+    // Covers the name and the binary, a module replaced under the same name hashes differently.
+    pub fn hash(&self) -> u32 {
+        let mut checksum = Checksum::default();
+        checksum.update(self.name.as_bytes());
+        checksum.update(&self.binary);
+        checksum.value()
+    }
+
+    // Announcement of a payload of `size` bytes cut into this module's chunks.
+    pub fn info(&self, size: usize) -> ModuleInfo {
+        ModuleInfo {
+            name: self.name.clone(),
+            size: size as u64,
+            chunk_size: self.chunk_size,
+            total_chunks: size.div_ceil(self.chunk_size as usize) as u32,
+            pinned: self.pinned,
+        }
+    }
+}
+
+impl Firmware {
+    pub fn info(&self) -> FirmwareInfo {
+        FirmwareInfo {
+            version: self.version.clone(),
+            size: self.binary.len() as u64,
+            chunk_size: self.chunk_size,
+            total_chunks: self.binary.len().div_ceil(self.chunk_size as usize) as u32,
+            checksum: Checksum::of(&self.binary),
+        }
+    }
+}
+
+impl Blob {
+    // Keys the device inventory, blobs of equal content are still cached under their own names.
+    pub fn hash(&self) -> u32 {
+        let mut checksum = Checksum::default();
+        checksum.update(protocol::blob_name(self.id).as_bytes());
+        checksum.update(&self.binary);
+        checksum.value()
+    }
+
+    // What the device checks the fetched content against, unlike `hash` it ignores the id.
+    pub fn checksum(&self) -> u32 {
+        Checksum::of(&self.binary)
+    }
+
+    pub fn reference(&self) -> Type {
+        Type::BlobRef(self.id, self.binary.len() as u64, self.checksum())
+    }
+
+    pub fn info(&self) -> ModuleInfo {
+        ModuleInfo {
+            name: protocol::blob_name(self.id),
+            size: self.binary.len() as u64,
+            chunk_size: self.chunk_size,
+            total_chunks: self.binary.len().div_ceil(self.chunk_size as usize) as u32,
+            pinned: false,
+        }
+    }
 }
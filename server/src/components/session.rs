@@ -1,22 +1,57 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, RandomState};
 use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use bytes::BytesMut;
-use hecs::Entity;
-use protocol::Message;
+use bytes::{Bytes, BytesMut};
+use protocol::{CacheStats, ExecutorFlavor, LogLevel, SequenceStats, SequenceTracker, ServerMessage, Telemetry, NATIVE_LABEL};
+use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::Mutex;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SessionHealth {
     pub retries: u8,
     pub status: SessionStatus,
     pub last_heartbeat: SystemTime,
+    // Frames refused in either direction, see Message::ProtocolError.
+    pub protocol_errors: u32,
+    pub last_protocol_error: Option<String>,
+    // How the device's frames arrived on the current connection, `None` unless it numbers them.
+    pub sequence: Option<SequenceStats>,
+}
+
+impl SessionHealth {
+    pub fn record_protocol_error(&mut self, error: String) {
+        self.protocol_errors += 1;
+        self.last_protocol_error = Some(error);
+    }
+}
+
+// Held by a new connection until it sends ClientReady, tasks skip it meanwhile and it is dropped
+// once `deadline` passes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionHandshake {
+    pub deadline: SystemTime,
+}
+
+// Set by an operator on a misbehaving device. The session keeps its connection and finishes what it
+// is running, but is handed no new tasks, prefetches or firmware until the quarantine is lifted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionQuarantine {
+    pub since: SystemTime,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+// Last ServerPing sent to the session, answered once the session's `last_heartbeat` passes `sent`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionPing {
+    pub nonce: u32,
+    pub sent: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SessionStatus {
     Connected,
     Occupied,
@@ -32,17 +67,212 @@ where
     pub inner: Arc<Mutex<T>>,
     pub incoming: BytesMut,
     pub outgoing: BytesMut,
+    // Frames from the device that carry sequence numbers are checked here, and from the first
+    // one on frames to it are numbered from `next_sequence`.
+    pub sequence: SequenceTracker,
+    pub next_sequence: Option<u32>,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct SessionInfo {
     pub device_addr: SocketAddr,
     pub device_ram: u64,
+    pub executor: ExecutorFlavor,
+    // Target architecture used to pick precompiled AOT artifacts, empty when unknown.
+    pub arch: String,
+}
+
+impl SessionInfo {
+    pub fn class(&self) -> DeviceClass {
+        DeviceClass {
+            executor: self.executor,
+            arch: self.arch.clone(),
+        }
+    }
+}
+
+// Work per second a device managed in each calibration kernel, see CalibrationKernel. Spawned empty
+// when calibration starts, kernels that failed leave their score unset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeviceBenchmark {
+    pub integer: Option<f64>,
+    pub float: Option<f64>,
+    pub memory: Option<f64>,
+}
+
+impl DeviceBenchmark {
+    // Geometric mean of the three scores, only comparable between devices that ran the same module.
+    pub fn score(&self) -> Option<f64> {
+        Some((self.integer? * self.float? * self.memory?).cbrt())
+    }
+}
+
+// Devices sharing a runtime and architecture are expected to perform alike.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct DeviceClass {
+    pub executor: ExecutorFlavor,
+    pub arch: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    Throttle,
+    Disconnect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionRateLimit {
+    pub max_messages: u32,
+    pub max_bytes: u64,
+    pub window: Duration,
+    pub action: RateLimitAction,
+    pub window_start: SystemTime,
+    pub messages: u32,
+    pub bytes: u64,
+}
+
+impl SessionRateLimit {
+    pub fn new(max_messages: u32, max_bytes: u64, window: Duration, action: RateLimitAction) -> Self {
+        Self {
+            max_messages,
+            max_bytes,
+            window,
+            action,
+            window_start: SystemTime::now(),
+            messages: 0,
+            bytes: 0,
+        }
+    }
+
+    pub fn refresh(&mut self, now: SystemTime) {
+        if now.duration_since(self.window_start).unwrap_or_default() >= self.window {
+            self.window_start = now;
+            self.messages = 0;
+            self.bytes = 0;
+        }
+    }
+
+    pub fn exceeded(&self) -> bool {
+        self.messages > self.max_messages || self.bytes > self.max_bytes
+    }
+}
+
+impl Default for SessionRateLimit {
+    fn default() -> Self {
+        Self::new(256, 64 * 1024, Duration::from_secs(1), RateLimitAction::Throttle)
+    }
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SessionLabels {
+    pub labels: HashSet<String>,
+}
+
+impl SessionLabels {
+    // Whether the device loads native cdylib modules, see protocol::NATIVE_LABEL.
+    pub fn native(&self) -> bool {
+        self.labels.contains(NATIVE_LABEL)
+    }
+}
+
+// Latest telemetry of a device, absent until it sends ClientTelemetry.
 #[derive(Debug, Clone, PartialEq)]
+pub struct SessionTelemetry {
+    pub telemetry: Telemetry,
+    pub received: SystemTime,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogEntry {
+    pub level: LogLevel,
+    pub module: String,
+    pub message: String,
+    // Device clock in nanoseconds since the UNIX epoch.
+    pub timestamp: u64,
+}
+
+// Most recent log lines forwarded by a device, the oldest are dropped first.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionLogs {
+    pub entries: VecDeque<LogEntry>,
+}
+
+impl SessionLogs {
+    pub const CAPACITY: usize = 256;
+
+    pub fn push(&mut self, entry: LogEntry) {
+        if self.entries.len() == Self::CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+// Issued once a device completes its handshake. A device reconnecting with the token gets its
+// new connection attached to this session instead of starting over.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionResume {
+    pub token: u64,
+}
+
+impl SessionResume {
+    pub fn issue() -> Self {
+        // RandomState is seeded from the OS, tokens cannot be guessed from one another.
+        static ISSUED: AtomicU64 = AtomicU64::new(0);
+        let issued = ISSUED.fetch_add(1, Ordering::Relaxed);
+        Self {
+            token: RandomState::new().hash_one(issued),
+        }
+    }
+}
+
+// Modules a device holds, keyed by `Module::hash` so a module replaced under the same name no
+// longer counts as cached. It is rebuilt from ClientReady and module list acks and grows as
+// transfers complete.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DeviceInventory {
+    modules: HashMap<u32, String>,
+}
+
+impl DeviceInventory {
+    pub fn insert(&mut self, hash: u32, name: &str) {
+        self.modules.insert(hash, name.to_string());
+    }
+
+    pub fn remove(&mut self, hash: u32) -> bool {
+        self.modules.remove(&hash).is_some()
+    }
+
+    pub fn contains(&self, hash: u32) -> bool {
+        self.modules.contains_key(&hash)
+    }
+
+    pub fn clear(&mut self) {
+        self.modules.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.modules.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modules.is_empty()
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.modules.values().map(String::as_str)
+    }
+}
+
+// The connection itself lives in `SessionStream` and is left out of snapshots.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Session {
-    pub message_queue: VecDeque<Message>,
-    pub modules: HashSet<Entity>,
+    pub message_queue: VecDeque<ServerMessage>,
     pub latency: Duration,
+    pub cache_stats: CacheStats,
 }
+
+// Frames encoded once and queued to several sessions, see `TransferGroup`. They go out after
+// the session's `message_queue`.
+#[derive(Debug, Clone, Default)]
+pub struct SharedFrames(pub VecDeque<Bytes>);
@@ -1,31 +1,283 @@
-use std::time::SystemTime;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use protocol::Type;
+use chrono::{DateTime, Utc};
+
+pub use protocol::TaskId;
+use protocol::{ExecutionStats, Type};
 
 use hecs::Entity;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+use super::{DeviceClass, SessionLabels};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum TaskStatePhase {
     Queued,
     Distributing,
     Executing {
+        started: SystemTime,
         deadline: SystemTime,
     },
     Completed,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct TaskState {
     pub phase: TaskStatePhase,
     pub assigned_device: Option<Entity>,
+    // Per-session results of a broadcast task, keyed by the session entity.
+    pub results: HashMap<Entity, Vec<Type>>,
+    // Latest ClientProgress from the assigned device, cleared whenever the task is assigned.
+    pub progress: Option<TaskProgress>,
+    // Transfer the task waits on before it can start, kept once done and cleared on assignment.
+    pub transfer: Option<TransferProgress>,
+    // Bumped on every assignment, results are only taken for the current attempt.
+    pub attempt: u32,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+// A task whose `updated` keeps moving is slow rather than hung.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TaskProgress {
+    pub percent: u8,
+    pub stage: String,
+    pub updated: SystemTime,
+}
+
+// Latest state the task's module saved through ClientCheckpoint, sent along when the task is
+// reassigned so the next device resumes from it. Dropped once the task completes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskCheckpoint {
+    pub attempt: u32,
+    pub data: Vec<u8>,
+    pub saved: SystemTime,
+}
+
+// Derived from the acks of `transfer` by `TaskSystem::track_transfers`, `updated` is the last
+// time an ack arrived so a stalled transfer stands out.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferProgress {
+    pub transfer: Entity,
+    pub chunks_acked: u32,
+    pub total_chunks: u32,
+    pub bytes_acked: u64,
+    pub started: SystemTime,
+    pub updated: SystemTime,
+    pub bytes_per_sec: f64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaskKind {
+    #[default]
+    Single,
+    Broadcast,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Task {
     pub name: String,
     pub params: Vec<Type>,
+    // Environment variables the module sees, sent along with the params.
+    pub env: Vec<(String, String)>,
     pub result: Vec<Type>,
     pub created_at: SystemTime,
     pub require_module: Entity,
     pub priority: u8,
+    pub kind: TaskKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskOrigin {
+    pub session: Entity,
+}
+
+// Tenant the task is accounted to for fair sharing, tasks without one share the default tenant.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TaskOwner {
+    pub tenant: String,
+}
+
+// Labels a session must advertise before the task may be routed to it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TaskSelector {
+    pub labels: Vec<String>,
+}
+
+impl TaskSelector {
+    pub fn matches(&self, labels: &SessionLabels) -> bool {
+        self.labels.iter().all(|label| labels.labels.contains(label))
+    }
+}
+
+// Tasks submitted as one job, such as the slices of a sweep. The counts are kept by
+// `TaskSystem::track_groups`, a member despawned before it completed counts as failed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskGroup {
+    pub name: String,
+    pub total: u32,
+    pub completed: u32,
+    pub failed: u32,
+    // Settled members plus the progress running members reported, out of 100.
+    pub percent: u8,
+    pub created_at: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskGroupStatus {
+    Running,
+    Completed,
+    Failed,
+}
+
+impl TaskGroup {
+    pub fn status(&self) -> TaskGroupStatus {
+        match (self.completed + self.failed < self.total, self.failed) {
+            (true, _) => TaskGroupStatus::Running,
+            (false, 0) => TaskGroupStatus::Completed,
+            (false, _) => TaskGroupStatus::Failed,
+        }
+    }
+}
+
+// Group the task belongs to, `settled` once the group counted the task as completed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskGroupMember {
+    pub group: Entity,
+    pub settled: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BroadcastTarget {
+    pub parent: Entity,
+    pub session: Entity,
+}
+
+// Left by an operator reassigning the task to one device, it waits for that device to be free
+// instead of taking any.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TaskTarget {
+    pub session: Entity,
+}
+
+// Workload a calibration task runs, passed to the calibration module as its only I32 parameter.
+// The module answers with the units of work it did, the device's wall time turns that into a score.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum CalibrationKernel {
+    Integer,
+    Float,
+    Memory,
+}
+
+impl CalibrationKernel {
+    pub const ALL: [Self; 3] = [Self::Integer, Self::Float, Self::Memory];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CalibrationTask {
+    pub kernel: CalibrationKernel,
+}
+
+// Background work that only takes devices nothing else wants, cancelled and requeued as soon as a
+// real task needs its device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FillerTask;
+
+// Lets a task take a device from a running task of lower priority when every device that fits it
+// is busy. The task taken off is requeued with its params and starts over.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreemptiveTask;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schedule {
+    Every(Duration),
+    Cron(Box<cron::Schedule>),
+}
+
+impl Schedule {
+    // Accepts the classic five field form as well as the six and seven field forms with seconds.
+    pub fn cron(expression: &str) -> Result<Self, cron::error::Error> {
+        let expression = match expression.split_whitespace().count() {
+            5 => format!("0 {}", expression),
+            _ => expression.to_string(),
+        };
+        cron::Schedule::from_str(&expression).map(|schedule| Self::Cron(Box::new(schedule)))
+    }
+
+    pub fn next_after(&self, now: SystemTime) -> Option<SystemTime> {
+        match self {
+            Self::Every(interval) => Some(now + *interval),
+            Self::Cron(schedule) => schedule
+                .after(&DateTime::<Utc>::from(now))
+                .next()
+                .map(SystemTime::from),
+        }
+    }
+}
+
+// Template re-enqueued as a fresh task whenever `next_run` passes. The template entity carries
+// a `Task` but no `TaskState`, so it is never scheduled itself.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecurringTask {
+    pub schedule: Schedule,
+    pub next_run: SystemTime,
+    pub last_task: Option<Entity>,
+    // Whether the module was already pushed to idle devices ahead of `next_run`.
+    pub prefetched: bool,
+}
+
+impl RecurringTask {
+    pub fn new(schedule: Schedule, now: SystemTime) -> Option<Self> {
+        Some(Self {
+            next_run: schedule.next_after(now)?,
+            schedule,
+            last_task: None,
+            prefetched: false,
+        })
+    }
+}
+
+// Duplicate of a straggling task, kept off `avoid`, the device still running the original.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpeculativeCopy {
+    pub original: Entity,
+    pub avoid: Entity,
+}
+
+// Execution cost reported by the device that completed the task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskMetrics {
+    pub device: Entity,
+    pub device_class: DeviceClass,
+    pub wall_time: Duration,
+    pub peak_memory: u64,
+    pub instructions: Option<u64>,
+}
+
+impl TaskMetrics {
+    pub fn new(device: Entity, device_class: DeviceClass, stats: &ExecutionStats) -> Self {
+        Self {
+            device,
+            device_class,
+            wall_time: Duration::from_micros(stats.wall_time_us),
+            peak_memory: stats.peak_memory,
+            instructions: stats.instructions,
+        }
+    }
+}
+
+pub fn next_task_id() -> TaskId {
+    // Seeded from wall-clock time so ids keep increasing across server restarts.
+    static NEXT_TASK_ID: OnceLock<AtomicU64> = OnceLock::new();
+
+    let next = NEXT_TASK_ID.get_or_init(|| {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64;
+        AtomicU64::new(seed)
+    });
+    TaskId(next.fetch_add(1, Ordering::Relaxed))
 }
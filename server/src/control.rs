@@ -0,0 +1,805 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hecs::{Entity, EntityBuilder, Or, World};
+use log::info;
+use protocol::Type;
+use tokio::sync::{mpsc, Mutex};
+use tokio_stream::wrappers::ReceiverStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::components::*;
+use crate::error::{Context, Error};
+use crate::export::{export, ExportFormat};
+use crate::results::{ResultQuery, ResultStore};
+use crate::systems::{LifecycleSystem, TaskSystem};
+
+#[allow(clippy::all)]
+mod pb {
+    tonic::include_proto!("control");
+}
+
+use pb::control_server::{Control, ControlServer};
+use pb::value::Kind;
+
+const EVENT_INTERVAL: Duration = Duration::from_millis(500);
+
+impl From<Type> for pb::Value {
+    fn from(value: Type) -> Self {
+        let kind = match value {
+            Type::Void => Kind::Void(true),
+            Type::I32(v) => Kind::I32(v),
+            Type::I64(v) => Kind::I64(v),
+            Type::F32(v) => Kind::F32(v),
+            Type::F64(v) => Kind::F64(v),
+            Type::V128(v) => Kind::V128(v.to_be_bytes().to_vec()),
+            Type::Bytes(v) => Kind::Bytes(v),
+            Type::BlobRef(id, size, hash) => Kind::BlobRef(pb::BlobRef { id, size, hash }),
+        };
+        Self { kind: Some(kind) }
+    }
+}
+
+impl TryFrom<pb::Value> for Type {
+    type Error = Status;
+
+    fn try_from(value: pb::Value) -> Result<Self, Status> {
+        match value.kind {
+            Some(Kind::Void(_)) => Ok(Type::Void),
+            Some(Kind::I32(v)) => Ok(Type::I32(v)),
+            Some(Kind::I64(v)) => Ok(Type::I64(v)),
+            Some(Kind::F32(v)) => Ok(Type::F32(v)),
+            Some(Kind::F64(v)) => Ok(Type::F64(v)),
+            Some(Kind::V128(v)) => v
+                .try_into()
+                .map(|bytes| Type::V128(i128::from_be_bytes(bytes)))
+                .map_err(|_| Status::invalid_argument("v128 value must be 16 bytes")),
+            Some(Kind::Bytes(v)) => Ok(Type::Bytes(v)),
+            Some(Kind::BlobRef(blob)) => Ok(Type::BlobRef(blob.id, blob.size, blob.hash)),
+            None => Err(Status::invalid_argument("missing value")),
+        }
+    }
+}
+
+impl From<&TaskStatePhase> for pb::TaskPhase {
+    fn from(phase: &TaskStatePhase) -> Self {
+        match phase {
+            TaskStatePhase::Queued => pb::TaskPhase::Queued,
+            TaskStatePhase::Distributing => pb::TaskPhase::Distributing,
+            TaskStatePhase::Executing { .. } => pb::TaskPhase::Executing,
+            TaskStatePhase::Completed => pb::TaskPhase::Completed,
+        }
+    }
+}
+
+pub struct ControlService {
+    world: Arc<Mutex<World>>,
+}
+
+impl ControlService {
+    pub fn new(world: Arc<Mutex<World>>) -> Self {
+        Self { world }
+    }
+
+    // Components shared by one-off and recurring tasks, the caller adds state or a schedule.
+    #[allow(clippy::result_large_err)]
+    fn task_builder(world: &World, request: pb::SubmitTaskRequest, name: String) -> Result<EntityBuilder, Status> {
+        let params = request
+            .params
+            .into_iter()
+            .map(|param| Self::resolve_blob(world, Type::try_from(param)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        let env = request.env.into_iter().map(|var| (var.key, var.value)).collect::<Vec<_>>();
+        if env.iter().any(|(key, _)| key.is_empty() || key.contains('=')) {
+            return Err(Status::invalid_argument("environment keys must be non-empty and contain no '='"));
+        }
+        let priority = u8::try_from(request.priority)
+            .map_err(|_| Status::invalid_argument("priority must fit in u8"))?;
+        if request.filler && request.broadcast {
+            return Err(Status::invalid_argument("broadcast tasks cannot be fillers"));
+        }
+        if request.preempt && (request.filler || request.broadcast) {
+            return Err(Status::invalid_argument("only single tasks that are not fillers can preempt"));
+        }
+        let module_entity = world
+            .query::<&Module>()
+            .iter()
+            .find(|(_, module)| module.name == request.module)
+            .map(|(entity, _)| entity)
+            .ok_or_else(|| Status::not_found(format!("unknown module {}", request.module)))?;
+
+        let mut builder = EntityBuilder::new();
+        builder.add(Task {
+            name,
+            params,
+            env,
+            result: vec![],
+            created_at: SystemTime::now(),
+            require_module: module_entity,
+            priority,
+            kind: if request.broadcast { TaskKind::Broadcast } else { TaskKind::Single },
+        });
+        if !request.labels.is_empty() {
+            builder.add(TaskSelector { labels: request.labels });
+        }
+        if !request.tenant.is_empty() {
+            builder.add(TaskOwner { tenant: request.tenant });
+        }
+        if request.filler {
+            builder.add(FillerTask);
+        }
+        if request.preempt {
+            builder.add(PreemptiveTask);
+        }
+        Ok(builder)
+    }
+
+    // Libraries bring the ones they link themselves, which come first so each library is
+    // instantiated after everything it imports from. A library linking `name` would be a cycle.
+    #[allow(clippy::result_large_err)]
+    fn resolve_libraries(world: &World, name: &str, libraries: &[String]) -> Result<Vec<Entity>, Status> {
+        let mut dependencies = Vec::new();
+        for library in libraries {
+            let mut query = world.query::<&Module>();
+            let (entity, module) = query
+                .iter()
+                .find(|(_, module)| &module.name == library)
+                .ok_or_else(|| Status::not_found(format!("unknown library {}", library)))?;
+            let linked = module.dependencies.iter().copied().chain([entity]);
+            for dependency in linked {
+                let Ok(module) = world.get::<&Module>(dependency) else {
+                    continue;
+                };
+                if module.name == name || world.satisfies::<&NativeModule>(dependency).unwrap_or(false) {
+                    return Err(Status::invalid_argument(format!("{} cannot be linked into {}", module.name, name)));
+                }
+                if !dependencies.contains(&dependency) {
+                    dependencies.push(dependency);
+                }
+            }
+        }
+        Ok(dependencies)
+    }
+
+    // Blob references must name a stored blob, one giving neither size nor hash is completed.
+    #[allow(clippy::result_large_err)]
+    fn resolve_blob(world: &World, param: Type) -> Result<Type, Status> {
+        let Type::BlobRef(id, size, hash) = param else {
+            return Ok(param);
+        };
+        let stored = TaskSystem::blob(world, id)
+            .and_then(|entity| world.get::<&Blob>(entity).ok().map(|blob| blob.reference()))
+            .ok_or_else(|| Status::not_found(format!("unknown blob {}", id)))?;
+        match stored {
+            Type::BlobRef(_, stored_size, stored_hash)
+                if (size, hash) == (0, 0) || (size, hash) == (stored_size, stored_hash) =>
+            {
+                Ok(stored)
+            }
+            _ => Err(Status::invalid_argument(format!("blob {} does not match the stored size and hash", id))),
+        }
+    }
+
+    fn task_reply(world: &World, task_id: TaskId) -> Option<pb::TaskReply> {
+        let mut query = world.query::<(&Task, &TaskState, &TaskId)>();
+        let (_, (task, state, _)) = query.iter().find(|(_, (_, _, id))| **id == task_id)?;
+        let module = world.get::<&Module>(task.require_module).ok()?;
+
+        Some(pb::TaskReply {
+            task_id: task_id.0,
+            name: task.name.clone(),
+            module: module.name.clone(),
+            phase: pb::TaskPhase::from(&state.phase) as i32,
+            result: task.result.iter().cloned().map(pb::Value::from).collect(),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl Control for ControlService {
+    async fn submit_task(
+        &self,
+        request: Request<pb::SubmitTaskRequest>,
+    ) -> Result<Response<pb::TaskReply>, Status> {
+        let request = request.into_inner();
+        let (module, group) = (request.module.clone(), request.group.clone());
+
+        let mut world = self.world.lock().await;
+        let task_id = next_task_id();
+        let mut builder = Self::task_builder(&world, request, format!("{}_{}", module, task_id))?;
+        if !group.is_empty() {
+            builder.add(TaskSystem::join_group(&mut world, &group));
+        }
+        world.spawn(
+            builder
+                .add(TaskState {
+                    phase: TaskStatePhase::Queued,
+                    assigned_device: None,
+                    results: HashMap::new(),
+                    progress: None,
+                    transfer: None,
+                    attempt: 0,
+                })
+                .add(task_id)
+                .build(),
+        );
+        info!("Control API submitted task {} for module {}", task_id, module);
+
+        Self::task_reply(&world, task_id)
+            .map(Response::new)
+            .ok_or_else(|| Status::internal("submitted task vanished"))
+    }
+
+    async fn schedule_task(
+        &self,
+        request: Request<pb::ScheduleTaskRequest>,
+    ) -> Result<Response<pb::ScheduleReply>, Status> {
+        let request = request.into_inner();
+        let task = request.task.ok_or_else(|| Status::invalid_argument("missing task"))?;
+        if !task.group.is_empty() {
+            return Err(Status::invalid_argument("recurring tasks cannot join a group"));
+        }
+        let schedule = match (request.interval_secs, request.cron.as_str()) {
+            (0, "") => return Err(Status::invalid_argument("either interval_secs or cron is required")),
+            (interval, "") => Schedule::Every(Duration::from_secs(interval)),
+            (0, expression) => Schedule::cron(expression)
+                .map_err(|e| Status::invalid_argument(format!("invalid cron expression: {}", e)))?,
+            _ => return Err(Status::invalid_argument("interval_secs and cron are exclusive")),
+        };
+        let recurring = RecurringTask::new(schedule, SystemTime::now())
+            .ok_or_else(|| Status::invalid_argument("schedule never fires"))?;
+        let next_run_secs = recurring
+            .next_run
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let name = match request.name.is_empty() {
+            true => task.module.clone(),
+            false => request.name,
+        };
+
+        let mut world = self.world.lock().await;
+        let mut builder = Self::task_builder(&world, task, name.clone())?;
+        world.spawn(builder.add(recurring).build());
+        info!("Control API scheduled recurring task {}", name);
+
+        Ok(Response::new(pb::ScheduleReply { name, next_run_secs }))
+    }
+
+    async fn get_task(
+        &self,
+        request: Request<pb::GetTaskRequest>,
+    ) -> Result<Response<pb::TaskReply>, Status> {
+        let task_id = TaskId(request.into_inner().task_id);
+        let world = self.world.lock().await;
+        Self::task_reply(&world, task_id)
+            .map(Response::new)
+            .ok_or_else(|| Status::not_found(format!("unknown task {}", task_id)))
+    }
+
+    async fn list_tasks(
+        &self,
+        _: Request<pb::ListTasksRequest>,
+    ) -> Result<Response<pb::ListTasksReply>, Status> {
+        let world = self.world.lock().await;
+        let mut task_ids = world
+            .query::<&TaskId>()
+            .without::<Or<&BroadcastTarget, &SpeculativeCopy>>()
+            .iter()
+            .map(|(_, task_id)| *task_id)
+            .collect::<Vec<_>>();
+        task_ids.sort();
+
+        let tasks = task_ids
+            .into_iter()
+            .filter_map(|task_id| Self::task_reply(&world, task_id))
+            .collect();
+        Ok(Response::new(pb::ListTasksReply { tasks }))
+    }
+
+    async fn upload_module(
+        &self,
+        request: Request<pb::UploadModuleRequest>,
+    ) -> Result<Response<pb::ModuleReply>, Status> {
+        let request = request.into_inner();
+        if request.name.is_empty() || request.binary.is_empty() {
+            return Err(Status::invalid_argument("module name and binary are required"));
+        }
+        let size = request.binary.len() as u64;
+        if request.native && !request.libraries.is_empty() {
+            return Err(Status::invalid_argument("native modules cannot link libraries"));
+        }
+
+        let mut world = self.world.lock().await;
+        let chunk_size = match request.chunk_size {
+            0 => LifecycleSystem::settings(&world).chunk_size,
+            chunk_size => chunk_size,
+        };
+        let dependencies = Self::resolve_libraries(&world, &request.name, &request.libraries)?;
+        let existing = world
+            .query_mut::<&mut Module>()
+            .into_iter()
+            .find(|(_, module)| module.name == request.name);
+        let (entity, replaced) = match existing {
+            Some((entity, module)) => {
+                module.binary = request.binary;
+                module.dependencies = dependencies;
+                module.chunk_size = chunk_size;
+                // Artifacts were compiled from the previous binary.
+                world.remove_one::<ModuleArtifacts>(entity).ok();
+                (entity, true)
+            }
+            None => {
+                let entity = world.spawn((Module {
+                    name: request.name.clone(),
+                    binary: request.binary,
+                    dependencies,
+                    chunk_size,
+                    pinned: false,
+                },));
+                (entity, false)
+            }
+        };
+        if request.native {
+            world.insert_one(entity, NativeModule).ok();
+        } else {
+            world.remove_one::<NativeModule>(entity).ok();
+        }
+        info!("Control API uploaded module {} ({} bytes)", request.name, size);
+
+        Ok(Response::new(pb::ModuleReply {
+            name: request.name,
+            size,
+            replaced,
+        }))
+    }
+
+    async fn pin_module(
+        &self,
+        request: Request<pb::PinModuleRequest>,
+    ) -> Result<Response<pb::PinReply>, Status> {
+        let request = request.into_inner();
+
+        let mut world = self.world.lock().await;
+        let module_entity = world
+            .query::<&Module>()
+            .iter()
+            .find(|(_, module)| module.name == request.name)
+            .map(|(entity, _)| entity)
+            .ok_or_else(|| Status::not_found(format!("unknown module {}", request.name)))?;
+        match request.pinned {
+            true => TaskSystem::pin_module(&mut world, module_entity),
+            false => TaskSystem::unpin_module(&mut world, module_entity),
+        }
+        info!("Control API {} module {}", if request.pinned { "pinned" } else { "unpinned" }, request.name);
+
+        Ok(Response::new(pb::PinReply {
+            name: request.name,
+            pinned: request.pinned,
+        }))
+    }
+
+    async fn prefetch_module(
+        &self,
+        request: Request<pb::PrefetchModuleRequest>,
+    ) -> Result<Response<pb::PrefetchReply>, Status> {
+        let name = request.into_inner().name;
+
+        let mut world = self.world.lock().await;
+        let module_entity = world
+            .query::<&Module>()
+            .iter()
+            .find(|(_, module)| module.name == name)
+            .map(|(entity, _)| entity)
+            .ok_or_else(|| Status::not_found(format!("unknown module {}", name)))?;
+        let devices = TaskSystem::prefetch_module(&mut world, module_entity)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        info!("Control API prefetching module {} to {} devices", name, devices);
+
+        Ok(Response::new(pb::PrefetchReply {
+            name,
+            devices: devices as u32,
+        }))
+    }
+
+    async fn update_firmware(
+        &self,
+        request: Request<pb::UpdateFirmwareRequest>,
+    ) -> Result<Response<pb::FirmwareReply>, Status> {
+        let request = request.into_inner();
+        if request.version.is_empty() || request.image.is_empty() {
+            return Err(Status::invalid_argument("firmware version and image are required"));
+        }
+
+        let mut world = self.world.lock().await;
+        let chunk_size = match request.chunk_size {
+            0 => LifecycleSystem::settings(&world).chunk_size,
+            chunk_size => chunk_size,
+        };
+        let firmware = world.spawn((Firmware {
+            version: request.version.clone(),
+            binary: request.image,
+            chunk_size,
+            arch: request.arch,
+        },));
+        let devices = TaskSystem::update_firmware(&mut world, firmware)
+            .map_err(|e| Status::internal(e.to_string()))?;
+        info!("Control API rolling out firmware {} to {} devices", request.version, devices);
+
+        Ok(Response::new(pb::FirmwareReply {
+            version: request.version,
+            devices: devices as u32,
+        }))
+    }
+
+    async fn upload_blob(
+        &self,
+        request: Request<pb::UploadBlobRequest>,
+    ) -> Result<Response<pb::BlobReply>, Status> {
+        let request = request.into_inner();
+        if request.data.is_empty() {
+            return Err(Status::invalid_argument("blob data is required"));
+        }
+
+        let size = request.data.len() as u64;
+
+        let mut world = self.world.lock().await;
+        let chunk_size = match request.chunk_size {
+            0 => LifecycleSystem::settings(&world).chunk_size,
+            chunk_size => chunk_size,
+        };
+        let existing = world
+            .query::<&Blob>()
+            .iter()
+            .find(|(_, blob)| blob.binary == request.data)
+            .map(|(_, blob)| (blob.id, blob.checksum()));
+        let (id, hash, existing) = match existing {
+            Some((id, hash)) => (id, hash, true),
+            None => {
+                // Drawn from the task id sequence, which keeps increasing across restarts.
+                let blob = Blob {
+                    id: next_task_id().0,
+                    binary: request.data,
+                    chunk_size,
+                };
+                let (id, hash) = (blob.id, blob.checksum());
+                world.spawn((blob,));
+                (id, hash, false)
+            }
+        };
+        info!("Control API stored blob {} ({} bytes)", id, size);
+
+        Ok(Response::new(pb::BlobReply {
+            blob: Some(pb::BlobRef { id, size, hash }),
+            existing,
+        }))
+    }
+
+    async fn list_sessions(
+        &self,
+        _: Request<pb::ListSessionsRequest>,
+    ) -> Result<Response<pb::ListSessionsReply>, Status> {
+        let world = self.world.lock().await;
+        let sessions = world
+            .query::<(&Session, &SessionInfo, &SessionHealth, Option<&SessionLabels>, Option<&DeviceBenchmark>)>()
+            .iter()
+            .map(|(entity, (session, info, health, labels, benchmark))| pb::SessionReply {
+                id: entity.to_bits().get(),
+                addr: info.device_addr.to_string(),
+                status: format!("{:?}", health.status),
+                ram: info.device_ram,
+                labels: labels.map_or(vec![], |labels| labels.labels.iter().cloned().collect()),
+                latency_ms: session.latency.as_millis() as u64,
+                protocol_errors: health.protocol_errors,
+                last_protocol_error: health.last_protocol_error.clone().unwrap_or_default(),
+                benchmark: benchmark.map(|benchmark| pb::DeviceBenchmark {
+                    integer: benchmark.integer.unwrap_or_default(),
+                    float: benchmark.float.unwrap_or_default(),
+                    memory: benchmark.memory.unwrap_or_default(),
+                }),
+            })
+            .collect();
+
+        Ok(Response::new(pb::ListSessionsReply { sessions }))
+    }
+
+    type StreamEventsStream = ReceiverStream<Result<pb::TaskEvent, Status>>;
+
+    async fn stream_events(
+        &self,
+        _: Request<pb::StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let (tx, rx) = mpsc::channel(64);
+        let world = self.world.clone();
+
+        tokio::spawn(async move {
+            let mut phases = HashMap::new();
+            while !tx.is_closed() {
+                let events = world
+                    .lock()
+                    .await
+                    .query::<(&TaskId, &TaskState)>()
+                    .iter()
+                    .filter_map(|(_, (task_id, state))| {
+                        let phase = pb::TaskPhase::from(&state.phase);
+                        (phases.insert(*task_id, phase) != Some(phase)).then_some(pb::TaskEvent {
+                            task_id: task_id.0,
+                            phase: phase as i32,
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                for event in events {
+                    if tx.send(Ok(event)).await.is_err() {
+                        return;
+                    }
+                }
+                tokio::time::sleep(EVENT_INTERVAL).await;
+            }
+        });
+
+        Ok(Response::new(ReceiverStream::new(rx)))
+    }
+
+    async fn export_results(
+        &self,
+        request: Request<pb::ExportResultsRequest>,
+    ) -> Result<Response<pb::ExportResultsReply>, Status> {
+        let request = request.into_inner();
+        let store = ResultStore::get(&*self.world.lock().await)
+            .ok_or_else(|| Status::failed_precondition("server keeps no result store, set RESULTS_DB"))?;
+        let format = match request.format() {
+            pb::ExportFormat::Csv => ExportFormat::Csv,
+            pb::ExportFormat::Parquet => ExportFormat::Parquet,
+        };
+        let query = ResultQuery {
+            module: (!request.module.is_empty()).then_some(request.module),
+            since: (request.since_secs > 0).then_some(request.since_secs),
+            ..Default::default()
+        };
+
+        let (file, rows) = tokio::task::spawn_blocking(move || export(&store, &query, format))
+            .await
+            .map_err(|e| Status::internal(e.to_string()))?
+            .map_err(|e| Status::internal(format!("export failed: {}", e)))?;
+        info!("Control API exported {} results as {:?}", rows, format);
+
+        Ok(Response::new(pb::ExportResultsReply { file, rows: rows as u64 }))
+    }
+}
+
+pub async fn run(world: &Arc<Mutex<World>>, addr: SocketAddr) -> Result<(), Error> {
+    info!("Control API listening on: {}", addr);
+
+    Server::builder()
+        .add_service(ControlServer::new(ControlService::new(world.clone())))
+        .serve(addr)
+        .await
+        .map_err(io::Error::other)
+        .context(format!("serving control API on {}", addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_control_service() {
+        let mut world = World::new();
+        world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        let service = ControlService::new(Arc::new(Mutex::new(world)));
+
+        let unknown = service
+            .submit_task(Request::new(pb::SubmitTaskRequest {
+                module: "unknown_module".into(),
+                ..Default::default()
+            }))
+            .await;
+        assert_eq!(unknown.unwrap_err().code(), tonic::Code::NotFound);
+
+        let bad_env = service
+            .submit_task(Request::new(pb::SubmitTaskRequest {
+                module: "mock_module".into(),
+                env: vec![pb::EnvVar { key: "A=B".into(), value: "c".into() }],
+                ..Default::default()
+            }))
+            .await;
+        assert_eq!(bad_env.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let broadcast_filler = service
+            .submit_task(Request::new(pb::SubmitTaskRequest {
+                module: "mock_module".into(),
+                broadcast: true,
+                filler: true,
+                ..Default::default()
+            }))
+            .await;
+        assert_eq!(broadcast_filler.unwrap_err().code(), tonic::Code::InvalidArgument);
+        let preempting_filler = service
+            .submit_task(Request::new(pb::SubmitTaskRequest {
+                module: "mock_module".into(),
+                filler: true,
+                preempt: true,
+                ..Default::default()
+            }))
+            .await;
+        assert_eq!(preempting_filler.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let submitted = service
+            .submit_task(Request::new(pb::SubmitTaskRequest {
+                module: "mock_module".into(),
+                params: vec![Type::I32(1).into(), Type::V128(-2).into(), Type::Bytes(vec![3]).into()],
+                priority: 3,
+                broadcast: false,
+                labels: vec!["camera".into()],
+                tenant: "sweep".into(),
+                env: vec![pb::EnvVar { key: "MODE".into(), value: "fast".into() }],
+                filler: false,
+                group: "sweep".into(),
+                preempt: true,
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(submitted.phase(), pb::TaskPhase::Queued);
+
+        {
+            let world = service.world.lock().await;
+            let mut query = world.query::<(&Task, &TaskSelector, &TaskOwner, &TaskGroupMember)>().with::<&PreemptiveTask>();
+            let (_, (task, selector, owner, member)) = query.iter().next().unwrap();
+            assert_eq!(task.params, vec![Type::I32(1), Type::V128(-2), Type::Bytes(vec![3])]);
+            assert_eq!(task.priority, 3);
+            assert_eq!(task.env, vec![("MODE".to_string(), "fast".to_string())]);
+            assert_eq!(selector.labels, vec!["camera".to_string()]);
+            assert_eq!(owner.tenant, "sweep");
+            let group = world.get::<&TaskGroup>(member.group).unwrap();
+            assert_eq!((group.name.as_str(), group.total), ("sweep", 1));
+        }
+
+        let fetched = service
+            .get_task(Request::new(pb::GetTaskRequest { task_id: submitted.task_id }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(fetched, submitted);
+
+        let mut events = service
+            .stream_events(Request::new(pb::StreamEventsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let event = events.next().await.unwrap().unwrap();
+        assert_eq!(event.task_id, submitted.task_id);
+        assert_eq!(event.phase(), pb::TaskPhase::Queued);
+
+        let tasks = service
+            .list_tasks(Request::new(pb::ListTasksRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(tasks.tasks, vec![submitted.clone()]);
+
+        let conflicting = service
+            .schedule_task(Request::new(pb::ScheduleTaskRequest {
+                task: Some(pb::SubmitTaskRequest {
+                    module: "mock_module".into(),
+                    ..Default::default()
+                }),
+                interval_secs: 30,
+                cron: "*/5 * * * *".into(),
+                ..Default::default()
+            }))
+            .await;
+        assert_eq!(conflicting.unwrap_err().code(), tonic::Code::InvalidArgument);
+
+        let scheduled = service
+            .schedule_task(Request::new(pb::ScheduleTaskRequest {
+                task: Some(pb::SubmitTaskRequest {
+                    module: "mock_module".into(),
+                    ..Default::default()
+                }),
+                name: "aggregate".into(),
+                interval_secs: 30,
+                ..Default::default()
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(scheduled.name, "aggregate");
+        assert!(scheduled.next_run_secs > 0);
+        {
+            let world = service.world.lock().await;
+            let mut query = world.query::<(&Task, &RecurringTask)>();
+            let (_, (task, recurring)) = query.iter().next().unwrap();
+            assert_eq!(task.name, "aggregate");
+            assert_eq!(recurring.schedule, Schedule::Every(Duration::from_secs(30)));
+        }
+
+        let uploaded = service
+            .upload_module(Request::new(pb::UploadModuleRequest {
+                name: "mock_module".into(),
+                binary: vec![1u8; 64],
+                chunk_size: 0,
+                native: true,
+                libraries: vec![],
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(uploaded.replaced);
+        assert_eq!(uploaded.size, 64);
+
+        let pin = |pinned| pb::PinModuleRequest { name: "mock_module".into(), pinned };
+        assert!(service.pin_module(Request::new(pin(true))).await.unwrap().into_inner().pinned);
+        assert!(service.world.lock().await.query_mut::<&Module>().into_iter().all(|(_, module)| module.pinned));
+        service.pin_module(Request::new(pin(false))).await.unwrap();
+        assert!(service.world.lock().await.query_mut::<&Module>().into_iter().all(|(_, module)| !module.pinned));
+        assert_eq!(service.world.lock().await.query_mut::<(&Module, &NativeModule)>().into_iter().count(), 1);
+
+        let sessions = service
+            .list_sessions(Request::new(pb::ListSessionsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        assert!(sessions.sessions.is_empty());
+
+        let request = || pb::ExportResultsRequest { format: pb::ExportFormat::Parquet as i32, ..Default::default() };
+        let missing = service.export_results(Request::new(request())).await;
+        assert_eq!(missing.unwrap_err().code(), tonic::Code::FailedPrecondition);
+        ResultStore::set(&mut *service.world.lock().await, ResultStore::open_in_memory().unwrap());
+        let exported = service.export_results(Request::new(request())).await.unwrap().into_inner();
+        assert_eq!(exported.rows, 0);
+        assert!(exported.file.starts_with(b"PAR1"));
+    }
+
+    #[tokio::test]
+    async fn test_upload_blob() {
+        let mut world = World::new();
+        world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        let service = ControlService::new(Arc::new(Mutex::new(world)));
+        let upload = || pb::UploadBlobRequest { data: vec![7u8; 100], chunk_size: 0 };
+
+        let stored = service.upload_blob(Request::new(upload())).await.unwrap().into_inner();
+        let blob = stored.blob.unwrap();
+        assert!(!stored.existing);
+        assert_eq!((blob.size, blob.hash), (100, protocol::Checksum::of(&[7u8; 100])));
+        let again = service.upload_blob(Request::new(upload())).await.unwrap().into_inner();
+        assert!(again.existing);
+        assert_eq!(again.blob.unwrap().id, blob.id);
+
+        let submit = |id, size, hash| {
+            service.submit_task(Request::new(pb::SubmitTaskRequest {
+                module: "mock_module".into(),
+                params: vec![Type::BlobRef(id, size, hash).into()],
+                ..Default::default()
+            }))
+        };
+        // A bare id is completed from the store.
+        submit(blob.id, 0, 0).await.unwrap();
+        {
+            let world = service.world.lock().await;
+            let mut query = world.query::<&Task>();
+            let (_, task) = query.iter().next().unwrap();
+            assert_eq!(task.params, vec![Type::BlobRef(blob.id, 100, blob.hash)]);
+        }
+        assert_eq!(submit(blob.id, 100, blob.hash + 1).await.unwrap_err().code(), tonic::Code::InvalidArgument);
+        assert_eq!(submit(blob.id + 1, 0, 0).await.unwrap_err().code(), tonic::Code::NotFound);
+    }
+}
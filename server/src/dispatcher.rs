@@ -1,85 +1,226 @@
-use std::collections::HashMap;
-use std::error::Error;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::future;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
+use futures::FutureExt;
 use hecs::{Entity, World};
-use log::info;
+use log::{info, warn};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::Mutex;
 
 use crate::components::*;
+use crate::error::{Context, Error};
+use crate::listen::bind;
 use crate::systems::*;
 
-const CHUNK_SIZE: usize = 1024;
+const TICK_INTERVAL: Duration = Duration::from_millis(5);
 
-async fn initialize_modules_and_tasks(world: &Arc<Mutex<World>>) {
-    let static_modules = task::get_static_modules();
+async fn initialize_modules_and_tasks(world: &Arc<Mutex<World>>, modules_dir: Option<&Path>) -> Result<(), Error> {
     let mut world_lock = world.lock().await;
+    let settings = LifecycleSystem::settings(&world_lock);
+    let chunk_size = settings.chunk_size;
+
+    // A standby taking over already holds the replicated modules and tasks.
+    if !settings.bundled_tasks || world_lock.query::<&Module>().iter().next().is_some() {
+        return Ok(());
+    }
+
+    let bundled_modules = task::load_modules(modules_dir).context("loading bundled modules")?;
+    // Bundled modules named in PINNED_MODULES, separated by commas, are never evicted from device
+    // caches. A pin only reaches a device with its next transfer of the module.
+    let pinned_modules = env::var("PINNED_MODULES").unwrap_or_default();
+    let pinned_modules = pinned_modules.split(',').map(str::trim).collect::<HashSet<_>>();
 
     let module_entities = world_lock
-        .spawn_batch(static_modules.iter().map(|module| {
+        .spawn_batch(bundled_modules.iter().map(|module| {
             (Module {
                 name: module.name.to_string(),
                 binary: module.binary.to_vec(),
                 dependencies: vec![],
-                chunk_size: CHUNK_SIZE as u32,
+                chunk_size,
+                pinned: pinned_modules.contains(module.name),
             },)
         }))
         .collect::<Vec<_>>();
 
-    let module_map = static_modules
+    let module_map = bundled_modules
         .iter()
         .zip(module_entities.iter())
         .map(|(module, entity)| (module.name.to_string(), *entity))
         .collect::<HashMap<String, Entity>>();
 
-    world
-        .lock()
-        .await
-        .spawn_batch(task::load_tasks().iter().filter_map(|task| {
-            Some((
-                Task {
-                    name: task.name.clone(),
-                    params: task.params.to_owned(),
-                    result: vec![],
-                    created_at: SystemTime::now(),
-                    require_module: *module_map.get(&task.module)?,
-                    priority: 1,
-                },
-                TaskState {
-                    phase: TaskStatePhase::Queued,
-                    assigned_device: None,
-                },
-            ))
-        }));
+    let owned_modules = module_map
+        .keys()
+        .filter(|name| ClusterSystem::owns_module(&world_lock, name))
+        .cloned()
+        .collect::<HashSet<_>>();
+
+    // Each module's tasks are slices of one sweep, grouped so they show up as one job.
+    for task in task::load_tasks() {
+        if !owned_modules.contains(&task.module) {
+            continue;
+        }
+        let Some(&module_entity) = module_map.get(&task.module) else {
+            continue;
+        };
+        let group = TaskSystem::join_group(&mut world_lock, &task.module);
+        world_lock.spawn((
+            Task {
+                name: task.name,
+                params: task.params,
+                env: vec![],
+                result: vec![],
+                created_at: SystemTime::now(),
+                require_module: module_entity,
+                priority: 1,
+                kind: TaskKind::Single,
+            },
+            TaskState {
+                phase: TaskStatePhase::Queued,
+                assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 0,
+            },
+            next_task_id(),
+            group,
+        ));
+    }
+    Ok(())
+}
+
+// Accepts devices on the listeners it is bound to and runs the systems over the shared world.
+// `serve` paces the systems itself, an application driving its own loop calls `tick` instead.
+pub struct DispatcherService {
+    world: Arc<Mutex<World>>,
+    listeners: Vec<TcpListener>,
+    modules_dir: Option<PathBuf>,
 }
 
-pub async fn run(world: &Arc<Mutex<World>>, addr: &str) -> Result<(), Box<dyn Error>> {
-    let listener = TcpListener::bind(addr).await?;
+impl DispatcherService {
+    pub fn new(world: Arc<Mutex<World>>) -> Self {
+        Self {
+            world,
+            listeners: Vec::new(),
+            modules_dir: None,
+        }
+    }
+
+    pub fn bind(mut self, listener: TcpListener) -> Self {
+        self.listeners.push(listener);
+        self
+    }
+
+    // Where a task crate built with its `lazy` feature finds the bundled module binaries, by
+    // default the directory they were built into.
+    pub fn modules_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.modules_dir = Some(dir.into());
+        self
+    }
 
-    info!("Dispatcher server listening on: {}", listener.local_addr()?);
+    // Seeds the world with the bundled modules and tasks unless settings leave them out or it
+    // already holds modules, then accepts and dispatches until a system fails.
+    pub async fn serve(self) -> Result<(), Error> {
+        // Readiness reports the first address, it is the one clients are usually given.
+        let local_addr = self
+            .listeners
+            .first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"))
+            .and_then(|listener| listener.local_addr())
+            .context("starting dispatcher")?;
 
-    initialize_modules_and_tasks(world).await;
+        initialize_modules_and_tasks(&self.world, self.modules_dir.as_deref()).await?;
 
-    let world_clone = world.clone();
-    tokio::spawn(async move {
-        while let Ok((stream, addr)) = listener.accept().await {
-            info!("Accepted connection from {}", addr);
-            let mut world = world_clone.lock().await;
-            LifecycleSystem::accept_connection(&mut world, stream, addr);
+        for listener in &self.listeners {
+            info!("Dispatcher server listening on: {}", listener.local_addr().context("starting dispatcher")?);
+        }
+        // Accepting runs within the dispatcher's own task, so its listeners close as soon as it stops.
+        let world = &self.world;
+        let accepting = futures::future::join_all(
+            self.listeners
+                .into_iter()
+                .map(|listener| accept_connections(world, listener)),
+        );
+        tokio::select! {
+            result = dispatch(world, local_addr) => result,
+            _ = accepting.then(|_| future::pending::<()>()) => Ok(()),
+        }
+    }
+
+    // One pass of every system over sessions accepted into `world`.
+    pub async fn tick(world: &mut World) -> Result<(), Error> {
+        LifecycleSystem::maintain_connection(world, TcpStream::connect).await;
+        LifecycleSystem::expire_handshakes::<TcpStream>(world).await;
+        NetworkSystem::process_inbound::<TcpStream>(world).await;
+        ClusterSystem::forward_registrations(world);
+        ScheduleSystem::enqueue_recurring(world);
+        CalibrationSystem::calibrate_devices(world);
+        TaskSystem::speculate_stragglers(world);
+        TaskSystem::assign_tasks(world)?;
+        // Only devices left idle by queued work are warmed up.
+        ScheduleSystem::prefetch_recurring(world)?;
+        TaskSystem::transfer_chunks(world)?;
+        TaskSystem::track_transfers(world, SystemTime::now());
+        TaskSystem::finalize_transfer(world)?;
+        TaskSystem::collect_broadcasts(world);
+        TaskSystem::collect_speculations(world)?;
+        CalibrationSystem::collect_calibrations(world);
+        TaskSystem::track_groups(world);
+        MetricsSystem::record(world, SystemTime::now());
+        NetworkSystem::process_outbound::<TcpStream>(world).await;
+        // Taken once the queued messages went out, so snapshots hold little more than state.
+        TimelineSystem::record(world, SystemTime::now());
+        LifecycleSystem::drain_sessions::<TcpStream>(world).await;
+        Ok(())
+    }
+}
+
+pub async fn run(world: &Arc<Mutex<World>>, addrs: &[SocketAddr], modules_dir: Option<PathBuf>) -> Result<(), Error> {
+    let service = DispatcherService {
+        modules_dir,
+        ..DispatcherService::new(world.clone())
+    };
+    // Bound up front so a taken port fails the dispatcher rather than one accept loop.
+    let service = addrs.iter().try_fold(service, |service, addr| {
+        bind(*addr).map(|listener| service.bind(listener)).context(format!("binding dispatcher to {}", addr))
+    })?;
+    service.serve().await
+}
+
+async fn accept_connections(world: &Arc<Mutex<World>>, listener: TcpListener) {
+    while let Ok((mut stream, addr)) = listener.accept().await {
+        let mut world = world.lock().await;
+        if LifecycleSystem::server_mode(&world) == ServerMode::Draining {
+            info!("Rejected connection from {} while draining", addr);
+            continue;
+        }
+        if LifecycleSystem::sessions_full(&world) {
+            let limits = LifecycleSystem::connection_limits(&world);
             drop(world);
+            warn!("Rejected connection from {}, all {} session slots taken", addr, limits.max_sessions);
+            tokio::spawn(async move { LifecycleSystem::reject_busy(&mut stream, limits).await.ok() });
+            continue;
         }
-    });
+        info!("Accepted connection from {}", addr);
+        LifecycleSystem::accept_connection(&mut world, stream, addr);
+        drop(world);
+    }
+}
 
+async fn dispatch(world: &Arc<Mutex<World>>, local_addr: SocketAddr) -> Result<(), Error> {
     loop {
         let mut locked = world.lock().await;
-        LifecycleSystem::maintain_connection(&mut locked, TcpStream::connect).await;
-        NetworkSystem::process_inbound::<TcpStream>(&mut locked).await;
-        TaskSystem::assign_tasks(&mut locked);
-        TaskSystem::transfer_chunks(&mut locked);
-        TaskSystem::finalize_transfer(&mut locked);
-        NetworkSystem::process_outbound::<TcpStream>(&mut locked).await;
+        LifecycleSystem::record_dispatcher_tick(&mut locked, local_addr);
+        DispatcherService::tick(&mut locked).await?;
         drop(locked);
+
+        // Reads no longer wait for data, so the loop paces itself and lets other services lock.
+        tokio::time::sleep(TICK_INTERVAL).await;
     }
 }
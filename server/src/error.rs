@@ -0,0 +1,67 @@
+use std::fmt::Display;
+use std::io;
+
+// What the server's services and systems fail with, so an embedder can tell a taken port from a
+// broken result store without matching on messages. `context` says what the server was doing.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("{context}: {source}")]
+    Io { context: String, source: io::Error },
+    #[error("{context}: {source}")]
+    Protocol { context: String, source: protocol::Error },
+    // The world no longer holds what a system just found in it, such as a task losing its state
+    // between two queries.
+    #[error("{context}: {reason}")]
+    Scheduling { context: String, reason: String },
+    #[error("{context}: {source}")]
+    Persistence { context: String, source: rusqlite::Error },
+    // A service task that panicked or was cancelled instead of returning.
+    #[error("{service} stopped: {reason}")]
+    Service { service: &'static str, reason: String },
+}
+
+impl Error {
+    pub(crate) fn scheduling(context: impl Into<String>, reason: impl Display) -> Self {
+        Self::Scheduling { context: context.into(), reason: reason.to_string() }
+    }
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+// Wraps the error of a fallible call into `Error` along with what the server was doing.
+pub(crate) trait Context<T> {
+    fn context(self, context: impl Into<String>) -> Result<T>;
+}
+
+impl<T> Context<T> for Result<T, io::Error> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::Io { context: context.into(), source })
+    }
+}
+
+impl<T> Context<T> for Result<T, protocol::Error> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::Protocol { context: context.into(), source })
+    }
+}
+
+impl<T> Context<T> for Result<T, rusqlite::Error> {
+    fn context(self, context: impl Into<String>) -> Result<T> {
+        self.map_err(|source| Error::Persistence { context: context.into(), source })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context() {
+        let error = Err::<(), _>(io::Error::from(io::ErrorKind::AddrInUse)).context("binding 0.0.0.0:3030").unwrap_err();
+        assert!(matches!(&error, Error::Io { source, .. } if source.kind() == io::ErrorKind::AddrInUse));
+        assert_eq!(error.to_string(), "binding 0.0.0.0:3030: address in use");
+
+        let error = Err::<(), _>(protocol::Error::InvalidMessage).context("following primary").unwrap_err();
+        assert!(matches!(error, Error::Protocol { source: protocol::Error::InvalidMessage, .. }));
+    }
+}
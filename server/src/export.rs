@@ -0,0 +1,252 @@
+use std::error::Error;
+use std::sync::Arc;
+
+use parquet::basic::{LogicalType, Repetition, Type as PhysicalType};
+use parquet::data_type::{ByteArray, ByteArrayType, Int32Type, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedColumnWriter, SerializedFileWriter};
+use parquet::schema::types::Type as SchemaType;
+use serde::Deserialize;
+
+use crate::results::{ResultQuery, ResultRecord, ResultStore};
+
+type ExportError = Box<dyn Error + Send + Sync>;
+
+const COLUMNS: [&str; 14] = [
+    "id",
+    "task_id",
+    "attempt",
+    "module",
+    "name",
+    "params",
+    "result",
+    "device",
+    "executor",
+    "arch",
+    "wall_time_us",
+    "peak_memory",
+    "instructions",
+    "completed_at",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+// Every stored result matching `query`, whatever its `after` and `limit`, as one file. Params and
+// results are JSON text columns, the same form `/api/results` serves.
+pub fn export(store: &ResultStore, query: &ResultQuery, format: ExportFormat) -> Result<(Vec<u8>, usize), ExportError> {
+    let mut records = Vec::new();
+    let mut query = ResultQuery { limit: ResultStore::MAX_PAGE, ..query.clone() };
+    loop {
+        let page = store.query(&query)?;
+        records.extend(page.results);
+        match page.next {
+            Some(next) => query.after = Some(next),
+            None => break,
+        }
+    }
+
+    let file = match format {
+        ExportFormat::Csv => to_csv(&records)?,
+        ExportFormat::Parquet => to_parquet(&records)?,
+    };
+    Ok((file, records.len()))
+}
+
+fn json(values: &[protocol::Type]) -> String {
+    serde_json::to_string(values).unwrap_or_else(|_| "[]".into())
+}
+
+fn to_csv(records: &[ResultRecord]) -> Result<Vec<u8>, ExportError> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    writer.write_record(COLUMNS)?;
+    for record in records {
+        writer.write_record([
+            record.id.to_string(),
+            record.task_id.to_string(),
+            record.attempt.to_string(),
+            record.module.clone(),
+            record.name.clone(),
+            json(&record.params),
+            json(&record.result),
+            record.device.to_string(),
+            record.executor.clone(),
+            record.arch.clone(),
+            record.wall_time_us.to_string(),
+            record.peak_memory.to_string(),
+            record.instructions.map(|instructions| instructions.to_string()).unwrap_or_default(),
+            record.completed_at.to_string(),
+        ])?;
+    }
+    Ok(writer.into_inner().map_err(|e| e.into_error())?)
+}
+
+enum Column {
+    Int32(Vec<i32>),
+    Int64(Vec<i64>),
+    OptionalInt64(Vec<Option<i64>>),
+    Text(Vec<ByteArray>),
+}
+
+impl Column {
+    fn field(&self, name: &str) -> Result<Arc<SchemaType>, ExportError> {
+        let (physical, logical, repetition) = match self {
+            Column::Int32(_) => (PhysicalType::INT32, None, Repetition::REQUIRED),
+            Column::Int64(_) => (PhysicalType::INT64, None, Repetition::REQUIRED),
+            Column::OptionalInt64(_) => (PhysicalType::INT64, None, Repetition::OPTIONAL),
+            Column::Text(_) => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String), Repetition::REQUIRED),
+        };
+        let field = SchemaType::primitive_type_builder(name, physical)
+            .with_repetition(repetition)
+            .with_logical_type(logical)
+            .build()?;
+        Ok(Arc::new(field))
+    }
+
+    fn write(&self, column: &mut SerializedColumnWriter<'_>) -> Result<(), ExportError> {
+        match self {
+            Column::Int32(values) => {
+                column.typed::<Int32Type>().write_batch(values, None, None)?;
+            }
+            Column::Int64(values) => {
+                column.typed::<Int64Type>().write_batch(values, None, None)?;
+            }
+            Column::OptionalInt64(values) => {
+                let present = values.iter().flatten().copied().collect::<Vec<_>>();
+                let levels = values.iter().map(|value| value.is_some() as i16).collect::<Vec<_>>();
+                column.typed::<Int64Type>().write_batch(&present, Some(&levels), None)?;
+            }
+            Column::Text(values) => {
+                column.typed::<ByteArrayType>().write_batch(values, None, None)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn to_parquet(records: &[ResultRecord]) -> Result<Vec<u8>, ExportError> {
+    let int64 = |value: fn(&ResultRecord) -> i64| Column::Int64(records.iter().map(value).collect());
+    let text = |value: fn(&ResultRecord) -> String| {
+        Column::Text(records.iter().map(|record| ByteArray::from(value(record).into_bytes())).collect())
+    };
+    let columns = [
+        int64(|record| record.id),
+        int64(|record| record.task_id as i64),
+        Column::Int32(records.iter().map(|record| record.attempt as i32).collect()),
+        text(|record| record.module.clone()),
+        text(|record| record.name.clone()),
+        text(|record| json(&record.params)),
+        text(|record| json(&record.result)),
+        int64(|record| record.device as i64),
+        text(|record| record.executor.clone()),
+        text(|record| record.arch.clone()),
+        int64(|record| record.wall_time_us as i64),
+        int64(|record| record.peak_memory as i64),
+        Column::OptionalInt64(records.iter().map(|record| record.instructions.map(|value| value as i64)).collect()),
+        int64(|record| record.completed_at as i64),
+    ];
+
+    let fields = COLUMNS
+        .iter()
+        .zip(&columns)
+        .map(|(name, column)| column.field(name))
+        .collect::<Result<Vec<_>, _>>()?;
+    let schema = SchemaType::group_type_builder("result").with_fields(fields).build()?;
+
+    let mut file = Vec::new();
+    let mut writer = SerializedFileWriter::new(&mut file, Arc::new(schema), Arc::new(WriterProperties::builder().build()))?;
+    let mut row_group = writer.next_row_group()?;
+    for column in &columns {
+        let mut writer = row_group.next_column()?.ok_or("schema has fewer columns than written")?;
+        column.write(&mut writer)?;
+        writer.close()?;
+    }
+    row_group.close()?;
+    writer.close()?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use parquet::file::reader::{FileReader, SerializedFileReader};
+    use parquet::record::RowAccessor;
+    use protocol::Type;
+
+    use super::*;
+
+    fn store() -> ResultStore {
+        let store = ResultStore::open_in_memory().unwrap();
+        for (task_id, module, instructions) in [(1, "sum", Some(42)), (2, "blink", None), (3, "sum", None)] {
+            let record = ResultRecord {
+                id: 0,
+                task_id,
+                attempt: 1,
+                module: module.into(),
+                name: format!("task, \"{}\"", task_id),
+                params: vec![Type::I32(task_id as i32)],
+                result: vec![Type::F64(0.5)],
+                device: 7,
+                executor: "Jit".into(),
+                arch: "x86_64".into(),
+                wall_time_us: 10,
+                peak_memory: 2048,
+                instructions,
+                completed_at: 1000 + task_id,
+            };
+            store.record(&record).unwrap();
+        }
+        store
+    }
+
+    #[test]
+    fn test_export_csv() {
+        let query = ResultQuery { module: Some("sum".into()), ..Default::default() };
+        let (file, rows) = export(&store(), &query, ExportFormat::Csv).unwrap();
+        assert_eq!(rows, 2);
+
+        let mut reader = csv::Reader::from_reader(file.as_slice());
+        assert_eq!(reader.headers().unwrap().iter().collect::<Vec<_>>(), COLUMNS);
+        let records = reader.records().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(&records[0][4], "task, \"1\"");
+        assert_eq!(&records[0][5], r#"[{"I32":1}]"#);
+        assert_eq!(&records[0][12], "42");
+        assert_eq!(&records[1][12], "");
+    }
+
+    #[test]
+    fn test_export_parquet() {
+        let (file, rows) = export(&store(), &ResultQuery::default(), ExportFormat::Parquet).unwrap();
+        assert_eq!(rows, 3);
+
+        let reader = SerializedFileReader::new(bytes::Bytes::from(file)).unwrap();
+        let metadata = reader.metadata();
+        assert_eq!(metadata.file_metadata().num_rows(), 3);
+        assert_eq!(metadata.file_metadata().schema_descr().num_columns(), COLUMNS.len());
+        let names = reader
+            .get_row_iter(None)
+            .unwrap()
+            .map(|row| row.unwrap().get_string(4).unwrap().clone())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["task, \"1\"", "task, \"2\"", "task, \"3\""]);
+    }
+}
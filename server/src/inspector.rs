@@ -1,77 +1,1870 @@
-use std::error::Error;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::IntoFuture;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use axum::Router;
-use hecs::{ChangeTracker, World};
+use axum::extract::ws::{Message as WsMessage, WebSocket, WebSocketUpgrade};
+use axum::extract::{FromRef, Path, Query, State};
+use axum::body::Bytes;
+use axum::http::{header, StatusCode};
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::Response;
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::{FutureExt, Stream, StreamExt};
+use hecs::{ChangeTracker, Entity, World};
 use log::info;
-use tokio::net::TcpListener;
-use tokio::sync::{watch, Mutex};
+use protocol::{CacheStats, LogLevel, Message, Telemetry, Type};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Builder;
+use tokio::sync::{broadcast, mpsc, oneshot, watch, Mutex, MutexGuard};
+use tokio_rustls::TlsAcceptor;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
 use tower_http::cors::CorsLayer;
 use tower_http::services::ServeDir;
 
 use crate::components::*;
+use crate::error::{Context, Error};
+use crate::export::{export, ExportFormat};
+use crate::listen::{bind, TlsListener};
+use crate::notifier::{self, DEFAULT_TEMPLATE};
+use crate::results::{ResultPage, ResultQuery, ResultStore};
+use crate::snapshot::{snapshot, WorldSnapshot};
+use crate::systems::{CalibrationSystem, ClusterSystem, LifecycleSystem, TaskSystem, TimelineSystem};
 
-struct InspectorState {
+const HISTORY_LEN: usize = 256;
+const UPDATE_INTERVAL: Duration = Duration::from_millis(250);
+const LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+const STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Serialize)]
+struct TaskView {
+    entity: u64,
+    task_id: Option<u64>,
+    name: String,
+    module: u64,
+    priority: u8,
+    kind: String,
+    tenant: Option<String>,
+    group: Option<u64>,
+    params: Vec<Type>,
+    result: Vec<Type>,
+}
+
+impl TaskView {
+    fn new(world: &World, entity: Entity, task: &Task) -> Self {
+        Self {
+            entity: entity.to_bits().get(),
+            task_id: world.get::<&TaskId>(entity).ok().map(|task_id| task_id.0),
+            name: task.name.clone(),
+            module: task.require_module.to_bits().get(),
+            priority: task.priority,
+            kind: format!("{:?}", task.kind),
+            tenant: world.get::<&TaskOwner>(entity).ok().map(|owner| owner.tenant.clone()),
+            group: world.get::<&TaskGroupMember>(entity).ok().map(|member| member.group.to_bits().get()),
+            params: task.params.clone(),
+            result: task.result.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TaskStateView {
+    entity: u64,
+    phase: String,
+    assigned_device: Option<u64>,
+    results: HashMap<u64, Vec<Type>>,
+    progress: Option<ProgressView>,
+    transfer: Option<TransferView>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ProgressView {
+    percent: u8,
+    stage: String,
+    // Seconds since the device last reported, a growing value points at a hung task.
+    age_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TransferView {
+    chunks_acked: u32,
+    total_chunks: u32,
+    bytes_acked: u64,
+    bytes_per_sec: f64,
+    // Seconds since the last ack, growing while the transfer is incomplete means it stalled.
+    stalled_secs: u64,
+}
+
+impl TaskStateView {
+    fn new(entity: Entity, state: &TaskState) -> Self {
+        Self {
+            entity: entity.to_bits().get(),
+            phase: phase_name(&state.phase).into(),
+            assigned_device: state.assigned_device.map(|device| device.to_bits().get()),
+            results: state
+                .results
+                .iter()
+                .map(|(session, result)| (session.to_bits().get(), result.clone()))
+                .collect(),
+            progress: state.progress.as_ref().map(|progress| ProgressView {
+                percent: progress.percent,
+                stage: progress.stage.clone(),
+                age_secs: progress.updated.elapsed().unwrap_or_default().as_secs(),
+            }),
+            transfer: state.transfer.as_ref().map(|transfer| TransferView {
+                chunks_acked: transfer.chunks_acked,
+                total_chunks: transfer.total_chunks,
+                bytes_acked: transfer.bytes_acked,
+                bytes_per_sec: transfer.bytes_per_sec,
+                stalled_secs: transfer.updated.elapsed().unwrap_or_default().as_secs(),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct TaskGroupView {
+    entity: u64,
+    name: String,
+    status: String,
+    total: u32,
+    completed: u32,
+    failed: u32,
+    percent: u8,
+    created_at: u64,
+}
+
+impl TaskGroupView {
+    fn new(entity: Entity, group: &TaskGroup) -> Self {
+        Self {
+            entity: entity.to_bits().get(),
+            name: group.name.clone(),
+            status: match group.status() {
+                TaskGroupStatus::Running => "running",
+                TaskGroupStatus::Completed => "completed",
+                TaskGroupStatus::Failed => "failed",
+            }
+            .into(),
+            total: group.total,
+            completed: group.completed,
+            failed: group.failed,
+            percent: group.percent,
+            created_at: group.created_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }
+    }
+}
+
+fn phase_name(phase: &TaskStatePhase) -> &'static str {
+    match phase {
+        TaskStatePhase::Queued => "queued",
+        TaskStatePhase::Distributing => "distributing",
+        TaskStatePhase::Executing { .. } => "executing",
+        TaskStatePhase::Completed => "completed",
+    }
+}
+
+fn status_name(status: &SessionStatus) -> &'static str {
+    match status {
+        SessionStatus::Connected => "connected",
+        SessionStatus::Occupied => "occupied",
+        SessionStatus::Disconnected => "disconnected",
+        SessionStatus::Zombie => "zombie",
+    }
+}
+
+// A task entering a phase, "task.queued" up to "task.completed" and "task.removed" once it is
+// despawned, or a session changing status, from "session.opened" to "session.closed".
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct LifecycleEvent {
+    event: String,
+    entity: u64,
+    timestamp: u64,
+}
+
+impl LifecycleEvent {
+    fn new(kind: &str, name: &str, entity: Entity, now: SystemTime) -> Self {
+        Self {
+            event: format!("{}.{}", kind, name),
+            entity: entity.to_bits().get(),
+            timestamp: unix_secs(now),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ComponentDiff<V> {
+    added: Vec<V>,
+    changed: Vec<V>,
+    removed: Vec<u64>,
+}
+
+impl<V> Default for ComponentDiff<V> {
+    fn default() -> Self {
+        Self {
+            added: Vec::new(),
+            changed: Vec::new(),
+            removed: Vec::new(),
+        }
+    }
+}
+
+impl<V> ComponentDiff<V> {
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct WorldDiff {
+    version: usize,
+    tasks: ComponentDiff<TaskView>,
+    task_states: ComponentDiff<TaskStateView>,
+    task_groups: ComponentDiff<TaskGroupView>,
+}
+
+impl WorldDiff {
+    fn snapshot(world: &World, version: usize) -> Self {
+        let mut diff = Self {
+            version,
+            ..Default::default()
+        };
+        for (entity, task) in world.query::<&Task>().iter() {
+            diff.tasks.added.push(TaskView::new(world, entity, task));
+        }
+        for (entity, state) in world.query::<&TaskState>().iter() {
+            diff.task_states.added.push(TaskStateView::new(entity, state));
+        }
+        for (entity, group) in world.query::<&TaskGroup>().iter() {
+            diff.task_groups.added.push(TaskGroupView::new(entity, group));
+        }
+        diff
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct DiffResponse {
+    version: usize,
+    resync: bool,
+    diffs: Vec<WorldDiff>,
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    since: Option<usize>,
+}
+
+enum InspectorRequest {
+    Diff {
+        since: Option<usize>,
+        reply: oneshot::Sender<DiffResponse>,
+    },
+}
+
+// Cheap to clone and Send + Sync, handlers talk to the tracker thread through it. The thread
+// stops once the last clone is dropped.
+#[derive(Clone)]
+pub struct InspectorHandle {
     world: Arc<Mutex<World>>,
-    version: Arc<watch::Sender<usize>>,
-    task_tracker: Arc<Mutex<ChangeTracker<Task>>>,
-    task_state_tracker: Arc<Mutex<ChangeTracker<TaskState>>>,
+    version: watch::Receiver<usize>,
+    requests: mpsc::Sender<InspectorRequest>,
+    events: broadcast::Sender<LifecycleEvent>,
 }
 
-unsafe impl Send for InspectorState {}
+impl InspectorHandle {
+    pub fn spawn(world: &Arc<Mutex<World>>) -> std::io::Result<Self> {
+        InspectorState::spawn(world)
+    }
+}
 
-impl InspectorState {
-    pub fn new(world: Arc<Mutex<hecs::World>>) -> Self {
-        let (version_tx, _) = watch::channel(0);
+impl FromRef<InspectorHandle> for Arc<Mutex<World>> {
+    fn from_ref(handle: &InspectorHandle) -> Self {
+        handle.world.clone()
+    }
+}
 
+// hecs change trackers hold raw query state and are not Send, so the state lives on
+// its own thread and is only reached through `InspectorHandle` requests.
+struct InspectorState {
+    world: Arc<Mutex<World>>,
+    version: watch::Sender<usize>,
+    events: broadcast::Sender<LifecycleEvent>,
+    history: VecDeque<WorldDiff>,
+    task_tracker: ChangeTracker<Task>,
+    task_state_tracker: ChangeTracker<TaskState>,
+    task_group_tracker: ChangeTracker<TaskGroup>,
+    known_tasks: HashSet<Entity>,
+    // Phase each task was last seen in, and likewise the status of each session.
+    task_phases: HashMap<Entity, &'static str>,
+    session_statuses: HashMap<Entity, &'static str>,
+    known_task_groups: HashSet<Entity>,
+}
+
+impl InspectorState {
+    pub fn new(
+        world: Arc<Mutex<hecs::World>>,
+        version: watch::Sender<usize>,
+        events: broadcast::Sender<LifecycleEvent>,
+    ) -> Self {
         Self {
             world,
-            version: Arc::new(version_tx),
-            task_tracker: Arc::new(Mutex::new(ChangeTracker::new())),
-            task_state_tracker: Arc::new(Mutex::new(ChangeTracker::new())),
+            version,
+            events,
+            history: VecDeque::new(),
+            task_tracker: ChangeTracker::new(),
+            task_state_tracker: ChangeTracker::new(),
+            task_group_tracker: ChangeTracker::new(),
+            known_tasks: HashSet::new(),
+            task_phases: HashMap::new(),
+            session_statuses: HashMap::new(),
+            known_task_groups: HashSet::new(),
+        }
+    }
+
+    pub fn spawn(world: &Arc<Mutex<World>>) -> std::io::Result<InspectorHandle> {
+        let (version_tx, version_rx) = watch::channel(0);
+        let (request_tx, mut request_rx) = mpsc::channel(64);
+        let (events, _) = broadcast::channel(HISTORY_LEN);
+        let runtime = Builder::new_current_thread().enable_time().build()?;
+
+        let state_world = world.clone();
+        let state_events = events.clone();
+        thread::Builder::new().name("inspector".into()).spawn(move || {
+            let mut state = InspectorState::new(state_world, version_tx, state_events);
+            runtime.block_on(async move {
+                let mut interval = tokio::time::interval(UPDATE_INTERVAL);
+                loop {
+                    tokio::select! {
+                        _ = interval.tick() => state.trigger_updates().await,
+                        request = request_rx.recv() => match request {
+                            Some(request) => state.handle_request(request).await,
+                            None => break,
+                        },
+                    }
+                }
+            });
+        })?;
+
+        Ok(InspectorHandle {
+            world: world.clone(),
+            version: version_rx,
+            requests: request_tx,
+            events,
+        })
+    }
+
+    async fn handle_request(&mut self, request: InspectorRequest) {
+        match request {
+            InspectorRequest::Diff { since, reply } => {
+                let response = self.diff(since).await;
+                reply.send(response).ok();
+            }
         }
     }
 
     pub async fn trigger_updates(&mut self) {
         let mut world = self.world.lock().await;
+        let mut diff = WorldDiff::default();
+        let mut events = Vec::new();
+        let now = SystemTime::now();
 
-        let task_changes = {
-            let mut locked = self.task_tracker.lock().await;
-            let mut task_tracker = locked.track(&mut world);
-            task_tracker.added().len() > 0
-                || task_tracker.changed().count() > 0
-                || task_tracker.removed().len() > 0
-        };
+        {
+            let mut task_tracker = self.task_tracker.track(&mut world);
+            let added = task_tracker.added().map(|(e, task)| (e, task.clone())).collect::<Vec<_>>();
+            let changed = task_tracker.changed().map(|(e, _, task)| (e, task.clone())).collect::<Vec<_>>();
+            let removed = task_tracker.removed().map(|(e, _)| e).collect::<Vec<_>>();
+            drop(task_tracker);
+
+            for (entity, task) in added {
+                self.known_tasks.insert(entity);
+                diff.tasks.added.push(TaskView::new(&world, entity, &task));
+            }
+            for (entity, task) in changed {
+                diff.tasks.changed.push(TaskView::new(&world, entity, &task));
+            }
+            // The tracker does not report despawned entities, so those are found by liveness.
+            let despawned = self.known_tasks.iter().filter(|e| !world.contains(**e)).copied();
+            for entity in removed.into_iter().chain(despawned.collect::<Vec<_>>()) {
+                self.known_tasks.remove(&entity);
+                diff.tasks.removed.push(entity.to_bits().get());
+            }
+        }
+
+        {
+            let mut task_state_tracker = self.task_state_tracker.track(&mut world);
+            let added = task_state_tracker
+                .added()
+                .map(|(e, state)| (e, phase_name(&state.phase), TaskStateView::new(e, state)))
+                .collect::<Vec<_>>();
+            let changed = task_state_tracker
+                .changed()
+                .map(|(e, _, state)| (e, phase_name(&state.phase), TaskStateView::new(e, state)))
+                .collect::<Vec<_>>();
+            let removed = task_state_tracker.removed().map(|(e, _)| e).collect::<Vec<_>>();
+            drop(task_state_tracker);
+
+            for (entity, phase, view) in added {
+                self.task_phases.insert(entity, phase);
+                events.push(LifecycleEvent::new("task", phase, entity, now));
+                diff.task_states.added.push(view);
+            }
+            for (entity, phase, view) in changed {
+                // Progress and results change the state too, only a new phase is an event.
+                if self.task_phases.insert(entity, phase) != Some(phase) {
+                    events.push(LifecycleEvent::new("task", phase, entity, now));
+                }
+                diff.task_states.changed.push(view);
+            }
+            let despawned = self.task_phases.keys().filter(|e| !world.contains(**e)).copied();
+            for entity in removed.into_iter().chain(despawned.collect::<Vec<_>>()) {
+                self.task_phases.remove(&entity);
+                events.push(LifecycleEvent::new("task", "removed", entity, now));
+                diff.task_states.removed.push(entity.to_bits().get());
+            }
+        }
+
+        {
+            let mut task_group_tracker = self.task_group_tracker.track(&mut world);
+            let added = task_group_tracker.added().map(|(e, group)| (e, TaskGroupView::new(e, group))).collect::<Vec<_>>();
+            let changed = task_group_tracker.changed().map(|(e, _, group)| TaskGroupView::new(e, group)).collect();
+            let removed = task_group_tracker.removed().map(|(e, _)| e).collect::<Vec<_>>();
+            drop(task_group_tracker);
+
+            for (entity, view) in added {
+                self.known_task_groups.insert(entity);
+                diff.task_groups.added.push(view);
+            }
+            diff.task_groups.changed = changed;
+            let despawned = self.known_task_groups.iter().filter(|e| !world.contains(**e)).copied();
+            for entity in removed.into_iter().chain(despawned.collect::<Vec<_>>()) {
+                self.known_task_groups.remove(&entity);
+                diff.task_groups.removed.push(entity.to_bits().get());
+            }
+        }
+
+        // Sessions are not part of the diff, their status is only compared for events.
+        let statuses = world
+            .query::<&SessionHealth>()
+            .iter()
+            .map(|(entity, health)| (entity, status_name(&health.status)))
+            .collect::<Vec<_>>();
+        for &(entity, status) in &statuses {
+            match self.session_statuses.insert(entity, status) {
+                None => events.push(LifecycleEvent::new("session", "opened", entity, now)),
+                Some(previous) if previous != status => {
+                    events.push(LifecycleEvent::new("session", status, entity, now))
+                }
+                Some(_) => {}
+            }
+        }
+        let closed = self.session_statuses.keys().filter(|e| !world.satisfies::<&SessionHealth>(**e).unwrap_or(false));
+        for entity in closed.copied().collect::<Vec<_>>() {
+            self.session_statuses.remove(&entity);
+            events.push(LifecycleEvent::new("session", "closed", entity, now));
+        }
+        drop(world);
 
-        let task_state_changes = {
-            let mut locked = self.task_state_tracker.lock().await;
-            let mut task_state_tracker = locked.track(&mut world);
-            task_state_tracker.added().len() > 0
-                || task_state_tracker.changed().count() > 0
-                || task_state_tracker.removed().len() > 0
+        // Sending only fails without subscribers.
+        for event in events {
+            self.events.send(event).ok();
+        }
+
+        if diff.tasks.is_empty() && diff.task_states.is_empty() && diff.task_groups.is_empty() {
+            return;
+        }
+
+        self.version.send_modify(|v| *v += 1);
+        diff.version = *self.version.borrow();
+
+        self.history.push_back(diff);
+        while self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+    }
+
+    async fn diff(&self, since: Option<usize>) -> DiffResponse {
+        let version = *self.version.borrow();
+
+        // Clients that are too far behind, or ahead after a restart, start over from a snapshot.
+        let oldest = self.history.front().map_or(version + 1, |diff| diff.version);
+        match since {
+            Some(since) if since <= version && (since + 1 >= oldest || since == version) => DiffResponse {
+                version,
+                resync: false,
+                diffs: self.history.iter().filter(|diff| diff.version > since).cloned().collect(),
+            },
+            _ => {
+                let world = self.world.lock().await;
+                DiffResponse {
+                    version,
+                    resync: true,
+                    diffs: vec![WorldDiff::snapshot(&world, version)],
+                }
+            }
+        }
+    }
+}
+
+async fn request_diff(handle: &InspectorHandle, since: Option<usize>) -> Option<DiffResponse> {
+    let (reply, response) = oneshot::channel();
+    handle.requests.send(InspectorRequest::Diff { since, reply }).await.ok()?;
+    response.await.ok()
+}
+
+async fn get_diff(
+    State(handle): State<InspectorHandle>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<DiffResponse>, StatusCode> {
+    // Up-to-date clients are answered from the watch channel without a round trip.
+    let version = *handle.version.borrow();
+    if query.since == Some(version) {
+        return Ok(Json(DiffResponse {
+            version,
+            resync: false,
+            diffs: vec![],
+        }));
+    }
+
+    request_diff(&handle, query.since).await.map(Json).ok_or(StatusCode::SERVICE_UNAVAILABLE)
+}
+
+async fn get_diff_stream(
+    ws: WebSocketUpgrade,
+    State(handle): State<InspectorHandle>,
+    Query(query): Query<DiffQuery>,
+) -> Response {
+    ws.on_upgrade(move |socket| stream_diffs(socket, handle, query.since))
+}
+
+// Pushes the same responses `/api/diff` would return, one whenever the version moves, so
+// clients stop polling. The first one brings the client up to date from `since`.
+async fn stream_diffs(mut socket: WebSocket, mut handle: InspectorHandle, mut since: Option<usize>) {
+    loop {
+        if since != Some(*handle.version.borrow_and_update()) {
+            let Some(response) = request_diff(&handle, since).await else {
+                break;
+            };
+            since = Some(response.version);
+            let Ok(text) = serde_json::to_string(&response) else {
+                break;
+            };
+            if socket.send(WsMessage::Text(text.into())).await.is_err() {
+                break;
+            }
+        }
+
+        tokio::select! {
+            changed = handle.version.changed() => if changed.is_err() {
+                break;
+            },
+            // Anything but a close from the client is ignored.
+            message = socket.recv() => match message {
+                Some(Ok(WsMessage::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+// Task and session lifecycle events as they happen, each a JSON object named after its event.
+// Clients too slow to keep up get a "lagged" event with the number they missed.
+async fn get_events(State(handle): State<InspectorHandle>) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let events = BroadcastStream::new(handle.events.subscribe()).map(|event| match event {
+        Ok(event) => Event::default().event(&event.event).json_data(&event),
+        Err(BroadcastStreamRecvError::Lagged(missed)) => Ok(Event::default().event("lagged").data(missed.to_string())),
+    });
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+#[derive(Serialize)]
+struct DrainStatus {
+    draining: bool,
+    in_flight: usize,
+    sessions: usize,
+}
+
+#[derive(Deserialize)]
+struct DrainRequest {
+    draining: bool,
+}
+
+fn drain_status(world: &World) -> DrainStatus {
+    DrainStatus {
+        draining: LifecycleSystem::server_mode(world) == ServerMode::Draining,
+        in_flight: LifecycleSystem::in_flight_tasks(world),
+        sessions: world.query::<&SessionHealth>().iter().count(),
+    }
+}
+
+async fn get_drain(State(world): State<Arc<Mutex<World>>>) -> Json<DrainStatus> {
+    let world = world.lock().await;
+    Json(drain_status(&world))
+}
+
+async fn set_drain(
+    State(world): State<Arc<Mutex<World>>>,
+    Json(request): Json<DrainRequest>,
+) -> Json<DrainStatus> {
+    let mut world = world.lock().await;
+    let mode = if request.draining { ServerMode::Draining } else { ServerMode::Running };
+    LifecycleSystem::set_server_mode(&mut world, mode);
+    Json(drain_status(&world))
+}
+
+#[derive(Serialize)]
+struct ClusterStatus {
+    index: usize,
+    peers: Vec<String>,
+    modules: HashMap<String, usize>,
+}
+
+async fn get_cluster(State(world): State<Arc<Mutex<World>>>) -> Json<ClusterStatus> {
+    let world = world.lock().await;
+    let shard = ClusterSystem::shard(&world).unwrap_or(ClusterShard { index: 0, peers: vec![] });
+    let modules = world
+        .query::<&Module>()
+        .iter()
+        .map(|(_, module)| (module.name.clone(), ClusterSystem::shard_of(&module.name, shard.peers.len())))
+        .collect();
+
+    Json(ClusterStatus {
+        index: shard.index,
+        peers: shard.peers,
+        modules,
+    })
+}
+
+#[derive(Serialize)]
+struct FairShareStatus {
+    weights: HashMap<String, u32>,
+    default_weight: u32,
+    in_flight: HashMap<String, usize>,
+    queued: HashMap<String, usize>,
+}
+
+#[derive(Deserialize)]
+struct FairShareRequest {
+    weights: HashMap<String, u32>,
+    default_weight: Option<u32>,
+}
+
+fn fair_share_status(world: &World) -> FairShareStatus {
+    let policy = TaskSystem::fair_share_policy(world);
+    let mut in_flight = HashMap::new();
+    let mut queued = HashMap::new();
+    for (_, (task, state, owner)) in world.query::<(&Task, &TaskState, Option<&TaskOwner>)>().iter() {
+        let counter = match state.phase {
+            _ if task.kind != TaskKind::Single => continue,
+            TaskStatePhase::Queued => &mut queued,
+            TaskStatePhase::Distributing | TaskStatePhase::Executing { .. } => &mut in_flight,
+            TaskStatePhase::Completed => continue,
         };
+        *counter.entry(owner.map(|owner| owner.tenant.clone()).unwrap_or_default()).or_insert(0) += 1;
+    }
+
+    FairShareStatus {
+        weights: policy.weights,
+        default_weight: policy.default_weight,
+        in_flight,
+        queued,
+    }
+}
+
+async fn get_fair_share(State(world): State<Arc<Mutex<World>>>) -> Json<FairShareStatus> {
+    let world = world.lock().await;
+    Json(fair_share_status(&world))
+}
+
+async fn set_fair_share(
+    State(world): State<Arc<Mutex<World>>>,
+    Json(request): Json<FairShareRequest>,
+) -> Result<Json<FairShareStatus>, StatusCode> {
+    let default_weight = request.default_weight.unwrap_or(1);
+    if default_weight == 0 || request.weights.values().any(|&weight| weight == 0) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    let mut world = world.lock().await;
+    TaskSystem::set_fair_share_policy(&mut world, FairSharePolicy {
+        weights: request.weights,
+        default_weight,
+    });
+    Ok(Json(fair_share_status(&world)))
+}
+
+#[derive(Serialize)]
+struct PauseStatus {
+    all: bool,
+    tenants: Vec<String>,
+    held: HashMap<String, usize>,
+}
+
+#[derive(Deserialize)]
+struct PauseRequest {
+    paused: bool,
+    tenant: Option<String>,
+}
+
+// `held` counts the queued tasks a pause keeps from being assigned, per tenant.
+fn pause_status(world: &World) -> PauseStatus {
+    let pause = TaskSystem::scheduling_pause(world);
+    let mut held = HashMap::new();
+    for (_, (task, state, owner)) in world.query::<(&Task, &TaskState, Option<&TaskOwner>)>().iter() {
+        let tenant = owner.map(|owner| owner.tenant.clone()).unwrap_or_default();
+        if task.kind == TaskKind::Single && state.phase == TaskStatePhase::Queued && pause.holds(&tenant) {
+            *held.entry(tenant).or_insert(0) += 1;
+        }
+    }
+    let mut tenants: Vec<_> = pause.tenants.into_iter().collect();
+    tenants.sort();
+
+    PauseStatus { all: pause.all, tenants, held }
+}
+
+async fn get_pause(State(world): State<Arc<Mutex<World>>>) -> Json<PauseStatus> {
+    let world = world.lock().await;
+    Json(pause_status(&world))
+}
+
+async fn set_pause(
+    State(world): State<Arc<Mutex<World>>>,
+    Json(request): Json<PauseRequest>,
+) -> Json<PauseStatus> {
+    let mut world = world.lock().await;
+    if request.paused {
+        TaskSystem::pause_scheduling(&mut world, request.tenant.as_deref());
+    } else {
+        TaskSystem::resume_scheduling(&mut world, request.tenant.as_deref());
+    }
+    Json(pause_status(&world))
+}
+
+#[derive(Serialize)]
+struct SpeculationStatus {
+    enabled: bool,
+    slowdown: f64,
+    max_ratio: f64,
+    active: usize,
+}
+
+#[derive(Deserialize)]
+struct SpeculationRequest {
+    enabled: Option<bool>,
+    slowdown: Option<f64>,
+    max_ratio: Option<f64>,
+}
+
+fn speculation_status(world: &World) -> SpeculationStatus {
+    let policy = TaskSystem::speculation_policy(world);
+    SpeculationStatus {
+        enabled: policy.enabled,
+        slowdown: policy.slowdown,
+        max_ratio: policy.max_ratio,
+        active: world.query::<&SpeculativeCopy>().iter().count(),
+    }
+}
+
+async fn get_speculation(State(world): State<Arc<Mutex<World>>>) -> Json<SpeculationStatus> {
+    let world = world.lock().await;
+    Json(speculation_status(&world))
+}
+
+async fn set_speculation(
+    State(world): State<Arc<Mutex<World>>>,
+    Json(request): Json<SpeculationRequest>,
+) -> Result<Json<SpeculationStatus>, StatusCode> {
+    let mut world = world.lock().await;
+    let current = TaskSystem::speculation_policy(&world);
+    let policy = SpeculationPolicy {
+        enabled: request.enabled.unwrap_or(current.enabled),
+        slowdown: request.slowdown.unwrap_or(current.slowdown),
+        max_ratio: request.max_ratio.unwrap_or(current.max_ratio),
+    };
+    if policy.slowdown < 1.0 || !(0.0..=1.0).contains(&policy.max_ratio) {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    TaskSystem::set_speculation_policy(&mut world, policy);
+    Ok(Json(speculation_status(&world)))
+}
+
+#[derive(Debug, Serialize)]
+struct LimitsStatus {
+    max_sessions: usize,
+    handshake_timeout_secs: u64,
+    retry_after_secs: u64,
+    sessions: usize,
+    handshaking: usize,
+}
+
+#[derive(Deserialize)]
+struct LimitsRequest {
+    max_sessions: Option<usize>,
+    handshake_timeout_secs: Option<u64>,
+    retry_after_secs: Option<u64>,
+}
+
+fn limits_status(world: &World) -> LimitsStatus {
+    let limits = LifecycleSystem::connection_limits(world);
+    LimitsStatus {
+        max_sessions: limits.max_sessions,
+        handshake_timeout_secs: limits.handshake_timeout.as_secs(),
+        retry_after_secs: limits.retry_after.as_secs(),
+        sessions: world.query::<&SessionHealth>().iter().count(),
+        handshaking: world.query::<&SessionHandshake>().iter().count(),
+    }
+}
+
+async fn get_limits(State(world): State<Arc<Mutex<World>>>) -> Json<LimitsStatus> {
+    let world = world.lock().await;
+    Json(limits_status(&world))
+}
+
+async fn set_limits(
+    State(world): State<Arc<Mutex<World>>>,
+    Json(request): Json<LimitsRequest>,
+) -> Result<Json<LimitsStatus>, StatusCode> {
+    let mut world = world.lock().await;
+    let current = LifecycleSystem::connection_limits(&world);
+    let limits = ConnectionLimits {
+        max_sessions: request.max_sessions.unwrap_or(current.max_sessions),
+        handshake_timeout: request
+            .handshake_timeout_secs
+            .map_or(current.handshake_timeout, Duration::from_secs),
+        retry_after: request.retry_after_secs.map_or(current.retry_after, Duration::from_secs),
+    };
+    if limits.max_sessions == 0 || limits.handshake_timeout.is_zero() {
+        return Err(StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    LifecycleSystem::set_connection_limits(&mut world, limits);
+    Ok(Json(limits_status(&world)))
+}
+
+#[derive(Debug, Serialize)]
+struct CalibrationStatus {
+    module: Option<String>,
+    calibrated: usize,
+    pending: usize,
+}
+
+#[derive(Deserialize)]
+struct CalibrationRequest {
+    // Leaving it out stops calibrating new devices, scores already taken are kept.
+    module: Option<String>,
+}
+
+fn calibration_status(world: &World) -> CalibrationStatus {
+    CalibrationStatus {
+        module: CalibrationSystem::calibration(world).map(|calibration| calibration.module),
+        calibrated: world
+            .query::<&DeviceBenchmark>()
+            .iter()
+            .filter(|(_, benchmark)| benchmark.score().is_some())
+            .count(),
+        pending: world.query::<&CalibrationTask>().iter().count(),
+    }
+}
+
+async fn get_calibration(State(world): State<Arc<Mutex<World>>>) -> Json<CalibrationStatus> {
+    Json(calibration_status(&*world.lock().await))
+}
+
+async fn set_calibration(
+    State(world): State<Arc<Mutex<World>>>,
+    Json(request): Json<CalibrationRequest>,
+) -> Result<Json<CalibrationStatus>, StatusCode> {
+    let mut world = world.lock().await;
+    if let Some(module) = &request.module {
+        if !world.query::<&Module>().iter().any(|(_, uploaded)| &uploaded.name == module) {
+            return Err(StatusCode::UNPROCESSABLE_ENTITY);
+        }
+    }
+    CalibrationSystem::set_calibration(&mut world, request.module.map(|module| Calibration { module }));
+    Ok(Json(calibration_status(&world)))
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WebhookView {
+    url: String,
+    // Defaults to a JSON object describing the event.
+    template: Option<String>,
+    // Event names such as "task.completed", empty subscribes to all of them.
+    #[serde(default)]
+    events: Vec<String>,
+}
+
+fn webhook_views(world: &World) -> Vec<WebhookView> {
+    notifier::webhooks(world)
+        .hooks
+        .into_iter()
+        .map(|hook| {
+            let mut events = hook.events.iter().map(|event| event.name().to_string()).collect::<Vec<_>>();
+            events.sort();
+            WebhookView {
+                url: hook.url,
+                template: Some(hook.template),
+                events,
+            }
+        })
+        .collect()
+}
+
+async fn get_webhooks(State(world): State<Arc<Mutex<World>>>) -> Json<Vec<WebhookView>> {
+    Json(webhook_views(&*world.lock().await))
+}
+
+// Replaces every configured webhook.
+async fn set_webhooks(
+    State(world): State<Arc<Mutex<World>>>,
+    Json(request): Json<Vec<WebhookView>>,
+) -> Result<Json<Vec<WebhookView>>, StatusCode> {
+    let hooks = request
+        .into_iter()
+        .map(|view| {
+            if !view.url.starts_with("http://") && !view.url.starts_with("https://") {
+                return None;
+            }
+            let events = match view.events.is_empty() {
+                true => NotificationEvent::ALL.into_iter().collect(),
+                false => view.events.iter().map(|event| NotificationEvent::parse(event)).collect::<Option<_>>()?,
+            };
+            Some(Webhook {
+                url: view.url,
+                template: view.template.unwrap_or_else(|| DEFAULT_TEMPLATE.into()),
+                events,
+            })
+        })
+        .collect::<Option<Vec<_>>>()
+        .ok_or(StatusCode::UNPROCESSABLE_ENTITY)?;
+
+    let mut world = world.lock().await;
+    notifier::set_webhooks(&mut world, Webhooks { hooks });
+    Ok(Json(webhook_views(&world)))
+}
+
+#[derive(Debug, Serialize)]
+struct HealthStatus {
+    healthy: bool,
+    lock_ms: Option<u64>,
+    last_tick_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct ReadyStatus {
+    ready: bool,
+    listening: Option<String>,
+    draining: bool,
+    sessions: usize,
+}
+
+#[derive(Serialize)]
+struct LogView {
+    level: LogLevel,
+    module: String,
+    message: String,
+    timestamp: u64,
+}
+
+async fn get_session_logs(
+    State(world): State<Arc<Mutex<World>>>,
+    Path(id): Path<u64>,
+) -> Result<Json<Vec<LogView>>, StatusCode> {
+    let world = world.lock().await;
+    let entity = Entity::from_bits(id)
+        .filter(|&entity| world.satisfies::<&Session>(entity).unwrap_or(false))
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let logs = world.get::<&SessionLogs>(entity).ok();
+    Ok(Json(
+        logs.iter()
+            .flat_map(|logs| logs.entries.iter())
+            .map(|entry| LogView {
+                level: entry.level,
+                module: entry.module.clone(),
+                message: entry.message.clone(),
+                timestamp: entry.timestamp,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Serialize)]
+struct TelemetryView {
+    #[serde(flatten)]
+    telemetry: Option<Telemetry>,
+    // Seconds since the telemetry arrived.
+    age_secs: Option<u64>,
+    latency_ms: u64,
+    cache: CacheStats,
+}
+
+async fn get_session_telemetry(
+    State(world): State<Arc<Mutex<World>>>,
+    Path(id): Path<u64>,
+) -> Result<Json<TelemetryView>, StatusCode> {
+    let world = world.lock().await;
+    let entity = Entity::from_bits(id).ok_or(StatusCode::NOT_FOUND)?;
+    let session = world.get::<&Session>(entity).map_err(|_| StatusCode::NOT_FOUND)?;
+    let telemetry = world.get::<&SessionTelemetry>(entity).ok();
+
+    Ok(Json(TelemetryView {
+        telemetry: telemetry.as_ref().map(|telemetry| telemetry.telemetry),
+        age_secs: telemetry.map(|telemetry| telemetry.received.elapsed().unwrap_or_default().as_secs()),
+        latency_ms: session.latency.as_millis() as u64,
+        cache: session.cache_stats.clone(),
+    }))
+}
+
+#[derive(Serialize)]
+struct SessionControlView {
+    status: String,
+    quarantined: bool,
+}
+
+fn session_control(world: &World, entity: Entity) -> Result<Json<SessionControlView>, StatusCode> {
+    let health = world.get::<&SessionHealth>(entity).map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok(Json(SessionControlView {
+        status: format!("{:?}", health.status),
+        quarantined: world.satisfies::<&SessionQuarantine>(entity).unwrap_or(false),
+    }))
+}
+
+async fn disconnect_session(
+    State(world): State<Arc<Mutex<World>>>,
+    Path(id): Path<u64>,
+) -> Result<Json<SessionControlView>, StatusCode> {
+    let mut world = world.lock().await;
+    let entity = Entity::from_bits(id).ok_or(StatusCode::NOT_FOUND)?;
+    if !LifecycleSystem::disconnect_session(&mut world, entity) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    session_control(&world, entity)
+}
 
-        if task_changes || task_state_changes {
-            self.version.send_modify(|v| *v += 1);
+#[derive(Deserialize)]
+struct QuarantineRequest {
+    quarantined: bool,
+}
+
+async fn quarantine_session(
+    State(world): State<Arc<Mutex<World>>>,
+    Path(id): Path<u64>,
+    Json(request): Json<QuarantineRequest>,
+) -> Result<Json<SessionControlView>, StatusCode> {
+    let mut world = world.lock().await;
+    let entity = Entity::from_bits(id).ok_or(StatusCode::NOT_FOUND)?;
+    if !LifecycleSystem::quarantine_session(&mut world, entity, request.quarantined) {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    session_control(&world, entity)
+}
+
+// A completed task has nothing left to stop, broadcasts and their per-device copies are bound to
+// the devices they fanned out to.
+fn task_entity(world: &World, id: u64, reassigning: bool) -> Result<Entity, StatusCode> {
+    let entity = Entity::from_bits(id).ok_or(StatusCode::NOT_FOUND)?;
+    let (Ok(task), Ok(state)) = (world.get::<&Task>(entity), world.get::<&TaskState>(entity)) else {
+        return Err(StatusCode::NOT_FOUND);
+    };
+    let bound = task.kind != TaskKind::Single || world.satisfies::<&BroadcastTarget>(entity).unwrap_or(false);
+    if state.phase == TaskStatePhase::Completed || (reassigning && bound) {
+        return Err(StatusCode::CONFLICT);
+    }
+    Ok(entity)
+}
+
+async fn cancel_task(State(world): State<Arc<Mutex<World>>>, Path(id): Path<u64>) -> StatusCode {
+    let mut world = world.lock().await;
+    match task_entity(&world, id, false) {
+        Ok(entity) => {
+            TaskSystem::cancel(&mut world, entity);
+            StatusCode::NO_CONTENT
         }
+        Err(status) => status,
     }
 }
 
-pub async fn run(world: &Arc<Mutex<World>>, addr: &str) -> Result<(), Box<dyn Error>> {
-    let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
-    let static_files_service = ServeDir::new(assets_dir).append_index_html_on_directories(true);
+#[derive(Deserialize)]
+struct ReassignQuery {
+    // Entity of the session to run on, any suitable device when left out.
+    device: Option<u64>,
+}
+
+async fn reassign_task(
+    State(world): State<Arc<Mutex<World>>>,
+    Path(id): Path<u64>,
+    Query(query): Query<ReassignQuery>,
+) -> Result<Json<TaskStateView>, StatusCode> {
+    let mut world = world.lock().await;
+    let entity = task_entity(&world, id, true)?;
+    let device = match query.device {
+        Some(device) => Some(
+            Entity::from_bits(device)
+                .filter(|&device| world.satisfies::<&SessionHealth>(device).unwrap_or(false))
+                .ok_or(StatusCode::BAD_REQUEST)?,
+        ),
+        None => None,
+    };
+    TaskSystem::reassign(&mut world, entity, device);
+    let state = world.get::<&TaskState>(entity).map_err(|_| StatusCode::NOT_FOUND)?;
+    Ok(Json(TaskStateView::new(entity, &state)))
+}
+
+async fn get_snapshot(State(world): State<Arc<Mutex<World>>>) -> Json<WorldSnapshot> {
+    let world = world.lock().await;
+    Json(snapshot(&world))
+}
+
+#[derive(Deserialize)]
+struct TimelineQuery {
+    // Seconds since the UNIX epoch.
+    at: u64,
+}
+
+#[derive(Serialize)]
+struct TimelineView {
+    // Last snapshot taken by `at`, the events replay what changed from it until then.
+    snapshot: WorldSnapshot,
+    events: Vec<SchedulerEvent>,
+}
+
+async fn get_timeline(
+    State(world): State<Arc<Mutex<World>>>,
+    Query(query): Query<TimelineQuery>,
+) -> Result<Json<TimelineView>, StatusCode> {
+    let world = world.lock().await;
+    let at = UNIX_EPOCH + Duration::from_secs(query.at);
+    let (snapshot, events) = TimelineSystem::at(&world, at).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(TimelineView { snapshot, events }))
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    // Seconds since the UNIX epoch, only later samples are returned.
+    since: Option<u64>,
+}
 
-    let listener = TcpListener::bind(addr).await?;
-    info!("Inspector server listening on: {}", listener.local_addr()?);
+#[derive(Serialize)]
+struct SessionSampleView {
+    session: u64,
+    #[serde(flatten)]
+    telemetry: Option<Telemetry>,
+    latency_ms: u64,
+    cache_bytes: u64,
+}
 
-    let state = InspectorState::new(world.clone());
+#[derive(Serialize)]
+struct MetricsSampleView {
+    timestamp: u64,
+    completed: usize,
+    queued: usize,
+    sessions: Vec<SessionSampleView>,
+}
 
-    let app = Router::new()
-        .fallback_service(static_files_service)
-        // .with_state(state)
-        .layer(CorsLayer::permissive());
+impl MetricsSampleView {
+    fn new(sample: &MetricsSample) -> Self {
+        Self {
+            timestamp: unix_secs(sample.timestamp),
+            completed: sample.completed,
+            queued: sample.queued,
+            sessions: sample
+                .sessions
+                .iter()
+                .map(|session| SessionSampleView {
+                    session: session.session.to_bits().get(),
+                    telemetry: session.telemetry,
+                    latency_ms: session.latency.as_millis() as u64,
+                    cache_bytes: session.cache_bytes,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+async fn get_metrics_history(
+    State(world): State<Arc<Mutex<World>>>,
+    Query(query): Query<HistoryQuery>,
+) -> Json<Vec<MetricsSampleView>> {
+    let world = world.lock().await;
+    let mut histories = world.query::<&MetricsHistory>();
+    Json(
+        histories
+            .iter()
+            .flat_map(|(_, history)| history.samples.iter())
+            .filter(|sample| query.since.is_none_or(|since| unix_secs(sample.timestamp) > since))
+            .map(MetricsSampleView::new)
+            .collect(),
+    )
+}
+
+#[derive(Deserialize)]
+struct ResultsQuery {
+    module: Option<String>,
+    // Seconds since the UNIX epoch, only later results are returned.
+    since: Option<u64>,
+    // The `next` of the previous page.
+    after: Option<i64>,
+    limit: Option<usize>,
+}
+
+// 404 when the server keeps no result store, see `RESULTS_DB`.
+async fn get_results(
+    State(world): State<Arc<Mutex<World>>>,
+    Query(query): Query<ResultsQuery>,
+) -> Result<Json<ResultPage>, StatusCode> {
+    let store = ResultStore::get(&*world.lock().await).ok_or(StatusCode::NOT_FOUND)?;
+    let query = ResultQuery {
+        module: query.module,
+        since: query.since,
+        after: query.after,
+        limit: query.limit.unwrap_or(100),
+    };
+    let page = tokio::task::spawn_blocking(move || store.query(&query))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(page))
+}
+
+#[derive(Deserialize)]
+struct ExportQuery {
+    format: ExportFormat,
+    module: Option<String>,
+    since: Option<u64>,
+}
+
+// Downloads every matching result as one file, e.g. for `pandas.read_parquet`.
+async fn export_results(
+    State(world): State<Arc<Mutex<World>>>,
+    Query(query): Query<ExportQuery>,
+) -> Result<([(header::HeaderName, String); 2], Vec<u8>), StatusCode> {
+    let store = ResultStore::get(&*world.lock().await).ok_or(StatusCode::NOT_FOUND)?;
+    let (format, results) = (query.format, ResultQuery { module: query.module, since: query.since, ..Default::default() });
+    let (file, _) = tokio::task::spawn_blocking(move || export(&store, &results, format))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (header::CONTENT_DISPOSITION, format!("attachment; filename=\"results.{}\"", format.extension())),
+    ];
+    Ok((headers, file))
+}
+
+fn status_code(ok: bool) -> StatusCode {
+    if ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE }
+}
+
+// A lock that cannot be taken in time means a system is stuck while holding the world.
+async fn lock_world(world: &Arc<Mutex<World>>) -> Option<(MutexGuard<'_, World>, Duration)> {
+    let started = Instant::now();
+    let world = tokio::time::timeout(LOCK_TIMEOUT, world.lock()).await.ok()?;
+    Some((world, started.elapsed()))
+}
+
+async fn get_healthz(State(world): State<Arc<Mutex<World>>>) -> (StatusCode, Json<HealthStatus>) {
+    let Some((world, waited)) = lock_world(&world).await else {
+        let status = HealthStatus {
+            healthy: false,
+            lock_ms: None,
+            last_tick_ms: None,
+        };
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(status));
+    };
+
+    let last_tick = LifecycleSystem::dispatcher_status(&world)
+        .map(|status| status.last_tick.elapsed().unwrap_or_default());
+    let status = HealthStatus {
+        healthy: last_tick.is_none_or(|elapsed| elapsed < STALL_TIMEOUT),
+        lock_ms: Some(waited.as_millis() as u64),
+        last_tick_ms: last_tick.map(|elapsed| elapsed.as_millis() as u64),
+    };
+    (status_code(status.healthy), Json(status))
+}
 
-    axum::serve(listener, app).await?;
+async fn get_readyz(State(world): State<Arc<Mutex<World>>>) -> (StatusCode, Json<ReadyStatus>) {
+    let Some((world, _)) = lock_world(&world).await else {
+        let status = ReadyStatus {
+            ready: false,
+            listening: None,
+            draining: false,
+            sessions: 0,
+        };
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(status));
+    };
+
+    let listening = LifecycleSystem::dispatcher_status(&world).map(|status| status.addr.to_string());
+    let draining = LifecycleSystem::server_mode(&world) == ServerMode::Draining;
+    let sessions = world
+        .query::<&SessionHealth>()
+        .iter()
+        .filter(|(_, health)| matches!(health.status, SessionStatus::Connected | SessionStatus::Occupied))
+        .count();
+    let status = ReadyStatus {
+        ready: listening.is_some() && !draining,
+        listening,
+        draining,
+        sessions,
+    };
+    (status_code(status.ready), Json(status))
+}
+
+// Turns a captured frame, length header included, into the JSON form of its message.
+async fn decode_frame(frame: Bytes) -> Result<([(header::HeaderName, &'static str); 1], String), StatusCode> {
+    let (message, _) = Message::decode(&frame).map_err(|_| StatusCode::BAD_REQUEST)?;
+    let json = message.encode_json().map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(([(header::CONTENT_TYPE, "application/json")], json))
+}
+
+// The inspector API and dashboard as a router of their own, so an application can nest them in
+// its axum app rather than give the inspector a port.
+pub struct InspectorRouter;
+
+impl InspectorRouter {
+    #[allow(clippy::new_ret_no_self)]
+    pub fn new(handle: InspectorHandle) -> Router {
+        let assets_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("assets");
+        let static_files_service = ServeDir::new(assets_dir).append_index_html_on_directories(true);
+
+        Router::new()
+            .route("/api/diff", get(get_diff))
+            .route("/api/diff/ws", get(get_diff_stream))
+            .route("/api/events", get(get_events))
+            .route("/api/drain", get(get_drain).post(set_drain))
+            .route("/api/cluster", get(get_cluster))
+            .route("/api/snapshot", get(get_snapshot))
+            .route("/api/timeline", get(get_timeline))
+            .route("/api/speculation", get(get_speculation).post(set_speculation))
+            .route("/api/fairshare", get(get_fair_share).post(set_fair_share))
+            .route("/api/pause", get(get_pause).post(set_pause))
+            .route("/api/limits", get(get_limits).post(set_limits))
+            .route("/api/calibration", get(get_calibration).post(set_calibration))
+            .route("/api/webhooks", get(get_webhooks).post(set_webhooks))
+            .route("/api/sessions/{id}/logs", get(get_session_logs))
+            .route("/api/sessions/{id}/telemetry", get(get_session_telemetry))
+            .route("/api/sessions/{id}/disconnect", post(disconnect_session))
+            .route("/api/sessions/{id}/quarantine", post(quarantine_session))
+            .route("/api/tasks/{id}/cancel", post(cancel_task))
+            .route("/api/tasks/{id}/reassign", post(reassign_task))
+            .route("/api/metrics/history", get(get_metrics_history))
+            .route("/api/results", get(get_results))
+            .route("/api/results/export", get(export_results))
+            .route("/api/protocol/decode", post(decode_frame))
+            .route("/healthz", get(get_healthz))
+            .route("/readyz", get(get_readyz))
+            .with_state(handle)
+            .fallback_service(static_files_service)
+            .layer(CorsLayer::permissive())
+    }
+}
+
+pub async fn run(world: &Arc<Mutex<World>>, addrs: &[SocketAddr], tls: Option<TlsAcceptor>) -> Result<(), Error> {
+    let listeners = addrs
+        .iter()
+        .map(|addr| bind(*addr).context(format!("binding inspector to {}", addr)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let handle = InspectorHandle::spawn(world).context("starting inspector state")?;
+    let app = InspectorRouter::new(handle);
+
+    let mut servers = Vec::with_capacity(listeners.len());
+    for listener in listeners {
+        let addr = listener.local_addr().context("reading inspector address")?;
+        info!("Inspector server listening on: {}{}", addr, if tls.is_some() { " (TLS)" } else { "" });
+        servers.push(match &tls {
+            Some(acceptor) => {
+                let listener = TlsListener { listener, acceptor: acceptor.clone() };
+                axum::serve(listener, app.clone()).into_future().boxed()
+            }
+            None => axum::serve(listener, app.clone()).into_future().boxed(),
+        });
+    }
+    futures::future::try_join_all(servers).await.context("serving inspector")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use axum::response::IntoResponse;
+    use protocol::SessionStats;
+
+    use super::*;
+    use crate::results::ResultRecord;
+    use crate::systems::MetricsSystem;
+
+    #[tokio::test]
+    async fn test_diff_updates() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let (version, _) = watch::channel(0);
+        let (events, _) = broadcast::channel(HISTORY_LEN);
+        let mut state = InspectorState::new(world.clone(), version, events);
+
+        let task = {
+            let mut world = world.lock().await;
+            let module = world.spawn((Module {
+                name: "mock_module".into(),
+                binary: vec![0u8; 16],
+                dependencies: vec![],
+                chunk_size: 16,
+                pinned: false,
+            },));
+            let member = TaskSystem::join_group(&mut world, "mock_sweep");
+            world.spawn((
+                Task {
+                    name: "mock_task".into(),
+                    params: vec![],
+                    env: vec![],
+                    result: vec![],
+                    created_at: SystemTime::now(),
+                    require_module: module,
+                    priority: 1,
+                    kind: TaskKind::Single,
+                },
+                TaskState {
+                    phase: TaskStatePhase::Queued,
+                    assigned_device: None,
+                    results: HashMap::new(),
+                    progress: None,
+                    transfer: None,
+                    attempt: 0,
+                },
+                member,
+            ))
+        };
+        state.trigger_updates().await;
+        state.trigger_updates().await;
+
+        {
+            let mut world = world.lock().await;
+            world.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+            TaskSystem::track_groups(&mut world);
+        }
+        state.trigger_updates().await;
+
+        let response = state.diff(Some(1)).await;
+        assert_eq!(response.version, 2);
+        assert!(!response.resync);
+        assert_eq!(response.diffs.len(), 1);
+        assert!(response.diffs[0].tasks.is_empty());
+        assert_eq!(response.diffs[0].task_states.changed[0].phase, "completed");
+        let group = &response.diffs[0].task_groups.changed[0];
+        assert_eq!((group.status.as_str(), group.completed, group.percent), ("completed", 1, 100));
+
+        world.lock().await.despawn(task).unwrap();
+        state.trigger_updates().await;
+        let response = state.diff(Some(2)).await;
+        assert_eq!(response.diffs[0].tasks.removed, vec![task.to_bits().get()]);
+
+        let response = state.diff(None).await;
+        assert!(response.resync);
+        assert!(response.diffs[0].tasks.added.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lifecycle_events() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let (version, _) = watch::channel(0);
+        let (events, mut received) = broadcast::channel(HISTORY_LEN);
+        let mut state = InspectorState::new(world.clone(), version, events);
+        let mut next = || received.try_recv().ok().map(|event: LifecycleEvent| event.event);
+
+        let (task, session) = {
+            let mut world = world.lock().await;
+            let module = world.spawn((Module {
+                name: "mock_module".into(),
+                binary: vec![0u8; 16],
+                dependencies: vec![],
+                chunk_size: 16,
+                pinned: false,
+            },));
+            let task = world.spawn((
+                Task {
+                    name: "mock_task".into(),
+                    params: vec![],
+                    env: vec![],
+                    result: vec![],
+                    created_at: SystemTime::now(),
+                    require_module: module,
+                    priority: 1,
+                    kind: TaskKind::Single,
+                },
+                TaskState {
+                    phase: TaskStatePhase::Queued,
+                    assigned_device: None,
+                    results: HashMap::new(),
+                    progress: None,
+                    transfer: None,
+                    attempt: 0,
+                },
+            ));
+            let session = world.spawn((SessionHealth {
+                retries: 0,
+                status: SessionStatus::Connected,
+                last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
+            },));
+            (task, session)
+        };
+        state.trigger_updates().await;
+        assert_eq!(next().as_deref(), Some("task.queued"));
+        assert_eq!(next().as_deref(), Some("session.opened"));
+        assert_eq!(next(), None);
+
+        {
+            let world = world.lock().await;
+            world.get::<&mut TaskState>(task).unwrap().progress = Some(TaskProgress {
+                percent: 50,
+                stage: "transfer".into(),
+                updated: SystemTime::now(),
+            });
+            world.get::<&mut SessionHealth>(session).unwrap().status = SessionStatus::Occupied;
+        }
+        state.trigger_updates().await;
+        assert_eq!(next().as_deref(), Some("session.occupied"));
+        assert_eq!(next(), None);
+
+        {
+            let mut world = world.lock().await;
+            world.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+            world.despawn(session).unwrap();
+        }
+        state.trigger_updates().await;
+        assert_eq!(next().as_deref(), Some("task.completed"));
+        assert_eq!(next().as_deref(), Some("session.closed"));
+
+        world.lock().await.despawn(task).unwrap();
+        state.trigger_updates().await;
+        let event = received.try_recv().unwrap();
+        assert_eq!((event.event.as_str(), event.entity), ("task.removed", task.to_bits().get()));
+    }
+
+    #[tokio::test]
+    async fn test_events_endpoint() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let handle = InspectorState::spawn(&world).unwrap();
+        let response = get_events(State(handle.clone())).await.into_response();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+        handle.events.send(LifecycleEvent::new("task", "queued", Entity::DANGLING, UNIX_EPOCH)).unwrap();
+        let mut body = response.into_body().into_data_stream();
+        let chunk = tokio::time::timeout(Duration::from_secs(1), body.next()).await.unwrap().unwrap().unwrap();
+        let text = String::from_utf8(chunk.to_vec()).unwrap();
+        assert!(text.starts_with("event: task.queued\ndata: {\"event\":\"task.queued\""), "{}", text);
+    }
+
+    #[tokio::test]
+    async fn test_inspector_handle() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let handle = InspectorState::spawn(&world).unwrap();
+
+        let Json(response) = get_diff(State(handle.clone()), Query(DiffQuery { since: Some(0) })).await.unwrap();
+        assert_eq!(response.version, 0);
+        assert!(!response.resync);
+        assert_eq!(*handle.version.borrow(), 0);
+
+        let Json(response) = get_diff(State(handle), Query(DiffQuery { since: None })).await.unwrap();
+        assert!(response.resync);
+        assert_eq!(response.diffs.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_session_logs() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let session = world.lock().await.spawn((
+            Session {
+                message_queue: VecDeque::new(),
+                latency: Duration::default(),
+                cache_stats: Default::default(),
+            },
+            SessionLogs::default(),
+        ));
+        for i in 0..SessionLogs::CAPACITY + 1 {
+            world.lock().await.get::<&mut SessionLogs>(session).unwrap().push(LogEntry {
+                level: LogLevel::Info,
+                module: "sensor".into(),
+                message: format!("reading {}", i),
+                timestamp: i as u64,
+            });
+        }
+
+        let id = session.to_bits().get();
+        let Json(logs) = get_session_logs(State(world.clone()), Path(id)).await.unwrap();
+        assert_eq!(logs.len(), SessionLogs::CAPACITY);
+        assert_eq!(logs[0].message, "reading 1");
+
+        let missing = get_session_logs(State(world.clone()), Path(id + 1)).await;
+        assert!(matches!(missing, Err(StatusCode::NOT_FOUND)));
+    }
+
+    #[tokio::test]
+    async fn test_session_telemetry() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let session = world.lock().await.spawn((Session {
+            message_queue: VecDeque::new(),
+            latency: Duration::from_millis(12),
+            cache_stats: Default::default(),
+        },));
+        let id = session.to_bits().get();
+
+        let Json(view) = get_session_telemetry(State(world.clone()), Path(id)).await.unwrap();
+        assert!(view.telemetry.is_none());
+        assert_eq!(view.latency_ms, 12);
+
+        let telemetry = Telemetry {
+            free_heap: Some(1024),
+            tasks_executed: 3,
+            uptime_secs: 60,
+            rssi: Some(-70),
+            sequence: None,
+            session: SessionStats {
+                decode_errors: 2,
+                ..Default::default()
+            },
+        };
+        world.lock().await.insert_one(session, SessionTelemetry {
+            telemetry,
+            received: SystemTime::now(),
+        }).unwrap();
+        let Json(view) = get_session_telemetry(State(world.clone()), Path(id)).await.unwrap();
+        let json = serde_json::to_value(&view).unwrap();
+        assert_eq!(json["rssi"], -70);
+        assert_eq!(json["tasks_executed"], 3);
+        assert_eq!(json["session"]["decode_errors"], 2);
+    }
+
+    #[tokio::test]
+    async fn test_session_control() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let session = world.lock().await.spawn((SessionHealth {
+            retries: 0,
+            status: SessionStatus::Occupied,
+            last_heartbeat: SystemTime::now(),
+            protocol_errors: 0,
+            last_protocol_error: None,
+            sequence: None,
+        },));
+        let id = session.to_bits().get();
+
+        let request = QuarantineRequest { quarantined: true };
+        let Json(view) = quarantine_session(State(world.clone()), Path(id), Json(request)).await.unwrap();
+        assert_eq!((view.status.as_str(), view.quarantined), ("Occupied", true));
+
+        let Json(view) = disconnect_session(State(world.clone()), Path(id)).await.unwrap();
+        assert_eq!(view.status, "Zombie");
+
+        let missing = disconnect_session(State(world.clone()), Path(id + 1)).await;
+        assert!(matches!(missing, Err(StatusCode::NOT_FOUND)));
+    }
+
+    #[tokio::test]
+    async fn test_task_control() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let task = {
+            let mut world = world.lock().await;
+            let module = world.spawn((Module {
+                name: "mock_module".into(),
+                binary: vec![0u8; 16],
+                dependencies: vec![],
+                chunk_size: 16,
+                pinned: false,
+            },));
+            world.spawn((
+                Task {
+                    name: "mock_task".into(),
+                    params: vec![],
+                    env: vec![],
+                    result: vec![],
+                    created_at: SystemTime::now(),
+                    require_module: module,
+                    priority: 1,
+                    kind: TaskKind::Single,
+                },
+                TaskState {
+                    phase: TaskStatePhase::Queued,
+                    assigned_device: None,
+                    results: HashMap::new(),
+                    progress: None,
+                    transfer: None,
+                    attempt: 0,
+                },
+            ))
+        };
+        let id = task.to_bits().get();
+
+        let unknown = reassign_task(State(world.clone()), Path(id), Query(ReassignQuery { device: Some(id) })).await;
+        assert!(matches!(unknown, Err(StatusCode::BAD_REQUEST)));
+        let Json(view) = reassign_task(State(world.clone()), Path(id), Query(ReassignQuery { device: None })).await.unwrap();
+        assert_eq!(view.phase, "queued");
+
+        world.lock().await.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+        assert_eq!(cancel_task(State(world.clone()), Path(id)).await, StatusCode::CONFLICT);
+        world.lock().await.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Queued;
+        assert_eq!(cancel_task(State(world.clone()), Path(id)).await, StatusCode::NO_CONTENT);
+        assert!(!world.lock().await.contains(task));
+        assert_eq!(cancel_task(State(world.clone()), Path(id)).await, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_timeline() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let now = SystemTime::now();
+        TimelineSystem::record(&mut *world.lock().await, now);
+
+        let at = unix_secs(now) + 1;
+        let Json(view) = get_timeline(State(world.clone()), Query(TimelineQuery { at })).await.unwrap();
+        assert_eq!(view.snapshot.taken_at, now);
+        let before = get_timeline(State(world.clone()), Query(TimelineQuery { at: at - 60 })).await;
+        assert!(matches!(before, Err(StatusCode::NOT_FOUND)));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_history() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let Json(samples) = get_metrics_history(State(world.clone()), Query(HistoryQuery { since: None })).await;
+        assert!(samples.is_empty());
+
+        let start = UNIX_EPOCH + Duration::from_secs(1000);
+        {
+            let mut world = world.lock().await;
+            world.spawn((Session {
+                message_queue: VecDeque::new(),
+                latency: Duration::from_millis(12),
+                cache_stats: Default::default(),
+            },));
+            MetricsSystem::record(&mut world, start);
+            MetricsSystem::record(&mut world, start + Duration::from_secs(60));
+        }
+
+        let Json(samples) = get_metrics_history(State(world.clone()), Query(HistoryQuery { since: None })).await;
+        assert_eq!(samples.len(), 2);
+        let json = serde_json::to_value(&samples[0]).unwrap();
+        assert_eq!(json["timestamp"], 1000);
+        assert_eq!(json["sessions"][0]["latency_ms"], 12);
+
+        let Json(samples) = get_metrics_history(State(world.clone()), Query(HistoryQuery { since: Some(1000) })).await;
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].timestamp, 1060);
+    }
+
+    #[tokio::test]
+    async fn test_results() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let query = || ResultsQuery { module: Some("sum".into()), since: None, after: None, limit: Some(1) };
+        let response = get_results(State(world.clone()), Query(query())).await;
+        assert_eq!(response.err(), Some(StatusCode::NOT_FOUND));
+
+        let store = ResultStore::open_in_memory().unwrap();
+        for (task_id, module) in [(1, "sum"), (2, "blink"), (3, "sum")] {
+            let record = ResultRecord {
+                id: 0,
+                task_id,
+                attempt: 1,
+                module: module.into(),
+                name: "task".into(),
+                params: vec![],
+                result: vec![Type::I32(task_id as i32)],
+                device: 7,
+                executor: "Jit".into(),
+                arch: "x86_64".into(),
+                wall_time_us: 10,
+                peak_memory: 0,
+                instructions: None,
+                completed_at: 1000,
+            };
+            store.record(&record).unwrap();
+        }
+        ResultStore::set(&mut *world.lock().await, store);
+
+        let Json(page) = get_results(State(world.clone()), Query(query())).await.unwrap();
+        assert_eq!(page.results[0].task_id, 1);
+        let after = ResultsQuery { after: page.next, ..query() };
+        let Json(page) = get_results(State(world.clone()), Query(after)).await.unwrap();
+        assert_eq!(page.results[0].task_id, 3);
+        assert_eq!(page.next, None);
+        let json = serde_json::to_value(&page).unwrap();
+        assert_eq!(json["results"][0]["result"][0]["I32"], 3);
+
+        let query = ExportQuery { format: ExportFormat::Csv, module: Some("sum".into()), since: None };
+        let (headers, file) = export_results(State(world.clone()), Query(query)).await.unwrap();
+        assert_eq!(headers[0].1, "text/csv");
+        assert_eq!(String::from_utf8(file).unwrap().lines().count(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_webhooks() {
+        let world = Arc::new(Mutex::new(World::new()));
+        let Json(hooks) = get_webhooks(State(world.clone())).await;
+        assert!(hooks.is_empty());
+
+        let view = |url: &str, events: &[&str]| WebhookView {
+            url: url.into(),
+            template: None,
+            events: events.iter().map(|event| event.to_string()).collect(),
+        };
+        let invalid = set_webhooks(State(world.clone()), Json(vec![view("http://ci/hook", &["task.started"])])).await;
+        assert_eq!(invalid.err(), Some(StatusCode::UNPROCESSABLE_ENTITY));
+        let invalid = set_webhooks(State(world.clone()), Json(vec![view("ci/hook", &[])])).await;
+        assert_eq!(invalid.err(), Some(StatusCode::UNPROCESSABLE_ENTITY));
+
+        let request = vec![view("http://ci/hook", &[]), view("https://chat/hook", &["group.completed"])];
+        let Json(hooks) = set_webhooks(State(world.clone()), Json(request)).await.unwrap();
+        assert_eq!(hooks[0].events, vec!["group.completed", "task.completed", "task.failed"]);
+        assert_eq!(hooks[0].template.as_deref(), Some(DEFAULT_TEMPLATE));
+        assert_eq!(hooks[1].events, vec!["group.completed"]);
+        assert_eq!(notifier::webhooks(&*world.lock().await).hooks.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_health_endpoints() {
+        let world = Arc::new(Mutex::new(World::new()));
+
+        let (code, Json(health)) = get_healthz(State(world.clone())).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(health.last_tick_ms.is_none());
+        let (code, Json(ready)) = get_readyz(State(world.clone())).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(ready.listening.is_none());
+
+        LifecycleSystem::record_dispatcher_tick(&mut *world.lock().await, "127.0.0.1:3030".parse().unwrap());
+        let (code, Json(ready)) = get_readyz(State(world.clone())).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(ready.listening.as_deref(), Some("127.0.0.1:3030"));
+
+        LifecycleSystem::set_server_mode(&mut *world.lock().await, ServerMode::Draining);
+        let (code, Json(ready)) = get_readyz(State(world.clone())).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(ready.draining);
+
+        {
+            let mut world = world.lock().await;
+            let (_, status) = world.query_mut::<&mut DispatcherStatus>().into_iter().next().unwrap();
+            status.last_tick -= STALL_TIMEOUT;
+        }
+        let (code, Json(health)) = get_healthz(State(world.clone())).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(!health.healthy);
+
+        let _held = world.lock().await;
+        let (code, Json(health)) = get_healthz(State(world.clone())).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(health.lock_ms.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_decode_frame() {
+        let message = Message::ServerCancel { task_id: protocol::TaskId(7) };
+        let (_, json) = decode_frame(Bytes::from(message.encode().unwrap())).await.unwrap();
+        assert_eq!(Message::decode_json(&json).unwrap(), message);
+
+        assert_eq!(decode_frame(Bytes::from_static(&[0, 4, 1])).await.unwrap_err(), StatusCode::BAD_REQUEST);
+    }
+}
@@ -1,34 +1,452 @@
+mod compiler;
 mod components;
+mod control;
 mod dispatcher;
+mod error;
+mod export;
 mod inspector;
+mod listen;
+mod notifier;
+mod replication;
+mod results;
+mod snapshot;
 mod systems;
 
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 
 use hecs::World;
-use tokio::sync::Mutex;
+use log::{info, warn};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::{Id, JoinHandle, JoinSet};
+use tokio_rustls::TlsAcceptor;
+
+use crate::error::Context;
 
 pub use crate::components::*;
+pub use crate::dispatcher::DispatcherService;
+pub use crate::error::Error;
+pub use crate::export::{export, ExportFormat};
+pub use crate::inspector::{InspectorHandle, InspectorRouter};
+pub use crate::listen::{ListenAddrs, TlsConfig};
+pub use crate::results::{ResultPage, ResultQuery, ResultRecord, ResultStore};
+pub use crate::snapshot::{snapshot, ModuleSnapshot, SessionSnapshot, TaskSnapshot, TransferSnapshot, WorldSnapshot};
 pub use crate::systems::*;
 
-pub async fn run(host: &str, ports: &[u16]) {
-    let inspector_addr = format!("{}:{}", host, ports[0]);
-    let dispatcher_addr = format!("{}:{}", host, ports[1]);
+// Services of one server, aborted together once the set is shut down or dropped so a stopped
+// server frees its ports.
+#[derive(Default)]
+struct Services {
+    tasks: JoinSet<Result<(), Error>>,
+    names: HashMap<Id, &'static str>,
+}
+
+impl Services {
+    fn spawn(&mut self, service: &'static str, future: impl Future<Output = Result<(), Error>> + Send + 'static) {
+        let handle = self.tasks.spawn(future);
+        self.names.insert(handle.id(), service);
+    }
+
+    fn spawn_inspector(&mut self, world: &Arc<Mutex<World>>, addrs: &[SocketAddr], tls: Option<TlsAcceptor>) {
+        let (inspector_world, inspector_addrs) = (Arc::clone(world), addrs.to_vec());
+        self.spawn("inspector", async move { inspector::run(&inspector_world, &inspector_addrs, tls).await });
+    }
+
+    fn spawn_dispatcher(&mut self, world: &Arc<Mutex<World>>, addrs: &[SocketAddr], modules_dir: Option<PathBuf>) {
+        let (dispatcher_world, dispatcher_addrs) = (Arc::clone(world), addrs.to_vec());
+        self.spawn("dispatcher", async move { dispatcher::run(&dispatcher_world, &dispatcher_addrs, modules_dir).await });
+    }
+
+    fn spawn_replication(&mut self, world: &Arc<Mutex<World>>, addr: Option<SocketAddr>) {
+        if let Some(addr) = addr {
+            let replication_world = Arc::clone(world);
+            self.spawn("replication", async move { replication::serve(&replication_world, addr).await });
+        }
+    }
+
+    fn spawn_control(&mut self, world: &Arc<Mutex<World>>, addr: Option<SocketAddr>) {
+        if let Some(addr) = addr {
+            let control_world = Arc::clone(world);
+            self.spawn("control", async move { control::run(&control_world, addr).await });
+        }
+    }
+
+    fn spawn_background(&mut self, world: &Arc<Mutex<World>>) {
+        let (compiler_world, notifier_world) = (Arc::clone(world), Arc::clone(world));
+        self.spawn("compiler", async move {
+            compiler::run(&compiler_world).await;
+            Ok(())
+        });
+        self.spawn("notifier", async move {
+            notifier::run(&notifier_world).await;
+            Ok(())
+        });
+    }
+
+    // Waits on every service and returns the first failure, a panicking service counts as failed.
+    async fn supervise(&mut self) -> Result<(), Error> {
+        while let Some(joined) = self.tasks.join_next().await {
+            joined.map_err(|e| Error::Service {
+                service: self.names.get(&e.id()).copied().unwrap_or("service"),
+                reason: e.to_string(),
+            })??;
+        }
+        Ok(())
+    }
+}
+
+// Opens the SQLite file completed results are kept in, without one the server keeps none.
+fn attach_result_store(world: &mut World, path: Option<&PathBuf>) -> Result<(), Error> {
+    if let Some(path) = path {
+        let store = ResultStore::open(path).context(format!("opening result store {}", path.display()))?;
+        ResultStore::set(world, store);
+        info!("Storing results in {}", path.display());
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+enum Role {
+    #[default]
+    Primary,
+    Shard(ClusterShard),
+    // Follows the primary at the address until it is lost, then takes over its dispatcher.
+    Standby(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ServerBuilder {
+    addrs: ListenAddrs,
+    settings: ServerSettings,
+    limits: ConnectionLimits,
+    fair_share: FairSharePolicy,
+    speculation: SpeculationPolicy,
+    results_db: Option<PathBuf>,
+    modules_dir: Option<PathBuf>,
+    tls: Option<TlsConfig>,
+    role: Role,
+}
+
+impl ServerBuilder {
+    pub fn listen(mut self, addrs: ListenAddrs) -> Self {
+        self.addrs = addrs;
+        self
+    }
+
+    pub fn chunk_size(mut self, chunk_size: u32) -> Self {
+        self.settings.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.idle_timeout = timeout;
+        self
+    }
+
+    pub fn heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.heartbeat_timeout = timeout;
+        self
+    }
+
+    pub fn execution_timeout(mut self, timeout: Duration) -> Self {
+        self.settings.execution_timeout = timeout;
+        self
+    }
+
+    pub fn connection_limits(mut self, limits: ConnectionLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn fair_share(mut self, policy: FairSharePolicy) -> Self {
+        self.fair_share = policy;
+        self
+    }
+
+    pub fn speculation(mut self, policy: SpeculationPolicy) -> Self {
+        self.speculation = policy;
+        self
+    }
+
+    pub fn results_db(mut self, path: impl Into<PathBuf>) -> Self {
+        self.results_db = Some(path.into());
+        self
+    }
+
+    // Where the bundled module binaries are read from when the task crate is built with its `lazy`
+    // feature, see task::load_modules.
+    pub fn modules_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.modules_dir = Some(path.into());
+        self
+    }
+
+    pub fn tls(mut self, tls: TlsConfig) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    pub fn metrics(mut self, enabled: bool) -> Self {
+        self.settings.metrics = enabled;
+        self
+    }
+
+    pub fn bundled_tasks(mut self, enabled: bool) -> Self {
+        self.settings.bundled_tasks = enabled;
+        self
+    }
+
+    pub fn shard(mut self, shard: ClusterShard) -> Self {
+        self.role = Role::Shard(shard);
+        self
+    }
+
+    pub fn standby(mut self, primary: impl Into<String>) -> Self {
+        self.role = Role::Standby(primary.into());
+        self
+    }
+
+    // Loads the TLS files and opens the result store, so a bad path fails here rather than once
+    // the services are up. A standby opens its store only when it takes over.
+    pub fn build(self) -> Result<Server, Error> {
+        let tls = self
+            .tls
+            .as_ref()
+            .map(|tls| tls.acceptor().context(format!("loading TLS certificates {}", tls.certificates.display())))
+            .transpose()?;
+
+        let mut world = World::new();
+        LifecycleSystem::set_settings(&mut world, self.settings);
+        LifecycleSystem::set_connection_limits(&mut world, self.limits);
+        TaskSystem::set_fair_share_policy(&mut world, self.fair_share);
+        TaskSystem::set_speculation_policy(&mut world, self.speculation);
+        match &self.role {
+            Role::Primary => attach_result_store(&mut world, self.results_db.as_ref())?,
+            Role::Shard(shard) => {
+                world.spawn((shard.clone(),));
+                attach_result_store(&mut world, self.results_db.as_ref())?;
+            }
+            Role::Standby(_) => {}
+        }
+
+        Ok(Server {
+            world: Arc::new(Mutex::new(world)),
+            addrs: self.addrs,
+            tls,
+            results_db: self.results_db,
+            modules_dir: self.modules_dir,
+            role: self.role,
+            running: None,
+        })
+    }
+}
+
+// Supervisor of a started server's services, they are shut down once `stop` fires or is dropped.
+struct Running {
+    supervisor: JoinHandle<Result<(), Error>>,
+    stop: oneshot::Sender<()>,
+}
+
+// A configured server, its services run from `start` until `shutdown` or until the server is
+// dropped.
+pub struct Server {
+    world: Arc<Mutex<World>>,
+    addrs: ListenAddrs,
+    tls: Option<TlsAcceptor>,
+    results_db: Option<PathBuf>,
+    modules_dir: Option<PathBuf>,
+    role: Role,
+    running: Option<Running>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    // Shared with every service, an embedding application locks it to read or seed the state.
+    pub fn world(&self) -> &Arc<Mutex<World>> {
+        &self.world
+    }
+
+    // Spawns the services on the current runtime, a server already started is left as it is.
+    pub fn start(&mut self) {
+        if self.running.is_some() {
+            return;
+        }
+        let (world, addrs, tls) = (Arc::clone(&self.world), self.addrs.clone(), self.tls.clone());
+        let (results_db, modules_dir, role) = (self.results_db.clone(), self.modules_dir.clone(), self.role.clone());
+        let (stop, stopped) = oneshot::channel();
+
+        let supervisor = tokio::spawn(async move {
+            let mut services = Services::default();
+            let result = tokio::select! {
+                result = serve(&mut services, &world, &addrs, tls, results_db, modules_dir, &role) => result,
+                _ = stopped => Ok(()),
+            };
+            services.tasks.shutdown().await;
+            result
+        });
+        self.running = Some(Running { supervisor, stop });
+    }
+
+    // Resolves with the first service failure, or right away when the server was not started.
+    pub async fn wait(&mut self) -> Result<(), Error> {
+        let Some(running) = &mut self.running else {
+            return Ok(());
+        };
+        let result = (&mut running.supervisor).await.map_err(|e| Error::Service { service: "server", reason: e.to_string() });
+        self.running = None;
+        result?
+    }
+
+    // Stops every service and returns once their listeners are closed.
+    pub async fn shutdown(&mut self) {
+        if let Some(running) = self.running.take() {
+            running.stop.send(()).ok();
+            running.supervisor.await.ok();
+            info!("Server stopped");
+        }
+    }
+}
+
+async fn serve(
+    services: &mut Services,
+    world: &Arc<Mutex<World>>,
+    addrs: &ListenAddrs,
+    tls: Option<TlsAcceptor>,
+    results_db: Option<PathBuf>,
+    modules_dir: Option<PathBuf>,
+    role: &Role,
+) -> Result<(), Error> {
+    services.spawn_inspector(world, &addrs.inspector, tls);
+
+    if let Role::Standby(primary) = role {
+        const FAILOVER_RETRIES: u8 = 3;
+
+        // Only consecutive failures to reach the primary count, a connection made resets them.
+        let mut failures = 0;
+        while failures < FAILOVER_RETRIES {
+            match replication::connect(primary).await {
+                Ok(stream) => {
+                    failures = 0;
+                    match replication::follow(world, stream, primary).await {
+                        Ok(()) => warn!("Primary {} closed replication stream", primary),
+                        Err(e) => warn!("Primary {} dropped replication stream: {}", primary, e),
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    warn!("Primary {} unreachable: {}", primary, e);
+                }
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        warn!("Primary {} lost, taking over dispatcher on {:?}", primary, addrs.dispatcher);
+        attach_result_store(&mut *world.lock().await, results_db.as_ref())?;
+    }
+
+    services.spawn_dispatcher(world, &addrs.dispatcher, modules_dir);
+    services.spawn_replication(world, addrs.replication);
+    // A standby that took over serves no control API.
+    if !matches!(role, Role::Standby(_)) {
+        services.spawn_control(world, addrs.control);
+    }
+    services.spawn_background(world);
+
+    services.supervise().await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::IntoFuture;
+
+    use super::*;
+    use crate::listen::bind;
+
+    fn free_addr() -> SocketAddr {
+        bind("127.0.0.1:0".parse().unwrap()).unwrap().local_addr().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_build() {
+        let server = Server::builder()
+            .chunk_size(4096)
+            .execution_timeout(Duration::from_secs(5))
+            .metrics(false)
+            .speculation(SpeculationPolicy { enabled: false, ..Default::default() })
+            .build()
+            .unwrap();
+        let world = server.world().lock().await;
+        let settings = LifecycleSystem::settings(&world);
+        assert_eq!(settings.chunk_size, 4096);
+        assert_eq!(settings.execution_timeout, Duration::from_secs(5));
+        assert_eq!(settings.heartbeat_timeout, ServerSettings::default().heartbeat_timeout);
+        assert!(!settings.metrics);
+        assert!(!TaskSystem::speculation_policy(&world).enabled);
+
+        let tls = TlsConfig {
+            certificates: "missing/cert.pem".into(),
+            private_key: "missing/key.pem".into(),
+        };
+        assert!(matches!(Server::builder().tls(tls).build(), Err(Error::Io { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_start_and_shutdown() {
+        let addrs = ListenAddrs {
+            inspector: vec![free_addr()],
+            dispatcher: vec![free_addr()],
+            ..Default::default()
+        };
+        let mut server = Server::builder().listen(addrs.clone()).build().unwrap();
+        server.start();
+
+        // Ready once the dispatcher ticked on the address it was given.
+        loop {
+            let listening = server.world().lock().await.query::<&DispatcherStatus>().iter().next().map(|(_, s)| s.addr);
+            if listening == Some(addrs.dispatcher[0]) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(bind(addrs.dispatcher[0]).is_err());
+
+        server.shutdown().await;
+        assert!(bind(addrs.dispatcher[0]).is_ok());
+        assert!(bind(addrs.inspector[0]).is_ok());
+        server.wait().await.unwrap();
+    }
 
-    let world = Arc::new(Mutex::new(World::new()));
+    #[tokio::test]
+    async fn test_embedded_services() {
+        let world = Arc::new(Mutex::new(World::new()));
 
-    let inspector_world = Arc::clone(&world);
-    let inspector_task = tokio::spawn(async move {
-        inspector::run(&inspector_world, &inspector_addr).await.unwrap()
-    });
+        // The inspector nested under an application's own router.
+        let app = axum::Router::new().nest("/inspector", InspectorRouter::new(InspectorHandle::spawn(&world).unwrap()));
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let inspector_addr = listener.local_addr().unwrap();
+        tokio::spawn(axum::serve(listener, app).into_future());
+        let response = reqwest::get(format!("http://{}/inspector/healthz", inspector_addr)).await.unwrap();
+        assert_eq!(response.status(), 200);
 
-    let dispatcher_world = Arc::clone(&world);
-    let dispatcher_task = tokio::spawn(async move {
-        dispatcher::run(&dispatcher_world, &dispatcher_addr).await.unwrap()
-    });
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let dispatcher_addr = listener.local_addr().unwrap();
+        tokio::spawn(DispatcherService::new(world.clone()).bind(listener).serve());
+        let _device = tokio::net::TcpStream::connect(dispatcher_addr).await.unwrap();
+        loop {
+            if world.lock().await.query::<&SessionHandshake>().iter().count() == 1 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
 
-    let (inspector_res, dispatcher_res) = tokio::join!(inspector_task, dispatcher_task);
+        assert_eq!(LifecycleSystem::dispatcher_status(&*world.lock().await).unwrap().addr, dispatcher_addr);
 
-    inspector_res.unwrap();
-    dispatcher_res.unwrap();
+        // An application running its own loop drives the same systems.
+        DispatcherService::tick(&mut *world.lock().await).await.unwrap();
+    }
 }
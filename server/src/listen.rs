@@ -0,0 +1,153 @@
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::debug;
+use socket2::{Domain, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Where each service listens. The dispatcher and inspector bind every address they are given,
+// replication and control a single one.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ListenAddrs {
+    pub inspector: Vec<SocketAddr>,
+    pub dispatcher: Vec<SocketAddr>,
+    pub replication: Option<SocketAddr>,
+    pub control: Option<SocketAddr>,
+}
+
+impl ListenAddrs {
+    // `hosts` is a comma separated list such as "0.0.0.0,::", a host name contributes its first
+    // resolved address. `ports` are inspector, dispatcher, replication and control in that order,
+    // replication and control only listen on the first host.
+    pub fn resolve(hosts: &str, ports: &[u16]) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let resolve = |port: u16| -> io::Result<Vec<SocketAddr>> {
+            let mut addrs = Vec::new();
+            for host in hosts.split(',').map(str::trim).filter(|host| !host.is_empty()) {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                let addr = (host, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| invalid(format!("{} did not resolve", host)))?;
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+            }
+            if addrs.is_empty() {
+                return Err(invalid(format!("no host to listen on in {:?}", hosts)));
+            }
+            Ok(addrs)
+        };
+        let port = |index: usize, service: &str| {
+            ports.get(index).copied().ok_or_else(|| invalid(format!("missing {} port", service)))
+        };
+
+        Ok(Self {
+            inspector: resolve(port(0, "inspector")?)?,
+            dispatcher: resolve(port(1, "dispatcher")?)?,
+            replication: ports.get(2).map(|&port| resolve(port).map(|addrs| addrs[0])).transpose()?,
+            control: ports.get(3).map(|&port| resolve(port).map(|addrs| addrs[0])).transpose()?,
+        })
+    }
+}
+
+// IPv6 sockets are bound v6-only so "::" and "0.0.0.0" can share a port.
+pub(crate) fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+// PEM files the inspector serves HTTPS with, `certificates` holds the chain starting with the
+// server's own. Devices keep speaking plain frames to the dispatcher.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsConfig {
+    pub certificates: PathBuf,
+    pub private_key: PathBuf,
+}
+
+impl TlsConfig {
+    pub(crate) fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let invalid = |path: &Path, error: &dyn std::fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), error))
+        };
+        let certificates = CertificateDer::pem_file_iter(&self.certificates)
+            .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+            .map_err(|e| invalid(&self.certificates, &e))?;
+        let private_key = PrivateKeyDer::from_pem_file(&self.private_key).map_err(|e| invalid(&self.private_key, &e))?;
+
+        let config = ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certificates, private_key))
+            .map_err(|e| invalid(&self.certificates, &e))?;
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+// Hands axum the streams that completed a TLS handshake. Handshakes run one at a time, a client
+// that stalls holds up the others for `TLS_HANDSHAKE_TIMEOUT` at most.
+pub(crate) struct TlsListener {
+    pub listener: TcpListener,
+    pub acceptor: TlsAcceptor,
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        loop {
+            let (stream, addr) = axum::serve::Listener::accept(&mut self.listener).await;
+            match tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, self.acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => return (stream, addr),
+                Ok(Err(e)) => debug!("TLS handshake with {} failed: {}", addr, e),
+                Err(_) => debug!("TLS handshake with {} timed out", addr),
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Self::Addr> {
+        self.listener.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let addrs = ListenAddrs::resolve("127.0.0.1, [::1],127.0.0.1", &[3000, 3030, 3031]).unwrap();
+        assert_eq!(addrs.inspector, vec!["127.0.0.1:3000".parse().unwrap(), "[::1]:3000".parse().unwrap()]);
+        assert_eq!(addrs.dispatcher.len(), 2);
+        assert_eq!(addrs.replication, Some("127.0.0.1:3031".parse().unwrap()));
+        assert_eq!(addrs.control, None);
+
+        assert!(ListenAddrs::resolve(" , ", &[3000, 3030]).is_err());
+        assert!(ListenAddrs::resolve("127.0.0.1", &[3000]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind() {
+        let listener = bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(bind(addr).is_err());
+    }
+}
@@ -1,11 +1,38 @@
+use std::error::Error;
+
 use protocol::Config;
-use server::run;
+use server::{ClusterShard, ListenAddrs, Server};
 
 #[tokio::main]
-async fn main() {
-    let Config { host, inspector_port, dispatcher_port, .. } = Config::new();
+async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
+    let Config { host, inspector_port, dispatcher_port, replication_port, control_port, .. } = Config::new();
 
     env_logger::init();
 
-    run(&host, &[inspector_port, dispatcher_port]).await;
+    // HOST may list several addresses, e.g. "0.0.0.0,::" for both IPv4 and IPv6.
+    let addrs = ListenAddrs::resolve(&host, &[inspector_port, dispatcher_port, replication_port, control_port])?;
+    let shard = std::env::var("SHARD_PEERS").ok().map(|peers| ClusterShard {
+        index: std::env::var("SHARD_INDEX").ok().and_then(|s| s.parse().ok()).unwrap_or(0),
+        peers: peers.split(',').map(str::to_owned).collect(),
+    });
+
+    let mut builder = Server::builder().listen(addrs);
+    // `RESULTS_DB` names the SQLite file completed results are kept in, unset keeps none.
+    if let Ok(path) = std::env::var("RESULTS_DB") {
+        builder = builder.results_db(path);
+    }
+    // `MODULES_DIR` overrides where a server built with task/lazy reads the bundled modules from.
+    if let Ok(path) = std::env::var("MODULES_DIR") {
+        builder = builder.modules_dir(path);
+    }
+    builder = match (std::env::var("PRIMARY_ADDR"), shard) {
+        (Ok(primary), _) => builder.standby(primary),
+        (Err(_), Some(shard)) => builder.shard(shard),
+        (Err(_), None) => builder,
+    };
+
+    let mut server = builder.build()?;
+    server.start();
+    server.wait().await?;
+    Ok(())
 }
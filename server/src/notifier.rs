@@ -0,0 +1,375 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use hecs::{Entity, Or, World};
+use log::{debug, info, warn};
+use protocol::Type;
+use tokio::sync::Mutex;
+
+use crate::components::*;
+
+const NOTIFY_INTERVAL: Duration = Duration::from_millis(500);
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+pub const DEFAULT_TEMPLATE: &str = r#"{"event": "{{event}}", "task_id": {{task_id}}, "name": "{{name}}", "module": "{{module}}", "attempt": {{attempt}}, "result": {{result}}, "timestamp": {{timestamp}}}"#;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Notification {
+    pub event: NotificationEvent,
+    pub task_id: TaskId,
+    pub name: String,
+    pub module: String,
+    pub attempt: u32,
+    // Empty for failures, the results of every device for a group.
+    pub result: Vec<Type>,
+    pub timestamp: SystemTime,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Seen {
+    Queued,
+    Running(u32),
+    Completed,
+}
+
+// Last phase seen of every task, notifications are the differences between two passes.
+#[derive(Debug, Default)]
+pub struct NotificationLog {
+    tasks: HashMap<Entity, Seen>,
+    primed: bool,
+}
+
+pub fn webhooks(world: &World) -> Webhooks {
+    world.query::<&Webhooks>().iter().next().map(|(_, webhooks)| webhooks.clone()).unwrap_or_default()
+}
+
+pub fn set_webhooks(world: &mut World, webhooks: Webhooks) {
+    let current = world.query_mut::<&mut Webhooks>().into_iter().next();
+    match current {
+        Some((_, current)) => *current = webhooks,
+        None => {
+            world.spawn((webhooks,));
+        }
+    }
+}
+
+// The first pass only learns what already happened, tasks that finished before the notifier
+// started are not announced.
+pub fn collect(world: &World, log: &mut NotificationLog, now: SystemTime) -> Vec<Notification> {
+    let mut notifications = Vec::new();
+    let mut present = HashMap::new();
+
+    for (entity, (task, &task_id, state)) in world
+        .query::<(&Task, &TaskId, &TaskState)>()
+        .without::<Or<&BroadcastTarget, &SpeculativeCopy>>()
+        .iter()
+    {
+        let seen = match state.phase {
+            TaskStatePhase::Queued => Seen::Queued,
+            TaskStatePhase::Distributing | TaskStatePhase::Executing { .. } => Seen::Running(state.attempt),
+            TaskStatePhase::Completed => Seen::Completed,
+        };
+        present.insert(entity, seen);
+
+        let previous = log.tasks.get(&entity).copied();
+        let (event, attempt) = match (previous, seen) {
+            (Some(Seen::Completed), _) => continue,
+            (_, Seen::Completed) if task.kind == TaskKind::Broadcast => (NotificationEvent::GroupCompleted, state.attempt),
+            (_, Seen::Completed) => (NotificationEvent::TaskCompleted, state.attempt),
+            (Some(Seen::Running(attempt)), Seen::Queued) => (NotificationEvent::TaskFailed, attempt),
+            (Some(Seen::Running(attempt)), Seen::Running(current)) if current != attempt => {
+                (NotificationEvent::TaskFailed, attempt)
+            }
+            _ => continue,
+        };
+        if previous.is_none() && !log.primed {
+            continue;
+        }
+
+        let result = match event {
+            NotificationEvent::TaskCompleted => task.result.clone(),
+            NotificationEvent::GroupCompleted => state.results.values().flatten().cloned().collect(),
+            NotificationEvent::TaskFailed => vec![],
+        };
+        notifications.push(Notification {
+            event,
+            task_id,
+            name: task.name.clone(),
+            module: world.get::<&Module>(task.require_module).map(|module| module.name.clone()).unwrap_or_default(),
+            attempt,
+            result,
+            timestamp: now,
+        });
+    }
+
+    log.tasks = present;
+    log.primed = true;
+    notifications
+}
+
+// Strings are JSON escaped without their quotes and `{{result}}` is a JSON array, so the default
+// template stays valid JSON whatever the task is called.
+pub fn render(template: &str, notification: &Notification) -> String {
+    let escape = |value: &str| {
+        let quoted = serde_json::to_string(value).unwrap_or_default();
+        quoted[1..quoted.len() - 1].to_string()
+    };
+    let timestamp = notification.timestamp.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    template
+        .replace("{{event}}", notification.event.name())
+        .replace("{{task_id}}", &notification.task_id.0.to_string())
+        .replace("{{name}}", &escape(&notification.name))
+        .replace("{{module}}", &escape(&notification.module))
+        .replace("{{attempt}}", &notification.attempt.to_string())
+        .replace("{{result}}", &serde_json::to_string(&notification.result).unwrap_or_default())
+        .replace("{{timestamp}}", &timestamp.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Delivery {
+    url: String,
+    body: String,
+    attempts: u32,
+    due: Instant,
+}
+
+impl Delivery {
+    // Doubles from BASE_DELAY after every failed attempt, up to MAX_DELAY.
+    fn backoff(attempts: u32) -> Duration {
+        BASE_DELAY.saturating_mul(1 << attempts.saturating_sub(1).min(16)).min(MAX_DELAY)
+    }
+}
+
+async fn post(client: &reqwest::Client, delivery: &Delivery) -> Result<(), String> {
+    let content_type = match serde_json::from_str::<serde_json::Value>(&delivery.body) {
+        Ok(_) => "application/json",
+        Err(_) => "text/plain",
+    };
+    let response = client
+        .post(&delivery.url)
+        .header(reqwest::header::CONTENT_TYPE, content_type)
+        .body(delivery.body.clone())
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(format!("status {}", response.status())),
+    }
+}
+
+pub async fn run(world: &Arc<Mutex<World>>) {
+    let client = match reqwest::Client::builder().timeout(REQUEST_TIMEOUT).build() {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("Webhook client unavailable, notifications are disabled: {}", e);
+            return;
+        }
+    };
+    let mut log = NotificationLog::default();
+    let mut pending = VecDeque::new();
+
+    loop {
+        let (notifications, webhooks) = {
+            let world = world.lock().await;
+            (collect(&world, &mut log, SystemTime::now()), webhooks(&world))
+        };
+        for notification in &notifications {
+            for hook in webhooks.hooks.iter().filter(|hook| hook.events.contains(&notification.event)) {
+                pending.push_back(Delivery {
+                    url: hook.url.clone(),
+                    body: render(&hook.template, notification),
+                    attempts: 0,
+                    due: Instant::now(),
+                });
+            }
+        }
+
+        // The world stays unlocked while webhooks are called, a slow endpoint holds up only them.
+        for _ in 0..pending.len() {
+            let Some(mut delivery) = pending.pop_front() else {
+                break;
+            };
+            if delivery.due > Instant::now() {
+                pending.push_back(delivery);
+                continue;
+            }
+            delivery.attempts += 1;
+            match post(&client, &delivery).await {
+                Ok(()) => debug!("Webhook {} notified after {} attempts", delivery.url, delivery.attempts),
+                Err(e) if delivery.attempts >= MAX_ATTEMPTS => {
+                    warn!("Webhook {} failed {} times, dropping notification: {}", delivery.url, delivery.attempts, e);
+                }
+                Err(e) => {
+                    let delay = Delivery::backoff(delivery.attempts);
+                    info!("Webhook {} failed: {}, retrying in {:?}", delivery.url, e, delay);
+                    delivery.due = Instant::now() + delay;
+                    pending.push_back(delivery);
+                }
+            }
+        }
+
+        tokio::time::sleep(NOTIFY_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn spawn_task(world: &mut World, name: &str, kind: TaskKind) -> Entity {
+        let module = world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        world.spawn((
+            Task {
+                name: name.into(),
+                params: vec![],
+                env: vec![],
+                result: vec![],
+                created_at: SystemTime::now(),
+                require_module: module,
+                priority: 1,
+                kind,
+            },
+            next_task_id(),
+            TaskState {
+                phase: TaskStatePhase::Queued,
+                assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 0,
+            },
+        ))
+    }
+
+    fn run_attempt(world: &World, task: Entity, attempt: u32) {
+        let mut state = world.get::<&mut TaskState>(task).unwrap();
+        state.phase = TaskStatePhase::Distributing;
+        state.attempt = attempt;
+    }
+
+    #[test]
+    fn test_collect() {
+        let mut world = World::new();
+        let mut log = NotificationLog::default();
+        let done = spawn_task(&mut world, "done", TaskKind::Single);
+        world.get::<&mut TaskState>(done).unwrap().phase = TaskStatePhase::Completed;
+        let task = spawn_task(&mut world, "sum", TaskKind::Single);
+        let group = spawn_task(&mut world, "blink", TaskKind::Broadcast);
+        let now = SystemTime::now();
+        assert!(collect(&world, &mut log, now).is_empty());
+
+        // Lost on its first attempt, reassigned and completed on its second.
+        run_attempt(&world, task, 1);
+        assert!(collect(&world, &mut log, now).is_empty());
+        run_attempt(&world, task, 2);
+        let events = collect(&world, &mut log, now);
+        assert_eq!(events.iter().map(|event| (event.event, event.attempt)).collect::<Vec<_>>(), vec![(
+            NotificationEvent::TaskFailed,
+            1
+        )]);
+
+        world.get::<&mut Task>(task).unwrap().result = vec![Type::I32(3)];
+        world.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+        let device = world.spawn(());
+        {
+            let mut state = world.get::<&mut TaskState>(group).unwrap();
+            state.phase = TaskStatePhase::Completed;
+            state.results.insert(device, vec![Type::I32(1)]);
+        }
+        // Tasks created and finished between two passes are still announced.
+        let fast = spawn_task(&mut world, "fast", TaskKind::Single);
+        world.get::<&mut TaskState>(fast).unwrap().phase = TaskStatePhase::Completed;
+
+        let mut events = collect(&world, &mut log, now)
+            .into_iter()
+            .map(|event| (event.name, event.event, event.result))
+            .collect::<Vec<_>>();
+        events.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(events, vec![
+            ("blink".into(), NotificationEvent::GroupCompleted, vec![Type::I32(1)]),
+            ("fast".into(), NotificationEvent::TaskCompleted, vec![]),
+            ("sum".into(), NotificationEvent::TaskCompleted, vec![Type::I32(3)]),
+        ]);
+        assert!(collect(&world, &mut log, now).is_empty());
+    }
+
+    #[test]
+    fn test_render() {
+        let notification = Notification {
+            event: NotificationEvent::TaskCompleted,
+            task_id: TaskId(7),
+            name: "say \"hi\"".into(),
+            module: "sum".into(),
+            attempt: 2,
+            result: vec![Type::I64(5)],
+            timestamp: UNIX_EPOCH + Duration::from_secs(1000),
+        };
+        let body = serde_json::from_str::<serde_json::Value>(&render(DEFAULT_TEMPLATE, &notification)).unwrap();
+        assert_eq!(body["event"], "task.completed");
+        assert_eq!(body["task_id"], 7);
+        assert_eq!(body["name"], "say \"hi\"");
+        assert_eq!(body["result"][0]["I64"], 5);
+        assert_eq!(body["timestamp"], 1000);
+        assert_eq!(render("{{module}} #{{attempt}}", &notification), "sum #2");
+    }
+
+    #[test]
+    fn test_backoff() {
+        let delays = (1..=8).map(Delivery::backoff).collect::<Vec<_>>();
+        assert_eq!(delays[..3], [Duration::from_secs(1), Duration::from_secs(2), Duration::from_secs(4)]);
+        assert_eq!(delays[7], MAX_DELAY);
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        use axum::http::StatusCode;
+        use axum::routing::post as route_post;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let app = axum::Router::new()
+            .route("/ok", route_post({
+                let received = received.clone();
+                move |body: String| async move {
+                    received.lock().await.push(body);
+                    StatusCode::OK
+                }
+            }))
+            .route("/down", route_post(|| async { StatusCode::SERVICE_UNAVAILABLE }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = reqwest::Client::new();
+        let delivery = |path: &str| Delivery {
+            url: format!("http://{}{}", addr, path),
+            body: "{}".into(),
+            attempts: 0,
+            due: Instant::now(),
+        };
+        post(&client, &delivery("/ok")).await.unwrap();
+        assert_eq!(*received.lock().await, vec!["{}".to_string()]);
+        assert_eq!(post(&client, &delivery("/down")).await.unwrap_err(), "status 503 Service Unavailable");
+
+        let mut world = World::new();
+        let hook = Webhook {
+            url: delivery("/ok").url,
+            template: DEFAULT_TEMPLATE.into(),
+            events: HashSet::from([NotificationEvent::TaskCompleted]),
+        };
+        set_webhooks(&mut world, Webhooks { hooks: vec![hook.clone()] });
+        assert_eq!(webhooks(&world).hooks, vec![hook]);
+    }
+}
@@ -0,0 +1,701 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use hecs::{Entity, Or, World};
+use log::{info, warn};
+use protocol::Type;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::components::*;
+use crate::error::{Context, Error};
+use crate::listen::bind;
+use crate::systems::TaskSystem;
+
+const SYNC_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ReplicationEvent {
+    Module {
+        name: String,
+        binary: Vec<u8>,
+        // Names of the libraries the module links, shipped before it.
+        #[serde(default)]
+        dependencies: Vec<String>,
+        chunk_size: u32,
+        pinned: bool,
+    },
+    Task {
+        task_id: TaskId,
+        name: String,
+        module: String,
+        params: Vec<Type>,
+        #[serde(default)]
+        env: Vec<(String, String)>,
+        result: Vec<Type>,
+        priority: u8,
+        broadcast: bool,
+        completed: bool,
+        #[serde(default)]
+        policy: TaskPolicy,
+        // Name of the group the task belongs to, joined again on the standby.
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        checkpoint: Option<Vec<u8>>,
+    },
+    TaskRemoved {
+        task_id: TaskId,
+    },
+    // Templates have no `TaskId`, they are known by name like in the control API.
+    Recurring {
+        name: String,
+        module: String,
+        params: Vec<Type>,
+        env: Vec<(String, String)>,
+        priority: u8,
+        broadcast: bool,
+        policy: TaskPolicy,
+        schedule: ScheduleSpec,
+        next_run: SystemTime,
+    },
+    RecurringRemoved {
+        name: String,
+    },
+}
+
+// Components a task may carry beside `Task` that decide where and when it runs.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TaskPolicy {
+    pub labels: Vec<String>,
+    pub tenant: Option<String>,
+    pub filler: bool,
+    pub preemptive: bool,
+}
+
+impl TaskPolicy {
+    fn of(world: &World, entity: Entity) -> Self {
+        Self {
+            labels: world.get::<&TaskSelector>(entity).map(|selector| selector.labels.clone()).unwrap_or_default(),
+            tenant: world.get::<&TaskOwner>(entity).ok().map(|owner| owner.tenant.clone()),
+            filler: world.satisfies::<&FillerTask>(entity).unwrap_or(false),
+            preemptive: world.satisfies::<&PreemptiveTask>(entity).unwrap_or(false),
+        }
+    }
+
+    fn insert(self, world: &mut World, entity: Entity) {
+        if !self.labels.is_empty() {
+            world.insert_one(entity, TaskSelector { labels: self.labels }).ok();
+        }
+        if let Some(tenant) = self.tenant {
+            world.insert_one(entity, TaskOwner { tenant }).ok();
+        }
+        if self.filler {
+            world.insert_one(entity, FillerTask).ok();
+        }
+        if self.preemptive {
+            world.insert_one(entity, PreemptiveTask).ok();
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScheduleSpec {
+    Every(Duration),
+    Cron(String),
+}
+
+impl From<&Schedule> for ScheduleSpec {
+    fn from(schedule: &Schedule) -> Self {
+        match schedule {
+            Schedule::Every(interval) => Self::Every(*interval),
+            Schedule::Cron(schedule) => Self::Cron(schedule.source().to_string()),
+        }
+    }
+}
+
+impl ScheduleSpec {
+    fn schedule(&self) -> Option<Schedule> {
+        match self {
+            Self::Every(interval) => Some(Schedule::Every(*interval)),
+            Self::Cron(expression) => Schedule::cron(expression).ok(),
+        }
+    }
+}
+
+// Remembers what has already been shipped to one standby so only changes are sent. Modules are
+// shipped again once their `Module::hash`, libraries, chunk size or pin changes, such as on a
+// replacement. Tasks once they complete or save a checkpoint, recurring templates each time they fire.
+#[derive(Debug, Default)]
+pub struct ReplicationLog {
+    modules: HashMap<String, (u32, Vec<String>, u32, bool)>,
+    tasks: HashMap<TaskId, (bool, Option<SystemTime>)>,
+    recurring: HashMap<String, SystemTime>,
+}
+
+impl ReplicationLog {
+    pub fn collect(&mut self, world: &World) -> Vec<ReplicationEvent> {
+        let mut events = Vec::new();
+
+        // A module lists every library it links, including the ones its libraries link, so a
+        // library always has fewer dependencies than the modules linking it and is shipped first.
+        let mut query = world.query::<&Module>();
+        let mut modules = query.iter().map(|(_, module)| module).collect::<Vec<_>>();
+        modules.sort_by_key(|module| module.dependencies.len());
+        for module in modules {
+            let dependencies = module
+                .dependencies
+                .iter()
+                .filter_map(|&dependency| world.get::<&Module>(dependency).ok().map(|library| library.name.clone()))
+                .collect::<Vec<_>>();
+            let shipped = (module.hash(), dependencies, module.chunk_size, module.pinned);
+            if self.modules.get(&module.name) == Some(&shipped) {
+                continue;
+            }
+            events.push(ReplicationEvent::Module {
+                name: module.name.clone(),
+                binary: module.binary.clone(),
+                dependencies: shipped.1.clone(),
+                chunk_size: module.chunk_size,
+                pinned: module.pinned,
+            });
+            self.modules.insert(module.name.clone(), shipped);
+        }
+
+        // Broadcast children and speculative copies are bound to primary sessions, the standby
+        // fans out and speculates again.
+        let mut live_tasks = HashSet::new();
+        for (entity, (task, task_id, state, checkpoint, member)) in world
+            .query::<(&Task, &TaskId, &TaskState, Option<&TaskCheckpoint>, Option<&TaskGroupMember>)>()
+            .without::<Or<&BroadcastTarget, &SpeculativeCopy>>()
+            .iter()
+        {
+            live_tasks.insert(*task_id);
+            let shipped = (state.phase == TaskStatePhase::Completed, checkpoint.map(|checkpoint| checkpoint.saved));
+            if self.tasks.get(task_id) == Some(&shipped) {
+                continue;
+            }
+
+            let Ok(module) = world.get::<&Module>(task.require_module) else {
+                continue;
+            };
+            self.tasks.insert(*task_id, shipped);
+            events.push(ReplicationEvent::Task {
+                task_id: *task_id,
+                name: task.name.clone(),
+                module: module.name.clone(),
+                params: task.params.clone(),
+                env: task.env.clone(),
+                result: task.result.clone(),
+                priority: task.priority,
+                broadcast: task.kind == TaskKind::Broadcast,
+                completed: shipped.0,
+                policy: TaskPolicy::of(world, entity),
+                group: member.and_then(|member| world.get::<&TaskGroup>(member.group).ok().map(|group| group.name.clone())),
+                checkpoint: checkpoint.map(|checkpoint| checkpoint.data.clone()),
+            });
+        }
+
+        self.tasks.retain(|task_id, _| {
+            let live = live_tasks.contains(task_id);
+            if !live {
+                events.push(ReplicationEvent::TaskRemoved { task_id: *task_id });
+            }
+            live
+        });
+
+        let mut live_templates = HashSet::new();
+        for (entity, (task, recurring)) in world.query::<(&Task, &RecurringTask)>().iter() {
+            live_templates.insert(task.name.clone());
+            if self.recurring.get(&task.name) == Some(&recurring.next_run) {
+                continue;
+            }
+
+            let Ok(module) = world.get::<&Module>(task.require_module) else {
+                continue;
+            };
+            self.recurring.insert(task.name.clone(), recurring.next_run);
+            events.push(ReplicationEvent::Recurring {
+                name: task.name.clone(),
+                module: module.name.clone(),
+                params: task.params.clone(),
+                env: task.env.clone(),
+                priority: task.priority,
+                broadcast: task.kind == TaskKind::Broadcast,
+                policy: TaskPolicy::of(world, entity),
+                schedule: ScheduleSpec::from(&recurring.schedule),
+                next_run: recurring.next_run,
+            });
+        }
+
+        self.recurring.retain(|name, _| {
+            let live = live_templates.contains(name);
+            if !live {
+                events.push(ReplicationEvent::RecurringRemoved { name: name.clone() });
+            }
+            live
+        });
+
+        events
+    }
+}
+
+pub fn apply(world: &mut World, events: Vec<ReplicationEvent>) {
+    let mut module_entities: HashMap<String, Entity> = world
+        .query::<&Module>()
+        .iter()
+        .map(|(entity, module)| (module.name.clone(), entity))
+        .collect();
+
+    let mut task_entities: HashMap<TaskId, Entity> = world
+        .query::<&TaskId>()
+        .iter()
+        .map(|(entity, task_id)| (*task_id, entity))
+        .collect();
+
+    let mut template_entities: HashMap<String, Entity> = world
+        .query::<&Task>()
+        .with::<&RecurringTask>()
+        .iter()
+        .map(|(entity, task)| (task.name.clone(), entity))
+        .collect();
+
+    for event in events {
+        match event {
+            ReplicationEvent::Module { name, binary, dependencies, chunk_size, pinned } => {
+                let dependencies = dependencies
+                    .iter()
+                    .filter_map(|library| {
+                        let entity = module_entities.get(library).copied();
+                        if entity.is_none() {
+                            warn!("Replicated module {} links unknown library {}", name, library);
+                        }
+                        entity
+                    })
+                    .collect::<Vec<_>>();
+
+                if let Some(&entity) = module_entities.get(&name) {
+                    if let Ok(mut module) = world.get::<&mut Module>(entity) {
+                        module.binary = binary;
+                        module.dependencies = dependencies;
+                        module.chunk_size = chunk_size;
+                        module.pinned = pinned;
+                    }
+                    // Artifacts were compiled from the previous binary.
+                    world.remove_one::<ModuleArtifacts>(entity).ok();
+                    continue;
+                }
+                let entity = world.spawn((Module {
+                    name: name.clone(),
+                    binary,
+                    dependencies,
+                    chunk_size,
+                    pinned,
+                },));
+                module_entities.insert(name, entity);
+            }
+            ReplicationEvent::Task {
+                task_id,
+                name,
+                module,
+                params,
+                env,
+                result,
+                priority,
+                broadcast,
+                completed,
+                policy,
+                group,
+                checkpoint,
+            } => {
+                // In-flight work on the primary is queued again, devices reconnect after failover.
+                let phase = if completed { TaskStatePhase::Completed } else { TaskStatePhase::Queued };
+                // Attempts start over on the standby, the checkpoint goes along with the first one.
+                let checkpoint = checkpoint.map(|data| TaskCheckpoint {
+                    attempt: 0,
+                    data,
+                    saved: SystemTime::now(),
+                });
+
+                if let Some(&entity) = task_entities.get(&task_id) {
+                    if let Ok((task, state)) = world.query_one_mut::<(&mut Task, &mut TaskState)>(entity) {
+                        task.result = result;
+                        state.phase = phase;
+                    }
+                    if let Some(checkpoint) = checkpoint {
+                        world.insert_one(entity, checkpoint).ok();
+                    } else {
+                        world.remove_one::<TaskCheckpoint>(entity).ok();
+                    }
+                    continue;
+                }
+
+                let Some(&module_entity) = module_entities.get(&module) else {
+                    warn!("Replicated task {} requires unknown module {}", task_id, module);
+                    continue;
+                };
+                let entity = world.spawn((
+                    Task {
+                        name,
+                        params,
+                        env,
+                        result,
+                        created_at: SystemTime::now(),
+                        require_module: module_entity,
+                        priority,
+                        kind: if broadcast { TaskKind::Broadcast } else { TaskKind::Single },
+                    },
+                    TaskState {
+                        phase,
+                        assigned_device: None,
+                        results: HashMap::new(),
+                        progress: None,
+                        transfer: None,
+                        attempt: 0,
+                    },
+                    task_id,
+                ));
+                policy.insert(world, entity);
+                if let Some(group) = group {
+                    let member = TaskSystem::join_group(world, &group);
+                    world.insert_one(entity, member).ok();
+                }
+                if let Some(checkpoint) = checkpoint {
+                    world.insert_one(entity, checkpoint).ok();
+                }
+                task_entities.insert(task_id, entity);
+            }
+            ReplicationEvent::TaskRemoved { task_id } => {
+                if let Some(entity) = task_entities.remove(&task_id) {
+                    world.despawn(entity).ok();
+                }
+            }
+            ReplicationEvent::Recurring { name, module, params, env, priority, broadcast, policy, schedule, next_run } => {
+                let Some(schedule) = schedule.schedule() else {
+                    warn!("Replicated recurring task {} has an invalid schedule", name);
+                    continue;
+                };
+                if let Some(&entity) = template_entities.get(&name) {
+                    if let Ok(mut recurring) = world.get::<&mut RecurringTask>(entity) {
+                        recurring.schedule = schedule;
+                        recurring.next_run = next_run;
+                    }
+                    continue;
+                }
+
+                let Some(&module_entity) = module_entities.get(&module) else {
+                    warn!("Replicated recurring task {} requires unknown module {}", name, module);
+                    continue;
+                };
+                let entity = world.spawn((
+                    Task {
+                        name: name.clone(),
+                        params,
+                        env,
+                        result: vec![],
+                        created_at: SystemTime::now(),
+                        require_module: module_entity,
+                        priority,
+                        kind: if broadcast { TaskKind::Broadcast } else { TaskKind::Single },
+                    },
+                    RecurringTask {
+                        schedule,
+                        next_run,
+                        last_task: None,
+                        prefetched: false,
+                    },
+                ));
+                policy.insert(world, entity);
+                template_entities.insert(name, entity);
+            }
+            ReplicationEvent::RecurringRemoved { name } => {
+                if let Some(entity) = template_entities.remove(&name) {
+                    world.despawn(entity).ok();
+                }
+            }
+        }
+    }
+}
+
+pub async fn serve(world: &Arc<Mutex<World>>, addr: SocketAddr) -> Result<(), Error> {
+    let listener = bind(addr).context(format!("binding replication server to {}", addr))?;
+    let local_addr = listener.local_addr().context("reading replication server address")?;
+    info!("Replication server listening on: {}", local_addr);
+
+    loop {
+        let (mut stream, addr) = listener.accept().await.context("accepting standby")?;
+        info!("Standby connected from {}", addr);
+
+        let world = world.clone();
+        tokio::spawn(async move {
+            let mut log = ReplicationLog::default();
+            loop {
+                let events = log.collect(&*world.lock().await);
+
+                let mut buf = Vec::new();
+                for event in events {
+                    if let Err(e) = serde_json::to_writer(&mut buf, &event) {
+                        warn!("Replication event for standby {} not encoded: {}", addr, e);
+                        continue;
+                    }
+                    buf.push(b'\n');
+                }
+                if let Err(e) = stream.write_all(&buf).await {
+                    warn!("Standby {} dropped: {}", addr, e);
+                    break;
+                }
+
+                tokio::time::sleep(SYNC_INTERVAL).await;
+            }
+        });
+    }
+}
+
+pub async fn connect(primary: &str) -> Result<TcpStream, Error> {
+    TcpStream::connect(primary).await.context(format!("connecting to primary {}", primary))
+}
+
+// Applies what the primary ships over `stream` until it closes the connection.
+pub async fn follow(world: &Arc<Mutex<World>>, stream: TcpStream, primary: &str) -> Result<(), Error> {
+    info!("Following primary at {}", primary);
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await.context(format!("reading from primary {}", primary))? {
+        let event = serde_json::from_str::<ReplicationEvent>(&line)
+            .map_err(protocol::Error::JsonError)
+            .context(format!("decoding event from primary {}", primary))?;
+        apply(&mut *world.lock().await, vec![event]);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use protocol::{CacheStats, ExecutorFlavor};
+
+    use super::*;
+
+    #[test]
+    fn test_replicate_tasks() {
+        let mut primary = World::new();
+        let module = primary.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 32],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        let task_id = next_task_id();
+        let task = primary.spawn((
+            Task {
+                name: "mock_task".into(),
+                params: vec![Type::I32(1)],
+                env: vec![],
+                result: vec![],
+                created_at: SystemTime::now(),
+                require_module: module,
+                priority: 1,
+                kind: TaskKind::Single,
+            },
+            TaskState {
+                phase: TaskStatePhase::Distributing,
+                assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 0,
+            },
+            task_id,
+        ));
+
+        let mut log = ReplicationLog::default();
+        let mut standby = World::new();
+        apply(&mut standby, log.collect(&primary));
+        assert!(log.collect(&primary).is_empty());
+
+        let (_, (state, id)) = standby.query_mut::<(&TaskState, &TaskId)>().into_iter().next().unwrap();
+        assert_eq!(state.phase, TaskStatePhase::Queued);
+        assert_eq!(*id, task_id);
+
+        primary.get::<&mut Task>(task).unwrap().result = vec![Type::I32(2)];
+        primary.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+        apply(&mut standby, log.collect(&primary));
+
+        let (_, (replica, state)) = standby.query_mut::<(&Task, &TaskState)>().into_iter().next().unwrap();
+        assert_eq!(state.phase, TaskStatePhase::Completed);
+        assert_eq!(replica.result, vec![Type::I32(2)]);
+
+        primary.despawn(task).unwrap();
+        apply(&mut standby, log.collect(&primary));
+        assert_eq!(standby.query_mut::<&Task>().into_iter().count(), 0);
+    }
+
+    #[test]
+    fn test_failover_keeps_policy() {
+        let mut primary = World::new();
+        let module = primary.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 32],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        let task = Task {
+            name: "mock_task".into(),
+            params: vec![],
+            env: vec![],
+            result: vec![],
+            created_at: SystemTime::now(),
+            require_module: module,
+            priority: 1,
+            kind: TaskKind::Single,
+        };
+        let selector = TaskSelector { labels: vec!["camera".into()] };
+        let member = TaskSystem::join_group(&mut primary, "sweep");
+        primary.spawn((
+            task.clone(),
+            TaskState {
+                phase: TaskStatePhase::Queued,
+                assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 1,
+            },
+            next_task_id(),
+            selector.clone(),
+            TaskOwner { tenant: "lab".into() },
+            PreemptiveTask,
+            member,
+            TaskCheckpoint {
+                attempt: 1,
+                data: vec![1, 2],
+                saved: SystemTime::now(),
+            },
+        ));
+        let recurring = RecurringTask::new(Schedule::cron("*/5 * * * *").unwrap(), SystemTime::now()).unwrap();
+        let next_run = recurring.next_run;
+        primary.spawn((Task { name: "mock_recurring".into(), ..task }, recurring, selector.clone()));
+
+        // Events cross the wire as JSON lines.
+        let events = ReplicationLog::default()
+            .collect(&primary)
+            .iter()
+            .map(|event| serde_json::from_str(&serde_json::to_string(event).unwrap()).unwrap())
+            .collect();
+        let mut standby = World::new();
+        apply(&mut standby, events);
+
+        let (template, recurring) = standby
+            .query_mut::<&RecurringTask>()
+            .into_iter()
+            .map(|(entity, recurring)| (entity, recurring.next_run))
+            .next()
+            .unwrap();
+        assert_eq!(recurring, next_run);
+        assert_eq!(*standby.get::<&TaskSelector>(template).unwrap(), selector);
+
+        let (replica, _) = standby.query_mut::<&TaskState>().into_iter().next().unwrap();
+        assert_eq!(*standby.get::<&TaskSelector>(replica).unwrap(), selector);
+        assert_eq!(standby.get::<&TaskOwner>(replica).unwrap().tenant, "lab");
+        assert!(standby.satisfies::<&PreemptiveTask>(replica).unwrap());
+        assert_eq!(standby.get::<&TaskCheckpoint>(replica).unwrap().data, vec![1, 2]);
+
+        // Promoted, the standby still routes the task by its labels and counts it in its group.
+        let device = standby.spawn((
+            Session {
+                message_queue: VecDeque::new(),
+                latency: Duration::default(),
+                cache_stats: CacheStats::default(),
+            },
+            DeviceInventory::default(),
+            SessionInfo {
+                device_addr: "0.0.0.0:0".parse().unwrap(),
+                device_ram: 4096,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
+            },
+            SessionHealth {
+                retries: 0,
+                status: SessionStatus::Connected,
+                last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
+            },
+            SessionLabels::default(),
+        ));
+        TaskSystem::assign_tasks(&mut standby).unwrap();
+        assert_eq!(standby.get::<&TaskState>(replica).unwrap().phase, TaskStatePhase::Queued);
+
+        standby.get::<&mut SessionLabels>(device).unwrap().labels.insert("camera".into());
+        TaskSystem::assign_tasks(&mut standby).unwrap();
+        assert_eq!(standby.get::<&TaskState>(replica).unwrap().assigned_device, Some(device));
+
+        standby.get::<&mut TaskState>(replica).unwrap().phase = TaskStatePhase::Completed;
+        TaskSystem::track_groups(&mut standby);
+        let group = standby.get::<&TaskGroupMember>(replica).unwrap().group;
+        let group = standby.get::<&TaskGroup>(group).unwrap();
+        assert_eq!((group.name.as_str(), group.total, group.status()), ("sweep", 1, TaskGroupStatus::Completed));
+    }
+
+    #[test]
+    fn test_replicate_replaced_module() {
+        let mut primary = World::new();
+        let module = primary.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 32],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+
+        let mut log = ReplicationLog::default();
+        let mut standby = World::new();
+        apply(&mut standby, log.collect(&primary));
+
+        primary.get::<&mut Module>(module).unwrap().binary = vec![1u8; 48];
+        let events = log.collect(&primary);
+        assert_eq!(events.len(), 1);
+        apply(&mut standby, events);
+        assert!(log.collect(&primary).is_empty());
+
+        let modules = standby.query_mut::<&Module>().into_iter().map(|(_, module)| module.binary.clone()).collect::<Vec<_>>();
+        assert_eq!(modules, vec![vec![1u8; 48]]);
+    }
+
+    #[test]
+    fn test_replicate_libraries() {
+        let mut primary = World::new();
+        let module = |name: &str| Module {
+            name: name.into(),
+            binary: vec![0u8; 32],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        };
+        let app = primary.spawn((module("app"),));
+        let library = primary.spawn((module("library"),));
+        primary.get::<&mut Module>(app).unwrap().dependencies = vec![library];
+
+        // The standby applies events one at a time as they arrive.
+        let mut standby = World::new();
+        for event in ReplicationLog::default().collect(&primary) {
+            apply(&mut standby, vec![event]);
+        }
+
+        let entity = |world: &mut World, name: &str| {
+            world.query_mut::<&Module>().into_iter().find(|(_, module)| module.name == name).unwrap().0
+        };
+        let library = entity(&mut standby, "library");
+        let app = entity(&mut standby, "app");
+        assert_eq!(standby.get::<&Module>(app).unwrap().dependencies, vec![library]);
+    }
+}
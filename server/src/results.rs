@@ -0,0 +1,273 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use hecs::World;
+use protocol::Type;
+use rusqlite::{params, Connection};
+use serde::Serialize;
+
+use crate::components::*;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS results (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        task_id INTEGER NOT NULL,
+        attempt INTEGER NOT NULL,
+        module TEXT NOT NULL,
+        name TEXT NOT NULL,
+        params TEXT NOT NULL,
+        result TEXT NOT NULL,
+        device INTEGER NOT NULL,
+        executor TEXT NOT NULL,
+        arch TEXT NOT NULL,
+        wall_time_us INTEGER NOT NULL,
+        peak_memory INTEGER NOT NULL,
+        instructions INTEGER,
+        completed_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS results_module ON results (module, completed_at);
+";
+
+// A completed task as written to and read back from the store.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResultRecord {
+    pub id: i64,
+    pub task_id: u64,
+    pub attempt: u32,
+    pub module: String,
+    pub name: String,
+    pub params: Vec<Type>,
+    pub result: Vec<Type>,
+    pub device: u64,
+    pub executor: String,
+    pub arch: String,
+    pub wall_time_us: u64,
+    pub peak_memory: u64,
+    pub instructions: Option<u64>,
+    // Seconds since the UNIX epoch.
+    pub completed_at: u64,
+}
+
+impl ResultRecord {
+    // `id` is assigned by the store on insert.
+    pub fn new(task_id: TaskId, attempt: u32, module: &str, task: &Task, metrics: &TaskMetrics, now: SystemTime) -> Self {
+        Self {
+            id: 0,
+            task_id: task_id.0,
+            attempt,
+            module: module.to_string(),
+            name: task.name.clone(),
+            params: task.params.clone(),
+            result: task.result.clone(),
+            device: metrics.device.to_bits().get(),
+            executor: format!("{:?}", metrics.device_class.executor),
+            arch: metrics.device_class.arch.clone(),
+            wall_time_us: metrics.wall_time.as_micros() as u64,
+            peak_memory: metrics.peak_memory,
+            instructions: metrics.instructions,
+            completed_at: now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ResultQuery {
+    pub module: Option<String>,
+    // Seconds since the UNIX epoch, only results completed later are returned.
+    pub since: Option<u64>,
+    // Id of the last record of the previous page.
+    pub after: Option<i64>,
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ResultPage {
+    pub results: Vec<ResultRecord>,
+    // Passed as `after` to fetch the next page, `None` on the last one.
+    pub next: Option<i64>,
+}
+
+// Completed task results kept in SQLite, they outlive the task entities in the world. A singleton
+// in the world, cheap to clone so handlers can query it without holding the world lock.
+#[derive(Clone)]
+pub struct ResultStore(Arc<Mutex<Connection>>);
+
+impl ResultStore {
+    pub const MAX_PAGE: usize = 1000;
+
+    pub fn open(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> rusqlite::Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(connection: Connection) -> rusqlite::Result<Self> {
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self(Arc::new(Mutex::new(connection))))
+    }
+
+    fn connection(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(world: &World) -> Option<ResultStore> {
+        world.query::<&ResultStore>().iter().next().map(|(_, store)| store.clone())
+    }
+
+    pub fn set(world: &mut World, store: ResultStore) {
+        let current = world.query_mut::<&mut ResultStore>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = store,
+            None => {
+                world.spawn((store,));
+            }
+        }
+    }
+
+    pub fn record(&self, record: &ResultRecord) -> rusqlite::Result<i64> {
+        let to_json = |values: &[Type]| serde_json::to_string(values).unwrap_or_else(|_| "[]".into());
+        let connection = self.connection();
+        connection.execute(
+            "INSERT INTO results (task_id, attempt, module, name, params, result, device, executor, arch,
+                wall_time_us, peak_memory, instructions, completed_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+            params![
+                record.task_id as i64,
+                record.attempt,
+                record.module,
+                record.name,
+                to_json(&record.params),
+                to_json(&record.result),
+                record.device as i64,
+                record.executor,
+                record.arch,
+                record.wall_time_us as i64,
+                record.peak_memory as i64,
+                record.instructions.map(|instructions| instructions as i64),
+                record.completed_at as i64,
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    // Oldest first, at most `query.limit` records capped to `MAX_PAGE`.
+    pub fn query(&self, query: &ResultQuery) -> rusqlite::Result<ResultPage> {
+        let limit = query.limit.clamp(1, Self::MAX_PAGE);
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT id, task_id, attempt, module, name, params, result, device, executor, arch,
+                wall_time_us, peak_memory, instructions, completed_at
+             FROM results
+             WHERE (?1 IS NULL OR module = ?1) AND (?2 IS NULL OR completed_at > ?2) AND id > ?3
+             ORDER BY id LIMIT ?4",
+        )?;
+        let from_json = |text: String| serde_json::from_str::<Vec<Type>>(&text).unwrap_or_default();
+        let mut results = statement
+            .query_map(
+                params![query.module, query.since.map(|since| since as i64), query.after.unwrap_or(0), limit as i64 + 1],
+                |row| {
+                    Ok(ResultRecord {
+                        id: row.get(0)?,
+                        task_id: row.get::<_, i64>(1)? as u64,
+                        attempt: row.get(2)?,
+                        module: row.get(3)?,
+                        name: row.get(4)?,
+                        params: from_json(row.get(5)?),
+                        result: from_json(row.get(6)?),
+                        device: row.get::<_, i64>(7)? as u64,
+                        executor: row.get(8)?,
+                        arch: row.get(9)?,
+                        wall_time_us: row.get::<_, i64>(10)? as u64,
+                        peak_memory: row.get::<_, i64>(11)? as u64,
+                        instructions: row.get::<_, Option<i64>>(12)?.map(|instructions| instructions as u64),
+                        completed_at: row.get::<_, i64>(13)? as u64,
+                    })
+                },
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        // One row past the page tells whether another page follows.
+        let next = (results.len() > limit).then(|| {
+            results.truncate(limit);
+            results[limit - 1].id
+        });
+        Ok(ResultPage { results, next })
+    }
+
+    pub fn count(&self) -> rusqlite::Result<u64> {
+        let count = self.connection().query_row("SELECT COUNT(*) FROM results", [], |row| row.get::<_, i64>(0))?;
+        Ok(count as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use protocol::ExecutorFlavor;
+
+    use super::*;
+
+    fn record(store: &ResultStore, task_id: u64, module: &str, completed_at: u64) {
+        let mut world = World::new();
+        let module_entity = world.spawn(());
+        let device = world.spawn(());
+        let task = Task {
+            name: format!("task-{}", task_id),
+            params: vec![Type::I32(task_id as i32)],
+            env: vec![],
+            result: vec![Type::I64(task_id as i64 * 2)],
+            created_at: SystemTime::now(),
+            require_module: module_entity,
+            priority: 1,
+            kind: TaskKind::Single,
+        };
+        let metrics = TaskMetrics {
+            device,
+            device_class: DeviceClass {
+                executor: ExecutorFlavor::Jit,
+                arch: "x86_64".into(),
+            },
+            wall_time: Duration::from_millis(3),
+            peak_memory: 4096,
+            instructions: None,
+        };
+        let now = UNIX_EPOCH + Duration::from_secs(completed_at);
+        store.record(&ResultRecord::new(TaskId(task_id), 1, module, &task, &metrics, now)).unwrap();
+    }
+
+    #[test]
+    fn test_query_pages() {
+        let store = ResultStore::open_in_memory().unwrap();
+        for task_id in 0..5 {
+            record(&store, task_id, "sum", 100 + task_id);
+        }
+        record(&store, 5, "blink", 200);
+
+        let query = ResultQuery { module: Some("sum".into()), limit: 2, ..Default::default() };
+        let first = store.query(&query).unwrap();
+        assert_eq!(first.results.iter().map(|record| record.task_id).collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(first.results[1].result, vec![Type::I64(2)]);
+        assert_eq!(first.results[0].wall_time_us, 3000);
+
+        let mut pages = vec![first.clone()];
+        while let Some(after) = pages.last().unwrap().next {
+            pages.push(store.query(&ResultQuery { after: Some(after), ..query.clone() }).unwrap());
+        }
+        let task_ids = pages.iter().flat_map(|page| page.results.iter().map(|record| record.task_id));
+        assert_eq!(task_ids.collect::<Vec<_>>(), vec![0, 1, 2, 3, 4]);
+
+        let since = ResultQuery { since: Some(102), limit: 10, ..Default::default() };
+        let page = store.query(&since).unwrap();
+        assert_eq!(page.results.iter().map(|record| record.task_id).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(page.next, None);
+        assert_eq!(store.count().unwrap(), 6);
+
+        let mut world = World::new();
+        ResultStore::set(&mut world, store);
+        assert_eq!(ResultStore::get(&world).unwrap().count().unwrap(), 6);
+    }
+}
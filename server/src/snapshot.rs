@@ -0,0 +1,196 @@
+use std::time::SystemTime;
+
+use hecs::{Entity, World};
+use serde::{Deserialize, Serialize};
+
+use crate::components::*;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SessionSnapshot {
+    pub entity: Entity,
+    pub session: Session,
+    pub health: SessionHealth,
+    // Absent while the connection is still handshaking.
+    pub info: Option<SessionInfo>,
+    pub labels: Option<SessionLabels>,
+    pub benchmark: Option<DeviceBenchmark>,
+    pub quarantined: bool,
+}
+
+// Modules are described without their binaries, `hash` tells replaced ones apart.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ModuleSnapshot {
+    pub entity: Entity,
+    pub name: String,
+    pub size: usize,
+    pub chunk_size: u32,
+    pub pinned: bool,
+    pub hash: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TaskSnapshot {
+    pub entity: Entity,
+    pub task_id: Option<TaskId>,
+    pub task: Task,
+    pub state: TaskState,
+    pub owner: Option<TaskOwner>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TransferSnapshot {
+    pub entity: Entity,
+    pub transfer: ModuleTransfer,
+}
+
+// Scheduler view of the world at `taken_at`. Entities keep their ids, so references between the
+// parts, such as a task's assigned device, resolve within the same snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WorldSnapshot {
+    pub taken_at: SystemTime,
+    pub sessions: Vec<SessionSnapshot>,
+    pub modules: Vec<ModuleSnapshot>,
+    pub tasks: Vec<TaskSnapshot>,
+    pub transfers: Vec<TransferSnapshot>,
+}
+
+// Taken under a single borrow of the world, every part reflects the same tick. Parts are ordered
+// by entity so snapshots of an unchanged world compare equal.
+pub fn snapshot(world: &World) -> WorldSnapshot {
+    let mut sessions = world
+        .query::<(&Session, &SessionHealth, Option<&SessionInfo>, Option<&SessionLabels>, Option<&DeviceBenchmark>)>()
+        .iter()
+        .map(|(entity, (session, health, info, labels, benchmark))| SessionSnapshot {
+            entity,
+            session: session.clone(),
+            health: health.clone(),
+            info: info.cloned(),
+            labels: labels.cloned(),
+            benchmark: benchmark.copied(),
+            quarantined: world.satisfies::<&SessionQuarantine>(entity).unwrap_or(false),
+        })
+        .collect::<Vec<_>>();
+    sessions.sort_by_key(|session| session.entity);
+
+    let mut modules = world
+        .query::<&Module>()
+        .iter()
+        .map(|(entity, module)| ModuleSnapshot {
+            entity,
+            name: module.name.clone(),
+            size: module.binary.len(),
+            chunk_size: module.chunk_size,
+            pinned: module.pinned,
+            hash: module.hash(),
+        })
+        .collect::<Vec<_>>();
+    modules.sort_by_key(|module| module.entity);
+
+    let mut tasks = world
+        .query::<(&Task, &TaskState, Option<&TaskId>, Option<&TaskOwner>)>()
+        .iter()
+        .map(|(entity, (task, state, task_id, owner))| TaskSnapshot {
+            entity,
+            task_id: task_id.copied(),
+            task: task.clone(),
+            state: state.clone(),
+            owner: owner.cloned(),
+        })
+        .collect::<Vec<_>>();
+    tasks.sort_by_key(|task| task.entity);
+
+    let mut transfers = world
+        .query::<&ModuleTransfer>()
+        .iter()
+        .map(|(entity, transfer)| TransferSnapshot {
+            entity,
+            transfer: transfer.clone(),
+        })
+        .collect::<Vec<_>>();
+    transfers.sort_by_key(|transfer| transfer.entity);
+
+    WorldSnapshot {
+        taken_at: SystemTime::now(),
+        sessions,
+        modules,
+        tasks,
+        transfers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+    use std::time::Duration;
+
+    use bitvec::prelude::*;
+    use protocol::{ServerMessage, Type};
+
+    use super::*;
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let mut world = World::new();
+        let session = world.spawn((
+            Session {
+                message_queue: VecDeque::from([ServerMessage::ServerCancel { task_id: TaskId(3) }]),
+                latency: Duration::from_millis(8),
+                cache_stats: Default::default(),
+            },
+            SessionHealth {
+                retries: 0,
+                status: SessionStatus::Occupied,
+                last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
+            },
+            SessionQuarantine { since: SystemTime::now() },
+        ));
+        let module = world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 40],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        let task = world.spawn((
+            Task {
+                name: "mock_task".into(),
+                params: vec![Type::I32(1)],
+                env: vec![],
+                result: vec![],
+                created_at: SystemTime::now(),
+                require_module: module,
+                priority: 1,
+                kind: TaskKind::Broadcast,
+            },
+            TaskState {
+                phase: TaskStatePhase::Distributing,
+                assigned_device: Some(session),
+                results: HashMap::from([(session, vec![Type::I32(2)])]),
+                progress: None,
+                transfer: None,
+                attempt: 1,
+            },
+            TaskId(3),
+        ));
+        world.spawn((ModuleTransfer {
+            task_id: TaskId(3),
+            state: ModuleTransferState::Transferring,
+            acked_chunks: bitvec![1, 0, 0],
+            session,
+            module,
+            arch: None,
+            in_flight: Default::default(),
+        },));
+
+        let snapshot = snapshot(&world);
+        assert!(snapshot.sessions[0].quarantined && snapshot.sessions[0].info.is_none());
+        assert_eq!((snapshot.modules[0].size, snapshot.tasks[0].entity), (40, task));
+        assert_eq!(snapshot.tasks[0].state.assigned_device, Some(snapshot.sessions[0].entity));
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(serde_json::from_str::<WorldSnapshot>(&json).unwrap(), snapshot);
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use hecs::{Or, World};
+use log::{debug, info};
+use protocol::Type;
+
+use crate::components::*;
+
+pub struct CalibrationSystem;
+
+impl CalibrationSystem {
+    // Calibration runs ahead of anything else queued for the new device.
+    const PRIORITY: u8 = u8::MAX;
+
+    pub fn calibration(world: &World) -> Option<Calibration> {
+        world
+            .query::<&Calibration>()
+            .iter()
+            .next()
+            .map(|(_, calibration)| calibration.clone())
+    }
+
+    pub fn set_calibration(world: &mut World, calibration: Option<Calibration>) {
+        let current = world.query_mut::<&Calibration>().into_iter().next().map(|(entity, _)| entity);
+        if let Some(entity) = current {
+            world.despawn(entity).ok();
+        }
+        info!("Calibration module set to {:?}", calibration);
+        if let Some(calibration) = calibration {
+            world.spawn((calibration,));
+        }
+    }
+
+    // Queues one task per kernel, pinned to each registered device that was never calibrated.
+    // Devices keep their scores across reconnects of the same session.
+    pub fn calibrate_devices(world: &mut World) {
+        let Some(calibration) = Self::calibration(world) else {
+            return;
+        };
+        let Some(module) = world
+            .query::<&Module>()
+            .iter()
+            .find(|(_, module)| module.name == calibration.module)
+            .map(|(entity, _)| entity)
+        else {
+            return;
+        };
+
+        let devices = world
+            .query::<&SessionHealth>()
+            .with::<&SessionInfo>()
+            .without::<Or<&DeviceBenchmark, Or<&SessionHandshake, &SessionQuarantine>>>()
+            .iter()
+            .filter(|(_, health)| matches!(health.status, SessionStatus::Connected | SessionStatus::Occupied))
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+
+        for device in devices {
+            info!("Calibrating device {:?} with module {}", device, calibration.module);
+            world.insert_one(device, DeviceBenchmark::default()).ok();
+            for kernel in CalibrationKernel::ALL {
+                let task_id = next_task_id();
+                world.spawn((
+                    Task {
+                        name: format!("{}_{}", calibration.module, task_id),
+                        params: vec![Type::I32(kernel as i32)],
+                        env: vec![],
+                        result: vec![],
+                        created_at: SystemTime::now(),
+                        require_module: module,
+                        priority: Self::PRIORITY,
+                        kind: TaskKind::Single,
+                    },
+                    TaskState {
+                        phase: TaskStatePhase::Queued,
+                        assigned_device: None,
+                        results: HashMap::new(),
+                        progress: None,
+                        transfer: None,
+                        attempt: 0,
+                    },
+                    task_id,
+                    TaskTarget { session: device },
+                    CalibrationTask { kernel },
+                ));
+            }
+        }
+    }
+
+    // Turns finished calibration tasks into scores on their device, tasks whose device is gone
+    // are dropped unscored.
+    pub fn collect_calibrations(world: &mut World) {
+        let mut finished = Vec::new();
+        for (entity, (calibration, task, state, target, metrics)) in world
+            .query::<(&CalibrationTask, &Task, &TaskState, &TaskTarget, Option<&TaskMetrics>)>()
+            .iter()
+        {
+            if state.phase == TaskStatePhase::Completed {
+                let work = match task.result.first() {
+                    Some(Type::I32(work)) => Some(*work as f64),
+                    Some(Type::I64(work)) => Some(*work as f64),
+                    Some(Type::F32(work)) => Some(*work as f64),
+                    Some(Type::F64(work)) => Some(*work),
+                    _ => None,
+                };
+                let seconds = metrics.map(|metrics| metrics.wall_time.as_secs_f64()).filter(|&seconds| seconds > 0.0);
+                let score = work.zip(seconds).map(|(work, seconds)| work / seconds);
+                finished.push((entity, target.session, calibration.kernel, score));
+            } else if !world.contains(target.session) {
+                finished.push((entity, target.session, calibration.kernel, None));
+            }
+        }
+
+        for (entity, device, kernel, score) in finished {
+            if let Ok(mut benchmark) = world.get::<&mut DeviceBenchmark>(device) {
+                debug!("Device {:?} scored {:?} in the {:?} kernel", device, score, kernel);
+                match kernel {
+                    CalibrationKernel::Integer => benchmark.integer = score,
+                    CalibrationKernel::Float => benchmark.float = score,
+                    CalibrationKernel::Memory => benchmark.memory = score,
+                }
+                if let Some(score) = benchmark.score() {
+                    info!("Device {:?} calibrated with score {:.1}", device, score);
+                }
+            }
+            world.despawn(entity).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    use hecs::Entity;
+    use protocol::{CacheStats, ExecutorFlavor};
+
+    use super::*;
+    use crate::systems::TaskSystem;
+
+    fn create_mock_device(world: &mut World) -> Entity {
+        world.spawn((
+            Session {
+                message_queue: VecDeque::new(),
+                latency: Duration::default(),
+                cache_stats: CacheStats::default(),
+            },
+            DeviceInventory::default(),
+            SessionInfo {
+                device_addr: "0.0.0.0:0".parse().unwrap(),
+                device_ram: 4096,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
+            },
+            SessionHealth {
+                retries: 0,
+                status: SessionStatus::Connected,
+                last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
+            },
+            SessionLabels::default(),
+        ))
+    }
+
+    #[test]
+    fn test_calibrate_devices() {
+        let mut world = World::new();
+        world.spawn((Module {
+            name: "calibrate".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        let device = create_mock_device(&mut world);
+        CalibrationSystem::calibrate_devices(&mut world);
+        assert!(world.get::<&DeviceBenchmark>(device).is_err());
+
+        CalibrationSystem::set_calibration(&mut world, Some(Calibration { module: "calibrate".into() }));
+        CalibrationSystem::calibrate_devices(&mut world);
+        CalibrationSystem::calibrate_devices(&mut world);
+        let tasks = world
+            .query::<(&CalibrationTask, &TaskTarget)>()
+            .iter()
+            .map(|(entity, (calibration, target))| {
+                assert_eq!(target.session, device);
+                (calibration.kernel, entity)
+            })
+            .collect::<HashMap<_, _>>();
+        assert_eq!(tasks.len(), CalibrationKernel::ALL.len());
+
+        // Each kernel reports its work, the device's wall time turns it into a score.
+        let kernels = [(CalibrationKernel::Integer, 8.0), (CalibrationKernel::Float, 1.0), (CalibrationKernel::Memory, 1.0)];
+        for (kernel, work) in kernels {
+            TaskSystem::assign_tasks(&mut world).unwrap();
+            let task = tasks[&kernel];
+            assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(device));
+            world.get::<&mut Task>(task).unwrap().result = vec![Type::F64(work)];
+            world.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+            let metrics = TaskMetrics {
+                device,
+                device_class: DeviceClass::default(),
+                wall_time: Duration::from_millis(500),
+                peak_memory: 0,
+                instructions: None,
+            };
+            world.insert_one(task, metrics).unwrap();
+            world.get::<&mut SessionHealth>(device).unwrap().status = SessionStatus::Connected;
+            CalibrationSystem::collect_calibrations(&mut world);
+            assert!(!world.contains(task));
+        }
+        let benchmark = *world.get::<&DeviceBenchmark>(device).unwrap();
+        assert_eq!((benchmark.integer, benchmark.float, benchmark.memory), (Some(16.0), Some(2.0), Some(2.0)));
+        assert_eq!(benchmark.score(), Some(4.0));
+
+        // A device that leaves before calibrating drops its pending tasks.
+        let gone = create_mock_device(&mut world);
+        CalibrationSystem::calibrate_devices(&mut world);
+        world.despawn(gone).unwrap();
+        CalibrationSystem::collect_calibrations(&mut world);
+        assert_eq!(world.query::<&CalibrationTask>().iter().count(), 0);
+    }
+}
@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+
+use hecs::World;
+use log::info;
+use protocol::ServerMessage;
+
+use crate::components::*;
+
+pub struct ClusterSystem;
+
+impl ClusterSystem {
+    pub fn shard(world: &World) -> Option<ClusterShard> {
+        world
+            .query::<&ClusterShard>()
+            .iter()
+            .next()
+            .map(|(_, shard)| shard.clone())
+    }
+
+    // FNV-1a keeps the mapping identical across processes and builds.
+    pub fn shard_of(module: &str, shards: usize) -> usize {
+        let hash = module.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+        (hash % shards.max(1) as u64) as usize
+    }
+
+    pub fn owns_module(world: &World, module: &str) -> bool {
+        Self::shard(world)
+            .is_none_or(|shard| Self::shard_of(module, shard.peers.len()) == shard.index)
+    }
+
+    pub fn forward_registrations(world: &mut World) {
+        let Some(shard) = Self::shard(world) else {
+            return;
+        };
+
+        // Sessions report their RAM in ClientReady, so a non-zero value marks a completed registration.
+        let registered = world
+            .query::<(&DeviceInventory, &SessionInfo, &SessionHealth)>()
+            .without::<&ShardChecked>()
+            .iter()
+            .filter(|&(_, (_, info, health))| {
+                info.device_ram > 0 && health.status == SessionStatus::Connected
+            })
+            .map(|(entity, (inventory, _, _))| (entity, inventory.names().map(String::from).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        for (entity, modules) in registered {
+            if let Some(owner) = Self::preferred_shard(&modules, shard.peers.len()) {
+                if owner != shard.index {
+                    let addr = shard.peers[owner].clone();
+                    info!("Session {:?} forwarded to shard {} at {}", entity, owner, addr);
+                    if let Ok(mut session) = world.get::<&mut Session>(entity) {
+                        session.message_queue.push_back(ServerMessage::ServerRedirect { addr });
+                    }
+                }
+            }
+            world.insert_one(entity, ShardChecked).ok();
+        }
+    }
+
+    fn preferred_shard(modules: &[String], shards: usize) -> Option<usize> {
+        let mut counts = HashMap::new();
+        for module in modules {
+            *counts.entry(Self::shard_of(module, shards)).or_insert(0) += 1;
+        }
+        counts
+            .into_iter()
+            .max_by_key(|&(shard, count)| (count, std::cmp::Reverse(shard)))
+            .map(|(shard, _)| shard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::{Duration, SystemTime};
+
+    use hecs::Entity;
+    use protocol::{CacheStats, ExecutorFlavor};
+
+    use super::*;
+
+    fn create_mock_module(world: &mut World, name: &str) -> Entity {
+        world.spawn((Module {
+            name: name.to_string(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },))
+    }
+
+    fn create_mock_device(world: &mut World, cached: &[Entity]) -> Entity {
+        let mut inventory = DeviceInventory::default();
+        for &module in cached {
+            let module = world.get::<&Module>(module).unwrap();
+            inventory.insert(module.hash(), &module.name);
+        }
+        world.spawn((
+            Session {
+                message_queue: VecDeque::new(),
+                latency: Duration::default(),
+                cache_stats: CacheStats::default(),
+            },
+            inventory,
+            SessionInfo {
+                device_addr: "0.0.0.0:0".parse().unwrap(),
+                device_ram: 4096,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
+            },
+            SessionHealth {
+                retries: 0,
+                status: SessionStatus::Connected,
+                last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
+            },
+        ))
+    }
+
+    #[test]
+    fn test_forward_registrations() {
+        let mut world = World::new();
+        let peers = vec!["shard0:3030".to_string(), "shard1:3030".to_string()];
+        world.spawn((ClusterShard { index: 0, peers: peers.clone() },));
+
+        let names = (0..16).map(|i| format!("module_{}", i)).collect::<Vec<_>>();
+        let local = names.iter().find(|name| ClusterSystem::shard_of(name, 2) == 0).unwrap();
+        let remote = names.iter().find(|name| ClusterSystem::shard_of(name, 2) == 1).unwrap();
+        assert!(ClusterSystem::owns_module(&world, local));
+        assert!(!ClusterSystem::owns_module(&world, remote));
+
+        let local_module = create_mock_module(&mut world, local);
+        let remote_module = create_mock_module(&mut world, remote);
+        let local_device = create_mock_device(&mut world, &[local_module]);
+        let remote_device = create_mock_device(&mut world, &[remote_module]);
+
+        ClusterSystem::forward_registrations(&mut world);
+        assert!(world.get::<&Session>(local_device).unwrap().message_queue.is_empty());
+        assert!(matches!(
+            world.get::<&Session>(remote_device).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerRedirect { addr }) if *addr == peers[1]
+        ));
+
+        world.get::<&mut Session>(remote_device).unwrap().message_queue.clear();
+        ClusterSystem::forward_registrations(&mut world);
+        assert!(world.get::<&Session>(remote_device).unwrap().message_queue.is_empty());
+    }
+}
@@ -1,12 +1,13 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use bytes::BytesMut;
-use hecs::World;
-use log::{info, warn};
-use tokio::io::{AsyncRead, AsyncWrite};
+use hecs::{Entity, Or, World};
+use log::{debug, info, warn};
+use protocol::{CacheStats, ExecutorFlavor, Message, SequenceTracker, ServerMessage};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
 use tokio::sync::Mutex;
 
@@ -16,29 +17,41 @@ pub struct LifecycleSystem;
 
 impl LifecycleSystem {
     const MAX_RETRIES: u8 = 5;
-    const TIMEOUT: Duration = Duration::from_secs(32);
 
     pub fn accept_connection(world: &mut World, stream: TcpStream, addr: SocketAddr) {
+        let deadline = SystemTime::now() + Self::connection_limits(world).handshake_timeout;
         world.spawn((
+            SessionHandshake { deadline },
             Session {
                 message_queue: VecDeque::new(),
-                modules: HashSet::new(),
                 latency: Duration::default(),
+                cache_stats: CacheStats::default(),
             },
+            DeviceInventory::default(),
             SessionInfo {
                 device_addr: addr,
                 device_ram: 0,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
             },
             SessionStream {
                 inner: Arc::new(Mutex::new(stream)),
                 incoming: BytesMut::new(),
                 outgoing: BytesMut::new(),
+                sequence: SequenceTracker::default(),
+                next_sequence: None,
             },
             SessionHealth {
                 retries: 0,
                 status: SessionStatus::Connected,
                 last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
             },
+            SessionLabels::default(),
+            SessionLogs::default(),
+            SessionRateLimit::default(),
         ));
     }
 
@@ -48,18 +61,35 @@ impl LifecycleSystem {
         F: AsyncFn(SocketAddr) -> std::io::Result<T>,
     {
         let mut dead_sessions = Vec::new();
+        let mut pings = Vec::new();
         let now = SystemTime::now();
+        let settings = Self::settings(world);
+        // However late the ping went out, the device gets this long to answer it.
+        let answer_within = settings.heartbeat_timeout.saturating_sub(settings.idle_timeout);
 
-        for (entity, (info, session, health)) in &mut world
-            .query::<(&SessionInfo, &mut SessionStream<T>, &mut SessionHealth)>()
+        for (entity, (info, session, health, queue, ping)) in &mut world
+            .query::<(&SessionInfo, &mut SessionStream<T>, &mut SessionHealth, Option<&mut Session>, Option<&SessionPing>)>()
             .iter()
         {
             let elapsed = now
                 .duration_since(health.last_heartbeat)
                 .unwrap_or_default();
+            let unanswered = ping.filter(|ping| ping.sent >= health.last_heartbeat);
 
             match health.status {
-                SessionStatus::Connected if elapsed > Self::TIMEOUT => {
+                // Quiet is not gone, the device is asked to show itself before it is given up on.
+                SessionStatus::Connected if elapsed > settings.idle_timeout && unanswered.is_none() => {
+                    let nonce = ping.map_or(0, |ping| ping.nonce.wrapping_add(1));
+                    debug!("Session {:?} idle for {} secs, sending ping {}", entity, elapsed.as_secs(), nonce);
+                    if let Some(queue) = queue {
+                        queue.message_queue.push_back(ServerMessage::ServerPing { nonce });
+                    }
+                    pings.push((entity, SessionPing { nonce, sent: now }));
+                }
+                SessionStatus::Connected
+                    if elapsed > settings.heartbeat_timeout
+                        && unanswered.is_some_and(|ping| now.duration_since(ping.sent).unwrap_or_default() >= answer_within) =>
+                {
                     warn!("Session {:?} timed out ({} secs), marked as zombie", entity, elapsed.as_secs());
                     health.status = SessionStatus::Zombie;
                     health.retries = 0;
@@ -84,16 +114,300 @@ impl LifecycleSystem {
             }
         }
 
-        for entity in dead_sessions {
+        for (entity, ping) in pings {
+            world.insert_one(entity, ping).ok();
+        }
+        if !dead_sessions.is_empty() {
+            for entity in dead_sessions {
+                world.despawn(entity).ok();
+            }
+            Self::recover_tasks(world);
+        }
+    }
+
+    // Moves the connection of `entity`, which just sent ClientReady with `token`, onto the session
+    // the token was issued to, so tasks, transfers and logs held by that session carry over.
+    // `entity` is despawned, the session it joined is returned.
+    pub fn resume_session<T>(world: &mut World, entity: Entity, token: u64) -> Option<Entity>
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let previous = world
+            .query::<&SessionResume>()
+            .iter()
+            .find(|&(other, resume)| other != entity && resume.token == token)
+            .map(|(other, _)| other)?;
+        let connection = world
+            .remove::<(SessionStream<T>, SessionInfo, SessionLabels, DeviceInventory)>(entity)
+            .ok()?;
+        world.despawn(entity).ok();
+        world.insert(previous, connection).ok()?;
+
+        // Chunks sent down the dropped connection may never have arrived.
+        let mut busy = false;
+        for (_, transfer) in world.query_mut::<&mut ModuleTransfer>() {
+            if transfer.session == previous {
+                busy = true;
+                if transfer.state == ModuleTransferState::Transferring {
+                    transfer.state = ModuleTransferState::Requested;
+                }
+            }
+        }
+        busy |= world.query::<&TaskState>().iter().any(|(_, state)| {
+            state.assigned_device == Some(previous)
+                && matches!(state.phase, TaskStatePhase::Distributing | TaskStatePhase::Executing { .. })
+        });
+
+        if let Ok(mut health) = world.get::<&mut SessionHealth>(previous) {
+            health.status = if busy { SessionStatus::Occupied } else { SessionStatus::Connected };
+            health.retries = 0;
+            health.last_heartbeat = SystemTime::now();
+        }
+        info!("Session {:?} resumed by connection {:?}", previous, entity);
+        Some(previous)
+    }
+
+    // Requeues tasks whose device session is gone, they would otherwise sit in Distributing or
+    // Executing forever. Speculative copies are dropped since their original still runs, broadcast
+    // children are settled by collect_broadcasts.
+    pub fn recover_tasks(world: &mut World) -> usize {
+        let stale_transfers = world
+            .query::<&ModuleTransfer>()
+            .without::<Or<&ModulePrefetch, &FirmwareUpdate>>()
+            .iter()
+            .filter(|(_, transfer)| !world.contains(transfer.session))
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        for entity in stale_transfers {
             world.despawn(entity).ok();
         }
+
+        let orphaned = world
+            .query::<(&TaskState, Option<&SpeculativeCopy>)>()
+            .without::<&BroadcastTarget>()
+            .iter()
+            .filter(|(_, (state, _))| {
+                matches!(state.phase, TaskStatePhase::Distributing | TaskStatePhase::Executing { .. })
+                    && state.assigned_device.is_none_or(|device| !world.contains(device))
+            })
+            .map(|(entity, (_, copy))| (entity, copy.is_some()))
+            .collect::<Vec<_>>();
+
+        for &(entity, speculative) in &orphaned {
+            if speculative {
+                debug!("Speculative copy {:?} lost its device, dropped", entity);
+                world.despawn(entity).ok();
+                continue;
+            }
+            warn!("Task {:?} lost its device, requeue", entity);
+            if let Ok(mut state) = world.get::<&mut TaskState>(entity) {
+                state.phase = TaskStatePhase::Queued;
+                state.assigned_device = None;
+            }
+        }
+        orphaned.len()
+    }
+
+    // Removes the session on the next maintain_connection pass, which closes its connection and
+    // requeues its tasks.
+    pub fn disconnect_session(world: &mut World, entity: Entity) -> bool {
+        let Ok(mut health) = world.get::<&mut SessionHealth>(entity) else {
+            return false;
+        };
+        warn!("Session {:?} disconnected by operator", entity);
+        health.status = SessionStatus::Zombie;
+        health.retries = Self::MAX_RETRIES;
+        true
+    }
+
+    pub fn quarantine_session(world: &mut World, entity: Entity, quarantined: bool) -> bool {
+        if !world.satisfies::<&SessionHealth>(entity).unwrap_or(false) {
+            return false;
+        }
+        if !quarantined {
+            if world.remove_one::<SessionQuarantine>(entity).is_ok() {
+                info!("Session {:?} released from quarantine", entity);
+            }
+        } else if !world.satisfies::<&SessionQuarantine>(entity).unwrap_or(false) {
+            warn!("Session {:?} quarantined by operator", entity);
+            world.insert_one(entity, SessionQuarantine { since: SystemTime::now() }).ok();
+        }
+        true
+    }
+
+    pub fn settings(world: &World) -> ServerSettings {
+        world
+            .query::<&ServerSettings>()
+            .iter()
+            .next()
+            .map(|(_, settings)| *settings)
+            .unwrap_or_default()
+    }
+
+    pub fn set_settings(world: &mut World, settings: ServerSettings) {
+        let current = world.query_mut::<&mut ServerSettings>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = settings,
+            None => {
+                world.spawn((settings,));
+            }
+        }
+    }
+
+    pub fn connection_limits(world: &World) -> ConnectionLimits {
+        world
+            .query::<&ConnectionLimits>()
+            .iter()
+            .next()
+            .map(|(_, limits)| *limits)
+            .unwrap_or_default()
+    }
+
+    pub fn set_connection_limits(world: &mut World, limits: ConnectionLimits) {
+        let current = world.query_mut::<&mut ConnectionLimits>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = limits,
+            None => {
+                world.spawn((limits,));
+            }
+        }
+    }
+
+    // Zombies and sessions still handshaking hold a slot as well.
+    pub fn sessions_full(world: &World) -> bool {
+        world.query::<&SessionHealth>().iter().count() >= Self::connection_limits(world).max_sessions
+    }
+
+    // Answers a connection that found every slot taken and closes it.
+    pub async fn reject_busy<T>(stream: &mut T, limits: ConnectionLimits) -> std::io::Result<()>
+    where
+        T: AsyncWrite + Unpin,
+    {
+        let message = ServerMessage::ServerBusy {
+            max_sessions: limits.max_sessions.min(u32::MAX as usize) as u32,
+            retry_after_secs: limits.retry_after.as_secs().min(u32::MAX as u64) as u32,
+        };
+        let frame = Message::from(message).encode().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        stream.write_all(&frame).await?;
+        stream.shutdown().await
+    }
+
+    // Drops connections that never sent ClientReady in time.
+    pub async fn expire_handshakes<T>(world: &mut World)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let now = SystemTime::now();
+        let mut expired = Vec::new();
+
+        for (entity, (handshake, stream)) in world.query::<(&SessionHandshake, &SessionStream<T>)>().iter() {
+            if now < handshake.deadline {
+                continue;
+            }
+            if let Ok(mut inner) = stream.inner.try_lock() {
+                inner.shutdown().await.ok();
+            }
+            warn!("Session {:?} sent no ClientReady in time, dropped", entity);
+            expired.push(entity);
+        }
+
+        if !expired.is_empty() {
+            for entity in expired {
+                world.despawn(entity).ok();
+            }
+            Self::recover_tasks(world);
+        }
+    }
+
+    pub fn server_mode(world: &World) -> ServerMode {
+        world
+            .query::<&ServerMode>()
+            .iter()
+            .next()
+            .map(|(_, mode)| *mode)
+            .unwrap_or_default()
+    }
+
+    pub fn set_server_mode(world: &mut World, mode: ServerMode) {
+        let current = world.query_mut::<&mut ServerMode>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = mode,
+            None => {
+                world.spawn((mode,));
+            }
+        }
+        info!("Server mode set to {:?}", mode);
+    }
+
+    pub fn dispatcher_status(world: &World) -> Option<DispatcherStatus> {
+        world
+            .query::<&DispatcherStatus>()
+            .iter()
+            .next()
+            .map(|(_, status)| *status)
+    }
+
+    pub fn record_dispatcher_tick(world: &mut World, addr: SocketAddr) {
+        let last_tick = SystemTime::now();
+        let current = world.query_mut::<&mut DispatcherStatus>().into_iter().next();
+        match current {
+            Some((_, status)) => *status = DispatcherStatus { addr, last_tick },
+            None => {
+                world.spawn((DispatcherStatus { addr, last_tick },));
+            }
+        }
+    }
+
+    pub fn in_flight_tasks(world: &World) -> usize {
+        world
+            .query::<&TaskState>()
+            .iter()
+            .filter(|(_, state)| {
+                matches!(state.phase, TaskStatePhase::Distributing | TaskStatePhase::Executing { .. })
+            })
+            .count()
+    }
+
+    pub async fn drain_sessions<T>(world: &mut World)
+    where
+        T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        if Self::server_mode(world) != ServerMode::Draining {
+            return;
+        }
+
+        let mut drained_sessions = Vec::new();
+
+        for (entity, (session, stream, health, shared)) in world
+            .query::<(&Session, &SessionStream<T>, &SessionHealth, Option<&SharedFrames>)>()
+            .iter()
+        {
+            let idle = session.message_queue.is_empty()
+                && stream.outgoing.is_empty()
+                && shared.is_none_or(|shared| shared.0.is_empty());
+            if health.status == SessionStatus::Occupied || !idle {
+                continue;
+            }
+
+            if let Err(e) = stream.inner.lock().await.shutdown().await {
+                warn!("Session {:?} shutdown failed: {}", entity, e);
+            }
+            info!("Session {:?} drained and closed", entity);
+            drained_sessions.push(entity);
+        }
+
+        if !drained_sessions.is_empty() {
+            for entity in drained_sessions {
+                world.despawn(entity).ok();
+            }
+            Self::recover_tasks(world);
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use hecs::Entity;
-    use tokio::io::SimplexStream;
+    use tokio::io::{AsyncReadExt, DuplexStream, SimplexStream};
 
     use super::*;
 
@@ -105,20 +419,64 @@ mod tests {
             SessionInfo {
                 device_addr: "0.0.0.0:0".parse().unwrap(),
                 device_ram: 1024,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
             },
             SessionStream {
                 inner: stream.clone(),
                 incoming: BytesMut::new(),
                 outgoing: BytesMut::new(),
+                sequence: SequenceTracker::default(),
+                next_sequence: None,
             },
             SessionHealth {
                 retries: 0,
                 status: SessionStatus::Connected,
                 last_heartbeat: SystemTime::now() - timeout,
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
             },
+            SessionLabels::default(),
         ))
     }
 
+    #[tokio::test]
+    async fn test_drain_sessions() {
+        let mut world = World::new();
+        let (mut client, server) = tokio::io::duplex(64);
+
+        let idle_entity = create_mock_device(&mut world, Duration::ZERO, &Arc::new(Mutex::new(server)));
+        let busy_entity = create_mock_device(
+            &mut world,
+            Duration::ZERO,
+            &Arc::new(Mutex::new(SimplexStream::new_unsplit(1))),
+        );
+        for entity in [idle_entity, busy_entity] {
+            world
+                .insert_one(entity, Session {
+                    message_queue: VecDeque::new(),
+                    latency: Duration::default(),
+                    cache_stats: CacheStats::default(),
+                })
+                .unwrap();
+        }
+        world.get::<&mut SessionHealth>(busy_entity).unwrap().status = SessionStatus::Occupied;
+
+        LifecycleSystem::drain_sessions::<DuplexStream>(&mut world).await;
+        assert!(world.contains(idle_entity));
+
+        LifecycleSystem::set_server_mode(&mut world, ServerMode::Draining);
+        assert_eq!(LifecycleSystem::server_mode(&world), ServerMode::Draining);
+        LifecycleSystem::drain_sessions::<DuplexStream>(&mut world).await;
+        LifecycleSystem::drain_sessions::<SimplexStream>(&mut world).await;
+        assert!(!world.contains(idle_entity));
+        assert!(world.contains(busy_entity));
+
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+    }
+
     #[tokio::test]
     async fn test_maintain_connection() {
         let mut world = World::new();
@@ -133,6 +491,15 @@ mod tests {
             Ok(SimplexStream::new_unsplit(1))
         }
 
+        // A silent session is pinged first and keeps its status until the ping goes unanswered.
+        LifecycleSystem::maintain_connection(&mut world, callback).await;
+        assert_eq!(world.get::<&SessionHealth>(device_entity).unwrap().status, SessionStatus::Connected);
+        let ping = *world.get::<&SessionPing>(device_entity).unwrap();
+        assert_eq!(ping.nonce, 0);
+        LifecycleSystem::maintain_connection(&mut world, callback).await;
+        assert_eq!(*world.get::<&SessionPing>(device_entity).unwrap(), ping);
+
+        world.get::<&mut SessionPing>(device_entity).unwrap().sent -= Duration::from_secs(16);
         LifecycleSystem::maintain_connection(&mut world, callback).await;
         assert_eq!(
             world.get::<&SessionHealth>(device_entity).unwrap().status,
@@ -144,4 +511,150 @@ mod tests {
         }
         assert!(world.get::<&SessionHealth>(device_entity).is_err());
     }
+
+    #[tokio::test]
+    async fn test_idle_ping() {
+        let mut world = World::new();
+        LifecycleSystem::set_settings(&mut world, ServerSettings {
+            idle_timeout: Duration::from_secs(10),
+            heartbeat_timeout: Duration::from_secs(20),
+            ..ServerSettings::default()
+        });
+        let stream = Arc::new(Mutex::new(SimplexStream::new_unsplit(1)));
+        let device_entity = create_mock_device(&mut world, Duration::from_secs(5), &stream);
+        world
+            .insert_one(device_entity, Session {
+                message_queue: VecDeque::new(),
+                latency: Duration::default(),
+                cache_stats: CacheStats::default(),
+            })
+            .unwrap();
+
+        async fn callback(_: SocketAddr) -> std::io::Result<SimplexStream> {
+            Ok(SimplexStream::new_unsplit(1))
+        }
+
+        LifecycleSystem::maintain_connection(&mut world, callback).await;
+        assert!(world.get::<&Session>(device_entity).unwrap().message_queue.is_empty());
+
+        world.get::<&mut SessionHealth>(device_entity).unwrap().last_heartbeat -= Duration::from_secs(20);
+        LifecycleSystem::maintain_connection(&mut world, callback).await;
+        let queue = world.get::<&Session>(device_entity).unwrap().message_queue.clone();
+        assert_eq!(queue, [ServerMessage::ServerPing { nonce: 0 }]);
+
+        // Any frame answers the ping, the next idle stretch is pinged with a fresh nonce.
+        let now = SystemTime::now();
+        world.get::<&mut SessionHealth>(device_entity).unwrap().last_heartbeat = now - Duration::from_secs(12);
+        world.get::<&mut SessionPing>(device_entity).unwrap().sent = now - Duration::from_secs(15);
+        LifecycleSystem::maintain_connection(&mut world, callback).await;
+        assert_eq!(world.get::<&SessionHealth>(device_entity).unwrap().status, SessionStatus::Connected);
+        assert_eq!(world.get::<&SessionPing>(device_entity).unwrap().nonce, 1);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_limits() {
+        let mut world = World::new();
+        LifecycleSystem::set_connection_limits(&mut world, ConnectionLimits {
+            max_sessions: 2,
+            ..ConnectionLimits::default()
+        });
+
+        let (mut client, server) = tokio::io::duplex(64);
+        let late = create_mock_device(&mut world, Duration::ZERO, &Arc::new(Mutex::new(server)));
+        let deadline = SystemTime::now() - Duration::from_secs(1);
+        world.insert_one(late, SessionHandshake { deadline }).unwrap();
+        assert!(!LifecycleSystem::sessions_full(&world));
+
+        let pending = create_mock_device(&mut world, Duration::ZERO, &Arc::new(Mutex::new(tokio::io::duplex(64).1)));
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        world.insert_one(pending, SessionHandshake { deadline }).unwrap();
+        assert!(LifecycleSystem::sessions_full(&world));
+
+        LifecycleSystem::expire_handshakes::<DuplexStream>(&mut world).await;
+        assert!(!world.contains(late));
+        assert!(world.contains(pending));
+        assert!(!LifecycleSystem::sessions_full(&world));
+        let mut buf = Vec::new();
+        assert_eq!(client.read_to_end(&mut buf).await.unwrap(), 0);
+
+        let (mut client, mut server) = tokio::io::duplex(64);
+        LifecycleSystem::reject_busy(&mut server, LifecycleSystem::connection_limits(&world)).await.unwrap();
+        let mut buf = Vec::new();
+        client.read_to_end(&mut buf).await.unwrap();
+        assert_eq!(Message::decode(&buf).unwrap().0, Message::ServerBusy {
+            max_sessions: 2,
+            retry_after_secs: 5,
+        });
+    }
+
+    #[tokio::test]
+    async fn test_recover_tasks() {
+        let mut world = World::new();
+        let device = create_mock_device(
+            &mut world,
+            Duration::from_secs(33),
+            &Arc::new(Mutex::new(SimplexStream::new_unsplit(1))),
+        );
+        let module = world.spawn(());
+        let spawn_task = |world: &mut World, phase: TaskStatePhase| {
+            world.spawn((
+                Task {
+                    name: "mock_task".into(),
+                    params: vec![],
+                    env: vec![],
+                    result: vec![],
+                    created_at: SystemTime::now(),
+                    require_module: module,
+                    priority: 1,
+                    kind: TaskKind::Single,
+                },
+                TaskState {
+                    phase,
+                    assigned_device: Some(device),
+                    results: Default::default(),
+                    progress: None,
+                    transfer: None,
+                    attempt: 0,
+                },
+            ))
+        };
+        let transfer = world.spawn((ModuleTransfer {
+            task_id: next_task_id(),
+            state: ModuleTransferState::Pending,
+            acked_chunks: Default::default(),
+            session: device,
+            module,
+            arch: None,
+            in_flight: Default::default(),
+        },));
+        let distributing = spawn_task(&mut world, TaskStatePhase::Distributing);
+        let executing = spawn_task(&mut world, TaskStatePhase::Executing {
+            started: SystemTime::now(),
+            deadline: SystemTime::now(),
+        });
+        let completed = spawn_task(&mut world, TaskStatePhase::Completed);
+        let copy = spawn_task(&mut world, TaskStatePhase::Distributing);
+        world.insert_one(copy, SpeculativeCopy { original: executing, avoid: device }).unwrap();
+
+        async fn callback(_: SocketAddr) -> std::io::Result<SimplexStream> {
+            Ok(SimplexStream::new_unsplit(1))
+        }
+        assert_eq!(LifecycleSystem::recover_tasks(&mut world), 0);
+        world
+            .insert_one(device, SessionPing { nonce: 0, sent: SystemTime::now() - Duration::from_secs(17) })
+            .unwrap();
+        for _ in 0..6 {
+            LifecycleSystem::maintain_connection(&mut world, callback).await;
+        }
+        assert!(!world.contains(device));
+
+        for task in [distributing, executing] {
+            let state = world.get::<&TaskState>(task).unwrap();
+            assert_eq!(state.phase, TaskStatePhase::Queued);
+            assert_eq!(state.assigned_device, None);
+        }
+        assert!(!world.contains(transfer));
+        assert_eq!(world.get::<&TaskState>(completed).unwrap().phase, TaskStatePhase::Completed);
+        assert!(!world.contains(copy));
+    }
 }
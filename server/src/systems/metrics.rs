@@ -0,0 +1,156 @@
+use std::time::SystemTime;
+
+use hecs::{Or, World};
+
+use super::LifecycleSystem;
+use crate::components::*;
+
+pub struct MetricsSystem;
+
+impl MetricsSystem {
+    pub fn history(world: &World) -> Option<MetricsHistory> {
+        world
+            .query::<&MetricsHistory>()
+            .iter()
+            .next()
+            .map(|(_, history)| history.clone())
+    }
+
+    pub fn set_history(world: &mut World, history: MetricsHistory) {
+        let current = world.query_mut::<&mut MetricsHistory>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = history,
+            None => {
+                world.spawn((history,));
+            }
+        }
+    }
+
+    // Appends a sample once the interval has passed, spawning the default history on first use.
+    pub fn record(world: &mut World, now: SystemTime) {
+        if !LifecycleSystem::settings(world).metrics {
+            return;
+        }
+        if world.query::<&MetricsHistory>().iter().next().is_none() {
+            world.spawn((MetricsHistory::default(),));
+        }
+        if !world.query::<&MetricsHistory>().iter().all(|(_, history)| history.due(now)) {
+            return;
+        }
+
+        let (mut completed_total, mut queued) = (0usize, 0);
+        for (_, state) in world
+            .query::<&TaskState>()
+            .without::<Or<&BroadcastTarget, &SpeculativeCopy>>()
+            .iter()
+        {
+            match state.phase {
+                TaskStatePhase::Completed => completed_total += 1,
+                TaskStatePhase::Queued => queued += 1,
+                _ => {}
+            }
+        }
+
+        let sessions = world
+            .query::<(&Session, Option<&SessionTelemetry>)>()
+            .iter()
+            .map(|(session_entity, (session, telemetry))| SessionSample {
+                session: session_entity,
+                telemetry: telemetry.map(|telemetry| telemetry.telemetry),
+                latency: session.latency,
+                cache_bytes: session.cache_stats.bytes_used,
+            })
+            .collect::<Vec<_>>();
+
+        for (_, history) in world.query_mut::<&mut MetricsHistory>() {
+            // Completed tasks that were despawned in between shrink the count, never below zero.
+            let completed = completed_total.saturating_sub(history.completed_total);
+            history.completed_total = completed_total;
+            history.push(MetricsSample {
+                timestamp: now,
+                completed,
+                queued,
+                sessions: sessions.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, VecDeque};
+    use std::time::Duration;
+
+    use hecs::Entity;
+
+    use super::*;
+
+    fn spawn_task(world: &mut World, module: Entity, phase: TaskStatePhase) -> Entity {
+        world.spawn((
+            Task {
+                name: "mock_task".into(),
+                params: vec![],
+                env: vec![],
+                result: vec![],
+                created_at: SystemTime::now(),
+                require_module: module,
+                priority: 1,
+                kind: TaskKind::Single,
+            },
+            TaskState {
+                phase,
+                assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 0,
+            },
+        ))
+    }
+
+    #[test]
+    fn test_record_history() {
+        let mut world = World::new();
+        let module = world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        let session = world.spawn((Session {
+            message_queue: VecDeque::new(),
+            latency: Duration::from_millis(5),
+            cache_stats: Default::default(),
+        },));
+        MetricsSystem::set_history(&mut world, MetricsHistory::new(Duration::from_secs(60), Duration::from_secs(10)));
+
+        let task = spawn_task(&mut world, module, TaskStatePhase::Queued);
+        spawn_task(&mut world, module, TaskStatePhase::Completed);
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        MetricsSystem::record(&mut world, start);
+
+        // Within the interval nothing is sampled.
+        world.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+        MetricsSystem::record(&mut world, start + Duration::from_secs(5));
+        MetricsSystem::record(&mut world, start + Duration::from_secs(10));
+
+        let history = MetricsSystem::history(&world).unwrap();
+        let samples = history.samples.iter().map(|s| (s.completed, s.queued)).collect::<Vec<_>>();
+        assert_eq!(samples, vec![(1, 1), (1, 0)]);
+        assert_eq!(history.samples[0].sessions[0].session, session);
+        assert_eq!(history.samples[0].sessions[0].latency, Duration::from_millis(5));
+
+        // Samples older than the retention are pruned.
+        MetricsSystem::record(&mut world, start + Duration::from_secs(65));
+        let history = MetricsSystem::history(&world).unwrap();
+        assert_eq!(history.samples.len(), 2);
+        assert_eq!(history.samples[1].completed, 0);
+
+        // Nothing is sampled once metrics are turned off.
+        let settings = ServerSettings { metrics: false, ..Default::default() };
+        LifecycleSystem::set_settings(&mut world, settings);
+        MetricsSystem::record(&mut world, start + Duration::from_secs(80));
+        assert_eq!(MetricsSystem::history(&world).unwrap().samples.len(), 2);
+    }
+}
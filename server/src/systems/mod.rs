@@ -1,7 +1,17 @@
+mod calibration;
+mod cluster;
 mod lifecycle;
+mod metrics;
 mod network;
+mod schedule;
 mod task;
+mod timeline;
 
+pub use calibration::CalibrationSystem;
+pub use cluster::ClusterSystem;
 pub use lifecycle::LifecycleSystem;
+pub use metrics::MetricsSystem;
 pub use network::NetworkSystem;
+pub use schedule::ScheduleSystem;
 pub use task::TaskSystem;
+pub use timeline::TimelineSystem;
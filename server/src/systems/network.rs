@@ -2,22 +2,38 @@ use std::collections::HashMap;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use bytes::Buf;
+use futures::FutureExt;
 use hecs::{Entity, World};
-use log::{debug, error, info};
-use protocol::{AckInfo, Message};
+use log::{debug, error, info, warn};
+use protocol::{
+    AckInfo, CacheStats, ClientMessage, Message, ProtocolErrorCode, ProtocolOptions, SequenceCheck, ServerMessage,
+};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use super::{LifecycleSystem, TaskSystem};
 use crate::components::*;
+use crate::results::{ResultRecord, ResultStore};
 
 pub struct NetworkSystem;
 
 impl NetworkSystem {
+    // A session sending this many frames that fail to decode is closed instead of skipped past.
+    pub const MAX_PROTOCOL_ERRORS: u32 = 16;
+
     pub async fn process_inbound<T>(world: &mut World)
     where
         T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        let mut task_transfer = HashMap::new();
-        let mut task_result = HashMap::new();
+        let mut transfer_acks = HashMap::new();
+        let mut task_result = Vec::new();
+        let mut task_submit = Vec::new();
+        let mut device_logs = Vec::new();
+        let mut device_telemetry = Vec::new();
+        let mut task_progress = Vec::new();
+        let mut task_checkpoints = Vec::new();
+        let mut busy_tasks = Vec::new();
+        let mut handshakes = Vec::new();
+        let mut resumes = Vec::new();
 
         let module_entities: HashMap<String, Entity> = world
             .query::<&Module>()
@@ -25,89 +41,263 @@ impl NetworkSystem {
             .map(|(entity, module)| (module.name.clone(), entity))
             .collect();
 
-        for (entity, (session, info, stream, health)) in world
-            .query::<(&mut Session, &mut SessionInfo, &mut SessionStream<T>, &mut SessionHealth)>()
+        // Blobs share the device's module cache, so its reports name them too.
+        let module_hashes: HashMap<String, u32> = world
+            .query::<&Module>()
+            .iter()
+            .map(|(_, module)| (module.name.clone(), module.hash()))
+            .chain(world.query::<&Blob>().iter().map(|(_, blob)| (protocol::blob_name(blob.id), blob.hash())))
+            .collect();
+
+        let task_entities: HashMap<TaskId, Entity> = world
+            .query::<&TaskId>()
+            .iter()
+            .map(|(entity, task_id)| (*task_id, entity))
+            .collect();
+
+        // Members of a TransferGroup share the task id, so acks are matched by session as well.
+        let transfer_entities: HashMap<(Entity, TaskId), Entity> = world
+            .query::<&ModuleTransfer>()
+            .iter()
+            .map(|(entity, transfer)| ((transfer.session, transfer.task_id), entity))
+            .collect();
+
+        for (entity, (session, inventory, info, labels, stream, health, mut rate_limit)) in world
+            .query::<(
+                &mut Session,
+                &mut DeviceInventory,
+                &mut SessionInfo,
+                &mut SessionLabels,
+                &mut SessionStream<T>,
+                &mut SessionHealth,
+                Option<&mut SessionRateLimit>,
+            )>()
             .iter()
         {
+            if let Some(limit) = rate_limit.as_mut() {
+                // A session dropped for flooding stays paused until it is removed.
+                if health.status != SessionStatus::Zombie {
+                    limit.refresh(SystemTime::now());
+                }
+                if limit.exceeded() {
+                    debug!("Session {:?} throttled until next rate limit window", entity);
+                    continue;
+                }
+            }
+
             let mut locked_stream = match stream.inner.try_lock() {
                 Ok(stream) => stream,
                 Err(_) => continue,
             };
 
-            match locked_stream.read_buf(&mut stream.incoming).await {
-                Ok(0) => {
+            // Only data that already arrived is taken, awaiting an idle session here would hold
+            // the world lock until that device next speaks.
+            match locked_stream.read_buf(&mut stream.incoming).now_or_never() {
+                Some(Ok(0)) => {
                     info!("Session {:?} closed connection gracefully", entity);
                     health.status = SessionStatus::Disconnected;
                     continue;
                 }
-                Err(e) => {
+                Some(Err(e)) => {
                     error!("Session {:?} read stream failed: {}", entity, e);
                     health.status = SessionStatus::Disconnected;
                     continue;
                 }
-                _ => {}
+                Some(Ok(read)) => {
+                    if let Some(limit) = rate_limit.as_mut() {
+                        limit.bytes += read as u64;
+                    }
+                }
+                None => {}
             }
 
-            while let Ok((message, consumed)) = Message::decode(&stream.incoming) {
+            loop {
+                let decoded = ProtocolOptions::DEFAULT.decode_as::<ClientMessage>(&stream.incoming);
+                let (message, sequence, consumed) = match decoded {
+                    Ok(decoded) => decoded,
+                    Err(e) => {
+                        let (Some(code), Some(len)) = (ProtocolErrorCode::of(&e), Message::frame_len(&stream.incoming))
+                        else {
+                            break;
+                        };
+                        // The length header still holds, so the next frame starts right after.
+                        warn!("Session {:?} sent a frame of {} bytes that failed to decode: {}", entity, len, e);
+                        health.record_protocol_error(format!("refused {:?}: {}", code, e));
+                        session.message_queue.push_back(ServerMessage::ProtocolError { code, detail: e.to_string() });
+                        stream.incoming.advance(len);
+                        if health.protocol_errors >= Self::MAX_PROTOCOL_ERRORS {
+                            warn!("Session {:?} reached {} protocol errors, disconnecting", entity, health.protocol_errors);
+                            health.status = SessionStatus::Zombie;
+                            stream.incoming.clear();
+                            break;
+                        }
+                        continue;
+                    }
+                };
+
+                if let Some(limit) = rate_limit.as_mut() {
+                    limit.messages += 1;
+                    if limit.exceeded() {
+                        match limit.action {
+                            RateLimitAction::Throttle => {
+                                warn!("Session {:?} exceeded rate limit, pausing reads", entity);
+                            }
+                            RateLimitAction::Disconnect => {
+                                warn!("Session {:?} exceeded rate limit, disconnecting", entity);
+                                session.message_queue.push_back(ServerMessage::ServerRateLimited {
+                                    max_messages: limit.max_messages,
+                                    max_bytes: limit.max_bytes,
+                                });
+                                health.status = SessionStatus::Zombie;
+                                stream.incoming.clear();
+                            }
+                        }
+                        break;
+                    }
+                }
+
                 stream.incoming.advance(consumed);
                 let now = SystemTime::now();
 
+                if let Some(sequence) = sequence {
+                    // Numbering is answered in kind, the device checks the server's frames too.
+                    stream.next_sequence.get_or_insert(0);
+                    let check = stream.sequence.check(sequence);
+                    health.sequence = Some(stream.sequence.stats());
+                    match check {
+                        SequenceCheck::InOrder => {}
+                        SequenceCheck::Reordered => debug!("Session {:?} frame {} arrived out of order", entity, sequence),
+                        SequenceCheck::Duplicate => {
+                            debug!("Session {:?} dropped duplicate frame {}", entity, sequence);
+                            continue;
+                        }
+                    }
+                }
+
                 match message {
-                    Message::Heartbeat { timestamp } => {
-                        let last_record = UNIX_EPOCH + Duration::from_nanos(timestamp);
-                        let latency = now.duration_since(last_record).unwrap();
+                    ClientMessage::Heartbeat { timestamp, cache } => {
+                        Self::record_heartbeat(entity, session, now, timestamp, cache);
+                    }
+                    ClientMessage::ClientTelemetry { timestamp, cache, telemetry } => {
+                        Self::record_heartbeat(entity, session, now, timestamp, cache);
+                        debug!("Session {:?} reported {:?}", entity, telemetry);
+                        device_telemetry.push((entity, SessionTelemetry { telemetry, received: now }));
+                    }
+                    ClientMessage::ClientReady { modules, device_ram, labels: advertised, executor, arch, resume_token }
+                        if health.status == SessionStatus::Connected =>
+                    {
                         info!(
-                            "Session {entity:?} received heartbeat with latency {}ms",
-                            latency.as_millis()
+                            "Session {:?} received client ready with cached module {:?}, ram {}, labels {:?} and {:?} executor on {:?}",
+                            entity, modules, device_ram, advertised, executor, arch
                         );
-                        session.latency = latency;
+                        resumes.push((entity, resume_token));
+                        labels.labels = advertised.into_iter().collect();
+                        Self::restock(inventory, &modules, &module_hashes);
+                        info.device_ram = device_ram;
+                        info.executor = executor;
+                        info.arch = arch;
+                        handshakes.push(entity);
                     }
-                    Message::ClientReady { modules, device_ram } => {
-                        if health.status == SessionStatus::Connected {
+                    ClientMessage::ClientCacheUpdate { added, removed } => {
+                        debug!("Session {:?} cached {:?} and evicted {:?}", entity, added, removed);
+                        for name in &removed {
+                            if let Some(&hash) = module_hashes.get(name) {
+                                inventory.remove(hash);
+                            }
+                        }
+                        for name in &added {
+                            if let Some(&hash) = module_hashes.get(name) {
+                                inventory.insert(hash, name);
+                            }
+                        }
+                    }
+                    ClientMessage::ClientAck { task_id, ack_info }
+                        if health.status == SessionStatus::Occupied =>
+                    {
+                        if let Some(&transfer) = transfer_entities.get(&(entity, task_id)) {
                             info!(
-                                "Session {:?} received client ready with cached module {:?} and ram {}",
-                                entity, modules, device_ram
+                                "Session {:?} received client ack with info {:?} for transfer {:?}",
+                                entity, ack_info, transfer
                             );
-                            session.modules.clear();
-                            session.modules.extend(
-                                modules.iter().filter_map(|name| module_entities.get(name)),
+                            match &ack_info {
+                                AckInfo::ModuleListAck { modules } => {
+                                    Self::restock(inventory, modules, &module_hashes);
+                                }
+                                AckInfo::TaskAck { accepted: false } => {
+                                    health.status = SessionStatus::Connected;
+                                }
+                                _ => {}
+                            }
+                            transfer_acks
+                                .entry(transfer)
+                                .or_insert(Vec::new())
+                                .push(ack_info);
+                        }
+                    }
+                    // Results are checked against the task's attempt once the query is done, a
+                    // resent one may arrive after the device moved on to another task.
+                    ClientMessage::ClientResult { task_id, attempt, result, stats } => match task_entities.get(&task_id) {
+                        Some(&task) => {
+                            info!(
+                                "Session {:?} received client result with result {:?} and {:?} for task {:?} attempt {}",
+                                entity, result, stats, task, attempt
                             );
-                            info.device_ram = device_ram;
+                            let metrics = TaskMetrics::new(entity, info.class(), &stats);
+                            task_result.push((entity, task, attempt, result, metrics));
+                        }
+                        None if health.status == SessionStatus::Occupied => health.status = SessionStatus::Connected,
+                        None => {}
+                    },
+                    ClientMessage::ClientProgress { task_id, percent, stage }
+                        if health.status == SessionStatus::Occupied =>
+                    {
+                        if let Some(&task) = task_entities.get(&task_id) {
+                            debug!("Session {:?} at {}% of {} for task {:?}", entity, percent, stage, task);
+                            task_progress.push((entity, task, TaskProgress {
+                                percent: percent.min(100),
+                                stage,
+                                updated: now,
+                            }));
                         }
                     }
-                    Message::ClientAck { task_id, ack_info } => {
-                        if health.status == SessionStatus::Occupied {
-                            if let Some(task) = Entity::from_bits(task_id) {
-                                info!(
-                                    "Session {:?} received client ack with info {:?} for task {:?}",
-                                    entity, ack_info, task
-                                );
-                                if let AckInfo::Module { modules } = &ack_info {
-                                    session.modules.clear();
-                                    session.modules.extend(
-                                        modules.iter().filter_map(|name| module_entities.get(name)),
-                                    );
-                                }
-                                task_transfer
-                                    .entry(task)
-                                    .or_insert(Vec::new())
-                                    .push(ack_info);
-                            }
+                    ClientMessage::ClientCheckpoint { task_id, attempt, data } if health.status == SessionStatus::Occupied => {
+                        if let Some(&task) = task_entities.get(&task_id) {
+                            debug!("Session {:?} saved {} bytes of task {:?} attempt {}", entity, data.len(), task, attempt);
+                            task_checkpoints.push((entity, task, TaskCheckpoint { attempt, data, saved: now }));
                         }
                     }
-                    Message::ClientResult { task_id, result } => {
-                        if health.status == SessionStatus::Occupied {
-                            if let Some(task) = Entity::from_bits(task_id) {
-                                info!(
-                                    "Session {:?} received client result with result {:?} for task {:?}",
-                                    entity, result, task
-                                );
-                                task_result.insert(task, result.clone());
-                            }
-
-                            health.status = SessionStatus::Connected
+                    // Refused like a negative TaskAck. A task riding on another task's transfer has
+                    // no transfer of its own to cancel and is requeued by itself.
+                    ClientMessage::ClientBusy { task_id, queue_len } if health.status == SessionStatus::Occupied => {
+                        info!("Session {:?} refused task {:?} with {} tasks queued", entity, task_id, queue_len);
+                        health.status = SessionStatus::Connected;
+                        if let Some(&transfer) = transfer_entities.get(&(entity, task_id)) {
+                            transfer_acks
+                                .entry(transfer)
+                                .or_insert(Vec::new())
+                                .push(AckInfo::TaskAck { accepted: false });
+                        } else if let Some(&task) = task_entities.get(&task_id) {
+                            busy_tasks.push((entity, task));
                         }
                     }
+                    ClientMessage::ClientLog { level, module, message, timestamp } => {
+                        debug!("Session {:?} logged [{:?} {}] {}", entity, level, module, message);
+                        device_logs.push((entity, LogEntry { level, module, message, timestamp }));
+                    }
+                    ClientMessage::ClientSubmit { module_name, params, priority } => {
+                        info!(
+                            "Session {:?} submitted module {} with params {:?} and priority {}",
+                            entity, module_name, params, priority
+                        );
+                        task_submit.push((entity, module_name, params, priority));
+                    }
+                    ClientMessage::ClientPong { nonce } => {
+                        debug!("Session {:?} answered ping {}", entity, nonce);
+                    }
+                    ClientMessage::ProtocolError { code, detail } => {
+                        warn!("Session {:?} refused a frame from the server ({:?}): {}", entity, code, detail);
+                        health.record_protocol_error(format!("device refused {:?}: {}", code, detail));
+                    }
                     _ => {}
                 };
 
@@ -115,52 +305,298 @@ impl NetworkSystem {
             }
         }
 
-        for (entity, acks) in task_transfer {
-            let module_entity = world.get::<&Task>(entity).map(|s| s.require_module).unwrap();
-            let module_name = world.get::<&Module>(module_entity).unwrap().name.clone();
+        for (entity, entry) in device_logs {
+            if world.get::<&SessionLogs>(entity).is_err() {
+                world.insert_one(entity, SessionLogs::default()).ok();
+            }
+            if let Ok(mut logs) = world.get::<&mut SessionLogs>(entity) {
+                logs.push(entry);
+            }
+        }
+
+        for (entity, telemetry) in device_telemetry {
+            world.insert_one(entity, telemetry).ok();
+        }
+
+        // Reports from a device the task has since moved away from are stale.
+        for (session, task, progress) in task_progress {
+            if let Ok(mut state) = world.get::<&mut TaskState>(task) {
+                if state.assigned_device == Some(session) && state.phase != TaskStatePhase::Completed {
+                    state.progress = Some(progress);
+                }
+            }
+        }
+
+        // Only the attempt the task is on may save, an older one would set it back.
+        for (session, task, checkpoint) in task_checkpoints {
+            let current = world.get::<&TaskState>(task).is_ok_and(|state| {
+                state.assigned_device == Some(session)
+                    && state.attempt == checkpoint.attempt
+                    && state.phase != TaskStatePhase::Completed
+            });
+            if current {
+                world.insert_one(task, checkpoint).ok();
+            }
+        }
+
+        for entity in handshakes {
+            world.remove_one::<SessionHandshake>(entity).ok();
+        }
+
+        // A token naming no live session, e.g. one removed as a zombie meanwhile, starts over.
+        for (entity, token) in resumes {
+            let resumed = token.and_then(|token| LifecycleSystem::resume_session::<T>(world, entity, token));
+            let session = resumed.unwrap_or(entity);
+            let issued = world.get::<&SessionResume>(session).map(|resume| *resume).ok();
+            let resume = issued.unwrap_or_else(SessionResume::issue);
+            if issued.is_none() {
+                world.insert_one(session, resume).ok();
+            }
+            if let Ok(mut session) = world.get::<&mut Session>(session) {
+                session.message_queue.push_back(ServerMessage::ServerSession {
+                    resume_token: resume.token,
+                    resumed: resumed.is_some(),
+                });
+            }
+        }
+
+        let mut rejected_tasks = Vec::new();
+        let mut firmware_acks = Vec::new();
 
+        for (entity, acks) in transfer_acks {
             if let Ok(mut transfer) = world.get::<&mut ModuleTransfer>(entity) {
+                // Firmware transfers point at a `Firmware`, no module list ever names it.
+                let module_name = world
+                    .get::<&Module>(transfer.module)
+                    .map(|module| module.name.clone())
+                    .or_else(|_| world.get::<&Blob>(transfer.module).map(|blob| protocol::blob_name(blob.id)))
+                    .ok();
+
                 for ack_info in acks {
                     match ack_info {
-                        AckInfo::Chunk { chunk_index, success } => {
-                            transfer.acked_chunks.set(chunk_index as usize, success);
+                        AckInfo::ChunkAck { chunk_index, success } => {
+                            TaskSystem::ack_chunk(world, &mut transfer, chunk_index, success, SystemTime::now());
                         }
-                        AckInfo::Module { modules } => {
+                        AckInfo::ModuleListAck { modules } => {
                             transfer.state = ModuleTransferState::Requested;
-                            if modules.contains(&module_name) {
+                            if module_name.as_ref().is_some_and(|name| modules.contains(name)) {
                                 transfer.acked_chunks.fill(true);
                                 break;
                             }
                         }
+                        AckInfo::TaskAck { accepted } => {
+                            if !accepted {
+                                rejected_tasks.push(entity);
+                                break;
+                            }
+                            if transfer.state == ModuleTransferState::Pending {
+                                transfer.state = ModuleTransferState::Requested;
+                            }
+                        }
+                        AckInfo::FirmwareAck { verified } => {
+                            firmware_acks.push((entity, transfer.session, verified));
+                            break;
+                        }
                     }
                 }
             }
         }
 
-        for (entity, result) in task_result {
-            let mut device_entity = None;
-            if let Ok((task, state)) = world.query_one_mut::<(&mut Task, &mut TaskState)>(entity) {
-                device_entity = state.assigned_device;
-                task.result = result;
-                state.phase = TaskStatePhase::Completed;
+        // A verified device reboots and reconnects as a new session, so it stays occupied.
+        for (entity, device, verified) in firmware_acks {
+            match verified {
+                true => info!("Device {:?} verified firmware, rebooting", device),
+                false => {
+                    warn!("Device {:?} failed to verify firmware", device);
+                    Self::release_device(world, device);
+                }
             }
-            if let Some(device_entity) = device_entity {
-                if let Ok(mut session) = world.get::<&mut Session>(device_entity) {
-                    session.message_queue.push_back(Message::ServerAck {
-                        task_id: entity.to_bits().into(),
-                        success: true,
-                    });
+            world.despawn(entity).ok();
+        }
+
+        for entity in rejected_tasks {
+            if world.satisfies::<&ModulePrefetch>(entity).unwrap_or(false)
+                || world.satisfies::<&FirmwareUpdate>(entity).unwrap_or(false)
+            {
+                warn!("Transfer {:?} rejected by device", entity);
+                if let Ok(device) = world.get::<&ModuleTransfer>(entity).map(|transfer| transfer.session) {
+                    Self::release_device(world, device);
+                }
+                world.despawn(entity).ok();
+                continue;
+            }
+            let tasks = match (world.get::<&ModuleTransfer>(entity), world.get::<&BlobFetch>(entity)) {
+                (Ok(_), Ok(fetch)) => vec![fetch.task],
+                (Ok(transfer), Err(_)) => TaskSystem::waiting_tasks(world, &transfer),
+                (Err(_), _) => continue,
+            };
+            world.despawn(entity).ok();
+            for task in tasks {
+                warn!("Task {:?} rejected by device, requeue", task);
+                if let Ok(mut state) = world.get::<&mut TaskState>(task) {
+                    state.phase = TaskStatePhase::Queued;
+                    state.assigned_device = None;
                 }
             }
         }
+
+        for (device, task) in busy_tasks {
+            if let Ok(mut state) = world.get::<&mut TaskState>(task) {
+                if state.assigned_device == Some(device) && state.phase == TaskStatePhase::Distributing {
+                    warn!("Task {:?} refused by busy device, requeue", task);
+                    state.phase = TaskStatePhase::Queued;
+                    state.assigned_device = None;
+                }
+            }
+        }
+
+        for (entity, module_name, params, priority) in task_submit {
+            let task_id = module_entities.get(&module_name).map(|&module_entity| {
+                let task_id = next_task_id();
+                world.spawn((
+                    Task {
+                        name: format!("{}_{}", module_name, task_id),
+                        params,
+                        env: vec![],
+                        result: vec![],
+                        created_at: SystemTime::now(),
+                        require_module: module_entity,
+                        priority,
+                        kind: TaskKind::Single,
+                    },
+                    TaskState {
+                        phase: TaskStatePhase::Queued,
+                        assigned_device: None,
+                        results: HashMap::new(),
+                        progress: None,
+                        transfer: None,
+                        attempt: 0,
+                    },
+                    task_id,
+                    TaskOrigin { session: entity },
+                ));
+                task_id
+            });
+
+            if task_id.is_none() {
+                warn!("Session {:?} submitted unknown module {}", entity, module_name);
+            }
+            if let Ok(mut session) = world.get::<&mut Session>(entity) {
+                session.message_queue.push_back(ServerMessage::ServerSubmitted { task_id });
+            }
+        }
+
+        for (device, entity, attempt, result, metrics) in task_result {
+            let Ok((task, task_id, state)) = world.query_one_mut::<(&mut Task, &TaskId, &mut TaskState)>(entity)
+            else {
+                continue;
+            };
+            let task_id = *task_id;
+
+            // A reassigned task, or the loser of a speculative race, is no longer waiting on this
+            // device.
+            if state.attempt != attempt || state.assigned_device != Some(device) {
+                debug!(
+                    "Dropping result of task {:?} attempt {} from {:?}, the task is on attempt {} at {:?}",
+                    entity, attempt, device, state.attempt, state.assigned_device
+                );
+                Self::acknowledge(world, device, task_id, false);
+                continue;
+            }
+            // The device missed the first ServerAck and resent the result.
+            if state.phase == TaskStatePhase::Completed {
+                debug!("Task {:?} attempt {} already completed, acknowledging again", entity, attempt);
+                Self::acknowledge(world, device, task_id, true);
+                continue;
+            }
+
+            let module_entity = task.require_module;
+            task.result = result.clone();
+            state.phase = TaskStatePhase::Completed;
+            Self::store_result(world, entity, attempt, &metrics);
+            world.remove_one::<TaskCheckpoint>(entity).ok();
+            Self::record_metrics(world, entity, module_entity, metrics);
+            Self::release_device(world, device);
+            Self::acknowledge(world, device, task_id, true);
+
+            let origin = world.get::<&TaskOrigin>(entity).map(|origin| origin.session).ok();
+            if let Some(mut session) = origin.and_then(|origin| world.get::<&mut Session>(origin).ok()) {
+                session.message_queue.push_back(ServerMessage::ServerResult { task_id, result });
+            }
+        }
+    }
+
+    fn acknowledge(world: &mut World, device: Entity, task_id: TaskId, success: bool) {
+        if let Ok(mut session) = world.get::<&mut Session>(device) {
+            session.message_queue.push_back(ServerMessage::ServerAck { task_id, success });
+        }
+    }
+
+    fn record_metrics(world: &mut World, task: Entity, module: Entity, metrics: TaskMetrics) {
+        if world.get::<&ModuleCost>(module).is_err() {
+            world.insert_one(module, ModuleCost::default()).ok();
+        }
+        if let Ok(mut cost) = world.get::<&mut ModuleCost>(module) {
+            cost.classes.entry(metrics.device_class.clone()).or_default().record(&metrics);
+        }
+        world.insert_one(task, metrics).ok();
+    }
+
+    // Kept past the task entity when a result store is attached, a failed write only loses the copy.
+    fn store_result(world: &World, task: Entity, attempt: u32, metrics: &TaskMetrics) {
+        let Some(store) = ResultStore::get(world) else {
+            return;
+        };
+        let Ok(mut query) = world.query_one::<(&Task, &TaskId)>(task) else {
+            return;
+        };
+        let Some((task, &task_id)) = query.get() else {
+            return;
+        };
+        let module = world.get::<&Module>(task.require_module).map(|module| module.name.clone()).unwrap_or_default();
+        let record = ResultRecord::new(task_id, attempt, &module, task, metrics, SystemTime::now());
+        if let Err(e) = store.record(&record) {
+            warn!("Failed to store result of task {:?}: {}", task_id, e);
+        }
+    }
+
+    // A module list from the device replaces what the server believed, names of modules the
+    // server does not know are dropped.
+    fn restock(inventory: &mut DeviceInventory, modules: &[String], module_hashes: &HashMap<String, u32>) {
+        inventory.clear();
+        for name in modules {
+            if let Some(&hash) = module_hashes.get(name) {
+                inventory.insert(hash, name);
+            }
+        }
+    }
+
+    fn record_heartbeat(entity: Entity, session: &mut Session, now: SystemTime, timestamp: u64, cache: CacheStats) {
+        let last_record = UNIX_EPOCH + Duration::from_nanos(timestamp);
+        let latency = now.duration_since(last_record).unwrap_or_default();
+        info!(
+            "Session {entity:?} received heartbeat with latency {}ms and cache {:?}",
+            latency.as_millis(),
+            cache
+        );
+        session.latency = latency;
+        session.cache_stats = cache;
+    }
+
+    fn release_device(world: &mut World, device: Entity) {
+        if let Ok(mut health) = world.get::<&mut SessionHealth>(device) {
+            if health.status == SessionStatus::Occupied {
+                health.status = SessionStatus::Connected;
+            }
+        }
     }
 
     pub async fn process_outbound<T>(world: &mut World)
     where
         T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
-        for (entity, (session, stream, health)) in world
-            .query::<(&mut Session, &mut SessionStream<T>, &mut SessionHealth)>()
+        for (entity, (session, stream, health, shared)) in world
+            .query::<(&mut Session, &mut SessionStream<T>, &mut SessionHealth, Option<&mut SharedFrames>)>()
             .iter()
         {
             let mut locked_stream = match stream.inner.try_lock() {
@@ -169,10 +605,27 @@ impl NetworkSystem {
             };
 
             while let Some(msg) = session.message_queue.pop_front() {
-                if let Ok(data) = msg.encode() {
+                let msg = Message::from(msg);
+                let encoded = match stream.next_sequence.as_mut() {
+                    Some(sequence) => {
+                        let encoded = msg.encode_sequenced(*sequence);
+                        *sequence = sequence.wrapping_add(1);
+                        encoded
+                    }
+                    None => msg.encode(),
+                };
+                if let Ok(data) = encoded {
                     stream.outgoing.extend(data);
                 }
             }
+            // Already encoded, only numbered here when the session numbers its frames.
+            for frame in shared.into_iter().flat_map(|shared| shared.0.drain(..)) {
+                if let Some(sequence) = stream.next_sequence.as_mut() {
+                    stream.outgoing.extend_from_slice(&Message::sequence_prefix(*sequence));
+                    *sequence = sequence.wrapping_add(1);
+                }
+                stream.outgoing.extend_from_slice(&frame);
+            }
 
             if stream.outgoing.is_empty() {
                 continue;
@@ -204,16 +657,20 @@ impl NetworkSystem {
 
 #[cfg(test)]
 mod tests {
-    use std::collections::{HashSet, VecDeque};
+    use std::collections::VecDeque;
     use std::sync::Arc;
 
     use bitvec::prelude::*;
     use bytes::BytesMut;
-    use protocol::{ModuleInfo, Type};
+    use protocol::{
+        CacheStats, ExecutionStats, ExecutorFlavor, LogLevel, ModuleInfo, SequenceStats, SequenceTracker, SessionStats,
+        Telemetry, Type,
+    };
     use tokio::io::{duplex, DuplexStream};
     use tokio::sync::Mutex;
 
     use super::*;
+    use crate::results::ResultQuery;
 
     const TOTAL_SIZE: usize = 1024;
     const CHUNK_SIZE: usize = 256;
@@ -226,22 +683,31 @@ mod tests {
             Session {
                 message_queue: VecDeque::new(),
                 latency: Duration::default(),
-                modules: HashSet::new(),
+                cache_stats: CacheStats::default(),
             },
+            DeviceInventory::default(),
             SessionInfo {
                 device_addr: "0.0.0.0:0".parse().unwrap(),
                 device_ram: 1024,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
             },
             SessionStream {
                 inner: stream.clone(),
                 incoming: BytesMut::new(),
                 outgoing: BytesMut::new(),
+                sequence: SequenceTracker::default(),
+                next_sequence: None,
             },
             SessionHealth {
                 retries: 0,
                 status: SessionStatus::Connected,
                 last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
             },
+            SessionLabels::default(),
         ))
     }
 
@@ -252,30 +718,43 @@ mod tests {
                 binary: vec![0u8; TOTAL_SIZE],
                 dependencies: Vec::default(),
                 chunk_size: CHUNK_SIZE as u32,
+                pinned: false,
             },
         ))
     }
 
     fn create_mock_task(world: &mut World, session_entity: &Entity, module_entity: &Entity) -> Entity {
         let total_chunks = TOTAL_SIZE.div_ceil(CHUNK_SIZE);
+        let task_id = next_task_id();
+        world.spawn((ModuleTransfer {
+            task_id,
+            state: ModuleTransferState::Requested,
+            acked_chunks: bitvec![0; total_chunks],
+            session: *session_entity,
+            module: *module_entity,
+            arch: None,
+            in_flight: Default::default(),
+        },));
         world.spawn((
             Task {
                 name: "mock_task".into(),
                 params: vec![Type::I32(0)],
+                env: vec![],
                 result: Vec::default(),
                 created_at: SystemTime::now(),
                 require_module: *module_entity,
                 priority: 1,
+                kind: TaskKind::Single,
             },
             TaskState {
-                phase: TaskStatePhase::Queued,
+                phase: TaskStatePhase::Distributing,
                 assigned_device: Some(*session_entity),
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 1,
             },
-            ModuleTransfer {
-                state: ModuleTransferState::Requested,
-                acked_chunks: bitvec![0; total_chunks],
-                session: *session_entity,
-            },
+            task_id,
         ))
     }
 
@@ -291,6 +770,12 @@ mod tests {
                 .duration_since(UNIX_EPOCH)
                 .unwrap_or_default()
                 .as_nanos() as u64,
+            cache: CacheStats {
+                hits: 3,
+                misses: 1,
+                evictions: 0,
+                bytes_used: 512,
+            },
         };
 
         let latency = world.get::<&Session>(session_entity).unwrap().latency;
@@ -300,6 +785,9 @@ mod tests {
         NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
         let latency = world.get::<&Session>(session_entity).unwrap().latency;
         assert!(latency.as_nanos() > 0);
+        let cache_stats = world.get::<&Session>(session_entity).unwrap().cache_stats.clone();
+        assert_eq!(cache_stats.hits, 3);
+        assert_eq!(cache_stats.bytes_used, 512);
     }
 
     #[tokio::test]
@@ -308,10 +796,17 @@ mod tests {
         let mut world = World::new();
 
         let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        world.insert_one(session_entity, SessionHandshake { deadline }).unwrap();
+        let module_entity = create_mock_module(&mut world);
 
         let message = Message::ClientReady {
-            modules: Vec::new(),
+            modules: vec!["mock_module".into(), "unknown_module".into()],
             device_ram: 2048,
+            labels: vec!["camera".into()],
+            executor: ExecutorFlavor::Aot,
+            arch: "xtensa".into(),
+            resume_token: None,
         };
 
         let ram = world.get::<&SessionInfo>(session_entity).unwrap().device_ram;
@@ -321,6 +816,208 @@ mod tests {
         NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
         let ram = world.get::<&SessionInfo>(session_entity).unwrap().device_ram;
         assert_eq!(ram, 2048);
+        assert!(!world.satisfies::<&SessionHandshake>(session_entity).unwrap());
+        let info = world.get::<&SessionInfo>(session_entity).unwrap().clone();
+        assert_eq!(info.executor, ExecutorFlavor::Aot);
+        assert_eq!(info.arch, "xtensa");
+        let labels = &world.get::<&SessionLabels>(session_entity).unwrap().labels;
+        assert!(labels.contains("camera"));
+        let hash = world.get::<&Module>(module_entity).unwrap().hash();
+        let inventory = world.get::<&DeviceInventory>(session_entity).unwrap();
+        assert_eq!(inventory.len(), 1);
+        assert!(inventory.contains(hash));
+        let token = world.get::<&SessionResume>(session_entity).unwrap().token;
+        assert_eq!(
+            world.get::<&Session>(session_entity).unwrap().message_queue.back(),
+            Some(&ServerMessage::ServerSession { resume_token: token, resumed: false })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_resume() {
+        let (_, stale) = duplex(1024);
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let previous = create_mock_network(&mut world, &Arc::new(Mutex::new(stale)));
+        world.insert_one(previous, SessionResume { token: 7 }).unwrap();
+        world.get::<&mut SessionHealth>(previous).unwrap().status = SessionStatus::Disconnected;
+        let module_entity = create_mock_module(&mut world);
+        let task_entity = create_mock_task(&mut world, &previous, &module_entity);
+        let transfer = TaskSystem::module_transfer(&world, previous, module_entity).unwrap();
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().state = ModuleTransferState::Transferring;
+
+        let reconnected = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let deadline = SystemTime::now() + Duration::from_secs(10);
+        world.insert_one(reconnected, SessionHandshake { deadline }).unwrap();
+        let message = Message::ClientReady {
+            modules: vec!["mock_module".into()],
+            device_ram: 2048,
+            labels: Vec::new(),
+            executor: ExecutorFlavor::Interpreter,
+            arch: String::new(),
+            resume_token: Some(7),
+        };
+        client.write_all(&message.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        assert!(!world.contains(reconnected));
+        assert_eq!(world.get::<&SessionInfo>(previous).unwrap().device_ram, 2048);
+        assert_eq!(world.get::<&SessionHealth>(previous).unwrap().status, SessionStatus::Occupied);
+        assert_eq!(world.get::<&TaskState>(task_entity).unwrap().assigned_device, Some(previous));
+        assert_eq!(world.get::<&ModuleTransfer>(transfer).unwrap().state, ModuleTransferState::Requested);
+        assert_eq!(
+            world.get::<&Session>(previous).unwrap().message_queue.back(),
+            Some(&ServerMessage::ServerSession { resume_token: 7, resumed: true })
+        );
+
+        // The new connection now reads for the resumed session.
+        let heartbeat = Message::Heartbeat { timestamp: 0, cache: CacheStats { hits: 3, ..Default::default() } };
+        client.write_all(&heartbeat.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert_eq!(world.get::<&Session>(previous).unwrap().cache_stats.hits, 3);
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_progress() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let module_entity = create_mock_module(&mut world);
+        let task_entity = create_mock_task(&mut world, &session_entity, &module_entity);
+        world.get::<&mut SessionHealth>(session_entity).unwrap().status = SessionStatus::Occupied;
+
+        let message = Message::ClientProgress {
+            task_id: *world.get::<&TaskId>(task_entity).unwrap(),
+            percent: 250,
+            stage: "transfer".into(),
+        };
+        client.write_all(&message.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        let progress = world.get::<&TaskState>(task_entity).unwrap().progress.clone().unwrap();
+        assert_eq!(progress.percent, 100);
+        assert_eq!(progress.stage, "transfer");
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_checkpoint() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let module_entity = create_mock_module(&mut world);
+        let task_entity = create_mock_task(&mut world, &session_entity, &module_entity);
+        world.get::<&mut SessionHealth>(session_entity).unwrap().status = SessionStatus::Occupied;
+
+        // The checkpoint of an attempt the task has moved on from is dropped.
+        let task_id = *world.get::<&TaskId>(task_entity).unwrap();
+        let messages = [
+            Message::ClientCheckpoint { task_id, attempt: 1, data: vec![1, 2] },
+            Message::ClientCheckpoint { task_id, attempt: 0, data: vec![9] },
+        ];
+        let encoded = messages.iter().flat_map(|message| message.encode().unwrap()).collect::<Vec<_>>();
+        client.write_all(&encoded).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        let checkpoint = (*world.get::<&TaskCheckpoint>(task_entity).unwrap()).clone();
+        assert_eq!((checkpoint.attempt, checkpoint.data), (1, vec![1, 2]));
+
+        let result = Message::ClientResult {
+            task_id,
+            attempt: 1,
+            result: vec![],
+            stats: ExecutionStats::default(),
+        };
+        client.write_all(&result.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert!(world.get::<&TaskCheckpoint>(task_entity).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_cache_update() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let module_entity = create_mock_module(&mut world);
+        let hash = world.get::<&Module>(module_entity).unwrap().hash();
+        world.get::<&mut DeviceInventory>(session_entity).unwrap().insert(hash, "mock_module");
+
+        let message = Message::ClientCacheUpdate {
+            added: vec!["unknown_module".into()],
+            removed: vec!["mock_module".into()],
+        };
+        client.write_all(&message.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert!(world.get::<&DeviceInventory>(session_entity).unwrap().is_empty());
+
+        let message = Message::ClientCacheUpdate {
+            added: vec!["mock_module".into()],
+            removed: vec![],
+        };
+        client.write_all(&message.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert!(world.get::<&DeviceInventory>(session_entity).unwrap().contains(hash));
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_telemetry() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let telemetry = Telemetry {
+            free_heap: Some(40_000),
+            tasks_executed: 7,
+            uptime_secs: 120,
+            rssi: Some(-58),
+            sequence: None,
+            session: SessionStats {
+                frames_in: 12,
+                executions: 7,
+                cache_hits: 5,
+                ..Default::default()
+            },
+        };
+        let message = Message::ClientTelemetry {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64,
+            cache: CacheStats {
+                bytes_used: 256,
+                ..Default::default()
+            },
+            telemetry,
+        };
+        client.write_all(&message.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        assert_eq!(world.get::<&SessionTelemetry>(session_entity).unwrap().telemetry, telemetry);
+        assert_eq!(world.get::<&Session>(session_entity).unwrap().cache_stats.bytes_used, 256);
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_log() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+
+        for message in ["booted", "wifi rssi -71"] {
+            let log = Message::ClientLog {
+                level: LogLevel::Info,
+                module: "esp".into(),
+                message: message.into(),
+                timestamp: 42,
+            };
+            client.write_all(&log.encode().unwrap()).await.unwrap();
+        }
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        let logs = world.get::<&SessionLogs>(session_entity).unwrap();
+        let messages = logs.entries.iter().map(|entry| entry.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(messages, ["booted", "wifi rssi -71"]);
+        assert_eq!(logs.entries[0].timestamp, 42);
     }
 
     #[tokio::test]
@@ -335,15 +1032,21 @@ mod tests {
 
         let messages = [
             Message::ClientAck {
-                task_id: task_entity.to_bits().into(),
-                ack_info: AckInfo::Chunk {
+                task_id: *world.get::<&TaskId>(task_entity).unwrap(),
+                ack_info: AckInfo::ChunkAck {
                     chunk_index: 2,
                     success: true,
                 },
             },
             Message::ClientResult {
-                task_id: task_entity.to_bits().into(),
+                task_id: *world.get::<&TaskId>(task_entity).unwrap(),
+                attempt: 1,
                 result: vec![Type::I32(0xcc), Type::I32(0xdd)],
+                stats: ExecutionStats {
+                    wall_time_us: 2000,
+                    peak_memory: 4096,
+                    instructions: None,
+                },
             },
         ];
 
@@ -369,12 +1072,297 @@ mod tests {
         NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
         job_handle.await.unwrap();
         NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
-        let acked = &world.get::<&ModuleTransfer>(task_entity).unwrap().acked_chunks;
+        let transfer = TaskSystem::module_transfer(&world, session_entity, module_entity).unwrap();
+        let acked = &world.get::<&ModuleTransfer>(transfer).unwrap().acked_chunks;
         assert_eq!(*acked, bits![0, 0, 1, 0]);
         let phase = &world.get::<&TaskState>(task_entity).unwrap().phase;
         assert_eq!(*phase, TaskStatePhase::Completed);
         let result = &world.get::<&Task>(task_entity).unwrap().result;
         assert_eq!(*result, vec![Type::I32(0xcc), Type::I32(0xdd)]);
+        let metrics = world.get::<&TaskMetrics>(task_entity).unwrap().clone();
+        assert_eq!(metrics.device, session_entity);
+        assert_eq!(metrics.wall_time, Duration::from_millis(2));
+        let module = world.get::<&Task>(task_entity).unwrap().require_module;
+        let cost = world.get::<&ModuleCost>(module).unwrap();
+        assert_eq!(cost.classes[&metrics.device_class].samples, 1);
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_duplicate_result() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let module_entity = create_mock_module(&mut world);
+        let task_entity = create_mock_task(&mut world, &session_entity, &module_entity);
+        let task_id = *world.get::<&TaskId>(task_entity).unwrap();
+        world.get::<&mut SessionHealth>(session_entity).unwrap().status = SessionStatus::Occupied;
+        let store = ResultStore::open_in_memory().unwrap();
+        ResultStore::set(&mut world, store.clone());
+
+        let result = |attempt, value| Message::ClientResult {
+            task_id,
+            attempt,
+            result: vec![Type::I32(value)],
+            stats: ExecutionStats::default(),
+        };
+        for message in [result(1, 1), result(1, 2), result(0, 3)] {
+            client.write_all(&message.encode().unwrap()).await.unwrap();
+        }
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        assert_eq!(world.get::<&TaskState>(task_entity).unwrap().phase, TaskStatePhase::Completed);
+        assert_eq!(world.get::<&Task>(task_entity).unwrap().result, vec![Type::I32(1)]);
+        assert_eq!(world.get::<&SessionHealth>(session_entity).unwrap().status, SessionStatus::Connected);
+        let cost = world.get::<&ModuleCost>(module_entity).unwrap();
+        assert_eq!(cost.classes.values().map(|class| class.samples).sum::<u32>(), 1);
+        drop(cost);
+        let page = store.query(&ResultQuery { limit: 10, ..Default::default() }).unwrap();
+        assert_eq!(page.results.len(), 1);
+        assert_eq!((page.results[0].task_id, page.results[0].attempt), (task_id.0, 1));
+        assert_eq!(page.results[0].result, vec![Type::I32(1)]);
+
+        // The resent result is acknowledged again, the stale attempt is refused.
+        let acks = world
+            .get::<&Session>(session_entity)
+            .unwrap()
+            .message_queue
+            .iter()
+            .filter_map(|message| match message {
+                ServerMessage::ServerAck { task_id: id, success } if *id == task_id => Some(*success),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(acks, vec![true, true, false]);
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_task_rejected() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let module_entity = create_mock_module(&mut world);
+        let task_entity = create_mock_task(&mut world, &session_entity, &module_entity);
+
+        world
+            .get::<&mut SessionHealth>(session_entity)
+            .unwrap()
+            .status = SessionStatus::Occupied;
+
+        let message = Message::ClientAck {
+            task_id: *world.get::<&TaskId>(task_entity).unwrap(),
+            ack_info: AckInfo::TaskAck { accepted: false },
+        };
+        client.write_all(&message.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        let state = world.get::<&TaskState>(task_entity).unwrap();
+        assert_eq!(state.phase, TaskStatePhase::Queued);
+        assert_eq!(state.assigned_device, None);
+        assert!(TaskSystem::module_transfer(&world, session_entity, module_entity).is_none());
+        let status = &world.get::<&SessionHealth>(session_entity).unwrap().status;
+        assert_eq!(*status, SessionStatus::Connected);
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_client_busy() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let module_entity = create_mock_module(&mut world);
+        let task_entity = create_mock_task(&mut world, &session_entity, &module_entity);
+
+        world
+            .get::<&mut SessionHealth>(session_entity)
+            .unwrap()
+            .status = SessionStatus::Occupied;
+
+        let message = Message::ClientBusy {
+            task_id: *world.get::<&TaskId>(task_entity).unwrap(),
+            queue_len: 2,
+        };
+        client.write_all(&message.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        let state = world.get::<&TaskState>(task_entity).unwrap();
+        assert_eq!(state.phase, TaskStatePhase::Queued);
+        assert_eq!(state.assigned_device, None);
+        assert!(TaskSystem::module_transfer(&world, session_entity, module_entity).is_none());
+        let status = &world.get::<&SessionHealth>(session_entity).unwrap().status;
+        assert_eq!(*status, SessionStatus::Connected);
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_submit() {
+        let (mut client, server) = duplex(1024);
+        let (mut worker_client, worker_server) = duplex(1024);
+        let mut world = World::new();
+
+        let origin_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        let worker_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(worker_server)));
+        create_mock_module(&mut world);
+
+        let messages = [
+            Message::ClientSubmit {
+                module_name: "mock_module".into(),
+                params: vec![Type::I32(1)],
+                priority: 2,
+            },
+            Message::ClientSubmit {
+                module_name: "unknown_module".into(),
+                params: vec![],
+                priority: 1,
+            },
+        ];
+        for message in messages {
+            client.write_all(&message.encode().unwrap()).await.unwrap();
+        }
+        let heartbeat = Message::Heartbeat {
+            timestamp: 0,
+            cache: CacheStats::default(),
+        };
+        worker_client.write_all(&heartbeat.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        let replies = world.get::<&Session>(origin_entity).unwrap().message_queue.clone();
+        let task_id = match replies.front() {
+            Some(ServerMessage::ServerSubmitted { task_id: Some(task_id) }) => *task_id,
+            other => panic!("unexpected reply {:?}", other),
+        };
+        assert!(matches!(replies.get(1), Some(ServerMessage::ServerSubmitted { task_id: None })));
+        world.get::<&mut Session>(origin_entity).unwrap().message_queue.clear();
+
+        let (task_entity, (task, origin)) = world
+            .query::<(&Task, &TaskOrigin)>()
+            .iter()
+            .map(|(entity, (task, origin))| (entity, (task.clone(), origin.clone())))
+            .next()
+            .unwrap();
+        assert_eq!(task.priority, 2);
+        assert_eq!(origin.session, origin_entity);
+
+        world.get::<&mut TaskState>(task_entity).unwrap().assigned_device = Some(worker_entity);
+        world
+            .get::<&mut SessionHealth>(worker_entity)
+            .unwrap()
+            .status = SessionStatus::Occupied;
+        let result = Message::ClientResult {
+            task_id,
+            attempt: 0,
+            result: vec![Type::I32(2)],
+            stats: ExecutionStats::default(),
+        };
+        worker_client.write_all(&result.encode().unwrap()).await.unwrap();
+        client.write_all(&heartbeat.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+
+        assert!(matches!(
+            world.get::<&Session>(origin_entity).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerResult { task_id: id, result }) if *id == task_id && *result == vec![Type::I32(2)]
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_rate_limit() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+        world
+            .insert_one(
+                session_entity,
+                SessionRateLimit::new(2, 1024, Duration::from_secs(60), RateLimitAction::Throttle),
+            )
+            .unwrap();
+
+        let heartbeat = Message::Heartbeat {
+            timestamp: 0,
+            cache: CacheStats::default(),
+        };
+        for _ in 0..3 {
+            client.write_all(&heartbeat.encode().unwrap()).await.unwrap();
+        }
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert!(!world.get::<&SessionStream<DuplexStream>>(session_entity).unwrap().incoming.is_empty());
+        assert_eq!(world.get::<&SessionHealth>(session_entity).unwrap().status, SessionStatus::Connected);
+
+        // Throttled sessions are skipped without touching the stream.
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert_eq!(world.get::<&SessionRateLimit>(session_entity).unwrap().messages, 3);
+
+        {
+            let mut limit = world.get::<&mut SessionRateLimit>(session_entity).unwrap();
+            limit.action = RateLimitAction::Disconnect;
+            limit.window = Duration::ZERO;
+        }
+        for _ in 0..3 {
+            client.write_all(&heartbeat.encode().unwrap()).await.unwrap();
+        }
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert_eq!(world.get::<&SessionHealth>(session_entity).unwrap().status, SessionStatus::Zombie);
+        assert!(matches!(
+            world.get::<&Session>(session_entity).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerRateLimited { max_messages: 2, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_process_inbound_protocol_error() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+
+        // An unknown variant is skipped and the heartbeat behind it still gets through.
+        let heartbeat = Message::Heartbeat {
+            timestamp: 42,
+            cache: CacheStats::default(),
+        };
+        client.write_all(&[0, 2, 250, 0]).await.unwrap();
+        client.write_all(&heartbeat.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert!(world.get::<&SessionStream<DuplexStream>>(session_entity).unwrap().incoming.is_empty());
+        assert!(matches!(
+            world.get::<&Session>(session_entity).unwrap().message_queue.front(),
+            Some(ServerMessage::ProtocolError { code: ProtocolErrorCode::Malformed, .. })
+        ));
+        {
+            let health = world.get::<&SessionHealth>(session_entity).unwrap();
+            assert_eq!((health.protocol_errors, health.status.clone()), (1, SessionStatus::Connected));
+            assert!(health.last_protocol_error.as_ref().unwrap().starts_with("refused Malformed"));
+        }
+
+        let report = Message::ProtocolError {
+            code: ProtocolErrorCode::Unexpected,
+            detail: "chunk 9 out of range".into(),
+        };
+        client.write_all(&report.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert_eq!(
+            world.get::<&SessionHealth>(session_entity).unwrap().last_protocol_error.as_deref(),
+            Some("device refused Unexpected: chunk 9 out of range")
+        );
+
+        // A device sending what only the server sends is refused the same way.
+        let cancel = Message::ServerCancel { task_id: TaskId(3) };
+        client.write_all(&cancel.encode().unwrap()).await.unwrap();
+        NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        assert!(matches!(
+            world.get::<&Session>(session_entity).unwrap().message_queue.back(),
+            Some(ServerMessage::ProtocolError { code: ProtocolErrorCode::Misdirected, .. })
+        ));
+        assert_eq!(world.get::<&SessionHealth>(session_entity).unwrap().protocol_errors, 3);
+
+        for _ in 0..NetworkSystem::MAX_PROTOCOL_ERRORS {
+            client.write_all(&[0, 1, 250]).await.unwrap();
+        }
+        for _ in 0..4 {
+            NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        }
+        let health = world.get::<&SessionHealth>(session_entity).unwrap();
+        assert_eq!(health.protocol_errors, NetworkSystem::MAX_PROTOCOL_ERRORS);
+        assert_eq!(health.status, SessionStatus::Zombie);
     }
 
     #[tokio::test]
@@ -398,15 +1386,21 @@ mod tests {
         let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
 
         if let Ok(mut session) = world.get::<&mut Session>(session_entity) {
-            session.message_queue.push_back(Message::ServerTask {
-                task_id: 0,
+            session.message_queue.push_back(ServerMessage::ServerTask {
+                task_id: TaskId(0),
+                attempt: 1,
                 module: ModuleInfo {
                     name: "mock_task".into(),
                     size: 1024,
                     chunk_size: 256,
                     total_chunks: 4,
+                    pinned: false,
                 },
                 params: vec![Type::I32(0xaa), Type::I32(0xbb)],
+                env: vec![],
+                libraries: vec![],
+                priority: 1,
+                checkpoint: None,
             });
         };
 
@@ -417,4 +1411,55 @@ mod tests {
         let decoded = Message::decode(&buf[..]).unwrap().0;
         assert!(matches!(decoded, Message::ServerTask { .. }));
     }
+
+    #[tokio::test]
+    async fn test_sequenced_frames() {
+        let (mut client, server) = duplex(1024);
+        let mut world = World::new();
+        let session_entity = create_mock_network(&mut world, &Arc::new(Mutex::new(server)));
+
+        let log = |message: &str| Message::ClientLog {
+            level: LogLevel::Info,
+            module: "app".into(),
+            message: message.into(),
+            timestamp: 0,
+        };
+        for (sequence, message) in [(0, "first"), (2, "third"), (2, "third"), (1, "second")] {
+            client.write_all(&log(message).encode_sequenced(sequence).unwrap()).await.unwrap();
+        }
+        for _ in 0..3 {
+            NetworkSystem::process_inbound::<DuplexStream>(&mut world).await;
+        }
+
+        let logs = world.get::<&SessionLogs>(session_entity).unwrap();
+        let messages = logs.entries.iter().map(|entry| entry.message.as_str()).collect::<Vec<_>>();
+        assert_eq!(messages, vec!["first", "third", "second"]);
+        drop(logs);
+        assert_eq!(world.get::<&SessionHealth>(session_entity).unwrap().sequence, Some(SequenceStats {
+            received: 3,
+            duplicates: 1,
+            reordered: 1,
+            missing: 0,
+        }));
+
+        // Frames to the device are numbered from then on, shared ones after the queued messages.
+        {
+            let mut session = world.get::<&mut Session>(session_entity).unwrap();
+            for task_id in [4, 5] {
+                session.message_queue.push_back(ServerMessage::ServerCancel { task_id: TaskId(task_id) });
+            }
+        }
+        let shared = Message::ServerCancel { task_id: TaskId(6) };
+        let frames = SharedFrames([shared.encode().unwrap().into()].into());
+        world.insert_one(session_entity, frames).unwrap();
+        NetworkSystem::process_outbound::<DuplexStream>(&mut world).await;
+        let mut buf = BytesMut::new();
+        client.read_buf(&mut buf).await.unwrap();
+        let (first, sequence, consumed) = Message::decode_sequenced(&buf).unwrap();
+        assert_eq!((first, sequence), (Message::ServerCancel { task_id: TaskId(4) }, Some(0)));
+        let (_, sequence, second) = Message::decode_sequenced(&buf[consumed..]).unwrap();
+        assert_eq!(sequence, Some(1));
+        assert_eq!(Message::decode_sequenced(&buf[consumed + second..]).unwrap(), (shared, Some(2), buf.len() - consumed - second));
+        assert!(world.get::<&SharedFrames>(session_entity).unwrap().0.is_empty());
+    }
 }
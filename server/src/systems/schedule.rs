@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+use hecs::World;
+use log::{debug, info};
+
+use super::{LifecycleSystem, TaskSystem};
+use crate::components::*;
+use crate::error::Error;
+
+pub struct ScheduleSystem;
+
+impl ScheduleSystem {
+    const PREFETCH_LEAD: Duration = Duration::from_secs(30);
+
+    // Warms idle devices with the module of every recurring task about to fire.
+    pub fn prefetch_recurring(world: &mut World) -> Result<(), Error> {
+        let horizon = SystemTime::now() + Self::PREFETCH_LEAD;
+        let upcoming = world
+            .query_mut::<(&Task, &mut RecurringTask)>()
+            .into_iter()
+            .filter(|(_, (_, recurring))| !recurring.prefetched && recurring.next_run <= horizon)
+            .map(|(_, (task, recurring))| {
+                recurring.prefetched = true;
+                task.require_module
+            })
+            .collect::<HashSet<_>>();
+
+        for module in upcoming {
+            TaskSystem::prefetch_module(world, module)?;
+        }
+        Ok(())
+    }
+
+    pub fn enqueue_recurring(world: &mut World) {
+        let now = SystemTime::now();
+        let due = world
+            .query::<(&Task, &RecurringTask, Option<&TaskSelector>, Option<&TaskOwner>)>()
+            .iter()
+            .filter(|(_, (_, recurring, _, _))| recurring.next_run <= now)
+            .map(|(entity, (task, recurring, selector, owner))| {
+                (entity, task.clone(), recurring.clone(), selector.cloned(), owner.cloned())
+            })
+            .collect::<Vec<_>>();
+
+        let draining = LifecycleSystem::server_mode(world) == ServerMode::Draining;
+
+        for (entity, task, recurring, selector, owner) in due {
+            // A run still in flight is not stacked upon, the slot is skipped instead.
+            let running = recurring
+                .last_task
+                .and_then(|last| world.get::<&TaskState>(last).ok().map(|state| state.phase.clone()))
+                .is_some_and(|phase| phase != TaskStatePhase::Completed);
+
+            let mut last_task = recurring.last_task;
+            if draining {
+                debug!("Recurring task {} skipped a run while draining", task.name);
+            } else if running {
+                debug!("Recurring task {} skipped a run, previous run still in flight", task.name);
+            } else {
+                let task_id = next_task_id();
+                let instance = world.spawn((
+                    Task {
+                        name: format!("{}_{}", task.name, task_id),
+                        created_at: now,
+                        ..task.clone()
+                    },
+                    TaskState {
+                        phase: TaskStatePhase::Queued,
+                        assigned_device: None,
+                        results: HashMap::new(),
+                        progress: None,
+                        transfer: None,
+                        attempt: 0,
+                    },
+                    task_id,
+                ));
+                if let Some(selector) = selector {
+                    world.insert_one(instance, selector).ok();
+                }
+                if let Some(owner) = owner {
+                    world.insert_one(instance, owner).ok();
+                }
+                info!("Recurring task {} enqueued as task {}", task.name, task_id);
+                last_task = Some(instance);
+            }
+
+            match recurring.schedule.next_after(now) {
+                Some(next_run) => {
+                    if let Ok(mut recurring) = world.get::<&mut RecurringTask>(entity) {
+                        recurring.next_run = next_run;
+                        recurring.last_task = last_task;
+                        recurring.prefetched = false;
+                    }
+                }
+                None => {
+                    info!("Recurring task {} has no further runs", task.name);
+                    world.despawn(entity).ok();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use hecs::Entity;
+    use protocol::{CacheStats, ExecutorFlavor, ServerMessage};
+
+    use super::*;
+
+    fn create_mock_recurring(world: &mut World, schedule: Schedule) -> Entity {
+        let module = world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        world.spawn((
+            Task {
+                name: "mock_recurring".into(),
+                params: vec![],
+                env: vec![],
+                result: vec![],
+                created_at: SystemTime::now(),
+                require_module: module,
+                priority: 1,
+                kind: TaskKind::Single,
+            },
+            RecurringTask {
+                schedule,
+                next_run: SystemTime::now() - Duration::from_secs(1),
+                last_task: None,
+                prefetched: false,
+            },
+        ))
+    }
+
+    fn instances(world: &World) -> Vec<Entity> {
+        world.query::<&TaskState>().iter().map(|(entity, _)| entity).collect()
+    }
+
+    #[test]
+    fn test_enqueue_recurring() {
+        let mut world = World::new();
+        let template = create_mock_recurring(&mut world, Schedule::Every(Duration::from_secs(60)));
+
+        ScheduleSystem::enqueue_recurring(&mut world);
+        ScheduleSystem::enqueue_recurring(&mut world);
+        let first = instances(&world);
+        assert_eq!(first.len(), 1);
+        assert!(world.get::<&RecurringTask>(template).unwrap().next_run > SystemTime::now());
+
+        // The next slot is skipped while the previous run is still queued.
+        world.get::<&mut RecurringTask>(template).unwrap().next_run = SystemTime::now();
+        ScheduleSystem::enqueue_recurring(&mut world);
+        assert_eq!(instances(&world).len(), 1);
+
+        world.get::<&mut TaskState>(first[0]).unwrap().phase = TaskStatePhase::Completed;
+        world.get::<&mut RecurringTask>(template).unwrap().next_run = SystemTime::now();
+        ScheduleSystem::enqueue_recurring(&mut world);
+        assert_eq!(instances(&world).len(), 2);
+    }
+
+    #[test]
+    fn test_prefetch_recurring() {
+        let mut world = World::new();
+        let template = create_mock_recurring(&mut world, Schedule::Every(Duration::from_secs(60)));
+        world.get::<&mut RecurringTask>(template).unwrap().next_run = SystemTime::now() + Duration::from_secs(90);
+        let device = world.spawn((
+            Session {
+                message_queue: VecDeque::new(),
+                latency: Duration::default(),
+                cache_stats: CacheStats::default(),
+            },
+            DeviceInventory::default(),
+            SessionInfo {
+                device_addr: "0.0.0.0:0".parse().unwrap(),
+                device_ram: 4096,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
+            },
+            SessionHealth {
+                retries: 0,
+                status: SessionStatus::Connected,
+                last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
+            },
+        ));
+
+        ScheduleSystem::prefetch_recurring(&mut world).unwrap();
+        assert!(world.get::<&Session>(device).unwrap().message_queue.is_empty());
+
+        world.get::<&mut RecurringTask>(template).unwrap().next_run = SystemTime::now() + Duration::from_secs(10);
+        ScheduleSystem::prefetch_recurring(&mut world).unwrap();
+        assert!(world.get::<&RecurringTask>(template).unwrap().prefetched);
+        assert!(matches!(
+            world.get::<&Session>(device).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerPrefetch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_cron_schedule() {
+        let schedule = Schedule::cron("*/5 * * * *").unwrap();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(61);
+        assert_eq!(schedule.next_after(now), Some(SystemTime::UNIX_EPOCH + Duration::from_secs(300)));
+        assert!(Schedule::cron("not a schedule").is_err());
+    }
+}
@@ -1,25 +1,46 @@
 use std::cmp::{Ordering, Reverse};
-use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 use std::time::{Duration, SystemTime};
 
 use bitvec::vec::BitVec;
-use hecs::{Entity, World};
+use bytes::Bytes;
+use hecs::{Entity, Or, World};
 use log::{debug, info};
-use protocol::{Message, ModuleInfo};
+use protocol::{ExecutorFlavor, Message, ServerMessage, Type};
 
+use super::LifecycleSystem;
 use crate::components::*;
+use crate::error::Error;
 
 pub struct TaskSystem;
 
 impl TaskSystem {
-    pub fn assign_tasks(world: &mut World) {
+    const CHUNKS_IN_FLIGHT: usize = 4;
+    // A message unacked for this long is sent again and counts as failed.
+    const CHUNK_LOST_AFTER: Duration = Duration::from_secs(5);
+
+    pub fn assign_tasks(world: &mut World) -> Result<(), Error> {
+        let pause = Self::scheduling_pause(world);
+        if LifecycleSystem::server_mode(world) == ServerMode::Draining || pause.all {
+            return Ok(());
+        }
+
         #[derive(Debug, Eq, PartialEq)]
         struct TaskRecord {
             entity: Entity,
             module_entity: Entity,
+            module_hash: u32,
             size: usize,
+            // Bytes of the blobs and libraries the task needs, cached on the device next to the module.
+            inputs: usize,
             chunk_size: usize,
             priority: u8,
+            target: Option<Entity>,
+            selector: Option<TaskSelector>,
+            native: bool,
+            avoid: Option<Entity>,
+            tenant: String,
+            preemptive: bool,
         }
 
         impl Ord for TaskRecord {
@@ -37,188 +58,1467 @@ impl TaskSystem {
             }
         }
 
-        #[derive(Debug, Eq, PartialEq)]
+        #[derive(Debug, PartialEq)]
         struct DeviceRecord {
             entity: Entity,
-            module_entities: HashSet<Entity>,
+            inventory: DeviceInventory,
+            labels: SessionLabels,
             ram: usize,
+            executor: ExecutorFlavor,
+            class: DeviceClass,
+            // Calibration score, see DeviceBenchmark::score.
+            score: Option<f64>,
+            // Filler task the device is running, given up when a real task takes the device.
+            filler: Option<Entity>,
+            // Any other task the device is running, given up only to a preemptive task.
+            running: Option<RunningRecord>,
         }
 
-        let mut queued_tasks = world
-            .query::<(&Task, &TaskState)>()
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        struct RunningRecord {
+            entity: Entity,
+            priority: u8,
+            // None while the task is still being distributed.
+            started: Option<SystemTime>,
+            target: Option<Entity>,
+        }
+
+        Self::fan_out_broadcasts(world, &pause)?;
+
+        let module_costs = world
+            .query::<&ModuleCost>()
             .iter()
-            .filter(|&(_, (_, state))| matches!(state.phase, TaskStatePhase::Queued))
-            .filter_map(|(entity, (task, _))| {
+            .map(|(entity, cost)| (entity, cost.clone()))
+            .collect::<HashMap<_, _>>();
+
+        let policy = Self::fair_share_policy(world);
+        let mut usage = world
+            .query::<(&Task, &TaskState, Option<&TaskOwner>)>()
+            .without::<&FillerTask>()
+            .iter()
+            .filter(|&(_, (task, state, _))| {
+                task.kind == TaskKind::Single
+                    && matches!(state.phase, TaskStatePhase::Distributing | TaskStatePhase::Executing { .. })
+            })
+            .fold(HashMap::<String, usize>::new(), |mut usage, (_, (_, _, owner))| {
+                *usage.entry(owner.map(|owner| owner.tenant.clone()).unwrap_or_default()).or_default() += 1;
+                usage
+            });
+
+        let mut queued_tasks = HashMap::<String, BinaryHeap<TaskRecord>>::new();
+        let mut fillers = BinaryHeap::new();
+        for record in world
+            .query::<(
+                &Task,
+                &TaskState,
+                Option<&BroadcastTarget>,
+                Option<&TaskSelector>,
+                Option<&SpeculativeCopy>,
+                Option<&TaskOwner>,
+                Option<&TaskTarget>,
+            )>()
+            .iter()
+            .filter(|&(_, (task, state, _, _, _, _, _))| {
+                task.kind == TaskKind::Single && matches!(state.phase, TaskStatePhase::Queued)
+            })
+            .filter_map(|(entity, (task, _, target, selector, copy, owner, pinned))| {
                 let module = world.get::<&Module>(task.require_module).ok()?;
                 Some(TaskRecord {
                     entity,
                     module_entity: task.require_module,
+                    module_hash: module.hash(),
                     size: module.binary.len(),
+                    inputs: task
+                        .params
+                        .iter()
+                        .map(|param| match param {
+                            Type::BlobRef(_, size, _) => *size as usize,
+                            _ => 0,
+                        })
+                        .chain(module.dependencies.iter().map(|&library| {
+                            world.get::<&Module>(library).map_or(0, |library| library.binary.len())
+                        }))
+                        .sum(),
                     chunk_size: module.chunk_size as usize,
                     priority: task.priority,
+                    target: target.map(|target| target.session).or(pinned.map(|pinned| pinned.session)),
+                    selector: selector.cloned(),
+                    native: world.satisfies::<&NativeModule>(task.require_module).unwrap_or(false),
+                    avoid: copy.map(|copy| copy.avoid),
+                    tenant: owner.map(|owner| owner.tenant.clone()).unwrap_or_default(),
+                    preemptive: world.satisfies::<&PreemptiveTask>(entity).unwrap_or(false),
                 })
             })
-            .collect::<BinaryHeap<_>>();
+        {
+            if pause.holds(&record.tenant) {
+                continue;
+            }
+            if world.satisfies::<&FillerTask>(record.entity).unwrap_or(false) {
+                fillers.push(record);
+                continue;
+            }
+            queued_tasks.entry(record.tenant.clone()).or_default().push(record);
+        }
 
-        let mut device_map = world
-            .query::<(&Session, &SessionHealth, &SessionInfo)>()
+        // The tenant holding the fewest devices relative to its weight goes next, so one large
+        // sweep cannot occupy the whole fleet while other tenants wait.
+        let next_task = |queued_tasks: &mut HashMap<String, BinaryHeap<TaskRecord>>, usage: &HashMap<String, usize>| {
+            let share = |tenant: &str| (usage.get(tenant).copied().unwrap_or(0) as u64, policy.weight(tenant) as u64);
+            let tenant = queued_tasks
+                .iter()
+                .filter_map(|(tenant, queue)| Some((tenant, queue.peek()?)))
+                .min_by(|(a, record_a), (b, record_b)| {
+                    let ((used_a, weight_a), (used_b, weight_b)) = (share(a), share(b));
+                    (used_a * weight_b).cmp(&(used_b * weight_a)).then_with(|| record_b.cmp(record_a))
+                })
+                .map(|(tenant, _)| tenant.clone())?;
+            queued_tasks.get_mut(&tenant)?.pop()
+        };
+
+        // Devices busy with a filler count as free, taking one preempts the filler.
+        let running_fillers = world
+            .query::<&TaskState>()
+            .with::<&FillerTask>()
+            .iter()
+            .filter(|(_, state)| matches!(state.phase, TaskStatePhase::Distributing | TaskStatePhase::Executing { .. }))
+            .filter_map(|(entity, state)| Some((state.assigned_device?, entity)))
+            .collect::<HashMap<_, _>>();
+        // Only looked up while a preemptive task waits.
+        let mut running_tasks = HashMap::new();
+        if queued_tasks.values().flatten().any(|record| record.preemptive) {
+            running_tasks = world
+                .query::<(&Task, &TaskState, Option<&TaskTarget>)>()
+                .without::<&FillerTask>()
+                .iter()
+                .filter(|(_, (task, _, _))| task.kind == TaskKind::Single)
+                .filter_map(|(entity, (task, state, target))| {
+                    let started = match state.phase {
+                        TaskStatePhase::Distributing => None,
+                        TaskStatePhase::Executing { started, .. } => Some(started),
+                        _ => return None,
+                    };
+                    let target = target.map(|target| target.session);
+                    Some((state.assigned_device?, RunningRecord { entity, priority: task.priority, started, target }))
+                })
+                .collect::<HashMap<_, _>>();
+        }
+        let (mut device_map, mut busy_devices): (HashMap<_, _>, HashMap<_, _>) = world
+            .query::<(&DeviceInventory, &SessionHealth, &SessionInfo, &SessionLabels, Option<&DeviceBenchmark>)>()
+            .without::<Or<&SessionHandshake, &SessionQuarantine>>()
             .iter()
-            .filter(|&(_, (_, health, _))| matches!(health.status, SessionStatus::Connected))
-            .map(|(entity, (session, _, info))| {
+            .filter(|&(entity, (_, health, _, _, _))| match health.status {
+                SessionStatus::Connected => true,
+                SessionStatus::Occupied => running_fillers.contains_key(&entity) || running_tasks.contains_key(&entity),
+                _ => false,
+            })
+            .map(|(entity, (inventory, _, info, labels, benchmark))| {
                 (entity, DeviceRecord {
                     entity,
-                    module_entities: session.modules.clone(),
+                    inventory: inventory.clone(),
+                    labels: labels.clone(),
                     ram: info.device_ram as usize,
+                    executor: info.executor,
+                    class: info.class(),
+                    score: benchmark.and_then(|benchmark| benchmark.score()),
+                    filler: running_fillers.get(&entity).copied(),
+                    running: running_tasks.get(&entity).copied().filter(|_| !running_fillers.contains_key(&entity)),
                 })
             })
-            .collect::<HashMap<_, _>>();
+            .partition(|(_, device)| device.running.is_none());
 
-        while let Some(task_record) = queued_tasks.pop() {
-            let required_ram = task_record.size + 2048;
+        // Devices with a pending broadcast are kept free for it.
+        let targeted_devices = queued_tasks
+            .values()
+            .flatten()
+            .filter_map(|record| record.target)
+            .collect::<HashSet<_>>();
+        let fits = |d: &DeviceRecord, record: &TaskRecord| {
+            d.ram >= record.size + record.inputs + 2048
+                && !targeted_devices.contains(&d.entity)
+                && record.selector.as_ref().is_none_or(|s| s.matches(&d.labels))
+                && (!record.native || d.labels.native())
+                && record.avoid != Some(d.entity)
+        };
 
-            let target_device = {
+        while let Some(task_record) = next_task(&mut queued_tasks, &usage) {
+            let target_device = if let Some(target) = task_record.target {
+                device_map.get(&target).map(|d| d.entity)
+            } else {
                 let mut suitable_devices = device_map.values_mut()
-                    .filter(|d| d.ram >= required_ram)
+                    .filter(|d| fits(d, &task_record))
                     .collect::<Vec<_>>();
+                // Fillers are only preempted when no idle device fits.
+                if suitable_devices.iter().any(|d| d.filler.is_none()) {
+                    suitable_devices.retain(|d| d.filler.is_none());
+                }
 
+                let cost = module_costs.get(&task_record.module_entity);
+                let predict = |d: &DeviceRecord| cost.and_then(|cost| cost.classes.get(&d.class)).map(|e| e.wall_time);
+                let slowest = suitable_devices.iter().filter_map(|d| predict(d)).max();
+                // Classes without history are scaled from the fastest calibrated device that has
+                // some, by how their calibration scores compare.
+                let reference = suitable_devices.iter()
+                    .filter_map(|d| Some((predict(d)?, d.score?)))
+                    .min_by_key(|(wall_time, _)| *wall_time);
+                let estimate = |d: &DeviceRecord| predict(d).or_else(|| {
+                    let (wall_time, score) = reference?;
+                    Some(wall_time.mul_f64(score / d.score.filter(|&score| score > 0.0)?))
+                });
+
+                // Interpreted runtimes are several times slower, so they only win when nothing faster fits.
                 let best_device_with_cache = suitable_devices.iter_mut()
-                    .filter(|d| d.module_entities.contains(&task_record.module_entity))
-                    .max_by_key(|d| Reverse(d.ram));
+                    .filter(|d| d.inventory.contains(task_record.module_hash))
+                    .max_by_key(|d| (d.executor, Reverse(d.ram)));
 
-                if let Some(device) = best_device_with_cache {
+                if let Some(slowest) = slowest {
+                    // With history, take the device predicted to finish first. Classes never seen
+                    // running this module are assumed to be as slow as the slowest known one.
+                    suitable_devices.iter()
+                        .min_by_key(|d| (
+                            estimate(d).unwrap_or(slowest),
+                            !d.inventory.contains(task_record.module_hash),
+                            Reverse(d.executor),
+                        ))
+                        .map(|d| d.entity)
+                } else if let Some(device) = best_device_with_cache {
                     Some(device.entity)
                 } else {
+                    // Calibration scores already reflect the executor, uncalibrated devices come last.
                     suitable_devices.iter_mut()
-                        .max_by_key(|d| d.ram)
+                        .max_by(|a, b| {
+                            a.score.partial_cmp(&b.score).unwrap_or(Ordering::Equal)
+                                .then_with(|| (a.executor, a.ram).cmp(&(b.executor, b.ram)))
+                        })
                         .map(|d| d.entity)
                 }
             }.and_then(|e| device_map.remove(&e));
 
+            // With every device that fits busy, a preemptive task takes the one running the task of
+            // lowest priority below its own. Of those the one that started last loses the least work.
+            let target_device = target_device.or_else(|| {
+                if !task_record.preemptive {
+                    return None;
+                }
+                let device = busy_devices
+                    .values()
+                    .filter(|d| match task_record.target {
+                        Some(target) => d.entity == target,
+                        None => fits(d, &task_record),
+                    })
+                    .filter_map(|d| Some((d.entity, d.running?)))
+                    .filter(|(_, running)| running.priority < task_record.priority)
+                    .min_by_key(|(_, running)| (running.priority, running.started.is_some(), Reverse(running.started)))
+                    .map(|(entity, _)| entity)?;
+                busy_devices.remove(&device)
+            });
+
             if let Some(device) = target_device {
-                let total_chunks = task_record.size.div_ceil(task_record.chunk_size) as u32;
-
-                let params = world
-                    .get::<&Task>(task_record.entity)
-                    .unwrap()
-                    .params
-                    .clone();
-
-                let module = {
-                    let task = world
-                        .get::<&Task>(task_record.entity)
-                        .unwrap();
-                    let mut state = world
-                        .get::<&mut TaskState>(task_record.entity)
-                        .unwrap();
-
-                    let module = world
-                        .get::<&Module>(task.require_module)
-                        .unwrap();
-
-                    state.phase = TaskStatePhase::Distributing;
-                    state.assigned_device = Some(device.entity);
-                    info!("Task {:?} assigned to device {:?}", task_record.entity, device.entity);
-                    ModuleInfo {
-                        name: module.name.clone(),
-                        size: module.binary.len() as u64,
-                        chunk_size: task_record.chunk_size as u32,
-                        total_chunks,
-                    }
-                };
+                if let Some(running) = device.running {
+                    info!("Task {:?} preempted on device {:?} by task {:?}", running.entity, device.entity, task_record.entity);
+                    Self::reassign(world, running.entity, running.target);
+                }
+                if let Some(filler) = device.filler {
+                    info!("Filler task {:?} preempted on device {:?}", filler, device.entity);
+                    Self::reassign(world, filler, None);
+                }
+                *usage.entry(task_record.tenant.clone()).or_default() += 1;
+                Self::assign(world, task_record.entity, device.entity)?;
+            }
+        }
 
-                let chunk_count = module.total_chunks as usize;
+        // Fillers take whatever stayed idle, preferring the device class the cost model knows
+        // least about for their module so each run also calibrates it.
+        while let Some(task_record) = fillers.pop() {
+            let required_ram = task_record.size + task_record.inputs + 2048;
+            let samples = |d: &DeviceRecord| {
+                module_costs
+                    .get(&task_record.module_entity)
+                    .and_then(|cost| cost.classes.get(&d.class))
+                    .map_or(0, |estimate| estimate.samples)
+            };
+            let target_device = device_map
+                .values()
+                .filter(|d| d.filler.is_none())
+                .filter(|d| d.ram >= required_ram)
+                .filter(|d| !targeted_devices.contains(&d.entity))
+                .filter(|d| task_record.selector.as_ref().is_none_or(|s| s.matches(&d.labels)))
+                .filter(|d| !task_record.native || d.labels.native())
+                .min_by_key(|d| (samples(d), Reverse(d.executor), d.entity))
+                .map(|d| d.entity)
+                .and_then(|e| device_map.remove(&e));
+            if let Some(device) = target_device {
+                Self::assign(world, task_record.entity, device.entity)?;
+            }
+        }
+        Ok(())
+    }
 
-                let (session, health) = world
-                    .query_one_mut::<(&mut Session, &mut SessionHealth)>(device.entity)
-                    .unwrap();
-                health.status = SessionStatus::Occupied;
-                session.message_queue.push_back(Message::ServerTask {
-                    task_id: task_record.entity.to_bits().into(),
-                    module,
-                    params,
-                });
+    fn assign(world: &mut World, entity: Entity, device: Entity) -> Result<(), Error> {
+        let context = || format!("assigning task {:?} to device {:?}", entity, device);
+        {
+            let mut state = world.get::<&mut TaskState>(entity).map_err(|e| Error::scheduling(context(), e))?;
+            state.phase = TaskStatePhase::Distributing;
+            state.assigned_device = Some(device);
+            state.progress = None;
+            state.transfer = None;
+            state.attempt += 1;
+            info!("Task {:?} assigned to device {:?} as attempt {}", entity, device, state.attempt);
+        }
+
+        let health = world
+            .query_one_mut::<&mut SessionHealth>(device)
+            .map_err(|e| Error::scheduling(context(), e))?;
+        health.status = SessionStatus::Occupied;
+        Self::dispatch(world, entity, device)
+    }
+
+    // Sends an assigned task to its device, preceded by the first blob it references or library
+    // its module links that the device does not hold yet. Runs again as each fetch arrives.
+    fn dispatch(world: &mut World, entity: Entity, device: Entity) -> Result<(), Error> {
+        let (module_entity, params, env, priority, attempt, task_id) = {
+            let (Ok(task), Ok(state), Ok(task_id)) =
+                (world.get::<&Task>(entity), world.get::<&TaskState>(entity), world.get::<&TaskId>(entity))
+            else {
+                return Ok(());
+            };
+            (task.require_module, task.params.clone(), task.env.clone(), task.priority, state.attempt, *task_id)
+        };
+        let libraries = world.get::<&Module>(module_entity).map(|module| module.dependencies.clone()).unwrap_or_default();
+        let checkpoint = world.get::<&TaskCheckpoint>(entity).ok().map(|checkpoint| checkpoint.data.clone());
+        let (Ok(info), Ok(inventory)) = (world.get::<&SessionInfo>(device), world.get::<&DeviceInventory>(device))
+        else {
+            return Ok(());
+        };
+        // Libraries are fetched like blobs, the device only looks them up once the task arrives.
+        let missing = params
+            .iter()
+            .filter_map(|param| match param {
+                Type::BlobRef(id, ..) => Self::blob(world, *id),
+                _ => None,
+            })
+            .find(|&blob| world.get::<&Blob>(blob).is_ok_and(|blob| !inventory.contains(blob.hash())))
+            .or_else(|| {
+                libraries.iter().copied().find(|&library| {
+                    world.get::<&Module>(library).is_ok_and(|library| !inventory.contains(library.hash()))
+                })
+            });
+        // Devices that can load AOT get the precompiled binary once it exists.
+        let aot_arch = (info.executor == ExecutorFlavor::Aot && !info.arch.is_empty()).then(|| info.arch.clone());
+        drop((info, inventory));
+
+        if let Some(fetched) = missing {
+            let fetch = match world.get::<&Blob>(fetched) {
+                Ok(blob) => blob.info(),
+                Err(_) => world
+                    .get::<&Module>(fetched)
+                    .map(|module| module.info(module.binary.len()))
+                    .map_err(|e| Error::scheduling(format!("fetching {:?} for task {:?}", fetched, entity), e))?,
+            };
+            debug!("Fetch {} to device {:?} for task {:?}", fetch.name, device, entity);
+            let chunk_count = fetch.total_chunks as usize;
+            if let Ok(mut session) = world.get::<&mut Session>(device) {
+                session.message_queue.push_back(ServerMessage::ServerPrefetch { task_id, module: fetch });
+            }
+            world.spawn((
+                BlobFetch { task: entity },
+                ModuleTransfer {
+                    task_id,
+                    state: ModuleTransferState::Pending,
+                    acked_chunks: BitVec::repeat(false, chunk_count),
+                    session: device,
+                    module: fetched,
+                    arch: None,
+                    in_flight: BTreeMap::new(),
+                },
+            ));
+            return Ok(());
+        }
+
+        let (module, arch) = {
+            let Ok(module) = world.get::<&Module>(module_entity) else {
+                return Ok(());
+            };
+            let artifacts = world.get::<&ModuleArtifacts>(module_entity).ok();
+            let arch = aot_arch.filter(|arch| {
+                artifacts.as_ref().is_some_and(|artifacts| artifacts.aot.contains_key(arch))
+            });
+            let size = module.payload(artifacts.as_deref(), arch.as_deref()).len();
+            (module.info(size), arch)
+        };
+        let chunk_count = module.total_chunks as usize;
+        let libraries = libraries
+            .iter()
+            .filter_map(|&library| world.get::<&Module>(library).ok().map(|library| library.info(library.binary.len())))
+            .collect();
+        if let Ok(mut session) = world.get::<&mut Session>(device) {
+            session.message_queue.push_back(ServerMessage::ServerTask {
+                task_id,
+                attempt,
+                module,
+                params,
+                env,
+                libraries,
+                priority,
+                checkpoint,
+            });
+        }
+
+        // The module goes to a device once, later tasks for it wait on the same transfer.
+        if Self::module_transfer(world, device, module_entity).is_none() {
+            world.spawn((ModuleTransfer {
+                task_id,
+                state: ModuleTransferState::Pending,
+                acked_chunks: BitVec::repeat(false, chunk_count),
+                session: device,
+                module: module_entity,
+                arch,
+                in_flight: BTreeMap::new(),
+            },));
+        }
+        Ok(())
+    }
+
+    pub fn blob(world: &World, id: u64) -> Option<Entity> {
+        world
+            .query::<&Blob>()
+            .iter()
+            .find(|(_, blob)| blob.id == id)
+            .map(|(entity, _)| entity)
+    }
+
+    fn fan_out_broadcasts(world: &mut World, pause: &SchedulingPause) -> Result<(), Error> {
+        let broadcasts = world
+            .query::<(&Task, &TaskState, Option<&TaskSelector>, Option<&TaskOwner>)>()
+            .iter()
+            .filter(|&(_, (task, state, _, owner))| {
+                task.kind == TaskKind::Broadcast
+                    && matches!(state.phase, TaskStatePhase::Queued)
+                    && !pause.holds(owner.map_or("", |owner| owner.tenant.as_str()))
+            })
+            .filter_map(|(entity, (task, _, selector, owner))| {
+                let size = world.get::<&Module>(task.require_module).ok()?.binary.len();
+                let native = world.satisfies::<&NativeModule>(task.require_module).unwrap_or(false);
+                Some((entity, task.clone(), size, native, selector.cloned(), owner.cloned()))
+            })
+            .collect::<Vec<_>>();
+
+        for (entity, task, size, native, selector, owner) in broadcasts {
+            let sessions = world
+                .query::<(&SessionHealth, &SessionInfo, &SessionLabels)>()
+                .without::<Or<&SessionHandshake, &SessionQuarantine>>()
+                .iter()
+                .filter(|&(_, (health, info, labels))| {
+                    matches!(health.status, SessionStatus::Connected | SessionStatus::Occupied)
+                        && info.device_ram as usize >= size + 2048
+                        && (!native || labels.native())
+                        && selector.as_ref().is_none_or(|s| s.matches(labels))
+                })
+                .map(|(session, _)| session)
+                .collect::<Vec<_>>();
+
+            if sessions.is_empty() {
+                continue;
+            }
+
+            info!("Broadcast task {:?} fan out to {} devices", entity, sessions.len());
+            for &session in &sessions {
+                let child = world.spawn((
+                    Task {
+                        name: format!("{}_{}", task.name, session.id()),
+                        kind: TaskKind::Single,
+                        ..task.clone()
+                    },
+                    TaskState {
+                        phase: TaskStatePhase::Queued,
+                        assigned_device: None,
+                        results: HashMap::new(),
+                        progress: None,
+                        transfer: None,
+                        attempt: 0,
+                    },
+                    next_task_id(),
+                    BroadcastTarget {
+                        parent: entity,
+                        session,
+                    },
+                ));
+                if let Some(owner) = owner.clone() {
+                    world.insert_one(child, owner).ok();
+                }
+            }
+
+            if let Ok(mut state) = world.get::<&mut TaskState>(entity) {
+                state.phase = TaskStatePhase::Distributing;
+            }
+            // Idle devices lacking the module get it through one shared transfer, their copies
+            // of the task are assigned once it has arrived. A single one just gets its copy.
+            let hash = world.get::<&Module>(task.require_module).map(|module| module.hash());
+            let lacking = sessions
+                .iter()
+                .copied()
+                .filter(|&session| {
+                    let idle = world.get::<&SessionHealth>(session).is_ok_and(|health| health.status == SessionStatus::Connected);
+                    let cached = world.get::<&DeviceInventory>(session).is_ok_and(|inventory| {
+                        hash.as_ref().is_ok_and(|&hash| inventory.contains(hash))
+                    });
+                    idle && !cached
+                })
+                .collect::<Vec<_>>();
+            if lacking.len() > 1 {
+                Self::push_module(world, task.require_module, Some(&lacking))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn collect_broadcasts(world: &mut World) {
+        let mut pending = HashSet::new();
+        let mut finished = Vec::new();
+
+        for (entity, (target, task, state)) in world
+            .query::<(&BroadcastTarget, &Task, &TaskState)>()
+            .iter()
+        {
+            if state.phase == TaskStatePhase::Completed {
+                finished.push((entity, target.clone(), Some(task.result.clone())));
+            } else if !world.contains(target.session) {
+                finished.push((entity, target.clone(), None));
+            } else {
+                pending.insert(target.parent);
+            }
+        }
+
+        let mut parents = HashSet::new();
+        for (entity, target, result) in finished {
+            if let Ok(mut state) = world.get::<&mut TaskState>(target.parent) {
+                if let Some(result) = result {
+                    state.results.insert(target.session, result);
+                } else {
+                    debug!("Broadcast task {:?} lost device {:?}", target.parent, target.session);
+                }
+            }
+            parents.insert(target.parent);
+            world.despawn(entity).ok();
+        }
+
+        for parent in parents.difference(&pending) {
+            if let Ok(mut state) = world.get::<&mut TaskState>(*parent) {
+                info!("Broadcast task {:?} completed on {} devices", parent, state.results.len());
+                state.phase = TaskStatePhase::Completed;
+            }
+        }
+    }
+
+    // Devices pin the module with its next transfer to them, see ModuleInfo.
+    pub fn pin_module(world: &mut World, module_entity: Entity) {
+        if let Ok(mut module) = world.get::<&mut Module>(module_entity) {
+            module.pinned = true;
+        }
+    }
 
-                world
-                    .insert_one(
-                        task_record.entity,
-                        ModuleTransfer {
-                            state: ModuleTransferState::Pending,
-                            acked_chunks: BitVec::repeat(false, chunk_count),
-                            session: device.entity,
-                        },
-                    )
-                    .unwrap();
+    pub fn unpin_module(world: &mut World, module_entity: Entity) {
+        let (name, hash) = match world.get::<&mut Module>(module_entity) {
+            Ok(mut module) if module.pinned => {
+                module.pinned = false;
+                (module.name.clone(), module.hash())
+            }
+            _ => return,
+        };
+
+        for (entity, (session, inventory)) in world.query_mut::<(&mut Session, &DeviceInventory)>() {
+            if inventory.contains(hash) {
+                debug!("Unpin module {} on device {:?}", name, entity);
+                session.message_queue.push_back(ServerMessage::ServerUnpin {
+                    module: name.clone(),
+                });
             }
         }
     }
 
-    pub fn transfer_chunks(world: &mut World) {
+    // Keeps a few messages of every transfer in flight, each covering as many chunks as the
+    // device's ChunkSizing allows when it is sent, so a transfer speeds up or slows down midway.
+    // Members of a TransferGroup get the group's frames of one chunk each instead.
+    pub fn transfer_chunks(world: &mut World) -> Result<(), Error> {
+        let now = SystemTime::now();
         let module_transfers = world
-            .query::<(&Task, &ModuleTransfer)>()
+            .query::<(&ModuleTransfer, Option<&SharedTransfer>)>()
             .iter()
-            .filter_map(|(task_entity, (task, transfer))| {
-                let module = world.get::<&Module>(task.require_module).ok()?;
+            .filter_map(|(transfer_entity, (transfer, shared))| {
                 let device_entity = transfer.session;
+                if transfer.state == ModuleTransferState::Pending {
+                    return None;
+                }
 
-                let messages = match transfer.state {
-                    ModuleTransferState::Requested => module
-                        .binary
-                        .chunks(module.chunk_size as usize)
-                        .enumerate()
-                        .filter(|(chunk_idx, _)| !transfer.acked_chunks[*chunk_idx])
-                        .map(|(chunk_idx, chunk)| Message::ServerModule {
-                            task_id: task_entity.to_bits().into(),
-                            chunk_index: chunk_idx as u32,
-                            chunk_data: chunk.to_vec(),
-                        })
-                        .collect::<Vec<_>>(),
-                    _ => None?,
+                let sizing = world.get::<&ChunkSizing>(device_entity).map_or_else(|_| ChunkSizing::default(), |sizing| *sizing);
+                let device_ram = world.get::<&SessionInfo>(device_entity).map_or(0, |info| info.device_ram);
+                // A requested transfer starts over, whatever is not acked counts as never sent.
+                let in_flight = match transfer.state {
+                    ModuleTransferState::Requested => BTreeMap::new(),
+                    _ => transfer.in_flight.clone(),
+                };
+                let (mut in_flight, lost): (BTreeMap<u32, ChunkSpan>, BTreeMap<_, _>) = in_flight
+                    .into_iter()
+                    .partition(|(_, span)| now.duration_since(span.sent).unwrap_or_default() < Self::CHUNK_LOST_AFTER);
+                let lost = lost.len();
+
+                let arch = transfer.arch.as_deref();
+                let chunk_size = Self::with_payload(world, transfer.module, arch, |_, chunk_size| chunk_size)?;
+                let span = match shared {
+                    Some(_) => 1,
+                    None => sizing.span(chunk_size, device_ram) as usize,
+                };
+                let spans = Self::next_spans(transfer, &in_flight, span);
+                let messages = match shared {
+                    Some(_) => Vec::new(),
+                    None => Self::with_payload(world, transfer.module, arch, |payload, chunk_size| {
+                        spans
+                            .iter()
+                            .map(|&(index, chunks)| ServerMessage::ServerModule {
+                                task_id: transfer.task_id,
+                                chunk_index: index as u32,
+                                chunk_data: Self::chunk(payload, chunk_size, index, chunks).to_vec(),
+                            })
+                            .collect()
+                    })?,
                 };
+                in_flight.extend(spans.iter().map(|&(index, chunks)| {
+                    (index as u32, ChunkSpan { chunks: chunks as u32, sent: now })
+                }));
 
-                Some((task_entity, device_entity, messages))
+                Some((transfer_entity, device_entity, shared.map(|shared| shared.group), spans, messages, in_flight, lost))
             })
             .collect::<Vec<_>>();
 
-        for (task_entity, device_entity, messages) in module_transfers {
-            let mut transfer = world.get::<&mut ModuleTransfer>(task_entity).unwrap();
-            transfer.state = ModuleTransferState::Transferring;
+        for (transfer_entity, device_entity, group, spans, messages, in_flight, lost) in module_transfers {
+            {
+                let mut transfer = world
+                    .get::<&mut ModuleTransfer>(transfer_entity)
+                    .map_err(|e| Error::scheduling(format!("sending transfer {:?}", transfer_entity), e))?;
+                transfer.state = ModuleTransferState::Transferring;
+                transfer.in_flight = in_flight;
+            }
+
+            let mut sizing = world.get::<&ChunkSizing>(device_entity).map_or_else(|_| ChunkSizing::default(), |sizing| *sizing);
+            for _ in 0..lost {
+                sizing.record(None);
+            }
+            world.insert_one(device_entity, sizing).ok();
+
+            if let Some(group) = group {
+                let frames = Self::group_frames(world, group, &spans);
+                if frames.is_empty() || !world.satisfies::<&Session>(device_entity).unwrap_or(false) {
+                    continue;
+                }
+                if !world.satisfies::<&SharedFrames>(device_entity).unwrap_or(false) {
+                    world.insert_one(device_entity, SharedFrames::default()).ok();
+                }
+                debug!("Transfer {:?} send {} shared frames to device {:?}", transfer_entity, frames.len(), device_entity);
+                if let Ok(mut shared) = world.get::<&mut SharedFrames>(device_entity) {
+                    shared.0.extend(frames);
+                }
+                continue;
+            }
 
+            if messages.is_empty() {
+                continue;
+            }
             if let Ok(mut session) = world.get::<&mut Session>(device_entity) {
-                debug!("Task {:?} send {} messages to device {:?}", task_entity, messages.len(), device_entity);
+                debug!("Transfer {:?} send {} messages to device {:?}", transfer_entity, messages.len(), device_entity);
                 session.message_queue.extend(messages);
             }
         }
+        Ok(())
+    }
+
+    // Messages to send next as their first chunk and the chunks they cover, each taking up to
+    // `span` chunks neither acked nor in flight, until CHUNKS_IN_FLIGHT are out.
+    fn next_spans(transfer: &ModuleTransfer, in_flight: &BTreeMap<u32, ChunkSpan>, span: usize) -> Vec<(usize, usize)> {
+        let mut taken = transfer.acked_chunks.clone();
+        let total = taken.len();
+        for (&index, span) in in_flight {
+            taken[(index as usize).min(total)..((index + span.chunks) as usize).min(total)].fill(true);
+        }
+
+        let mut spans = Vec::new();
+        let mut index = 0;
+        while index < total && in_flight.len() + spans.len() < Self::CHUNKS_IN_FLIGHT {
+            if taken[index] {
+                index += 1;
+                continue;
+            }
+            let end = index + (index..total).take(span).take_while(|&chunk| !taken[chunk]).count();
+            spans.push((index, end - index));
+            index = end;
+        }
+        spans
+    }
+
+    fn chunk(payload: &[u8], chunk_size: u32, index: usize, chunks: usize) -> &[u8] {
+        let chunk_size = chunk_size.max(1) as usize;
+        let start = (index * chunk_size).min(payload.len());
+        &payload[start..((index + chunks) * chunk_size).min(payload.len())]
+    }
+
+    // Calls `f` with the bytes a transfer of `target` sends and their chunk size, `target` being
+    // a `Module`, `Blob` or `Firmware`.
+    fn with_payload<R>(world: &World, target: Entity, arch: Option<&str>, f: impl FnOnce(&[u8], u32) -> R) -> Option<R> {
+        if let Ok(module) = world.get::<&Module>(target) {
+            let artifacts = world.get::<&ModuleArtifacts>(target).ok();
+            Some(f(module.payload(artifacts.as_deref(), arch), module.chunk_size))
+        } else if let Ok(blob) = world.get::<&Blob>(target) {
+            Some(f(&blob.binary, blob.chunk_size))
+        } else {
+            let firmware = world.get::<&Firmware>(target).ok()?;
+            Some(f(&firmware.binary, firmware.chunk_size))
+        }
+    }
+
+    // The group's frames for the first chunk of every span, encoding those not sent before.
+    fn group_frames(world: &World, group: Entity, spans: &[(usize, usize)]) -> Vec<Bytes> {
+        let Ok(mut group) = world.get::<&mut TransferGroup>(group) else {
+            return Vec::new();
+        };
+        let (task_id, module, arch) = (group.task_id, group.module, group.arch.clone());
+        Self::with_payload(world, module, arch.as_deref(), |payload, chunk_size| {
+            spans
+                .iter()
+                .filter_map(|&(index, _)| {
+                    let frame = group.frames.get_mut(index)?;
+                    if frame.is_none() {
+                        let message = Message::from(ServerMessage::ServerModule {
+                            task_id,
+                            chunk_index: index as u32,
+                            chunk_data: Self::chunk(payload, chunk_size, index, 1).to_vec(),
+                        });
+                        *frame = message.encode().ok().map(Bytes::from);
+                    }
+                    frame.clone()
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+    }
+
+    // Marks the chunks of the message starting at `chunk_index` and adapts the device's chunk
+    // size to how long the ack took. An ack for nothing in flight, e.g. one from before a
+    // resend, only marks its chunk.
+    pub fn ack_chunk(world: &World, transfer: &mut ModuleTransfer, chunk_index: u32, success: bool, now: SystemTime) {
+        let span = transfer.in_flight.remove(&chunk_index);
+        let start = (chunk_index as usize).min(transfer.acked_chunks.len());
+        let end = (start + span.map_or(1, |span| span.chunks as usize)).min(transfer.acked_chunks.len());
+        transfer.acked_chunks[start..end].fill(success);
+
+        if let (Some(span), Ok(mut sizing)) = (span, world.get::<&mut ChunkSizing>(transfer.session)) {
+            sizing.record(success.then(|| now.duration_since(span.sent).unwrap_or_default()));
+        }
+    }
+
+    // Copies the acks of every transfer into the tasks waiting on it, a blob or library fetch
+    // counts for its task alone. Runs ahead of `finalize_transfer` so the last ack is seen.
+    pub fn track_transfers(world: &mut World, now: SystemTime) {
+        let transfers = world
+            .query::<(&ModuleTransfer, Option<&BlobFetch>)>()
+            .iter()
+            .filter_map(|(entity, (transfer, fetch))| {
+                let (size, chunk_size) = Self::with_payload(world, transfer.module, transfer.arch.as_deref(), |payload, chunk_size| {
+                    (payload.len(), chunk_size)
+                })?;
+                let chunk_size = chunk_size.max(1) as usize;
+                let bytes_acked = transfer
+                    .acked_chunks
+                    .iter_ones()
+                    .map(|chunk| chunk_size.min(size.saturating_sub(chunk * chunk_size)) as u64)
+                    .sum();
+                let tasks = match fetch {
+                    Some(fetch) => vec![fetch.task],
+                    None => Self::waiting_tasks(world, transfer),
+                };
+                let counts = (transfer.acked_chunks.count_ones() as u32, transfer.acked_chunks.len() as u32);
+                Some((entity, tasks, counts, bytes_acked))
+            })
+            .collect::<Vec<_>>();
+
+        for (transfer, tasks, (chunks_acked, total_chunks), bytes_acked) in transfers {
+            for task in tasks {
+                let Ok(mut state) = world.get::<&mut TaskState>(task) else {
+                    continue;
+                };
+                let previous = state.transfer.take().filter(|progress| progress.transfer == transfer);
+                let started = previous.as_ref().map_or(now, |progress| progress.started);
+                let updated = match &previous {
+                    Some(progress) if progress.chunks_acked == chunks_acked => progress.updated,
+                    _ => now,
+                };
+                let elapsed = now.duration_since(started).unwrap_or_default().as_secs_f64();
+                state.transfer = Some(TransferProgress {
+                    transfer,
+                    chunks_acked,
+                    total_chunks,
+                    bytes_acked,
+                    started,
+                    updated,
+                    bytes_per_sec: if elapsed > 0.0 { bytes_acked as f64 / elapsed } else { 0.0 },
+                });
+            }
+        }
+    }
+
+    pub fn finalize_transfer(world: &mut World) -> Result<(), Error> {
+        let completed_prefetches = world
+            .query::<(&ModulePrefetch, &ModuleTransfer)>()
+            .iter()
+            .filter(|(_, (_, transfer))| transfer.acked_chunks.all() || !world.contains(transfer.session))
+            .map(|(entity, (prefetch, transfer))| (entity, prefetch.module, transfer.session))
+            .collect::<Vec<_>>();
+
+        for (entity, module_entity, session_entity) in completed_prefetches {
+            Self::store_module(world, session_entity, module_entity);
+            if let Ok(health) = world.query_one_mut::<&mut SessionHealth>(session_entity) {
+                debug!("Module {:?} prefetched to device {:?}", module_entity, session_entity);
+                if health.status == SessionStatus::Occupied {
+                    health.status = SessionStatus::Connected;
+                }
+            }
+            world.despawn(entity).ok();
+        }
+
+        // The task moves on to its next blob or library, or to its module once it has all of them.
+        let fetched_blobs = world
+            .query::<(&BlobFetch, &ModuleTransfer)>()
+            .iter()
+            .filter(|(_, (_, transfer))| transfer.acked_chunks.all())
+            .map(|(entity, (fetch, transfer))| (entity, fetch.task, transfer.session, transfer.module))
+            .collect::<Vec<_>>();
+
+        for (entity, task_entity, session_entity, blob_entity) in fetched_blobs {
+            Self::store_module(world, session_entity, blob_entity);
+            world.despawn(entity).ok();
+            let waiting = world.get::<&TaskState>(task_entity).is_ok_and(|state| {
+                state.phase == TaskStatePhase::Distributing && state.assigned_device == Some(session_entity)
+            });
+            if waiting {
+                Self::dispatch(world, task_entity, session_entity)?;
+            }
+        }
+
+        // A group ends with its last member.
+        let finished_groups = world
+            .query::<&TransferGroup>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .filter(|&group| !world.query::<&SharedTransfer>().iter().any(|(_, shared)| shared.group == group))
+            .collect::<Vec<_>>();
+        for group in finished_groups {
+            world.despawn(group).ok();
+        }
+
+        // Firmware updates end on the device's FirmwareAck, only vanished devices are reaped here.
+        let orphaned_updates = world
+            .query::<(&FirmwareUpdate, &ModuleTransfer)>()
+            .iter()
+            .filter(|(_, (_, transfer))| !world.contains(transfer.session))
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+        for entity in orphaned_updates {
+            world.despawn(entity).ok();
+        }
+        let finished_firmware = world
+            .query::<&Firmware>()
+            .iter()
+            .map(|(entity, _)| entity)
+            .filter(|&firmware| {
+                !world
+                    .query::<&FirmwareUpdate>()
+                    .iter()
+                    .any(|(_, update)| update.firmware == firmware)
+            })
+            .collect::<Vec<_>>();
+        for firmware in finished_firmware {
+            info!("Firmware rollout {:?} finished", firmware);
+            world.despawn(firmware).ok();
+        }
+
+        let completed_transfers = world
+            .query::<&ModuleTransfer>()
+            .without::<Or<&ModulePrefetch, Or<&FirmwareUpdate, &BlobFetch>>>()
+            .iter()
+            .filter(|(_, transfer)| transfer.acked_chunks.all())
+            .map(|(entity, transfer)| (entity, Self::waiting_tasks(world, transfer), transfer.session, transfer.module))
+            .collect::<Vec<_>>();
+
+        for (transfer_entity, tasks, session_entity, module_entity) in completed_transfers {
+            // A cached module lets the result arrive in the same pass as the final ack, such tasks
+            // are no longer waiting and keep their phase.
+            let started = SystemTime::now();
+            let timeout = LifecycleSystem::settings(world).execution_timeout;
+            for task_entity in tasks {
+                if let Ok(mut state) = world.get::<&mut TaskState>(task_entity) {
+                    state.phase = TaskStatePhase::Executing {
+                        started,
+                        deadline: started + timeout,
+                    };
+                }
+            }
+
+            Self::store_module(world, session_entity, module_entity);
+            world.despawn(transfer_entity).ok();
+        }
+        Ok(())
+    }
+
+    fn store_module(world: &World, session: Entity, module: Entity) {
+        let Ok(mut inventory) = world.get::<&mut DeviceInventory>(session) else {
+            return;
+        };
+        if let Ok(module) = world.get::<&Module>(module) {
+            inventory.insert(module.hash(), &module.name);
+        } else if let Ok(blob) = world.get::<&Blob>(module) {
+            inventory.insert(blob.hash(), &protocol::blob_name(blob.id));
+        }
+    }
+
+    // The transfer sending `module` to `session`, there is at most one per pair.
+    pub fn module_transfer(world: &World, session: Entity, module: Entity) -> Option<Entity> {
+        world
+            .query::<&ModuleTransfer>()
+            .iter()
+            .find(|(_, transfer)| transfer.session == session && transfer.module == module)
+            .map(|(entity, _)| entity)
+    }
+
+    // Tasks distributed to the transfer's device that start once its module has arrived.
+    pub fn waiting_tasks(world: &World, transfer: &ModuleTransfer) -> Vec<Entity> {
+        world
+            .query::<(&Task, &TaskState)>()
+            .iter()
+            .filter(|(_, (task, state))| {
+                task.require_module == transfer.module
+                    && state.phase == TaskStatePhase::Distributing
+                    && state.assigned_device == Some(transfer.session)
+            })
+            .map(|(entity, _)| entity)
+            .collect()
+    }
+
+    // Pushes a module to idle devices lacking it, so the first task needing it skips the transfer.
+    pub fn prefetch_module(world: &mut World, module_entity: Entity) -> Result<usize, Error> {
+        Self::push_module(world, module_entity, None)
+    }
+
+    // Prefetches to the idle devices lacking the module, only those in `only` when given. Devices
+    // getting the same payload share a TransferGroup, so each chunk is encoded once for all.
+    fn push_module(world: &mut World, module_entity: Entity, only: Option<&[Entity]>) -> Result<usize, Error> {
+        if LifecycleSystem::server_mode(world) == ServerMode::Draining {
+            return Ok(0);
+        }
+
+        let Ok((size, hash)) = world.get::<&Module>(module_entity).map(|module| (module.binary.len(), module.hash())) else {
+            return Ok(0);
+        };
+        let native = world.satisfies::<&NativeModule>(module_entity).unwrap_or(false);
+        let devices = world
+            .query::<(&DeviceInventory, &SessionHealth, &SessionInfo, Option<&SessionLabels>)>()
+            .without::<Or<&SessionHandshake, &SessionQuarantine>>()
+            .iter()
+            .filter(|&(entity, (inventory, health, info, labels))| {
+                health.status == SessionStatus::Connected
+                    && !inventory.contains(hash)
+                    && info.device_ram as usize >= size + 2048
+                    && (!native || labels.is_some_and(SessionLabels::native))
+                    && only.is_none_or(|only| only.contains(&entity))
+            })
+            .map(|(entity, (_, _, info, _))| {
+                let arch = (info.executor == ExecutorFlavor::Aot && !info.arch.is_empty()).then(|| info.arch.clone());
+                (entity, arch)
+            })
+            .collect::<Vec<_>>();
+
+        let payloads = devices
+            .into_iter()
+            .map(|(device, arch)| {
+                let module = world
+                    .get::<&Module>(module_entity)
+                    .map_err(|e| Error::scheduling(format!("prefetching module {:?}", module_entity), e))?;
+                let artifacts = world.get::<&ModuleArtifacts>(module_entity).ok();
+                let arch = arch.filter(|arch| {
+                    artifacts.as_ref().is_some_and(|artifacts| artifacts.aot.contains_key(arch))
+                });
+                let size = module.payload(artifacts.as_deref(), arch.as_deref()).len();
+                Ok((device, module.info(size), arch))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+
+        let mut groups = HashMap::<Option<String>, (Option<Entity>, TaskId)>::new();
+        for (_, module, arch) in &payloads {
+            match groups.get_mut(arch) {
+                Some((group @ None, task_id)) => {
+                    *group = Some(world.spawn((TransferGroup {
+                        module: module_entity,
+                        task_id: *task_id,
+                        arch: arch.clone(),
+                        frames: vec![None; module.total_chunks as usize],
+                    },)));
+                }
+                Some(_) => {}
+                None => {
+                    groups.insert(arch.clone(), (None, next_task_id()));
+                }
+            }
+        }
+
+        for (device, module, arch) in &payloads {
+            let (group, task_id) = groups[arch];
+            let chunk_count = module.total_chunks as usize;
+            info!("Prefetch module {} to device {:?}", module.name, device);
+            let (session, health) = world
+                .query_one_mut::<(&mut Session, &mut SessionHealth)>(*device)
+                .map_err(|e| Error::scheduling(format!("prefetching module {:?} to device {:?}", module_entity, device), e))?;
+            health.status = SessionStatus::Occupied;
+            session.message_queue.push_back(ServerMessage::ServerPrefetch { task_id, module: module.clone() });
+
+            let transfer = world.spawn((
+                ModulePrefetch { module: module_entity },
+                ModuleTransfer {
+                    task_id,
+                    state: ModuleTransferState::Pending,
+                    acked_chunks: BitVec::repeat(false, chunk_count),
+                    session: *device,
+                    module: module_entity,
+                    arch: arch.clone(),
+                    in_flight: BTreeMap::new(),
+                },
+            ));
+            if let Some(group) = group {
+                world.insert_one(transfer, SharedTransfer { group }).ok();
+            }
+        }
+
+        Ok(payloads.len())
+    }
+
+    // Starts a firmware update on every idle device of the image's architecture. Devices answer
+    // with a FirmwareAck and reboot, the rollout ends once all of them have answered.
+    pub fn update_firmware(world: &mut World, firmware_entity: Entity) -> Result<usize, Error> {
+        if LifecycleSystem::server_mode(world) == ServerMode::Draining {
+            return Ok(0);
+        }
+
+        let Ok((info, arch)) = world
+            .get::<&Firmware>(firmware_entity)
+            .map(|firmware| (firmware.info(), firmware.arch.clone()))
+        else {
+            return Ok(0);
+        };
+        let devices = world
+            .query::<(&SessionHealth, &SessionInfo)>()
+            .with::<&Session>()
+            .without::<Or<&SessionHandshake, &SessionQuarantine>>()
+            .iter()
+            .filter(|(_, (health, info))| {
+                health.status == SessionStatus::Connected && (arch.is_empty() || info.arch == arch)
+            })
+            .map(|(entity, _)| entity)
+            .collect::<Vec<_>>();
+
+        for device in &devices {
+            let task_id = next_task_id();
+            info!("Update device {:?} to firmware {}", device, info.version);
+            let (session, health) = world
+                .query_one_mut::<(&mut Session, &mut SessionHealth)>(*device)
+                .map_err(|e| Error::scheduling(format!("updating firmware of device {:?}", device), e))?;
+            health.status = SessionStatus::Occupied;
+            session.message_queue.push_back(ServerMessage::ServerFirmware {
+                task_id,
+                firmware: info.clone(),
+            });
+
+            world.spawn((
+                FirmwareUpdate { firmware: firmware_entity },
+                ModuleTransfer {
+                    task_id,
+                    state: ModuleTransferState::Pending,
+                    acked_chunks: BitVec::repeat(false, info.total_chunks as usize),
+                    session: *device,
+                    module: firmware_entity,
+                    arch: None,
+                    in_flight: BTreeMap::new(),
+                },
+            ));
+        }
+
+        Ok(devices.len())
+    }
+
+    pub fn scheduling_pause(world: &World) -> SchedulingPause {
+        world
+            .query::<&SchedulingPause>()
+            .iter()
+            .next()
+            .map(|(_, pause)| pause.clone())
+            .unwrap_or_default()
+    }
+
+    // Pauses one tenant, or everything with `None`. Resuming everything also lifts every
+    // tenant's pause.
+    pub fn pause_scheduling(world: &mut World, tenant: Option<&str>) {
+        let mut pause = Self::scheduling_pause(world);
+        match tenant {
+            Some(tenant) => {
+                info!("Scheduling paused for tenant {:?}", tenant);
+                pause.tenants.insert(tenant.to_string());
+            }
+            None => {
+                info!("Scheduling paused");
+                pause.all = true;
+            }
+        }
+        Self::set_scheduling_pause(world, pause);
+    }
+
+    pub fn resume_scheduling(world: &mut World, tenant: Option<&str>) {
+        let mut pause = Self::scheduling_pause(world);
+        match tenant {
+            Some(tenant) => {
+                info!("Scheduling resumed for tenant {:?}", tenant);
+                pause.tenants.remove(tenant);
+            }
+            None => {
+                info!("Scheduling resumed");
+                pause = SchedulingPause::default();
+            }
+        }
+        Self::set_scheduling_pause(world, pause);
+    }
+
+    fn set_scheduling_pause(world: &mut World, pause: SchedulingPause) {
+        let current = world.query_mut::<&mut SchedulingPause>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = pause,
+            None => {
+                world.spawn((pause,));
+            }
+        }
+    }
+
+    pub fn fair_share_policy(world: &World) -> FairSharePolicy {
+        world
+            .query::<&FairSharePolicy>()
+            .iter()
+            .next()
+            .map(|(_, policy)| policy.clone())
+            .unwrap_or_default()
+    }
+
+    pub fn set_fair_share_policy(world: &mut World, policy: FairSharePolicy) {
+        info!("Fair share policy set to {:?}", policy);
+        let current = world.query_mut::<&mut FairSharePolicy>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = policy,
+            None => {
+                world.spawn((policy,));
+            }
+        }
+    }
+
+    pub fn speculation_policy(world: &World) -> SpeculationPolicy {
+        world
+            .query::<&SpeculationPolicy>()
+            .iter()
+            .next()
+            .map(|(_, policy)| *policy)
+            .unwrap_or_default()
+    }
+
+    pub fn set_speculation_policy(world: &mut World, policy: SpeculationPolicy) {
+        let current = world.query_mut::<&mut SpeculationPolicy>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = policy,
+            None => {
+                world.spawn((policy,));
+            }
+        }
+        info!("Speculation policy set to {:?}", policy);
+    }
+
+    pub fn speculate_stragglers(world: &mut World) {
+        let policy = Self::speculation_policy(world);
+        if !policy.enabled || LifecycleSystem::server_mode(world) == ServerMode::Draining {
+            return;
+        }
+
+        let (fleet, idle) = world
+            .query::<&SessionHealth>()
+            .without::<&SessionQuarantine>()
+            .iter()
+            .fold((0, 0), |(fleet, idle), (_, health)| match health.status {
+                SessionStatus::Connected => (fleet + 1, idle + 1),
+                SessionStatus::Occupied => (fleet + 1, idle),
+                _ => (fleet, idle),
+            });
+        let originals = world
+            .query::<&SpeculativeCopy>()
+            .iter()
+            .map(|(_, copy)| copy.original)
+            .collect::<HashSet<_>>();
+        let limit = (fleet as f64 * policy.max_ratio) as usize;
+        let budget = limit.saturating_sub(originals.len()).min(idle);
+        if budget == 0 {
+            return;
+        }
+
+        let now = SystemTime::now();
+        let mut stragglers = world
+            .query::<(&Task, &TaskState)>()
+            .without::<Or<&SpeculativeCopy, &BroadcastTarget>>()
+            .iter()
+            .filter(|(entity, (task, _))| task.kind == TaskKind::Single && !originals.contains(entity))
+            .filter(|(entity, _)| !world.satisfies::<&FillerTask>(*entity).unwrap_or(false))
+            .filter_map(|(entity, (task, state))| {
+                let TaskStatePhase::Executing { started, .. } = state.phase else {
+                    return None;
+                };
+                let device = state.assigned_device?;
+                let class = world.get::<&SessionInfo>(device).ok()?.class();
+                let predicted = world
+                    .get::<&ModuleCost>(task.require_module)
+                    .ok()?
+                    .classes
+                    .get(&class)?
+                    .wall_time;
+                let elapsed = now.duration_since(started).unwrap_or_default();
+                (elapsed > predicted.mul_f64(policy.slowdown)).then(|| {
+                    (entity, device, elapsed.as_secs_f64() / predicted.as_secs_f64().max(f64::EPSILON))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // The furthest behind schedule are duplicated first.
+        stragglers.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        for (entity, device, _) in stragglers.into_iter().take(budget) {
+            let Ok(task) = world.get::<&Task>(entity).map(|task| (*task).clone()) else {
+                continue;
+            };
+            let selector = world.get::<&TaskSelector>(entity).ok().map(|selector| (*selector).clone());
+            let owner = world.get::<&TaskOwner>(entity).ok().map(|owner| (*owner).clone());
+
+            info!("Task {:?} is straggling on device {:?}, launching a speculative copy", entity, device);
+            let copy = world.spawn((
+                Task {
+                    name: format!("{}_speculative", task.name),
+                    ..task
+                },
+                TaskState {
+                    phase: TaskStatePhase::Queued,
+                    assigned_device: None,
+                    results: HashMap::new(),
+                    progress: None,
+                    transfer: None,
+                    attempt: 0,
+                },
+                next_task_id(),
+                SpeculativeCopy {
+                    original: entity,
+                    avoid: device,
+                },
+            ));
+            if let Some(selector) = selector {
+                world.insert_one(copy, selector).ok();
+            }
+            if let Some(owner) = owner {
+                world.insert_one(copy, owner).ok();
+            }
+        }
+    }
+
+    // Whichever of the original and its copy completes first wins, the other is canceled.
+    pub fn collect_speculations(world: &mut World) -> Result<(), Error> {
+        let copies = world
+            .query::<(&SpeculativeCopy, &TaskState)>()
+            .iter()
+            .map(|(entity, (copy, state))| (entity, copy.clone(), state.phase == TaskStatePhase::Completed))
+            .collect::<Vec<_>>();
+
+        for (entity, copy, copy_done) in copies {
+            let original_done = match world.get::<&TaskState>(copy.original) {
+                Ok(state) => Some(state.phase == TaskStatePhase::Completed),
+                Err(_) => None,
+            };
+
+            match original_done {
+                Some(false) if copy_done => {
+                    info!("Speculative copy of task {:?} finished first", copy.original);
+                    Self::cancel_task(world, copy.original);
+
+                    let (result, winner) = world
+                        .query_one_mut::<(&Task, &TaskState)>(entity)
+                        .map(|(task, state)| (task.result.clone(), state.assigned_device))
+                        .map_err(|e| Error::scheduling(format!("collecting speculative copy {:?}", entity), e))?;
+                    if let Ok((task, task_id, state)) =
+                        world.query_one_mut::<(&mut Task, &TaskId, &mut TaskState)>(copy.original)
+                    {
+                        task.result = result.clone();
+                        state.phase = TaskStatePhase::Completed;
+                        state.assigned_device = winner;
+
+                        let task_id = *task_id;
+                        let origin = world.get::<&TaskOrigin>(copy.original).map(|origin| origin.session).ok();
+                        if let Some(mut session) = origin.and_then(|origin| world.get::<&mut Session>(origin).ok()) {
+                            session.message_queue.push_back(ServerMessage::ServerResult { task_id, result });
+                        }
+                    }
+                    if let Ok(metrics) = world.remove_one::<TaskMetrics>(entity) {
+                        world.insert_one(copy.original, metrics).ok();
+                    }
+                }
+                Some(false) => continue,
+                _ => {
+                    debug!("Task {:?} no longer needs its speculative copy {:?}", copy.original, entity);
+                    Self::cancel_task(world, entity);
+                }
+            }
+
+            world.despawn(entity).ok();
+        }
+        Ok(())
+    }
+
+    // Counts a new task into the running group called `name`, a finished group of that name is
+    // left as it is and a new one started.
+    pub fn join_group(world: &mut World, name: &str) -> TaskGroupMember {
+        let running = world
+            .query_mut::<&mut TaskGroup>()
+            .into_iter()
+            .find(|(_, group)| group.name == name && group.status() == TaskGroupStatus::Running);
+        let group = match running {
+            Some((entity, group)) => {
+                group.total += 1;
+                entity
+            }
+            None => world.spawn((TaskGroup {
+                name: name.into(),
+                total: 1,
+                completed: 0,
+                failed: 0,
+                percent: 0,
+                created_at: SystemTime::now(),
+            },)),
+        };
+        TaskGroupMember { group, settled: false }
+    }
+
+    pub fn track_groups(world: &mut World) {
+        let mut completed = HashMap::<Entity, u32>::new();
+        // Members still running and the fraction of them done, by group.
+        let mut running = HashMap::<Entity, (u32, f64)>::new();
+        for (_, (member, state)) in world.query_mut::<(&mut TaskGroupMember, &TaskState)>() {
+            if member.settled {
+                continue;
+            }
+            if state.phase == TaskStatePhase::Completed {
+                member.settled = true;
+                *completed.entry(member.group).or_default() += 1;
+            } else {
+                let (count, done) = running.entry(member.group).or_default();
+                *count += 1;
+                *done += state.progress.as_ref().map_or(0.0, |progress| progress.percent.min(100) as f64 / 100.0);
+            }
+        }
+
+        for (entity, group) in world.query_mut::<&mut TaskGroup>() {
+            if group.status() != TaskGroupStatus::Running {
+                continue;
+            }
+            let (count, done) = running.get(&entity).copied().unwrap_or_default();
+            let completed = group.completed + completed.get(&entity).copied().unwrap_or_default();
+            let failed = group.total.saturating_sub(completed + count);
+            let percent = ((completed + failed) as f64 + done) * 100.0 / group.total.max(1) as f64;
+            (group.completed, group.failed, group.percent) = (completed, failed, percent as u8);
+            match group.status() {
+                TaskGroupStatus::Running => {}
+                status => info!("Task group {} finished {:?}, {} of {} failed", group.name, status, failed, group.total),
+            }
+        }
+    }
+
+    // Drops the transfer `entity` was waiting on unless another task still needs it.
+    pub fn release_transfer(world: &mut World, entity: Entity, module: Entity, device: Option<Entity>) {
+        let Some(transfer_entity) = device.and_then(|device| Self::module_transfer(world, device, module)) else {
+            return;
+        };
+        let shared = world
+            .get::<&ModuleTransfer>(transfer_entity)
+            .is_ok_and(|transfer| Self::waiting_tasks(world, &transfer).iter().any(|&task| task != entity));
+        if !shared {
+            world.despawn(transfer_entity).ok();
+        }
     }
 
-    pub fn finalize_transfer(world: &mut World) {
-        let completed_transfers = world
-            .query::<(&TaskState, &ModuleTransfer)>()
+    // Stops the task wherever it runs and drops it, a broadcast takes the copies it fanned out
+    // along. Speculative copies go once collect_speculations finds their original gone.
+    pub fn cancel(world: &mut World, entity: Entity) {
+        let children = world
+            .query::<&BroadcastTarget>()
             .iter()
-            .filter_map(|(entity, (state, transfer))| {
-                if transfer.acked_chunks.all() {
-                    state.assigned_device.map(|device| (entity, device))
-                } else {
-                    None
-                }
-            })
+            .filter(|(_, target)| target.parent == entity)
+            .map(|(child, _)| child)
             .collect::<Vec<_>>();
+        for task in children.into_iter().chain([entity]) {
+            Self::cancel_task(world, task);
+            world.despawn(task).ok();
+        }
+        info!("Task {:?} canceled", entity);
+    }
 
-        for (module_entity, session_entity) in completed_transfers {
-            if let Ok(mut session) = world.get::<&mut Session>(session_entity) {
-                session.modules.insert(module_entity);
+    // Takes the task off its device and queues it again, for `device` alone when one is given.
+    // A result still arriving from the old device no longer matches the task's attempt.
+    pub fn reassign(world: &mut World, entity: Entity, device: Option<Entity>) {
+        Self::cancel_task(world, entity);
+        if let Ok(mut state) = world.get::<&mut TaskState>(entity) {
+            state.phase = TaskStatePhase::Queued;
+            state.assigned_device = None;
+            state.progress = None;
+            state.transfer = None;
+        }
+        match device {
+            Some(session) => {
+                world.insert_one(entity, TaskTarget { session }).ok();
             }
-
-            for (_, (_, state)) in world
-                .query::<(&Task, &mut TaskState)>()
-                .iter()
-                .filter(|(_, (task, _))| task.require_module == module_entity)
-            {
-                state.phase = TaskStatePhase::Executing {
-                    deadline: SystemTime::now() + Duration::from_secs(60),
-                }
+            None => {
+                world.remove_one::<TaskTarget>(entity).ok();
             }
+        }
+        info!("Task {:?} requeued for {:?}", entity, device);
+    }
+
+    // Stops a task still in flight on its device and frees the device for other work.
+    fn cancel_task(world: &mut World, entity: Entity) {
+        let Ok((task, task_id, state)) = world.query_one_mut::<(&Task, &TaskId, &TaskState)>(entity) else {
+            return;
+        };
+        if !matches!(state.phase, TaskStatePhase::Distributing | TaskStatePhase::Executing { .. }) {
+            return;
+        }
+        let (module, task_id, device) = (task.require_module, *task_id, state.assigned_device);
 
-            world.remove_one::<ModuleTransfer>(module_entity).ok();
+        Self::release_transfer(world, entity, module, device);
+        let fetches = world
+            .query::<&BlobFetch>()
+            .iter()
+            .filter(|(_, fetch)| fetch.task == entity)
+            .map(|(fetch, _)| fetch)
+            .collect::<Vec<_>>();
+        for fetch in fetches {
+            world.despawn(fetch).ok();
+        }
+        if let Some(Ok((session, health))) =
+            device.map(|device| world.query_one_mut::<(&mut Session, &mut SessionHealth)>(device))
+        {
+            debug!("Cancel task {:?} on device {:?}", entity, device);
+            session.message_queue.push_back(ServerMessage::ServerCancel { task_id });
+            if health.status == SessionStatus::Occupied {
+                health.status = SessionStatus::Connected;
+            }
         }
     }
 }
@@ -229,7 +1529,7 @@ mod tests {
     use std::time::{Duration, SystemTime};
 
     use hecs::Entity;
-    use protocol::Type;
+    use protocol::{CacheStats, Checksum, ExecutionStats, Type};
 
     use super::*;
 
@@ -240,6 +1540,7 @@ mod tests {
                 binary: vec![0u8; size],
                 dependencies: vec![],
                 chunk_size: chunk_size as u32,
+                pinned: false,
             },
         ))
     }
@@ -249,34 +1550,53 @@ mod tests {
             Task {
                 name: name.to_string(),
                 params: vec![Type::I32(0)],
+                env: vec![],
                 result: vec![],
                 created_at: SystemTime::now(),
                 require_module: *module_entity,
                 priority,
+                kind: TaskKind::Single,
             },
             TaskState {
                 phase: TaskStatePhase::Queued,
                 assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 0,
             },
+            next_task_id(),
         ))
     }
 
     fn create_mock_device(world: &mut World, ram: usize, cached: &[Entity]) -> Entity {
+        let mut inventory = DeviceInventory::default();
+        for &module in cached {
+            let module = world.get::<&Module>(module).unwrap();
+            inventory.insert(module.hash(), &module.name);
+        }
         world.spawn((
             Session {
                 message_queue: VecDeque::new(),
-                modules: cached.iter().cloned().collect(),
                 latency: Duration::default(),
+                cache_stats: CacheStats::default(),
             },
+            inventory,
             SessionInfo {
                 device_addr: "0.0.0.0:0".parse().unwrap(),
                 device_ram: ram as u64,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
             },
             SessionHealth {
                 retries: 0,
                 status: SessionStatus::Connected,
                 last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
             },
+            SessionLabels::default(),
         ))
     }
 
@@ -305,8 +1625,7 @@ mod tests {
         ];
 
         for (task_indices, expected_devices) in test_phases {
-            TaskSystem::assign_tasks(&mut world);
-
+            TaskSystem::assign_tasks(&mut world).unwrap();
             for (i, &device) in task_indices.iter().zip(expected_devices.iter()) {
                 let state = world.get::<&TaskState>(tasks[*i]).unwrap();
                 log::info!("{:?}", state);
@@ -323,35 +1642,589 @@ mod tests {
     }
 
     #[test]
-    fn test_transfer_chunks() {
+    fn test_assign_tasks_quarantine() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "module", 25, 16);
+        let task = create_mock_task(&mut world, "task", &module, 1);
+        let device = create_mock_device(&mut world, 4096, &[]);
+        assert!(LifecycleSystem::quarantine_session(&mut world, device, true));
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Queued);
+
+        LifecycleSystem::quarantine_session(&mut world, device, false);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(device));
+    }
+
+    #[test]
+    fn test_assign_tasks_selector() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        world
+            .insert_one(task, TaskSelector {
+                labels: vec!["camera".into(), "outdoor".into()],
+            })
+            .unwrap();
+        let plain_device = create_mock_device(&mut world, 8192, &[module]);
+        let camera_device = create_mock_device(&mut world, 4096, &[]);
+        world.get::<&mut SessionLabels>(camera_device).unwrap().labels.insert("camera".into());
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Queued);
+
+        world.get::<&mut SessionLabels>(camera_device).unwrap().labels.insert("outdoor".into());
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        let state = world.get::<&TaskState>(task).unwrap();
+        assert_eq!(state.phase, TaskStatePhase::Distributing);
+        assert_eq!(state.assigned_device, Some(camera_device));
+        assert!(world.get::<&Session>(plain_device).unwrap().message_queue.is_empty());
+    }
+
+    #[test]
+    fn test_assign_tasks_executor() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let tasks = (0..2)
+            .map(|_| create_mock_task(&mut world, "mock_task", &module, 1))
+            .collect::<Vec<_>>();
+        let interpreter_device = create_mock_device(&mut world, 8192, &[]);
+        let jit_device = create_mock_device(&mut world, 4096, &[]);
+        world.get::<&mut SessionInfo>(jit_device).unwrap().executor = ExecutorFlavor::Jit;
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(tasks[0]).unwrap().assigned_device, Some(jit_device));
+        assert_eq!(world.get::<&TaskState>(tasks[1]).unwrap().assigned_device, Some(interpreter_device));
+    }
+
+    #[test]
+    fn test_assign_tasks_history() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        let interpreter_device = create_mock_device(&mut world, 4096, &[]);
+        let jit_device = create_mock_device(&mut world, 8192, &[module]);
+        world.get::<&mut SessionInfo>(jit_device).unwrap().executor = ExecutorFlavor::Jit;
+
+        // This module happens to run faster on the interpreter class, e.g. due to JIT warmup.
+        let mut cost = ModuleCost::default();
+        for (device, millis) in [(interpreter_device, 5), (jit_device, 40)] {
+            let class = world.get::<&SessionInfo>(device).unwrap().class();
+            let stats = ExecutionStats {
+                wall_time_us: millis * 1000,
+                ..Default::default()
+            };
+            cost.classes.entry(class.clone()).or_default().record(&TaskMetrics::new(device, class, &stats));
+        }
+        world.insert_one(module, cost).unwrap();
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(interpreter_device));
+    }
+
+    #[test]
+    fn test_assign_tasks_benchmark() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let benchmark = |score| DeviceBenchmark {
+            integer: Some(score),
+            float: Some(score),
+            memory: Some(score),
+        };
+        let jit_device = create_mock_device(&mut world, 8192, &[]);
+        let interpreter_device = create_mock_device(&mut world, 4096, &[]);
+        world.get::<&mut SessionInfo>(jit_device).unwrap().executor = ExecutorFlavor::Jit;
+        world.insert_one(jit_device, benchmark(10.0)).unwrap();
+        world.insert_one(interpreter_device, benchmark(40.0)).unwrap();
+
+        // Without history the better score wins over the faster executor.
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(interpreter_device));
+        world.despawn(task).unwrap();
+        world.get::<&mut SessionHealth>(interpreter_device).unwrap().status = SessionStatus::Connected;
+
+        // History on the JIT class alone still predicts the interpreter four times faster.
+        let class = world.get::<&SessionInfo>(jit_device).unwrap().class();
+        let stats = ExecutionStats {
+            wall_time_us: 40_000,
+            ..Default::default()
+        };
+        let mut cost = ModuleCost::default();
+        cost.classes.entry(class.clone()).or_default().record(&TaskMetrics::new(jit_device, class, &stats));
+        world.insert_one(module, cost).unwrap();
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(interpreter_device));
+    }
+
+    #[test]
+    fn test_assign_tasks_fair_share() {
+        fn setup(world: &mut World, sensor_priority: u8) -> (Vec<Entity>, Vec<Entity>) {
+            let module = create_mock_module(world, "mock_module", 25, 16);
+            let mut spawn = |name: &str, priority: u8| {
+                (0..4)
+                    .map(|_| {
+                        let task = create_mock_task(world, name, &module, priority);
+                        world.insert_one(task, TaskOwner { tenant: name.into() }).unwrap();
+                        task
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let tasks = (spawn("sweep", 1), spawn("sensor", sensor_priority));
+            for _ in 0..4 {
+                create_mock_device(world, 4096, &[module]);
+            }
+            tasks
+        }
+
+        fn assigned(world: &World, tasks: &[Entity]) -> usize {
+            tasks.iter()
+                .filter(|&&task| world.get::<&TaskState>(task).unwrap().phase != TaskStatePhase::Queued)
+                .count()
+        }
+
+        // Without weights the devices are split evenly although the sweep has the higher priority.
+        let mut world = World::new();
+        let (sweep, sensor) = setup(&mut world, 5);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!((assigned(&world, &sweep), assigned(&world, &sensor)), (2, 2));
+
+        let mut world = World::new();
+        let (sweep, sensor) = setup(&mut world, 1);
+        TaskSystem::set_fair_share_policy(&mut world, FairSharePolicy {
+            weights: HashMap::from([("sensor".into(), 3)]),
+            ..Default::default()
+        });
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!((assigned(&world, &sweep), assigned(&world, &sensor)), (1, 3));
+    }
+
+    #[test]
+    fn test_pause_scheduling() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let tasks = ["sweep", "sweep", "sensor", "sensor"].map(|tenant| {
+            let task = create_mock_task(&mut world, tenant, &module, 1);
+            world.insert_one(task, TaskOwner { tenant: tenant.into() }).unwrap();
+            task
+        });
+        for _ in 0..4 {
+            create_mock_device(&mut world, 4096, &[module]);
+        }
+        let queued = |world: &World| {
+            tasks.map(|task| world.get::<&TaskState>(task).unwrap().phase == TaskStatePhase::Queued)
+        };
+
+        TaskSystem::pause_scheduling(&mut world, None);
+        TaskSystem::pause_scheduling(&mut world, Some("sweep"));
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(queued(&world), [true; 4]);
+
+        TaskSystem::resume_scheduling(&mut world, None);
+        TaskSystem::pause_scheduling(&mut world, Some("sweep"));
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(queued(&world), [true, true, false, false]);
+
+        TaskSystem::resume_scheduling(&mut world, Some("sweep"));
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(queued(&world), [false; 4]);
+        assert_eq!(TaskSystem::scheduling_pause(&world), SchedulingPause::default());
+    }
+
+    #[test]
+    fn test_cancel_and_reassign() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        let cached_device = create_mock_device(&mut world, 4096, &[module]);
+        let other_device = create_mock_device(&mut world, 4096, &[]);
+        let last_message = |world: &World, device| world.get::<&Session>(device).unwrap().message_queue.back().cloned();
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(cached_device));
+        let task_id = *world.get::<&TaskId>(task).unwrap();
+
+        // Pinned to the other device, the task skips the one holding its module.
+        TaskSystem::reassign(&mut world, task, Some(other_device));
+        assert_eq!(last_message(&world, cached_device), Some(ServerMessage::ServerCancel { task_id }));
+        assert_eq!(world.get::<&SessionHealth>(cached_device).unwrap().status, SessionStatus::Connected);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(other_device));
+        assert!(TaskSystem::module_transfer(&world, other_device, module).is_some());
+
+        TaskSystem::cancel(&mut world, task);
+        assert!(!world.contains(task));
+        assert_eq!(last_message(&world, other_device), Some(ServerMessage::ServerCancel { task_id }));
+        assert!(TaskSystem::module_transfer(&world, other_device, module).is_none());
+    }
+
+    #[test]
+    fn test_resume_checkpoint() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        let device = create_mock_device(&mut world, 4096, &[module]);
+        let other_device = create_mock_device(&mut world, 4096, &[]);
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(device));
+        let checkpoint = TaskCheckpoint {
+            attempt: 1,
+            data: vec![1, 2, 3],
+            saved: SystemTime::now(),
+        };
+        world.insert_one(task, checkpoint).unwrap();
+
+        // The device the task moves to picks up where the last one left off.
+        TaskSystem::reassign(&mut world, task, Some(other_device));
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        let message = world.get::<&Session>(other_device).unwrap().message_queue.back().cloned();
+        assert!(matches!(
+            message,
+            Some(ServerMessage::ServerTask { attempt: 2, checkpoint: Some(data), .. }) if data == [1, 2, 3]
+        ));
+    }
+
+    #[test]
+    fn test_track_groups() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "fractal", 25, 16);
+        let tasks = [0, 1, 2, 3].map(|i| {
+            let task = create_mock_task(&mut world, &format!("fractal_{i}"), &module, 1);
+            let member = TaskSystem::join_group(&mut world, "fractal");
+            world.insert_one(task, member).unwrap();
+            task
+        });
+        let group = world.get::<&TaskGroupMember>(tasks[0]).unwrap().group;
+        let counts = |world: &World| {
+            let group = world.get::<&TaskGroup>(group).unwrap();
+            (group.completed, group.failed, group.percent, group.status())
+        };
+        TaskSystem::track_groups(&mut world);
+        assert_eq!(counts(&world), (0, 0, 0, TaskGroupStatus::Running));
+
+        // Progress of running members counts toward the group, a canceled member fails.
+        world.get::<&mut TaskState>(tasks[0]).unwrap().phase = TaskStatePhase::Completed;
+        world.get::<&mut TaskState>(tasks[1]).unwrap().progress = Some(TaskProgress {
+            percent: 40,
+            stage: "execute".into(),
+            updated: SystemTime::now(),
+        });
+        TaskSystem::cancel(&mut world, tasks[2]);
+        TaskSystem::track_groups(&mut world);
+        TaskSystem::track_groups(&mut world);
+        assert_eq!(counts(&world), (1, 1, 60, TaskGroupStatus::Running));
+
+        for task in [tasks[1], tasks[3]] {
+            world.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Completed;
+        }
+        TaskSystem::track_groups(&mut world);
+        assert_eq!(counts(&world), (3, 1, 100, TaskGroupStatus::Failed));
+
+        // A finished group is not joined again.
+        let member = TaskSystem::join_group(&mut world, "fractal");
+        assert_ne!(member.group, group);
+        assert_eq!(world.get::<&TaskGroup>(member.group).unwrap().total, 1);
+    }
+
+    #[test]
+    fn test_filler_tasks() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let filler = create_mock_task(&mut world, "benchmark", &module, 9);
+        world.insert_one(filler, FillerTask).unwrap();
+        let device = create_mock_device(&mut world, 4096, &[]);
+        let filler_id = *world.get::<&TaskId>(filler).unwrap();
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(filler).unwrap().assigned_device, Some(device));
+        assert_eq!(world.get::<&SessionHealth>(device).unwrap().status, SessionStatus::Occupied);
+
+        // A real task takes the device over, the filler is cancelled there and waits again.
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(device));
+        let state = world.get::<&TaskState>(filler).unwrap();
+        assert_eq!((state.phase.clone(), state.assigned_device), (TaskStatePhase::Queued, None));
+        drop(state);
+        let queue = world.get::<&Session>(device).unwrap().message_queue.clone();
+        assert!(matches!(
+            queue.iter().skip_while(|message| !matches!(message, ServerMessage::ServerCancel { .. })).collect::<Vec<_>>()[..],
+            [ServerMessage::ServerCancel { task_id }, ServerMessage::ServerTask { .. }, ..] if *task_id == filler_id
+        ));
+
+        // Until the device frees up the filler stays queued.
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(filler).unwrap().phase, TaskStatePhase::Queued);
+    }
+
+    #[test]
+    fn test_preempt_tasks() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let low = create_mock_task(&mut world, "low", &module, 1);
+        let mid = create_mock_task(&mut world, "mid", &module, 3);
+        let devices = [create_mock_device(&mut world, 4096, &[]), create_mock_device(&mut world, 4096, &[])];
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        let low_device = world.get::<&TaskState>(low).unwrap().assigned_device.unwrap();
+        let low_id = *world.get::<&TaskId>(low).unwrap();
+        assert!(devices.contains(&low_device));
+
+        // Without the flag a critical task waits for a device like any other.
+        let critical = create_mock_task(&mut world, "critical", &module, 9);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(critical).unwrap().phase, TaskStatePhase::Queued);
+
+        world.insert_one(critical, PreemptiveTask).unwrap();
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(critical).unwrap().assigned_device, Some(low_device));
+        let state = world.get::<&TaskState>(low).unwrap();
+        assert_eq!((state.phase.clone(), state.assigned_device), (TaskStatePhase::Queued, None));
+        drop(state);
+        let queue = world.get::<&Session>(low_device).unwrap().message_queue.clone();
+        assert!(matches!(
+            queue.iter().skip_while(|message| !matches!(message, ServerMessage::ServerCancel { .. })).collect::<Vec<_>>()[..],
+            [ServerMessage::ServerCancel { task_id }, ServerMessage::ServerTask { priority: 9, .. }, ..] if *task_id == low_id
+        ));
+
+        // Tasks of the same priority are never taken off.
+        let peer = create_mock_task(&mut world, "peer", &module, 3);
+        world.insert_one(peer, PreemptiveTask).unwrap();
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(peer).unwrap().phase, TaskStatePhase::Queued);
+        assert_ne!(world.get::<&TaskState>(mid).unwrap().phase, TaskStatePhase::Queued);
+    }
+
+    #[test]
+    fn test_speculate_stragglers() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        let slow_device = create_mock_device(&mut world, 8192, &[module]);
+        let idle_device = create_mock_device(&mut world, 4096, &[]);
+
+        let class = world.get::<&SessionInfo>(slow_device).unwrap().class();
+        let stats = ExecutionStats {
+            wall_time_us: 10_000,
+            ..Default::default()
+        };
+        let mut cost = ModuleCost::default();
+        cost.classes.entry(class.clone()).or_default().record(&TaskMetrics::new(slow_device, class, &stats));
+        world.insert_one(module, cost).unwrap();
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        world.get::<&mut TaskState>(task).unwrap().phase = TaskStatePhase::Executing {
+            started: SystemTime::now() - Duration::from_secs(1),
+            deadline: SystemTime::now() + Duration::from_secs(59),
+        };
+
+        // Two devices at the default ratio leave no room for a duplicate.
+        TaskSystem::speculate_stragglers(&mut world);
+        assert_eq!(world.query::<&SpeculativeCopy>().iter().count(), 0);
+
+        TaskSystem::set_speculation_policy(&mut world, SpeculationPolicy {
+            max_ratio: 0.5,
+            ..Default::default()
+        });
+        TaskSystem::speculate_stragglers(&mut world);
+        TaskSystem::speculate_stragglers(&mut world);
+        let copies = world.query::<&SpeculativeCopy>().iter().map(|(entity, _)| entity).collect::<Vec<_>>();
+        assert_eq!(copies.len(), 1);
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(copies[0]).unwrap().assigned_device, Some(idle_device));
+
+        world.get::<&mut Task>(copies[0]).unwrap().result = vec![Type::I32(1)];
+        world.get::<&mut TaskState>(copies[0]).unwrap().phase = TaskStatePhase::Completed;
+        world.get::<&mut Session>(slow_device).unwrap().message_queue.clear();
+        TaskSystem::collect_speculations(&mut world).unwrap();
+        assert!(!world.contains(copies[0]));
+        let state = world.get::<&TaskState>(task).unwrap();
+        assert_eq!(state.phase, TaskStatePhase::Completed);
+        assert_eq!(state.assigned_device, Some(idle_device));
+        assert_eq!(world.get::<&Task>(task).unwrap().result, vec![Type::I32(1)]);
+        let task_id = *world.get::<&TaskId>(task).unwrap();
+        assert_eq!(
+            world.get::<&Session>(slow_device).unwrap().message_queue.front(),
+            Some(&ServerMessage::ServerCancel { task_id })
+        );
+        assert_eq!(world.get::<&SessionHealth>(slow_device).unwrap().status, SessionStatus::Connected);
+    }
+
+    #[test]
+    fn test_assign_tasks_native() {
         let mut world = World::new();
         let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        world.insert_one(module, NativeModule).unwrap();
         let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        let wasm_device = create_mock_device(&mut world, 8192, &[module]);
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Queued);
+
+        let native_device = create_mock_device(&mut world, 4096, &[]);
+        world.get::<&mut SessionLabels>(native_device).unwrap().labels.insert(protocol::NATIVE_LABEL.into());
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(native_device));
+        assert!(world.get::<&Session>(wasm_device).unwrap().message_queue.is_empty());
+    }
+
+    #[test]
+    fn test_transfer_chunks() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        create_mock_task(&mut world, "mock_task", &module, 1);
         let device = create_mock_device(&mut world, 4096, &[]);
 
-        TaskSystem::assign_tasks(&mut world);
+        TaskSystem::assign_tasks(&mut world).unwrap();
         assert_eq!(world.get::<&Session>(device).unwrap().message_queue.len(), 1);
+        let transfer = TaskSystem::module_transfer(&world, device, module).unwrap();
 
-        world.get::<&mut ModuleTransfer>(task).unwrap().state = ModuleTransferState::Requested;
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().state = ModuleTransferState::Requested;
         world.get::<&mut Session>(device).unwrap().message_queue.clear();
-        TaskSystem::transfer_chunks(&mut world);
-
+        TaskSystem::transfer_chunks(&mut world).unwrap();
         let chunks = world.get::<&Session>(device).unwrap().message_queue
             .iter()
-            .map(|message: &Message| match message {
-                Message::ServerModule { chunk_data, .. } => chunk_data.len(),
+            .map(|message: &ServerMessage| match message {
+                ServerMessage::ServerModule { chunk_data, .. } => chunk_data.len(),
                 _ => unreachable!(),
             })
             .collect::<Vec<_>>();
         assert_eq!(chunks, vec![16, 9]);
 
-        world.get::<&mut ModuleTransfer>(task).unwrap().state = ModuleTransferState::Requested;
-        world.get::<&mut ModuleTransfer>(task).unwrap().acked_chunks.set(0, true);
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().state = ModuleTransferState::Requested;
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().acked_chunks.set(0, true);
         world.get::<&mut Session>(device).unwrap().message_queue.clear();
-        TaskSystem::transfer_chunks(&mut world);
+        TaskSystem::transfer_chunks(&mut world).unwrap();
         assert_eq!(world.get::<&Session>(device).unwrap().message_queue.len(), 1);
     }
 
+    #[test]
+    fn test_adaptive_chunks() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 128, 16);
+        create_mock_task(&mut world, "mock_task", &module, 1);
+        let device = create_mock_device(&mut world, 1 << 20, &[]);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        let transfer = TaskSystem::module_transfer(&world, device, module).unwrap();
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().state = ModuleTransferState::Requested;
+
+        let sent = |world: &mut World| {
+            let mut session = world.get::<&mut Session>(device).unwrap();
+            session
+                .message_queue
+                .drain(..)
+                .filter_map(|message| match message {
+                    ServerMessage::ServerModule { chunk_index, chunk_data, .. } => Some((chunk_index, chunk_data.len())),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let ack = |world: &World, chunk_index, success| {
+            let mut transfer = world.get::<&mut ModuleTransfer>(transfer).unwrap();
+            TaskSystem::ack_chunk(world, &mut transfer, chunk_index, success, SystemTime::now());
+        };
+        let delay = |world: &World, chunk_index, by| {
+            let mut transfer = world.get::<&mut ModuleTransfer>(transfer).unwrap();
+            transfer.in_flight.get_mut(&chunk_index).unwrap().sent -= by;
+        };
+
+        // A few single chunks go out first, a quick ack doubles the next message.
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        assert_eq!(sent(&mut world), vec![(0, 16), (1, 16), (2, 16), (3, 16)]);
+        ack(&world, 0, true);
+        assert_eq!(world.get::<&ChunkSizing>(device).unwrap().chunks, 2);
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        assert_eq!(sent(&mut world), vec![(4, 32)]);
+
+        // A slow ack halves the size, a failed one starts over and the chunk is sent again.
+        delay(&world, 1, Duration::from_secs(1));
+        ack(&world, 1, true);
+        ack(&world, 4, true);
+        assert_eq!(world.get::<&ChunkSizing>(device).unwrap().chunks, 2);
+        ack(&world, 2, false);
+        let sizing = *world.get::<&ChunkSizing>(device).unwrap();
+        assert_eq!((sizing.chunks, sizing.threshold), (1, 1));
+        assert_eq!(world.get::<&ModuleTransfer>(transfer).unwrap().acked_chunks.count_ones(), 4);
+
+        // A message never acked is lost and goes out again.
+        delay(&world, 3, Duration::from_secs(10));
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        assert_eq!(sent(&mut world), vec![(2, 16), (3, 16), (6, 16), (7, 16)]);
+        assert_eq!(world.get::<&ModuleTransfer>(transfer).unwrap().in_flight.len(), 4);
+    }
+
+    #[test]
+    fn test_track_transfers() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        let device = create_mock_device(&mut world, 4096, &[]);
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        let transfer = TaskSystem::module_transfer(&world, device, module).unwrap();
+        let start = SystemTime::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+        let progress = |world: &World| world.get::<&TaskState>(task).unwrap().transfer.clone().unwrap();
+
+        TaskSystem::track_transfers(&mut world, start);
+        assert_eq!((progress(&world).chunks_acked, progress(&world).total_chunks), (0, 2));
+
+        // Only the short last chunk has arrived, a pass without new acks keeps `updated`.
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().acked_chunks.set(1, true);
+        TaskSystem::track_transfers(&mut world, secs(1));
+        TaskSystem::track_transfers(&mut world, secs(3));
+        let current = progress(&world);
+        assert_eq!((current.chunks_acked, current.bytes_acked), (1, 9));
+        assert_eq!((current.started, current.updated), (start, secs(1)));
+        assert_eq!(current.bytes_per_sec, 3.0);
+
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().acked_chunks.fill(true);
+        TaskSystem::track_transfers(&mut world, secs(5));
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert_eq!(progress(&world).bytes_acked, 25);
+        assert!(!world.contains(transfer));
+
+        TaskSystem::reassign(&mut world, task, None);
+        assert!(world.get::<&TaskState>(task).unwrap().transfer.is_none());
+    }
+
+    #[test]
+    fn test_transfer_aot_artifact() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let mut artifacts = ModuleArtifacts::default();
+        artifacts.aot.insert("xtensa".into(), vec![1u8; 40]);
+        world.insert_one(module, artifacts).unwrap();
+        create_mock_task(&mut world, "mock_task", &module, 1);
+        let device = create_mock_device(&mut world, 4096, &[]);
+        {
+            let mut info = world.get::<&mut SessionInfo>(device).unwrap();
+            info.executor = ExecutorFlavor::Aot;
+            info.arch = "xtensa".into();
+        }
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert!(matches!(
+            world.get::<&Session>(device).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerTask { module, attempt: 1, .. }) if module.size == 40 && module.total_chunks == 3
+        ));
+        let transfer = TaskSystem::module_transfer(&world, device, module).unwrap();
+
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().state = ModuleTransferState::Requested;
+        world.get::<&mut Session>(device).unwrap().message_queue.clear();
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        let chunks = world.get::<&Session>(device).unwrap().message_queue
+            .iter()
+            .map(|message: &ServerMessage| match message {
+                ServerMessage::ServerModule { chunk_data, .. } => chunk_data[0],
+                _ => unreachable!(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(chunks, vec![1, 1, 1]);
+    }
+
     #[test]
     fn test_finalize_tasks() {
         let mut world = World::new();
@@ -359,17 +2232,304 @@ mod tests {
         let task = create_mock_task(&mut world, "mock_task", &module, 1);
         let device = create_mock_device(&mut world, 4096, &[]);
 
-        TaskSystem::assign_tasks(&mut world);
+        TaskSystem::assign_tasks(&mut world).unwrap();
         assert_eq!(world.get::<&Session>(device).unwrap().message_queue.len(), 1);
-        world.get::<&mut ModuleTransfer>(task).unwrap().state = ModuleTransferState::Requested;
-        TaskSystem::transfer_chunks(&mut world);
+        let transfer = TaskSystem::module_transfer(&world, device, module).unwrap();
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().state = ModuleTransferState::Requested;
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().acked_chunks.set(0, true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert_eq!(world.get::<&mut ModuleTransfer>(transfer).unwrap().state, ModuleTransferState::Transferring);
+
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().acked_chunks.set(1, true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(!world.contains(transfer));
+        assert!(matches!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Executing { .. }));
+        let hash = world.get::<&Module>(module).unwrap().hash();
+        assert!(world.get::<&DeviceInventory>(device).unwrap().contains(hash));
+    }
+
+    #[test]
+    fn test_shared_module_transfer() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let first = create_mock_task(&mut world, "first_task", &module, 1);
+        let second = create_mock_task(&mut world, "second_task", &module, 2);
+        let device = create_mock_device(&mut world, 4096, &[]);
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        world.get::<&mut SessionHealth>(device).unwrap().status = SessionStatus::Connected;
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        for task in [first, second] {
+            assert_eq!(world.get::<&TaskState>(task).unwrap().assigned_device, Some(device));
+        }
+        assert_eq!(world.query::<&ModuleTransfer>().iter().count(), 1);
+
+        let transfer = TaskSystem::module_transfer(&world, device, module).unwrap();
+        assert_eq!(world.get::<&ModuleTransfer>(transfer).unwrap().task_id, *world.get::<&TaskId>(first).unwrap());
+        world.get::<&mut ModuleTransfer>(transfer).unwrap().acked_chunks.fill(true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(!world.contains(transfer));
+        for task in [first, second] {
+            assert!(matches!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Executing { .. }));
+        }
+    }
+
+    #[test]
+    fn test_prefetch_module() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let cached_device = create_mock_device(&mut world, 4096, &[module]);
+        let idle_device = create_mock_device(&mut world, 4096, &[]);
+
+        assert_eq!(TaskSystem::prefetch_module(&mut world, module).unwrap(), 1);
+        assert!(world.get::<&Session>(cached_device).unwrap().message_queue.is_empty());
+        assert!(matches!(
+            world.get::<&Session>(idle_device).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerPrefetch { module, .. }) if module.total_chunks == 2
+        ));
+        assert_eq!(world.get::<&SessionHealth>(idle_device).unwrap().status, SessionStatus::Occupied);
+
+        let prefetch = world.query::<&ModulePrefetch>().iter().map(|(entity, _)| entity).next().unwrap();
+        world.get::<&mut ModuleTransfer>(prefetch).unwrap().state = ModuleTransferState::Requested;
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        assert_eq!(world.get::<&Session>(idle_device).unwrap().message_queue.len(), 3);
+
+        world.get::<&mut ModuleTransfer>(prefetch).unwrap().acked_chunks.fill(true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(!world.contains(prefetch));
+        let hash = world.get::<&Module>(module).unwrap().hash();
+        assert!(world.get::<&DeviceInventory>(idle_device).unwrap().contains(hash));
+        assert_eq!(world.get::<&SessionHealth>(idle_device).unwrap().status, SessionStatus::Connected);
+        assert_eq!(TaskSystem::prefetch_module(&mut world, module).unwrap(), 0);
+
+        // A module replaced under the same name is not cached anywhere.
+        world.get::<&mut Module>(module).unwrap().binary = vec![1u8; 25];
+        assert_eq!(TaskSystem::prefetch_module(&mut world, module).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_shared_prefetch() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 40, 16);
+        let devices = [0; 3].map(|_| create_mock_device(&mut world, 4096, &[]));
+
+        // All devices get the module under one task id, each chunk is encoded once for all of them.
+        assert_eq!(TaskSystem::prefetch_module(&mut world, module).unwrap(), 3);
+        let (group, task_id) = world
+            .query::<&TransferGroup>()
+            .iter()
+            .map(|(entity, group)| (entity, group.task_id))
+            .next()
+            .unwrap();
+        let transfers = world
+            .query::<(&ModuleTransfer, &SharedTransfer)>()
+            .iter()
+            .map(|(entity, (transfer, shared))| {
+                assert_eq!((transfer.task_id, shared.group), (task_id, group));
+                entity
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(transfers.len(), 3);
+        for &transfer in &transfers {
+            world.get::<&mut ModuleTransfer>(transfer).unwrap().state = ModuleTransferState::Requested;
+        }
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        let frames = devices.map(|device| world.get::<&SharedFrames>(device).unwrap().0.clone());
+        assert_eq!(frames[0].len(), 3);
+        let pointers = frames.each_ref().map(|frames| frames.iter().map(|frame| frame.as_ptr()).collect::<Vec<_>>());
+        assert!(pointers[1] == pointers[0] && pointers[2] == pointers[0]);
+        assert_eq!(Message::decode(&frames[0][2]).unwrap().0, Message::ServerModule {
+            task_id,
+            chunk_index: 2,
+            chunk_data: vec![0u8; 8],
+        });
+
+        // Members finish on their own acks, the group goes with the last of them.
+        for &transfer in &transfers[..2] {
+            world.get::<&mut ModuleTransfer>(transfer).unwrap().acked_chunks.fill(true);
+        }
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(world.contains(group) && world.contains(transfers[2]));
+        world.get::<&mut ModuleTransfer>(transfers[2]).unwrap().acked_chunks.fill(true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(!world.contains(group));
+        let hash = world.get::<&Module>(module).unwrap().hash();
+        assert!(devices.iter().all(|&device| world.get::<&DeviceInventory>(device).unwrap().contains(hash)));
+    }
+
+    #[test]
+    fn test_fetch_blobs() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 16, 16);
+        let device = create_mock_device(&mut world, 4096, &[module]);
+        let blobs = [1, 2].map(|id| Blob {
+            id,
+            binary: vec![id as u8; 20],
+            chunk_size: 16,
+        });
+        let references = blobs.iter().map(Blob::reference).collect::<Vec<_>>();
+        let blob_entities = blobs.map(|blob| world.spawn((blob,)));
+        world
+            .get::<&mut DeviceInventory>(device)
+            .unwrap()
+            .insert(world.get::<&Blob>(blob_entities[1]).unwrap().hash(), "blob:2");
+        let task = create_mock_task(&mut world, "task", &module, 1);
+        world.get::<&mut Task>(task).unwrap().params = references.clone();
+        let task_id = *world.get::<&TaskId>(task).unwrap();
+
+        // Only the blob the device lacks is fetched, under the task's id and ahead of the task.
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Distributing);
+        assert!(matches!(
+            world.get::<&mut Session>(device).unwrap().message_queue.pop_front(),
+            Some(ServerMessage::ServerPrefetch { task_id: id, module }) if id == task_id && module.name == "blob:1" && module.total_chunks == 2
+        ));
+        let fetch = world.query::<&BlobFetch>().iter().map(|(entity, _)| entity).next().unwrap();
+        world.get::<&mut ModuleTransfer>(fetch).unwrap().state = ModuleTransferState::Requested;
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        assert_eq!(world.get::<&Session>(device).unwrap().message_queue.len(), 2);
+        world.get::<&mut Session>(device).unwrap().message_queue.clear();
+
+        world.get::<&mut ModuleTransfer>(fetch).unwrap().acked_chunks.fill(true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(!world.contains(fetch));
+        let hash = world.get::<&Blob>(blob_entities[0]).unwrap().hash();
+        assert!(world.get::<&DeviceInventory>(device).unwrap().contains(hash));
+        assert!(matches!(
+            world.get::<&Session>(device).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerTask { params, .. }) if *params == references
+        ));
+        assert_eq!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Distributing);
+        assert_eq!(world.query::<&ModuleTransfer>().iter().count(), 1);
+    }
+
+    #[test]
+    fn test_fetch_libraries() {
+        let mut world = World::new();
+        let library = create_mock_module(&mut world, "libm", 20, 16);
+        let module = create_mock_module(&mut world, "mock_module", 16, 16);
+        world.get::<&mut Module>(module).unwrap().dependencies = vec![library];
+        let device = create_mock_device(&mut world, 4096, &[module]);
+        let task = create_mock_task(&mut world, "task", &module, 1);
+
+        // The library is fetched like a blob before the task names it.
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert!(matches!(
+            world.get::<&mut Session>(device).unwrap().message_queue.pop_front(),
+            Some(ServerMessage::ServerPrefetch { module, .. }) if module.name == "libm" && module.total_chunks == 2
+        ));
+        let fetch = world.query::<&BlobFetch>().iter().map(|(entity, _)| entity).next().unwrap();
+        assert_eq!(world.get::<&ModuleTransfer>(fetch).unwrap().module, library);
+
+        world.get::<&mut ModuleTransfer>(fetch).unwrap().acked_chunks.fill(true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(!world.contains(fetch));
+        let hash = world.get::<&Module>(library).unwrap().hash();
+        assert!(world.get::<&DeviceInventory>(device).unwrap().contains(hash));
+        assert!(matches!(
+            world.get::<&Session>(device).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerTask { libraries, .. }) if libraries.len() == 1 && libraries[0].name == "libm"
+        ));
+        assert_eq!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Distributing);
+    }
+
+    #[test]
+    fn test_update_firmware() {
+        let mut world = World::new();
+        let esp = create_mock_device(&mut world, 4096, &[]);
+        world.get::<&mut SessionInfo>(esp).unwrap().arch = "xtensa".into();
+        let desktop = create_mock_device(&mut world, 4096, &[]);
+        world.get::<&mut SessionInfo>(desktop).unwrap().arch = "x86_64".into();
+        let firmware = world.spawn((Firmware {
+            version: "1.1.0".into(),
+            binary: vec![7; 40],
+            chunk_size: 16,
+            arch: "xtensa".into(),
+        },));
+
+        assert_eq!(TaskSystem::update_firmware(&mut world, firmware).unwrap(), 1);
+        assert!(world.get::<&Session>(desktop).unwrap().message_queue.is_empty());
+        assert!(matches!(
+            world.get::<&mut Session>(esp).unwrap().message_queue.pop_front(),
+            Some(ServerMessage::ServerFirmware { firmware, .. }) if firmware.total_chunks == 3 && firmware.checksum == Checksum::of(&[7; 40])
+        ));
+        assert_eq!(world.get::<&SessionHealth>(esp).unwrap().status, SessionStatus::Occupied);
+
+        let update = world.query::<&FirmwareUpdate>().iter().map(|(entity, _)| entity).next().unwrap();
+        world.get::<&mut ModuleTransfer>(update).unwrap().state = ModuleTransferState::Requested;
+        TaskSystem::transfer_chunks(&mut world).unwrap();
+        let queue = world.get::<&Session>(esp).unwrap().message_queue.clone();
+        assert!(matches!(queue.back(), Some(ServerMessage::ServerModule { chunk_index: 2, chunk_data, .. }) if chunk_data.len() == 8));
+
+        // The image stays around while the update is in flight, even with every chunk acked.
+        world.get::<&mut ModuleTransfer>(update).unwrap().acked_chunks.fill(true);
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(world.contains(update) && world.contains(firmware));
+
+        world.despawn(esp).unwrap();
+        TaskSystem::finalize_transfer(&mut world).unwrap();
+        assert!(!world.contains(update));
+        assert!(!world.contains(firmware));
+    }
+
+    #[test]
+    fn test_unpin_module() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        world.get::<&mut Module>(module).unwrap().pinned = true;
+        let cached_device = create_mock_device(&mut world, 4096, &[module]);
+        let other_device = create_mock_device(&mut world, 4096, &[]);
 
-        world.get::<&mut ModuleTransfer>(task).unwrap().acked_chunks.set(0, true);
-        TaskSystem::finalize_transfer(&mut world);
-        assert_eq!(world.get::<&mut ModuleTransfer>(task).unwrap().state, ModuleTransferState::Transferring);
+        TaskSystem::unpin_module(&mut world, module);
+        assert!(!world.get::<&Module>(module).unwrap().pinned);
+        assert!(matches!(
+            world.get::<&Session>(cached_device).unwrap().message_queue.front(),
+            Some(ServerMessage::ServerUnpin { module }) if module == "mock_module"
+        ));
+        assert!(world.get::<&Session>(other_device).unwrap().message_queue.is_empty());
+    }
+
+    #[test]
+    fn test_broadcast_tasks() {
+        let mut world = World::new();
+        let module = create_mock_module(&mut world, "mock_module", 25, 16);
+        let task = create_mock_task(&mut world, "mock_task", &module, 1);
+        world.get::<&mut Task>(task).unwrap().kind = TaskKind::Broadcast;
+        let devices = [
+            create_mock_device(&mut world, 4096, &[]),
+            create_mock_device(&mut world, 4096, &[module]),
+        ];
+        create_mock_device(&mut world, 1024, &[]);
+
+        TaskSystem::assign_tasks(&mut world).unwrap();
+        assert_eq!(world.get::<&TaskState>(task).unwrap().phase, TaskStatePhase::Distributing);
 
-        world.get::<&mut ModuleTransfer>(task).unwrap().acked_chunks.set(1, true);
-        TaskSystem::finalize_transfer(&mut world);
-        assert!(world.get::<&ModuleTransfer>(task).is_err());
+        let children = world
+            .query::<(&BroadcastTarget, &TaskState)>()
+            .iter()
+            .map(|(entity, (target, state))| {
+                assert_eq!(target.parent, task);
+                assert_eq!(state.assigned_device, Some(target.session));
+                (entity, target.session)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(children.len(), 2);
+
+        for (i, &(child, _)) in children.iter().enumerate() {
+            world.get::<&mut Task>(child).unwrap().result = vec![Type::I32(i as i32)];
+            world.get::<&mut TaskState>(child).unwrap().phase = TaskStatePhase::Completed;
+
+            TaskSystem::collect_broadcasts(&mut world);
+            assert!(!world.contains(child));
+        }
+
+        let state = world.get::<&TaskState>(task).unwrap();
+        assert_eq!(state.phase, TaskStatePhase::Completed);
+        assert_eq!(state.results.len(), 2);
+        for (i, &(_, session)) in children.iter().enumerate() {
+            assert!(devices.contains(&session));
+            assert_eq!(state.results[&session], vec![Type::I32(i as i32)]);
+        }
     }
 }
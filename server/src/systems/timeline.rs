@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::mem::discriminant;
+use std::time::SystemTime;
+
+use hecs::World;
+
+use crate::components::*;
+use crate::snapshot::{snapshot, WorldSnapshot};
+
+pub struct TimelineSystem;
+
+impl TimelineSystem {
+    pub fn set_timeline(world: &mut World, timeline: SchedulerTimeline) {
+        let current = world.query_mut::<&mut SchedulerTimeline>().into_iter().next();
+        match current {
+            Some((_, current)) => *current = timeline,
+            None => {
+                world.spawn((timeline,));
+            }
+        }
+    }
+
+    // Logs how tasks moved since the previous pass and snapshots the world once the interval has
+    // passed, spawning the default timeline on first use.
+    pub fn record(world: &mut World, now: SystemTime) {
+        if world.query::<&SchedulerTimeline>().iter().next().is_none() {
+            world.spawn((SchedulerTimeline::default(),));
+        }
+
+        let tasks = world
+            .query::<&TaskState>()
+            .iter()
+            .map(|(entity, state)| (entity, (state.phase.clone(), state.assigned_device, state.attempt)))
+            .collect::<Vec<_>>();
+        let mut snapshot = match world.query::<&SchedulerTimeline>().iter().all(|(_, timeline)| timeline.due(now)) {
+            true => Some(WorldSnapshot { taken_at: now, ..snapshot(world) }),
+            false => None,
+        };
+
+        for (_, timeline) in world.query_mut::<&mut SchedulerTimeline>() {
+            let mut events = Vec::new();
+            let mut seen = HashMap::with_capacity(tasks.len());
+            for (task, current) in &tasks {
+                let previous = timeline.seen.remove(task);
+                let (phase, device, attempt) = current;
+                let moved = previous.as_ref().is_none_or(|(previous, ..)| discriminant(previous) != discriminant(phase));
+                let kind = match phase {
+                    TaskStatePhase::Queued if moved => Some(SchedulerEventKind::Queued),
+                    TaskStatePhase::Distributing
+                        if moved || previous.as_ref().is_some_and(|(_, _, previous)| previous != attempt) =>
+                    {
+                        device.map(|device| SchedulerEventKind::Assigned { device, attempt: *attempt })
+                    }
+                    TaskStatePhase::Executing { .. } if moved => Some(SchedulerEventKind::Executing),
+                    TaskStatePhase::Completed if moved => Some(SchedulerEventKind::Completed),
+                    _ => None,
+                };
+                events.extend(kind.map(|kind| (*task, kind)));
+                seen.insert(*task, current.clone());
+            }
+            // Whatever is left was seen last pass and is gone now.
+            events.extend(timeline.seen.drain().map(|(task, _)| (task, SchedulerEventKind::Removed)));
+            timeline.seen = seen;
+            timeline.events.extend(events.into_iter().map(|(task, kind)| SchedulerEvent {
+                timestamp: now,
+                task,
+                kind,
+            }));
+            if let Some(snapshot) = snapshot.take() {
+                timeline.push(snapshot);
+            }
+        }
+    }
+
+    pub fn at(world: &World, at: SystemTime) -> Option<(WorldSnapshot, Vec<SchedulerEvent>)> {
+        let mut timelines = world.query::<&SchedulerTimeline>();
+        let (_, timeline) = timelines.iter().next()?;
+        let (snapshot, events) = timeline.at(at)?;
+        Some((snapshot.clone(), events.into_iter().cloned().collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use hecs::Entity;
+
+    use super::*;
+
+    fn spawn_task(world: &mut World) -> Entity {
+        let module = world.spawn((Module {
+            name: "mock_module".into(),
+            binary: vec![0u8; 16],
+            dependencies: vec![],
+            chunk_size: 16,
+            pinned: false,
+        },));
+        world.spawn((
+            Task {
+                name: "mock_task".into(),
+                params: vec![],
+                env: vec![],
+                result: vec![],
+                created_at: SystemTime::now(),
+                require_module: module,
+                priority: 1,
+                kind: TaskKind::Single,
+            },
+            TaskState {
+                phase: TaskStatePhase::Queued,
+                assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 0,
+            },
+        ))
+    }
+
+    #[test]
+    fn test_record_timeline() {
+        let mut world = World::new();
+        TimelineSystem::set_timeline(&mut world, SchedulerTimeline::new(Duration::from_secs(10), 2));
+        let task = spawn_task(&mut world);
+        let device = world.spawn(());
+        let start = SystemTime::now();
+        let secs = |secs| start + Duration::from_secs(secs);
+
+        TimelineSystem::record(&mut world, start);
+        {
+            let mut state = world.get::<&mut TaskState>(task).unwrap();
+            state.phase = TaskStatePhase::Distributing;
+            state.assigned_device = Some(device);
+            state.attempt = 1;
+        }
+        TimelineSystem::record(&mut world, secs(1));
+        TimelineSystem::record(&mut world, secs(2));
+
+        // The snapshot already shows the task queued, only the assignment after it is replayed.
+        let (snapshot, events) = TimelineSystem::at(&world, secs(5)).unwrap();
+        assert_eq!(snapshot.taken_at, start);
+        assert_eq!(snapshot.tasks[0].state.phase, TaskStatePhase::Queued);
+        assert_eq!(events, vec![SchedulerEvent {
+            timestamp: secs(1),
+            task,
+            kind: SchedulerEventKind::Assigned { device, attempt: 1 },
+        }]);
+        assert!(TimelineSystem::at(&world, start - Duration::from_secs(1)).is_none());
+
+        // Two more snapshots push out the first along with the events it covered.
+        world.despawn(task).unwrap();
+        TimelineSystem::record(&mut world, secs(10));
+        TimelineSystem::record(&mut world, secs(20));
+        assert!(TimelineSystem::at(&world, secs(5)).is_none());
+        let (snapshot, events) = TimelineSystem::at(&world, secs(20)).unwrap();
+        assert!(snapshot.tasks.is_empty() && events.is_empty());
+        let (_, events) = TimelineSystem::at(&world, secs(10)).unwrap();
+        assert!(events.is_empty());
+    }
+}
@@ -3,7 +3,7 @@ mod common;
 use std::time::{Duration, SystemTime};
 
 use common::{TestClient, TestServer};
-use protocol::{AckInfo, Message, Type};
+use protocol::{AckInfo, ExecutionStats, Message, Type};
 use server::*;
 use tokio::io::*;
 
@@ -29,14 +29,14 @@ async fn run_client(stream: DuplexStream) {
         .await
         .unwrap();
 
-    if let Message::ServerTask { task_id, module, .. } = task_msg {
+    if let Message::ServerTask { task_id, attempt, module, .. } = task_msg {
         assert_eq!(module.name, "test_module");
         assert_eq!(module.chunk_size, 16);
         assert_eq!(module.total_chunks, 3);
 
         let ack_msg = Message::ClientAck {
             task_id,
-            ack_info: AckInfo::Module {
+            ack_info: AckInfo::ModuleListAck {
                 modules: vec![],
             },
         };
@@ -55,7 +55,7 @@ async fn run_client(stream: DuplexStream) {
 
             let ack_msg = Message::ClientAck {
                 task_id,
-                ack_info: AckInfo::Chunk {
+                ack_info: AckInfo::ChunkAck {
                     chunk_index: idx,
                     success: true,
                 },
@@ -65,7 +65,9 @@ async fn run_client(stream: DuplexStream) {
 
         let result_msg = Message::ClientResult {
             task_id,
+            attempt,
             result: vec![Type::I32(30)],
+            stats: ExecutionStats::default(),
         };
         client.send(&result_msg).await.unwrap();
 
@@ -86,15 +88,18 @@ async fn run_server(stream: DuplexStream) {
         name: "test_module".into(),
         binary: TEST_MODULE.to_vec(),
         dependencies: vec![],
-        chunk_size: 16
+        chunk_size: 16,
+        pinned: false,
     });
     let task_entity = server.add_task(Task {
         name: "test_task".into(),
         params: vec![Type::I32(10), Type::I32(20)],
+        env: vec![],
         result: vec![],
         created_at: SystemTime::now(),
         require_module: module_entity,
         priority: 1,
+        kind: TaskKind::Single,
     });
 
     loop {
@@ -2,7 +2,7 @@ use std::error::Error;
 use std::sync::Arc;
 use std::time::Duration;
 
-use protocol::Message;
+use protocol::{ExecutorFlavor, Message};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::sync::Mutex;
 use tokio::time::timeout;
@@ -68,15 +68,24 @@ where
         }
     }
 
+    // Returns the resume token the server answered with.
     pub async fn handshake(
         &mut self,
         modules: Vec<String>,
         ram: u64,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<u64, Box<dyn Error>> {
         self.send(&Message::ClientReady {
             modules,
             device_ram: ram,
+            labels: Vec::new(),
+            executor: ExecutorFlavor::Interpreter,
+            arch: String::new(),
+            resume_token: None,
         })
-        .await
+        .await?;
+        match self.receive(None).await? {
+            Message::ServerSession { resume_token, .. } => Ok(resume_token),
+            other => Err(format!("expected ServerSession, got {:?}", other).into()),
+        }
     }
 }
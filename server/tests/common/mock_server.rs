@@ -1,9 +1,10 @@
-use std::collections::{HashSet, VecDeque};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
 use bytes::BytesMut;
 use hecs::{Entity, World};
+use protocol::{CacheStats, ExecutorFlavor, SequenceTracker};
 use server::*;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::sync::Mutex;
@@ -29,7 +30,12 @@ impl TestServer {
             TaskState {
                 phase: TaskStatePhase::Queued,
                 assigned_device: None,
+                results: HashMap::new(),
+                progress: None,
+                transfer: None,
+                attempt: 0,
             },
+            next_task_id(),
         ))
     }
 
@@ -41,22 +47,31 @@ impl TestServer {
             Session {
                 message_queue: VecDeque::new(),
                 latency: Duration::default(),
-                modules: HashSet::new(),
+                cache_stats: CacheStats::default(),
             },
+            DeviceInventory::default(),
             SessionInfo {
                 device_addr: "0.0.0.0:0".parse().unwrap(),
                 device_ram: 0,
+                executor: ExecutorFlavor::Interpreter,
+                arch: String::new(),
             },
             SessionStream {
                 inner: Arc::new(Mutex::new(stream)),
                 incoming: BytesMut::new(),
                 outgoing: BytesMut::new(),
+                sequence: SequenceTracker::default(),
+                next_sequence: None,
             },
             SessionHealth {
                 retries: 0,
                 status: SessionStatus::Connected,
                 last_heartbeat: SystemTime::now(),
+                protocol_errors: 0,
+                last_protocol_error: None,
+                sequence: None,
             },
+            SessionLabels::default(),
         ))
     }
 
@@ -65,9 +80,12 @@ impl TestServer {
         T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
         NetworkSystem::process_inbound::<T>(&mut self.world).await;
-        TaskSystem::assign_tasks(&mut self.world);
-        TaskSystem::transfer_chunks(&mut self.world);
-        TaskSystem::finalize_transfer(&mut self.world);
+        TaskSystem::assign_tasks(&mut self.world).unwrap();
+        TaskSystem::transfer_chunks(&mut self.world).unwrap();
+        TaskSystem::track_transfers(&mut self.world, SystemTime::now());
+        TaskSystem::finalize_transfer(&mut self.world).unwrap();
         NetworkSystem::process_outbound::<T>(&mut self.world).await;
+        // Inbound reads no longer wait for data, give the client task a turn like the dispatcher does.
+        tokio::task::yield_now().await;
     }
 }
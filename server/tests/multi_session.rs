@@ -5,7 +5,7 @@ use std::time::{Duration, SystemTime};
 
 use common::{TestClient, TestServer};
 use hecs::Entity;
-use protocol::{AckInfo, Message, Type};
+use protocol::{AckInfo, ExecutionStats, Message, Type};
 use server::*;
 use tokio::io::*;
 use tokio::task::JoinSet;
@@ -32,10 +32,10 @@ async fn run_client(streams: Vec<DuplexStream>) {
                 .await
                 .unwrap();
 
-            if let Message::ServerTask { task_id, module, params } = task_msg {
+            if let Message::ServerTask { task_id, attempt, module, params, .. } = task_msg {
                 let ack_msg = Message::ClientAck {
                     task_id,
-                    ack_info: AckInfo::Module {
+                    ack_info: AckInfo::ModuleListAck {
                         modules: cached.as_ref().map_or(Vec::new(), |v| vec![v.clone()]),
                     },
                 };
@@ -50,7 +50,7 @@ async fn run_client(streams: Vec<DuplexStream>) {
 
                         let ack_msg = Message::ClientAck {
                             task_id,
-                            ack_info: AckInfo::Chunk {
+                            ack_info: AckInfo::ChunkAck {
                                 chunk_index: idx,
                                 success: true,
                             },
@@ -66,7 +66,9 @@ async fn run_client(streams: Vec<DuplexStream>) {
                 });
                 let result_msg = Message::ClientResult {
                     task_id,
+                    attempt,
                     result: vec![Type::I32(result)],
+                    stats: ExecutionStats::default(),
                 };
                 client.send(&result_msg).await.unwrap();
 
@@ -106,6 +108,7 @@ async fn run_server(streams: Vec<DuplexStream>, module_count: usize, task_count:
                 binary: TEST_MODULE.to_vec(),
                 dependencies: vec![],
                 chunk_size: 16,
+                pinned: false,
             })
         })
         .collect();
@@ -115,10 +118,12 @@ async fn run_server(streams: Vec<DuplexStream>, module_count: usize, task_count:
             server.add_task(Task {
                 name: format!("task_{}", i),
                 params: vec![Type::I32(i as i32 * 10), Type::I32((i + 1) as i32 * 10)],
+                env: vec![],
                 result: vec![],
                 created_at: SystemTime::now(),
                 require_module: *modules.get(i % module_count).unwrap(),
                 priority: 1,
+                kind: TaskKind::Single,
             })
         })
         .collect();
@@ -0,0 +1,38 @@
+// Host side of this convention lives in program::memory. Re-export `alloc`,
+// `output_ptr` and `output_len` from a task module to take part in it:
+//
+//     export { alloc, output_ptr, output_len } from "./memory";
+
+let outputPtr: usize = 0;
+let outputLen: i32 = 0;
+let outputBuffer: ArrayBuffer | null = null;
+
+// Called by the host for every byte buffer param, the buffer stays pinned until `release`.
+export function alloc(len: i32): usize {
+    const buffer = new ArrayBuffer(len);
+    return __pin(changetype<usize>(buffer));
+}
+
+export function release(ptr: usize): void {
+    __unpin(ptr);
+}
+
+// Views a (ptr, len) param pair passed by the host as a byte array.
+export function input(ptr: usize, len: i32): Uint8Array {
+    return Uint8Array.wrap(changetype<ArrayBuffer>(ptr), 0, len);
+}
+
+// Publishes `data` as the task's buffer result, it is kept alive until the next call.
+export function setOutput(data: ArrayBufferView): void {
+    outputBuffer = data.buffer;
+    outputPtr = data.dataStart;
+    outputLen = data.byteLength;
+}
+
+export function output_ptr(): usize {
+    return outputPtr;
+}
+
+export function output_len(): i32 {
+    return outputLen;
+}
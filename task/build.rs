@@ -4,8 +4,19 @@ use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Output};
 
+use sha2::{Digest, Sha256};
+
+// How a project is built. AssemblyScript projects need npm, Rust ones only the
+// wasm32-unknown-unknown target (`rustup target add wasm32-unknown-unknown`).
+#[derive(PartialEq)]
+enum Toolchain {
+    AssemblyScript,
+    Rust,
+}
+
 struct Project<'a> {
     name: &'a str,
+    toolchain: Toolchain,
     src: &'a Path,
     dist: &'a Path,
 }
@@ -15,11 +26,13 @@ fn run_command(cwd: &Path, command: &str) -> Result<(), Box<dyn Error>> {
          Command::new("cmd")
             .current_dir(cwd)
             .args(["/C", command])
+            .env_remove("CARGO_ENCODED_RUSTFLAGS")
             .output()?
     } else {
         Command::new("sh")
             .current_dir(cwd)
             .args(["-c", command])
+            .env_remove("CARGO_ENCODED_RUSTFLAGS")
             .output()?
     };
 
@@ -45,7 +58,18 @@ fn build_project(cwd: &Path, project: &Project) -> Result<(), Box<dyn Error>> {
         "debug"
     };
 
-    let build_cmd = format!("npm run build --workspace={}", project.name);
+    let build_cmd = match project.toolchain {
+        Toolchain::AssemblyScript => format!("npm run build --workspace={}", project.name),
+        // Flags meant for the host build must not reach the wasm one, hence the cleared rustflags
+        // in `run_command`. A target dir of its own keeps clear of the lock on ours.
+        Toolchain::Rust => format!(
+            "\"{}\" build --manifest-path \"{}\" --target wasm32-unknown-unknown --target-dir \"{}\" {}",
+            std::env::var("CARGO")?,
+            project.src.join("Cargo.toml").display(),
+            project.dist.parent().unwrap().display(),
+            if mode == "release" { "--release" } else { "" }
+        ),
+    };
     run_command(cwd, &build_cmd)?;
 
     let source_dir = project.dist.join(mode);
@@ -64,39 +88,54 @@ fn build_project(cwd: &Path, project: &Project) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+// Module metadata is always generated. The binaries are embedded too unless the `lazy` feature is
+// on, then they are read from `dist_dir` when the server starts.
 fn generate_static_modules(dist_dir: &Path) -> Result<(), Box<dyn Error>> {
     let out_dir = std::env::var("OUT_DIR")?;
     let dest_path = Path::new(&out_dir).join("generate.rs");
     let mut file = File::create(&dest_path)?;
+    let lazy = std::env::var_os("CARGO_FEATURE_LAZY").is_some();
 
-    writeln!(file, "static STATIC_MODULES: &[StaticModule] = &[")?;
-
+    let mut modules = Vec::new();
     for entry in dist_dir.read_dir()? {
         let entry = entry?;
         let path = entry.path();
 
         if path.extension().and_then(|e| e.to_str()) == Some("wasm") {
-            let module_name = path.file_stem().and_then(|n| n.to_str()).unwrap();
+            let module_name = path.file_stem().and_then(|n| n.to_str()).unwrap().to_string();
+            modules.push((module_name, fs::read(&path)?));
+        }
+    }
 
-            let wasm_bytes = fs::read(&path)?;
+    writeln!(file, "static MODULES_DIR: &str = {:?};", dist_dir.display().to_string())?;
 
-            writeln!(file, "    StaticModule {{")?;
-            writeln!(file, "        name: \"{}\",", module_name)?;
-            writeln!(file, "        binary: &[")?;
+    writeln!(file, "static MODULE_METADATA: &[ModuleMetadata] = &[")?;
+    for (module_name, wasm_bytes) in &modules {
+        writeln!(file, "    ModuleMetadata {{")?;
+        writeln!(file, "        name: \"{}\",", module_name)?;
+        writeln!(file, "        size: {},", wasm_bytes.len())?;
+        writeln!(file, "        hash: {:?},", <[u8; 32]>::from(Sha256::digest(wasm_bytes)))?;
+        writeln!(file, "    }},")?;
+    }
+    writeln!(file, "];")?;
 
-            for chunk in wasm_bytes.chunks(12) {
-                write!(file, "            ")?;
-                for byte in chunk {
-                    write!(file, "0x{:02x}, ", byte)?;
-                }
-                writeln!(file)?;
+    writeln!(file, "static STATIC_MODULES: &[StaticModule] = &[")?;
+    for (module_name, wasm_bytes) in modules.iter().filter(|_| !lazy) {
+        writeln!(file, "    StaticModule {{")?;
+        writeln!(file, "        name: \"{}\",", module_name)?;
+        writeln!(file, "        binary: &[")?;
+
+        for chunk in wasm_bytes.chunks(12) {
+            write!(file, "            ")?;
+            for byte in chunk {
+                write!(file, "0x{:02x}, ", byte)?;
             }
-
-            writeln!(file, "        ],")?;
-            writeln!(file, "    }},")?;
+            writeln!(file)?;
         }
-    }
 
+        writeln!(file, "        ],")?;
+        writeln!(file, "    }},")?;
+    }
     writeln!(file, "];")?;
 
     Ok(())
@@ -107,15 +146,24 @@ fn main() {
     let dist_dir = manifest_dir.join("dist");
     fs::create_dir_all(&dist_dir).unwrap();
 
-    run_command(&manifest_dir, "npm install").unwrap();
-
     let projects = &[
         Project {
             name: "assembly",
+            toolchain: Toolchain::AssemblyScript,
             src: &manifest_dir.join("assembly/src"),
             dist: &manifest_dir.join("assembly/dist"),
-        }
+        },
+        Project {
+            name: "checksum",
+            toolchain: Toolchain::Rust,
+            src: &manifest_dir.join("rust/checksum"),
+            dist: &manifest_dir.join("rust/target/wasm32-unknown-unknown"),
+        },
     ];
+
+    if projects.iter().any(|project| project.toolchain == Toolchain::AssemblyScript) {
+        run_command(&manifest_dir, "npm install").unwrap();
+    }
     for project in projects {
         build_project(&manifest_dir, project).unwrap();
     }
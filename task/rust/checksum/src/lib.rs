@@ -0,0 +1,25 @@
+// Adler-32 of a byte buffer, passed in through `alloc` as described in program::memory.
+
+const MOD_ADLER: u32 = 65521;
+
+// Called by the host for every byte buffer param. Buffers live as long as the instance.
+#[no_mangle]
+pub extern "C" fn alloc(len: i32) -> *mut u8 {
+    let mut buffer = Vec::<u8>::with_capacity(len as usize);
+    let ptr = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+    ptr
+}
+
+// # Safety
+//
+// `ptr` and `len` describe a buffer handed out by `alloc` and filled by the host.
+#[no_mangle]
+pub unsafe extern "C" fn run(ptr: *const u8, len: i32) -> i32 {
+    let data = std::slice::from_raw_parts(ptr, len as usize);
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % MOD_ADLER;
+        (a, (b + a) % MOD_ADLER)
+    });
+    ((b << 16) | a) as i32
+}
@@ -1,4 +1,10 @@
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::Path;
+
 use protocol::Type;
+use sha2::{Digest, Sha256};
 
 include!(concat!(env!("OUT_DIR"), "/generate.rs"));
 
@@ -8,10 +14,66 @@ pub struct StaticModule {
     pub binary: &'static [u8],
 }
 
+// What the build recorded of a module, with or without its binary embedded.
+#[derive(Debug)]
+pub struct ModuleMetadata {
+    pub name: &'static str,
+    pub size: usize,
+    // SHA-256 of the binary.
+    pub hash: [u8; 32],
+}
+
+impl ModuleMetadata {
+    pub fn matches(&self, binary: &[u8]) -> bool {
+        binary.len() == self.size && Sha256::digest(binary)[..] == self.hash
+    }
+}
+
+#[derive(Debug)]
+pub struct LoadedModule {
+    pub name: &'static str,
+    pub binary: Cow<'static, [u8]>,
+}
+
+// Empty when built with the `lazy` feature.
 pub fn get_static_modules() -> &'static [StaticModule] {
     STATIC_MODULES
 }
 
+pub fn get_module_metadata() -> &'static [ModuleMetadata] {
+    MODULE_METADATA
+}
+
+// Binaries of every module, borrowed from the embedded ones unless built with the `lazy` feature.
+// Then each is read from `<dir>/<name>.wasm`, `dir` being the directory the build put them in
+// unless given, and refused unless it is the binary the build recorded.
+pub fn load_modules(dir: Option<&Path>) -> io::Result<Vec<LoadedModule>> {
+    if !cfg!(feature = "lazy") {
+        let modules = get_static_modules().iter().map(|module| LoadedModule {
+            name: module.name,
+            binary: Cow::Borrowed(module.binary),
+        });
+        return Ok(modules.collect());
+    }
+
+    let dir = dir.unwrap_or(Path::new(MODULES_DIR));
+    get_module_metadata()
+        .iter()
+        .map(|metadata| {
+            let path = dir.join(format!("{}.wasm", metadata.name));
+            let binary = fs::read(&path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))?;
+            if !metadata.matches(&binary) {
+                let reason = format!("{} does not match the module {} was built with", path.display(), metadata.name);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, reason));
+            }
+            Ok(LoadedModule {
+                name: metadata.name,
+                binary: Cow::Owned(binary),
+            })
+        })
+        .collect()
+}
+
 #[derive(Debug)]
 pub struct Task {
     pub name: String,
@@ -22,7 +84,7 @@ pub struct Task {
 pub fn load_tasks() -> Vec<Task> {
     let mut modules = Vec::new();
 
-    for module in get_static_modules().iter() {
+    for module in get_module_metadata().iter() {
         match module.name {
             "fractal" => {
                 const WIDTH: i32 = 800;
@@ -52,6 +114,20 @@ pub fn load_tasks() -> Vec<Task> {
             },
             "fiber" => {
                 
+            },
+            "checksum" => {
+                const BLOCKS: usize = 8;
+                const BLOCK_SIZE: usize = 4096;
+
+                for block in 0..BLOCKS {
+                    let data = (0..BLOCK_SIZE).map(|i| (i * 31 + block) as u8).collect();
+
+                    modules.push(Task {
+                        name: format!("checksum_{block}"),
+                        module: module.name.into(),
+                        params: vec![Type::Bytes(data)],
+                    });
+                }
             },
             _ => {}
         }
@@ -59,3 +135,43 @@ pub fn load_tasks() -> Vec<Task> {
 
     modules
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_modules() {
+        let modules = load_modules(None).unwrap();
+        assert_eq!(modules.len(), get_module_metadata().len());
+        for (module, metadata) in modules.iter().zip(get_module_metadata()) {
+            assert_eq!(module.name, metadata.name);
+            assert!(metadata.matches(&module.binary));
+        }
+    }
+
+    #[cfg(feature = "lazy")]
+    #[test]
+    fn test_load_modules_verified() {
+        let Some(metadata) = get_module_metadata().first() else {
+            return;
+        };
+        let dir = std::env::temp_dir().join(format!("task-modules-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for module in get_module_metadata() {
+            let file = format!("{}.wasm", module.name);
+            fs::copy(Path::new(MODULES_DIR).join(&file), dir.join(&file)).unwrap();
+        }
+        assert_eq!(load_modules(Some(&dir)).unwrap().len(), get_module_metadata().len());
+
+        let path = dir.join(format!("{}.wasm", metadata.name));
+        let mut binary = fs::read(&path).unwrap();
+        binary[0] ^= 0xff;
+        fs::write(&path, binary).unwrap();
+        assert_eq!(load_modules(Some(&dir)).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        fs::remove_file(&path).unwrap();
+        assert_eq!(load_modules(Some(&dir)).unwrap_err().kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}